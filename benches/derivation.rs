@@ -0,0 +1,70 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Derivation of a discovery scan
+//!
+//! Derives the look ahead of a 10k address scan for each address type. Compare
+//! `cargo bench --bench derivation` with `cargo bench --bench derivation --features parallel`.
+//!
+extern crate bitcoin;
+extern crate bitcoin_wallet;
+
+use std::time::{Duration, Instant};
+
+use bitcoin::Network;
+use bitcoin_wallet::account::{
+    Account, AccountAddressType, MasterAccount, MasterKeyEntropy, Unlocker,
+};
+
+const PASSPHRASE: &str = "correct horse battery staple";
+const ADDRESSES: u32 = 10_000;
+const ROUNDS: u32 = 3;
+
+fn main() {
+    let master =
+        MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+    let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+    println!(
+        "deriving {} addresses, parallel feature {}",
+        ADDRESSES,
+        if cfg!(feature = "parallel") {
+            "on"
+        } else {
+            "off"
+        }
+    );
+    for (name, address_type) in [
+        ("P2PKH", AccountAddressType::P2PKH),
+        ("P2SHWPKH", AccountAddressType::P2SHWPKH),
+        ("P2WPKH", AccountAddressType::P2WPKH),
+    ]
+    .iter()
+    {
+        let mut best = Duration::from_secs(u64::MAX);
+        for _ in 0..ROUNDS {
+            let started = Instant::now();
+            let account = Account::new(&mut unlocker, *address_type, 0, 0, ADDRESSES).unwrap();
+            best = best.min(started.elapsed());
+            assert_eq!(account.instantiated().len(), ADDRESSES as usize);
+        }
+        println!(
+            "{}: {:?}, {:.1} µs per address",
+            name,
+            best,
+            best.as_secs_f64() * 1e6 / f64::from(ADDRESSES)
+        );
+    }
+}
@@ -18,6 +18,13 @@
 //!
 //! Accounts compatible with BIP32, BIP39, BIP44, BIP49, BIP84
 //!
+//! With the parallel feature, keys of a look ahead are derived on all cores, e.g. the
+//! thousands of scripts of a discovery scan.
+//!
+//! Keys derived beyond those instantiated, e.g. by address searches, are kept in a key cache
+//! and not derived again when a look ahead reaches them.
+//!
+use bitcoin::consensus::{encode, Decodable, Encodable};
 use bitcoin::hashes::{hash160, Hash};
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::{
@@ -29,7 +36,8 @@ use bitcoin::{
     network::constants::Network,
     util::bip143,
     util::bip32::{ChildNumber, ExtendedPrivKey},
-    Address, OutPoint, PrivateKey, PublicKey, Script, Transaction,
+    util::psbt::PartiallySignedTransaction,
+    Address, OutPoint, PrivateKey, PublicKey, Script, Transaction, VarInt,
 };
 use crypto::{
     aes, blockmodes, buffer,
@@ -38,18 +46,26 @@ use crypto::{
     sha2::Sha256,
 };
 use rand::{thread_rng, RngCore};
+#[cfg(feature = "parallel")]
+use std::thread;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    io,
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use context::SecpContext;
+use context::{SecpContext, SigningConfig};
 use error::Error;
+use keycache::{KeyCache, KeyIndex};
 use sss::{ShamirSecretSharing, Share};
 
 use crate::mnemonic::Mnemonic;
 
+/// fewer keys are derived on the calling thread
+#[cfg(feature = "parallel")]
+const PARALLEL_DERIVATION: u32 = 256;
+
 /// chose your security level
 #[derive(Copy, Clone)]
 pub enum MasterKeyEntropy {
@@ -64,6 +80,18 @@ pub struct MasterAccount {
     encrypted: Vec<u8>,
     accounts: HashMap<(u32, u32), Account>,
     birth: u64,
+    watched: HashSet<Script>,
+    /// reject address revelation and signing, not serialized
+    read_only: bool,
+    index: ScriptIndex,
+}
+
+/// derivation of the scripts of instantiated keys, not serialized
+#[derive(Default)]
+struct ScriptIndex {
+    derivations: HashMap<Script, KeyDerivation>,
+    /// keys indexed of each account
+    indexed: HashMap<(u32, u32), usize>,
 }
 
 impl MasterAccount {
@@ -96,6 +124,9 @@ impl MasterAccount {
             encrypted,
             accounts: HashMap::new(),
             birth,
+            watched: HashSet::new(),
+            read_only: false,
+            index: ScriptIndex::default(),
         }
     }
 
@@ -106,6 +137,9 @@ impl MasterAccount {
             encrypted: Vec::new(),
             accounts: HashMap::new(),
             birth,
+            watched: HashSet::new(),
+            read_only: false,
+            index: ScriptIndex::default(),
         }
     }
 
@@ -133,6 +167,17 @@ impl MasterAccount {
         Self::from_seed(&seed, birth, network, passphrase)
     }
 
+    /// Restore from the master private key of an other wallet, e.g. Bitcoin Core
+    /// The key takes the place of the seed and is stored encrypted with passphrase.
+    pub fn from_master_key(
+        master_key: &ExtendedPrivKey,
+        birth: u64,
+        passphrase: &str,
+    ) -> Result<MasterAccount, Error> {
+        let seed = Seed::from_master_key(master_key);
+        Self::from_seed(&seed, birth, master_key.network, passphrase)
+    }
+
     pub fn from_seed(
         seed: &Seed,
         birth: u64,
@@ -141,13 +186,16 @@ impl MasterAccount {
     ) -> Result<MasterAccount, Error> {
         let context = SecpContext::new();
         let encrypted = seed.encrypt(passphrase)?;
-        let master_key = context.master_private_key(network, &seed)?;
+        let master_key = context.master_private_key(network, seed)?;
         let public_master_key = context.extended_public_from_private(&master_key);
         Ok(MasterAccount {
             master_public: public_master_key,
             encrypted,
             accounts: HashMap::new(),
             birth,
+            watched: HashSet::new(),
+            read_only: false,
+            index: ScriptIndex::default(),
         })
     }
 
@@ -169,6 +217,13 @@ impl MasterAccount {
         &self.encrypted
     }
 
+    /// a copy without the encrypted seed, it watches the same accounts but can not sign
+    pub fn to_watch_only(&self) -> Result<MasterAccount, Error> {
+        let mut copy: MasterAccount = encode::deserialize(&encode::serialize(self))?;
+        copy.encrypted = Vec::new();
+        Ok(copy)
+    }
+
     pub fn birth(&self) -> u64 {
         self.birth
     }
@@ -185,6 +240,20 @@ impl MasterAccount {
         &self.accounts
     }
 
+    /// accounts that are not archived
+    /// these are the ones considered for balances and spending by default
+    pub fn active_accounts(&self) -> impl Iterator<Item = (&(u32, u32), &Account)> {
+        self.accounts.iter().filter(|(_, a)| !a.is_archived())
+    }
+
+    /// true if the account exists and is archived
+    pub fn is_archived(&self, account: (u32, u32)) -> bool {
+        self.accounts
+            .get(&account)
+            .map(|a| a.is_archived())
+            .unwrap_or(false)
+    }
+
     pub fn get_scripts<'a>(&'a self) -> impl Iterator<Item = (Script, KeyDerivation)> + 'a {
         self.accounts.iter().flat_map(|((an, sub), a)| {
             a.get_scripts().map(move |(kix, s, tweak, csv)| {
@@ -202,11 +271,100 @@ impl MasterAccount {
         })
     }
 
-    pub fn add_account(&mut self, account: Account) {
-        self.accounts.insert(
-            (account.account_number, account.sub_account_number),
-            account,
-        );
+    /// derivation of a script of an instantiated key, a lookup in an index
+    /// Keys instantiated through get_mut are found after index_scripts.
+    pub fn derivation(&self, script: &Script) -> Option<&KeyDerivation> {
+        self.index.derivations.get(script)
+    }
+
+    /// index keys instantiated since the last call
+    pub fn index_scripts(&mut self) {
+        let keys = self.accounts.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.index_account(key);
+        }
+    }
+
+    /// look ahead from seen in an account, indexing the scripts of the keys it adds
+    pub fn look_ahead(&mut self, account: (u32, u32), seen: u32) -> Result<(), Error> {
+        self.accounts
+            .get_mut(&account)
+            .ok_or(Error::Unsupported("no such account"))?
+            .do_look_ahead(Some(seen))?;
+        self.index_account(account);
+        Ok(())
+    }
+
+    /// index keys of an account instantiated since it was indexed last
+    fn index_account(&mut self, (an, sub): (u32, u32)) {
+        let account = match self.accounts.get(&(an, sub)) {
+            Some(account) => account,
+            None => return,
+        };
+        let indexed = self.index.indexed.entry((an, sub)).or_insert(0);
+        for (kix, key) in account.instantiated.iter().enumerate().skip(*indexed) {
+            self.index.derivations.insert(
+                key.address.script_pubkey(),
+                KeyDerivation {
+                    account: an,
+                    sub,
+                    kix: kix as u32,
+                    tweak: key.tweak.clone(),
+                    csv: key.csv,
+                },
+            );
+        }
+        *indexed = account.instantiated.len();
+    }
+
+    /// watch a script this wallet can not spend, e.g. a cold storage address
+    pub fn add_watched_script(&mut self, script: Script) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("watching scripts"));
+        }
+        self.watched.insert(script);
+        Ok(())
+    }
+
+    /// stop watching a script
+    pub fn remove_watched_script(&mut self, script: &Script) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("watching scripts"));
+        }
+        Ok(self.watched.remove(script))
+    }
+
+    pub fn watched_scripts(&self) -> &HashSet<Script> {
+        &self.watched
+    }
+
+    pub fn is_watched(&self, script: &Script) -> bool {
+        self.watched.contains(script)
+    }
+
+    /// reject address revelation, watching scripts and signing with Error::ReadOnly, e.g. for
+    /// audits of a production wallet
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        for account in self.accounts.values_mut() {
+            account.read_only = read_only;
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn add_account(&mut self, mut account: Account) {
+        account.read_only |= self.read_only;
+        let key = (account.account_number, account.sub_account_number);
+        if self.accounts.insert(key, account).is_some() {
+            self.index
+                .derivations
+                .retain(|_, d| (d.account, d.sub) != key);
+            self.index.indexed.remove(&key);
+        }
+        self.index_scripts();
     }
 
     pub fn sign<R>(
@@ -219,12 +377,31 @@ impl MasterAccount {
     where
         R: Fn(&OutPoint) -> Option<TxOut>,
     {
+        if self.read_only {
+            return Err(Error::ReadOnly("signing"));
+        }
         let mut n_signatures = 0;
         for (_, a) in self.accounts.iter() {
             n_signatures += a.sign(transaction, hash_type, resolver, unlocker)?;
         }
         Ok(n_signatures)
     }
+
+    /// add partial signatures of own keys to a PSBT, returns the number of signatures added
+    pub fn sign_psbt(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        unlocker: &mut Unlocker,
+    ) -> Result<usize, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("signing"));
+        }
+        let mut n_signatures = 0;
+        for (_, a) in self.accounts.iter() {
+            n_signatures += a.sign_psbt(psbt, unlocker)?;
+        }
+        Ok(n_signatures)
+    }
 }
 
 /// calculator of private keys
@@ -232,6 +409,7 @@ pub struct Unlocker {
     master_private: ExtendedPrivKey,
     network: Network,
     context: Arc<SecpContext>,
+    #[allow(clippy::type_complexity)]
     cached: HashMap<
         AccountAddressType,
         (
@@ -319,9 +497,8 @@ impl Unlocker {
                 .private_child(&by_coin_type.0, ChildNumber::Hardened { index: account })?,
             HashMap::new(),
         ));
-        Ok(self
-            .context
-            .private_child(&by_account.0, ChildNumber::Normal { index: sub_account })?)
+        self.context
+            .private_child(&by_account.0, ChildNumber::Normal { index: sub_account })
     }
 
     pub fn unlock(
@@ -346,8 +523,16 @@ impl Unlocker {
     pub fn context(&self) -> Arc<SecpContext> {
         self.context.clone()
     }
+
+    /// sign with config from now on, e.g. SigningConfig::deterministic() for test vectors
+    pub fn set_signing_config(&mut self, config: SigningConfig) {
+        self.context = Arc::new(SecpContext::with_signing_config(config));
+    }
 }
 
+/// sub-account of change keys, the internal chain of BIP44
+pub const CHANGE_SUB_ACCOUNT: u32 = 1;
+
 /// Key derivation detail information
 /// coordinates of a key as defined in BIP32 and BIP44
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -364,6 +549,52 @@ pub struct KeyDerivation {
     pub csv: Option<u16>,
 }
 
+impl Encodable for KeyDerivation {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.account.consensus_encode(&mut w)?;
+        len += self.sub.consensus_encode(&mut w)?;
+        len += self.kix.consensus_encode(&mut w)?;
+        match self.tweak {
+            Some(ref tweak) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += tweak.consensus_encode(&mut w)?;
+            }
+            None => len += 0u8.consensus_encode(&mut w)?,
+        }
+        match self.csv {
+            Some(csv) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += csv.consensus_encode(&mut w)?;
+            }
+            None => len += 0u8.consensus_encode(&mut w)?,
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for KeyDerivation {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<KeyDerivation, encode::Error> {
+        let account = u32::consensus_decode(&mut d)?;
+        let sub = u32::consensus_decode(&mut d)?;
+        let kix = u32::consensus_decode(&mut d)?;
+        let tweak = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(Vec::<u8>::consensus_decode(&mut d)?),
+        };
+        let csv = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(u16::consensus_decode(&mut d)?),
+        };
+        Ok(KeyDerivation {
+            account,
+            sub,
+            kix,
+            tweak,
+            csv,
+        })
+    }
+}
+
 /// Address type an account is using
 #[derive(Copy, Clone, Hash, Eq, PartialEq)]
 pub enum AccountAddressType {
@@ -400,6 +631,18 @@ impl AccountAddressType {
     }
 }
 
+/// descriptive data of an account that is not needed for key derivation
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AccountMetadata {
+    /// name to display
+    pub name: String,
+    /// creation time, seconds since epoch
+    pub created: u64,
+    /// archived accounts are still scanned but they are excluded from balances and spending
+    pub archived: bool,
+}
+
 pub struct Account {
     address_type: AccountAddressType,
     account_number: u32,
@@ -410,6 +653,11 @@ pub struct Account {
     next: u32,
     look_ahead: u32,
     network: Network,
+    metadata: AccountMetadata,
+    single_key: bool,
+    read_only: bool,
+    /// keys derived beyond those instantiated, not stored
+    cache: Arc<KeyCache>,
 }
 
 impl Account {
@@ -434,11 +682,66 @@ impl Account {
             next: 0,
             look_ahead,
             network: pubic_key.network,
+            metadata: AccountMetadata {
+                created: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                ..Default::default()
+            },
+            single_key: false,
+            read_only: false,
+            cache: Arc::new(KeyCache::default()),
         };
         sub.do_look_ahead(None)?;
         Ok(sub)
     }
 
+    /// create an account that holds a single imported key, e.g. of a paper wallet
+    /// The key is stored as an additive tweak to the first key of the account's derivation path,
+    /// so it is only spendable with the unlocker of the master that created this account.
+    pub fn new_single_key(
+        unlocker: &mut Unlocker,
+        address_type: AccountAddressType,
+        account_number: u32,
+        sub_account_number: u32,
+        key: &PrivateKey,
+    ) -> Result<Account, Error> {
+        if let AccountAddressType::P2WSH(_) = address_type {
+            return Err(Error::Unsupported(
+                "single key accounts can not be used with P2WSH",
+            ));
+        }
+        if !key.compressed {
+            return Err(Error::Unsupported(
+                "single key accounts need a compressed key",
+            ));
+        }
+        let mut account = Account::new(
+            unlocker,
+            address_type,
+            account_number,
+            sub_account_number,
+            0,
+        )?;
+        let base = unlocker.unlock(address_type, account_number, sub_account_number, 0, None)?;
+        let tweak = account.context.tweak_between(&base, key)?;
+        let instantiated = InstantiatedKey::new(
+            address_type,
+            account.network,
+            &account.master_public,
+            Some(tweak.as_slice()),
+            0,
+            |public: &PublicKey, _| Self::script_code(address_type, public),
+            None,
+            account.context.clone(),
+        )?;
+        account.instantiated.push(instantiated);
+        account.single_key = true;
+        Ok(account)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_storage(
         address_type: AccountAddressType,
         account_number: u32,
@@ -460,9 +763,40 @@ impl Account {
             next,
             look_ahead,
             network,
+            metadata: AccountMetadata::default(),
+            single_key: false,
+            read_only: false,
+            cache: Arc::new(KeyCache::default()),
         }
     }
 
+    /// mark an account restored from storage as single key account
+    pub fn with_single_key(mut self) -> Account {
+        self.single_key = true;
+        self
+    }
+
+    /// cache derived keys in a cache shared, e.g. by the accounts of a master account
+    pub fn with_key_cache(mut self, cache: Arc<KeyCache>) -> Account {
+        self.cache = cache;
+        self
+    }
+
+    pub fn key_cache(&self) -> &KeyCache {
+        &self.cache
+    }
+
+    /// true if this account holds a single imported key instead of a chain of derived keys
+    pub fn is_single_key(&self) -> bool {
+        self.single_key
+    }
+
+    /// set metadata e.g. while restoring from storage
+    pub fn with_metadata(mut self, metadata: AccountMetadata) -> Account {
+        self.metadata = metadata;
+        self
+    }
+
     pub fn address_type(&self) -> AccountAddressType {
         self.address_type
     }
@@ -495,6 +829,23 @@ impl Account {
         &self.instantiated
     }
 
+    pub fn metadata(&self) -> &AccountMetadata {
+        &self.metadata
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.metadata.name = name.to_string();
+    }
+
+    pub fn is_archived(&self) -> bool {
+        self.metadata.archived
+    }
+
+    /// archive or restore an account
+    pub fn set_archived(&mut self, archived: bool) {
+        self.metadata.archived = archived;
+    }
+
     /// look ahead from last seen
     pub fn do_look_ahead(&mut self, seen: Option<u32>) -> Result<Vec<(u32, Script)>, Error> {
         use std::cmp::max;
@@ -506,28 +857,20 @@ impl Account {
         let seen = seen.unwrap_or(0);
         let have = self.instantiated.len() as u32;
         let need = max(seen + self.look_ahead, have) - have;
-        let mut new = Vec::new();
-        for i in 0..need {
-            new.push((
-                have + i,
-                self.instantiate_more()?.address.script_pubkey().clone(),
-            ));
-        }
+        let keys = self.derive(have, need)?;
+        let new = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (have + i as u32, key.address.script_pubkey()))
+            .collect();
+        self.instantiated.extend(keys);
         Ok(new)
     }
 
-    fn instantiate_more(&mut self) -> Result<&InstantiatedKey, Error> {
-        let kix = self.instantiated.len() as u32;
-
-        let scripter = |public: &PublicKey, _| match self.address_type {
-            AccountAddressType::P2SHWPKH => Builder::new()
-                .push_opcode(all::OP_DUP)
-                .push_opcode(all::OP_HASH160)
-                .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
-                .push_opcode(all::OP_EQUALVERIFY)
-                .push_opcode(all::OP_CHECKSIG)
-                .into_script(),
-            AccountAddressType::P2WPKH => Builder::new()
+    /// script code of single key address types
+    fn script_code(address_type: AccountAddressType, public: &PublicKey) -> Script {
+        match address_type {
+            AccountAddressType::P2SHWPKH | AccountAddressType::P2WPKH => Builder::new()
                 .push_opcode(all::OP_DUP)
                 .push_opcode(all::OP_HASH160)
                 .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
@@ -535,46 +878,172 @@ impl Account {
                 .push_opcode(all::OP_CHECKSIG)
                 .into_script(),
             _ => Script::new(),
+        }
+    }
+
+    /// count keys from index start, derived on all cores with the parallel feature
+    fn derive(&self, start: u32, count: u32) -> Result<Vec<InstantiatedKey>, Error> {
+        let address_type = self.address_type;
+        let derive = |kix| {
+            // keys of a look ahead are not cached, only those searched or checked before
+            let base = match self.cache.get(self.key_index(kix)) {
+                Some(key) => key.public,
+                None => self.derive_public(kix)?,
+            };
+            InstantiatedKey::with_base(
+                address_type,
+                self.network,
+                base,
+                None,
+                |public: &PublicKey, _| Self::script_code(address_type, public),
+                None,
+                &self.context,
+            )
         };
-        let instantiated = InstantiatedKey::new(
-            self.address_type,
-            self.network,
-            &self.master_public,
-            None,
-            kix,
-            scripter,
-            None,
-            self.context.clone(),
-        )?;
+        #[cfg(feature = "parallel")]
+        {
+            let threads = thread::available_parallelism().map_or(1, |n| n.get()) as u32;
+            if threads > 1 && count >= PARALLEL_DERIVATION {
+                let chunk = count.div_ceil(threads);
+                let derive = &derive;
+                return thread::scope(|scope| {
+                    let workers = (0..threads)
+                        .map(|t| {
+                            let from = start + (t * chunk).min(count);
+                            let to = start + ((t + 1) * chunk).min(count);
+                            scope.spawn(move || {
+                                (from..to).map(derive).collect::<Result<Vec<_>, _>>()
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    let mut keys = Vec::with_capacity(count as usize);
+                    for worker in workers {
+                        keys.extend(worker.join().expect("derivation does not panic")?);
+                    }
+                    Ok(keys)
+                });
+            }
+        }
+        (start..start + count).map(derive).collect()
+    }
 
-        let len = self.instantiated.len();
-        self.instantiated.push(instantiated);
-        Ok(&self.instantiated[len])
+    fn instantiate_more(&mut self) -> Result<&InstantiatedKey, Error> {
+        let kix = self.instantiated.len() as u32;
+        let instantiated = self.derive(kix, 1)?;
+        self.instantiated.extend(instantiated);
+        Ok(&self.instantiated[kix as usize])
     }
 
     /// create a new key
     pub fn next_key(&mut self) -> Result<&InstantiatedKey, Error> {
-        match self.address_type {
-            AccountAddressType::P2WSH(_) => {
-                return Err(Error::Unsupported(
-                    "next_key can not be used for P2WSH accounts",
-                ))
-            }
-            _ => {}
+        if self.read_only {
+            return Err(Error::ReadOnly("address revelation"));
+        }
+        if let AccountAddressType::P2WSH(_) = self.address_type {
+            return Err(Error::Unsupported(
+                "next_key can not be used for P2WSH accounts",
+            ));
+        }
+        if self.single_key {
+            return Err(Error::Unsupported(
+                "next_key can not be used for single key accounts",
+            ));
         }
         self.instantiate_more()?;
         let key = &self.instantiated[self.next as usize];
         self.next += 1;
-        Ok(&key)
+        Ok(key)
     }
 
+    /// the untweaked public key kix, cached if not instantiated
     pub fn compute_base_public_key(&self, kix: u32) -> Result<PublicKey, Error> {
+        match self.instantiated.get(kix as usize) {
+            Some(key) if key.tweak.is_none() => Ok(key.public),
+            _ => self
+                .cache
+                .public_key(self.key_index(kix), || self.derive_public(kix)),
+        }
+    }
+
+    fn derive_public(&self, kix: u32) -> Result<PublicKey, Error> {
         Ok(self
             .context
             .public_child(&self.master_public, ChildNumber::Normal { index: kix })?
             .public_key)
     }
 
+    fn key_index(&self, kix: u32) -> KeyIndex {
+        (self.account_number, self.sub_account_number, kix)
+    }
+
+    /// search up to count not yet instantiated keys of this account for an address the predicate
+    /// accepts. The predicate is given the address body: the part after the human readable part,
+    /// separator and witness version of bech32 addresses or after the version character of legacy
+    /// addresses. Only public derivation is used. Returns key index and address of the first match.
+    /// Use do_look_ahead(Some(index)) to instantiate keys up to the match.
+    pub fn search_address<P>(&self, count: u32, matches: P) -> Result<Option<(u32, Address)>, Error>
+    where
+        P: Fn(&str) -> bool,
+    {
+        match self.address_type {
+            AccountAddressType::P2WSH(_) => {
+                return Err(Error::Unsupported(
+                    "address search is not possible for P2WSH accounts",
+                ))
+            }
+            _ if self.single_key => {
+                return Err(Error::Unsupported(
+                    "address search is not possible for single key accounts",
+                ))
+            }
+            _ => {}
+        }
+        let start = self.instantiated.len() as u32;
+        let address = |public: &PublicKey| match self.address_type {
+            AccountAddressType::P2PKH => Address::p2pkh(public, self.network),
+            AccountAddressType::P2SHWPKH => {
+                Address::p2shwpkh(public, self.network).expect("compressed pubkey")
+            }
+            _ => Address::p2wpkh(public, self.network).expect("compressed pubkey"),
+        };
+        for kix in start..start.saturating_add(count) {
+            let (_, script) = self.cache.script(
+                self.key_index(kix),
+                || self.derive_public(kix),
+                |public| address(public).script_pubkey(),
+            )?;
+            let address = Address::from_script(&script, self.network).expect("standard script");
+            let text = address.to_string();
+            let body = match text.rfind('1') {
+                Some(separator) if self.address_type == AccountAddressType::P2WPKH => {
+                    &text[separator + 2..]
+                }
+                _ => &text[1..],
+            };
+            if matches(body) {
+                return Ok(Some((kix, address)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// search for an address whose body starts with prefix, see search_address
+    pub fn search_address_prefix(
+        &self,
+        prefix: &str,
+        count: u32,
+    ) -> Result<Option<(u32, Address)>, Error> {
+        let prefix = prefix.to_lowercase();
+        let bech32 = self.address_type == AccountAddressType::P2WPKH;
+        self.search_address(count, |body| {
+            if bech32 {
+                body.starts_with(prefix.as_str())
+            } else {
+                body.to_lowercase().starts_with(prefix.as_str())
+            }
+        })
+    }
+
     /// get a previously instantiated key
     pub fn get_key(&self, kix: u32) -> Option<&InstantiatedKey> {
         self.instantiated.get(kix as usize)
@@ -589,6 +1058,9 @@ impl Account {
     where
         W: FnOnce(&PublicKey, Option<u16>) -> Script,
     {
+        if self.read_only {
+            return Err(Error::ReadOnly("address revelation"));
+        }
         match self.address_type {
             AccountAddressType::P2WSH(_) => {}
             _ => {
@@ -598,15 +1070,14 @@ impl Account {
             }
         }
         let kix = self.instantiated.len() as u32;
-        let instantiated = InstantiatedKey::new(
+        let instantiated = InstantiatedKey::with_base(
             self.address_type,
             self.network,
-            &self.master_public,
+            self.compute_base_public_key(kix)?,
             tweak,
-            kix,
             scripter,
             csv,
-            self.context.clone(),
+            &self.context,
         )?;
         self.instantiated.push(instantiated);
         Ok(kix)
@@ -625,7 +1096,7 @@ impl Account {
                 kix as u32,
                 i.address.script_pubkey().clone(),
                 i.tweak.clone(),
-                i.csv.clone(),
+                i.csv,
             )
         })
     }
@@ -641,6 +1112,9 @@ impl Account {
     where
         R: Fn(&OutPoint) -> Option<TxOut>,
     {
+        if self.read_only {
+            return Err(Error::ReadOnly("signing"));
+        }
         let mut signed = 0;
         //TODO(stevenroose) try to prevent this clone here
         let txclone = transaction.clone();
@@ -667,7 +1141,8 @@ impl Account {
                                 &instantiated.address.script_pubkey(),
                                 hash_type.as_u32(),
                             );
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
+                            let signature =
+                                unlocker.context().sign(&sighash[..], &pk)?.serialize_der();
                             let mut with_hashtype = signature.to_vec();
                             with_hashtype.push(hash_type.as_u32() as u8);
                             input.script_sig = Builder::new()
@@ -688,7 +1163,8 @@ impl Account {
                                 spend.value,
                                 hash_type,
                             );
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
+                            let signature =
+                                unlocker.context().sign(&sighash[..], &pk)?.serialize_der();
                             let mut with_hashtype = signature.to_vec();
                             with_hashtype.push(hash_type.as_u32() as u8);
                             input.witness.clear();
@@ -718,7 +1194,8 @@ impl Account {
                                 spend.value,
                                 hash_type,
                             );
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
+                            let signature =
+                                unlocker.context().sign(&sighash[..], &pk)?.serialize_der();
                             let mut with_hashtype = signature.to_vec();
                             with_hashtype.push(hash_type.as_u32() as u8);
                             input.witness.clear();
@@ -737,7 +1214,8 @@ impl Account {
                                 spend.value,
                                 hash_type,
                             );
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
+                            let signature =
+                                unlocker.context().sign(&sighash[..], &pk)?.serialize_der();
                             let mut with_hashtype = signature.to_vec();
                             with_hashtype.push(hash_type.as_u32() as u8);
                             input.witness.clear();
@@ -751,6 +1229,222 @@ impl Account {
         }
         Ok(signed)
     }
+
+    /// add partial signatures for inputs of a PSBT spending keys of this account
+    /// inputs must have the spent output, the sighash type of the input defaults to SIGHASH_ALL
+    pub fn sign_psbt(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        unlocker: &mut Unlocker,
+    ) -> Result<usize, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("signing"));
+        }
+        let mut signed = 0;
+        let transaction = psbt.global.unsigned_tx.clone();
+        let mut bip143hasher = bip143::SigHashCache::new(&transaction);
+        for (ix, input) in psbt.inputs.iter_mut().enumerate() {
+            let vout = transaction.input[ix].previous_output.vout as usize;
+            let spend = match (&input.witness_utxo, &input.non_witness_utxo) {
+                (Some(output), _) => output.clone(),
+                (None, Some(previous)) => match previous.output.get(vout) {
+                    Some(output) => output.clone(),
+                    None => continue,
+                },
+                (None, None) => continue,
+            };
+            if let Some((kix, instantiated)) = self
+                .instantiated
+                .iter()
+                .enumerate()
+                .find(|(_, i)| i.address.script_pubkey() == spend.script_pubkey)
+            {
+                if input.partial_sigs.contains_key(&instantiated.public) {
+                    continue;
+                }
+                let hash_type = input.sighash_type.unwrap_or(SigHashType::All);
+                if hash_type.as_u32() & 0x1f == SigHashType::Single.as_u32()
+                    && ix >= transaction.output.len()
+                {
+                    return Err(Error::Unsupported("SIGHASH_SINGLE without matching output"));
+                }
+                let sighash = match self.address_type {
+                    AccountAddressType::P2PKH => {
+                        transaction.signature_hash(ix, &spend.script_pubkey, hash_type.as_u32())
+                    }
+                    _ => bip143hasher.signature_hash(
+                        ix,
+                        &instantiated.script_code,
+                        spend.value,
+                        hash_type,
+                    ),
+                };
+                let pk = unlocker.unlock(
+                    self.address_type,
+                    self.account_number,
+                    self.sub_account_number,
+                    kix as u32,
+                    instantiated.tweak.clone(),
+                )?;
+                let mut signature = unlocker
+                    .context()
+                    .sign(&sighash[..], &pk)?
+                    .serialize_der()
+                    .to_vec();
+                signature.push(hash_type.as_u32() as u8);
+                input.partial_sigs.insert(instantiated.public, signature);
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+}
+
+/// extended public key with its network, as the BIP32 encoding does not tell regtest
+fn encode_xpub<W: io::Write>(xpub: &ExtendedPubKey, mut w: W) -> Result<usize, io::Error> {
+    w.write_all(&xpub.encode())?;
+    Ok(78 + xpub.network.magic().consensus_encode(&mut w)?)
+}
+
+fn decode_xpub<D: io::Read>(mut d: D) -> Result<ExtendedPubKey, encode::Error> {
+    let mut data = [0u8; 78];
+    d.read_exact(&mut data)?;
+    let mut xpub = ExtendedPubKey::decode(&data)
+        .map_err(|_| encode::Error::ParseFailed("invalid extended public key"))?;
+    xpub.network = Network::from_magic(u32::consensus_decode(&mut d)?)
+        .ok_or(encode::Error::ParseFailed("unknown network"))?;
+    Ok(xpub)
+}
+
+impl Encodable for Account {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.address_type.as_u32().consensus_encode(&mut w)?;
+        len += self.account_number.consensus_encode(&mut w)?;
+        len += self.sub_account_number.consensus_encode(&mut w)?;
+        len += encode_xpub(&self.master_public, &mut w)?;
+        len += self.next.consensus_encode(&mut w)?;
+        len += self.look_ahead.consensus_encode(&mut w)?;
+        len += (self.single_key as u8).consensus_encode(&mut w)?;
+        len += self.metadata.name.consensus_encode(&mut w)?;
+        len += self.metadata.created.consensus_encode(&mut w)?;
+        len += (self.metadata.archived as u8).consensus_encode(&mut w)?;
+        len += VarInt(self.instantiated.len() as u64).consensus_encode(&mut w)?;
+        for key in self.instantiated.iter() {
+            len += key.public.to_bytes().consensus_encode(&mut w)?;
+            len += key.script_code.consensus_encode(&mut w)?;
+            len += key.address.script_pubkey().consensus_encode(&mut w)?;
+            match key.tweak {
+                Some(ref tweak) => {
+                    len += 1u8.consensus_encode(&mut w)?;
+                    len += tweak.consensus_encode(&mut w)?;
+                }
+                None => len += 0u8.consensus_encode(&mut w)?,
+            }
+            match key.csv {
+                Some(csv) => {
+                    len += 1u8.consensus_encode(&mut w)?;
+                    len += csv.consensus_encode(&mut w)?;
+                }
+                None => len += 0u8.consensus_encode(&mut w)?,
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Account {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Account, encode::Error> {
+        let address_type = AccountAddressType::from_u32(u32::consensus_decode(&mut d)?);
+        let account_number = u32::consensus_decode(&mut d)?;
+        let sub_account_number = u32::consensus_decode(&mut d)?;
+        let master_public = decode_xpub(&mut d)?;
+        let network = master_public.network;
+        let next = u32::consensus_decode(&mut d)?;
+        let look_ahead = u32::consensus_decode(&mut d)?;
+        let single_key = u8::consensus_decode(&mut d)? != 0;
+        let metadata = AccountMetadata {
+            name: String::consensus_decode(&mut d)?,
+            created: u64::consensus_decode(&mut d)?,
+            archived: u8::consensus_decode(&mut d)? != 0,
+        };
+        let mut instantiated = Vec::new();
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let public = PublicKey::from_slice(Vec::<u8>::consensus_decode(&mut d)?.as_slice())
+                .map_err(|_| encode::Error::ParseFailed("invalid public key"))?;
+            let script_code = Script::consensus_decode(&mut d)?;
+            let address = Address::from_script(&Script::consensus_decode(&mut d)?, network)
+                .ok_or(encode::Error::ParseFailed("invalid address"))?;
+            let tweak = match u8::consensus_decode(&mut d)? {
+                0 => None,
+                _ => Some(Vec::<u8>::consensus_decode(&mut d)?),
+            };
+            let csv = match u8::consensus_decode(&mut d)? {
+                0 => None,
+                _ => Some(u16::consensus_decode(&mut d)?),
+            };
+            instantiated.push(InstantiatedKey {
+                public,
+                script_code,
+                address,
+                tweak,
+                csv,
+            });
+        }
+        let mut account = Account::new_from_storage(
+            address_type,
+            account_number,
+            sub_account_number,
+            master_public,
+            instantiated,
+            next,
+            look_ahead,
+            network,
+        )
+        .with_metadata(metadata);
+        account.single_key = single_key;
+        Ok(account)
+    }
+}
+
+impl Encodable for MasterAccount {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = encode_xpub(&self.master_public, &mut w)?;
+        len += self.encrypted.consensus_encode(&mut w)?;
+        len += self.birth.consensus_encode(&mut w)?;
+        let mut accounts = self.accounts.values().collect::<Vec<_>>();
+        accounts.sort_by_key(|a| (a.account_number, a.sub_account_number));
+        len += VarInt(accounts.len() as u64).consensus_encode(&mut w)?;
+        for account in accounts {
+            len += account.consensus_encode(&mut w)?;
+        }
+        let mut watched = self.watched.iter().collect::<Vec<_>>();
+        watched.sort();
+        len += VarInt(watched.len() as u64).consensus_encode(&mut w)?;
+        for script in watched {
+            len += script.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for MasterAccount {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<MasterAccount, encode::Error> {
+        let master_public = decode_xpub(&mut d)?;
+        let encrypted = Vec::<u8>::consensus_decode(&mut d)?;
+        let birth = u64::consensus_decode(&mut d)?;
+        let mut master = if encrypted.is_empty() {
+            MasterAccount::watch_only(master_public, birth)
+        } else {
+            MasterAccount::from_encrypted(encrypted.as_slice(), master_public, birth)
+        };
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            master.add_account(Account::consensus_decode(&mut d)?);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            master.watched.insert(Script::consensus_decode(&mut d)?);
+        }
+        Ok(master)
+    }
 }
 
 /// instantiated key of an account
@@ -765,6 +1459,7 @@ pub struct InstantiatedKey {
 }
 
 impl InstantiatedKey {
+    #[allow(clippy::too_many_arguments)]
     pub fn new<W>(
         address_type: AccountAddressType,
         network: Network,
@@ -778,9 +1473,25 @@ impl InstantiatedKey {
     where
         W: FnOnce(&PublicKey, Option<u16>) -> Script,
     {
-        let mut public = context
+        let base = context
             .public_child(master, ChildNumber::Normal { index: kix })?
             .public_key;
+        Self::with_base(address_type, network, base, tweak, scripter, csv, &context)
+    }
+
+    /// the key of an untweaked public key already derived
+    fn with_base<W>(
+        address_type: AccountAddressType,
+        network: Network,
+        mut public: PublicKey,
+        tweak: Option<&[u8]>,
+        scripter: W,
+        csv: Option<u16>,
+        context: &SecpContext,
+    ) -> Result<InstantiatedKey, Error>
+    where
+        W: FnOnce(&PublicKey, Option<u16>) -> Script,
+    {
         if let Some(tweak) = tweak {
             context.tweak_exp_add(&mut public, tweak)?;
         }
@@ -810,7 +1521,20 @@ impl InstantiatedKey {
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Seed(pub Vec<u8>);
 
+/// length of a serialized extended private key, BIP32 seeds are 16 to 64 bytes
+const MASTER_KEY_SEED_LEN: usize = 78;
+
 impl Seed {
+    /// a master key imported from an other wallet in place of a seed
+    pub fn from_master_key(master_key: &ExtendedPrivKey) -> Seed {
+        Seed(master_key.encode().to_vec())
+    }
+
+    /// true if this is an imported master key rather than a BIP32 seed
+    pub fn is_master_key(&self) -> bool {
+        self.0.len() == MASTER_KEY_SEED_LEN
+    }
+
     /// encrypt seed
     /// encryption algorithm: AES256(Sha256(passphrase), ECB, PKCS padding
     pub fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
@@ -827,13 +1551,7 @@ impl Seed {
         let mut writer = buffer::RefWriteBuffer::new(&mut buffer);
         loop {
             let result = encryptor.encrypt(&mut reader, &mut writer, true)?;
-            encrypted.extend(
-                writer
-                    .take_read_buffer()
-                    .take_remaining()
-                    .iter()
-                    .map(|i| *i),
-            );
+            encrypted.extend(writer.take_read_buffer().take_remaining().iter().copied());
             match result {
                 BufferResult::BufferUnderflow => break,
                 BufferResult::BufferOverflow => {}
@@ -858,13 +1576,7 @@ impl Seed {
             aes::ecb_decryptor(aes::KeySize::KeySize256, &key, blockmodes::PkcsPadding {});
         loop {
             let result = decryptor.decrypt(&mut reader, &mut writer, true)?;
-            decrypted.extend(
-                writer
-                    .take_read_buffer()
-                    .take_remaining()
-                    .iter()
-                    .map(|i| *i),
-            );
+            decrypted.extend(writer.take_read_buffer().take_remaining().iter().copied());
             match result {
                 BufferResult::BufferUnderflow => break,
                 BufferResult::BufferOverflow => {}
@@ -882,10 +1594,10 @@ mod test {
     use std::io::Read;
     use std::path::PathBuf;
 
-    use bitcoin::hashes::hex::FromHex;
     use bitcoin::blockdata::opcodes::all;
     use bitcoin::blockdata::script::Builder;
     use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+    use bitcoin::hashes::hex::FromHex;
     use bitcoin::network::constants::Network;
     use bitcoin::util::bip32::ChildNumber;
     use rand::Rng;
@@ -1046,6 +1758,88 @@ mod test {
             .unwrap();
     }
 
+    #[test]
+    fn deterministic_signing() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let account = Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        master.add_account(account);
+        let script = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .script_pubkey();
+        let spent = TxOut {
+            script_pubkey: script.clone(),
+            value: 5000000000,
+        };
+        let unsigned = Transaction {
+            input: (0..8)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint {
+                        txid: bitcoin::Txid::default(),
+                        vout,
+                    },
+                    sequence: RBF,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                script_pubkey: script,
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let sign = |unlocker: &mut Unlocker| {
+            let mut transaction = unsigned.clone();
+            master
+                .sign(
+                    &mut transaction,
+                    SigHashType::All,
+                    &(|_| Some(spent.clone())),
+                    unlocker,
+                )
+                .unwrap();
+            transaction
+        };
+        let mut other = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        other.set_signing_config(SigningConfig::deterministic());
+        assert_eq!(sign(&mut unlocker), sign(&mut other));
+
+        let low_r = SigningConfig {
+            low_r: true,
+            ..SigningConfig::deterministic()
+        };
+        unlocker.set_signing_config(low_r);
+        let transaction = sign(&mut unlocker);
+        assert_eq!(transaction, sign(&mut unlocker));
+        // DER signature with hash type
+        assert!(transaction.input.iter().all(|i| i.witness[0].len() <= 72));
+        transaction.verify(|_| Some(spent.clone())).unwrap();
+
+        let key = unlocker
+            .unlock(AccountAddressType::P2WPKH, 0, 0, 0, None)
+            .unwrap();
+        let digest = [1u8; 32];
+        let context = unlocker.context();
+        let public = context.schnorr_public(&key);
+        let signature = context.sign_schnorr(&digest, &key).unwrap();
+        assert_eq!(signature, context.sign_schnorr(&digest, &key).unwrap());
+        context
+            .verify_schnorr(&digest, &signature, &public)
+            .unwrap();
+        // fresh auxiliary data for every signature
+        let random = SecpContext::new();
+        let first = random.sign_schnorr(&digest, &key).unwrap();
+        assert_ne!(first, random.sign_schnorr(&digest, &key).unwrap());
+        random.verify_schnorr(&digest, &first, &public).unwrap();
+    }
+
     #[test]
     fn test_shwpkh() {
         let mut master =
@@ -1321,6 +2115,205 @@ mod test {
             .is_err());
     }
 
+    #[test]
+    fn test_single_key() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let mut secret = [0u8; 32];
+        thread_rng().fill(&mut secret);
+        let paper = PrivateKey {
+            compressed: true,
+            network: Network::Bitcoin,
+            key: bitcoin::secp256k1::SecretKey::from_slice(&secret).unwrap(),
+        };
+        let paper_address = Address::p2wpkh(
+            &unlocker.context().public_from_private(&paper),
+            Network::Bitcoin,
+        )
+        .unwrap();
+        let mut account =
+            Account::new_single_key(&mut unlocker, AccountAddressType::P2WPKH, 5, 0, &paper)
+                .unwrap();
+        assert!(account.is_single_key());
+        assert!(account.next_key().is_err());
+        assert_eq!(account.get_key(0).unwrap().address, paper_address);
+        master.add_account(account);
+
+        let input_transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: bitcoin::Txid::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: paper_address.script_pubkey(),
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let txid = input_transaction.txid();
+
+        let mut spending_transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint { txid, vout: 0 },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: paper_address.script_pubkey(),
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+
+        assert_eq!(
+            master
+                .sign(
+                    &mut spending_transaction,
+                    SigHashType::All,
+                    &(|_| Some(input_transaction.output[0].clone())),
+                    &mut unlocker
+                )
+                .unwrap(),
+            1
+        );
+
+        spending_transaction
+            .verify(|point| {
+                if point.txid == txid {
+                    input_transaction.output.get(point.vout as usize).cloned()
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_search_address() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let mut account =
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        let (kix, address) = account.search_address_prefix("q", 10000).unwrap().unwrap();
+        assert!(kix >= 10);
+        assert!(address.to_string().starts_with("bc1qq"));
+        account.do_look_ahead(Some(kix)).unwrap();
+        assert_eq!(account.get_key(kix).unwrap().address, address);
+        assert!(account.search_address(5, |_| false).unwrap().is_none());
+    }
+
+    #[test]
+    fn script_index() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap(),
+        );
+        let script = master
+            .get((0, 1))
+            .unwrap()
+            .get_key(9)
+            .unwrap()
+            .address
+            .script_pubkey();
+        assert_eq!(master.derivation(&script).unwrap().kix, 9);
+        assert_eq!(
+            master.derivation(&script),
+            master
+                .get_scripts()
+                .find(|(s, _)| *s == script)
+                .map(|(_, d)| d)
+                .as_ref()
+        );
+
+        let next = master
+            .get_mut((0, 1))
+            .unwrap()
+            .do_look_ahead(Some(10))
+            .unwrap();
+        assert!(master.derivation(&next[0].1).is_none());
+        master.index_scripts();
+        assert_eq!(master.derivation(&next[0].1).unwrap().kix, next[0].0);
+
+        // a replaced account is indexed again
+        master
+            .add_account(Account::new(&mut unlocker, AccountAddressType::P2PKH, 0, 1, 5).unwrap());
+        assert!(master.derivation(&script).is_none());
+        let script = master
+            .get((0, 1))
+            .unwrap()
+            .get_key(4)
+            .unwrap()
+            .address
+            .script_pubkey();
+        assert_eq!(master.derivation(&script).unwrap().kix, 4);
+
+        // look ahead through the master account indexes the keys it adds
+        master.look_ahead((0, 1), 4).unwrap();
+        assert_eq!(master.get((0, 1)).unwrap().instantiated().len(), 9);
+        let script = master
+            .get((0, 1))
+            .unwrap()
+            .get_key(8)
+            .unwrap()
+            .address
+            .script_pubkey();
+        assert_eq!(master.derivation(&script).unwrap().kix, 8);
+        assert!(master.look_ahead((1, 0), 0).is_err());
+    }
+
+    #[test]
+    fn large_look_ahead() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let mut account =
+            Account::new(&mut unlocker, AccountAddressType::P2SHWPKH, 0, 0, 10).unwrap();
+        let new = account.do_look_ahead(Some(1000)).unwrap();
+        assert_eq!(new.len(), 1000);
+        assert_eq!(account.instantiated().len(), 1010);
+        for (kix, script) in new.iter().step_by(97) {
+            let public = account.compute_base_public_key(*kix).unwrap();
+            let address = Address::p2shwpkh(&public, Network::Bitcoin).unwrap();
+            assert_eq!(*script, address.script_pubkey());
+            assert_eq!(account.get_key(*kix).unwrap().address, address);
+        }
+    }
+
+    #[test]
+    fn key_cache() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let cache = Arc::new(KeyCache::new(100));
+        let mut account = Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10)
+            .unwrap()
+            .with_key_cache(cache.clone());
+        assert!(account.search_address(10, |_| false).unwrap().is_none());
+        assert_eq!(cache.len(), 10);
+        // the look ahead reaches the keys searched without deriving them again
+        account.do_look_ahead(Some(10)).unwrap();
+        assert_eq!(account.instantiated().len(), 20);
+        assert_eq!(account.key_cache().hits(), 10);
+        let public = account.compute_base_public_key(15).unwrap();
+        assert_eq!(account.get_key(15).unwrap().public, public);
+        assert_eq!(
+            account.get_key(15).unwrap().address,
+            Address::p2wpkh(&public, Network::Bitcoin).unwrap()
+        );
+    }
+
     #[test]
     fn crosscheck_with_hardware_wallet() {
         let words = "announce damage viable ticket engage curious yellow ten clock finish burden orient faculty rigid smile host offer affair suffer slogan mercy another switch park";
@@ -1367,7 +2360,7 @@ mod test {
                     .to_string()
             );
             for d in test["derived"].as_array().unwrap() {
-                let mut key = master_private.clone();
+                let mut key = master_private;
                 for l in d["locator"].as_array().unwrap() {
                     let sequence = l["sequence"].as_u64().unwrap();
                     let private = l["private"].as_bool().unwrap();
@@ -0,0 +1,395 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Async API
+//!
+//! Backends block on the network. AsyncWallet runs sync, scan and broadcast on a Runtime and
+//! returns futures, so GUIs and servers await them instead of wrapping every call.
+//!
+//! The library depends on no executor, tokio is not a dependency. PoolRuntime runs tasks on
+//! a fixed number of threads, an AsyncWallet runs its tasks on a thread of its own unless
+//! given an other runtime. With tokio, implement Runtime by handing the task to
+//! tokio::task::spawn_blocking.
+//!
+//! A task that panics resolves to Error::Task, the wallet stays usable.
+//!
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use bitcoin::{Transaction, Txid};
+
+use account::MasterAccount;
+use backend::{BitcoindBackend, ChainBackend, ChainSync};
+use coins::Coins;
+use error::Error;
+use fee::JsonRpc;
+
+/// Runs blocking tasks off the async executor
+pub trait Runtime: Send + Sync {
+    /// run the task to completion, e.g. on a thread pool for blocking calls
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>);
+}
+
+/// Runs each task on a new thread
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadRuntime;
+
+impl Runtime for ThreadRuntime {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        thread::spawn(task);
+    }
+}
+
+/// Runs tasks on a fixed number of threads
+/// The threads end once the runtime is dropped and their tasks are done.
+pub struct PoolRuntime {
+    sender: Mutex<Sender<Box<dyn FnOnce() + Send>>>,
+}
+
+impl PoolRuntime {
+    /// a runtime with this number of threads, at least one
+    pub fn new(threads: usize) -> PoolRuntime {
+        let (sender, receiver) = channel::<Box<dyn FnOnce() + Send>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let task = match lock(&receiver).recv() {
+                    Ok(task) => task,
+                    Err(_) => return,
+                };
+                // a panicking task does not end the thread, its Task is resolved
+                catch_unwind(AssertUnwindSafe(task)).ok();
+            });
+        }
+        PoolRuntime {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl Runtime for PoolRuntime {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        // a task not sent is dropped, which resolves its Task
+        lock(&self.sender).send(task).ok();
+    }
+}
+
+type Slot<T> = Arc<Mutex<(Option<Result<T, Error>>, Option<Waker>)>>;
+
+/// resolves a task when dropped, with an error if the task did not complete
+struct Completion<T> {
+    shared: Slot<T>,
+    result: Option<Result<T, Error>>,
+}
+
+impl<T> Completion<T> {
+    fn resolve(mut self, result: Result<T, Error>) {
+        self.result = Some(result);
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let result = self
+            .result
+            .take()
+            .unwrap_or(Err(Error::Task("task panicked or was dropped")));
+        let mut shared = lock(&self.shared);
+        shared.0 = Some(result);
+        if let Some(waker) = shared.1.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The result of a task run on a Runtime
+pub struct Task<T> {
+    shared: Slot<T>,
+}
+
+impl<T: Send + 'static> Task<T> {
+    /// run f on the runtime, the task resolves to Error::Task if f panics
+    pub fn spawn<F>(runtime: &dyn Runtime, f: F) -> Task<T>
+    where
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new((None, None::<Waker>)));
+        let completion = Completion {
+            shared: shared.clone(),
+            result: None,
+        };
+        runtime.spawn_blocking(Box::new(move || completion.resolve(f())));
+        Task { shared }
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<T, Error>> {
+        let mut shared = lock(&self.shared);
+        match shared.0.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.1 = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// What a wallet needs to follow the chain
+pub struct WalletState<B> {
+    pub backend: B,
+    pub sync: ChainSync,
+    pub master: MasterAccount,
+    pub coins: Coins,
+}
+
+/// A wallet whose blocking calls return futures
+/// Tasks run one at a time as they share the state, clones share it too.
+pub struct AsyncWallet<B> {
+    state: Arc<Mutex<WalletState<B>>>,
+    runtime: Arc<dyn Runtime>,
+}
+
+impl<B> Clone for AsyncWallet<B> {
+    fn clone(&self) -> AsyncWallet<B> {
+        AsyncWallet {
+            state: self.state.clone(),
+            runtime: self.runtime.clone(),
+        }
+    }
+}
+
+impl<B: ChainBackend + Send + 'static> AsyncWallet<B> {
+    /// run tasks on a thread of the wallet
+    pub fn new(state: WalletState<B>) -> AsyncWallet<B> {
+        AsyncWallet {
+            state: Arc::new(Mutex::new(state)),
+            runtime: Arc::new(PoolRuntime::new(1)),
+        }
+    }
+
+    /// run tasks on the runtime
+    pub fn runtime<R: Runtime + 'static>(mut self, runtime: R) -> AsyncWallet<B> {
+        self.runtime = Arc::new(runtime);
+        self
+    }
+
+    /// the state, blocks while a task runs
+    pub fn state(&self) -> MutexGuard<'_, WalletState<B>> {
+        lock(&self.state)
+    }
+
+    /// run f with the state on the runtime
+    pub fn run<T, F>(&self, f: F) -> Task<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut WalletState<B>) -> Result<T, Error> + Send + 'static,
+    {
+        let state = self.state.clone();
+        Task::spawn(self.runtime.as_ref(), move || f(&mut lock(&state)))
+    }
+
+    /// ChainSync::sync, returns the number of blocks processed
+    pub fn sync(&self) -> Task<usize> {
+        self.run(|state| {
+            let WalletState {
+                ref backend,
+                ref mut sync,
+                ref mut master,
+                ref mut coins,
+            } = *state;
+            sync.sync(backend, master, coins)
+        })
+    }
+
+    /// ChainSync::rescan from a height, returns the number of blocks processed
+    pub fn rescan(&self, from_height: u32) -> Task<usize> {
+        self.run(move |state| {
+            let WalletState {
+                ref backend,
+                ref mut sync,
+                ref mut master,
+                ref mut coins,
+            } = *state;
+            sync.rescan(from_height, backend, master, coins, None)
+        })
+    }
+
+    /// relay a transaction through the backend
+    pub fn broadcast(&self, transaction: Transaction) -> Task<Txid> {
+        self.run(move |state| state.backend.broadcast(&transaction))
+    }
+}
+
+impl<R: JsonRpc + Send + 'static> AsyncWallet<BitcoindBackend<R>> {
+    /// BitcoindBackend::scan_utxos, returns the height scanned at
+    pub fn scan(&self) -> Task<u32> {
+        self.run(|state| {
+            let WalletState {
+                ref backend,
+                ref mut master,
+                ref mut coins,
+                ..
+            } = *state;
+            backend.scan_utxos(master, coins)
+        })
+    }
+}
+
+// a task that panicked does not keep others from the state
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::bip158::BlockFilter;
+    use bitcoin::{Block, BlockHash, BlockHeader, Network};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    use backend::TxStatus;
+    use fee::FeeRate;
+    use fixtures::master_account;
+
+    use super::*;
+
+    struct Genesis(Block);
+
+    impl ChainBackend for Genesis {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(0)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(Some(self.0.header).filter(|_| height == 0))
+        }
+
+        fn block(&self, _: &BlockHash) -> Result<Block, Error> {
+            Ok(self.0.clone())
+        }
+
+        fn filter(&self, _: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            Ok(None)
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Err(Error::Backend("no fee rates"))
+        }
+    }
+
+    /// counts tasks and runs them on threads
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Runtime for Counting {
+        fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ThreadRuntime.spawn_blocking(task)
+        }
+    }
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// a minimal executor
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn async_wallet() {
+        let (master, _) = master_account(Network::Testnet);
+        let genesis = genesis_block(Network::Testnet);
+        let coinbase = genesis.txdata[0].clone();
+        let tasks = Arc::new(AtomicUsize::new(0));
+        let wallet = AsyncWallet::new(WalletState {
+            backend: Genesis(genesis.clone()),
+            sync: ChainSync::new(0),
+            master,
+            coins: Coins::new(),
+        })
+        .runtime(Counting(tasks.clone()));
+
+        assert_eq!(block_on(wallet.sync()).unwrap(), 1);
+        assert_eq!(block_on(wallet.rescan(0)).unwrap(), 1);
+        assert_eq!(wallet.state().sync.tip(), Some((0, genesis.block_hash())));
+        let other = wallet.clone();
+        assert_eq!(
+            block_on(other.broadcast(coinbase.clone())).unwrap(),
+            coinbase.txid()
+        );
+        assert_eq!(
+            block_on(wallet.run(|state| Ok(state.coins.confirmed_balance()))).unwrap(),
+            0
+        );
+        assert_eq!(tasks.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn panic() {
+        let genesis = genesis_block(Network::Testnet);
+        let state = || WalletState {
+            backend: Genesis(genesis.clone()),
+            sync: ChainSync::new(0),
+            master: master_account(Network::Testnet).0,
+            coins: Coins::new(),
+        };
+        let pooled = AsyncWallet::new(state());
+        let threaded = AsyncWallet::new(state()).runtime(ThreadRuntime);
+        for wallet in [pooled, threaded].iter() {
+            let panicked = wallet.run(|_| -> Result<(), Error> { panic!("backend failed") });
+            match block_on(panicked) {
+                Err(Error::Task(_)) => {}
+                _ => panic!("panic not reported"),
+            }
+            // the wallet and its runtime stay usable
+            assert_eq!(block_on(wallet.sync()).unwrap(), 1);
+        }
+    }
+}
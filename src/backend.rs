@@ -0,0 +1,1360 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Chain backends
+//!
+//! A ChainBackend is the view of the wallet on the chain: headers and blocks of the best chain,
+//! BIP158 filters, the status of transactions, broadcast and fee rates. Electrum, Esplora,
+//! bitcoind or P2P backends implement it, so they are interchangeable and tests use a mock.
+//!
+//! ChainSync follows the best chain of a backend and processes its blocks into coins. Blocks
+//! whose filter matches no script of the wallet are not fetched, blocks that left the best
+//! chain are unwound. A rescan unwinds and processes blocks again from a height, e.g. after
+//! importing keys older than the wallet.
+//!
+//! BitcoindBackend speaks the RPC interface of Bitcoin Core, e.g. through HttpJsonRpc with
+//! cookie or user and password authentication, for users who run their own node. RpcConfig
+//! adds TLS with client certificates, private roots and a server name other than the host, for
+//! nodes behind a TLS terminating proxy. The handshake is left to a TlsConnector of the user.
+//!
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHeader, OutPoint, Script, Transaction, Txid};
+
+use account::MasterAccount;
+use coins::Coins;
+use error::Error;
+use fee::{
+    BitcoindFeeEstimator, FeeEstimator, FeeHistogram, FeeRate, JsonRpc, RpcError,
+    RPC_METHOD_NOT_FOUND,
+};
+use history::History;
+use json::{parse_json, Json};
+use message::base64_encode;
+use proxy::{host_port, Connector};
+
+/// Status of a transaction as seen by a backend
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxStatus {
+    /// not known to the backend
+    Unknown,
+    /// in the mempool of the backend
+    Unconfirmed,
+    /// in a block of the best chain
+    Confirmed { height: u32, block_hash: BlockHash },
+}
+
+/// Access to the chain, all sync and broadcast goes through a backend
+pub trait ChainBackend {
+    /// height of the best chain
+    fn tip(&self) -> Result<u32, Error>;
+    /// header of the best chain at height, None above the tip
+    fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error>;
+    /// block with the hash
+    fn block(&self, hash: &BlockHash) -> Result<Block, Error>;
+    /// BIP158 basic filter of the block, None if the backend does not serve filters
+    fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error>;
+    /// BIP157 header of the filter of the block, None if the backend does not serve them
+    fn filter_header(&self, _hash: &BlockHash) -> Result<Option<FilterHeader>, Error> {
+        Ok(None)
+    }
+    /// status of a transaction
+    fn tx_status(&self, txid: &Txid) -> Result<TxStatus, Error>;
+    /// transaction in the mempool spending an output, None if there is none or the backend
+    /// does not tell
+    fn spender(&self, _point: &OutPoint) -> Result<Option<Txid>, Error> {
+        Ok(None)
+    }
+    /// reject reasons of the mempool for transactions given parents first, None for those it
+    /// would accept, None if the backend can not tell
+    fn mempool_accept(
+        &self,
+        _transactions: &[Transaction],
+    ) -> Result<Option<Vec<Option<String>>>, Error> {
+        Ok(None)
+    }
+    /// relay a transaction
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error>;
+    /// relay parents with their child, as a package if the backend supports it,
+    /// otherwise one by one, parents first
+    fn broadcast_package(&self, transactions: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        transactions.iter().map(|t| self.broadcast(t)).collect()
+    }
+    /// fee rate to confirm within target blocks
+    fn feerate(&self, target: u16) -> Result<FeeRate, Error>;
+    /// the mempool by fee rate, None if the backend does not tell
+    fn fee_histogram(&self) -> Result<Option<FeeHistogram>, Error> {
+        Ok(None)
+    }
+}
+
+impl<B: ChainBackend> FeeEstimator for B {
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+        self.feerate(target)
+    }
+}
+
+/// Follows the best chain of a backend
+#[derive(Clone, Debug, Default)]
+pub struct ChainSync {
+    start: u32,
+    /// hashes of processed blocks from start
+    hashes: Vec<BlockHash>,
+}
+
+impl ChainSync {
+    /// process blocks from height start, e.g. the height at the birth of the wallet
+    pub fn new(start: u32) -> ChainSync {
+        ChainSync {
+            start,
+            hashes: Vec::new(),
+        }
+    }
+
+    /// height and hash of the last processed block
+    pub fn tip(&self) -> Option<(u32, BlockHash)> {
+        self.hashes
+            .last()
+            .map(|hash| (self.start + self.hashes.len() as u32 - 1, *hash))
+    }
+
+    /// height of a processed block
+    pub fn height(&self, hash: &BlockHash) -> Option<u32> {
+        self.hashes
+            .iter()
+            .rposition(|h| h == hash)
+            .map(|p| self.start + p as u32)
+    }
+
+    /// unwind blocks that left the best chain and process new blocks into coins
+    /// returns the number of blocks processed
+    pub fn sync<B: ChainBackend>(
+        &mut self,
+        backend: &B,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+    ) -> Result<usize, Error> {
+        while let Some((height, hash)) = self.tip() {
+            match backend.header(height)? {
+                Some(ref header) if header.block_hash() == hash => break,
+                _ => {
+                    coins.unwind_tip(&hash);
+                    self.hashes.pop();
+                }
+            }
+        }
+        self.replay(backend, master, coins, None)
+    }
+
+    /// forget blocks from from_height and process them again, e.g. after importing keys used
+    /// before the wallet was born. History is rebuilt too if given.
+    /// returns the number of blocks processed
+    pub fn rescan<B: ChainBackend>(
+        &mut self,
+        from_height: u32,
+        backend: &B,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+        mut history: Option<&mut History>,
+    ) -> Result<usize, Error> {
+        while let Some((height, hash)) = self.tip() {
+            if height < from_height {
+                break;
+            }
+            coins.unwind_tip(&hash);
+            if let Some(ref mut history) = history {
+                history.unwind_tip(&hash);
+            }
+            self.hashes.pop();
+        }
+        if self.hashes.is_empty() {
+            self.start = from_height;
+        }
+        self.replay(backend, master, coins, history)
+    }
+
+    // process blocks of the best chain after the processed ones
+    fn replay<B: ChainBackend>(
+        &mut self,
+        backend: &B,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+        mut history: Option<&mut History>,
+    ) -> Result<usize, Error> {
+        let tip = backend.tip()?;
+        let mut processed = 0;
+        for height in self.start + self.hashes.len() as u32..=tip {
+            let header = backend
+                .header(height)?
+                .ok_or(Error::Backend("header of the best chain missing"))?;
+            if let Some(last) = self.hashes.last() {
+                if *last != header.prev_blockhash {
+                    return Err(Error::Backend("best chain changed during sync"));
+                }
+            }
+            let hash = header.block_hash();
+            if Self::relevant(backend, master, &hash)? {
+                let block = backend.block(&hash)?;
+                if block.block_hash() != hash || !block.check_merkle_root() {
+                    return Err(Error::Backend("block does not match its header"));
+                }
+                if let Some(ref mut history) = history {
+                    history.process(master, coins, &block, height);
+                }
+                coins.process(master, &block);
+                processed += 1;
+            }
+            self.hashes.push(hash);
+        }
+        coins.update_tip(tip, |hash| self.height(hash));
+        Ok(processed)
+    }
+
+    /// process a block pushed by a node if it extends the processed blocks
+    /// false if it does not, sync then catches up with the backend
+    pub fn connect(
+        &mut self,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+        block: &Block,
+    ) -> bool {
+        match self.tip() {
+            Some((_, hash)) if hash == block.header.prev_blockhash => {}
+            _ => return false,
+        }
+        if !block.check_merkle_root() {
+            return false;
+        }
+        coins.process(master, block);
+        self.hashes.push(block.block_hash());
+        let height = self.start + self.hashes.len() as u32 - 1;
+        coins.update_tip(height, |hash| self.height(hash));
+        true
+    }
+
+    /// false if the filter of the block rules out scripts of the wallet
+    fn relevant<B: ChainBackend>(
+        backend: &B,
+        master: &MasterAccount,
+        hash: &BlockHash,
+    ) -> Result<bool, Error> {
+        let filter = match backend.filter(hash)? {
+            Some(filter) => filter,
+            None => return Ok(true),
+        };
+        let scripts = wallet_scripts(master);
+        filter
+            .match_any(hash, &mut scripts.iter().map(|s| s.as_bytes()))
+            .map_err(|_| Error::Backend("invalid block filter"))
+    }
+}
+
+/// evict pending transactions the backend found replaced, e.g. incoming payments double spent
+/// A pending transaction unknown to the backend is replaced if the backend knows an other
+/// transaction spending one of its inputs. Returns replaced transactions with their replacement.
+pub fn detect_replacements<B: ChainBackend>(
+    backend: &B,
+    coins: &mut Coins,
+) -> Result<Vec<(Txid, Txid)>, Error> {
+    let mut replaced = Vec::new();
+    let pending = coins.pending().values().cloned().collect::<Vec<_>>();
+    for transaction in pending {
+        let txid = transaction.txid();
+        // evicted with a replaced ancestor
+        if !coins.pending().contains_key(&txid) || backend.tx_status(&txid)? != TxStatus::Unknown {
+            continue;
+        }
+        for input in transaction.input.iter() {
+            match backend.spender(&input.previous_output)? {
+                Some(by) if by != txid => {
+                    coins.replaced(&txid, by);
+                    replaced.push((txid, by));
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(replaced)
+}
+
+/// scripts a block filter is matched against
+/// Spends of own coins match through the scripts they spend.
+pub(crate) fn wallet_scripts(master: &MasterAccount) -> Vec<Script> {
+    master
+        .get_scripts()
+        .map(|(s, _)| s)
+        .chain(master.watched_scripts().iter().cloned())
+        .collect()
+}
+
+/// Credentials of the RPC interface of bitcoind
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RpcAuth {
+    /// the .cookie file bitcoind writes to its data directory at start
+    Cookie(PathBuf),
+    /// rpcuser and rpcpassword or credentials of rpcauth
+    UserPass(String, String),
+}
+
+/// Settings of TLS to a node behind a TLS terminating proxy
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TlsConfig {
+    /// PEM files of a client certificate and its key, for servers requiring mutual TLS
+    pub client_certificate: Option<(PathBuf, PathBuf)>,
+    /// PEM files of roots trusted instead of the system roots, e.g. of a private CA
+    pub ca_roots: Vec<PathBuf>,
+    /// name sent as SNI and checked against the certificate of the server, the host if None
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// authenticate with a client certificate
+    pub fn client_certificate<P: Into<PathBuf>>(mut self, certificate: P, key: P) -> TlsConfig {
+        self.client_certificate = Some((certificate.into(), key.into()));
+        self
+    }
+
+    /// trust a root, system roots are no longer trusted
+    pub fn ca_root<P: Into<PathBuf>>(mut self, root: P) -> TlsConfig {
+        self.ca_roots.push(root.into());
+        self
+    }
+
+    /// name of the server other than its host, e.g. of a node reached by its IP address
+    pub fn server_name(mut self, name: &str) -> TlsConfig {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
+    /// fail early if a file configured is missing
+    pub fn check(&self) -> Result<(), Error> {
+        let files = self
+            .client_certificate
+            .iter()
+            .flat_map(|(certificate, key)| vec![certificate, key])
+            .chain(self.ca_roots.iter());
+        for file in files {
+            if !file.is_file() {
+                return Err(Error::Backend("TLS file missing"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A connection read and written by the HTTP transport
+pub trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+/// A TLS client
+/// This library does not implement TLS, wrap the connection with a TLS client of your choice.
+pub trait TlsConnector {
+    /// handshake over the connection, checking the server against server_name
+    fn connect(
+        &self,
+        stream: TcpStream,
+        server_name: &str,
+        config: &TlsConfig,
+    ) -> Result<Box<dyn Stream>, Error>;
+}
+
+/// Configuration of the RPC interface of a node
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RpcConfig {
+    /// host:port of the server
+    pub address: String,
+    pub auth: RpcAuth,
+    /// time to wait for a response
+    pub timeout: Duration,
+    pub connector: Connector,
+    /// TLS settings, plain HTTP if None
+    pub tls: Option<TlsConfig>,
+}
+
+impl RpcConfig {
+    /// plain HTTP to a server at host:port
+    pub fn new(address: &str, auth: RpcAuth) -> RpcConfig {
+        RpcConfig {
+            address: address.to_string(),
+            auth,
+            timeout: Duration::from_secs(60),
+            connector: Connector::direct(),
+            tls: None,
+        }
+    }
+
+    /// connect through a proxy, e.g. to a node at an .onion address
+    pub fn connector(mut self, connector: Connector) -> RpcConfig {
+        self.connector = connector;
+        self
+    }
+
+    /// time to wait for a response, scans of the UTXO set take minutes
+    pub fn timeout(mut self, timeout: Duration) -> RpcConfig {
+        self.timeout = timeout;
+        self
+    }
+
+    /// speak TLS with the settings
+    pub fn tls(mut self, tls: TlsConfig) -> RpcConfig {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+/// JSON-RPC over HTTP, e.g. to bitcoind at 127.0.0.1:8332
+pub struct HttpJsonRpc {
+    config: RpcConfig,
+    tls: Option<Box<dyn TlsConnector>>,
+}
+
+impl HttpJsonRpc {
+    /// a server at host:port
+    pub fn new(address: &str, auth: RpcAuth) -> HttpJsonRpc {
+        HttpJsonRpc::with_config(RpcConfig::new(address, auth))
+    }
+
+    /// a server as configured, a configuration with TLS needs a tls_connector
+    pub fn with_config(config: RpcConfig) -> HttpJsonRpc {
+        HttpJsonRpc { config, tls: None }
+    }
+
+    /// connect through a proxy, e.g. to a node at an .onion address
+    pub fn connector(mut self, connector: Connector) -> HttpJsonRpc {
+        self.config.connector = connector;
+        self
+    }
+
+    /// time to wait for a response, scans of the UTXO set take minutes
+    pub fn timeout(mut self, timeout: Duration) -> HttpJsonRpc {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// the TLS client of connections configured with TLS
+    pub fn tls_connector<T: TlsConnector + 'static>(mut self, tls: T) -> HttpJsonRpc {
+        self.tls = Some(Box::new(tls));
+        self
+    }
+
+    /// user:password, the cookie is read at each call as bitcoind replaces it at restart
+    fn credentials(&self) -> Result<String, Error> {
+        match self.config.auth {
+            RpcAuth::Cookie(ref path) => Ok(fs::read_to_string(path)?.trim().to_string()),
+            RpcAuth::UserPass(ref user, ref password) => Ok(format!("{}:{}", user, password)),
+        }
+    }
+
+    /// the Host header, names the server of a TLS connection as its handshake does
+    fn host(&self) -> Result<String, Error> {
+        match self.config.tls {
+            Some(TlsConfig {
+                server_name: Some(ref name),
+                ..
+            }) => {
+                let (_, port) = host_port(self.config.address.as_str(), 8332)?;
+                Ok(format!("{}:{}", name, port))
+            }
+            _ => Ok(self.config.address.clone()),
+        }
+    }
+
+    fn connect(&self) -> Result<Box<dyn Stream>, Error> {
+        if self.config.tls.is_some() && self.tls.is_none() {
+            return Err(Error::Unsupported("TLS needs a TLS connector"));
+        }
+        let (host, port) = host_port(self.config.address.as_str(), 8332)?;
+        let stream = self.config.connector.connect(host.as_str(), port)?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+        match (&self.config.tls, &self.tls) {
+            (Some(config), Some(tls)) => {
+                let server_name = config.server_name.as_ref().unwrap_or(&host);
+                tls.connect(stream, server_name.as_str(), config)
+            }
+            _ => Ok(Box::new(stream)),
+        }
+    }
+}
+
+impl JsonRpc for HttpJsonRpc {
+    fn call(&self, method: &str, params: &str) -> Result<String, Error> {
+        let body = format!(
+            r#"{{"jsonrpc":"1.0","id":"rust-wallet","method":"{}","params":{}}}"#,
+            method, params
+        );
+        let mut stream = self.connect()?;
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.host()?,
+            base64_encode(self.credentials()?.as_bytes()),
+            body.len(),
+            body
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response =
+            String::from_utf8(response).map_err(|_| Error::Backend("invalid HTTP response"))?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or(Error::Backend("invalid HTTP response"))?;
+        if head.split(' ').nth(1) == Some("401") {
+            return Err(Error::Backend("RPC authentication failed"));
+        }
+        // errors of calls come with an error status and a JSON body
+        let mut reply = match parse_json(body) {
+            Ok(Json::Object(reply)) => reply,
+            _ => return Err(Error::Backend("invalid RPC response")),
+        };
+        match reply.remove("error") {
+            None | Some(Json::Null) => {}
+            Some(Json::Object(error)) => {
+                return match (error.get("code"), error.get("message")) {
+                    (Some(Json::Number(code)), Some(Json::String(message))) => {
+                        Err(Error::Rpc(RpcError {
+                            code: *code as i64,
+                            message: message.clone(),
+                        }))
+                    }
+                    _ => Err(Error::Backend("invalid RPC response")),
+                };
+            }
+            Some(_) => return Err(Error::Backend("invalid RPC response")),
+        }
+        reply
+            .remove("result")
+            .map(|result| result.to_string())
+            .ok_or(Error::Backend("invalid RPC response"))
+    }
+}
+
+/// A bitcoind node reached through its JSON-RPC interface
+/// Filters need -blockfilterindex, the status of transactions not in the wallet of the node
+/// needs -txindex.
+pub struct BitcoindBackend<R: JsonRpc> {
+    rpc: R,
+}
+
+impl<R: JsonRpc> BitcoindBackend<R> {
+    pub fn new(rpc: R) -> BitcoindBackend<R> {
+        BitcoindBackend { rpc }
+    }
+
+    fn call(&self, method: &str, params: &str) -> Result<Json, Error> {
+        parse_json(self.rpc.call(method, params)?.as_str())
+            .map_err(|_| Error::Backend("invalid JSON response"))
+    }
+
+    /// a consensus encoded result given as hex
+    fn call_hex<T: bitcoin::consensus::Decodable>(
+        &self,
+        method: &str,
+        params: &str,
+    ) -> Result<T, Error> {
+        match self.call(method, params)? {
+            Json::String(hex) => Vec::<u8>::from_hex(hex.as_str())
+                .ok()
+                .and_then(|data| deserialize(data.as_slice()).ok())
+                .ok_or(Error::Backend("invalid hex response")),
+            _ => Err(Error::Backend("unexpected response")),
+        }
+    }
+
+    /// a field of the getblockfilter result, None if the node does not index filters
+    fn block_filter(&self, hash: &BlockHash, field: &str) -> Result<Option<String>, Error> {
+        let mut result = match self.call("getblockfilter", format!("[\"{}\"]", hash).as_str()) {
+            Ok(Json::Object(result)) => result,
+            _ => return Ok(None),
+        };
+        match result.remove(field) {
+            Some(Json::String(hex)) => Ok(Some(hex)),
+            _ => Err(Error::Backend("unexpected response")),
+        }
+    }
+
+    /// find the unspent coins of the wallet with scantxoutset and process the blocks that
+    /// confirmed them. Spent coins are not found, ChainSync continues after the returned height
+    /// of the scan.
+    pub fn scan_utxos(&self, master: &mut MasterAccount, coins: &mut Coins) -> Result<u32, Error> {
+        let descriptors = wallet_scripts(master)
+            .iter()
+            .map(|s| format!("\"raw({:x})\"", s))
+            .collect::<Vec<_>>();
+        let mut result = match self.call(
+            "scantxoutset",
+            format!("[\"start\",[{}]]", descriptors.join(",")).as_str(),
+        )? {
+            Json::Object(result) => result,
+            _ => return Err(Error::Backend("unexpected response")),
+        };
+        let height = match result.get("height") {
+            Some(Json::Number(height)) => *height as u32,
+            _ => return Err(Error::Backend("scan did not complete")),
+        };
+        let mut heights = match result.remove("unspents") {
+            Some(Json::Array(unspents)) => unspents
+                .iter()
+                .map(|unspent| match unspent {
+                    Json::Object(unspent) => match unspent.get("height") {
+                        Some(Json::Number(height)) => Ok(*height as u32),
+                        _ => Err(Error::Backend("unexpected response")),
+                    },
+                    _ => Err(Error::Backend("unexpected response")),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(Error::Backend("unexpected response")),
+        };
+        heights.sort_unstable();
+        heights.dedup();
+        let mut processed = HashMap::new();
+        for block_height in heights {
+            let header = self
+                .header(block_height)?
+                .ok_or(Error::Backend("header of the best chain missing"))?;
+            let block = self.block(&header.block_hash())?;
+            coins.process(master, &block);
+            processed.insert(block.block_hash(), block_height);
+        }
+        coins.update_tip(height, |hash| processed.get(hash).copied());
+        Ok(height)
+    }
+
+    /// check if the node would accept the transactions, without relaying them
+    /// returns the reject reason of each transaction, None if it would be accepted
+    pub fn test_mempool_accept(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Option<String>>, Error> {
+        let hex = transactions
+            .iter()
+            .map(|t| format!("\"{}\"", serialize_hex(t)))
+            .collect::<Vec<_>>();
+        let results = match self.call(
+            "testmempoolaccept",
+            format!("[[{}]]", hex.join(",")).as_str(),
+        )? {
+            Json::Array(results) if results.len() == transactions.len() => results,
+            _ => return Err(Error::Backend("unexpected response")),
+        };
+        Ok(results
+            .iter()
+            .map(|result| {
+                let reason = |key: &str| match result {
+                    Json::Object(result) => match result.get(key) {
+                        Some(Json::String(reason)) => Some(reason.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match result {
+                    Json::Object(r) if r.get("allowed") == Some(&Json::Bool(true)) => None,
+                    // a failed package leaves transactions untested
+                    _ => Some(
+                        reason("reject-reason")
+                            .or_else(|| reason("package-error"))
+                            .unwrap_or_else(|| "not tested".to_string()),
+                    ),
+                }
+            })
+            .collect())
+    }
+}
+
+impl<R: JsonRpc> ChainBackend for BitcoindBackend<R> {
+    fn tip(&self) -> Result<u32, Error> {
+        match self.call("getblockcount", "[]")? {
+            Json::Number(height) if height >= 0.0 => Ok(height as u32),
+            _ => Err(Error::Backend("unexpected response")),
+        }
+    }
+
+    fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        if height > self.tip()? {
+            return Ok(None);
+        }
+        let hash = match self.call("getblockhash", format!("[{}]", height).as_str())? {
+            Json::String(hash) => hash,
+            _ => return Err(Error::Backend("unexpected response")),
+        };
+        self.call_hex("getblockheader", format!("[\"{}\",false]", hash).as_str())
+            .map(Some)
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.call_hex("getblock", format!("[\"{}\",0]", hash).as_str())
+    }
+
+    fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+        match self.block_filter(hash, "filter")? {
+            Some(hex) => Vec::<u8>::from_hex(hex.as_str())
+                .map(|content| Some(BlockFilter::new(content.as_slice())))
+                .map_err(|_| Error::Backend("invalid hex response")),
+            None => Ok(None),
+        }
+    }
+
+    fn filter_header(&self, hash: &BlockHash) -> Result<Option<FilterHeader>, Error> {
+        match self.block_filter(hash, "header")? {
+            Some(hex) => FilterHeader::from_hex(hex.as_str())
+                .map(Some)
+                .map_err(|_| Error::Backend("invalid hex response")),
+            None => Ok(None),
+        }
+    }
+
+    fn tx_status(&self, txid: &Txid) -> Result<TxStatus, Error> {
+        let result = match self.call("getrawtransaction", format!("[\"{}\",true]", txid).as_str()) {
+            Ok(Json::Object(result)) => result,
+            _ => return Ok(TxStatus::Unknown),
+        };
+        match (result.get("blockhash"), result.get("confirmations")) {
+            (Some(Json::String(hash)), Some(Json::Number(confirmations)))
+                if *confirmations >= 1.0 =>
+            {
+                let block_hash = BlockHash::from_hex(hash.as_str())
+                    .map_err(|_| Error::Backend("invalid block hash"))?;
+                let height = (self.tip()? + 1)
+                    .checked_sub(*confirmations as u32)
+                    .ok_or(Error::Backend("confirmations above the tip"))?;
+                Ok(TxStatus::Confirmed { height, block_hash })
+            }
+            // in a block of a fork
+            (Some(_), _) => Ok(TxStatus::Unknown),
+            _ => Ok(TxStatus::Unconfirmed),
+        }
+    }
+
+    /// gettxspendingprevout, bitcoind from version 24 on
+    fn spender(&self, point: &OutPoint) -> Result<Option<Txid>, Error> {
+        let params = format!(r#"[[{{"txid":"{}","vout":{}}}]]"#, point.txid, point.vout);
+        match self.call("gettxspendingprevout", params.as_str())? {
+            Json::Array(ref results) => match results.first() {
+                Some(Json::Object(result)) => match result.get("spendingtxid") {
+                    Some(Json::String(txid)) => Txid::from_hex(txid.as_str())
+                        .map(Some)
+                        .map_err(|_| Error::Backend("invalid txid")),
+                    _ => Ok(None),
+                },
+                _ => Err(Error::Backend("unexpected response")),
+            },
+            _ => Err(Error::Backend("unexpected response")),
+        }
+    }
+
+    fn mempool_accept(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Option<Vec<Option<String>>>, Error> {
+        self.test_mempool_accept(transactions).map(Some)
+    }
+
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+        self.rpc.call(
+            "sendrawtransaction",
+            format!("[\"{}\"]", serialize_hex(transaction)).as_str(),
+        )?;
+        Ok(transaction.txid())
+    }
+
+    /// submitpackage if the node knows it, one by one otherwise
+    fn broadcast_package(&self, transactions: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        let hex = transactions
+            .iter()
+            .map(|t| format!("\"{}\"", serialize_hex(t)))
+            .collect::<Vec<_>>();
+        match self.call("submitpackage", format!("[[{}]]", hex.join(",")).as_str()) {
+            Ok(Json::Object(result)) => {
+                // bitcoind before version 28 does not report a package message
+                match result.get("package_msg") {
+                    None => {}
+                    Some(Json::String(message)) if message == "success" => {}
+                    Some(_) => return Err(Error::Broadcast("package rejected")),
+                }
+                Ok(transactions.iter().map(|t| t.txid()).collect())
+            }
+            Ok(_) => Err(Error::Backend("unexpected response")),
+            Err(Error::Rpc(ref error)) if error.code == RPC_METHOD_NOT_FOUND => {
+                transactions.iter().map(|t| self.broadcast(t)).collect()
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn feerate(&self, target: u16) -> Result<FeeRate, Error> {
+        BitcoindFeeEstimator::new(&self.rpc).estimate(target)
+    }
+
+    fn fee_histogram(&self) -> Result<Option<FeeHistogram>, Error> {
+        let mempool = match self.call("getrawmempool", "[true]")? {
+            Json::Object(mempool) => mempool,
+            _ => return Err(Error::Backend("unexpected response")),
+        };
+        let mut transactions = Vec::new();
+        for entry in mempool.values() {
+            let (vsize, fee) = match entry {
+                Json::Object(entry) => match (entry.get("vsize"), entry.get("fees")) {
+                    (Some(Json::Number(vsize)), Some(Json::Object(fees))) => {
+                        match fees.get("base") {
+                            Some(Json::Number(base)) => (*vsize, *base),
+                            _ => return Err(Error::Backend("unexpected response")),
+                        }
+                    }
+                    _ => return Err(Error::Backend("unexpected response")),
+                },
+                _ => return Err(Error::Backend("unexpected response")),
+            };
+            transactions.push(((fee * 1e8).round() as u64, vsize as u64));
+        }
+        Ok(Some(FeeHistogram::from_transactions(transactions)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::bip158;
+    use bitcoin::{Network, OutPoint, Script, TxIn, TxOut};
+    use std::cell::RefCell;
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::thread;
+
+    use account::{Account, AccountAddressType};
+    use broadcast::{validate, Rejection};
+    use coins::CoinEvent;
+    use fixtures::{block_after, master_account, next_script};
+
+    use super::*;
+
+    struct Mock {
+        blocks: Vec<Block>,
+        filters: bool,
+        fetched: RefCell<Vec<BlockHash>>,
+        mempool: Vec<Transaction>,
+    }
+
+    impl ChainBackend for Mock {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(self.blocks.get(height as usize).map(|b| b.header))
+        }
+
+        fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+            self.fetched.borrow_mut().push(*hash);
+            self.blocks
+                .iter()
+                .find(|b| b.block_hash() == *hash)
+                .cloned()
+                .ok_or(Error::Backend("unknown block"))
+        }
+
+        fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            if !self.filters {
+                return Ok(None);
+            }
+            let block = self
+                .blocks
+                .iter()
+                .find(|b| b.block_hash() == *hash)
+                .ok_or(Error::Backend("unknown block"))?;
+            // outputs only, the mock does not know spent outputs
+            let mut content = Vec::new();
+            {
+                let mut writer = bip158::BlockFilterWriter::new(&mut content, block);
+                writer.add_output_scripts();
+                writer.finish()?;
+            }
+            Ok(Some(BlockFilter::new(content.as_slice())))
+        }
+
+        fn tx_status(&self, txid: &Txid) -> Result<TxStatus, Error> {
+            Ok(self
+                .blocks
+                .iter()
+                .enumerate()
+                .find(|(_, b)| b.txdata.iter().any(|t| t.txid() == *txid))
+                .map(|(height, b)| TxStatus::Confirmed {
+                    height: height as u32,
+                    block_hash: b.block_hash(),
+                })
+                .unwrap_or(if self.mempool.iter().any(|t| t.txid() == *txid) {
+                    TxStatus::Unconfirmed
+                } else {
+                    TxStatus::Unknown
+                }))
+        }
+
+        fn spender(&self, point: &OutPoint) -> Result<Option<Txid>, Error> {
+            Ok(self
+                .mempool
+                .iter()
+                .find(|t| t.input.iter().any(|i| i.previous_output == *point))
+                .map(|t| t.txid()))
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(3))
+        }
+    }
+
+    #[test]
+    fn chain_sync() {
+        let (mut master, _) = master_account(Network::Testnet);
+        let script = next_script(&mut master, (0, 0));
+        let transaction = |script: Script, value: u64| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: bitcoin::Txid::default(),
+                    vout: value as u32,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script,
+            }],
+        };
+        let genesis = genesis_block(Network::Testnet);
+        let funding = transaction(script, 100_000);
+        let one = block_after(&genesis, vec![funding.clone()]);
+        let two = block_after(&one, vec![transaction(Script::new(), 1)]);
+        let mut backend = Mock {
+            blocks: vec![genesis.clone(), one, two],
+            filters: true,
+            fetched: RefCell::new(Vec::new()),
+            mempool: Vec::new(),
+        };
+        let mut coins = Coins::new();
+        let mut sync = ChainSync::new(0);
+        assert_eq!(sync.sync(&backend, &mut master, &mut coins).unwrap(), 1);
+        assert_eq!(coins.confirmed_balance(), 100_000);
+        assert_eq!(sync.tip().unwrap().0, 2);
+        assert_eq!(
+            *backend.fetched.borrow(),
+            vec![backend.blocks[1].block_hash()]
+        );
+        assert_eq!(
+            backend.tx_status(&funding.txid()).unwrap(),
+            TxStatus::Confirmed {
+                height: 1,
+                block_hash: backend.blocks[1].block_hash()
+            }
+        );
+        assert_eq!(sync.sync(&backend, &mut master, &mut coins).unwrap(), 0);
+
+        // a reorg drops the funding
+        let one = block_after(&genesis, vec![transaction(Script::new(), 2)]);
+        let two = block_after(&one, Vec::new());
+        let three = block_after(&two, Vec::new());
+        backend.blocks = vec![genesis, one, two, three];
+        backend.filters = false;
+        assert_eq!(sync.sync(&backend, &mut master, &mut coins).unwrap(), 3);
+        assert_eq!(coins.confirmed_balance(), 0);
+        assert_eq!(sync.tip().unwrap(), (3, backend.blocks[3].block_hash()));
+        assert_eq!(backend.estimate(2).unwrap(), FeeRate::from_sat_per_vb(3));
+    }
+
+    #[test]
+    fn rescan() {
+        let (mut master, mut unlocker) = master_account(Network::Testnet);
+        let mut account =
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 1, 0, 10).unwrap();
+        // a key used before the wallet was born at height 2
+        let script = account.next_key().unwrap().address.script_pubkey();
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script,
+            }],
+        };
+        let genesis = genesis_block(Network::Testnet);
+        let one = block_after(&genesis, vec![funding.clone()]);
+        let two = block_after(&one, Vec::new());
+        let three = block_after(&two, Vec::new());
+        let backend = Mock {
+            blocks: vec![genesis, one, two, three],
+            filters: true,
+            fetched: RefCell::new(Vec::new()),
+            mempool: Vec::new(),
+        };
+        let mut coins = Coins::new();
+        let mut sync = ChainSync::new(2);
+        sync.sync(&backend, &mut master, &mut coins).unwrap();
+        assert_eq!(coins.confirmed_balance(), 0);
+
+        // import the key, then rescan from before its first use
+        master.add_account(account);
+        let mut history = History::new();
+        let processed = sync
+            .rescan(1, &backend, &mut master, &mut coins, Some(&mut history))
+            .unwrap();
+        assert_eq!(processed, 1);
+        assert_eq!(coins.confirmed_balance(), 100_000);
+        assert_eq!(sync.tip().unwrap(), (3, backend.blocks[3].block_hash()));
+        assert_eq!(sync.height(&backend.blocks[1].block_hash()), Some(1));
+        assert_eq!(
+            history.get(&funding.txid()).unwrap().confirmation,
+            Some((backend.blocks[1].block_hash(), 1))
+        );
+
+        // rescanning processed blocks does not count coins twice
+        sync.rescan(0, &backend, &mut master, &mut coins, Some(&mut history))
+            .unwrap();
+        assert_eq!(coins.confirmed_balance(), 100_000);
+        assert!(coins.pending().is_empty());
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn replacements() {
+        let (mut master, _) = master_account(Network::Testnet);
+        let script = next_script(&mut master, (0, 0));
+        let transaction = |previous_output, script_pubkey, value| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output,
+                sequence: 0xfffffffd,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey,
+            }],
+        };
+        let foreign = OutPoint {
+            txid: Txid::default(),
+            vout: 7,
+        };
+        let payment = transaction(foreign, script.clone(), 100_000);
+        let spend = transaction(
+            OutPoint {
+                txid: payment.txid(),
+                vout: 0,
+            },
+            Script::new(),
+            90_000,
+        );
+        let mut coins = Coins::new();
+        coins.process_unconfirmed_transaction(&mut master, &payment);
+        coins.process_unconfirmed_transaction(&mut master, &spend);
+
+        // while the payment is in the mempool nothing is replaced
+        let mut backend = Mock {
+            blocks: vec![genesis_block(Network::Testnet)],
+            filters: false,
+            fetched: RefCell::new(Vec::new()),
+            mempool: vec![payment.clone(), spend.clone()],
+        };
+        assert!(detect_replacements(&backend, &mut coins)
+            .unwrap()
+            .is_empty());
+
+        // the payer double spends the payment
+        let double = transaction(foreign, Script::new(), 99_000);
+        backend.mempool = vec![double.clone()];
+        let replaced = detect_replacements(&backend, &mut coins).unwrap();
+        assert_eq!(replaced, vec![(payment.txid(), double.txid())]);
+        assert!(coins.pending().is_empty());
+        assert_eq!(coins.unconfirmed_balance(), 0);
+        assert_eq!(
+            coins.take_events(),
+            vec![
+                CoinEvent::Conflict {
+                    evicted: spend.txid(),
+                    by: double.txid()
+                },
+                CoinEvent::Replaced {
+                    evicted: payment.txid(),
+                    by: double.txid(),
+                    received: 100_000
+                },
+            ]
+        );
+    }
+
+    /// a node serving blocks over RPC
+    struct Node {
+        blocks: Vec<Block>,
+    }
+
+    impl JsonRpc for Node {
+        fn call(&self, method: &str, params: &str) -> Result<String, Error> {
+            let block = || {
+                self.blocks
+                    .iter()
+                    .find(|b| params.contains(b.block_hash().to_string().as_str()))
+                    .ok_or(Error::Backend("unknown block"))
+            };
+            match method {
+                "getblockcount" => Ok((self.blocks.len() - 1).to_string()),
+                "getblockhash" => {
+                    let height = params
+                        .trim_matches(&['[', ']'][..])
+                        .parse::<usize>()
+                        .unwrap();
+                    Ok(format!("\"{}\"", self.blocks[height].block_hash()))
+                }
+                "getblockheader" => Ok(format!("\"{}\"", serialize_hex(&block()?.header))),
+                "getblock" => Ok(format!("\"{}\"", serialize_hex(block()?))),
+                "scantxoutset" => {
+                    let mut unspents = Vec::new();
+                    for (height, block) in self.blocks.iter().enumerate() {
+                        for transaction in block.txdata.iter() {
+                            for (vout, output) in transaction.output.iter().enumerate() {
+                                if params
+                                    .contains(format!("raw({:x})", output.script_pubkey).as_str())
+                                {
+                                    unspents.push(format!(
+                                        r#"{{"txid": "{}", "vout": {}, "amount": {}, "height": {}}}"#,
+                                        transaction.txid(),
+                                        vout,
+                                        output.value as f64 / 1e8,
+                                        height
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    Ok(format!(
+                        r#"{{"success": true, "height": {}, "unspents": [{}]}}"#,
+                        self.blocks.len() - 1,
+                        unspents.join(", ")
+                    ))
+                }
+                "gettxspendingprevout" => Ok(format!(
+                    r#"[{{"txid": "{}", "vout": 0, "spendingtxid": "{}"}}]"#,
+                    self.blocks[0].txdata[0].txid(),
+                    self.blocks[1].txdata[0].txid()
+                )),
+                "getrawmempool" => Ok(format!(
+                    r#"{{"{}": {{"vsize": 141, "fees": {{"base": 0.00001410}}}}}}"#,
+                    self.blocks[1].txdata[0].txid()
+                )),
+                "testmempoolaccept" => Ok(
+                    r#"[{"txid": "00", "allowed": false, "reject-reason": "missing-inputs"}]"#
+                        .to_string(),
+                ),
+                _ => Err(Error::Rpc(RpcError {
+                    code: RPC_METHOD_NOT_FOUND,
+                    message: "Method not found".to_string(),
+                })),
+            }
+        }
+    }
+
+    /// a TLS client passing the connection through, records server names
+    struct Passthrough(Rc<RefCell<Vec<String>>>);
+
+    impl TlsConnector for Passthrough {
+        fn connect(
+            &self,
+            stream: TcpStream,
+            server_name: &str,
+            _: &TlsConfig,
+        ) -> Result<Box<dyn Stream>, Error> {
+            self.0.borrow_mut().push(server_name.to_string());
+            Ok(Box::new(stream))
+        }
+    }
+
+    #[test]
+    fn bitcoind() {
+        let (mut master, _) = master_account(Network::Testnet);
+        let script = master
+            .get((0, 0))
+            .unwrap()
+            .get_key(3)
+            .unwrap()
+            .address
+            .script_pubkey();
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script,
+            }],
+        };
+        let genesis = genesis_block(Network::Testnet);
+        let one = block_after(&genesis, vec![funding.clone()]);
+        let two = block_after(&one, Vec::new());
+        let backend = BitcoindBackend::new(Node {
+            blocks: vec![genesis, one, two],
+        });
+        let mut coins = Coins::new();
+        assert_eq!(backend.scan_utxos(&mut master, &mut coins).unwrap(), 2);
+        assert_eq!(coins.confirmed_balance(), 100_000);
+        assert_eq!(backend.header(3).unwrap(), None);
+        let coinbase = OutPoint {
+            txid: backend.rpc.blocks[0].txdata[0].txid(),
+            vout: 0,
+        };
+        assert_eq!(
+            backend.spender(&coinbase).unwrap(),
+            Some(backend.rpc.blocks[1].txdata[0].txid())
+        );
+        assert_eq!(
+            backend
+                .test_mempool_accept(std::slice::from_ref(&funding))
+                .unwrap(),
+            vec![Some("missing-inputs".to_string())]
+        );
+        match validate(&backend, &[funding]) {
+            Err(Error::Rejected(Rejection::MissingInputs)) => {}
+            _ => panic!("missing inputs not rejected"),
+        }
+        assert_eq!(
+            backend.fee_histogram().unwrap().unwrap().buckets(),
+            &[(FeeRate::from_sat_per_vb(10), 141)][..]
+        );
+
+        // HTTP transport with cookie authentication
+        let cookie = std::env::temp_dir().join(format!("rpc-cookie-{}", std::process::id()));
+        fs::write(&cookie, "__cookie__:secret\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            let fee = r#"{"result": {"feerate": 0.0002, "blocks": 2}, "error": null, "id": "rust-wallet"}"#;
+            let dust = r#"{"result": null, "error": {"code": -26, "message": "dust"}, "id": "rust-wallet"}"#;
+            for (status, body) in [
+                ("200 OK", fee),
+                ("401 Unauthorized", fee),
+                ("500 Internal Server Error", dust),
+                ("200 OK", fee),
+            ]
+            .iter()
+            {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.ends_with(b"}") {
+                    let n = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            requests
+        });
+        let backend =
+            BitcoindBackend::new(HttpJsonRpc::new(&address, RpcAuth::Cookie(cookie.clone())));
+        assert_eq!(
+            backend.feerate(2).unwrap(),
+            FeeRate::from_sat_per_kvb(20_000)
+        );
+        let refused = BitcoindBackend::new(HttpJsonRpc::new(
+            &address,
+            RpcAuth::UserPass("user".to_string(), "wrong".to_string()),
+        ));
+        assert!(refused.feerate(2).is_err());
+        // the error of the node is kept
+        match HttpJsonRpc::new(&address, RpcAuth::Cookie(cookie.clone()))
+            .call("sendrawtransaction", "[\"00\"]")
+        {
+            Err(Error::Rpc(error)) => {
+                assert_eq!((error.code, error.message.as_str()), (-26, "dust"))
+            }
+            _ => panic!("error of the node lost"),
+        }
+
+        // TLS with a server name other than the host
+        let config = RpcConfig::new(
+            &address,
+            RpcAuth::UserPass("user".to_string(), "password".to_string()),
+        )
+        .tls(
+            TlsConfig::default()
+                .ca_root(cookie.clone())
+                .server_name("node.example"),
+        );
+        assert!(config.tls.as_ref().unwrap().check().is_ok());
+        assert!(TlsConfig::default()
+            .client_certificate("missing.pem", "missing.key")
+            .check()
+            .is_err());
+        assert!(HttpJsonRpc::with_config(config.clone())
+            .call("getblockcount", "[]")
+            .is_err());
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let tls = BitcoindBackend::new(
+            HttpJsonRpc::with_config(config).tls_connector(Passthrough(names.clone())),
+        );
+        assert_eq!(tls.feerate(2).unwrap(), FeeRate::from_sat_per_kvb(20_000));
+        assert_eq!(*names.borrow(), vec!["node.example".to_string()]);
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains(
+            format!(
+                "Authorization: Basic {}",
+                base64_encode(b"__cookie__:secret")
+            )
+            .as_str()
+        ));
+        assert!(requests[0].contains(r#""method":"estimatesmartfee","params":[2]"#));
+        assert!(requests[1].contains(base64_encode(b"user:wrong").as_str()));
+        assert!(requests[0].contains(format!("Host: {}\r\n", address).as_str()));
+        let port = address.rsplit(':').next().unwrap();
+        assert!(requests[3].contains(format!("Host: node.example:{}\r\n", port).as_str()));
+        fs::remove_file(&cookie).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Backup
+//!
+//! A single encrypted blob to keep in an untrusted store, e.g. a cloud drive. It holds the
+//! accounts with their derivation and next indexes, watched scripts, labels and drafts, and
+//! optionally the encrypted seed, and notes of transactions. Coins and proofs are not backed up,
+//! they are found again by a rescan from the birth of the master account.
+//!
+//! ```text
+//! sealed(magic "RWBK" | version | master account | changeset of edits | notes)
+//! ```
+//!
+//! The blob is encrypted and authenticated as a wallet file, see the storage module.
+//!
+//! A BackupRotation writes timestamped backups to a directory after significant changes, i.e. a
+//! new account, an imported key or watched script, or a new passphrase, and keeps the newest.
+//!
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::Txid;
+
+use account::MasterAccount;
+use changeset::ChangeSet;
+use coins::Coins;
+use error::Error;
+use storage::{decode_notes, encode_notes, seal, unseal, write_atomic, KdfParams};
+
+const BACKUP_MAGIC: &[u8; 4] = b"RWBK";
+/// version of backups written by Backup::export_backup
+pub const BACKUP_VERSION: u8 = 2;
+
+/// Backup of a wallet
+pub struct Backup<'a> {
+    master: &'a MasterAccount,
+    coins: &'a Coins,
+    seed: bool,
+    kdf: KdfParams,
+}
+
+impl<'a> Backup<'a> {
+    /// a backup of accounts, labels, drafts and notes, without the seed
+    pub fn new(master: &'a MasterAccount, coins: &'a Coins) -> Backup<'a> {
+        Backup {
+            master,
+            coins,
+            seed: false,
+            kdf: KdfParams::default(),
+        }
+    }
+
+    /// also back up the seed, it stays encrypted with the passphrase of the master account
+    pub fn with_seed(mut self) -> Backup<'a> {
+        self.seed = true;
+        self
+    }
+
+    /// key derivation cost of the backup encryption
+    pub fn with_kdf(mut self, kdf: KdfParams) -> Backup<'a> {
+        self.kdf = kdf;
+        self
+    }
+
+    /// the encrypted backup
+    pub fn export_backup(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
+        let mut payload = BACKUP_MAGIC.to_vec();
+        payload.push(BACKUP_VERSION);
+        if self.seed {
+            self.master.consensus_encode(&mut payload)?;
+        } else {
+            self.master
+                .to_watch_only()?
+                .consensus_encode(&mut payload)?;
+        }
+        self.coins
+            .edits(self.master)
+            .consensus_encode(&mut payload)?;
+        encode_notes(self.coins.notes(), &mut payload)?;
+        seal(payload.as_slice(), passphrase, self.kdf)
+    }
+}
+
+/// the master account, the edits and the notes of a backup
+/// The master account is watch only if the backup has no seed. The edits are restored with
+/// Coins::sync, labels of coins not yet found again are kept until the rescan finds them. Notes
+/// are restored with Coins::set_note, backups of version 1 have none.
+pub fn restore_backup(
+    data: &[u8],
+    passphrase: &str,
+) -> Result<(MasterAccount, ChangeSet, HashMap<Txid, String>), Error> {
+    let payload = unseal(data, passphrase)?;
+    if payload.len() < 5 || &payload[..4] != BACKUP_MAGIC {
+        return Err(Error::Storage("not a backup"));
+    }
+    if payload[4] == 0 || payload[4] > BACKUP_VERSION {
+        return Err(Error::Storage("unknown backup version"));
+    }
+    let mut data = &payload[5..];
+    let master = MasterAccount::consensus_decode(&mut data)?;
+    let edits = ChangeSet::consensus_decode(&mut data)?;
+    let notes = if payload[4] > 1 {
+        decode_notes(&mut data)?
+    } else {
+        HashMap::new()
+    };
+    if !data.is_empty() {
+        return Err(Error::Storage("trailing data in backup"));
+    }
+    Ok((master, edits, notes))
+}
+
+const BACKUP_EXTENSION: &str = "rwbk";
+
+/// commitment to the state a backup rotation watches for changes
+fn significant_state(master: &MasterAccount) -> Result<sha256::Hash, Error> {
+    let mut engine = sha256::Hash::engine();
+    engine.input(master.encrypted());
+    engine.input(&master.master_public().encode());
+    let mut accounts = master.accounts().iter().collect::<Vec<_>>();
+    accounts.sort_by_key(|(id, _)| **id);
+    for (id, account) in accounts {
+        id.0.consensus_encode(&mut engine)?;
+        id.1.consensus_encode(&mut engine)?;
+        account
+            .address_type()
+            .as_u32()
+            .consensus_encode(&mut engine)?;
+        // imported script keys, not keys of the look ahead
+        for key in account.instantiated() {
+            if key.tweak.is_some() || key.csv.is_some() {
+                key.script_code.consensus_encode(&mut engine)?;
+            }
+        }
+    }
+    let mut watched = master.watched_scripts().iter().collect::<Vec<_>>();
+    watched.sort();
+    for script in watched {
+        script.consensus_encode(&mut engine)?;
+    }
+    Ok(sha256::Hash::from_engine(engine))
+}
+
+/// Timestamped backups in a directory written after significant changes
+pub struct BackupRotation {
+    directory: PathBuf,
+    keep: usize,
+    seed: bool,
+    kdf: KdfParams,
+    last: Option<sha256::Hash>,
+}
+
+impl BackupRotation {
+    /// keep the newest keep backups in directory
+    pub fn new<P: AsRef<Path>>(directory: P, keep: usize) -> BackupRotation {
+        BackupRotation {
+            directory: directory.as_ref().to_path_buf(),
+            keep: keep.max(1),
+            seed: false,
+            kdf: KdfParams::default(),
+            last: None,
+        }
+    }
+
+    /// also back up the seed, see Backup::with_seed
+    pub fn with_seed(mut self) -> BackupRotation {
+        self.seed = true;
+        self
+    }
+
+    /// key derivation cost of the backup encryption
+    pub fn with_kdf(mut self, kdf: KdfParams) -> BackupRotation {
+        self.kdf = kdf;
+        self
+    }
+
+    /// backups in the directory, oldest first
+    pub fn backups(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut backups = Vec::new();
+        if !self.directory.exists() {
+            return Ok(backups);
+        }
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension() == Some(BACKUP_EXTENSION.as_ref()) {
+                backups.push(path);
+            }
+        }
+        // names are zero padded timestamps
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// write a backup if accounts, imported keys, watched scripts or the passphrase changed
+    /// since the last backup of this rotation, the first call always writes one
+    pub fn after_change(
+        &mut self,
+        master: &MasterAccount,
+        coins: &Coins,
+        passphrase: &str,
+    ) -> Result<Option<PathBuf>, Error> {
+        if self.last == Some(significant_state(master)?) {
+            return Ok(None);
+        }
+        self.backup(master, coins, passphrase).map(Some)
+    }
+
+    /// write a backup now and remove all but the newest
+    pub fn backup(
+        &mut self,
+        master: &MasterAccount,
+        coins: &Coins,
+        passphrase: &str,
+    ) -> Result<PathBuf, Error> {
+        let mut backup = Backup::new(master, coins).with_kdf(self.kdf);
+        if self.seed {
+            backup = backup.with_seed();
+        }
+        let data = backup.export_backup(passphrase)?;
+        fs::create_dir_all(&self.directory)?;
+        let mut time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let mut path;
+        loop {
+            path = self
+                .directory
+                .join(format!("backup-{:016}.{}", time, BACKUP_EXTENSION));
+            if !path.exists() {
+                break;
+            }
+            time += 1;
+        }
+        write_atomic(&path, data.as_slice())?;
+        self.last = Some(significant_state(master)?);
+        let backups = self.backups()?;
+        if backups.len() > self.keep {
+            for old in &backups[..backups.len() - self.keep] {
+                fs::remove_file(old)?;
+            }
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::consensus::serialize;
+    use bitcoin::{Network, OutPoint, Script, TxOut};
+
+    use account::{Account, AccountAddressType, Unlocker};
+    use fixtures::{block, funding, master_account, next_script, PASSPHRASE};
+
+    use super::*;
+    const CHEAP: KdfParams = KdfParams {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    };
+
+    #[test]
+    fn backup_restore() {
+        let (mut master, mut unlocker) = master_account(Network::Regtest);
+        master.get_mut((0, 0)).unwrap().set_name("savings");
+        let funded = next_script(&mut master, (0, 0));
+        let block = block(
+            Network::Regtest,
+            vec![funding(vec![TxOut {
+                value: 100_000,
+                script_pubkey: funded,
+            }])],
+        );
+        let point = OutPoint {
+            txid: block.txdata[0].txid(),
+            vout: 0,
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block);
+        coins.set_memo(&point, "salary").unwrap();
+        coins.set_note(&point.txid, "invoice #1234").unwrap();
+
+        let backup = Backup::new(&master, &coins).with_kdf(CHEAP);
+        let data = backup.export_backup("backup").unwrap();
+        assert!(restore_backup(data.as_slice(), PASSPHRASE).is_err());
+        let (mut restored, edits, notes) = restore_backup(data.as_slice(), "backup").unwrap();
+        assert!(restored.encrypted().is_empty());
+        assert_eq!(restored.master_public(), master.master_public());
+        assert_eq!(restored.get((0, 0)).unwrap().metadata().name, "savings");
+
+        // labels wait for the rescan
+        let mut rescanned = Coins::new();
+        rescanned.sync(&mut restored, &edits).unwrap();
+        rescanned.process(&mut restored, &block);
+        assert_eq!(
+            rescanned.metadata(&point).unwrap().memo,
+            Some("salary".to_string())
+        );
+        assert_eq!(rescanned.available_balance(1, |_| Some(1)), 100_000);
+        assert_eq!(&notes, coins.notes());
+        for (txid, note) in notes {
+            rescanned.set_note(&txid, &note).unwrap();
+        }
+        assert_eq!(rescanned.note(&point.txid).unwrap(), "invoice #1234");
+
+        let data = backup.with_seed().export_backup("backup").unwrap();
+        let (restored, _, _) = restore_backup(data.as_slice(), "backup").unwrap();
+        assert_eq!(serialize(&restored), serialize(&master));
+        assert!(Unlocker::new_for_master(&restored, PASSPHRASE).is_ok());
+
+        let directory = std::env::temp_dir().join(format!("backups-{}", std::process::id()));
+        let mut rotation = BackupRotation::new(&directory, 2).with_kdf(CHEAP);
+        let first = rotation.after_change(&master, &coins, "backup").unwrap();
+        assert!(first.is_some());
+        master.get_mut((0, 0)).unwrap().next_key().unwrap();
+        assert!(rotation
+            .after_change(&master, &coins, "backup")
+            .unwrap()
+            .is_none());
+        master
+            .add_account(Account::new(&mut unlocker, AccountAddressType::P2PKH, 1, 0, 10).unwrap());
+        assert!(rotation
+            .after_change(&master, &coins, "backup")
+            .unwrap()
+            .is_some());
+        master
+            .add_watched_script(Script::new_op_return(&[1]))
+            .unwrap();
+        let newest = rotation
+            .after_change(&master, &coins, "backup")
+            .unwrap()
+            .unwrap();
+        let backups = rotation.backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(!backups.contains(&first.unwrap()));
+        assert_eq!(backups[1], newest);
+        let (restored, _, _) =
+            restore_backup(fs::read(&newest).unwrap().as_slice(), "backup").unwrap();
+        assert_eq!(restored.accounts().len(), 2);
+        assert_eq!(restored.watched_scripts().len(), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
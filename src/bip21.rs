@@ -0,0 +1,211 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # BIP21 payment URI
+//!
+//! ```text
+//! bitcoin:<address>?amount=<btc>&label=<label>&message=<message>
+//! ```
+//!
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::util::amount::{Amount, Denomination};
+use bitcoin::Address;
+
+use error::Error;
+
+const SCHEME: &str = "bitcoin:";
+
+/// A parsed BIP21 payment URI
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaymentUri {
+    /// address to pay to. BIP21 allows to omit it if other parameters tell how to pay
+    pub address: Option<Address>,
+    /// amount in satoshis
+    pub amount: Option<u64>,
+    /// label of the recipient
+    pub label: Option<String>,
+    /// message describing the payment
+    pub message: Option<String>,
+    /// other parameters in order of appearance, e.g. pj for payjoin
+    pub extras: Vec<(String, String)>,
+}
+
+impl PaymentUri {
+    /// a payment request for an address
+    pub fn new(address: Address) -> PaymentUri {
+        PaymentUri {
+            address: Some(address),
+            amount: None,
+            label: None,
+            message: None,
+            extras: Vec::new(),
+        }
+    }
+
+    /// parse a BIP21 URI
+    /// fails on unknown required (req-) parameters as demanded by BIP21
+    pub fn parse(uri: &str) -> Result<PaymentUri, Error> {
+        if uri.len() < SCHEME.len() || !uri[..SCHEME.len()].eq_ignore_ascii_case(SCHEME) {
+            return Err(Error::Uri("not a bitcoin: URI"));
+        }
+        let rest = &uri[SCHEME.len()..];
+        let (address, query) = match rest.find('?') {
+            Some(q) => (&rest[..q], Some(&rest[q + 1..])),
+            None => (rest, None),
+        };
+        let mut result = PaymentUri {
+            address: None,
+            amount: None,
+            label: None,
+            message: None,
+            extras: Vec::new(),
+        };
+        if !address.is_empty() {
+            result.address =
+                Some(Address::from_str(address).map_err(|_| Error::Uri("invalid address"))?);
+        }
+        for pair in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(e) => (&pair[..e], decode(&pair[e + 1..])?),
+                None => (pair, String::new()),
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "amount" => {
+                    if result.amount.is_some() {
+                        return Err(Error::Uri("duplicate amount"));
+                    }
+                    result.amount = Some(
+                        Amount::from_str_in(value.as_str(), Denomination::Bitcoin)
+                            .map_err(|_| Error::Uri("invalid amount"))?
+                            .as_sat(),
+                    );
+                }
+                "label" => result.label = Some(value),
+                "message" => result.message = Some(value),
+                k if k.starts_with("req-") => return Err(Error::Uri("unknown required parameter")),
+                _ => result.extras.push((key.to_string(), value)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// get the value of an extra parameter
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.extras
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl fmt::Display for PaymentUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", SCHEME)?;
+        if let Some(ref address) = self.address {
+            write!(f, "{}", address)?;
+        }
+        let mut params = Vec::new();
+        if let Some(amount) = self.amount {
+            params.push((
+                "amount".to_string(),
+                Amount::from_sat(amount)
+                    .to_string_in(Denomination::Bitcoin)
+                    .trim_end_matches('0')
+                    .trim_end_matches('.')
+                    .to_string(),
+            ));
+        }
+        if let Some(ref label) = self.label {
+            params.push(("label".to_string(), encode(label)));
+        }
+        if let Some(ref message) = self.message {
+            params.push(("message".to_string(), encode(message)));
+        }
+        for (k, v) in &self.extras {
+            params.push((k.clone(), encode(v)));
+        }
+        for (i, (k, v)) in params.iter().enumerate() {
+            write!(f, "{}{}={}", if i == 0 { '?' } else { '&' }, k, v)?;
+        }
+        Ok(())
+    }
+}
+
+fn decode(s: &str) -> Result<String, Error> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 3 > bytes.len() {
+                return Err(Error::Uri("invalid percent encoding"));
+            }
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .map_err(|_| Error::Uri("invalid percent encoding"))?;
+            decoded.push(
+                u8::from_str_radix(hex, 16).map_err(|_| Error::Uri("invalid percent encoding"))?,
+            );
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| Error::Uri("invalid utf8 in parameter"))
+}
+
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' | b':' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_uri() {
+        let uri = PaymentUri::parse(
+            "bitcoin:1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa?amount=20.3&label=Luke-Jr&message=Donation%20for%20project%20xyz&pj=https://example.com/pj",
+        )
+        .unwrap();
+        assert_eq!(
+            uri.address.as_ref().unwrap().to_string(),
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"
+        );
+        assert_eq!(uri.amount, Some(2030000000));
+        assert_eq!(uri.label.as_deref(), Some("Luke-Jr"));
+        assert_eq!(uri.message.as_deref(), Some("Donation for project xyz"));
+        assert_eq!(uri.get("pj"), Some("https://example.com/pj"));
+        assert_eq!(PaymentUri::parse(&uri.to_string()).unwrap(), uri);
+
+        let uri = PaymentUri::parse("bitcoin:?lno=lno1abc").unwrap();
+        assert!(uri.address.is_none());
+        assert!(PaymentUri::parse("bitcoin:1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa?req-x=1").is_err());
+        assert!(PaymentUri::parse("litecoin:1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").is_err());
+        assert!(PaymentUri::parse("bitcoin:?amount=1%2").is_err());
+    }
+}
@@ -0,0 +1,792 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # BIP324 encrypted transport
+//!
+//! Peers exchange ephemeral keys encoded with ElligatorSwift, so the handshake is 64 bytes
+//! indistinguishable from random, followed by random garbage. Keys of both directions are
+//! derived from their x-only ECDH secret. Every packet is an encrypted 3 byte length, then
+//! the content sealed with ChaCha20-Poly1305 of RFC 8439. Both ciphers are rekeyed every 224
+//! uses for forward secrecy.
+//!
+//! The session id is the same on both sides unless a man in the middle intercepted the
+//! handshake; comparing it out of band detects that.
+//!
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::Network;
+use crypto::chacha20::ChaCha20;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::mac::Mac;
+use crypto::poly1305::Poly1305;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use crypto::util::fixed_time_eq;
+use rand::{thread_rng, Rng, RngCore};
+
+use error::Error;
+
+/// uses of a cipher before it is rekeyed
+const REKEY_INTERVAL: u64 = 224;
+/// length of the ElligatorSwift encoded key
+pub const ELLSWIFT_LEN: usize = 64;
+/// length of the garbage terminator
+pub const TERMINATOR_LEN: usize = 16;
+/// garbage sent at most after the key
+pub const MAX_GARBAGE_LEN: usize = 4095;
+/// the packet is a decoy
+const IGNORE_BIT: u8 = 0x80;
+const TAG_LEN: usize = 16;
+
+/// element of the field of secp256k1 in little endian 64 bit limbs, always reduced
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Fe([u64; 4]);
+
+/// 2^256 - 2^32 - 977
+const P: [u64; 4] = [
+    0xFFFF_FFFE_FFFF_FC2F,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+];
+/// 2^256 mod p
+const R: u128 = 0x1_0000_03D1;
+/// p - 2, inverts by Fermat
+const P_MINUS_2: [u64; 4] = [
+    0xFFFF_FFFE_FFFF_FC2D,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+];
+/// (p + 1) / 4, square root as p = 3 mod 4
+const P_PLUS_1_DIV_4: [u64; 4] = [
+    0xFFFF_FFFF_BFFF_FF0C,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0x3FFF_FFFF_FFFF_FFFF,
+];
+
+impl Fe {
+    fn from_u64(v: u64) -> Fe {
+        Fe([v, 0, 0, 0])
+    }
+
+    /// big endian, reduced mod p
+    fn from_bytes(bytes: &[u8]) -> Fe {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[24 - 8 * i..32 - 8 * i]);
+            *limb = u64::from_be_bytes(b);
+        }
+        Fe(limbs).reduce(false)
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for i in 0..4 {
+            bytes[24 - 8 * i..32 - 8 * i].copy_from_slice(&self.0[i].to_be_bytes());
+        }
+        bytes
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// subtract p if the value with overflow bit is at least p
+    fn reduce(self, overflow: bool) -> Fe {
+        let at_least_p = overflow
+            || (0..4)
+                .rev()
+                .find(|&i| self.0[i] != P[i])
+                .is_none_or(|i| self.0[i] > P[i]);
+        if !at_least_p {
+            return self;
+        }
+        let mut r = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in r.iter_mut().enumerate() {
+            let (d, b1) = self.0[i].overflowing_sub(P[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            *limb = d;
+            borrow = b1 || b2;
+        }
+        Fe(r)
+    }
+
+    fn add(self, other: Fe) -> Fe {
+        let mut r = [0u64; 4];
+        let mut carry = 0u128;
+        for (i, limb) in r.iter_mut().enumerate() {
+            let v = self.0[i] as u128 + other.0[i] as u128 + carry;
+            *limb = v as u64;
+            carry = v >> 64;
+        }
+        Fe(r).reduce(carry > 0)
+    }
+
+    fn neg(self) -> Fe {
+        if self.is_zero() {
+            return self;
+        }
+        let mut r = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in r.iter_mut().enumerate() {
+            let (d, b1) = P[i].overflowing_sub(self.0[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            *limb = d;
+            borrow = b1 || b2;
+        }
+        Fe(r)
+    }
+
+    fn sub(self, other: Fe) -> Fe {
+        self.add(other.neg())
+    }
+
+    fn mul(self, other: Fe) -> Fe {
+        let mut t = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let v = t[i + j] as u128 + self.0[i] as u128 * other.0[j] as u128 + carry;
+                t[i + j] = v as u64;
+                carry = v >> 64;
+            }
+            t[i + 4] = carry as u64;
+        }
+        // fold the high half twice as 2^256 = R mod p
+        let mut r = [0u64; 4];
+        let mut carry = 0u128;
+        for i in 0..4 {
+            let v = t[i] as u128 + t[i + 4] as u128 * R + carry;
+            r[i] = v as u64;
+            carry = v >> 64;
+        }
+        let mut carry = carry * R;
+        for limb in r.iter_mut() {
+            let v = *limb as u128 + carry;
+            *limb = v as u64;
+            carry = v >> 64;
+        }
+        if carry > 0 {
+            // the value wrapped, so it is small and adding R can not overflow again
+            let mut add = R;
+            for limb in r.iter_mut() {
+                let v = *limb as u128 + add;
+                *limb = v as u64;
+                add = v >> 64;
+            }
+        }
+        Fe(r).reduce(false)
+    }
+
+    fn square(self) -> Fe {
+        self.mul(self)
+    }
+
+    fn pow(self, exponent: &[u64; 4]) -> Fe {
+        let mut r = Fe::from_u64(1);
+        for limb in exponent.iter().rev() {
+            for bit in (0..64).rev() {
+                r = r.square();
+                if limb >> bit & 1 == 1 {
+                    r = r.mul(self);
+                }
+            }
+        }
+        r
+    }
+
+    fn inv(self) -> Fe {
+        self.pow(&P_MINUS_2)
+    }
+
+    fn div(self, other: Fe) -> Fe {
+        self.mul(other.inv())
+    }
+
+    fn sqrt(self) -> Option<Fe> {
+        let r = self.pow(&P_PLUS_1_DIV_4);
+        if r.square() == self {
+            Some(r)
+        } else {
+            None
+        }
+    }
+
+    fn half(self) -> Fe {
+        self.div(Fe::from_u64(2))
+    }
+}
+
+/// x^3 + 7
+fn curve(x: Fe) -> Fe {
+    x.square().mul(x).add(Fe::from_u64(7))
+}
+
+fn is_valid_x(x: Fe) -> bool {
+    curve(x).sqrt().is_some()
+}
+
+fn minus_3_sqrt() -> Fe {
+    Fe::from_u64(3).neg().sqrt().expect("-3 is a square mod p")
+}
+
+/// decode field elements u and t to an x coordinate on the curve
+fn xswiftec(u: Fe, t: Fe) -> Fe {
+    let one = Fe::from_u64(1);
+    let u = if u.is_zero() { one } else { u };
+    let mut t = if t.is_zero() { one } else { t };
+    if curve(u).add(t.square()).is_zero() {
+        t = t.add(t);
+    }
+    let x = curve(u).sub(t.square()).div(t.add(t));
+    let y = x.add(t).div(minus_3_sqrt().mul(u));
+    let candidates = [
+        u.add(Fe::from_u64(4).mul(y.square())),
+        x.div(y).neg().sub(u).half(),
+        x.div(y).sub(u).half(),
+    ];
+    *candidates
+        .iter()
+        .find(|x| is_valid_x(**x))
+        .expect("one of the candidates is on the curve")
+}
+
+/// a t such that xswiftec(u, t) = x, if the case allows one
+fn xswiftec_inv(x: Fe, u: Fe, case: u8) -> Option<Fe> {
+    let (v, s) = if case & 2 == 0 {
+        if is_valid_x(x.neg().sub(u)) {
+            return None;
+        }
+        let s = curve(u).neg().div(u.square().add(u.mul(x)).add(x.square()));
+        (x, s)
+    } else {
+        let s = x.sub(u);
+        if s.is_zero() {
+            return None;
+        }
+        let r = s
+            .neg()
+            .mul(
+                Fe::from_u64(4)
+                    .mul(curve(u))
+                    .add(Fe::from_u64(3).mul(s).mul(u.square())),
+            )
+            .sqrt()?;
+        if case & 1 == 1 && r.is_zero() {
+            return None;
+        }
+        (r.div(s).sub(u).half(), s)
+    };
+    let w = s.sqrt()?;
+    let one = Fe::from_u64(1);
+    let low = u.mul(one.sub(minus_3_sqrt())).half().add(v);
+    let high = u.mul(one.add(minus_3_sqrt())).half().add(v);
+    Some(match case & 5 {
+        0 => w.neg().mul(low),
+        1 => w.mul(high),
+        4 => w.mul(low),
+        _ => w.neg().mul(high),
+    })
+}
+
+/// the 64 byte ElligatorSwift encoding of a public key
+fn ellswift_encode(key: &PublicKey) -> [u8; ELLSWIFT_LEN] {
+    let x = Fe::from_bytes(&key.serialize()[1..]);
+    let mut rng = thread_rng();
+    loop {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        let u = Fe::from_bytes(&bytes);
+        if let Some(t) = xswiftec_inv(x, u, rng.gen_range(0, 8)) {
+            if xswiftec(u, t) == x {
+                let mut encoded = [0u8; ELLSWIFT_LEN];
+                encoded[..32].copy_from_slice(&u.to_bytes());
+                encoded[32..].copy_from_slice(&t.to_bytes());
+                return encoded;
+            }
+        }
+    }
+}
+
+/// x coordinate of the key of an ElligatorSwift encoding
+fn ellswift_decode(encoded: &[u8]) -> [u8; 32] {
+    xswiftec(
+        Fe::from_bytes(&encoded[..32]),
+        Fe::from_bytes(&encoded[32..]),
+    )
+    .to_bytes()
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// ChaCha20 of RFC 8439 with a 96 bit nonce of a counter and an epoch
+fn chacha20(key: &[u8], counter: u32, epoch: u64) -> ChaCha20 {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&counter.to_le_bytes());
+    nonce[4..].copy_from_slice(&epoch.to_le_bytes());
+    ChaCha20::new(key, &nonce)
+}
+
+/// ChaCha20 rekeyed from its own keystream, encrypts packet lengths
+struct FsChaCha20 {
+    cipher: ChaCha20,
+    chunks: u64,
+}
+
+impl FsChaCha20 {
+    fn new(key: &[u8]) -> FsChaCha20 {
+        FsChaCha20 {
+            cipher: chacha20(key, 0, 0),
+            chunks: 0,
+        }
+    }
+
+    fn crypt(&mut self, data: &mut [u8]) {
+        let input = data.to_vec();
+        self.cipher.process(&input, data);
+        self.chunks += 1;
+        if self.chunks.is_multiple_of(REKEY_INTERVAL) {
+            let mut key = [0u8; 32];
+            self.cipher.process(&[0u8; 32], &mut key);
+            self.cipher = chacha20(&key, 0, self.chunks / REKEY_INTERVAL);
+        }
+    }
+}
+
+/// ChaCha20-Poly1305 rekeyed from its own keystream, seals packets
+struct FsChaCha20Poly1305 {
+    key: [u8; 32],
+    packets: u64,
+}
+
+impl FsChaCha20Poly1305 {
+    fn new(key: &[u8]) -> FsChaCha20Poly1305 {
+        let mut k = [0u8; 32];
+        k.copy_from_slice(key);
+        FsChaCha20Poly1305 { key: k, packets: 0 }
+    }
+
+    /// encrypt or decrypt text in place, returns the tag of the ciphertext
+    fn crypt(&mut self, aad: &[u8], text: &mut [u8], encrypt: bool) -> [u8; TAG_LEN] {
+        let counter = (self.packets % REKEY_INTERVAL) as u32;
+        let epoch = self.packets / REKEY_INTERVAL;
+        let tag = aead(&self.key, counter, epoch, aad, text, encrypt);
+        self.packets += 1;
+        if self.packets.is_multiple_of(REKEY_INTERVAL) {
+            let mut key = [0u8; 32];
+            aead(&self.key, 0xffff_ffff, epoch, &[], &mut key, true);
+            self.key = key;
+        }
+        tag
+    }
+}
+
+/// AEAD_CHACHA20_POLY1305 of RFC 8439 in place, returns the tag over the ciphertext
+fn aead(
+    key: &[u8],
+    counter: u32,
+    epoch: u64,
+    aad: &[u8],
+    text: &mut [u8],
+    encrypt: bool,
+) -> [u8; TAG_LEN] {
+    let mut cipher = chacha20(key, counter, epoch);
+    let mut block = [0u8; 64];
+    cipher.process(&[0u8; 64], &mut block);
+    let mut mac = Poly1305::new(&block[..32]);
+    let pad = |mac: &mut Poly1305, len: usize| mac.input(&[0u8; 16][..(16 - len % 16) % 16]);
+    mac.input(aad);
+    pad(&mut mac, aad.len());
+    if encrypt {
+        let input = text.to_vec();
+        cipher.process(&input, text);
+        mac.input(text);
+    } else {
+        mac.input(text);
+        let input = text.to_vec();
+        cipher.process(&input, text);
+    }
+    pad(&mut mac, text.len());
+    mac.input(&(aad.len() as u64).to_le_bytes());
+    mac.input(&(text.len() as u64).to_le_bytes());
+    let mut tag = [0u8; TAG_LEN];
+    mac.raw_result(&mut tag);
+    tag
+}
+
+/// Our side of a handshake in progress
+pub struct Handshake {
+    secret: SecretKey,
+    ellswift: [u8; ELLSWIFT_LEN],
+    garbage: Vec<u8>,
+    initiator: bool,
+}
+
+impl Handshake {
+    /// a fresh ephemeral key and random garbage, the initiator connected to the responder
+    pub fn new(network: Network, initiator: bool) -> Handshake {
+        let secp = Secp256k1::signing_only();
+        let mut rng = thread_rng();
+        let (secret, ellswift) = loop {
+            let mut bytes = [0u8; 32];
+            rng.fill_bytes(&mut bytes);
+            if let Ok(secret) = SecretKey::from_slice(&bytes) {
+                let ellswift = ellswift_encode(&PublicKey::from_secret_key(&secp, &secret));
+                // a responder would take a key starting with the magic for a v1 version message
+                if !initiator || ellswift[..4] != network.magic().to_le_bytes() {
+                    break (secret, ellswift);
+                }
+            }
+        };
+        let mut garbage = vec![0u8; rng.gen_range(0, MAX_GARBAGE_LEN + 1)];
+        rng.fill_bytes(garbage.as_mut_slice());
+        Handshake {
+            secret,
+            ellswift,
+            garbage,
+            initiator,
+        }
+    }
+
+    /// the encoded key and garbage to send first
+    pub fn hello(&self) -> Vec<u8> {
+        let mut hello = self.ellswift.to_vec();
+        hello.extend_from_slice(self.garbage.as_slice());
+        hello
+    }
+
+    /// garbage sent, authenticated with the first packet
+    pub fn garbage(&self) -> &[u8] {
+        self.garbage.as_slice()
+    }
+
+    /// derive the ciphers from the encoded key of the other side
+    pub fn complete(self, theirs: &[u8], network: Network) -> Result<Cipher, Error> {
+        let secp = Secp256k1::verification_only();
+        let mut point = [0x02u8; 33];
+        point[1..].copy_from_slice(&ellswift_decode(theirs));
+        let mut shared = PublicKey::from_slice(&point).map_err(|_| Error::Peer("invalid key"))?;
+        shared
+            .mul_assign(&secp, &self.secret[..])
+            .map_err(|_| Error::Peer("invalid key"))?;
+        let (a, b) = if self.initiator {
+            (&self.ellswift[..], theirs)
+        } else {
+            (theirs, &self.ellswift[..])
+        };
+        let secret = tagged_hash(
+            "bip324_ellswift_xonly_ecdh",
+            &[a, b, &shared.serialize()[1..]],
+        );
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&network.magic().to_le_bytes());
+        let mut prk = [0u8; 32];
+        hkdf_extract(Sha256::new(), &salt, &secret, &mut prk);
+        let expand = |info: &str| {
+            let mut okm = [0u8; 32];
+            hkdf_expand(Sha256::new(), &prk, info.as_bytes(), &mut okm);
+            okm
+        };
+        let terminators = expand("garbage_terminators");
+        let (initiator, responder) = (
+            (expand("initiator_L"), expand("initiator_P")),
+            (expand("responder_L"), expand("responder_P")),
+        );
+        let (send, receive) = if self.initiator {
+            (initiator, responder)
+        } else {
+            (responder, initiator)
+        };
+        let (ours, theirs) = if self.initiator {
+            (&terminators[..16], &terminators[16..])
+        } else {
+            (&terminators[16..], &terminators[..16])
+        };
+        let mut cipher = Cipher {
+            send_length: FsChaCha20::new(&send.0),
+            send_packet: FsChaCha20Poly1305::new(&send.1),
+            receive_length: FsChaCha20::new(&receive.0),
+            receive_packet: FsChaCha20Poly1305::new(&receive.1),
+            session_id: expand("session_id"),
+            terminator: [0u8; TERMINATOR_LEN],
+            their_terminator: [0u8; TERMINATOR_LEN],
+        };
+        cipher.terminator.copy_from_slice(ours);
+        cipher.their_terminator.copy_from_slice(theirs);
+        Ok(cipher)
+    }
+}
+
+/// Ciphers of both directions of an established session
+pub struct Cipher {
+    send_length: FsChaCha20,
+    send_packet: FsChaCha20Poly1305,
+    receive_length: FsChaCha20,
+    receive_packet: FsChaCha20Poly1305,
+    session_id: [u8; 32],
+    terminator: [u8; TERMINATOR_LEN],
+    their_terminator: [u8; TERMINATOR_LEN],
+}
+
+impl Cipher {
+    /// the same on both sides unless the handshake was intercepted
+    pub fn session_id(&self) -> &[u8; 32] {
+        &self.session_id
+    }
+
+    /// ends our garbage
+    pub fn terminator(&self) -> &[u8; TERMINATOR_LEN] {
+        &self.terminator
+    }
+
+    /// ends the garbage of the other side
+    pub fn their_terminator(&self) -> &[u8; TERMINATOR_LEN] {
+        &self.their_terminator
+    }
+
+    /// encrypt a packet, aad is our garbage for the first packet
+    pub fn encrypt(&mut self, contents: &[u8], aad: &[u8], decoy: bool) -> Vec<u8> {
+        let mut packet = (contents.len() as u32).to_le_bytes()[..3].to_vec();
+        self.send_length.crypt(&mut packet[..3]);
+        packet.push(if decoy { IGNORE_BIT } else { 0 });
+        packet.extend_from_slice(contents);
+        let tag = self.send_packet.crypt(aad, &mut packet[3..], true);
+        packet.extend_from_slice(&tag);
+        packet
+    }
+
+    /// length of the contents of the packet starting with these 3 bytes
+    pub fn decrypt_length(&mut self, length: &[u8]) -> usize {
+        let mut length = [length[0], length[1], length[2], 0];
+        self.receive_length.crypt(&mut length[..3]);
+        u32::from_le_bytes(length) as usize
+    }
+
+    /// decrypt the rest of a packet after its length, returns None for a decoy
+    pub fn decrypt(&mut self, sealed: &[u8], aad: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if sealed.len() < 1 + TAG_LEN {
+            return Err(Error::Peer("packet too short"));
+        }
+        let (text, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        let mut text = text.to_vec();
+        let expected = self.receive_packet.crypt(aad, text.as_mut_slice(), false);
+        if !fixed_time_eq(&expected, tag) {
+            return Err(Error::Peer("packet fails authentication"));
+        }
+        if text[0] & IGNORE_BIT != 0 {
+            return Ok(None);
+        }
+        Ok(Some(text[1..].to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+
+    use super::*;
+
+    fn fe(hex: &str) -> Fe {
+        Fe::from_bytes(&Vec::<u8>::from_hex(hex).unwrap())
+    }
+
+    #[test]
+    fn bip324() {
+        // RFC 8439 2.8.2
+        let key =
+            Vec::<u8>::from_hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f")
+                .unwrap();
+        let aad = Vec::<u8>::from_hex("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let mut text = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+                         tip for the future, sunscreen would be it."
+            .to_vec();
+        let tag = aead(&key, 7, 0x4746_4544_4342_4140, &aad, &mut text, true);
+        assert_eq!(
+            tag.to_vec(),
+            Vec::<u8>::from_hex("1ae10b594f09e26a7e902ecbd0600691").unwrap()
+        );
+        assert_eq!(&text[..4], &[0xd3, 0x1a, 0x8d, 0x34]);
+
+        let secp = Secp256k1::new();
+        let secret = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let key = PublicKey::from_secret_key(&secp, &secret);
+        for _ in 0..8 {
+            assert_eq!(
+                ellswift_decode(&ellswift_encode(&key)),
+                key.serialize()[1..]
+            );
+        }
+
+        let initiator = Handshake::new(Network::Bitcoin, true);
+        let responder = Handshake::new(Network::Bitcoin, false);
+        let (ours, theirs) = (initiator.hello(), responder.hello());
+        let garbage = initiator.garbage().to_vec();
+        let mut alice = initiator.complete(&theirs[..64], Network::Bitcoin).unwrap();
+        let mut bob = responder.complete(&ours[..64], Network::Bitcoin).unwrap();
+        assert_eq!(alice.session_id(), bob.session_id());
+        assert_eq!(alice.terminator(), bob.their_terminator());
+
+        // enough packets to rekey both ciphers
+        for i in 0..500usize {
+            let aad = if i == 0 { garbage.as_slice() } else { &[] };
+            let contents = vec![i as u8; i];
+            let packet = alice.encrypt(&contents, aad, i == 1);
+            assert_eq!(bob.decrypt_length(&packet[..3]), i);
+            let decrypted = bob.decrypt(&packet[3..], aad).unwrap();
+            assert_eq!(decrypted, if i == 1 { None } else { Some(contents) });
+        }
+        let mut packet = bob.encrypt(b"ping", &[], false);
+        packet[5] ^= 1;
+        alice.decrypt_length(&packet[..3]);
+        assert!(alice.decrypt(&packet[3..], &[]).is_err());
+    }
+
+    #[test]
+    fn ellswift_decode_vector() {
+        // ellswift_decode_test_vectors.csv of BIP324, u = t = 0
+        assert_eq!(
+            ellswift_decode(&[0u8; ELLSWIFT_LEN]).to_hex(),
+            "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c"
+        );
+    }
+
+    #[test]
+    fn xswiftec_inv_vector() {
+        // xswiftec_inv_test_vectors.csv of BIP324, first row
+        let u = fe("05ff6bdad900fc3261bc7fe34e2fb0f569f06e091ae437d3a52e9da0cbfb9590");
+        let x = fe("80cdf63774ec7022c89a5a8558e373a279170285e0ab27412dbce510bdfe23fc");
+        let expected = [
+            None,
+            None,
+            Some("45654798ece071ba79286d04f7f3eb1c3f1d17dd883610f2ad2efd82a287466b"),
+            Some("0aeaa886f6b76c7158452418cbf5033adc5747e9e9b5d3b2303db96936528557"),
+            None,
+            None,
+            Some("ba9ab867131f8e4586d792fb080c14e3c0e2e82277c9ef0d52d1027c5d78b5c4"),
+            Some("f51557790948938ea7badbe7340afcc523a8b816164a2c4dcfc24695c9ad76d8"),
+        ];
+        for (case, t) in expected.iter().enumerate() {
+            let inverse = xswiftec_inv(x, u, case as u8);
+            assert_eq!(inverse.map(|t| t.to_bytes().to_hex()), t.map(String::from));
+            if let Some(t) = inverse {
+                assert_eq!(xswiftec(u, t), x);
+            }
+        }
+    }
+
+    #[test]
+    fn packet_vector() {
+        // packet_encoding_test_vectors.csv of BIP324, first row
+        let secret = SecretKey::from_slice(
+            &Vec::<u8>::from_hex(
+                "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let mut ellswift = [0u8; ELLSWIFT_LEN];
+        ellswift.copy_from_slice(
+            &Vec::<u8>::from_hex(
+                "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa1\
+                 86f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+            )
+            .unwrap(),
+        );
+        let theirs = Vec::<u8>::from_hex(
+            "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafa\
+             ffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+        )
+        .unwrap();
+        assert_eq!(
+            ellswift_decode(&ellswift).to_hex(),
+            "19e965bc20fc40614e33f2f82d4eeff81b5e7516b12a5c6c0d6053527eba0923"
+        );
+        assert_eq!(
+            ellswift_decode(&theirs).to_hex(),
+            "0c71defa3fafd74cb835102acd81490963f6b72d889495e06561375bd65f6ffc"
+        );
+        let handshake = Handshake {
+            secret,
+            ellswift,
+            garbage: Vec::new(),
+            initiator: true,
+        };
+        let mut cipher = handshake.complete(&theirs, Network::Bitcoin).unwrap();
+        assert_eq!(
+            cipher.session_id().to_hex(),
+            "ce72dffb015da62b0d0f5474cab8bc72605225b0cee3f62312ec680ec5f41ba5"
+        );
+        assert_eq!(
+            cipher.terminator().to_hex(),
+            "faef555dfcdb936425d84aba524758f3"
+        );
+        assert_eq!(
+            cipher.their_terminator().to_hex(),
+            "02cb8ff24307a6e27de3b4e7ea3fa65b"
+        );
+        cipher.encrypt(&[], &[], false);
+        assert_eq!(
+            cipher.encrypt(&[0x8e], &[], false).to_hex(),
+            "7530d2a18720162ac09c25329a60d75adf36eda3c3"
+        );
+    }
+
+    #[test]
+    fn rekey() {
+        // initiator keys of the first packet vector, sending to itself past four rekeys
+        let length =
+            Vec::<u8>::from_hex("9a6478b5fbab1f4dd2f78994b774c03211c78312786e602da75a0d1767fb55cf")
+                .unwrap();
+        let packet =
+            Vec::<u8>::from_hex("7d0c7820ba6a4d29ce40baf2caa6035e04f1e1cefd59f3e7e59e9e5af84f1f51")
+                .unwrap();
+        let mut cipher = Cipher {
+            send_length: FsChaCha20::new(&length),
+            send_packet: FsChaCha20Poly1305::new(&packet),
+            receive_length: FsChaCha20::new(&length),
+            receive_packet: FsChaCha20Poly1305::new(&packet),
+            session_id: [0u8; 32],
+            terminator: [0u8; TERMINATOR_LEN],
+            their_terminator: [0u8; TERMINATOR_LEN],
+        };
+        for _ in 0..999 {
+            let packet = cipher.encrypt(&[], &[], false);
+            assert_eq!(cipher.decrypt_length(&packet[..3]), 0);
+            assert_eq!(cipher.decrypt(&packet[3..], &[]).unwrap(), Some(Vec::new()));
+        }
+        let contents = Vec::<u8>::from_hex("3eb1d4e98035cfd8eeb29bac969ed3824a").unwrap();
+        let packet = cipher.encrypt(&contents, &[], false);
+        assert_eq!(
+            packet.to_hex(),
+            "41ef16b97b9fa2cce2d2bb751057a668b0209fab17744c2779e4ae9d4457897b11c5b2c835"
+        );
+        assert_eq!(cipher.decrypt_length(&packet[..3]), contents.len());
+        assert_eq!(cipher.decrypt(&packet[3..], &[]).unwrap(), Some(contents));
+    }
+}
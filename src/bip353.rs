@@ -0,0 +1,160 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # BIP353 DNS payment instructions
+//!
+//! Resolve human readable names like ₿alice@example.com to BIP21 payment instructions.
+//! This library does not talk DNS itself, supply a resolver that validates DNSSEC.
+//!
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::network::constants::Network;
+
+use bip21::PaymentUri;
+use error::Error;
+
+/// A resolver of DNS TXT records
+pub trait DnssecResolver {
+    /// Return the TXT records of a fully qualified name.
+    /// Each record is the list of its character strings.
+    /// Implementations must only return records whose DNSSEC signature chain validated up to
+    /// the root and should fail otherwise, since an unauthenticated answer could redirect
+    /// payments.
+    fn resolve_txt(&self, name: &str) -> Result<Vec<Vec<String>>, Error>;
+}
+
+/// A human readable name user@domain
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct HumanReadableName {
+    user: String,
+    domain: String,
+}
+
+impl FromStr for HumanReadableName {
+    type Err = Error;
+
+    /// parse user@domain with optional leading ₿
+    fn from_str(s: &str) -> Result<HumanReadableName, Error> {
+        let s = s.trim_start_matches('₿');
+        let at = s
+            .find('@')
+            .ok_or(Error::Dns("name should be user@domain"))?;
+        let (user, domain) = (&s[..at], &s[at + 1..]);
+        if user.is_empty() || domain.is_empty() {
+            return Err(Error::Dns("name should be user@domain"));
+        }
+        if !s.is_ascii() || s[at + 1..].contains('@') {
+            return Err(Error::Dns("invalid characters in name"));
+        }
+        if user.len() + domain.len() + "._bitcoin-payment.".len() > 255 {
+            return Err(Error::Dns("name is too long"));
+        }
+        Ok(HumanReadableName {
+            user: user.to_ascii_lowercase(),
+            domain: domain.trim_end_matches('.').to_ascii_lowercase(),
+        })
+    }
+}
+
+impl HumanReadableName {
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// the DNS name holding the payment instructions
+    pub fn dns_name(&self) -> String {
+        format!("{}.user._bitcoin-payment.{}.", self.user, self.domain)
+    }
+}
+
+impl fmt::Display for HumanReadableName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "₿{}@{}", self.user, self.domain)
+    }
+}
+
+/// resolve a human readable name to payment instructions
+/// the result can be used to add a recipient to a transaction
+pub fn resolve<R: DnssecResolver>(
+    resolver: &R,
+    name: &HumanReadableName,
+    network: Network,
+) -> Result<PaymentUri, Error> {
+    let mut instructions = resolver
+        .resolve_txt(name.dns_name().as_str())?
+        .into_iter()
+        .map(|strings| strings.concat())
+        .filter(|txt| txt.len() >= 8 && txt[..8].eq_ignore_ascii_case("bitcoin:"));
+    let txt = instructions
+        .next()
+        .ok_or(Error::Dns("no payment instructions found"))?;
+    if instructions.next().is_some() {
+        return Err(Error::Dns("ambiguous payment instructions"));
+    }
+    let uri = PaymentUri::parse(txt.as_str())?;
+    if let Some(ref address) = uri.address {
+        if address.network != network {
+            return Err(Error::Network);
+        }
+    }
+    Ok(uri)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Mock(Vec<Vec<String>>);
+
+    impl DnssecResolver for Mock {
+        fn resolve_txt(&self, name: &str) -> Result<Vec<Vec<String>>, Error> {
+            assert_eq!(name, "alice.user._bitcoin-payment.example.com.");
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn resolve_name() {
+        let name = HumanReadableName::from_str("₿Alice@Example.com").unwrap();
+        assert_eq!(name.to_string(), "₿alice@example.com");
+        assert_eq!(
+            "alice@example.com".parse::<HumanReadableName>().unwrap(),
+            name
+        );
+        let resolver = Mock(vec![
+            vec!["v=spf1 -all".to_string()],
+            vec![
+                "bitcoin:1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string(),
+                "?amount=0.001".to_string(),
+            ],
+        ]);
+        let uri = resolve(&resolver, &name, Network::Bitcoin).unwrap();
+        assert_eq!(uri.amount, Some(100000));
+        assert!(resolve(&resolver, &name, Network::Testnet).is_err());
+
+        let ambiguous = Mock(vec![
+            vec!["bitcoin:1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string()],
+            vec!["bitcoin:?lno=lno1abc".to_string()],
+        ]);
+        assert!(resolve(&ambiguous, &name, Network::Bitcoin).is_err());
+        assert!(HumanReadableName::from_str("example.com").is_err());
+    }
+}
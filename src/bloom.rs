@@ -0,0 +1,522 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Bloom filter sync
+//!
+//! A light client following BIP37, for nodes that serve no compact block filters. BloomSync
+//! loads a bloom filter of the scripts and coins of the wallet into a peer, then asks for
+//! filtered blocks. The peer answers with a merkle block, the header and a partial merkle tree
+//! of the transactions matching the filter, followed by those transactions. They are
+//! processed into coins with the proof the partial tree gives.
+//!
+//! Prefer NeutrinoSync, BIP37 is inferior in privacy and security. The peer learns the
+//! filter, false positives are the only cover of the wallet and filters loaded as the wallet
+//! grows can be intersected to tell its scripts. The peer may also omit matching
+//! transactions, the partial merkle tree does not show. Use bloom sync with a node you trust.
+//!
+use std::io;
+
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::consensus::{encode, serialize, Decodable, Encodable};
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::{BlockHash, OutPoint, Transaction};
+use rand::{thread_rng, RngCore};
+
+use account::MasterAccount;
+use backend::{wallet_scripts, ChainBackend};
+use coins::Coins;
+use error::Error;
+use proved::ProvedTransaction;
+
+/// largest filter a node accepts, in bytes
+const MAX_FILTER_SIZE: usize = 36_000;
+/// most hash functions a node accepts
+const MAX_HASH_FUNCS: u32 = 50;
+/// the node adds outpoints of matching outputs to the filter, so spends of them match
+const BLOOM_UPDATE_ALL: u8 = 1;
+
+/// A BIP37 bloom filter
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BloomFilter {
+    content: Vec<u8>,
+    hashes: u32,
+    tweak: u32,
+    flags: u8,
+}
+
+impl BloomFilter {
+    /// a filter for elements at a false positive rate, the tweak varies its hash functions
+    pub fn new(elements: usize, rate: f64, tweak: u32) -> BloomFilter {
+        let ln2 = std::f64::consts::LN_2;
+        let elements = elements.max(1) as f64;
+        let bits = (-1.0 / (ln2 * ln2) * elements * rate.ln()).min(MAX_FILTER_SIZE as f64 * 8.0);
+        let size = ((bits as usize) / 8).max(1);
+        let hashes = ((size * 8) as f64 / elements * ln2) as u32;
+        BloomFilter {
+            content: vec![0u8; size],
+            hashes: hashes.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+            flags: BLOOM_UPDATE_ALL,
+        }
+    }
+
+    /// a filter of the scripts and coins of the wallet with a random tweak
+    pub fn for_wallet(master: &MasterAccount, coins: &Coins, rate: f64) -> BloomFilter {
+        let mut elements = wallet_scripts(master)
+            .iter()
+            .flat_map(|s| pushes(s.instructions()))
+            .collect::<Vec<_>>();
+        elements.extend(
+            coins
+                .confirmed()
+                .keys()
+                .chain(coins.unconfirmed().keys())
+                .map(serialize),
+        );
+        let mut filter = BloomFilter::new(elements.len(), rate, thread_rng().next_u32());
+        for element in elements {
+            filter.insert(&element);
+        }
+        filter
+    }
+
+    /// add data
+    pub fn insert(&mut self, data: &[u8]) {
+        for n in 0..self.hashes {
+            let bit = self.bit(n, data);
+            self.content[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// true if data was added, or as a false positive
+    pub fn contains(&self, data: &[u8]) -> bool {
+        (0..self.hashes).all(|n| {
+            let bit = self.bit(n, data);
+            self.content[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// match a transaction as a node does, adding the outpoints of matching outputs
+    pub fn update(&mut self, transaction: &Transaction) -> bool {
+        let txid = transaction.txid();
+        let mut found = self.contains(&txid[..]);
+        for (vout, output) in transaction.output.iter().enumerate() {
+            if pushes(output.script_pubkey.instructions()).any(|d| self.contains(&d)) {
+                found = true;
+                if self.flags == BLOOM_UPDATE_ALL {
+                    self.insert(&serialize(&OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    }));
+                }
+            }
+        }
+        found
+            || transaction.input.iter().any(|input| {
+                self.contains(&serialize(&input.previous_output))
+                    || pushes(input.script_sig.instructions()).any(|d| self.contains(&d))
+            })
+    }
+
+    fn bit(&self, n: u32, data: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xfba4_c795).wrapping_add(self.tweak);
+        murmur3(seed, data) as usize % (self.content.len() * 8)
+    }
+}
+
+impl Encodable for BloomFilter {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.content.consensus_encode(&mut w)?;
+        len += self.hashes.consensus_encode(&mut w)?;
+        len += self.tweak.consensus_encode(&mut w)?;
+        len += self.flags.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for BloomFilter {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<BloomFilter, encode::Error> {
+        let content = Vec::<u8>::consensus_decode(&mut d)?;
+        let hashes = u32::consensus_decode(&mut d)?;
+        if content.is_empty() || content.len() > MAX_FILTER_SIZE || hashes > MAX_HASH_FUNCS {
+            return Err(encode::Error::ParseFailed("bloom filter too large"));
+        }
+        Ok(BloomFilter {
+            content,
+            hashes,
+            tweak: u32::consensus_decode(&mut d)?,
+            flags: u8::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// data pushed by a script
+fn pushes<'a, I>(instructions: I) -> impl Iterator<Item = Vec<u8>> + 'a
+where
+    I: Iterator<Item = Result<Instruction<'a>, bitcoin::blockdata::script::Error>> + 'a,
+{
+    instructions.filter_map(|i| match i {
+        Ok(Instruction::PushBytes(data)) if !data.is_empty() => Some(data.to_vec()),
+        _ => None,
+    })
+}
+
+/// 32 bit murmur3 hash
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    let mut h = seed;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        h ^= mix(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .enumerate()
+            .fold(0u32, |k, (i, b)| k | (*b as u32) << (8 * i));
+        h ^= mix(k);
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// A peer serving BIP37 filtered blocks
+pub trait BloomPeer: ChainBackend {
+    /// replace the filter of the connection
+    fn load_filter(&self, filter: &BloomFilter) -> Result<(), Error>;
+    /// merkle block with the transactions matching the filter loaded
+    fn merkle_block(&self, hash: &BlockHash) -> Result<(MerkleBlock, Vec<Transaction>), Error>;
+}
+
+/// Outcome of a sync
+#[derive(Clone, Debug, Default)]
+pub struct BloomUpdate {
+    /// height of the best chain after the sync
+    pub height: u32,
+    /// blocks unwound as they left the best chain
+    pub unwound: usize,
+    /// filtered blocks fetched
+    pub blocks: usize,
+    /// proved transactions of the wallet, false positives left out
+    pub proofs: Vec<ProvedTransaction>,
+}
+
+/// Follows the best chain of a peer serving BIP37 filtered blocks
+#[derive(Clone, Debug)]
+pub struct BloomSync {
+    start: u32,
+    /// hashes of blocks from start
+    chain: Vec<BlockHash>,
+    rate: f64,
+}
+
+impl BloomSync {
+    /// sync from height start, e.g. the height at the birth of the wallet
+    pub fn new(start: u32) -> BloomSync {
+        BloomSync {
+            start,
+            chain: Vec::new(),
+            rate: 0.001,
+        }
+    }
+
+    /// false positive rate of filters, higher hides the wallet better at the cost of bandwidth
+    pub fn false_positive_rate(mut self, rate: f64) -> BloomSync {
+        self.rate = rate;
+        self
+    }
+
+    /// height and hash of the last synced block
+    pub fn tip(&self) -> Option<(u32, BlockHash)> {
+        self.chain
+            .last()
+            .map(|hash| (self.start + self.chain.len() as u32 - 1, *hash))
+    }
+
+    /// height of a synced block
+    pub fn height(&self, hash: &BlockHash) -> Option<u32> {
+        self.chain
+            .iter()
+            .rposition(|h| h == hash)
+            .map(|p| self.start + p as u32)
+    }
+
+    /// unwind blocks that left the best chain, then load a filter of the wallet and process
+    /// the filtered blocks of new blocks into coins
+    /// A block paying lookahead scripts is fetched again with a filter holding those added.
+    pub fn sync<P: BloomPeer>(
+        &mut self,
+        peer: &P,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+    ) -> Result<BloomUpdate, Error> {
+        let mut update = BloomUpdate::default();
+        while let Some((height, hash)) = self.tip() {
+            match peer.header(height)? {
+                Some(ref header) if header.block_hash() == hash => break,
+                _ => {
+                    coins.unwind_tip(&hash);
+                    self.chain.pop();
+                    update.unwound += 1;
+                }
+            }
+        }
+        let tip = peer.tip()?;
+        let mut scripts = wallet_scripts(master).len();
+        peer.load_filter(&BloomFilter::for_wallet(master, coins, self.rate))?;
+        for height in self.start + self.chain.len() as u32..=tip {
+            let hash = peer
+                .header(height)?
+                .ok_or(Error::Peer("header of the best chain missing"))?
+                .block_hash();
+            let mut proofs = filtered(peer, &hash)?;
+            update.blocks += 1;
+            coins.process_proved(master, &hash, &proofs);
+            while wallet_scripts(master).len() != scripts {
+                scripts = wallet_scripts(master).len();
+                peer.load_filter(&BloomFilter::for_wallet(master, coins, self.rate))?;
+                let more = filtered(peer, &hash)?;
+                update.blocks += 1;
+                if more.len() > proofs.len() {
+                    coins.unwind_tip(&hash);
+                    proofs = more;
+                    coins.process_proved(master, &hash, &proofs);
+                }
+            }
+            update.proofs.extend(
+                proofs
+                    .iter()
+                    .filter_map(|p| coins.proofs().get(&p.get_transaction().txid()))
+                    .filter(|p| *p.get_block_hash() == hash)
+                    .cloned(),
+            );
+            self.chain.push(hash);
+        }
+        coins.update_tip(tip, |hash| self.height(hash));
+        update.height = tip;
+        Ok(update)
+    }
+}
+
+/// proved transactions of a filtered block
+fn filtered<P: BloomPeer>(peer: &P, hash: &BlockHash) -> Result<Vec<ProvedTransaction>, Error> {
+    let (block, transactions) = peer.merkle_block(hash)?;
+    if block.header.block_hash() != *hash {
+        return Err(Error::Peer("merkle block of an other block"));
+    }
+    ProvedTransaction::from_merkle_block(&block, &transactions)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::hashes::Hash;
+    use bitcoin::util::bip158::BlockFilter;
+    use bitcoin::{
+        Block, BlockHeader, Network, Script, TxIn, TxMerkleNode, TxOut, Txid, WPubkeyHash,
+    };
+    use std::cell::{Cell, RefCell};
+
+    use backend::TxStatus;
+    use fee::FeeRate;
+    use fixtures::master_account;
+
+    use super::*;
+
+    struct Node {
+        blocks: Vec<Block>,
+        filter: RefCell<Option<BloomFilter>>,
+        requests: Cell<usize>,
+    }
+
+    impl ChainBackend for Node {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(self.blocks.get(height as usize).map(|b| b.header))
+        }
+
+        fn block(&self, _: &BlockHash) -> Result<Block, Error> {
+            Err(Error::Peer("filtered blocks only"))
+        }
+
+        fn filter(&self, _: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            Ok(None)
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    impl BloomPeer for Node {
+        fn load_filter(&self, filter: &BloomFilter) -> Result<(), Error> {
+            *self.filter.borrow_mut() = Some(filter.clone());
+            Ok(())
+        }
+
+        fn merkle_block(&self, hash: &BlockHash) -> Result<(MerkleBlock, Vec<Transaction>), Error> {
+            self.requests.set(self.requests.get() + 1);
+            let block = self
+                .blocks
+                .iter()
+                .find(|b| b.block_hash() == *hash)
+                .ok_or(Error::Peer("unknown block"))?;
+            let mut filter = self.filter.borrow_mut();
+            let filter = filter.as_mut().ok_or(Error::Peer("no filter loaded"))?;
+            let matched = block
+                .txdata
+                .iter()
+                .filter(|t| filter.update(t))
+                .cloned()
+                .collect::<Vec<_>>();
+            let txids = matched.iter().map(|t| t.txid()).collect();
+            Ok((MerkleBlock::from_block(block, &txids), matched))
+        }
+    }
+
+    #[test]
+    fn bloom_filter() {
+        // test vectors of Bitcoin Core
+        assert_eq!(murmur3(0, &[]), 0);
+        assert_eq!(murmur3(0xfba4_c795, &[]), 0x6a39_6f08);
+        assert_eq!(murmur3(0, &[0]), 0x514e_28b7);
+        assert_eq!(murmur3(0, &[0x21, 0x43, 0x65, 0x87]), 0xf55b_516b);
+        let mut filter = BloomFilter::new(3, 0.01, 0);
+        let element = Vec::<u8>::from_hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        filter.insert(&element);
+        assert!(filter.contains(&element));
+        assert!(!filter
+            .contains(&Vec::<u8>::from_hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap()));
+        filter.insert(&Vec::<u8>::from_hex("b5a2c786d9ef4658287ced5914b37a1b4aa32eee").unwrap());
+        filter.insert(&Vec::<u8>::from_hex("b9300670b4c5366e95b2699e8b18bc75e5f729c5").unwrap());
+        assert_eq!(serialize(&filter).to_hex(), "03614e9b050000000000000001");
+        assert_eq!(
+            encode::deserialize::<BloomFilter>(&serialize(&filter)).unwrap(),
+            filter
+        );
+    }
+
+    #[test]
+    fn bloom_sync() {
+        let (mut master, _) = master_account(Network::Testnet);
+        let account = master.get((0, 0)).unwrap();
+        let last = account.get_key(9).unwrap().address.script_pubkey();
+        // beyond the lookahead until last is seen
+        let public = account.compute_base_public_key(15).unwrap();
+        let beyond = Script::new_v0_wpkh(&WPubkeyHash::hash(&public.to_bytes()));
+        let transaction = |previous_output: OutPoint, script: Script| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script,
+            }],
+        };
+        let mine = |prev: &Block, txdata: Vec<Transaction>| {
+            let mut block = prev.clone();
+            block.header.prev_blockhash = prev.block_hash();
+            block.header.merkle_root = TxMerkleNode::default();
+            block.txdata = txdata;
+            block.header.merkle_root = block.merkle_root();
+            block
+        };
+        let coinbase = |n: u32| {
+            transaction(
+                OutPoint {
+                    txid: Txid::default(),
+                    vout: n,
+                },
+                Script::new(),
+            )
+        };
+        let genesis = genesis_block(Network::Testnet);
+        let funding = transaction(coinbase(1).input[0].previous_output, last);
+        let later = transaction(coinbase(2).input[0].previous_output, beyond.clone());
+        let one = mine(
+            &genesis,
+            vec![coinbase(3), funding.clone(), coinbase(4), later.clone()],
+        );
+        let spend = transaction(
+            OutPoint {
+                txid: funding.txid(),
+                vout: 0,
+            },
+            Script::new(),
+        );
+        let two = mine(&one, vec![coinbase(5), coinbase(6), spend]);
+        let node = Node {
+            blocks: vec![genesis, one, two],
+            filter: RefCell::new(None),
+            requests: Cell::new(0),
+        };
+
+        let mut coins = Coins::new();
+        let mut sync = BloomSync::new(0).false_positive_rate(0.000_001);
+        let update = sync.sync(&node, &mut master, &mut coins).unwrap();
+        assert_eq!(update.height, 2);
+        assert_eq!(
+            master
+                .get((0, 0))
+                .unwrap()
+                .get_key(15)
+                .unwrap()
+                .address
+                .script_pubkey(),
+            beyond
+        );
+        // the first block is fetched again with the lookahead scripts
+        assert_eq!((update.blocks, node.requests.get()), (5, 5));
+        let proved = update
+            .proofs
+            .iter()
+            .map(|p| p.get_transaction().txid())
+            .collect::<Vec<_>>();
+        assert_eq!(proved, vec![funding.txid(), later.txid()]);
+        assert_eq!(
+            update.proofs[1].merkle_root(),
+            node.blocks[1].header.merkle_root
+        );
+        assert_eq!(update.proofs[1].position(), 3);
+        assert_eq!(coins.confirmed_balance(), 100_000);
+        assert_eq!(sync.tip(), Some((2, node.blocks[2].block_hash())));
+    }
+}
@@ -0,0 +1,581 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Broadcast
+//!
+//! A transaction relayed by a single backend is lost if that backend is down, censors it or
+//! drops it from its mempool. BroadcastPolicy submits to several backends, records which
+//! accepted, and rebroadcasts unconfirmed transactions of the wallet until they confirm or a
+//! conflicting transaction evicts them.
+//!
+//! With validate a submission is first tested for mempool acceptance, by a backend that can
+//! tell, e.g. bitcoind with testmempoolaccept, otherwise by local standardness checks. A
+//! rejection is returned as a Rejection, e.g. Dust or MissingInputs, instead of the opaque
+//! error of a failed broadcast, and nothing is relayed.
+//!
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::blockdata::opcodes::all::OP_PUSHNUM_16;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{Transaction, Txid};
+
+use backend::ChainBackend;
+use coins::Coins;
+use error::Error;
+
+/// Relays transactions to the network
+pub trait Broadcaster {
+    /// relay a transaction, an error if it was rejected
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error>;
+    /// reject reasons of the mempool, None if the broadcaster can not tell
+    fn mempool_accept(
+        &self,
+        _transactions: &[Transaction],
+    ) -> Result<Option<Vec<Option<String>>>, Error> {
+        Ok(None)
+    }
+}
+
+impl<B: ChainBackend> Broadcaster for B {
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+        ChainBackend::broadcast(self, transaction)
+    }
+
+    fn mempool_accept(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Option<Vec<Option<String>>>, Error> {
+        ChainBackend::mempool_accept(self, transactions)
+    }
+}
+
+/// Why the mempool would not accept a transaction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Rejection {
+    /// spends an output that does not exist or was spent
+    MissingInputs,
+    /// spends an output spent by a transaction in the mempool it does not replace
+    Conflict,
+    /// fee below the minimum relay fee or too low to replace
+    InsufficientFee,
+    /// an output is below its dust value
+    Dust,
+    /// violates a standardness rule, the reason of bitcoind
+    NonStandard(String),
+    /// in the mempool already
+    AlreadyKnown,
+    /// lock time or relative lock time not yet reached
+    NonFinal,
+    /// an other reason
+    Other(String),
+}
+
+impl Rejection {
+    /// classify a reject reason of bitcoind
+    pub fn from_reason(reason: &str) -> Rejection {
+        match reason {
+            "missing-inputs" | "bad-txns-inputs-missingorspent" => Rejection::MissingInputs,
+            "txn-mempool-conflict" => Rejection::Conflict,
+            "dust" => Rejection::Dust,
+            "txn-already-in-mempool"
+            | "txn-already-known"
+            | "txn-same-nonwitness-data-in-mempool" => Rejection::AlreadyKnown,
+            "non-final" | "non-BIP68-final" => Rejection::NonFinal,
+            "version"
+            | "tx-size"
+            | "tx-size-small"
+            | "scriptsig-size"
+            | "scriptsig-not-pushonly"
+            | "scriptpubkey"
+            | "bare-multisig"
+            | "multi-op-return" => Rejection::NonStandard(reason.to_string()),
+            r if r.starts_with("min relay fee not met")
+                || r.starts_with("mempool min fee not met")
+                || r.starts_with("insufficient fee") =>
+            {
+                Rejection::InsufficientFee
+            }
+            r => Rejection::Other(r.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rejection::MissingInputs => write!(f, "inputs missing or spent"),
+            Rejection::Conflict => write!(f, "conflicts with a transaction in the mempool"),
+            Rejection::InsufficientFee => write!(f, "fee too low"),
+            Rejection::Dust => write!(f, "output below dust"),
+            Rejection::NonStandard(ref reason) => write!(f, "non standard: {}", reason),
+            Rejection::AlreadyKnown => write!(f, "already in the mempool"),
+            Rejection::NonFinal => write!(f, "not final"),
+            Rejection::Other(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// local standardness checks of bitcoind for a transaction without its inputs, these do not
+/// find missing inputs or a fee too low
+pub fn check_standard(transaction: &Transaction) -> Result<(), Rejection> {
+    let non_standard = |reason: &str| Err(Rejection::NonStandard(reason.to_string()));
+    if transaction.version < 1 || transaction.version > 3 {
+        return non_standard("version");
+    }
+    if transaction.get_weight() > 400_000 {
+        return non_standard("tx-size");
+    }
+    for input in transaction.input.iter() {
+        if input.script_sig.len() > 1650 {
+            return non_standard("scriptsig-size");
+        }
+        if !input.script_sig.instructions().all(|i| match i {
+            Ok(Instruction::PushBytes(_)) => true,
+            Ok(Instruction::Op(op)) => op.into_u8() <= OP_PUSHNUM_16.into_u8(),
+            Err(_) => false,
+        }) {
+            return non_standard("scriptsig-not-pushonly");
+        }
+    }
+    let mut data = 0;
+    for output in transaction.output.iter() {
+        let script = &output.script_pubkey;
+        if script.is_op_return() {
+            if script.len() > 83 {
+                return non_standard("scriptpubkey");
+            }
+            data += 1;
+            continue;
+        }
+        if !(script.is_p2pkh()
+            || script.is_p2sh()
+            || script.is_p2pk()
+            || script.is_witness_program())
+        {
+            return non_standard("scriptpubkey");
+        }
+        if output.value < script.dust_value() {
+            return Err(Rejection::Dust);
+        }
+    }
+    if data > 1 {
+        return non_standard("multi-op-return");
+    }
+    Ok(())
+}
+
+/// test transactions given parents first for mempool acceptance, with the backend if it can
+/// tell, otherwise with local standardness checks
+pub fn validate<B: Broadcaster + ?Sized>(
+    backend: &B,
+    transactions: &[Transaction],
+) -> Result<(), Error> {
+    match backend.mempool_accept(transactions)? {
+        Some(results) => match results.iter().find_map(|r| r.as_ref()) {
+            Some(reason) => Err(Error::Rejected(Rejection::from_reason(reason))),
+            None => Ok(()),
+        },
+        None => transactions
+            .iter()
+            .try_for_each(check_standard)
+            .map_err(Error::Rejected),
+    }
+}
+
+/// What the backends made of a transaction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Acceptance {
+    /// per backend in the order added, None if accepted, otherwise the error
+    pub results: Vec<Option<String>>,
+    /// seconds since epoch of the last submission
+    pub last: u64,
+    /// submissions so far
+    pub attempts: u32,
+}
+
+impl Acceptance {
+    /// number of backends that accepted at the last submission
+    pub fn accepted(&self) -> usize {
+        self.results.iter().filter(|r| r.is_none()).count()
+    }
+}
+
+/// Outcome of a rebroadcast
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rebroadcast {
+    /// submitted again, parents first
+    pub submitted: Vec<Txid>,
+    /// confirmed since the last call and no longer tracked
+    pub confirmed: Vec<Txid>,
+    /// evicted by a conflicting transaction and no longer tracked
+    pub conflicted: Vec<Txid>,
+}
+
+/// Submits transactions to several backends and rebroadcasts them until confirmed
+pub struct BroadcastPolicy {
+    backends: Vec<Box<dyn Broadcaster>>,
+    min_accepted: usize,
+    interval: Duration,
+    validate: bool,
+    tracked: HashMap<Txid, Acceptance>,
+}
+
+impl Default for BroadcastPolicy {
+    fn default() -> BroadcastPolicy {
+        BroadcastPolicy::new()
+    }
+}
+
+impl BroadcastPolicy {
+    /// without backends, a submission succeeds if one backend accepts, rebroadcast hourly
+    pub fn new() -> BroadcastPolicy {
+        BroadcastPolicy {
+            backends: Vec::new(),
+            min_accepted: 1,
+            interval: Duration::from_secs(60 * 60),
+            validate: false,
+            tracked: HashMap::new(),
+        }
+    }
+
+    /// add a backend to submit to
+    pub fn backend<B: Broadcaster + 'static>(mut self, backend: B) -> BroadcastPolicy {
+        self.backends.push(Box::new(backend));
+        self
+    }
+
+    /// backends that must accept for a submission to succeed
+    pub fn min_accepted(mut self, min_accepted: usize) -> BroadcastPolicy {
+        self.min_accepted = min_accepted;
+        self
+    }
+
+    /// time between submissions of an unconfirmed transaction
+    pub fn interval(mut self, interval: Duration) -> BroadcastPolicy {
+        self.interval = interval;
+        self
+    }
+
+    /// test a transaction for mempool acceptance before submitting it
+    pub fn validate(mut self) -> BroadcastPolicy {
+        self.validate = true;
+        self
+    }
+
+    /// acceptance of a tracked transaction
+    pub fn acceptance(&self, txid: &Txid) -> Option<&Acceptance> {
+        self.tracked.get(txid)
+    }
+
+    /// transactions tracked for rebroadcast
+    pub fn tracked(&self) -> impl Iterator<Item = &Txid> {
+        self.tracked.keys()
+    }
+
+    /// submit to all backends, the transaction is tracked if any accepted
+    pub fn submit(&mut self, transaction: &Transaction) -> Result<Txid, Error> {
+        if self.backends.is_empty() {
+            return Err(Error::Broadcast("no backend to broadcast to"));
+        }
+        if self.validate {
+            // the first backend that can tell, local checks if none can
+            let tested = self.backends.iter().find_map(|b| {
+                b.mempool_accept(std::slice::from_ref(transaction))
+                    .ok()
+                    .and_then(|r| r)
+            });
+            match tested {
+                Some(results) => {
+                    if let Some(reason) = results.iter().find_map(|r| r.as_ref()) {
+                        return Err(Error::Rejected(Rejection::from_reason(reason)));
+                    }
+                }
+                None => check_standard(transaction).map_err(Error::Rejected)?,
+            }
+        }
+        let txid = transaction.txid();
+        let results = self
+            .backends
+            .iter()
+            .map(|b| b.broadcast(transaction).err().map(|e| e.to_string()))
+            .collect::<Vec<_>>();
+        let acceptance = self.tracked.entry(txid).or_insert(Acceptance {
+            results: Vec::new(),
+            last: 0,
+            attempts: 0,
+        });
+        acceptance.results = results;
+        acceptance.last = now();
+        acceptance.attempts += 1;
+        let accepted = acceptance.accepted();
+        if accepted == 0 {
+            self.tracked.remove(&txid);
+            return Err(Error::Broadcast("rejected by all backends"));
+        }
+        if accepted < self.min_accepted {
+            return Err(Error::Broadcast("accepted by too few backends"));
+        }
+        Ok(txid)
+    }
+
+    /// forget confirmed and conflicted transactions, then submit unconfirmed transactions
+    /// of the wallet again once the interval passed since their last submission
+    pub fn rebroadcast(&mut self, coins: &Coins) -> Rebroadcast {
+        let mut report = Rebroadcast::default();
+        let gone = self
+            .tracked
+            .keys()
+            .filter(|txid| !coins.pending().contains_key(*txid))
+            .cloned()
+            .collect::<Vec<_>>();
+        for txid in gone {
+            self.tracked.remove(&txid);
+            if coins.proofs().contains_key(&txid) {
+                report.confirmed.push(txid);
+            } else if coins.conflicts().contains_key(&txid) {
+                report.conflicted.push(txid);
+            }
+        }
+        let now = now();
+        let mut due = coins
+            .pending()
+            .iter()
+            .filter(|(txid, _)| {
+                self.tracked
+                    .get(*txid)
+                    .is_none_or(|a| a.last + self.interval.as_secs() <= now)
+            })
+            .map(|(txid, transaction)| (coins.ancestors(txid).0, transaction))
+            .collect::<Vec<_>>();
+        due.sort_by_key(|(ancestors, transaction)| (*ancestors, transaction.txid()));
+        for (_, transaction) in due {
+            // failures stay tracked and are retried at the next interval
+            match self.submit(transaction) {
+                Ok(txid) => report.submitted.push(txid),
+                Err(_) => {
+                    self.tracked
+                        .entry(transaction.txid())
+                        .or_insert(Acceptance {
+                            results: Vec::new(),
+                            last: now,
+                            attempts: 1,
+                        })
+                        .last = now;
+                }
+            }
+        }
+        report
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, OutPoint, Script, TxIn, TxOut};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use fixtures::{master_account, next_script};
+
+    use super::*;
+
+    /// records submissions, rejects all if down
+    struct Relay {
+        down: bool,
+        seen: Rc<RefCell<Vec<Txid>>>,
+    }
+
+    impl Broadcaster for Relay {
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            if self.down {
+                return Err(Error::Broadcast("backend down"));
+            }
+            self.seen.borrow_mut().push(transaction.txid());
+            Ok(transaction.txid())
+        }
+    }
+
+    /// a node that tests for mempool acceptance
+    struct Node {
+        reason: &'static str,
+    }
+
+    impl Broadcaster for Node {
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn mempool_accept(
+            &self,
+            transactions: &[Transaction],
+        ) -> Result<Option<Vec<Option<String>>>, Error> {
+            Ok(Some(
+                transactions
+                    .iter()
+                    .map(|_| Some(self.reason.to_string()))
+                    .collect(),
+            ))
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let payment = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                sequence: 0xfffffffd,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_v0_wpkh(&Default::default()),
+            }],
+        };
+        assert_eq!(check_standard(&payment), Ok(()));
+        let mut dust = payment.clone();
+        dust.output[0].value = 100;
+        assert_eq!(check_standard(&dust), Err(Rejection::Dust));
+        let mut version = payment.clone();
+        version.version = 4;
+        assert_eq!(
+            check_standard(&version),
+            Err(Rejection::NonStandard("version".to_string()))
+        );
+        let mut data = payment.clone();
+        let op_return = TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(b"data"),
+        };
+        data.output.push(op_return.clone());
+        assert_eq!(check_standard(&data), Ok(()));
+        data.output.push(op_return);
+        assert_eq!(
+            check_standard(&data),
+            Err(Rejection::NonStandard("multi-op-return".to_string()))
+        );
+        assert_eq!(
+            Rejection::from_reason("min relay fee not met, 100 < 110"),
+            Rejection::InsufficientFee
+        );
+
+        // without a backend that can tell, local checks reject before relaying
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut policy = BroadcastPolicy::new()
+            .backend(Relay {
+                down: false,
+                seen: seen.clone(),
+            })
+            .validate();
+        match policy.submit(&dust) {
+            Err(Error::Rejected(Rejection::Dust)) => {}
+            _ => panic!("dust not rejected"),
+        }
+        assert!(seen.borrow().is_empty());
+        assert!(policy.acceptance(&dust.txid()).is_none());
+        assert!(policy.submit(&payment).is_ok());
+
+        let mut policy = BroadcastPolicy::new()
+            .backend(Relay {
+                down: false,
+                seen: seen.clone(),
+            })
+            .backend(Node {
+                reason: "txn-mempool-conflict",
+            })
+            .validate();
+        match policy.submit(&payment) {
+            Err(Error::Rejected(Rejection::Conflict)) => {}
+            _ => panic!("conflict not rejected"),
+        }
+        assert_eq!(seen.borrow().len(), 1);
+    }
+
+    #[test]
+    fn broadcast_policy() {
+        let (mut master, _) = master_account(Network::Testnet);
+        let script = next_script(&mut master, (0, 0));
+        let spend = |txid: Txid, value: u64, script: Script| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint { txid, vout: 0 },
+                sequence: 0xfffffffd,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script,
+            }],
+        };
+        let parent = spend(Txid::default(), 60_000, script.clone());
+        let child = spend(parent.txid(), 50_000, script.clone());
+        let mut coins = Coins::new();
+        coins.process_unconfirmed_transaction(&mut master, &parent);
+        coins.process_unconfirmed_transaction(&mut master, &child);
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut policy = BroadcastPolicy::new()
+            .backend(Relay {
+                down: true,
+                seen: seen.clone(),
+            })
+            .backend(Relay {
+                down: false,
+                seen: seen.clone(),
+            })
+            .interval(Duration::from_secs(0));
+        assert_eq!(policy.submit(&parent).unwrap(), parent.txid());
+        let acceptance = policy.acceptance(&parent.txid()).unwrap();
+        assert_eq!(acceptance.accepted(), 1);
+        assert!(acceptance.results[0].is_some());
+
+        // parents are rebroadcast before children
+        seen.borrow_mut().clear();
+        let report = policy.rebroadcast(&coins);
+        assert_eq!(report.submitted, vec![parent.txid(), child.txid()]);
+        assert_eq!(*seen.borrow(), vec![parent.txid(), child.txid()]);
+        assert_eq!(policy.acceptance(&parent.txid()).unwrap().attempts, 2);
+
+        // a conflict evicts the child, which is no longer rebroadcast
+        let replacement = spend(parent.txid(), 40_000, script);
+        coins.process_unconfirmed_transaction(&mut master, &replacement);
+        let report = policy.rebroadcast(&coins);
+        assert_eq!(report.conflicted, vec![child.txid()]);
+        assert!(!report.submitted.contains(&child.txid()));
+        assert!(report.submitted.contains(&replacement.txid()));
+
+        let mut strict = BroadcastPolicy::new()
+            .backend(Relay {
+                down: true,
+                seen: seen.clone(),
+            })
+            .backend(Relay { down: false, seen })
+            .min_accepted(2);
+        assert!(strict.submit(&parent).is_err());
+        assert!(strict.acceptance(&parent.txid()).is_some());
+    }
+}
@@ -0,0 +1,2039 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Transaction builders
+//!
+//! Builders choose coins and compute fees, they return unsigned transactions.
+//!
+use std::cmp::max;
+use std::collections::HashMap;
+
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::{read_scriptint, Instruction};
+use bitcoin::{Address, OutPoint, Script, Transaction, TxIn, TxOut, Txid, VarInt};
+use rand::{prelude::SliceRandom, thread_rng, Rng};
+
+use account::{MasterAccount, CHANGE_SUB_ACCOUNT};
+use coins::{Coin, CoinControl, Coins};
+use error::Error;
+use fee::{FeeBounds, FeeEstimator, FeeRate};
+use psbt::{self, Psbt};
+use selection::{output_weight, BranchAndBound, Candidate, CoinSelector, LargestFirst, Selection};
+
+/// sequence number of inputs signalling replaceability (BIP125)
+pub const RBF_SEQUENCE: u32 = 0xffff_fffd;
+
+/// sequence number of inputs not signalling replaceability that still enables lock time
+pub(crate) const NO_RBF_SEQUENCE: u32 = 0xffff_fffe;
+
+/// BIP125 limit of transactions a replacement may evict
+const MAX_REPLACED: usize = 100;
+
+/// version of transactions opting into topologically restricted until confirmation (TRUC)
+/// relay, BIP431
+pub const TRUC_VERSION: i32 = 3;
+
+/// maximum virtual size of a TRUC transaction
+const TRUC_MAX_VSIZE: u64 = 10_000;
+
+/// maximum virtual size of a TRUC transaction with an unconfirmed parent
+const TRUC_CHILD_MAX_VSIZE: u64 = 1_000;
+
+/// no output can pay more than all bitcoins
+const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// Who pays the fee of a payment
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FeeDeduction {
+    /// the fee is paid on top of the amounts
+    Sender,
+    /// the fee is deducted from all recipients in proportion to their amounts
+    Proportional,
+    /// the fee is deducted from these recipients in equal parts, by index of addition
+    Recipients(Vec<usize>),
+}
+
+impl FeeDeduction {
+    /// deduct the fee from recipient outputs, the first one pays the remainder of divisions
+    fn deduct(&self, fee: u64, recipients: &mut [TxOut]) -> Result<(), Error> {
+        let shares = match *self {
+            FeeDeduction::Sender => return Ok(()),
+            FeeDeduction::Proportional => {
+                let total = recipients.iter().map(|o| o.value as u128).sum::<u128>();
+                recipients
+                    .iter()
+                    .enumerate()
+                    .map(|(i, o)| (i, (fee as u128 * o.value as u128 / total) as u64))
+                    .collect::<Vec<_>>()
+            }
+            FeeDeduction::Recipients(ref indices) => indices
+                .iter()
+                .map(|i| (*i, fee / indices.len() as u64))
+                .collect(),
+        };
+        let remainder = fee - shares.iter().map(|(_, s)| s).sum::<u64>();
+        for (n, (i, share)) in shares.into_iter().enumerate() {
+            let share = if n == 0 { share + remainder } else { share };
+            let output = &mut recipients[i];
+            if output.value < share + output.script_pubkey.dust_value() {
+                return Err(Error::CoinSelection(
+                    "recipient can not pay its share of the fee",
+                ));
+            }
+            output.value -= share;
+        }
+        Ok(())
+    }
+}
+
+/// Order of inputs and outputs of a payment
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputOrdering {
+    /// outputs as added with change at its ChangePosition, inputs as selected
+    AsGiven,
+    /// lexicographic order of BIP69
+    Bip69,
+    /// random order of outputs
+    Shuffle,
+}
+
+/// Position of the change output among outputs ordered AsGiven
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ChangePosition {
+    /// a random position, so change is not revealed by being the last output
+    #[default]
+    Random,
+    Last,
+    /// at this index of the outputs
+    Index(usize),
+}
+
+/// weight of version, lock time, segwit marker and flag and the input and output counts
+pub fn transaction_base_weight(inputs: usize, outputs: usize) -> u64 {
+    (4 + 4 + VarInt(inputs as u64).len() + VarInt(outputs as u64).len()) as u64 * 4 + 2
+}
+
+/// lock times below are block heights, above are unix times
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// relative lock of a sequence number is in units of 512 seconds
+pub(crate) const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+/// sequence number without relative lock
+pub(crate) const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+
+/// Time locks of a script, `<n> OP_CHECKLOCKTIMEVERIFY` and `<n> OP_CHECKSEQUENCEVERIFY`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timelocks {
+    /// absolute lock, a height or a time, see LOCKTIME_THRESHOLD
+    pub after: Option<u32>,
+    /// relative lock as in the sequence number
+    pub older: Option<u32>,
+}
+
+impl Timelocks {
+    /// the locks of a script, the highest if there are several of a kind
+    pub fn of(script: &Script) -> Timelocks {
+        let mut locks = Timelocks::default();
+        let mut pushed = None;
+        for instruction in script.instructions() {
+            match instruction {
+                Ok(Instruction::PushBytes(bytes)) => {
+                    pushed = read_scriptint(bytes)
+                        .ok()
+                        .filter(|n| *n >= 0 && *n <= u32::MAX as i64)
+                        .map(|n| n as u32)
+                }
+                Ok(Instruction::Op(op)) => {
+                    if op == all::OP_CLTV {
+                        locks.after = max(locks.after, pushed);
+                    } else if op == all::OP_CSV {
+                        locks.older = max(locks.older, pushed);
+                    }
+                    let code = op.into_u8();
+                    pushed = if code >= all::OP_PUSHNUM_1.into_u8()
+                        && code <= all::OP_PUSHNUM_16.into_u8()
+                    {
+                        Some((code - all::OP_PUSHNUM_1.into_u8() + 1) as u32)
+                    } else {
+                        None
+                    };
+                }
+                Err(_) => break,
+            }
+        }
+        locks
+    }
+
+    /// locks of an own coin, the relative lock of its key and, if master is given, the locks
+    /// of its script
+    pub fn of_coin(coin: &Coin, master: Option<&MasterAccount>) -> Timelocks {
+        let derivation = &coin.derivation;
+        let mut locks = master
+            .and_then(|m| m.get((derivation.account, derivation.sub)))
+            .and_then(|a| a.get_key(derivation.kix))
+            .map(|k| Timelocks::of(&k.script_code))
+            .unwrap_or_default();
+        locks.older = max(locks.older, derivation.csv.map(u32::from));
+        locks
+    }
+
+    /// the absolute lock permits a spend in the block after a tip at height with median time
+    pub fn after_expired(&self, height: u32, median_time: Option<u32>) -> bool {
+        match self.after {
+            None => true,
+            Some(after) if after < LOCKTIME_THRESHOLD => after <= height,
+            Some(after) => median_time.is_some_and(|t| after < t),
+        }
+    }
+
+    /// the relative lock permits a spend after a tip at height of a coin confirmed at conf
+    /// height. Relative locks in time are not supported and never expire.
+    pub fn older_expired(&self, conf: u32, height: u32) -> bool {
+        match self.older {
+            None => true,
+            Some(older) => older & SEQUENCE_TYPE_FLAG == 0 && conf > 0 && conf + older <= height,
+        }
+    }
+}
+
+/// build a transaction without signatures
+/// inputs spending a key with relative lock get it as sequence number
+fn unsigned(
+    selection: &Selection,
+    outputs: Vec<TxOut>,
+    lock_time: u32,
+    sequence: u32,
+) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time,
+        input: selection
+            .selected
+            .iter()
+            .map(|c| TxIn {
+                previous_output: c.point,
+                script_sig: Script::new(),
+                sequence: c.coin.derivation.csv.map(u32::from).unwrap_or(sequence),
+                witness: Vec::new(),
+            })
+            .collect(),
+        output: outputs,
+    }
+}
+
+/// Builder of a transaction that gathers small coins into one output of the wallet
+/// Small coins would cost more to spend when fees are high, so it is best done in times of low
+/// fees. build only returns a transaction if the fee rate is at most max_feerate.
+pub struct Consolidation<'a> {
+    coins: &'a Coins,
+    threshold: u64,
+    feerate: FeeRate,
+    max_feerate: FeeRate,
+    min_inputs: usize,
+    max_inputs: usize,
+}
+
+impl<'a> Consolidation<'a> {
+    pub fn new(coins: &'a Coins) -> Consolidation<'a> {
+        Consolidation {
+            coins,
+            threshold: 100_000,
+            feerate: FeeRate::from_sat_per_vb(1),
+            max_feerate: FeeRate::from_sat_per_vb(2),
+            min_inputs: 2,
+            max_inputs: 200,
+        }
+    }
+
+    /// consolidate coins below this value
+    pub fn threshold(mut self, threshold: u64) -> Consolidation<'a> {
+        self.threshold = threshold;
+        self
+    }
+
+    /// fee rate the consolidation pays, this is usually the current low fee rate
+    pub fn feerate(mut self, feerate: FeeRate) -> Consolidation<'a> {
+        self.feerate = feerate;
+        self
+    }
+
+    /// consolidate only if the fee rate is not above this
+    pub fn max_feerate(mut self, max_feerate: FeeRate) -> Consolidation<'a> {
+        self.max_feerate = max_feerate;
+        self
+    }
+
+    /// do not consolidate fewer coins
+    pub fn min_inputs(mut self, min_inputs: usize) -> Consolidation<'a> {
+        self.min_inputs = min_inputs;
+        self
+    }
+
+    /// do not consolidate more coins in one transaction
+    pub fn max_inputs(mut self, max_inputs: usize) -> Consolidation<'a> {
+        self.max_inputs = max_inputs;
+        self
+    }
+
+    /// build the consolidation paying to an own script
+    /// returns None if consolidation is not worthwhile now
+    pub fn build<H>(
+        self,
+        to: &Script,
+        height: u32,
+        block_height: H,
+    ) -> Result<Option<(Transaction, Selection)>, Error>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        if self.feerate > self.max_feerate {
+            return Ok(None);
+        }
+        let feerate = self.feerate;
+        let mut small = self
+            .coins
+            .spendable_coins(true, height, block_height)
+            .into_iter()
+            .map(|(p, c, h)| Candidate::new(p, c, h))
+            .filter(|c| c.value() < self.threshold && c.effective_value(feerate) > 0)
+            .collect::<Vec<_>>();
+        if small.len() < self.min_inputs {
+            return Ok(None);
+        }
+        // spend the smallest, those are most expensive to spend later
+        small.sort_by_key(|c| c.value());
+        small.truncate(self.max_inputs);
+        let base_fee = feerate.fee(transaction_base_weight(small.len(), 1));
+        let selection = match Selection::new(small, base_fee, feerate, to) {
+            Ok(selection) => selection,
+            Err(_) => return Ok(None),
+        };
+        match selection.change {
+            Some(value) => {
+                let output = TxOut {
+                    value,
+                    script_pubkey: to.clone(),
+                };
+                Ok(Some((
+                    unsigned(&selection, vec![output], 0, RBF_SEQUENCE),
+                    selection,
+                )))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Builder of a payment
+/// Coins are selected with the coin selector, given BranchAndBound by default, under coin
+/// control, change above dust is paid to the change script given to build.
+/// With drain_to the coins are spent without selection and without change, see drain_to.
+pub struct TransactionBuilder<'a, S = BranchAndBound> {
+    coins: &'a Coins,
+    recipients: Vec<TxOut>,
+    drain: Option<Script>,
+    deduction: FeeDeduction,
+    ordering: OutputOrdering,
+    change_position: ChangePosition,
+    change: Option<Script>,
+    feerate: Option<FeeRate>,
+    estimator: Option<(&'a dyn FeeEstimator, u16)>,
+    rbf: bool,
+    lock_time: u32,
+    sequences: HashMap<OutPoint, u32>,
+    master: Option<&'a MasterAccount>,
+    median_time: Option<u32>,
+    truc: bool,
+    bounds: FeeBounds,
+    absurd_fee: bool,
+    control: CoinControl,
+    selector: S,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn new(coins: &'a Coins) -> TransactionBuilder<'a> {
+        TransactionBuilder {
+            coins,
+            recipients: Vec::new(),
+            drain: None,
+            deduction: FeeDeduction::Sender,
+            ordering: OutputOrdering::AsGiven,
+            change_position: ChangePosition::default(),
+            change: None,
+            feerate: None,
+            estimator: None,
+            rbf: false,
+            lock_time: 0,
+            sequences: HashMap::new(),
+            master: None,
+            median_time: None,
+            truc: false,
+            bounds: FeeBounds::default(),
+            absurd_fee: false,
+            control: CoinControl::new(),
+            selector: BranchAndBound::default(),
+        }
+    }
+}
+
+impl<'a, S: CoinSelector> TransactionBuilder<'a, S> {
+    /// pay amount to an address
+    pub fn add_recipient(self, address: &Address, amount: u64) -> TransactionBuilder<'a, S> {
+        self.add_output(TxOut {
+            value: amount,
+            script_pubkey: address.script_pubkey(),
+        })
+    }
+
+    /// pay to an arbitrary output script
+    pub fn add_output(mut self, output: TxOut) -> TransactionBuilder<'a, S> {
+        self.recipients.push(output);
+        self
+    }
+
+    /// pay everything left after recipients and fees to this script
+    /// Spends the required coins of coin control if there are any, all spendable coins allowed
+    /// by coin control otherwise. Coins that cost more to spend than they are worth are left.
+    pub fn drain_to(mut self, script: Script) -> TransactionBuilder<'a, S> {
+        self.drain = Some(script);
+        self
+    }
+
+    /// who pays the fee
+    pub fn fee_deduction(mut self, deduction: FeeDeduction) -> TransactionBuilder<'a, S> {
+        self.deduction = deduction;
+        self
+    }
+
+    /// order of inputs and outputs
+    pub fn ordering(mut self, ordering: OutputOrdering) -> TransactionBuilder<'a, S> {
+        self.ordering = ordering;
+        self
+    }
+
+    /// position of change if outputs are ordered AsGiven, random by default
+    pub fn change_position(mut self, position: ChangePosition) -> TransactionBuilder<'a, S> {
+        self.change_position = position;
+        self
+    }
+
+    /// pay change to this address instead of the change script given to build, e.g. to an
+    /// other account
+    pub fn change_address(mut self, address: &Address) -> TransactionBuilder<'a, S> {
+        self.change = Some(address.script_pubkey());
+        self
+    }
+
+    /// fee rate, 1 sat/vB if neither given nor estimated
+    pub fn feerate(mut self, feerate: FeeRate) -> TransactionBuilder<'a, S> {
+        self.feerate = Some(feerate);
+        self
+    }
+
+    /// estimate the fee rate to confirm within target blocks if no fee rate is given
+    pub fn fee_estimator(
+        mut self,
+        estimator: &'a dyn FeeEstimator,
+        target: u16,
+    ) -> TransactionBuilder<'a, S> {
+        self.estimator = Some((estimator, target));
+        self
+    }
+
+    /// signal replaceability (BIP125) on all inputs
+    pub fn enable_rbf(mut self) -> TransactionBuilder<'a, S> {
+        self.rbf = true;
+        self
+    }
+
+    /// lock time of the transaction, raised to absolute locks of spent coins by timelocks_of
+    /// A lock time is only enforced if an input has a sequence number below 0xffffffff.
+    pub fn lock_time(mut self, lock_time: u32) -> TransactionBuilder<'a, S> {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// sequence number of the input spending a coin, the coin must be spent
+    /// The sequence number must signal replaceability if enable_rbf is set and satisfy the
+    /// relative lock of the coin. A relative lock it sets in blocks must have expired.
+    pub fn input_sequence(mut self, point: OutPoint, sequence: u32) -> TransactionBuilder<'a, S> {
+        self.sequences.insert(point, sequence);
+        self
+    }
+
+    /// satisfy the time locks of scripts of master, see Timelocks::of_coin
+    /// Coins with locks that did not expire are not spent, inputs get the relative lock as
+    /// sequence number and the lock time is at least the highest absolute lock.
+    pub fn timelocks_of(mut self, master: &'a MasterAccount) -> TransactionBuilder<'a, S> {
+        self.master = Some(master);
+        self
+    }
+
+    /// median time past of the tip, coins locked until a time are not spent without it
+    pub fn median_time_past(mut self, time: u32) -> TransactionBuilder<'a, S> {
+        self.median_time = Some(time);
+        self
+    }
+
+    /// build a version 3 transaction with the topology limits of TRUC relay (BIP431)
+    /// Unconfirmed coins are only spent if their transaction is TRUC, has no unconfirmed
+    /// ancestors and no other unconfirmed child. Transactions of other versions do not spend
+    /// unconfirmed coins of TRUC transactions.
+    pub fn truc(mut self) -> TransactionBuilder<'a, S> {
+        self.truc = true;
+        self
+    }
+
+    /// sanity bounds of the fee, FeeBounds::default() if not given
+    pub fn fee_bounds(mut self, bounds: FeeBounds) -> TransactionBuilder<'a, S> {
+        self.bounds = bounds;
+        self
+    }
+
+    /// accept a fee above the ceilings of the fee bounds, e.g. to sweep coins close to dust
+    pub fn allow_absurd_fee(mut self) -> TransactionBuilder<'a, S> {
+        self.absurd_fee = true;
+        self
+    }
+
+    /// manual coin control
+    pub fn coin_control(mut self, control: CoinControl) -> TransactionBuilder<'a, S> {
+        self.control = control;
+        self
+    }
+
+    /// select coins with an other strategy
+    pub fn coin_selection<T: CoinSelector>(self, selector: T) -> TransactionBuilder<'a, T> {
+        TransactionBuilder {
+            coins: self.coins,
+            recipients: self.recipients,
+            drain: self.drain,
+            deduction: self.deduction,
+            ordering: self.ordering,
+            change_position: self.change_position,
+            change: self.change,
+            feerate: self.feerate,
+            estimator: self.estimator,
+            rbf: self.rbf,
+            lock_time: self.lock_time,
+            sequences: self.sequences,
+            master: self.master,
+            median_time: self.median_time,
+            truc: self.truc,
+            bounds: self.bounds,
+            absurd_fee: self.absurd_fee,
+            control: self.control,
+            selector,
+        }
+    }
+
+    /// select coins and return the unsigned transaction with the selection
+    /// change is paid to the change address if given, to change otherwise, change is not
+    /// used if draining
+    pub fn build<H>(
+        mut self,
+        change: &Script,
+        height: u32,
+        block_height: H,
+    ) -> Result<(Transaction, Selection), Error>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        if self.recipients.is_empty() && self.drain.is_none() {
+            return Err(Error::CoinSelection("no recipients"));
+        }
+        if let ChangePosition::Index(ix) = self.change_position {
+            if ix > self.recipients.len() {
+                return Err(Error::CoinSelection("change position after the outputs"));
+            }
+        }
+        let custom_change = self.change.take();
+        let change = custom_change.as_ref().unwrap_or(change);
+        for output in self.recipients.iter() {
+            if output.value < output.script_pubkey.dust_value() {
+                return Err(Error::CoinSelection("output below dust"));
+            }
+            if output.value > MAX_MONEY {
+                return Err(Error::CoinSelection("output above the maximum amount"));
+            }
+        }
+        if let FeeDeduction::Recipients(ref indices) = self.deduction {
+            if indices.is_empty() || indices.iter().any(|i| *i >= self.recipients.len()) {
+                return Err(Error::CoinSelection("fee deducted from unknown recipient"));
+            }
+        }
+        let recipients_pay = self.deduction != FeeDeduction::Sender;
+        if recipients_pay && self.drain.is_some() {
+            return Err(Error::CoinSelection(
+                "fee deduction can not be combined with drain",
+            ));
+        }
+        // coins with locks that did not expire are not spent
+        for (point, coin, conf) in
+            self.coins
+                .spendable_coins(self.control.is_self_transfer(), height, &block_height)
+        {
+            let locks = Timelocks::of_coin(&coin, self.master);
+            if !locks.older_expired(conf, height) || !locks.after_expired(height, self.median_time)
+            {
+                if self.control.required().contains(&point) {
+                    return Err(Error::CoinSelection("required coin is time locked"));
+                }
+                self.control = self.control.exclude(point);
+            }
+        }
+        // unconfirmed coins TRUC rules do not allow to spend
+        for (point, _, _) in self
+            .coins
+            .spendable_coins(self.control.is_self_transfer(), height, &block_height)
+            .into_iter()
+            .filter(|(_, _, conf)| *conf == 0)
+        {
+            let allowed = match self.coins.pending().get(&point.txid) {
+                Some(parent) if parent.version == TRUC_VERSION => {
+                    self.truc
+                        && self.coins.ancestors(&point.txid).0 == 1
+                        && self.coins.descendants(&point.txid).is_empty()
+                }
+                _ => !self.truc,
+            };
+            if !allowed {
+                if self.control.required().contains(&point) {
+                    return Err(Error::CoinSelection(
+                        "required coin can not be spent under TRUC rules",
+                    ));
+                }
+                self.control = self.control.exclude(point);
+            }
+        }
+        let feerate = match (self.feerate, self.estimator) {
+            (Some(feerate), _) => feerate,
+            (None, Some((estimator, target))) => estimator.estimate(target)?,
+            (None, None) => FeeRate::from_sat_per_vb(1),
+        };
+        self.bounds.check_feerate(feerate, !self.absurd_fee)?;
+        let weight = transaction_base_weight(1, self.recipients.len() + 1)
+            + self
+                .recipients
+                .iter()
+                .map(|o| output_weight(&o.script_pubkey))
+                .sum::<u64>();
+        let amount = self.recipients.iter().map(|o| o.value).sum::<u64>();
+        // if recipients pay, the sender only contributes their amount
+        let (target, selection_feerate) = if recipients_pay {
+            (amount, FeeRate::default())
+        } else {
+            (amount + feerate.fee(weight), feerate)
+        };
+        let (mut selection, change) = match self.drain {
+            Some(ref drain) => {
+                let available = self.coins.spendable_coins(
+                    self.control.is_self_transfer(),
+                    height,
+                    block_height,
+                );
+                let required = self.control.required();
+                let spent = available
+                    .into_iter()
+                    .filter(|(p, _, _)| {
+                        if required.is_empty() {
+                            self.control.allows(p)
+                        } else {
+                            required.contains(p)
+                        }
+                    })
+                    .map(|(p, c, h)| Candidate::new(p, c, h))
+                    .filter(|c| !required.is_empty() || c.effective_value(feerate) > 0)
+                    .collect::<Vec<_>>();
+                if spent.len() < required.len() {
+                    return Err(Error::CoinSelection("required coin is not available"));
+                }
+                // the drain output is the change of this selection
+                let selection = Selection::new(spent, target, feerate, drain)?;
+                if selection.change.is_none() {
+                    return Err(Error::CoinSelection("drain output would be dust"));
+                }
+                (selection, drain)
+            }
+            None => (
+                self.coins.select(
+                    &self.selector,
+                    &self.control,
+                    target,
+                    selection_feerate,
+                    change,
+                    height,
+                    block_height,
+                )?,
+                change,
+            ),
+        };
+        let mut outputs = self.recipients.clone();
+        if let Some(value) = selection.change {
+            outputs.push(TxOut {
+                value,
+                script_pubkey: change.clone(),
+            });
+        }
+        if recipients_pay {
+            let weight = transaction_base_weight(selection.selected.len(), outputs.len())
+                + selection.selected.iter().map(|c| c.weight).sum::<u64>()
+                + outputs
+                    .iter()
+                    .map(|o| output_weight(&o.script_pubkey))
+                    .sum::<u64>();
+            let fee = feerate.fee(weight);
+            self.deduction
+                .deduct(fee, &mut outputs[..self.recipients.len()])?;
+            selection.fee += fee;
+        }
+        let mut transaction = unsigned(&selection, outputs, self.lock_time, self.sequence());
+        for (input, candidate) in transaction.input.iter_mut().zip(selection.selected.iter()) {
+            let locks = Timelocks::of_coin(&candidate.coin, self.master);
+            if let Some(older) = locks.older {
+                input.sequence = older;
+            }
+            if let Some(after) = locks.after {
+                let lock_time = transaction.lock_time;
+                if lock_time != 0
+                    && (lock_time < LOCKTIME_THRESHOLD) != (after < LOCKTIME_THRESHOLD)
+                {
+                    return Err(Error::CoinSelection(
+                        "lock time can not be a height and a time",
+                    ));
+                }
+                transaction.lock_time = max(lock_time, after);
+            }
+            if let Some(sequence) = self.sequences.get(&candidate.point) {
+                let sequence = *sequence;
+                if self.rbf && sequence > RBF_SEQUENCE {
+                    return Err(Error::CoinSelection(
+                        "sequence does not signal replaceability",
+                    ));
+                }
+                let relative = Timelocks {
+                    after: None,
+                    older: Some(sequence & (SEQUENCE_TYPE_FLAG | 0xffff)),
+                };
+                if sequence & SEQUENCE_DISABLE_FLAG == 0 {
+                    if sequence & SEQUENCE_TYPE_FLAG == 0
+                        && !relative.older_expired(candidate.height, height)
+                    {
+                        return Err(Error::CoinSelection("relative lock did not expire"));
+                    }
+                } else if locks.older.is_some() {
+                    return Err(Error::CoinSelection("sequence disables the relative lock"));
+                }
+                if let Some(older) = locks.older {
+                    if (older & SEQUENCE_TYPE_FLAG) != (sequence & SEQUENCE_TYPE_FLAG)
+                        || (sequence & 0xffff) < (older & 0xffff)
+                    {
+                        return Err(Error::CoinSelection("sequence below the relative lock"));
+                    }
+                }
+                input.sequence = sequence;
+            }
+        }
+        if self
+            .sequences
+            .keys()
+            .any(|p| !transaction.input.iter().any(|i| i.previous_output == *p))
+        {
+            return Err(Error::CoinSelection("sequence set for a coin not spent"));
+        }
+        if transaction.lock_time != 0 && transaction.input.iter().all(|i| i.sequence == 0xffff_ffff)
+        {
+            return Err(Error::CoinSelection(
+                "lock time is not enforced with final sequence numbers",
+            ));
+        }
+        let weight = transaction_base_weight(selection.selected.len(), transaction.output.len())
+            + selection.selected.iter().map(|c| c.weight).sum::<u64>()
+            + transaction
+                .output
+                .iter()
+                .map(|o| output_weight(&o.script_pubkey))
+                .sum::<u64>();
+        if !self.absurd_fee {
+            let spent = selection
+                .selected
+                .iter()
+                .map(|c| c.coin.output.value)
+                .sum::<u64>();
+            let paid = transaction.output.iter().map(|o| o.value).sum::<u64>();
+            // a drain output is paid, change is not
+            let change = match self.drain {
+                Some(_) => 0,
+                None => selection.change.unwrap_or(0),
+            };
+            self.bounds.check_fee(spent - paid, weight, paid - change)?;
+        }
+        if self.truc {
+            transaction.version = TRUC_VERSION;
+            let mut parents = selection
+                .selected
+                .iter()
+                .filter(|c| c.height == 0)
+                .map(|c| c.point.txid)
+                .collect::<Vec<_>>();
+            parents.sort();
+            parents.dedup();
+            let vsize = weight.div_ceil(4);
+            if parents.len() > 1 {
+                return Err(Error::CoinSelection(
+                    "TRUC transaction with more than one unconfirmed parent",
+                ));
+            }
+            if vsize > TRUC_MAX_VSIZE || (!parents.is_empty() && vsize > TRUC_CHILD_MAX_VSIZE) {
+                return Err(Error::CoinSelection("TRUC transaction too large"));
+            }
+        }
+        match self.ordering {
+            OutputOrdering::AsGiven => {
+                if selection.change.is_some() {
+                    let last = transaction.output.len() - 1;
+                    let position = match self.change_position {
+                        ChangePosition::Random => thread_rng().gen_range(0, last + 1),
+                        ChangePosition::Last => last,
+                        ChangePosition::Index(ix) => ix,
+                    };
+                    let output = transaction.output.remove(last);
+                    transaction.output.insert(position, output);
+                }
+            }
+            OutputOrdering::Shuffle => transaction.output.shuffle(&mut thread_rng()),
+            OutputOrdering::Bip69 => {
+                let key = |point: &OutPoint| {
+                    let mut txid = point.txid[..].to_vec();
+                    txid.reverse();
+                    (txid, point.vout)
+                };
+                selection.selected.sort_by_key(|c| key(&c.point));
+                transaction.input.sort_by_key(|i| key(&i.previous_output));
+                transaction.output.sort_by(|a, b| {
+                    (a.value, &a.script_pubkey[..]).cmp(&(b.value, &b.script_pubkey[..]))
+                });
+            }
+        }
+        Ok((transaction, selection))
+    }
+
+    fn sequence(&self) -> u32 {
+        if self.rbf {
+            RBF_SEQUENCE
+        } else {
+            NO_RBF_SEQUENCE
+        }
+    }
+
+    /// build a PSBT ready for signers, see psbt::create
+    pub fn build_psbt<H>(
+        self,
+        master: &'a MasterAccount,
+        change: &Script,
+        height: u32,
+        block_height: H,
+    ) -> Result<(Psbt, Selection), Error>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let coins = self.coins;
+        let (transaction, selection) =
+            self.timelocks_of(master)
+                .build(change, height, block_height)?;
+        Ok((psbt::create(transaction, coins, master)?, selection))
+    }
+}
+
+/// Builder of a replacement of a pending transaction (BIP125)
+/// The replacement spends the inputs of the original and pays its outputs except the change,
+/// which shrinks to pay the higher fee. Confirmed coins are added if the change is not
+/// sufficient. The original and its descendants are evicted, so the replacement must pay
+/// their fees plus the relay fee of itself.
+pub struct FeeBump<'a> {
+    coins: &'a Coins,
+    txid: Txid,
+    feerate: FeeRate,
+    min_relay_feerate: FeeRate,
+    cancel: bool,
+    change_vout: Option<u32>,
+    bounds: FeeBounds,
+    absurd_fee: bool,
+}
+
+impl<'a> FeeBump<'a> {
+    pub fn new(coins: &'a Coins, txid: &Txid) -> FeeBump<'a> {
+        FeeBump {
+            coins,
+            txid: *txid,
+            feerate: FeeRate::from_sat_per_vb(2),
+            min_relay_feerate: FeeRate::from_sat_per_vb(1),
+            cancel: false,
+            change_vout: None,
+            bounds: FeeBounds::default(),
+            absurd_fee: false,
+        }
+    }
+
+    /// a replacement that pays everything back to the wallet, cancelling the payment
+    /// It spends only the inputs of the original, its single output pays the fee required to
+    /// replace the original at least.
+    pub fn cancel(coins: &'a Coins, txid: &Txid) -> FeeBump<'a> {
+        FeeBump {
+            cancel: true,
+            ..FeeBump::new(coins, txid)
+        }
+    }
+
+    /// fee rate of the replacement, must be higher than that of the original
+    pub fn feerate(mut self, feerate: FeeRate) -> FeeBump<'a> {
+        self.feerate = feerate;
+        self
+    }
+
+    /// incremental relay fee rate of nodes, 1 sat/vB by default
+    pub fn min_relay_feerate(mut self, feerate: FeeRate) -> FeeBump<'a> {
+        self.min_relay_feerate = feerate;
+        self
+    }
+
+    /// sanity bounds of the fee, FeeBounds::default() if not given
+    pub fn fee_bounds(mut self, bounds: FeeBounds) -> FeeBump<'a> {
+        self.bounds = bounds;
+        self
+    }
+
+    /// accept a fee above the ceilings of the fee bounds
+    pub fn allow_absurd_fee(mut self) -> FeeBump<'a> {
+        self.absurd_fee = true;
+        self
+    }
+
+    /// the output of the original that is change, an own output on the change sub-account
+    /// (CHANGE_SUB_ACCOUNT) by default
+    pub fn change_vout(mut self, vout: u32) -> FeeBump<'a> {
+        self.change_vout = Some(vout);
+        self
+    }
+
+    /// build the unsigned replacement
+    /// change is paid to the change script of the original if it has one, to change otherwise
+    /// Other outputs of the original, also payments to own keys, are kept.
+    pub fn build<H>(
+        self,
+        change: &Script,
+        height: u32,
+        block_height: H,
+    ) -> Result<(Transaction, Selection), Error>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let original = self
+            .coins
+            .pending()
+            .get(&self.txid)
+            .ok_or(Error::Replacement("transaction is not pending"))?;
+        // TRUC transactions are always replaceable
+        if original.version != TRUC_VERSION
+            && !original.input.iter().any(|i| i.sequence < NO_RBF_SEQUENCE)
+        {
+            return Err(Error::Replacement(
+                "transaction does not signal replaceability",
+            ));
+        }
+        let mut replaced = self.coins.descendants(&self.txid);
+        replaced.push(self.txid);
+        if replaced.len() > MAX_REPLACED {
+            return Err(Error::Replacement(
+                "too many transactions would be replaced",
+            ));
+        }
+        let mut replaced_fee = 0;
+        for txid in replaced.iter() {
+            replaced_fee +=
+                self.coins
+                    .fee_of(&self.coins.pending()[txid])
+                    .ok_or(Error::Replacement(
+                        "fee of a replaced transaction is not known",
+                    ))?;
+        }
+        self.bounds.check_feerate(self.feerate, !self.absurd_fee)?;
+        let original_fee = self.coins.fee_of(original).unwrap_or(0);
+        if self.feerate.fee(original.get_weight() as u64) <= original_fee {
+            return Err(Error::Replacement(
+                "fee rate is not higher than the original",
+            ));
+        }
+
+        let spent = self.coins.spent_by(&self.txid);
+        if spent.len() != original.input.len() {
+            return Err(Error::Replacement("can only replace spends of own coins"));
+        }
+        // keep the input order of the original
+        let required = original
+            .input
+            .iter()
+            .map(|i| {
+                let (point, coin) = spent
+                    .iter()
+                    .find(|(p, _)| *p == i.previous_output)
+                    .cloned()
+                    .unwrap();
+                Candidate::new(point, coin, 1)
+            })
+            .collect::<Vec<_>>();
+        let change_vout = match self.change_vout {
+            Some(vout) => {
+                if !self.coins.is_own(&OutPoint::new(self.txid, vout)) {
+                    return Err(Error::Replacement("change is not an own output"));
+                }
+                Some(vout as usize)
+            }
+            None => (0..original.output.len()).rev().find(|vout| {
+                self.coins
+                    .derivation(&OutPoint::new(self.txid, *vout as u32))
+                    .is_some_and(|d| d.sub == CHANGE_SUB_ACCOUNT)
+            }),
+        };
+        let change = change_vout
+            .map(|vout| original.output[vout].script_pubkey.clone())
+            .unwrap_or_else(|| change.clone());
+        let recipients = original
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(vout, _)| !self.cancel && Some(*vout) != change_vout)
+            .map(|(_, o)| o.clone())
+            .collect::<Vec<_>>();
+
+        let weight = transaction_base_weight(1, recipients.len() + 1)
+            + recipients
+                .iter()
+                .map(|o| output_weight(&o.script_pubkey))
+                .sum::<u64>();
+        let target = recipients.iter().map(|o| o.value).sum::<u64>() + self.feerate.fee(weight);
+        let pinned = required
+            .iter()
+            .map(|c| c.effective_value(self.feerate))
+            .sum::<i64>();
+        let mut selection = if pinned >= target as i64 {
+            Selection::new(required, target, self.feerate, &change)?
+        } else if self.cancel {
+            return Err(Error::Replacement("inputs do not pay for the cancellation"));
+        } else {
+            // BIP125 does not allow new unconfirmed inputs
+            let candidates = self
+                .coins
+                .available_coins(height, block_height)
+                .into_iter()
+                .map(|(p, c, h)| Candidate::new(p, c, h))
+                .collect();
+            LargestFirst
+                .select(
+                    candidates,
+                    (target as i64 - pinned) as u64,
+                    self.feerate,
+                    &change,
+                )?
+                .prepend(required, self.feerate)
+        };
+
+        let mut outputs = recipients;
+        if let Some(value) = selection.change {
+            outputs.push(TxOut {
+                value,
+                script_pubkey: change,
+            });
+        }
+        let mut fee = selection.value() - outputs.iter().map(|o| o.value).sum::<u64>();
+        let weight = transaction_base_weight(selection.selected.len(), outputs.len())
+            + selection.selected.iter().map(|c| c.weight).sum::<u64>()
+            + outputs
+                .iter()
+                .map(|o| output_weight(&o.script_pubkey))
+                .sum::<u64>();
+        // the single output of a cancellation pays what replacement requires
+        let required_fee = replaced_fee + self.min_relay_feerate.fee(weight);
+        if self.cancel && fee < required_fee {
+            let output = outputs
+                .last_mut()
+                .ok_or(Error::Replacement("inputs do not pay for the cancellation"))?;
+            let missing = required_fee - fee;
+            if output.value < missing + output.script_pubkey.dust_value() {
+                return Err(Error::Replacement("inputs do not pay for the cancellation"));
+            }
+            output.value -= missing;
+            selection.change = Some(output.value);
+            selection.fee += missing;
+            fee = required_fee;
+        }
+        if fee < replaced_fee + self.min_relay_feerate.fee(weight) {
+            return Err(Error::Replacement(
+                "fee does not pay for the replaced transactions and relay",
+            ));
+        }
+        if !self.absurd_fee {
+            let paid = outputs.iter().map(|o| o.value).sum::<u64>();
+            // the output of a cancellation is paid as a drain is
+            let change = if self.cancel {
+                0
+            } else {
+                selection.change.unwrap_or(0)
+            };
+            self.bounds.check_fee(fee, weight, paid - change)?;
+        }
+        let mut transaction = unsigned(&selection, outputs, original.lock_time, RBF_SEQUENCE);
+        transaction.version = original.version;
+        Ok((transaction, selection))
+    }
+}
+
+/// Builder of a child transaction that raises the fee rate of a pending transaction with its
+/// child (CPFP) to a target, by spending the own outputs of the pending transaction into one
+/// output to the change script. The pending transaction must have been processed as
+/// unconfirmed transaction before, e.g. with Coins::process_unconfirmed_transaction.
+pub struct ChildPaysForParent<'a> {
+    coins: &'a Coins,
+    txid: Txid,
+    feerate: FeeRate,
+    bounds: FeeBounds,
+    absurd_fee: bool,
+}
+
+impl<'a> ChildPaysForParent<'a> {
+    pub fn new(coins: &'a Coins, txid: &Txid) -> ChildPaysForParent<'a> {
+        ChildPaysForParent {
+            coins,
+            txid: *txid,
+            feerate: FeeRate::from_sat_per_vb(2),
+            bounds: FeeBounds::default(),
+            absurd_fee: false,
+        }
+    }
+
+    /// fee rate of the parent and child together
+    pub fn feerate(mut self, feerate: FeeRate) -> ChildPaysForParent<'a> {
+        self.feerate = feerate;
+        self
+    }
+
+    /// sanity bounds of the fee of parent and child together, FeeBounds::default() if not given
+    pub fn fee_bounds(mut self, bounds: FeeBounds) -> ChildPaysForParent<'a> {
+        self.bounds = bounds;
+        self
+    }
+
+    /// accept a fee above the ceilings of the fee bounds
+    pub fn allow_absurd_fee(mut self) -> ChildPaysForParent<'a> {
+        self.absurd_fee = true;
+        self
+    }
+
+    /// build the unsigned child
+    pub fn build(self, change: &Script) -> Result<(Transaction, Selection), Error> {
+        let parent = self
+            .coins
+            .pending()
+            .get(&self.txid)
+            .ok_or(Error::CoinSelection("transaction is not pending"))?;
+        self.bounds.check_feerate(self.feerate, !self.absurd_fee)?;
+        let parent_fee = self
+            .coins
+            .fee_of(parent)
+            .ok_or(Error::CoinSelection("fee of the parent is not known"))?;
+        let mut spent = self
+            .coins
+            .unconfirmed()
+            .iter()
+            .filter(|(p, _)| p.txid == self.txid && !self.coins.is_frozen(p))
+            .map(|(p, c)| Candidate::new(*p, c.clone(), 0))
+            .collect::<Vec<_>>();
+        if spent.is_empty() {
+            return Err(Error::CoinSelection("transaction pays no own output"));
+        }
+        spent.sort_by_key(|c| c.point.vout);
+        // the child pays for its own weight and what the parent lacks
+        let deficit = self
+            .feerate
+            .fee(parent.get_weight() as u64)
+            .saturating_sub(parent_fee);
+        let target = self.feerate.fee(transaction_base_weight(spent.len(), 1)) + deficit;
+        let selection = Selection::new(spent, target, self.feerate, change)?;
+        let value = selection
+            .change
+            .ok_or(Error::CoinSelection("child output would be dust"))?;
+        if !self.absurd_fee {
+            let weight = transaction_base_weight(selection.selected.len(), 1)
+                + selection.selected.iter().map(|c| c.weight).sum::<u64>()
+                + output_weight(change);
+            let spent = selection
+                .selected
+                .iter()
+                .map(|c| c.coin.output.value)
+                .sum::<u64>();
+            // the package pays what the parent pays to others
+            let paid = (0..parent.output.len())
+                .filter(|vout| !self.coins.is_own(&OutPoint::new(self.txid, *vout as u32)))
+                .map(|vout| parent.output[vout].value)
+                .sum::<u64>();
+            self.bounds.check_fee(
+                parent_fee + spent - value,
+                parent.get_weight() as u64 + weight,
+                paid,
+            )?;
+        }
+        let output = TxOut {
+            value,
+            script_pubkey: change.clone(),
+        };
+        let mut transaction = unsigned(&selection, vec![output], 0, RBF_SEQUENCE);
+        if parent.version == TRUC_VERSION {
+            transaction.version = TRUC_VERSION;
+        }
+        Ok((transaction, selection))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network, OutPoint, TxOut};
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use account::{Account, AccountAddressType, MasterKeyEntropy, Unlocker};
+    use coins::{CoinControl, Coins};
+    use fee::{FeeRate, StaticFeeEstimator};
+    use fixtures::{self, block, master_account, next_script, PASSPHRASE};
+
+    use super::*;
+
+    fn recipient() -> Address {
+        Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7").unwrap()
+    }
+
+    /// a wallet with coins of these values confirmed at height 1 and a change account
+    fn wallet(values: &[u64]) -> (MasterAccount, Coins) {
+        let (mut master, mut unlocker) = master_account(Network::Testnet);
+        fixtures::add_change_account(&mut master, &mut unlocker);
+        let (coins, _) = fixtures::funded(&mut master, Network::Testnet, values);
+        (master, coins)
+    }
+
+    /// coins of these values confirmed at height 1 and a script to pay change to
+    fn funded(values: &[u64]) -> (Coins, Script) {
+        let (mut master, coins) = wallet(values);
+        (coins, next_script(&mut master, (0, 0)))
+    }
+
+    #[test]
+    fn consolidate() {
+        let (coins, script) = funded(&[1000, 2000, 50, 1_000_000]);
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let (tx, selection) = coins
+            .consolidate()
+            .feerate(FeeRate::from_sat_per_vb(1))
+            .build(&script, 200, heights)
+            .unwrap()
+            .unwrap();
+        // the 50 sat output is not worth spending, the large one is not small
+        // coins of the genesis coinbase are spendable after COINBASE_MATURITY blocks
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output.len(), 1);
+        let base_fee = FeeRate::from_sat_per_vb(1).fee(transaction_base_weight(2, 1));
+        assert_eq!(tx.output[0].value + selection.fee + base_fee, 3000);
+        assert!(coins
+            .consolidate()
+            .feerate(FeeRate::from_sat_per_vb(10))
+            .build(&script, 200, heights)
+            .unwrap()
+            .is_none());
+        assert!(coins
+            .consolidate()
+            .min_inputs(3)
+            .build(&script, 200, heights)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn build_tx() {
+        let (coins, change) = funded(&[100_000, 200_000]);
+        let to = recipient();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let feerate = FeeRate::from_sat_per_vb(2);
+        let (tx, selection) = coins
+            .build_tx()
+            .add_recipient(&to, 150_000)
+            .feerate(feerate)
+            .enable_rbf()
+            .change_position(ChangePosition::Last)
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(tx.output[0].script_pubkey, to.script_pubkey());
+        assert_eq!(tx.output[0].value, 150_000);
+        assert!(tx.input.iter().all(|i| i.sequence == RBF_SEQUENCE));
+        let spent = selection.value();
+        let paid = tx.output.iter().map(|o| o.value).sum::<u64>();
+        // fee pays at least the fee rate for the estimated weight
+        let weight = transaction_base_weight(tx.input.len(), tx.output.len())
+            + tx.input.len() as u64 * 272
+            + tx.output
+                .iter()
+                .map(|o| output_weight(&o.script_pubkey))
+                .sum::<u64>();
+        assert!(spent - paid >= feerate.fee(weight));
+        if let Some(value) = selection.change {
+            assert_eq!(tx.output.last().unwrap().value, value);
+            assert_eq!(tx.output.last().unwrap().script_pubkey, change);
+        }
+
+        let (tx, _) = coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .coin_control(CoinControl::new().add_utxo(tx.input[0].previous_output))
+            .build(&change, 200, heights)
+            .unwrap();
+        assert!(tx.input.iter().all(|i| i.sequence != RBF_SEQUENCE));
+    }
+
+    #[test]
+    fn change_position() {
+        let (coins, change) = funded(&[100_000, 200_000]);
+        let to = recipient();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        // change is at a random position unless placed
+        let other = Address::from_str("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn").unwrap();
+        let payment = || {
+            coins
+                .build_tx()
+                .add_recipient(&to, 50_000)
+                .add_recipient(&to, 50_000)
+                .add_recipient(&to, 50_000)
+        };
+        let positions = (0..50)
+            .map(|_| {
+                let (tx, _) = payment().build(&change, 200, heights).unwrap();
+                tx.output
+                    .iter()
+                    .position(|o| o.script_pubkey == change)
+                    .unwrap()
+            })
+            .collect::<HashSet<_>>();
+        assert!(positions.len() > 1);
+        let (tx, _) = payment()
+            .change_position(ChangePosition::Index(1))
+            .change_address(&other)
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(tx.output[1].script_pubkey, other.script_pubkey());
+        assert!(payment()
+            .change_position(ChangePosition::Index(4))
+            .build(&change, 200, heights)
+            .is_err());
+    }
+
+    #[test]
+    fn fee_estimate() {
+        let (coins, change) = funded(&[100_000, 200_000]);
+        let to = recipient();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        // an estimate is used if no fee rate is given
+        let estimator = StaticFeeEstimator::new(FeeRate::from_sat_per_vb(10));
+        let (tx, _) = coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .build(&change, 200, heights)
+            .unwrap();
+        let control = CoinControl::new().add_utxo(tx.input[0].previous_output);
+        let (_, explicit) = coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .feerate(FeeRate::from_sat_per_vb(10))
+            .coin_control(control.clone())
+            .build(&change, 200, heights)
+            .unwrap();
+        let (_, estimated) = coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .fee_estimator(&estimator, 6)
+            .coin_control(control.clone())
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(estimated.fee, explicit.fee);
+        let (_, given) = coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .feerate(FeeRate::from_sat_per_vb(1))
+            .fee_estimator(&estimator, 6)
+            .coin_control(control)
+            .build(&change, 200, heights)
+            .unwrap();
+        assert!(given.fee < estimated.fee);
+    }
+
+    #[test]
+    fn unpayable() {
+        let (coins, change) = funded(&[100_000, 200_000]);
+        let to = recipient();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        assert!(coins.build_tx().build(&change, 200, heights).is_err());
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, 100)
+            .build(&change, 200, heights)
+            .is_err());
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, 1_000_000)
+            .build(&change, 200, heights)
+            .is_err());
+    }
+
+    #[test]
+    fn archived() {
+        let (mut master, mut coins) = wallet(&[100_000, 200_000]);
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = next_script(&mut master, (0, 0));
+        master.get_mut((0, 0)).unwrap().set_archived(true);
+        // the accounts the coins last followed are excluded
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .timelocks_of(&master)
+            .build(&change, 200, heights)
+            .is_ok());
+        coins.follow_archived(&master);
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .build(&change, 200, heights)
+            .is_err());
+        assert_eq!(coins.balance(200, heights).confirmed, 0);
+        assert!(coins.available_coins(200, heights).is_empty());
+        master.get_mut((0, 0)).unwrap().set_archived(false);
+        coins.follow_archived(&master);
+        assert_eq!(coins.balance(200, heights).confirmed, 300_000);
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .build(&change, 200, heights)
+            .is_ok());
+    }
+
+    #[test]
+    fn bump_fee() {
+        let (mut master, mut coins) = wallet(&[100_000, 100_000, 1_000_000]);
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let small = coins
+            .confirmed()
+            .iter()
+            .filter(|(_, c)| c.output.value == 100_000)
+            .map(|(p, _)| *p)
+            .collect::<Vec<_>>();
+        let change = next_script(&mut master, (0, 1));
+        let (original, _) = coins
+            .build_tx()
+            .add_recipient(&to, 150_000)
+            .feerate(FeeRate::from_sat_per_vb(2))
+            .enable_rbf()
+            .coin_control(CoinControl::new().add_utxo(small[0]).add_utxo(small[1]))
+            .change_position(ChangePosition::Last)
+            .build(&change, 200, heights)
+            .unwrap();
+        coins.process_unconfirmed_transaction(&mut master, &original);
+        let txid = original.txid();
+        let original_fee = coins.fee_of(&original).unwrap();
+
+        let (replacement, selection) = coins
+            .bump_fee(&txid)
+            .feerate(FeeRate::from_sat_per_vb(5))
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(replacement.input.len(), 2);
+        assert_eq!(replacement.output[0], original.output[0]);
+        assert_eq!(selection.change, Some(replacement.output[1].value));
+        assert!(replacement.output[1].value < original.output[1].value);
+        let fee = selection.value() - replacement.output.iter().map(|o| o.value).sum::<u64>();
+        assert!(fee > original_fee);
+
+        // the change is not sufficient, a confirmed coin is added to pay an absurd fee
+        assert!(matches!(
+            coins
+                .bump_fee(&txid)
+                .feerate(FeeRate::from_sat_per_vb(300))
+                .build(&change, 200, heights),
+            Err(Error::FeeBounds(_))
+        ));
+        let (replacement, _) = coins
+            .bump_fee(&txid)
+            .feerate(FeeRate::from_sat_per_vb(300))
+            .allow_absurd_fee()
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(replacement.input.len(), 3);
+        assert_eq!(
+            replacement.input[0].previous_output,
+            original.input[0].previous_output
+        );
+
+        assert!(coins
+            .bump_fee(&txid)
+            .feerate(FeeRate::from_sat_per_vb(2))
+            .build(&change, 200, heights)
+            .is_err());
+        assert!(coins
+            .bump_fee(&bitcoin::Txid::default())
+            .build(&change, 200, heights)
+            .is_err());
+
+        // not signalling
+        let (final_tx, _) = coins
+            .build_tx()
+            .add_recipient(&to, 150_000)
+            .build(&change, 200, heights)
+            .unwrap();
+        coins.process_unconfirmed_transaction(&mut master, &final_tx);
+        assert!(coins
+            .bump_fee(&final_tx.txid())
+            .feerate(FeeRate::from_sat_per_vb(10))
+            .build(&change, 200, heights)
+            .is_err());
+    }
+
+    #[test]
+    fn bump_self_payment() {
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let to = recipient();
+        for _ in 0..8 {
+            let (mut master, mut coins) = wallet(&[1_000_000]);
+            let own = next_script(&mut master, (0, 0));
+            let change = next_script(&mut master, (0, 1));
+            let (original, _) = coins
+                .build_tx()
+                .add_recipient(&to, 50_000)
+                .add_recipient(
+                    &Address::from_script(&own, Network::Testnet).unwrap(),
+                    30_000,
+                )
+                .feerate(FeeRate::from_sat_per_vb(2))
+                .enable_rbf()
+                .build(&change, 200, heights)
+                .unwrap();
+            coins.process_unconfirmed_transaction(&mut master, &original);
+            let txid = original.txid();
+            let own_outputs = (0..original.output.len() as u32)
+                .filter(|vout| coins.is_own(&OutPoint::new(txid, *vout)))
+                .count();
+            assert_eq!(own_outputs, 2);
+            // the payment to the own key is kept wherever the change is
+            let (replacement, selection) = coins
+                .bump_fee(&txid)
+                .feerate(FeeRate::from_sat_per_vb(5))
+                .build(&next_script(&mut master, (0, 1)), 200, heights)
+                .unwrap();
+            assert_eq!(replacement.output.len(), 3);
+            for payment in original.output.iter().filter(|o| o.script_pubkey != change) {
+                assert!(replacement.output.contains(payment));
+            }
+            let bumped = replacement
+                .output
+                .iter()
+                .find(|o| o.script_pubkey == change)
+                .unwrap();
+            assert_eq!(selection.change, Some(bumped.value));
+            // change must be own
+            let foreign = original
+                .output
+                .iter()
+                .position(|o| o.value == 50_000)
+                .unwrap() as u32;
+            assert!(coins
+                .bump_fee(&txid)
+                .feerate(FeeRate::from_sat_per_vb(5))
+                .change_vout(foreign)
+                .build(&change, 200, heights)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let (mut master, mut coins) = wallet(&[100_000]);
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = next_script(&mut master, (0, 1));
+        let (original, _) = coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .feerate(FeeRate::from_sat_per_vb(2))
+            .enable_rbf()
+            .change_position(ChangePosition::Last)
+            .build(&change, 200, heights)
+            .unwrap();
+        coins.process_unconfirmed_transaction(&mut master, &original);
+        let original_fee = coins.fee_of(&original).unwrap();
+
+        let (cancellation, selection) = coins
+            .cancel(&original.txid())
+            .feerate(FeeRate::from_sat_per_vb(3))
+            .build(&next_script(&mut master, (0, 0)), 200, heights)
+            .unwrap();
+        assert_eq!(cancellation.input, original.input);
+        assert_eq!(cancellation.output.len(), 1);
+        // back to the change of the original
+        assert_eq!(cancellation.output[0].script_pubkey, change);
+        assert_eq!(selection.change, Some(cancellation.output[0].value));
+        let fee = 100_000 - cancellation.output[0].value;
+        // the smaller transaction at a higher fee rate still pays for the replaced one
+        let weight =
+            transaction_base_weight(1, 1) + selection.selected[0].weight + output_weight(&change);
+        assert!(fee >= original_fee + FeeRate::from_sat_per_vb(1).fee(weight));
+        let low = FeeBounds {
+            max_fee: 100,
+            ..FeeBounds::default()
+        };
+        assert!(matches!(
+            coins
+                .cancel(&original.txid())
+                .feerate(FeeRate::from_sat_per_vb(3))
+                .fee_bounds(low)
+                .build(&change, 200, heights),
+            Err(Error::FeeBounds(_))
+        ));
+        assert!(coins
+            .cancel(&original.txid())
+            .feerate(FeeRate::from_sat_per_vb(2))
+            .build(&change, 200, heights)
+            .is_err());
+    }
+
+    #[test]
+    fn drain_to() {
+        let (coins, script) = funded(&[100_000, 200_000, 50]);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let (tx, selection) = coins
+            .build_tx()
+            .drain_to(to.script_pubkey())
+            .build(&script, 200, heights)
+            .unwrap();
+        // the 50 sat coin is not worth spending
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(tx.output[0].script_pubkey, to.script_pubkey());
+        let weight = transaction_base_weight(2, 1)
+            + selection.selected.iter().map(|c| c.weight).sum::<u64>()
+            + output_weight(&to.script_pubkey());
+        assert_eq!(
+            tx.output[0].value + FeeRate::from_sat_per_vb(1).fee(weight),
+            300_000
+        );
+
+        // drain a subset next to a payment
+        let point = selection
+            .selected
+            .iter()
+            .find(|c| c.value() == 200_000)
+            .unwrap()
+            .point;
+        let (tx, _) = coins
+            .build_tx()
+            .add_output(TxOut {
+                value: 50_000,
+                script_pubkey: script.clone(),
+            })
+            .drain_to(to.script_pubkey())
+            .coin_control(CoinControl::new().add_utxo(point))
+            .change_position(ChangePosition::Last)
+            .build(&script, 200, heights)
+            .unwrap();
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.output.len(), 2);
+        assert_eq!(tx.output[0].value, 50_000);
+        assert!(tx.output[1].value > 149_000);
+
+        assert!(coins
+            .build_tx()
+            .add_output(TxOut {
+                value: 199_900,
+                script_pubkey: script.clone(),
+            })
+            .drain_to(to.script_pubkey())
+            .coin_control(CoinControl::new().add_utxo(point))
+            .build(&script, 200, heights)
+            .is_err());
+    }
+
+    #[test]
+    fn fee_bounds() {
+        let (coins, change) = funded(&[100_000, 200_000]);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let pay = |amount: u64, feerate: FeeRate, bounds: FeeBounds, absurd: bool| {
+            let builder = coins
+                .build_tx()
+                .add_recipient(&to, amount)
+                .feerate(feerate)
+                .fee_bounds(bounds);
+            if absurd {
+                builder.allow_absurd_fee()
+            } else {
+                builder
+            }
+            .build(&change, 200, heights)
+        };
+        let bounds = FeeBounds::default();
+        assert!(pay(50_000, FeeRate::from_sat_per_vb(10), bounds, false).is_ok());
+        // the minimum relay fee rate can not be lifted
+        for absurd in [false, true].iter() {
+            assert!(matches!(
+                pay(50_000, FeeRate::from_sat_per_kwu(200), bounds, *absurd),
+                Err(Error::FeeBounds(_))
+            ));
+        }
+        // more than 10% of the amount
+        assert!(matches!(
+            pay(10_000, FeeRate::from_sat_per_vb(20), bounds, false),
+            Err(Error::FeeBounds(_))
+        ));
+        let (tx, _) = pay(10_000, FeeRate::from_sat_per_vb(20), bounds, true).unwrap();
+        assert!(coins.fee_of(&tx).unwrap() > 1_000);
+        // unless the fee is the least nodes relay
+        assert!(pay(1_000, FeeRate::from_sat_per_vb(1), bounds, false).is_ok());
+
+        let low = FeeBounds {
+            max_feerate: FeeRate::from_sat_per_vb(50),
+            max_fee: 1_000,
+            ..bounds
+        };
+        assert!(matches!(
+            pay(50_000, FeeRate::from_sat_per_vb(100), low, false),
+            Err(Error::FeeBounds(_))
+        ));
+        assert!(pay(50_000, FeeRate::from_sat_per_vb(100), low, true).is_ok());
+        assert!(matches!(
+            pay(50_000, FeeRate::from_sat_per_vb(10), low, false),
+            Err(Error::FeeBounds(_))
+        ));
+        assert!(pay(50_000, FeeRate::from_sat_per_vb(5), low, false).is_ok());
+    }
+
+    #[test]
+    fn batching() {
+        let (coins, change) = funded(&[100_000, 200_000]);
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let a = Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default());
+        let b = Script::new_v0_wsh(&bitcoin::WScriptHash::default());
+        let batch = || {
+            coins
+                .build_tx()
+                .add_output(TxOut {
+                    value: 60_000,
+                    script_pubkey: a.clone(),
+                })
+                .add_output(TxOut {
+                    value: 20_000,
+                    script_pubkey: b.clone(),
+                })
+                .feerate(FeeRate::from_sat_per_vb(10))
+                .change_position(ChangePosition::Last)
+        };
+        let (paid, selection) = batch().build(&change, 200, heights).unwrap();
+        let weight = transaction_base_weight(paid.input.len(), paid.output.len())
+            + selection.selected.iter().map(|c| c.weight).sum::<u64>()
+            + paid
+                .output
+                .iter()
+                .map(|o| output_weight(&o.script_pubkey))
+                .sum::<u64>();
+        let fee = FeeRate::from_sat_per_vb(10).fee(weight);
+
+        // recipients pay, the sender spends exactly the amounts
+        let (tx, selection) = batch()
+            .fee_deduction(FeeDeduction::Proportional)
+            .build(&change, 200, heights)
+            .unwrap();
+        let spent = selection.value();
+        assert_eq!(spent - tx.output[2].value, 80_000);
+        let deducted = 80_000 - tx.output[0].value - tx.output[1].value;
+        assert_eq!(deducted, fee);
+        // the second pays a quarter, rounded down
+        assert_eq!(20_000 - tx.output[1].value, fee / 4);
+
+        let (tx, _) = batch()
+            .fee_deduction(FeeDeduction::Recipients(vec![1]))
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(tx.output[0].value, 60_000);
+        assert_eq!(tx.output[1].value, 20_000 - fee);
+        assert!(batch()
+            .fee_deduction(FeeDeduction::Recipients(vec![2]))
+            .build(&change, 200, heights)
+            .is_err());
+        assert!(batch()
+            .add_output(TxOut {
+                value: 1000,
+                script_pubkey: a.clone(),
+            })
+            .fee_deduction(FeeDeduction::Recipients(vec![2]))
+            .build(&change, 200, heights)
+            .is_err());
+
+        let (tx, selection) = batch()
+            .ordering(OutputOrdering::Bip69)
+            .build(&change, 200, heights)
+            .unwrap();
+        assert!(tx.output.windows(2).all(|w| w[0].value <= w[1].value));
+        let inputs = tx
+            .input
+            .iter()
+            .map(|i| i.previous_output)
+            .collect::<Vec<_>>();
+        let selected = selection
+            .selected
+            .iter()
+            .map(|c| c.point)
+            .collect::<Vec<_>>();
+        assert_eq!(inputs, selected);
+    }
+
+    #[test]
+    fn sequences() {
+        let (mut master, coins) = wallet(&[100_000, 100_000]);
+        let change = next_script(&mut master, (0, 0));
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let point = *coins.confirmed().keys().next().unwrap();
+        let other = *coins.confirmed().keys().find(|p| **p != point).unwrap();
+        let build = |sequence: u32, rbf: bool, lock_time: u32, height: u32| {
+            let mut builder = coins
+                .build_tx()
+                .add_recipient(&to, 50_000)
+                .coin_control(CoinControl::new().add_utxo(point))
+                .input_sequence(point, sequence)
+                .lock_time(lock_time);
+            if rbf {
+                builder = builder.enable_rbf();
+            }
+            builder.build(&change, height, heights)
+        };
+        let (transaction, _) = build(0xffff_fffd, true, 20, 10).unwrap();
+        assert_eq!(transaction.input[0].sequence, 0xffff_fffd);
+        assert_eq!(transaction.lock_time, 20);
+        // final sequence numbers do not signal replaceability and do not enforce lock time
+        assert!(build(0xffff_fffe, true, 0, 10).is_err());
+        assert!(build(0xffff_ffff, false, 20, 10).is_err());
+        assert!(build(0xffff_ffff, false, 0, 10).is_ok());
+        // a relative lock of 5 blocks after confirmation at height 1
+        assert!(build(5, false, 0, 5).is_err());
+        assert_eq!(build(5, false, 0, 6).unwrap().0.input[0].sequence, 5);
+        // relative locks in time are not checked
+        assert!(build(SEQUENCE_TYPE_FLAG | 1, false, 0, 1).is_ok());
+        // the coin must be spent
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .coin_control(CoinControl::new().add_utxo(point))
+            .input_sequence(other, 1)
+            .build(&change, 10, heights)
+            .is_err());
+    }
+
+    #[test]
+    fn truc() {
+        use coins::UnconfirmedPolicy;
+
+        let (mut master, mut coins) = wallet(&[100_000, 100_000]);
+        coins.set_unconfirmed_policy(UnconfirmedPolicy::mempool_limits());
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = next_script(&mut master, (0, 0));
+        let own = next_script(&mut master, (0, 0));
+        let confirmed = *coins.confirmed().keys().next().unwrap();
+        let (parent, _) = coins
+            .build_tx()
+            .add_recipient(&to, 10_000)
+            .add_output(TxOut {
+                value: 20_000,
+                script_pubkey: own,
+            })
+            .coin_control(CoinControl::new().add_utxo(confirmed))
+            .change_position(ChangePosition::Last)
+            .truc()
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(parent.version, TRUC_VERSION);
+        coins.process_unconfirmed_transaction(&mut master, &parent);
+        let output = |txid: Txid, vout: u32| OutPoint { txid, vout };
+        let spend = |coins: &Coins, point: OutPoint, truc: bool, outputs: usize| {
+            let mut builder = coins
+                .build_tx()
+                .coin_control(CoinControl::new().add_utxo(point));
+            for _ in 0..outputs {
+                builder = builder.add_recipient(&to, 1_000);
+            }
+            if truc {
+                builder = builder.truc();
+            }
+            builder
+                .change_position(ChangePosition::Last)
+                .build(&change, 200, heights)
+        };
+        // a TRUC child of a TRUC parent
+        let (child, _) = spend(&coins, output(parent.txid(), 2), true, 1).unwrap();
+        assert_eq!(child.version, TRUC_VERSION);
+        // other versions do not spend unconfirmed coins of TRUC parents
+        assert!(spend(&coins, output(parent.txid(), 2), false, 1).is_err());
+        // a child is at most 1000 vB
+        assert!(spend(&coins, output(parent.txid(), 2), true, 30).is_err());
+
+        coins.process_unconfirmed_transaction(&mut master, &child);
+        // TRUC parents have at most one unconfirmed child
+        assert!(spend(&coins, output(parent.txid(), 1), true, 1).is_err());
+        // and children have no children
+        assert!(spend(&coins, output(child.txid(), 1), true, 1).is_err());
+        // the replacement keeps the version and needs no signalling
+        let (replacement, _) = coins
+            .bump_fee(&child.txid())
+            .feerate(FeeRate::from_sat_per_vb(10))
+            .build(&change, 200, heights)
+            .unwrap();
+        assert_eq!(replacement.version, TRUC_VERSION);
+    }
+
+    #[test]
+    fn timelocks() {
+        use bitcoin::blockdata::script::Builder;
+        use bitcoin::{PublicKey, SigHashType};
+        use coins::Maturity;
+
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 0, 0, 0).unwrap(),
+        );
+        // spendable after height 100 and 10 blocks after confirmation
+        let scripter = |pk: &PublicKey, csv: Option<u16>| {
+            Builder::new()
+                .push_int(100)
+                .push_opcode(all::OP_CLTV)
+                .push_opcode(all::OP_DROP)
+                .push_int(csv.unwrap() as i64)
+                .push_opcode(all::OP_CSV)
+                .push_opcode(all::OP_DROP)
+                .push_slice(pk.to_bytes().as_slice())
+                .push_opcode(all::OP_CHECKSIG)
+                .into_script()
+        };
+        let account = master.get_mut((0, 0)).unwrap();
+        account.add_script_key(scripter, None, Some(10)).unwrap();
+        let script_code = account.get_key(0).unwrap().script_code.clone();
+        assert_eq!(
+            Timelocks::of(&script_code),
+            Timelocks {
+                after: Some(100),
+                older: Some(10)
+            }
+        );
+        let funding = fixtures::funding(vec![TxOut {
+            value: 100_000,
+            script_pubkey: account.get_key(0).unwrap().address.script_pubkey(),
+        }]);
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block(Network::Testnet, vec![funding.clone()]));
+        let point = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        assert_eq!(
+            coins.earliest_spend(&point, None, heights),
+            Some(Maturity {
+                height: 11,
+                time: None
+            })
+        );
+        assert_eq!(
+            coins.earliest_spend(&point, Some(&master), heights),
+            Some(Maturity {
+                height: 100,
+                time: None
+            })
+        );
+
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = funding.output[0].script_pubkey.clone();
+        let build = |height| {
+            coins
+                .build_tx()
+                .add_recipient(&to, 50_000)
+                .timelocks_of(&master)
+                .build(&change, height, heights)
+        };
+        assert!(build(5).is_err());
+        assert!(build(50).is_err());
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .coin_control(CoinControl::new().add_utxo(point))
+            .timelocks_of(&master)
+            .build(&change, 50, heights)
+            .is_err());
+        // explicit sequence numbers must keep the relative lock
+        for sequence in [9, 0xffff_fffe].iter() {
+            assert!(coins
+                .build_tx()
+                .add_recipient(&to, 50_000)
+                .timelocks_of(&master)
+                .input_sequence(point, *sequence)
+                .build(&change, 100, heights)
+                .is_err());
+        }
+        let (mut transaction, _) = build(100).unwrap();
+        assert_eq!(transaction.input[0].sequence, 10);
+        assert_eq!(transaction.lock_time, 100);
+
+        master
+            .sign(
+                &mut transaction,
+                SigHashType::All,
+                &|_: &OutPoint| Some(funding.output[0].clone()),
+                &mut unlocker,
+            )
+            .unwrap();
+        transaction
+            .verify(|_| Some(funding.output[0].clone()))
+            .unwrap();
+    }
+}
@@ -0,0 +1,394 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Filter and block cache
+//!
+//! A rescan, e.g. after importing one more account, asks the backend again for filters and
+//! blocks it served before. CachedBackend keeps them in a directory, a file per filter or
+//! block named by the block hash, and serves them from there.
+//!
+//! The cache is bounded in size, the least recently used files are deleted first. Files are
+//! written atomically, a file that does not decode or a block that does not hash to its name is
+//! dropped and fetched again. Failing to write the cache does not fail the request, the cache
+//! only saves bandwidth.
+//!
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHeader, OutPoint, Transaction, Txid};
+
+use backend::{ChainBackend, TxStatus};
+use error::Error;
+use fee::{FeeHistogram, FeeRate};
+use storage::write_atomic;
+
+const FILTER_EXTENSION: &str = "filter";
+const BLOCK_EXTENSION: &str = "block";
+
+/// files in the cache
+#[derive(Default)]
+struct Entries {
+    /// size and last use of files by name
+    files: HashMap<String, (u64, u64)>,
+    size: u64,
+    /// counts uses
+    clock: u64,
+    hits: usize,
+}
+
+/// A backend keeping filters and blocks it served in a directory
+pub struct CachedBackend<B> {
+    backend: B,
+    directory: PathBuf,
+    max_size: u64,
+    entries: RefCell<Entries>,
+}
+
+impl<B: ChainBackend> CachedBackend<B> {
+    /// cache in directory, created if missing, up to 256 MiB
+    pub fn open<P: AsRef<Path>>(backend: B, directory: P) -> Result<CachedBackend<B>, Error> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            if name.ends_with(".tmp") {
+                fs::remove_file(entry.path()).ok();
+                continue;
+            }
+            files.push((metadata.modified().ok(), name, metadata.len()));
+        }
+        // files used last were written last when the cache was open before
+        files.sort();
+        let mut entries = Entries::default();
+        for (_, name, size) in files {
+            entries.clock += 1;
+            entries.size += size;
+            entries.files.insert(name, (size, entries.clock));
+        }
+        Ok(CachedBackend {
+            backend,
+            directory,
+            max_size: 256 << 20,
+            entries: RefCell::new(entries),
+        })
+    }
+
+    /// bound the cache to bytes, evicting at once if it holds more
+    pub fn max_size(mut self, bytes: u64) -> CachedBackend<B> {
+        self.max_size = bytes;
+        self.evict();
+        self
+    }
+
+    /// bytes held by the cache
+    pub fn size(&self) -> u64 {
+        self.entries.borrow().size
+    }
+
+    /// requests served from the cache
+    pub fn hits(&self) -> usize {
+        self.entries.borrow().hits
+    }
+
+    /// the wrapped backend
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    /// delete all cached files
+    pub fn clear(&self) -> Result<(), Error> {
+        let names = self
+            .entries
+            .borrow()
+            .files
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in names {
+            self.remove(&name);
+        }
+        Ok(())
+    }
+
+    fn name(hash: &BlockHash, extension: &str) -> String {
+        format!("{}.{}", hash, extension)
+    }
+
+    /// content of a cached file, marked as used
+    fn get(&self, name: &str) -> Option<Vec<u8>> {
+        if !self.entries.borrow().files.contains_key(name) {
+            return None;
+        }
+        match fs::read(self.directory.join(name)) {
+            Ok(data) => {
+                let mut entries = self.entries.borrow_mut();
+                entries.clock += 1;
+                let clock = entries.clock;
+                entries.hits += 1;
+                if let Some(entry) = entries.files.get_mut(name) {
+                    entry.1 = clock;
+                }
+                Some(data)
+            }
+            Err(_) => {
+                self.remove(name);
+                None
+            }
+        }
+    }
+
+    fn put(&self, name: String, data: &[u8]) {
+        let size = data.len() as u64;
+        if size > self.max_size || write_atomic(self.directory.join(&name), data).is_err() {
+            return;
+        }
+        {
+            let mut entries = self.entries.borrow_mut();
+            entries.clock += 1;
+            let clock = entries.clock;
+            if let Some((previous, _)) = entries.files.insert(name, (size, clock)) {
+                entries.size -= previous;
+            }
+            entries.size += size;
+        }
+        self.evict();
+    }
+
+    fn remove(&self, name: &str) {
+        fs::remove_file(self.directory.join(name)).ok();
+        let mut entries = self.entries.borrow_mut();
+        if let Some((size, _)) = entries.files.remove(name) {
+            entries.size -= size;
+        }
+    }
+
+    /// delete least recently used files until the cache fits its bound
+    fn evict(&self) {
+        loop {
+            let oldest = {
+                let entries = self.entries.borrow();
+                if entries.size <= self.max_size {
+                    return;
+                }
+                entries
+                    .files
+                    .iter()
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(name, _)| name.clone())
+            };
+            match oldest {
+                Some(name) => self.remove(&name),
+                None => return,
+            }
+        }
+    }
+}
+
+impl<B: ChainBackend> ChainBackend for CachedBackend<B> {
+    fn tip(&self) -> Result<u32, Error> {
+        self.backend.tip()
+    }
+
+    fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        self.backend.header(height)
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let name = Self::name(hash, BLOCK_EXTENSION);
+        if let Some(data) = self.get(&name) {
+            match deserialize::<Block>(data.as_slice()) {
+                Ok(block) if block.block_hash() == *hash => return Ok(block),
+                _ => self.remove(&name),
+            }
+        }
+        let block = self.backend.block(hash)?;
+        if block.block_hash() == *hash {
+            self.put(name, &serialize(&block));
+        }
+        Ok(block)
+    }
+
+    fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+        let name = Self::name(hash, FILTER_EXTENSION);
+        if let Some(data) = self.get(&name) {
+            match deserialize::<Vec<u8>>(data.as_slice()) {
+                Ok(content) => return Ok(Some(BlockFilter::new(content.as_slice()))),
+                Err(_) => self.remove(&name),
+            }
+        }
+        let filter = self.backend.filter(hash)?;
+        if let Some(ref filter) = filter {
+            self.put(name, &serialize(&filter.content));
+        }
+        Ok(filter)
+    }
+
+    fn filter_header(&self, hash: &BlockHash) -> Result<Option<FilterHeader>, Error> {
+        self.backend.filter_header(hash)
+    }
+
+    fn tx_status(&self, txid: &Txid) -> Result<TxStatus, Error> {
+        self.backend.tx_status(txid)
+    }
+
+    fn spender(&self, point: &OutPoint) -> Result<Option<Txid>, Error> {
+        self.backend.spender(point)
+    }
+
+    fn mempool_accept(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Option<Vec<Option<String>>>, Error> {
+        self.backend.mempool_accept(transactions)
+    }
+
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+        self.backend.broadcast(transaction)
+    }
+
+    fn broadcast_package(&self, transactions: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        self.backend.broadcast_package(transactions)
+    }
+
+    fn feerate(&self, target: u16) -> Result<FeeRate, Error> {
+        self.backend.feerate(target)
+    }
+
+    fn fee_histogram(&self) -> Result<Option<FeeHistogram>, Error> {
+        self.backend.fee_histogram()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::bip158::BlockFilterWriter;
+    use bitcoin::Network;
+    use std::cell::Cell;
+
+    use super::*;
+
+    struct Chain {
+        blocks: Vec<Block>,
+        requests: Cell<usize>,
+    }
+
+    impl ChainBackend for Chain {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(self.blocks.get(height as usize).map(|b| b.header))
+        }
+
+        fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+            self.requests.set(self.requests.get() + 1);
+            self.blocks
+                .iter()
+                .find(|b| b.block_hash() == *hash)
+                .cloned()
+                .ok_or(Error::Backend("unknown block"))
+        }
+
+        fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            self.requests.set(self.requests.get() + 1);
+            let block = self.block(hash)?;
+            self.requests.set(self.requests.get() - 1);
+            let mut content = Vec::new();
+            {
+                let mut writer = BlockFilterWriter::new(&mut content, &block);
+                writer.add_output_scripts();
+                writer.finish().unwrap();
+            }
+            Ok(Some(BlockFilter::new(content.as_slice())))
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    #[test]
+    fn cached_backend() {
+        let directory = std::env::temp_dir().join(format!("cache-{}", std::process::id()));
+        let mut blocks = vec![genesis_block(Network::Testnet)];
+        for nonce in 1..4 {
+            let mut block = blocks[0].clone();
+            block.header.nonce = nonce;
+            blocks.push(block);
+        }
+        let hashes = blocks.iter().map(|b| b.block_hash()).collect::<Vec<_>>();
+        let size = serialize(&blocks[0]).len() as u64;
+        let chain = Chain {
+            blocks: blocks.clone(),
+            requests: Cell::new(0),
+        };
+        let cache = CachedBackend::open(chain, &directory).unwrap();
+        let filter = cache.filter(&hashes[0]).unwrap().unwrap();
+        for hash in hashes.iter() {
+            assert_eq!(cache.block(hash).unwrap().block_hash(), *hash);
+        }
+        assert_eq!(cache.inner().requests.get(), 5);
+        assert_eq!(cache.filter(&hashes[0]).unwrap().unwrap(), filter);
+        assert_eq!(cache.block(&hashes[1]).unwrap(), blocks[1]);
+        assert_eq!((cache.inner().requests.get(), cache.hits()), (5, 2));
+
+        // the cache survives a restart and forgets what was used least
+        let chain = Chain {
+            blocks,
+            requests: Cell::new(0),
+        };
+        let cache = CachedBackend::open(chain, &directory)
+            .unwrap()
+            .max_size(2 * size);
+        assert!(cache.size() <= 2 * size);
+        cache.block(&hashes[3]).unwrap();
+        assert_eq!(cache.inner().requests.get(), 0);
+        cache.block(&hashes[0]).unwrap();
+        assert_eq!(cache.inner().requests.get(), 1);
+
+        // a corrupt file is fetched again
+        fs::write(
+            directory.join(CachedBackend::<Chain>::name(&hashes[0], BLOCK_EXTENSION)),
+            b"junk",
+        )
+        .unwrap();
+        assert_eq!(cache.block(&hashes[0]).unwrap().block_hash(), hashes[0]);
+        assert_eq!(cache.inner().requests.get(), 2);
+        cache.clear().unwrap();
+        assert_eq!(cache.size(), 0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
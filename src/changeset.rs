@@ -0,0 +1,679 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Changesets
+//!
+//! Coins record what their mutations touch, Coins::take_changeset turns that into a ChangeSet
+//! carrying the new state of only those records. Backends append changesets instead of
+//! rewriting the wallet state and compact them from time to time.
+//!
+//! Devices sharing a seed follow the chain each on their own but share the edits of the user.
+//! Labels and drafts carry the time of their last edit, the later edit wins whatever order
+//! changesets are merged in. ChangeSet::export_batch encrypts those edits for an other device,
+//! which merges them with Coins::sync.
+//!
+//! Notes of transactions are not part of changesets, they are only kept in the encrypted wallet
+//! file and backups.
+//!
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::{OutPoint, Transaction, Txid, VarInt};
+
+use account::MasterAccount;
+use cluster::Clusters;
+use coins::{CoinMetadata, CoinStore, Coins, Draft, MemoryCoinStore, StoredCoin};
+use error::Error;
+use proved::ProvedTransaction;
+use storage::{seal, unseal, write_atomic, KdfParams};
+
+/// Changes of wallet state, None for removed records
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChangeSet {
+    pub coins: BTreeMap<OutPoint, Option<StoredCoin>>,
+    /// proofs by the id of the proved transaction
+    pub proofs: BTreeMap<Txid, Option<ProvedTransaction>>,
+    /// all linkage clusters if they changed
+    pub clusters: Option<Clusters>,
+    pub pending: BTreeMap<Txid, Option<Transaction>>,
+    /// drafts by name with the time they were saved or discarded, in milliseconds since epoch
+    pub drafts: BTreeMap<String, (u64, Option<Draft>)>,
+    /// next address index of accounts that revealed addresses
+    pub revealed: BTreeMap<(u32, u32), u32>,
+    /// metadata the user edited with the time of the edit, in milliseconds since epoch
+    pub labels: BTreeMap<OutPoint, (u64, CoinMetadata)>,
+}
+
+/// whether an edit replaces an other, the later wins and edits of the same time are ordered by
+/// their serialization so that all devices keep the same
+fn supersedes(edit: (u64, Vec<u8>), other: (u64, Vec<u8>)) -> bool {
+    edit > other
+}
+
+pub(crate) fn label_supersedes(edit: &(u64, CoinMetadata), other: &(u64, CoinMetadata)) -> bool {
+    supersedes(
+        (edit.0, encode::serialize(&edit.1)),
+        (other.0, encode::serialize(&other.1)),
+    )
+}
+
+pub(crate) fn draft_supersedes(edit: &(u64, Option<Draft>), other: &(u64, Option<Draft>)) -> bool {
+    let serialize = |draft: &Option<Draft>| {
+        let mut data = Vec::new();
+        encode_option(draft, &mut data).expect("in memory writers don't error");
+        data
+    };
+    supersedes((edit.0, serialize(&edit.1)), (other.0, serialize(&other.1)))
+}
+
+const SYNC_BATCH_MAGIC: &[u8; 4] = b"RWSB";
+const SYNC_BATCH_VERSION: u8 = 1;
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+            && self.proofs.is_empty()
+            && self.clusters.is_none()
+            && self.pending.is_empty()
+            && self.drafts.is_empty()
+            && self.revealed.is_empty()
+            && self.labels.is_empty()
+    }
+
+    /// add a later changeset
+    /// Coins, proofs, clusters and pending transactions of the later changeset replace those
+    /// before. Drafts, labels and revealed addresses merge in any order, so changesets of other
+    /// devices sharing the wallet may be merged too.
+    pub fn merge(&mut self, later: ChangeSet) {
+        self.coins.extend(later.coins);
+        self.proofs.extend(later.proofs);
+        if later.clusters.is_some() {
+            self.clusters = later.clusters;
+        }
+        self.pending.extend(later.pending);
+        for (name, draft) in later.drafts {
+            match self.drafts.entry(name) {
+                Entry::Vacant(entry) => {
+                    entry.insert(draft);
+                }
+                Entry::Occupied(mut entry) => {
+                    if draft_supersedes(&draft, entry.get()) {
+                        entry.insert(draft);
+                    }
+                }
+            }
+        }
+        for (id, next) in later.revealed {
+            let known = self.revealed.entry(id).or_insert(next);
+            *known = (*known).max(next);
+        }
+        for (point, label) in later.labels {
+            match self.labels.entry(point) {
+                Entry::Vacant(entry) => {
+                    entry.insert(label);
+                }
+                Entry::Occupied(mut entry) => {
+                    if label_supersedes(&label, entry.get()) {
+                        entry.insert(label);
+                    }
+                }
+            }
+        }
+    }
+
+    /// the edits devices sharing a wallet exchange: drafts, labels and revealed addresses
+    /// Coins, proofs and pending transactions are not shared, every device follows the chain.
+    pub fn shared(&self) -> ChangeSet {
+        ChangeSet {
+            drafts: self.drafts.clone(),
+            revealed: self.revealed.clone(),
+            labels: self.labels.clone(),
+            ..ChangeSet::default()
+        }
+    }
+
+    /// encrypt the shared edits for an other device of the wallet
+    pub fn export_batch(&self, passphrase: &str, kdf: KdfParams) -> Result<Vec<u8>, Error> {
+        let mut payload = SYNC_BATCH_MAGIC.to_vec();
+        payload.push(SYNC_BATCH_VERSION);
+        self.shared().consensus_encode(&mut payload)?;
+        seal(payload.as_slice(), passphrase, kdf)
+    }
+
+    /// decrypt a batch of export_batch
+    pub fn import_batch(data: &[u8], passphrase: &str) -> Result<ChangeSet, Error> {
+        let payload = unseal(data, passphrase)?;
+        if payload.len() < 5 || &payload[..4] != SYNC_BATCH_MAGIC {
+            return Err(Error::Storage("not a changeset batch"));
+        }
+        if payload[4] == 0 || payload[4] > SYNC_BATCH_VERSION {
+            return Err(Error::Storage("unknown changeset batch version"));
+        }
+        let changeset: ChangeSet = encode::deserialize(&payload[5..])?;
+        Ok(changeset.shared())
+    }
+
+    /// write the changes into a coin store, does not flush
+    pub fn apply<S: CoinStore>(&self, store: &mut S) -> Result<(), Error> {
+        for (point, coin) in self.coins.iter() {
+            match coin {
+                Some(coin) => store.put_coin(point, coin)?,
+                None => store.remove_coin(point)?,
+            }
+        }
+        for (txid, proof) in self.proofs.iter() {
+            match proof {
+                Some(proof) => store.put_proof(proof)?,
+                None => store.remove_proof(txid)?,
+            }
+        }
+        if let Some(ref clusters) = self.clusters {
+            store.put_clusters(clusters)?;
+        }
+        for (txid, transaction) in self.pending.iter() {
+            match transaction {
+                Some(transaction) => store.put_pending(transaction)?,
+                None => store.remove_pending(txid)?,
+            }
+        }
+        for (name, (_, draft)) in self.drafts.iter() {
+            match draft {
+                Some(draft) => store.put_draft(name, draft)?,
+                None => store.remove_draft(name)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// coins of an aggregate of all changesets
+    pub fn to_coins(&self) -> Result<Coins, Error> {
+        let mut memory = MemoryCoinStore::default();
+        self.apply(&mut memory)?;
+        let mut coins = Coins::load(&memory)?;
+        coins.merge_edits(self, false);
+        Ok(coins)
+    }
+
+    /// instantiate the revealed addresses of known accounts
+    pub fn reveal(&self, master_account: &mut MasterAccount) -> Result<(), Error> {
+        for (id, next) in self.revealed.iter() {
+            if let Some(account) = master_account.get_mut(*id) {
+                if *next > account.next() {
+                    account.do_look_ahead(Some(*next - 1))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode_option<T: Encodable, W: io::Write>(
+    value: &Option<T>,
+    mut w: W,
+) -> Result<usize, io::Error> {
+    match value {
+        Some(value) => Ok(1u8.consensus_encode(&mut w)? + value.consensus_encode(&mut w)?),
+        None => 0u8.consensus_encode(&mut w),
+    }
+}
+
+fn decode_option<T: Decodable, D: io::Read>(mut d: D) -> Result<Option<T>, encode::Error> {
+    match u8::consensus_decode(&mut d)? {
+        0 => Ok(None),
+        1 => Ok(Some(T::consensus_decode(&mut d)?)),
+        _ => Err(encode::Error::ParseFailed("invalid option")),
+    }
+}
+
+impl Encodable for ChangeSet {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = VarInt(self.coins.len() as u64).consensus_encode(&mut w)?;
+        for (point, coin) in self.coins.iter() {
+            len += point.consensus_encode(&mut w)?;
+            len += encode_option(coin, &mut w)?;
+        }
+        len += VarInt(self.proofs.len() as u64).consensus_encode(&mut w)?;
+        for (txid, proof) in self.proofs.iter() {
+            len += txid.consensus_encode(&mut w)?;
+            len += encode_option(proof, &mut w)?;
+        }
+        len += encode_option(&self.clusters, &mut w)?;
+        len += VarInt(self.pending.len() as u64).consensus_encode(&mut w)?;
+        for (txid, transaction) in self.pending.iter() {
+            len += txid.consensus_encode(&mut w)?;
+            len += encode_option(transaction, &mut w)?;
+        }
+        len += VarInt(self.drafts.len() as u64).consensus_encode(&mut w)?;
+        for (name, (time, draft)) in self.drafts.iter() {
+            len += name.consensus_encode(&mut w)?;
+            len += time.consensus_encode(&mut w)?;
+            len += encode_option(draft, &mut w)?;
+        }
+        len += VarInt(self.revealed.len() as u64).consensus_encode(&mut w)?;
+        for ((account, sub), next) in self.revealed.iter() {
+            len += account.consensus_encode(&mut w)?;
+            len += sub.consensus_encode(&mut w)?;
+            len += next.consensus_encode(&mut w)?;
+        }
+        len += VarInt(self.labels.len() as u64).consensus_encode(&mut w)?;
+        for (point, (time, metadata)) in self.labels.iter() {
+            len += point.consensus_encode(&mut w)?;
+            len += time.consensus_encode(&mut w)?;
+            len += metadata.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for ChangeSet {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<ChangeSet, encode::Error> {
+        let mut changeset = ChangeSet::default();
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let point = OutPoint::consensus_decode(&mut d)?;
+            changeset.coins.insert(point, decode_option(&mut d)?);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let txid = Txid::consensus_decode(&mut d)?;
+            changeset.proofs.insert(txid, decode_option(&mut d)?);
+        }
+        changeset.clusters = decode_option(&mut d)?;
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let txid = Txid::consensus_decode(&mut d)?;
+            changeset.pending.insert(txid, decode_option(&mut d)?);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let name = String::consensus_decode(&mut d)?;
+            let time = u64::consensus_decode(&mut d)?;
+            changeset
+                .drafts
+                .insert(name, (time, decode_option(&mut d)?));
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let account = u32::consensus_decode(&mut d)?;
+            let sub = u32::consensus_decode(&mut d)?;
+            changeset
+                .revealed
+                .insert((account, sub), u32::consensus_decode(&mut d)?);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let point = OutPoint::consensus_decode(&mut d)?;
+            let time = u64::consensus_decode(&mut d)?;
+            changeset
+                .labels
+                .insert(point, (time, CoinMetadata::consensus_decode(&mut d)?));
+        }
+        Ok(changeset)
+    }
+}
+
+/// A store of changesets
+pub trait ChangeSetStore {
+    /// persist a changeset after those before
+    fn append(&mut self, changeset: &ChangeSet) -> Result<(), Error>;
+    /// all persisted changesets merged into one
+    fn aggregate(&self) -> Result<ChangeSet, Error>;
+    /// replace the persisted changesets with their aggregate
+    fn compact(&mut self) -> Result<(), Error>;
+}
+
+const CHANGESET_FILE_MAGIC: &[u8; 4] = b"RWCH";
+const CHANGESET_FILE_VERSION: u8 = 1;
+/// changesets appended before a ChangeSetFile compacts itself
+pub const COMPACT_AFTER: usize = 256;
+
+/// A changeset store in an append-only file
+/// Each changeset is appended with a checksum and synced. A changeset torn by a crash is cut off
+/// when the file is opened, compaction rewrites the file through a temporary file.
+pub struct ChangeSetFile {
+    path: PathBuf,
+    aggregate: ChangeSet,
+    records: usize,
+}
+
+impl ChangeSetFile {
+    /// open or create a changeset file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ChangeSetFile, Error> {
+        let mut file = ChangeSetFile {
+            path: path.as_ref().to_path_buf(),
+            aggregate: ChangeSet::default(),
+            records: 0,
+        };
+        match fs::read(&file.path) {
+            Ok(content) => {
+                let valid = file.replay(content.as_slice())?;
+                if valid < content.len() {
+                    let torn = fs::OpenOptions::new().write(true).open(&file.path)?;
+                    torn.set_len(valid as u64)?;
+                    torn.sync_all()?;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => file.compact()?,
+            Err(e) => return Err(Error::IO(e)),
+        }
+        Ok(file)
+    }
+
+    /// merge the changesets of a file, returns the length of its intact part
+    fn replay(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if data.len() < 5 || &data[..4] != CHANGESET_FILE_MAGIC {
+            return Err(Error::Storage("not a changeset file"));
+        }
+        if data[4] == 0 || data[4] > CHANGESET_FILE_VERSION {
+            return Err(Error::Storage("unknown changeset file version"));
+        }
+        let mut rest = &data[5..];
+        while let Some(changeset) = Self::record(&mut rest) {
+            self.aggregate.merge(changeset);
+            self.records += 1;
+        }
+        Ok(data.len() - rest.len())
+    }
+
+    /// decode a record, None if it is incomplete or does not match its checksum
+    /// data is only advanced past intact records
+    fn record(data: &mut &[u8]) -> Option<ChangeSet> {
+        let mut cursor = *data;
+        let body = Vec::<u8>::consensus_decode(&mut cursor).ok()?;
+        let checksum = <[u8; 4]>::consensus_decode(&mut cursor).ok()?;
+        if checksum[..] != sha256d::Hash::hash(body.as_slice())[..4] {
+            return None;
+        }
+        let changeset = encode::deserialize(body.as_slice()).ok()?;
+        *data = cursor;
+        Some(changeset)
+    }
+
+    fn encode_record(changeset: &ChangeSet) -> Vec<u8> {
+        let body = encode::serialize(changeset);
+        let mut record = encode::serialize(&body);
+        record.extend_from_slice(&sha256d::Hash::hash(body.as_slice())[..4]);
+        record
+    }
+}
+
+impl ChangeSetStore for ChangeSetFile {
+    fn append(&mut self, changeset: &ChangeSet) -> Result<(), Error> {
+        if changeset.is_empty() {
+            return Ok(());
+        }
+        let mut file = fs::OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(Self::encode_record(changeset).as_slice())?;
+        file.sync_all()?;
+        self.aggregate.merge(changeset.clone());
+        self.records += 1;
+        if self.records > COMPACT_AFTER {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn aggregate(&self) -> Result<ChangeSet, Error> {
+        Ok(self.aggregate.clone())
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        // removals are no longer needed once nothing precedes them, discarded drafts are kept
+        // as they supersede older drafts of other devices
+        let aggregate = &mut self.aggregate;
+        aggregate.coins.retain(|_, c| c.is_some());
+        aggregate.proofs.retain(|_, p| p.is_some());
+        aggregate.pending.retain(|_, t| t.is_some());
+        let mut data = CHANGESET_FILE_MAGIC.to_vec();
+        data.push(CHANGESET_FILE_VERSION);
+        if !self.aggregate.is_empty() {
+            data.extend(Self::encode_record(&self.aggregate));
+        }
+        write_atomic(&self.path, data.as_slice())?;
+        self.records = if self.aggregate.is_empty() { 0 } else { 1 };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, Script, TxIn, TxOut};
+
+    use account::{Account, AccountAddressType, MasterKeyEntropy, Unlocker};
+    use fixtures::{block, master_account, next_script, PASSPHRASE};
+    use psbt::Psbt;
+
+    use super::*;
+
+    #[test]
+    fn changesets() {
+        let (mut master, _) = master_account(Network::Regtest);
+        let funded = next_script(&mut master, (0, 0));
+        let change = next_script(&mut master, (0, 0));
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::default(),
+                    vout: 1,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: funded,
+            }],
+        };
+        let first = block(Network::Regtest, vec![coinbase.clone()]);
+        let mut coins = Coins::new();
+        coins.process(&mut master, &first);
+
+        let path = std::env::temp_dir().join(format!("changes-{}.dat", std::process::id()));
+        let mut file = ChangeSetFile::open(&path).unwrap();
+        coins.persist(&master, &mut file).unwrap();
+        assert!(coins.take_changeset(&master).is_empty());
+        let aggregate = file.aggregate().unwrap();
+        assert_eq!(aggregate.revealed.get(&(0, 0)), Some(&2));
+        assert!(aggregate.to_coins().unwrap() == coins);
+
+        // only what changed is appended
+        let spent = OutPoint {
+            txid: coinbase.txid(),
+            vout: 0,
+        };
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: spent,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 90_000,
+                script_pubkey: change,
+            }],
+        };
+        coins.process_unconfirmed_transaction(&mut master, &spend);
+        let received = OutPoint {
+            txid: spend.txid(),
+            vout: 0,
+        };
+        coins.set_memo(&received, "change").unwrap();
+        let changeset = coins.take_changeset(&master);
+        assert_eq!(
+            changeset.coins.keys().cloned().collect::<Vec<_>>(),
+            vec![spent, received]
+                .into_iter()
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+        assert!(changeset.revealed.is_empty());
+        file.append(&changeset).unwrap();
+
+        // a torn changeset is cut off
+        let mut content = fs::read(&path).unwrap();
+        let intact = content.len();
+        let mut torn = ChangeSetFile::encode_record(&changeset);
+        torn.pop();
+        content.extend(torn);
+        fs::write(&path, content).unwrap();
+        let mut file = ChangeSetFile::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, intact);
+        let aggregate = file.aggregate().unwrap();
+        assert!(aggregate.to_coins().unwrap() == coins);
+        assert_eq!(
+            aggregate
+                .to_coins()
+                .unwrap()
+                .metadata(&received)
+                .unwrap()
+                .memo,
+            Some("change".to_string())
+        );
+
+        file.compact().unwrap();
+        let file = ChangeSetFile::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.records, 1);
+        let aggregate = file.aggregate().unwrap();
+        assert!(aggregate.to_coins().unwrap() == coins);
+
+        // revealed addresses are restored on an other device
+        let mut other = MasterAccount::from_encrypted(
+            master.encrypted(),
+            *master.master_public(),
+            master.birth(),
+        );
+        let mut unlocker = Unlocker::new_for_master(&other, PASSPHRASE).unwrap();
+        other.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
+        );
+        aggregate.reveal(&mut other).unwrap();
+        assert_eq!(other.get((0, 0)).unwrap().next(), 2);
+    }
+
+    #[test]
+    fn sync() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Regtest, PASSPHRASE).unwrap();
+        let mut other = MasterAccount::from_encrypted(
+            master.encrypted(),
+            *master.master_public(),
+            master.birth(),
+        );
+        for master in [&mut master, &mut other].iter_mut() {
+            let mut unlocker = Unlocker::new_for_master(master, PASSPHRASE).unwrap();
+            master.add_account(
+                Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
+            );
+        }
+        let funded = next_script(&mut master, (0, 0));
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::default(),
+                    vout: 1,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: funded,
+            }],
+        };
+        let point = OutPoint {
+            txid: coinbase.txid(),
+            vout: 0,
+        };
+        let first = block(Network::Regtest, vec![coinbase.clone()]);
+
+        // a device labels a coin the other did not see yet
+        let mut coins = Coins::new();
+        coins.process(&mut master, &first);
+        coins.set_memo(&point, "salary").unwrap();
+        let shared = coins.take_changeset(&master).shared();
+        assert!(shared.coins.is_empty() && shared.proofs.is_empty());
+        assert_eq!(shared.revealed.get(&(0, 0)), Some(&1));
+
+        const CHEAP: KdfParams = KdfParams {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        };
+        let batch = shared.export_batch(PASSPHRASE, CHEAP).unwrap();
+        assert!(ChangeSet::import_batch(batch.as_slice(), "wrong").is_err());
+        let imported = ChangeSet::import_batch(batch.as_slice(), PASSPHRASE).unwrap();
+        assert_eq!(imported, shared);
+
+        let mut other_coins = Coins::new();
+        assert!(other_coins.sync(&mut other, &imported).unwrap());
+        assert!(!other_coins.sync(&mut other, &imported).unwrap());
+        assert_eq!(other.get((0, 0)).unwrap().next(), 1);
+        other_coins.process(&mut other, &first);
+        assert_eq!(
+            other_coins.metadata(&point).unwrap().memo,
+            Some("salary".to_string())
+        );
+
+        // concurrent edits, the later wins on both devices
+        other_coins.take_changeset(&other);
+        coins.add_tag(&point, "work").unwrap();
+        let edit = coins.take_changeset(&master).shared();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        other_coins.set_memo(&point, "bonus").unwrap();
+        let other_edit = other_coins.take_changeset(&other).shared();
+        coins.sync(&mut master, &other_edit).unwrap();
+        other_coins.sync(&mut other, &edit).unwrap();
+        assert_eq!(coins.metadata(&point), other_coins.metadata(&point));
+        assert_eq!(
+            coins.metadata(&point).unwrap().memo,
+            Some("bonus".to_string())
+        );
+        assert!(coins.metadata(&point).unwrap().tags.is_empty());
+
+        // changesets merge in any order
+        let draft = Draft::new(Psbt::from_unsigned_tx(coinbase).unwrap());
+        let mut saved = ChangeSet::default();
+        saved.drafts.insert("cold".to_string(), (1, Some(draft)));
+        let mut discarded = ChangeSet::default();
+        discarded.drafts.insert("cold".to_string(), (2, None));
+        for (a, b) in [
+            (&edit, &other_edit),
+            (&saved, &discarded),
+            (&saved, &other_edit),
+        ]
+        .iter()
+        {
+            let mut ab = (*a).clone();
+            ab.merge((*b).clone());
+            let mut ba = (*b).clone();
+            ba.merge((*a).clone());
+            assert_eq!(ab, ba);
+        }
+        coins.sync(&mut master, &discarded).unwrap();
+        assert!(!coins.sync(&mut master, &saved).unwrap());
+        assert!(coins.draft("cold").is_none());
+    }
+}
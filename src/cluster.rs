@@ -0,0 +1,154 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Address clusters
+//!
+//! Scripts of the wallet that an observer can link through the common input ownership
+//! heuristic: scripts spent together in a transaction and the change of that transaction.
+//!
+use std::collections::HashMap;
+use std::io;
+
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::{Script, VarInt};
+
+/// Identifier of a cluster of linked scripts
+pub type ClusterId = u32;
+
+/// Linkage clusters of own scripts
+#[derive(Clone, Debug, Default)]
+pub struct Clusters {
+    clusters: HashMap<Script, ClusterId>,
+    next: ClusterId,
+}
+
+// the next id is not stored, clusters restored from storage equal the originals
+impl PartialEq for Clusters {
+    fn eq(&self, other: &Clusters) -> bool {
+        self.clusters == other.clusters
+    }
+}
+
+impl Eq for Clusters {}
+
+impl Clusters {
+    pub fn new() -> Clusters {
+        Clusters {
+            clusters: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    /// restore from storage
+    pub fn from_map(clusters: HashMap<Script, ClusterId>) -> Clusters {
+        let next = clusters.values().max().map(|m| m + 1).unwrap_or(0);
+        Clusters { clusters, next }
+    }
+
+    /// all known scripts with their cluster
+    pub fn map(&self) -> &HashMap<Script, ClusterId> {
+        &self.clusters
+    }
+
+    /// cluster of a script, scripts are added to a new cluster of their own when first seen
+    pub fn add(&mut self, script: &Script) -> ClusterId {
+        if let Some(id) = self.clusters.get(script) {
+            return *id;
+        }
+        let id = self.next;
+        self.next += 1;
+        self.clusters.insert(script.clone(), id);
+        id
+    }
+
+    /// get the cluster of a script
+    pub fn get(&self, script: &Script) -> Option<ClusterId> {
+        self.clusters.get(script).cloned()
+    }
+
+    /// link scripts that are now known to have the same owner
+    /// the resulting cluster has the lowest id of the merged ones
+    pub fn link(&mut self, scripts: &[Script]) -> Option<ClusterId> {
+        let ids = scripts.iter().map(|s| self.add(s)).collect::<Vec<_>>();
+        let target = ids.iter().min().cloned()?;
+        for id in self.clusters.values_mut() {
+            if ids.contains(id) {
+                *id = target;
+            }
+        }
+        Some(target)
+    }
+
+    /// scripts of a cluster
+    pub fn members(&self, id: ClusterId) -> Vec<&Script> {
+        self.clusters
+            .iter()
+            .filter_map(|(s, c)| if *c == id { Some(s) } else { None })
+            .collect()
+    }
+}
+
+// scripts in order, so equal clusters encode the same
+impl Encodable for Clusters {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut map = self.clusters.iter().collect::<Vec<_>>();
+        map.sort();
+        let mut len = VarInt(map.len() as u64).consensus_encode(&mut w)?;
+        for (script, id) in map {
+            len += script.consensus_encode(&mut w)?;
+            len += id.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Clusters {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Clusters, encode::Error> {
+        let mut clusters = HashMap::new();
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let script = Script::consensus_decode(&mut d)?;
+            clusters.insert(script, ClusterId::consensus_decode(&mut d)?);
+        }
+        Ok(Clusters::from_map(clusters))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn link_clusters() {
+        let a = Script::from(vec![1u8]);
+        let b = Script::from(vec![2u8]);
+        let c = Script::from(vec![3u8]);
+        let d = Script::from(vec![4u8]);
+        let mut clusters = Clusters::new();
+        let ca = clusters.add(&a);
+        let cb = clusters.add(&b);
+        assert_ne!(ca, cb);
+        clusters.link(&[c.clone(), d.clone()]);
+        assert_eq!(clusters.get(&c), clusters.get(&d));
+        assert_ne!(clusters.get(&a), clusters.get(&c));
+        let merged = clusters.link(&[b.clone(), d.clone()]).unwrap();
+        assert_eq!(clusters.get(&c), Some(merged));
+        assert_eq!(clusters.members(merged).len(), 3);
+        assert_eq!(
+            Clusters::from_map(clusters.map().clone()).map(),
+            clusters.map()
+        );
+    }
+}
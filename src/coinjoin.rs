@@ -0,0 +1,342 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Coinjoin participation
+//!
+//! Primitives for a client of a coinjoin coordinator. Coins are registered as inputs and frozen
+//! for the round, outputs are registered separately (e.g. blinded) so the coordinator can not
+//! link them to inputs. Before signing the shared transaction the participant checks that it
+//! spends only registered coins of the wallet and pays every expected output.
+//! This library does not talk to coordinators itself.
+//!
+use std::collections::HashMap;
+
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+
+use account::{MasterAccount, Unlocker};
+use coins::{Coin, Coins};
+use error::Error;
+use psbt::{finalize, update_input, Psbt};
+
+/// Participation of the wallet in a coinjoin round
+#[derive(Clone, Debug, Default)]
+pub struct Participation {
+    registered: HashMap<OutPoint, Coin>,
+    outputs: Vec<TxOut>,
+}
+
+impl Participation {
+    pub fn new() -> Participation {
+        Participation::default()
+    }
+
+    /// register a confirmed own coin as input of the round
+    /// the coin is frozen so other transactions do not spend it
+    pub fn register(&mut self, coins: &mut Coins, point: &OutPoint) -> Result<(), Error> {
+        if self.registered.contains_key(point) {
+            return Ok(());
+        }
+        if coins.is_frozen(point) {
+            return Err(Error::Coinjoin("coin is frozen"));
+        }
+        let coin = coins
+            .confirmed()
+            .get(point)
+            .cloned()
+            .ok_or(Error::Coinjoin("coin is not confirmed"))?;
+        coins.freeze(point)?;
+        self.registered.insert(*point, coin);
+        Ok(())
+    }
+
+    /// expect an output in the shared transaction, e.g. the blinded output or change
+    pub fn expect_output(&mut self, output: TxOut) {
+        self.outputs.push(output);
+    }
+
+    /// unfreeze registered coins after the round failed or was left
+    pub fn release(self, coins: &mut Coins) -> Result<(), Error> {
+        for point in self.registered.keys() {
+            coins.unfreeze(point)?;
+        }
+        Ok(())
+    }
+
+    /// registered coins
+    pub fn registered(&self) -> &HashMap<OutPoint, Coin> {
+        &self.registered
+    }
+
+    /// expected outputs
+    pub fn outputs(&self) -> &[TxOut] {
+        self.outputs.as_slice()
+    }
+
+    /// total value of registered coins
+    pub fn input_value(&self) -> u64 {
+        self.registered
+            .values()
+            .map(|c| c.output.value)
+            .sum::<u64>()
+    }
+
+    /// unsigned inputs for the input registration with the outputs they spend
+    /// they carry nothing that links them to outputs
+    pub fn inputs(&self) -> Vec<(TxIn, TxOut)> {
+        let mut inputs = self
+            .registered
+            .iter()
+            .map(|(point, coin)| {
+                (
+                    TxIn {
+                        previous_output: *point,
+                        script_sig: Script::new(),
+                        sequence: 0xffff_ffff,
+                        witness: Vec::new(),
+                    },
+                    coin.output.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        inputs.sort_by_key(|(i, _)| i.previous_output);
+        inputs
+    }
+
+    /// check that the shared transaction spends every registered coin once, does not spend other
+    /// coins of the wallet and pays all expected outputs
+    pub fn verify(&self, transaction: &Transaction, coins: &Coins) -> Result<(), Error> {
+        let mut spent = 0;
+        for (ix, input) in transaction.input.iter().enumerate() {
+            let point = &input.previous_output;
+            if transaction.input[..ix]
+                .iter()
+                .any(|i| i.previous_output == *point)
+            {
+                return Err(Error::Coinjoin("input spent twice"));
+            }
+            if self.registered.contains_key(point) {
+                spent += 1;
+            } else if coins.is_own(point) {
+                return Err(Error::Coinjoin("spends an unregistered own coin"));
+            }
+        }
+        if spent != self.registered.len() {
+            return Err(Error::Coinjoin("registered coin missing"));
+        }
+        let mut outputs = transaction.output.iter().collect::<Vec<_>>();
+        for expected in &self.outputs {
+            match outputs.iter().position(|o| *o == expected) {
+                Some(pos) => {
+                    outputs.swap_remove(pos);
+                }
+                None => return Err(Error::Coinjoin("expected output missing")),
+            }
+        }
+        Ok(())
+    }
+
+    /// verify the shared transaction then sign only the registered inputs
+    /// returns the transaction with own inputs signed, others as given
+    pub fn sign(
+        &self,
+        transaction: &Transaction,
+        coins: &Coins,
+        master: &MasterAccount,
+        unlocker: &mut Unlocker,
+    ) -> Result<Transaction, Error> {
+        self.verify(transaction, coins)?;
+        let mut unsigned = transaction.clone();
+        for input in unsigned.input.iter_mut() {
+            input.script_sig = Script::new();
+            input.witness.clear();
+        }
+        let mut psbt = Psbt::from_unsigned_tx(unsigned)?;
+        let own = transaction
+            .input
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| self.registered.contains_key(&i.previous_output))
+            .map(|(ix, _)| ix)
+            .collect::<Vec<_>>();
+        for ix in &own {
+            update_input(&mut psbt, *ix, coins, master)?;
+        }
+        master.sign_psbt(&mut psbt, unlocker)?;
+        finalize(&mut psbt);
+        let mut signed = transaction.clone();
+        for ix in own {
+            let input = &psbt.inputs[ix];
+            if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+                return Err(Error::Coinjoin("can not sign registered coin"));
+            }
+            signed.input[ix].script_sig = input.final_script_sig.clone().unwrap_or_default();
+            signed.input[ix].witness = input.final_script_witness.clone().unwrap_or_default();
+        }
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use fixtures::{block, funding, master_account, next_script};
+
+    use super::*;
+
+    /// three own coins of 100_000 sat, two registered to a round with a foreign input
+    struct Round {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        funding: Transaction,
+        participation: Participation,
+        shared: Transaction,
+    }
+
+    impl Round {
+        fn new() -> Round {
+            let (mut master, unlocker) = master_account(Network::Testnet);
+            let funding = funding(
+                (0..3)
+                    .map(|_| TxOut {
+                        value: 100_000,
+                        script_pubkey: next_script(&mut master, (0, 0)),
+                    })
+                    .collect(),
+            );
+            // a coin of someone else
+            let mut foreign = funding.clone();
+            foreign.input[0].previous_output.vout = 2;
+            foreign.output = vec![mixed()];
+            let mut coins = Coins::new();
+            coins.process(
+                &mut master,
+                &block(Network::Testnet, vec![funding.clone(), foreign.clone()]),
+            );
+
+            let mut participation = Participation::new();
+            for vout in 0..2 {
+                participation
+                    .register(
+                        &mut coins,
+                        &OutPoint {
+                            txid: funding.txid(),
+                            vout,
+                        },
+                    )
+                    .unwrap();
+                participation.expect_output(mixed());
+            }
+            let mut input = participation
+                .inputs()
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            let mut other = input[0].clone();
+            other.previous_output = OutPoint {
+                txid: foreign.txid(),
+                vout: 0,
+            };
+            other.script_sig = Script::from(vec![0x51]);
+            input.insert(1, other);
+            let shared = Transaction {
+                version: 2,
+                lock_time: 0,
+                input,
+                output: vec![mixed(), mixed(), mixed()],
+            };
+            Round {
+                master,
+                unlocker,
+                coins,
+                funding,
+                participation,
+                shared,
+            }
+        }
+    }
+
+    fn mixed() -> TxOut {
+        TxOut {
+            value: 99_000,
+            script_pubkey: Script::new_v0_wpkh(&Default::default()),
+        }
+    }
+
+    #[test]
+    fn register() {
+        let mut round = Round::new();
+        let point = OutPoint {
+            txid: round.funding.txid(),
+            vout: 0,
+        };
+        assert!(round.coins.is_frozen(&point));
+        assert_eq!(round.participation.input_value(), 200_000);
+        round.participation.release(&mut round.coins).unwrap();
+        assert!(round.coins.frozen().is_empty());
+    }
+
+    #[test]
+    fn participate() {
+        let mut round = Round::new();
+        let signed = round
+            .participation
+            .sign(
+                &round.shared,
+                &round.coins,
+                &round.master,
+                &mut round.unlocker,
+            )
+            .unwrap();
+        // the foreign input is left alone
+        assert_eq!(signed.input[1].script_sig, Script::from(vec![0x51]));
+        assert!(signed.input[1].witness.is_empty());
+        assert_eq!(signed.input[0].witness.len(), 2);
+        assert_eq!(signed.input[2].witness.len(), 2);
+    }
+
+    #[test]
+    fn verify() {
+        let Round {
+            coins,
+            funding,
+            participation,
+            mut shared,
+            ..
+        } = Round::new();
+        assert!(participation.verify(&shared, &coins).is_ok());
+        // one of our outputs is missing
+        shared.output.pop();
+        shared.output.pop();
+        assert!(participation.verify(&shared, &coins).is_err());
+        shared.output.push(mixed());
+        // spends an own coin that is not registered
+        shared.input.push(TxIn {
+            previous_output: OutPoint {
+                txid: funding.txid(),
+                vout: 2,
+            },
+            script_sig: Script::new(),
+            sequence: 0xffff_ffff,
+            witness: Vec::new(),
+        });
+        assert!(participation.verify(&shared, &coins).is_err());
+        shared.input.pop();
+        shared.input.remove(0);
+        assert!(participation.verify(&shared, &coins).is_err());
+    }
+}
@@ -18,13 +18,32 @@
 //!
 //!
 
-use std::collections::HashMap;
+use std::{
+    cmp::max,
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use bitcoin::{Block, OutPoint, Script, Transaction, TxOut};
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::{Block, BlockHash, BlockHeader, OutPoint, Script, Transaction, TxOut, Txid, VarInt};
 use rand::thread_rng;
 
-use account::{KeyDerivation, MasterAccount};
-use proved::ProvedTransaction;
+use account::{AccountAddressType, KeyDerivation, MasterAccount};
+use builder::{
+    transaction_base_weight, ChildPaysForParent, Consolidation, FeeBump, Timelocks,
+    TransactionBuilder, LOCKTIME_THRESHOLD,
+};
+use changeset::{draft_supersedes, label_supersedes, ChangeSet, ChangeSetStore};
+use cluster::{ClusterId, Clusters};
+use error::Error;
+use fee::FeeRate;
+use migration;
+use proved::{BlockProver, ProvedTransaction};
+use psbt::Psbt;
+use selection::{output_weight, Candidate, CoinSelector, Selection};
+use storage::write_atomic;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// a coin is defined by the spendable output
@@ -34,346 +53,3239 @@ pub struct Coin {
     pub derivation: KeyDerivation,
 }
 
-/// Manage coins
-#[derive(Eq, PartialEq)]
-pub struct Coins {
-    /// unconfirmed coins
-    unconfirmed: HashMap<OutPoint, Coin>,
-    /// confirmed coins (these have SPV proofs)
-    confirmed: HashMap<OutPoint, Coin>,
-    /// SPV proofs of transactions confirming coins
-    proofs: HashMap<bitcoin::Txid, ProvedTransaction>,
+/// an output to a watched script, this wallet can not spend it
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WatchedOutput {
+    pub output: TxOut,
+    /// confirmed outputs have an SPV proof in the coin store
+    pub confirmed: bool,
 }
 
-impl Coins {
-    pub fn new() -> Coins {
-        Coins {
-            confirmed: HashMap::new(),
-            proofs: HashMap::new(),
-            unconfirmed: HashMap::new(),
-        }
+/// User supplied information about an own coin
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CoinMetadata {
+    /// free text
+    pub memo: Option<String>,
+    /// origin tags e.g. "salary"
+    pub tags: Vec<String>,
+}
+
+impl CoinMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.memo.is_none() && self.tags.is_empty()
     }
 
-    /// this should only be used to restore previously computed state
-    pub fn add_confirmed(&mut self, point: OutPoint, coin: Coin, proof: ProvedTransaction) {
-        self.confirmed.insert(point, coin);
-        self.proofs.insert(proof.get_transaction().txid(), proof);
+    /// add what propagates from a spent coin
+    fn inherit(&mut self, spent: &CoinMetadata, propagation: MetadataPropagation) {
+        if propagation == MetadataPropagation::Never {
+            return;
+        }
+        for tag in spent.tags.iter() {
+            if !self.tags.contains(tag) {
+                self.tags.push(tag.clone());
+            }
+        }
+        if propagation == MetadataPropagation::TagsAndMemos {
+            if let Some(ref memo) = spent.memo {
+                self.memo = Some(match self.memo.take() {
+                    Some(m) if m != *memo => format!("{}; {}", m, memo),
+                    _ => memo.clone(),
+                });
+            }
+        }
     }
+}
 
-    pub fn remove_confirmed(&mut self, point: &OutPoint) -> bool {
-        let modified = self.confirmed.remove(point).is_some();
-        if modified && self.confirmed.iter().any(|(p, _)| p.txid == point.txid) == false {
-            self.proofs.remove(&point.txid);
+impl Encodable for CoinMetadata {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = match self.memo {
+            Some(ref memo) => 1u8.consensus_encode(&mut w)? + memo.consensus_encode(&mut w)?,
+            None => 0u8.consensus_encode(&mut w)?,
+        };
+        len += VarInt(self.tags.len() as u64).consensus_encode(&mut w)?;
+        for tag in self.tags.iter() {
+            len += tag.consensus_encode(&mut w)?;
         }
-        modified
+        Ok(len)
     }
+}
 
-    /// process an unconfirmed transaction. Useful eg. to process own spends.
-    pub fn process_unconfirmed_transaction(
-        &mut self,
-        master_account: &mut MasterAccount,
-        transaction: &Transaction,
-    ) -> bool {
-        let mut scripts: HashMap<Script, KeyDerivation> = master_account.get_scripts().collect();
-        let mut modified = false;
-        for input in transaction.input.iter() {
-            modified |= self.remove_confirmed(&input.previous_output);
+impl Decodable for CoinMetadata {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<CoinMetadata, encode::Error> {
+        let memo = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(String::consensus_decode(&mut d)?),
+        };
+        let mut tags = Vec::new();
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            tags.push(String::consensus_decode(&mut d)?);
         }
-        for (vout, output) in transaction.output.iter().enumerate() {
-            let mut lookahead = Vec::new();
-            if let Some(d) = scripts.get(&output.script_pubkey) {
-                lookahead = master_account
-                    .get_mut((d.account, d.sub))
-                    .unwrap()
-                    .do_look_ahead(Some(d.kix))
-                    .unwrap()
-                    .iter()
-                    .map(move |(kix, s)| {
-                        (
-                            s.clone(),
-                            KeyDerivation {
-                                kix: *kix,
-                                account: d.account,
-                                sub: d.sub,
-                                tweak: d.tweak.clone(),
-                                csv: d.csv.clone(),
-                            },
-                        )
-                    })
-                    .collect();
-                self.unconfirmed.insert(
-                    OutPoint {
-                        txid: transaction.txid(),
-                        vout: vout as u32,
-                    },
-                    Coin {
-                        output: output.clone(),
-                        derivation: d.clone(),
-                    },
-                );
-                modified = true;
+        Ok(CoinMetadata { memo, tags })
+    }
+}
+
+/// What metadata change and other own outputs inherit from the coins a transaction spends
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MetadataPropagation {
+    /// nothing
+    Never,
+    /// the union of tags
+    #[default]
+    Tags,
+    /// the union of tags and the memos joined
+    TagsAndMemos,
+}
+
+/// an own coin or watched output as kept in a CoinStore
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredCoin {
+    pub output: TxOut,
+    /// key derivation of own coins, None for outputs to watched scripts
+    pub derivation: Option<KeyDerivation>,
+    /// confirmed coins have a proof stored under their txid
+    pub confirmed: bool,
+    /// frozen coins are not chosen as inputs
+    pub frozen: bool,
+    /// unconfirmed coins of transactions spending own coins
+    pub trusted: bool,
+    /// the pending transaction spending this own coin
+    pub spent_by: Option<Txid>,
+    /// memo and origin tags
+    pub metadata: CoinMetadata,
+    /// suspected dust attack
+    pub dust: bool,
+}
+
+impl Encodable for StoredCoin {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.output.consensus_encode(&mut w)?;
+        match self.derivation {
+            Some(ref derivation) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += derivation.consensus_encode(&mut w)?;
             }
-            for (s, d) in lookahead {
-                scripts.insert(s.clone(), d);
+            None => len += 0u8.consensus_encode(&mut w)?,
+        }
+        len += self.confirmed.consensus_encode(&mut w)?;
+        len += self.frozen.consensus_encode(&mut w)?;
+        len += self.trusted.consensus_encode(&mut w)?;
+        match self.spent_by {
+            Some(ref txid) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += txid.consensus_encode(&mut w)?;
             }
+            None => len += 0u8.consensus_encode(&mut w)?,
         }
-        modified
+        len += self.metadata.consensus_encode(&mut w)?;
+        len += self.dust.consensus_encode(&mut w)?;
+        Ok(len)
     }
+}
 
-    pub fn confirmed(&self) -> &HashMap<OutPoint, Coin> {
-        &self.confirmed
+impl Decodable for StoredCoin {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<StoredCoin, encode::Error> {
+        let output = TxOut::consensus_decode(&mut d)?;
+        let derivation = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(KeyDerivation::consensus_decode(&mut d)?),
+        };
+        let confirmed = bool::consensus_decode(&mut d)?;
+        let frozen = bool::consensus_decode(&mut d)?;
+        let trusted = bool::consensus_decode(&mut d)?;
+        let spent_by = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(Txid::consensus_decode(&mut d)?),
+        };
+        let metadata = CoinMetadata::consensus_decode(&mut d)?;
+        let dust = bool::consensus_decode(&mut d)?;
+        Ok(StoredCoin {
+            output,
+            derivation,
+            confirmed,
+            frozen,
+            trusted,
+            spent_by,
+            metadata,
+            dust,
+        })
     }
+}
 
-    pub fn unconfirmed(&self) -> &HashMap<OutPoint, Coin> {
-        &self.unconfirmed
+/// A transaction in progress, e.g. while its PSBT is with an airgapped signer
+/// The coins it spends are reserved, other transactions do not spend them until the draft is
+/// discarded or a transaction spending them is processed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Draft {
+    psbt: Psbt,
+}
+
+impl Eq for Draft {}
+
+impl Draft {
+    pub fn new(psbt: Psbt) -> Draft {
+        Draft { psbt }
     }
 
-    pub fn proofs(&self) -> &HashMap<bitcoin::Txid, ProvedTransaction> {
-        &self.proofs
+    pub fn psbt(&self) -> &Psbt {
+        &self.psbt
     }
 
-    pub fn available_balance<H>(&self, height: u32, block_height: H) -> u64
-    where
-        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
-    {
-        self.available_coins(height, block_height)
-            .iter()
-            .map(|(_, c, _)| c.output.value)
-            .sum::<u64>()
+    pub fn into_psbt(self) -> Psbt {
+        self.psbt
     }
 
-    pub fn available_coins<H>(&self, height: u32, block_height: H) -> Vec<(OutPoint, Coin, u32)>
-    where
-        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
-    {
-        self.confirmed
+    /// coins the draft spends
+    pub fn spends(&self) -> Vec<OutPoint> {
+        self.psbt
+            .global
+            .unsigned_tx
+            .input
             .iter()
-            .filter_map(|(p, c)| {
-                let confirmed = self
-                    .proofs
-                    .get(&p.txid)
-                    .expect("confirmed coin without proof");
-                let conf_height =
-                    block_height(confirmed.get_block_hash()).expect("proof not on trunk");
-                if let Some(csv) = c.derivation.csv {
-                    if height >= conf_height + csv as u32 {
-                        return Some(((*p).clone(), (*c).clone(), conf_height));
-                    } else {
-                        None
-                    }
-                } else {
-                    return Some(((*p).clone(), (*c).clone(), conf_height));
-                }
-            })
+            .map(|i| i.previous_output)
             .collect()
     }
 
-    pub fn confirmed_balance(&self) -> u64 {
-        self.confirmed.values().map(|c| c.output.value).sum::<u64>()
+    /// recipients and change
+    pub fn outputs(&self) -> &[TxOut] {
+        self.psbt.global.unsigned_tx.output.as_slice()
     }
+}
 
-    pub fn unconfirmed_balance(&self) -> u64 {
-        self.unconfirmed
-            .values()
-            .map(|c| c.output.value)
-            .sum::<u64>()
+impl Encodable for Draft {
+    fn consensus_encode<W: io::Write>(&self, w: W) -> Result<usize, io::Error> {
+        self.psbt.consensus_encode(w)
     }
+}
 
-    /// unwind the tip of the trunk
-    pub fn unwind_tip(&mut self, block_hash: &bitcoin::BlockHash) {
-        // this means we might have lost control of coins at least temporarily
-        let lost_coins = self
-            .proofs
-            .values()
-            .filter_map(|t| {
-                if *t.get_block_hash() == *block_hash {
-                    Some(t.get_transaction().txid())
-                } else {
-                    None
-                }
-            })
-            .flat_map(|txid| {
-                self.confirmed
-                    .keys()
-                    .filter(move |point| point.txid == txid)
-            })
-            .cloned()
-            .collect::<Vec<OutPoint>>();
-
-        for point in lost_coins {
-            self.proofs.remove(&point.txid);
-            let coin = self.confirmed.remove(&point).unwrap();
-            self.unconfirmed.insert(point, coin);
-        }
+impl Decodable for Draft {
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Draft, encode::Error> {
+        Ok(Draft {
+            psbt: Psbt::consensus_decode(d)?,
+        })
     }
+}
 
-    /// process a block to find own coins
-    /// processing should be in ascending height order, it is fine to skip blocks  if you know
-    /// there is nothing in them you would care (this will be easy to tell with committed BIP158
-    /// filters, but we are not yet there)
-    pub fn process(&mut self, master_account: &mut MasterAccount, block: &Block) -> bool {
-        let mut scripts: HashMap<Script, KeyDerivation> = master_account.get_scripts().collect();
+/// Manual coin control: coins that must be spent and coins that must not be
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CoinControl {
+    required: Vec<OutPoint>,
+    only: Option<HashSet<OutPoint>>,
+    excluded: HashSet<OutPoint>,
+    self_transfer: bool,
+}
 
-        let mut modified = false;
-        for (txnr, tx) in block.txdata.iter().enumerate() {
-            if txnr > 0 {
-                // skip coinbase
-                for input in tx.input.iter() {
-                    modified |= self.remove_confirmed(&input.previous_output);
-                }
-            }
-            for (vout, output) in tx.output.iter().enumerate() {
-                let mut lookahead = Vec::new();
-                if let Some(d) = scripts.get(&output.script_pubkey) {
-                    lookahead = master_account
-                        .get_mut((d.account, d.sub))
-                        .unwrap()
-                        .do_look_ahead(Some(d.kix))
-                        .unwrap()
-                        .iter()
-                        .map(move |(kix, s)| {
-                            (
-                                s.clone(),
-                                KeyDerivation {
-                                    kix: *kix,
-                                    account: d.account,
-                                    sub: d.sub,
-                                    tweak: d.tweak.clone(),
-                                    csv: d.csv.clone(),
-                                },
-                            )
-                        })
-                        .collect();
-                    let point = OutPoint {
-                        txid: tx.txid(),
-                        vout: vout as u32,
-                    };
-                    self.unconfirmed.remove(&point);
-                    self.confirmed.insert(
-                        point,
-                        Coin {
-                            output: output.clone(),
-                            derivation: d.clone(),
-                        },
-                    );
-                    self.proofs
-                        .entry(tx.txid())
-                        .or_insert(ProvedTransaction::new(block, txnr));
-                    modified = true;
-                }
-                for (s, d) in lookahead {
-                    scripts.insert(s.clone(), d);
-                }
-            }
-        }
-        modified
+impl CoinControl {
+    pub fn new() -> CoinControl {
+        CoinControl::default()
     }
 
-    /// get random confirmed coins of sufficient amount
-    /// returns a vector of spent outpoins, coins and their confirmation height
-    pub fn choose_inputs<H>(
-        &self,
-        minimum: u64,
-        height: u32,
-        block_height: H,
-    ) -> Vec<(OutPoint, Coin, u32)>
-    where
-        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
-    {
-        use rand::prelude::SliceRandom;
-        // TODO: knapsack
-        let mut sum = 0u64;
-        let mut have = self.available_coins(height, block_height);
-        have.sort_by(|(_, a, _), (_, b, _)| a.output.value.cmp(&b.output.value));
-        let mut inputs = Vec::new();
-        for (point, coin, height) in have.iter() {
-            sum += coin.output.value;
-            inputs.push(((*point).clone(), (*coin).clone(), *height));
-            if sum >= minimum {
-                break;
-            }
-        }
-        if sum > minimum {
-            let mut change = sum - minimum;
-            // drop some if possible
-            while let Some(index) = inputs.iter().enumerate().find_map(|(i, (_, c, _))| {
-                if c.output.value <= change {
-                    Some(i)
-                } else {
-                    None
-                }
-            }) {
-                let removed = inputs[index].1.output.value;
-                change -= removed;
-                inputs.remove(index);
-            }
+    /// spend this coin in any case
+    pub fn add_utxo(mut self, point: OutPoint) -> CoinControl {
+        if !self.required.contains(&point) {
+            self.required.push(point);
         }
-        inputs.shuffle(&mut thread_rng());
-        inputs
+        self
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::{
-        str::FromStr,
-        time::{SystemTime, UNIX_EPOCH},
-    };
+    /// top up required coins only from these
+    pub fn only_spend_from(mut self, points: &[OutPoint]) -> CoinControl {
+        self.only = Some(points.iter().cloned().collect());
+        self
+    }
 
-use bitcoin::hashes::hex::FromHex;
-    use bitcoin::blockdata::constants::genesis_block;
-    use bitcoin::blockdata::script::Builder;
-    use bitcoin::util::bip32::ExtendedPubKey;
-    use bitcoin::{
-        network::constants::Network, Address, Block, BlockHeader, OutPoint,
-        Transaction, TxIn, TxOut,
-    };
+    /// never spend this coin
+    pub fn exclude(mut self, point: OutPoint) -> CoinControl {
+        self.excluded.insert(point);
+        self
+    }
 
-    use account::{Account, AccountAddressType, MasterAccount, Unlocker};
-    use coins::Coins;
+    /// the transaction only pays to own scripts, see UnconfirmedPolicy
+    pub fn self_transfer(mut self) -> CoinControl {
+        self.self_transfer = true;
+        self
+    }
 
-    const NEW_COINS: u64 = 5000000000;
+    pub fn is_self_transfer(&self) -> bool {
+        self.self_transfer
+    }
 
-    fn new_block(prev: &bitcoin::BlockHash) -> Block {
-        Block {
-            header: BlockHeader {
-                version: 1,
-                time: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32,
-                nonce: 0,
-                bits: 0x1d00ffff,
-                prev_blockhash: prev.clone(),
-                merkle_root: bitcoin::TxMerkleNode::default(),
-            },
-            txdata: Vec::new(),
-        }
+    /// coins that must be spent
+    pub fn required(&self) -> &[OutPoint] {
+        self.required.as_slice()
     }
 
-    fn coin_base(miner: Address, height: u32) -> Transaction {
-        Transaction {
-            version: 2,
-            lock_time: 0,
-            input: vec![TxIn {
-                sequence: 0xffffffff,
-                witness: Vec::new(),
-                previous_output: OutPoint {
-                    txid: bitcoin::Txid::default(),
-                    vout: 0,
-                },
-                script_sig: Builder::new().push_int(height as i64).into_script(),
-            }],
-            output: vec![TxOut {
-                value: NEW_COINS,
-                script_pubkey: miner.script_pubkey(),
-            }],
-        }
+    /// true if a coin may be chosen to top up required coins
+    pub fn allows(&self, point: &OutPoint) -> bool {
+        !self.excluded.contains(point)
+            && !self.required.contains(point)
+            && self
+                .only
+                .as_ref()
+                .map(|o| o.contains(point))
+                .unwrap_or(true)
     }
+}
 
-    fn add_tx(block: &mut Block, tx: Transaction) {
-        block.txdata.push(tx);
-        block.header.merkle_root = block.merkle_root();
+/// Type of an output script
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ScriptType {
+    P2PKH,
+    P2SH,
+    P2WPKH,
+    P2WSH,
+    Other,
+}
+
+impl ScriptType {
+    pub fn of(script: &Script) -> ScriptType {
+        if script.is_p2pkh() {
+            ScriptType::P2PKH
+        } else if script.is_p2sh() {
+            ScriptType::P2SH
+        } else if script.is_v0_p2wpkh() {
+            ScriptType::P2WPKH
+        } else if script.is_v0_p2wsh() {
+            ScriptType::P2WSH
+        } else {
+            ScriptType::Other
+        }
+    }
+}
+
+/// Order of listed coins
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CoinOrder {
+    /// smallest first
+    #[default]
+    Value,
+    /// least confirmed first, unconfirmed coins have depth 0
+    Depth,
+    /// by txid and vout
+    OutPoint,
+}
+
+/// Filters and order of Coins::list_coins
+/// Coins are listed if they pass all filters set, the default lists all own coins not yet spent.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CoinQuery {
+    account: Option<u32>,
+    sub: Option<u32>,
+    min_depth: Option<u32>,
+    max_depth: Option<u32>,
+    min_value: Option<u64>,
+    max_value: Option<u64>,
+    label: Option<String>,
+    frozen: Option<bool>,
+    script_type: Option<ScriptType>,
+    order: CoinOrder,
+    descending: bool,
+}
+
+impl CoinQuery {
+    pub fn new() -> CoinQuery {
+        CoinQuery::default()
+    }
+
+    /// coins of an account, of all its sub accounts unless sub_account is also given
+    pub fn account(mut self, account: u32) -> CoinQuery {
+        self.account = Some(account);
+        self
+    }
+
+    pub fn sub_account(mut self, sub: u32) -> CoinQuery {
+        self.sub = Some(sub);
+        self
+    }
+
+    /// coins with at least this many confirmations, 0 includes unconfirmed coins
+    pub fn min_depth(mut self, depth: u32) -> CoinQuery {
+        self.min_depth = Some(depth);
+        self
+    }
+
+    pub fn max_depth(mut self, depth: u32) -> CoinQuery {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn min_value(mut self, value: u64) -> CoinQuery {
+        self.min_value = Some(value);
+        self
+    }
+
+    pub fn max_value(mut self, value: u64) -> CoinQuery {
+        self.max_value = Some(value);
+        self
+    }
+
+    /// coins tagged with label or with a memo containing it
+    pub fn label(mut self, label: &str) -> CoinQuery {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// only frozen or only not frozen coins
+    pub fn frozen(mut self, frozen: bool) -> CoinQuery {
+        self.frozen = Some(frozen);
+        self
+    }
+
+    pub fn script_type(mut self, script_type: ScriptType) -> CoinQuery {
+        self.script_type = Some(script_type);
+        self
+    }
+
+    pub fn order_by(mut self, order: CoinOrder) -> CoinQuery {
+        self.order = order;
+        self
+    }
+
+    /// reverse the order
+    pub fn descending(mut self) -> CoinQuery {
+        self.descending = true;
+        self
+    }
+}
+
+/// an own coin spent by a pending transaction, it is restored if that transaction is evicted
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct SpentCoin {
+    coin: Coin,
+    confirmed: bool,
+    by: Txid,
+}
+
+/// keys of state changed since the last changeset was taken
+#[derive(Default)]
+struct Changes {
+    coins: HashSet<OutPoint>,
+    /// proofs and pending transactions
+    transactions: HashSet<Txid>,
+    drafts: HashSet<String>,
+    clusters: bool,
+    /// next address index of accounts as in changesets taken
+    revealed: HashMap<(u32, u32), u32>,
+    /// metadata edited by the user
+    labels: HashSet<OutPoint>,
+}
+
+// changes not yet taken do not make coins different
+impl PartialEq for Changes {
+    fn eq(&self, _: &Changes) -> bool {
+        true
+    }
+}
+
+impl Eq for Changes {}
+
+/// time of the last edit of labels and drafts, in milliseconds since epoch
+#[derive(Default)]
+struct Edits {
+    labels: HashMap<OutPoint, u64>,
+    drafts: HashMap<String, u64>,
+}
+
+// edit times only order edits of devices sharing the wallet, they do not make coins different
+impl PartialEq for Edits {
+    fn eq(&self, _: &Edits) -> bool {
+        true
+    }
+}
+
+impl Eq for Edits {}
+
+/// blocks touching the wallet that can be disconnected without losing spent coins
+const UNDO_DEPTH: usize = 100;
+
+/// what a block changed of the wallet
+#[derive(Clone, Debug, Default)]
+struct BlockUndo {
+    /// own coins the block spent, with the proof of their transaction
+    spent: Vec<(OutPoint, SpentCoin, Option<ProvedTransaction>)>,
+    /// wallet transactions the block confirmed
+    transactions: Vec<Transaction>,
+}
+
+/// changes of recent blocks, to give back what they spent if they are disconnected
+/// Not stored, a reorg of blocks processed before loading forgets their spent coins.
+#[derive(Default)]
+struct Undo {
+    blocks: Vec<(BlockHash, BlockUndo)>,
+}
+
+impl Undo {
+    fn record(&mut self, block_hash: BlockHash, undo: BlockUndo) {
+        self.blocks.retain(|(h, _)| *h != block_hash);
+        self.blocks.push((block_hash, undo));
+        if self.blocks.len() > UNDO_DEPTH {
+            self.blocks.remove(0);
+        }
+    }
+
+    fn take(&mut self, block_hash: &BlockHash) -> Option<BlockUndo> {
+        let position = self.blocks.iter().rposition(|(h, _)| h == block_hash)?;
+        Some(self.blocks.remove(position).1)
+    }
+
+    fn block_of(&self, txid: &Txid) -> Option<BlockHash> {
+        self.blocks
+            .iter()
+            .rev()
+            .find(|(_, u)| u.transactions.iter().any(|t| t.txid() == *txid))
+            .map(|(h, _)| *h)
+    }
+}
+
+// undo records are derived from processed blocks, they do not make coins different
+impl PartialEq for Undo {
+    fn eq(&self, _: &Undo) -> bool {
+        true
+    }
+}
+
+impl Eq for Undo {}
+
+/// time of an edit, later than a known edit even if the clock went backwards
+fn edit_time(known: Option<&u64>) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    max(now, known.map(|t| t + 1).unwrap_or(0))
+}
+
+/// An inconsistency found by Coins::check
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Inconsistency {
+    /// a confirmed coin without proof of its transaction
+    MissingProof(OutPoint),
+    /// an unconfirmed coin of an unknown transaction
+    MissingTransaction(OutPoint),
+    /// a coin does not match the output of its transaction
+    OutputMismatch(OutPoint),
+    /// a coin of an unknown account or key, or of a key with an other script
+    UnknownDerivation(OutPoint),
+    /// a proof does not lead to the merkle root of its block
+    InvalidProof(Txid),
+    /// a coin was received on a key beyond the next index of its account
+    IndexBehind((u32, u32), u32),
+    /// the next index of an account is beyond its instantiated keys
+    InvalidNext((u32, u32)),
+    /// a label of an output of a known transaction that is no own coin
+    OrphanedLabel(OutPoint),
+    /// a frozen, trusted, immature or dust mark of an unknown coin
+    DanglingMark(OutPoint),
+    /// a draft spending unknown coins
+    OrphanedDraft(String),
+}
+
+/// Findings of Coins::check and Coins::repair
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CheckReport {
+    /// inconsistencies found, true if repaired
+    pub findings: Vec<(Inconsistency, bool)>,
+}
+
+impl CheckReport {
+    pub fn is_consistent(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// inconsistencies left after a repair
+    pub fn unrepaired(&self) -> impl Iterator<Item = &Inconsistency> {
+        self.findings.iter().filter(|(_, r)| !r).map(|(i, _)| i)
+    }
+}
+
+/// Notable changes of coins
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CoinEvent {
+    /// a pending transaction and its descendants were evicted by a conflicting transaction
+    Conflict { evicted: Txid, by: Txid },
+    /// a pending transaction, e.g. an incoming payment, was replaced in the mempool by one the
+    /// wallet does not know, received is what it paid to own coins
+    Replaced {
+        evicted: Txid,
+        by: Txid,
+        received: u64,
+    },
+    /// an own coinbase coin can now be spent
+    Matured { point: OutPoint },
+    /// a tiny unsolicited output to a used address, spending it with other coins would link them
+    Dust { point: OutPoint, frozen: bool },
+}
+
+/// Detection of dust attacks
+/// An attacker sends tiny amounts to addresses already used and hopes the wallet spends them
+/// together with other coins, linking them. Outputs of transactions that spend own coins are
+/// never considered dust.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DustPolicy {
+    /// outputs of at most this value are suspected
+    pub threshold: u64,
+    /// freeze suspected outputs
+    pub freeze: bool,
+}
+
+impl Default for DustPolicy {
+    fn default() -> DustPolicy {
+        DustPolicy {
+            threshold: 1000,
+            freeze: true,
+        }
+    }
+}
+
+/// a coinbase output can be spent in this many blocks after the block mining it
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// Earliest spend of a coin, see Coins::earliest_spend
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Maturity {
+    /// tip height from which on the coin can be spent
+    pub height: u32,
+    /// median time past the tip must exceed for an absolute lock in time
+    pub time: Option<u32>,
+}
+
+/// Balance of own coins by state
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Balance {
+    /// confirmed and mature coins that are not frozen
+    pub confirmed: u64,
+    /// unconfirmed coins of own transactions, e.g. change
+    pub trusted_pending: u64,
+    /// unconfirmed coins received from others
+    pub untrusted_pending: u64,
+    /// confirmed coins that can not yet be spent
+    pub immature: u64,
+    /// frozen coins, confirmed or not
+    pub frozen: u64,
+}
+
+impl Balance {
+    /// what can be spent without relying on others: confirmed and own unconfirmed coins
+    pub fn spendable(&self) -> u64 {
+        self.confirmed + self.trusted_pending
+    }
+
+    pub fn total(&self) -> u64 {
+        self.confirmed + self.trusted_pending + self.untrusted_pending + self.immature + self.frozen
+    }
+}
+
+/// Policy for spending coins of unconfirmed transactions
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UnconfirmedPolicy {
+    /// only spend confirmed coins
+    #[default]
+    Never,
+    /// spend unconfirmed coins only in transactions paying to own scripts
+    SelfTransfersOnly,
+    /// spend unconfirmed coins if the unconfirmed ancestors of the new transaction stay within
+    /// these limits, counting the new transaction
+    Limited {
+        max_ancestors: usize,
+        max_ancestor_vsize: u64,
+    },
+}
+
+impl UnconfirmedPolicy {
+    /// limits of bitcoind's default mempool policy
+    pub fn mempool_limits() -> UnconfirmedPolicy {
+        UnconfirmedPolicy::Limited {
+            max_ancestors: 25,
+            max_ancestor_vsize: 101_000,
+        }
+    }
+}
+
+/// Durable storage of coins and their proofs
+pub trait CoinStore {
+    /// store or replace a coin
+    fn put_coin(&mut self, point: &OutPoint, coin: &StoredCoin) -> Result<(), Error>;
+    /// remove a coin
+    fn remove_coin(&mut self, point: &OutPoint) -> Result<(), Error>;
+    /// all stored coins
+    fn coins(&self) -> Result<HashMap<OutPoint, StoredCoin>, Error>;
+    /// store a proof, it is keyed by its transaction id
+    fn put_proof(&mut self, proof: &ProvedTransaction) -> Result<(), Error>;
+    /// remove a proof
+    fn remove_proof(&mut self, txid: &Txid) -> Result<(), Error>;
+    /// all stored proofs
+    fn proofs(&self) -> Result<HashMap<Txid, ProvedTransaction>, Error>;
+    /// store linkage clusters
+    fn put_clusters(&mut self, clusters: &Clusters) -> Result<(), Error>;
+    /// stored linkage clusters
+    fn clusters(&self) -> Result<Clusters, Error>;
+    /// store an unconfirmed transaction that created or spent own coins
+    fn put_pending(&mut self, transaction: &Transaction) -> Result<(), Error>;
+    /// remove an unconfirmed transaction
+    fn remove_pending(&mut self, txid: &Txid) -> Result<(), Error>;
+    /// stored unconfirmed transactions
+    fn pending(&self) -> Result<HashMap<Txid, Transaction>, Error>;
+    /// store or replace a draft
+    fn put_draft(&mut self, name: &str, draft: &Draft) -> Result<(), Error>;
+    /// remove a draft
+    fn remove_draft(&mut self, name: &str) -> Result<(), Error>;
+    /// stored drafts by name
+    fn drafts(&self) -> Result<HashMap<String, Draft>, Error>;
+    /// make changes durable
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Manage coins
+#[derive(Eq, PartialEq)]
+pub struct Coins {
+    /// unconfirmed coins
+    unconfirmed: HashMap<OutPoint, Coin>,
+    /// confirmed coins (these have SPV proofs)
+    confirmed: HashMap<OutPoint, Coin>,
+    /// SPV proofs of transactions confirming coins
+    proofs: HashMap<bitcoin::Txid, ProvedTransaction>,
+    /// outputs to watched scripts
+    watched: HashMap<OutPoint, WatchedOutput>,
+    /// linkage of own scripts
+    clusters: Clusters,
+    /// own coins that should not be spent
+    frozen: HashSet<OutPoint>,
+    /// unconfirmed coins of transactions spending own coins
+    trusted: HashSet<OutPoint>,
+    /// own coins spent by pending transactions
+    spent: HashMap<OutPoint, SpentCoin>,
+    /// own coinbase coins not yet seen mature
+    immature: HashSet<OutPoint>,
+    /// user supplied information about own coins
+    metadata: HashMap<OutPoint, CoinMetadata>,
+    /// what own outputs inherit from spent coins
+    propagation: MetadataPropagation,
+    /// suspected dust attack outputs
+    dust: HashSet<OutPoint>,
+    dust_policy: DustPolicy,
+    /// evicted transactions with the transaction that conflicted with them
+    conflicts: HashMap<Txid, Txid>,
+    /// events not yet taken
+    events: Vec<CoinEvent>,
+    /// unconfirmed transactions creating or spending own coins
+    pending: HashMap<Txid, Transaction>,
+    /// spending of unconfirmed coins
+    unconfirmed_policy: UnconfirmedPolicy,
+    /// transactions in progress by name
+    drafts: HashMap<String, Draft>,
+    /// free text notes of transactions, only stored in the encrypted wallet file
+    notes: HashMap<Txid, String>,
+    /// changes for the next changeset
+    changes: Changes,
+    /// times of user edits
+    edits: Edits,
+    /// changes of recent blocks
+    undo: Undo,
+    /// reject drafts and saving
+    read_only: bool,
+    /// accounts archived in the master account when last seen, their coins are left out of
+    /// balances and spending
+    archived: HashSet<(u32, u32)>,
+}
+
+impl Default for Coins {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coins {
+    pub fn new() -> Coins {
+        Coins {
+            confirmed: HashMap::new(),
+            proofs: HashMap::new(),
+            unconfirmed: HashMap::new(),
+            watched: HashMap::new(),
+            clusters: Clusters::new(),
+            frozen: HashSet::new(),
+            trusted: HashSet::new(),
+            spent: HashMap::new(),
+            immature: HashSet::new(),
+            metadata: HashMap::new(),
+            propagation: MetadataPropagation::default(),
+            dust: HashSet::new(),
+            dust_policy: DustPolicy::default(),
+            conflicts: HashMap::new(),
+            events: Vec::new(),
+            pending: HashMap::new(),
+            unconfirmed_policy: UnconfirmedPolicy::default(),
+            drafts: HashMap::new(),
+            notes: HashMap::new(),
+            changes: Changes::default(),
+            edits: Edits::default(),
+            undo: Undo::default(),
+            read_only: false,
+            archived: HashSet::new(),
+        }
+    }
+
+    /// load coins from a store
+    pub fn load<S: CoinStore>(store: &S) -> Result<Coins, Error> {
+        let mut coins = Coins::new();
+        coins.proofs = store.proofs()?;
+        for (point, stored) in store.coins()? {
+            if stored.confirmed && !coins.proofs.contains_key(&point.txid) {
+                return Err(Error::Unsupported("confirmed coin without proof in store"));
+            }
+            if stored.frozen {
+                coins.frozen.insert(point);
+            }
+            if stored.trusted {
+                coins.trusted.insert(point);
+            }
+            if !stored.metadata.is_empty() {
+                coins.metadata.insert(point, stored.metadata.clone());
+            }
+            if stored.dust {
+                coins.dust.insert(point);
+            }
+            match stored.derivation {
+                Some(derivation) => {
+                    let coin = Coin {
+                        output: stored.output,
+                        derivation,
+                    };
+                    if let Some(by) = stored.spent_by {
+                        coins.spent.insert(
+                            point,
+                            SpentCoin {
+                                coin,
+                                confirmed: stored.confirmed,
+                                by,
+                            },
+                        );
+                    } else if stored.confirmed {
+                        coins.confirmed.insert(point, coin);
+                    } else {
+                        coins.unconfirmed.insert(point, coin);
+                    }
+                }
+                None => {
+                    coins.watched.insert(
+                        point,
+                        WatchedOutput {
+                            output: stored.output,
+                            confirmed: stored.confirmed,
+                        },
+                    );
+                }
+            }
+        }
+        coins.immature = coins
+            .confirmed
+            .keys()
+            .filter(|p| coins.proofs[&p.txid].get_transaction().is_coin_base())
+            .cloned()
+            .collect();
+        coins.clusters = store.clusters()?;
+        coins.pending = store.pending()?;
+        coins.drafts = store.drafts()?;
+        Ok(coins)
+    }
+
+    /// a coin as it should be stored, None if it is not known
+    fn stored_coin(&self, point: &OutPoint) -> Option<StoredCoin> {
+        let own = |coin: &Coin, confirmed: bool, spent_by: Option<Txid>| StoredCoin {
+            output: coin.output.clone(),
+            derivation: Some(coin.derivation.clone()),
+            confirmed,
+            frozen: self.frozen.contains(point),
+            trusted: self.trusted.contains(point),
+            spent_by,
+            metadata: self.metadata.get(point).cloned().unwrap_or_default(),
+            dust: self.dust.contains(point),
+        };
+        if let Some(coin) = self.confirmed.get(point) {
+            Some(own(coin, true, None))
+        } else if let Some(coin) = self.unconfirmed.get(point) {
+            Some(own(coin, false, None))
+        } else if let Some(spent) = self.spent.get(point) {
+            Some(StoredCoin {
+                frozen: false,
+                trusted: false,
+                ..own(&spent.coin, spent.confirmed, Some(spent.by))
+            })
+        } else {
+            self.watched.get(point).map(|watched| StoredCoin {
+                output: watched.output.clone(),
+                derivation: None,
+                confirmed: watched.confirmed,
+                frozen: false,
+                trusted: false,
+                spent_by: None,
+                metadata: CoinMetadata::default(),
+                dust: false,
+            })
+        }
+    }
+
+    /// all coins as they should be stored
+    fn stored_coins(&self) -> HashMap<OutPoint, StoredCoin> {
+        self.confirmed
+            .keys()
+            .chain(self.unconfirmed.keys())
+            .chain(self.watched.keys())
+            .chain(self.spent.keys())
+            .filter_map(|point| self.stored_coin(point).map(|coin| (*point, coin)))
+            .collect()
+    }
+
+    /// take the changes since the last call
+    /// Coins record what their mutations touch, the changeset carries the current state of
+    /// those coins, proofs, pending transactions, drafts and clusters. It also carries the next
+    /// address index of accounts that revealed addresses since and the labels the user edited.
+    pub fn take_changeset(&mut self, master_account: &MasterAccount) -> ChangeSet {
+        let changes = std::mem::take(&mut self.changes);
+        let mut changeset = ChangeSet::default();
+        for point in changes.coins.iter() {
+            changeset.coins.insert(*point, self.stored_coin(point));
+        }
+        for txid in changes.transactions.iter() {
+            changeset
+                .proofs
+                .insert(*txid, self.proofs.get(txid).cloned());
+            changeset
+                .pending
+                .insert(*txid, self.pending.get(txid).cloned());
+        }
+        for name in changes.drafts.iter() {
+            let time = self.edits.drafts.get(name).cloned().unwrap_or_default();
+            changeset
+                .drafts
+                .insert(name.clone(), (time, self.drafts.get(name).cloned()));
+        }
+        for point in changes.labels.iter() {
+            if let Some(time) = self.edits.labels.get(point) {
+                let metadata = self.metadata.get(point).cloned().unwrap_or_default();
+                changeset.labels.insert(*point, (*time, metadata));
+            }
+        }
+        if changes.clusters {
+            changeset.clusters = Some(self.clusters.clone());
+        }
+        self.changes.revealed = changes.revealed;
+        for (id, account) in master_account.accounts().iter() {
+            if self.changes.revealed.get(id) != Some(&account.next()) {
+                self.changes.revealed.insert(*id, account.next());
+                changeset.revealed.insert(*id, account.next());
+            }
+        }
+        changeset
+    }
+
+    /// export the complete coin state into a versioned snapshot
+    /// The snapshot holds coins with their derivation, proofs, clusters and pending transactions,
+    /// so import_snapshot restores the state on an other device without a rescan of the chain.
+    pub fn export_snapshot(&self) -> Result<Vec<u8>, Error> {
+        let mut memory = MemoryCoinStore::default();
+        self.save(&mut memory)?;
+        memory.encode(SNAPSHOT_MAGIC, SNAPSHOT_VERSION)
+    }
+
+    /// restore coins from a snapshot of export_snapshot, snapshots of earlier versions are
+    /// migrated first
+    pub fn import_snapshot(snapshot: &[u8]) -> Result<Coins, Error> {
+        let snapshot = migration::snapshot().migrate(snapshot)?;
+        let memory = MemoryCoinStore::decode(&snapshot, SNAPSHOT_MAGIC, SNAPSHOT_VERSION)?;
+        Coins::load(&memory)
+    }
+
+    /// bring a store in sync with these coins
+    /// only differences are written
+    pub fn save<S: CoinStore>(&self, store: &mut S) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("saving coins"));
+        }
+        let current = self.stored_coins();
+        let stored = store.coins()?;
+        for point in stored.keys() {
+            if !current.contains_key(point) {
+                store.remove_coin(point)?;
+            }
+        }
+        for (point, coin) in current.iter() {
+            if stored.get(point) != Some(coin) {
+                store.put_coin(point, coin)?;
+            }
+        }
+        let stored = store.proofs()?;
+        for txid in stored.keys() {
+            if !self.proofs.contains_key(txid) {
+                store.remove_proof(txid)?;
+            }
+        }
+        for (txid, proof) in self.proofs.iter() {
+            if !stored.contains_key(txid) {
+                store.put_proof(proof)?;
+            }
+        }
+        if store.clusters()?.map() != self.clusters.map() {
+            store.put_clusters(&self.clusters)?;
+        }
+        let stored = store.pending()?;
+        for txid in stored.keys() {
+            if !self.pending.contains_key(txid) {
+                store.remove_pending(txid)?;
+            }
+        }
+        for (txid, transaction) in self.pending.iter() {
+            if !stored.contains_key(txid) {
+                store.put_pending(transaction)?;
+            }
+        }
+        let stored = store.drafts()?;
+        for name in stored.keys() {
+            if !self.drafts.contains_key(name) {
+                store.remove_draft(name)?;
+            }
+        }
+        for (name, draft) in self.drafts.iter() {
+            if stored.get(name) != Some(draft) {
+                store.put_draft(name, draft)?;
+            }
+        }
+        store.flush()
+    }
+
+    /// append the changes since the last changeset to a changeset store
+    /// The changes are kept for the next changeset if the store fails.
+    pub fn persist<S: ChangeSetStore>(
+        &mut self,
+        master_account: &MasterAccount,
+        store: &mut S,
+    ) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("saving coins"));
+        }
+        let changeset = self.take_changeset(master_account);
+        let appended = store.append(&changeset);
+        if appended.is_err() {
+            self.changes.coins.extend(changeset.coins.keys());
+            self.changes.transactions.extend(changeset.proofs.keys());
+            self.changes.drafts.extend(changeset.drafts.keys().cloned());
+            self.changes.clusters |= changeset.clusters.is_some();
+            self.changes.labels.extend(changeset.labels.keys());
+            for id in changeset.revealed.keys() {
+                self.changes.revealed.remove(id);
+            }
+        }
+        appended
+    }
+
+    /// all labels, drafts and revealed addresses as a changeset
+    /// Labels inherited from spent coins or restored from a coin store carry no edit time, edits
+    /// of other devices supersede them.
+    pub fn edits(&self, master_account: &MasterAccount) -> ChangeSet {
+        let mut changeset = ChangeSet::default();
+        for (name, time) in self.edits.drafts.iter() {
+            changeset
+                .drafts
+                .insert(name.clone(), (*time, self.drafts.get(name).cloned()));
+        }
+        for (name, draft) in self.drafts.iter() {
+            changeset
+                .drafts
+                .entry(name.clone())
+                .or_insert_with(|| (0, Some(draft.clone())));
+        }
+        for (point, time) in self.edits.labels.iter() {
+            let metadata = self.metadata.get(point).cloned().unwrap_or_default();
+            changeset.labels.insert(*point, (*time, metadata));
+        }
+        for (point, metadata) in self.metadata.iter() {
+            changeset
+                .labels
+                .entry(*point)
+                .or_insert_with(|| (0, metadata.clone()));
+        }
+        for (id, account) in master_account.accounts().iter() {
+            changeset.revealed.insert(*id, account.next());
+        }
+        changeset
+    }
+
+    /// merge the edits of an other device sharing this wallet, e.g. of
+    /// ChangeSet::import_batch
+    /// Labels and drafts edited there later than here replace those here, addresses revealed
+    /// there are revealed here. Returns true if coins changed.
+    pub fn sync(
+        &mut self,
+        master_account: &mut MasterAccount,
+        changeset: &ChangeSet,
+    ) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("merging edits"));
+        }
+        changeset.reveal(master_account)?;
+        Ok(self.merge_edits(changeset, true))
+    }
+
+    /// take labels and drafts of a changeset edited later than those here
+    pub(crate) fn merge_edits(&mut self, changeset: &ChangeSet, record: bool) -> bool {
+        let mut modified = false;
+        for (name, edit) in changeset.drafts.iter() {
+            let known = (
+                self.edits.drafts.get(name).cloned().unwrap_or_default(),
+                self.drafts.get(name).cloned(),
+            );
+            if !draft_supersedes(edit, &known) {
+                continue;
+            }
+            match edit.1 {
+                Some(ref draft) => self.drafts.insert(name.clone(), draft.clone()),
+                None => self.drafts.remove(name),
+            };
+            self.edits.drafts.insert(name.clone(), edit.0);
+            if record {
+                self.changes.drafts.insert(name.clone());
+            }
+            modified = true;
+        }
+        for (point, edit) in changeset.labels.iter() {
+            let known = (
+                self.edits.labels.get(point).cloned().unwrap_or_default(),
+                self.metadata.get(point).cloned().unwrap_or_default(),
+            );
+            if !label_supersedes(edit, &known) {
+                continue;
+            }
+            if edit.1.is_empty() {
+                self.metadata.remove(point);
+            } else {
+                self.metadata.insert(*point, edit.1.clone());
+            }
+            self.edits.labels.insert(*point, edit.0);
+            if record {
+                self.changes.labels.insert(*point);
+                self.changes.coins.insert(*point);
+            }
+            modified = true;
+        }
+        modified
+    }
+
+    /// this should only be used to restore previously computed state
+    pub fn add_confirmed(&mut self, point: OutPoint, coin: Coin, proof: ProvedTransaction) {
+        if proof.get_transaction().is_coin_base() {
+            self.immature.insert(point);
+        }
+        self.changes.coins.insert(point);
+        self.changes.transactions.insert(point.txid);
+        self.confirmed.insert(point, coin);
+        self.proofs.insert(proof.get_transaction().txid(), proof);
+    }
+
+    /// this should only be used to restore previously computed state
+    /// proof is needed for confirmed outputs
+    pub fn add_watched(
+        &mut self,
+        point: OutPoint,
+        output: WatchedOutput,
+        proof: Option<ProvedTransaction>,
+    ) {
+        self.watched.insert(point, output);
+        self.changes.coins.insert(point);
+        self.changes.transactions.insert(point.txid);
+        if let Some(proof) = proof {
+            self.proofs.insert(proof.get_transaction().txid(), proof);
+        }
+    }
+
+    pub fn remove_confirmed(&mut self, point: &OutPoint) -> bool {
+        let modified = self.confirmed.remove(point).is_some();
+        if modified {
+            self.changes.coins.insert(*point);
+            self.frozen.remove(point);
+            self.immature.remove(point);
+            self.metadata.remove(point);
+            self.dust.remove(point);
+            self.forget_unused_proof(&point.txid);
+        }
+        modified
+    }
+
+    /// freeze an own coin so it is not chosen as input
+    /// returns false if the coin is not known
+    pub fn freeze(&mut self, point: &OutPoint) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("freezing coins"));
+        }
+        if self.confirmed.contains_key(point) || self.unconfirmed.contains_key(point) {
+            self.frozen.insert(*point);
+            self.changes.coins.insert(*point);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// make a frozen coin spendable again
+    pub fn unfreeze(&mut self, point: &OutPoint) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("freezing coins"));
+        }
+        let modified = self.frozen.remove(point);
+        if modified {
+            self.changes.coins.insert(*point);
+        }
+        Ok(modified)
+    }
+
+    pub fn is_frozen(&self, point: &OutPoint) -> bool {
+        self.frozen.contains(point)
+    }
+
+    /// frozen coins
+    pub fn frozen(&self) -> &HashSet<OutPoint> {
+        &self.frozen
+    }
+
+    /// balance of frozen coins, confirmed or not
+    pub fn frozen_balance(&self) -> u64 {
+        self.frozen
+            .iter()
+            .filter_map(|p| self.confirmed.get(p).or_else(|| self.unconfirmed.get(p)))
+            .map(|c| c.output.value)
+            .sum::<u64>()
+    }
+
+    /// leave coins of accounts archived in the master account out of balances and spending
+    /// Balances, available coins and builders only consult the accounts followed here. Call
+    /// after archiving or restoring an account, processing blocks and transactions and loading
+    /// a wallet file do so.
+    pub fn follow_archived(&mut self, master_account: &MasterAccount) {
+        self.archived = master_account
+            .accounts()
+            .iter()
+            .filter(|(_, a)| a.is_archived())
+            .map(|(key, _)| *key)
+            .collect();
+    }
+
+    /// false for coins of archived accounts
+    fn is_active(&self, coin: &Coin) -> bool {
+        !self
+            .archived
+            .contains(&(coin.derivation.account, coin.derivation.sub))
+    }
+
+    /// reject drafts, labels, freezing, merging edits and saving with Error::ReadOnly
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// keep a transaction in progress and reserve the coins it spends
+    /// A draft of the same name is replaced. The coins must be own coins not reserved by other
+    /// drafts.
+    pub fn save_draft(&mut self, name: &str, psbt: Psbt) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("drafts"));
+        }
+        let draft = Draft::new(psbt);
+        for point in draft.spends() {
+            if !self.confirmed.contains_key(&point) && !self.unconfirmed.contains_key(&point) {
+                return Err(Error::CoinSelection("draft spends an unknown coin"));
+            }
+            if let Some(other) = self.reserved_by(&point) {
+                if other != name {
+                    return Err(Error::CoinSelection("coin is reserved by an other draft"));
+                }
+            }
+        }
+        self.drafts.insert(name.to_string(), draft);
+        self.draft_edited(name);
+        Ok(())
+    }
+
+    pub fn draft(&self, name: &str) -> Option<&Draft> {
+        self.drafts.get(name)
+    }
+
+    /// transactions in progress by name
+    pub fn drafts(&self) -> &HashMap<String, Draft> {
+        &self.drafts
+    }
+
+    /// give up a draft, its coins may be spent again
+    pub fn discard_draft(&mut self, name: &str) -> Result<Option<Draft>, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("drafts"));
+        }
+        self.draft_edited(name);
+        Ok(self.drafts.remove(name))
+    }
+
+    fn draft_edited(&mut self, name: &str) {
+        let time = edit_time(self.edits.drafts.get(name));
+        self.edits.drafts.insert(name.to_string(), time);
+        self.changes.drafts.insert(name.to_string());
+    }
+
+    /// name of the draft that reserved a coin
+    pub fn reserved_by(&self, point: &OutPoint) -> Option<&str> {
+        self.drafts
+            .iter()
+            .find(|(_, d)| d.spends().contains(point))
+            .map(|(n, _)| n.as_str())
+    }
+
+    pub fn is_reserved(&self, point: &OutPoint) -> bool {
+        self.reserved_by(point).is_some()
+    }
+
+    /// drafts spending inputs of a transaction are done or can no longer be sent
+    fn settle_drafts(&mut self, transaction: &Transaction) -> bool {
+        let settled = self
+            .drafts
+            .iter()
+            .filter(|(_, d)| {
+                transaction
+                    .input
+                    .iter()
+                    .any(|i| d.spends().contains(&i.previous_output))
+            })
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+        for name in settled.iter() {
+            self.drafts.remove(name);
+            self.draft_edited(name);
+        }
+        !settled.is_empty()
+    }
+
+    /// remove a spent unconfirmed coin
+    pub fn remove_unconfirmed(&mut self, point: &OutPoint) -> bool {
+        let modified = self.unconfirmed.remove(point).is_some();
+        if modified {
+            self.changes.coins.insert(*point);
+            self.frozen.remove(point);
+            self.trusted.remove(point);
+            self.forget_label(point);
+            self.dust.remove(point);
+        }
+        modified
+    }
+
+    /// remove a spent watched output
+    pub fn remove_watched(&mut self, point: &OutPoint) -> bool {
+        let modified = self.watched.remove(point).is_some();
+        if modified {
+            self.changes.coins.insert(*point);
+            self.forget_unused_proof(&point.txid);
+        }
+        modified
+    }
+
+    /// validate invariants of coins and the accounts they belong to
+    /// Proofs are verified against the header of their block if headers knows it, other proofs
+    /// are assumed valid.
+    pub fn check<H>(&self, master_account: &MasterAccount, headers: H) -> CheckReport
+    where
+        H: Fn(&BlockHash) -> Option<BlockHeader>,
+    {
+        CheckReport {
+            findings: self
+                .inconsistencies(master_account, &headers)
+                .into_iter()
+                .map(|i| (i, false))
+                .collect(),
+        }
+    }
+
+    /// check and repair recoverable inconsistencies
+    /// Coins not backed by their transaction and invalid proofs are removed, a rescan finds them
+    /// again. Labels of removed coins are kept for the rescan. Next indexes of accounts are
+    /// advanced past used keys, orphaned labels, marks and drafts are removed.
+    pub fn repair<H>(
+        &mut self,
+        master_account: &mut MasterAccount,
+        headers: H,
+    ) -> Result<CheckReport, Error>
+    where
+        H: Fn(&BlockHash) -> Option<BlockHeader>,
+    {
+        if self.read_only || master_account.is_read_only() {
+            return Err(Error::ReadOnly("repair"));
+        }
+        let mut report = CheckReport::default();
+        for inconsistency in self.inconsistencies(master_account, &headers) {
+            let repaired = match inconsistency {
+                Inconsistency::MissingProof(ref point)
+                | Inconsistency::MissingTransaction(ref point)
+                | Inconsistency::UnknownDerivation(ref point) => {
+                    let label = self.metadata.get(point).cloned();
+                    let removed = self.remove_confirmed(point) || self.remove_unconfirmed(point);
+                    if let Some(label) = label {
+                        self.metadata.insert(*point, label);
+                    }
+                    removed
+                }
+                Inconsistency::OutputMismatch(ref point) => {
+                    self.remove_confirmed(point) || self.remove_unconfirmed(point)
+                }
+                Inconsistency::InvalidProof(ref txid) => {
+                    let removed = self.proofs.remove(txid).is_some();
+                    let confirmed = self
+                        .confirmed
+                        .keys()
+                        .filter(|p| p.txid == *txid)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    for point in confirmed.iter() {
+                        let label = self.metadata.get(point).cloned();
+                        self.remove_confirmed(point);
+                        if let Some(label) = label {
+                            self.metadata.insert(*point, label);
+                        }
+                    }
+                    self.watched.retain(|p, w| !w.confirmed || p.txid != *txid);
+                    self.spent.retain(|p, s| !s.confirmed || p.txid != *txid);
+                    self.changes.transactions.insert(*txid);
+                    removed
+                }
+                Inconsistency::IndexBehind(id, kix) => match master_account.get_mut(id) {
+                    Some(account) => account.do_look_ahead(Some(kix)).is_ok(),
+                    None => false,
+                },
+                Inconsistency::InvalidNext(id) => match master_account.get_mut(id) {
+                    Some(account) if account.next() > 0 => {
+                        let last = account.next() - 1;
+                        account.do_look_ahead(Some(last)).is_ok()
+                    }
+                    _ => false,
+                },
+                Inconsistency::OrphanedLabel(ref point) => {
+                    self.forget_label(point);
+                    self.label_edited(point);
+                    true
+                }
+                Inconsistency::DanglingMark(ref point) => {
+                    self.frozen.remove(point);
+                    self.trusted.remove(point);
+                    self.immature.remove(point);
+                    self.dust.remove(point);
+                    self.changes.coins.insert(*point);
+                    true
+                }
+                Inconsistency::OrphanedDraft(ref name) => self.discard_draft(name)?.is_some(),
+            };
+            report.findings.push((inconsistency, repaired));
+        }
+        Ok(report)
+    }
+
+    fn inconsistencies<H>(&self, master_account: &MasterAccount, headers: &H) -> Vec<Inconsistency>
+    where
+        H: Fn(&BlockHash) -> Option<BlockHeader>,
+    {
+        let mut found = Vec::new();
+        for (txid, proof) in self.proofs.iter() {
+            if let Some(header) = headers(proof.get_block_hash()) {
+                if header.merkle_root != proof.merkle_root() {
+                    found.push(Inconsistency::InvalidProof(*txid));
+                }
+            }
+        }
+        let mut coins = self
+            .confirmed
+            .iter()
+            .map(|(p, c)| (p, c, true))
+            .chain(self.unconfirmed.iter().map(|(p, c)| (p, c, false)))
+            .collect::<Vec<_>>();
+        coins.sort_by_key(|(p, _, _)| **p);
+        let mut behind = HashMap::new();
+        for (point, coin, confirmed) in coins {
+            let transaction = if confirmed {
+                match self.proofs.get(&point.txid) {
+                    Some(proof) => proof.get_transaction(),
+                    None => {
+                        found.push(Inconsistency::MissingProof(*point));
+                        continue;
+                    }
+                }
+            } else {
+                match self.pending.get(&point.txid) {
+                    Some(transaction) => transaction.clone(),
+                    None => {
+                        found.push(Inconsistency::MissingTransaction(*point));
+                        continue;
+                    }
+                }
+            };
+            if transaction.output.get(point.vout as usize) != Some(&coin.output) {
+                found.push(Inconsistency::OutputMismatch(*point));
+                continue;
+            }
+            let derivation = &coin.derivation;
+            let id = (derivation.account, derivation.sub);
+            let account = match master_account.get(id) {
+                Some(account) => account,
+                None => {
+                    found.push(Inconsistency::UnknownDerivation(*point));
+                    continue;
+                }
+            };
+            match account.get_key(derivation.kix) {
+                Some(key) if key.address.script_pubkey() == coin.output.script_pubkey => {}
+                _ => {
+                    found.push(Inconsistency::UnknownDerivation(*point));
+                    continue;
+                }
+            }
+            let chained = match account.address_type() {
+                AccountAddressType::P2WSH(_) => false,
+                _ => !account.is_single_key(),
+            };
+            if chained && derivation.kix >= account.next() {
+                let kix = behind.entry(id).or_insert(derivation.kix);
+                *kix = max(*kix, derivation.kix);
+            }
+        }
+        let mut behind = behind.into_iter().collect::<Vec<_>>();
+        behind.sort();
+        for (id, kix) in behind {
+            found.push(Inconsistency::IndexBehind(id, kix));
+        }
+        let mut accounts = master_account.accounts().iter().collect::<Vec<_>>();
+        accounts.sort_by_key(|(id, _)| **id);
+        for (id, account) in accounts {
+            if account.next() as usize > account.instantiated().len() {
+                found.push(Inconsistency::InvalidNext(*id));
+            }
+        }
+        let own = |point: &OutPoint| {
+            self.confirmed.contains_key(point)
+                || self.unconfirmed.contains_key(point)
+                || self.spent.contains_key(point)
+        };
+        let mut labels = self
+            .metadata
+            .keys()
+            .filter(|p| {
+                !own(p) && (self.proofs.contains_key(&p.txid) || self.pending.contains_key(&p.txid))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        labels.sort();
+        found.extend(labels.into_iter().map(Inconsistency::OrphanedLabel));
+        let mut marks = self
+            .frozen
+            .iter()
+            .chain(self.trusted.iter())
+            .chain(self.immature.iter())
+            .chain(self.dust.iter())
+            .filter(|p| !own(p))
+            .cloned()
+            .collect::<Vec<_>>();
+        marks.sort();
+        marks.dedup();
+        found.extend(marks.into_iter().map(Inconsistency::DanglingMark));
+        let mut drafts = self
+            .drafts
+            .iter()
+            .filter(|(_, d)| {
+                d.spends()
+                    .iter()
+                    .any(|p| !self.confirmed.contains_key(p) && !self.unconfirmed.contains_key(p))
+            })
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+        drafts.sort();
+        found.extend(drafts.into_iter().map(Inconsistency::OrphanedDraft));
+        found
+    }
+
+    /// restore clusters from storage
+    pub fn set_clusters(&mut self, clusters: Clusters) {
+        self.clusters = clusters;
+        self.changes.clusters = true;
+    }
+
+    pub fn clusters(&self) -> &Clusters {
+        &self.clusters
+    }
+
+    /// linkage cluster of an own coin
+    pub fn cluster_of(&self, point: &OutPoint) -> Option<ClusterId> {
+        self.confirmed
+            .get(point)
+            .or_else(|| self.unconfirmed.get(point))
+            .and_then(|c| self.clusters.get(&c.output.script_pubkey))
+    }
+
+    /// scripts of own coins spent by a transaction
+    fn spent_scripts(&self, transaction: &Transaction) -> Vec<Script> {
+        transaction
+            .input
+            .iter()
+            .filter_map(|i| {
+                self.confirmed
+                    .get(&i.previous_output)
+                    .or_else(|| self.unconfirmed.get(&i.previous_output))
+                    .or_else(|| self.spent.get(&i.previous_output).map(|s| &s.coin))
+                    .map(|c| c.output.script_pubkey.clone())
+            })
+            .collect()
+    }
+
+    fn forget_unused_proof(&mut self, txid: &bitcoin::Txid) {
+        self.changes.transactions.insert(*txid);
+        if !self.confirmed.keys().any(|p| p.txid == *txid)
+            && !self
+                .spent
+                .iter()
+                .any(|(p, s)| s.confirmed && p.txid == *txid)
+            && !self
+                .watched
+                .iter()
+                .any(|(p, w)| w.confirmed && p.txid == *txid)
+        {
+            self.proofs.remove(txid);
+        }
+    }
+
+    /// move an own coin spent by a pending transaction aside
+    fn stash_spent(&mut self, point: &OutPoint, by: Txid) -> bool {
+        let (coin, confirmed) = if let Some(coin) = self.confirmed.remove(point) {
+            self.immature.remove(point);
+            (coin, true)
+        } else if let Some(coin) = self.unconfirmed.remove(point) {
+            self.trusted.remove(point);
+            (coin, false)
+        } else {
+            return false;
+        };
+        self.frozen.remove(point);
+        self.changes.coins.insert(*point);
+        self.spent.insert(
+            *point,
+            SpentCoin {
+                coin,
+                confirmed,
+                by,
+            },
+        );
+        true
+    }
+
+    /// pending transactions spending the same own coins as this one
+    /// true if a confirmed transaction spending inputs concerns coins regardless of its
+    /// outputs: it is pending, spends a coin or a watched output or conflicts with a draft
+    pub(crate) fn concerns(&self, txid: &Txid, inputs: &[OutPoint]) -> bool {
+        self.pending.contains_key(txid)
+            || inputs.iter().any(|point| {
+                self.confirmed.contains_key(point)
+                    || self.unconfirmed.contains_key(point)
+                    || self.watched.contains_key(point)
+                    || self.spent.contains_key(point)
+                    || self.drafts.values().any(|d| d.spends().contains(point))
+            })
+    }
+
+    fn find_conflicts(&self, transaction: &Transaction) -> Vec<Txid> {
+        let txid = transaction.txid();
+        let mut conflicts = Vec::new();
+        for input in transaction.input.iter() {
+            if let Some(spent) = self.spent.get(&input.previous_output) {
+                if spent.by != txid && !conflicts.contains(&spent.by) {
+                    conflicts.push(spent.by);
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// evict a pending transaction and its descendants, restore the coins they spent
+    fn evict(&mut self, evicted: Txid, by: Txid) {
+        self.evict_with(evicted, by, CoinEvent::Conflict { evicted, by })
+    }
+
+    /// evict, raising event for the evicted transaction
+    fn evict_with(&mut self, evicted: Txid, by: Txid, event: CoinEvent) {
+        if let Some(transaction) = self.pending.remove(&evicted) {
+            self.changes.transactions.insert(evicted);
+            for vout in 0..transaction.output.len() {
+                let point = OutPoint {
+                    txid: evicted,
+                    vout: vout as u32,
+                };
+                self.remove_unconfirmed(&point);
+                self.watched.remove(&point);
+                self.changes.coins.insert(point);
+                if let Some(descendant) = self.spent.remove(&point) {
+                    self.evict(descendant.by, by);
+                }
+            }
+            let restored = self
+                .spent
+                .iter()
+                .filter(|(_, s)| s.by == evicted)
+                .map(|(p, _)| *p)
+                .collect::<Vec<_>>();
+            for point in restored {
+                let spent = self.spent.remove(&point).unwrap();
+                self.changes.coins.insert(point);
+                if spent.confirmed {
+                    self.confirmed.insert(point, spent.coin);
+                } else {
+                    self.unconfirmed.insert(point, spent.coin);
+                }
+            }
+            self.conflicts.insert(evicted, by);
+            self.events.push(event);
+        }
+    }
+
+    /// forget coins spent by a transaction that is now confirmed
+    fn settle(&mut self, txid: &Txid) {
+        let settled = self
+            .spent
+            .iter()
+            .filter(|(_, s)| s.by == *txid)
+            .map(|(p, _)| *p)
+            .collect::<Vec<_>>();
+        for point in settled {
+            self.spent.remove(&point);
+            self.forget_label(&point);
+            self.dust.remove(&point);
+            self.changes.coins.insert(point);
+            self.forget_unused_proof(&point.txid);
+        }
+    }
+
+    /// true for own coins, also if spent by a pending transaction
+    pub fn is_own(&self, point: &OutPoint) -> bool {
+        self.confirmed.contains_key(point)
+            || self.unconfirmed.contains_key(point)
+            || self.spent.contains_key(point)
+    }
+
+    /// derivation of the key of an own coin, also if spent by a pending transaction
+    pub fn derivation(&self, point: &OutPoint) -> Option<&KeyDerivation> {
+        self.confirmed
+            .get(point)
+            .or_else(|| self.unconfirmed.get(point))
+            .or_else(|| self.spent.get(point).map(|s| &s.coin))
+            .map(|c| &c.derivation)
+    }
+
+    /// attach a note to a transaction, an empty note removes it
+    /// Notes are not written to coin stores, changesets or snapshots, which may be kept in plain
+    /// text, but only to the encrypted wallet file and backups.
+    pub fn set_note(&mut self, txid: &Txid, note: &str) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("notes"));
+        }
+        if note.is_empty() {
+            self.notes.remove(txid);
+        } else {
+            self.notes.insert(*txid, note.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn note(&self, txid: &Txid) -> Option<&String> {
+        self.notes.get(txid)
+    }
+
+    /// notes of transactions
+    pub fn notes(&self) -> &HashMap<Txid, String> {
+        &self.notes
+    }
+
+    /// attach a memo to an own coin, returns false if the coin is not known
+    pub fn set_memo(&mut self, point: &OutPoint, memo: &str) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("labels"));
+        }
+        if !self.is_own(point) {
+            return Ok(false);
+        }
+        self.metadata.entry(*point).or_default().memo = Some(memo.to_string());
+        self.label_edited(point);
+        Ok(true)
+    }
+
+    /// tag an own coin with its origin, returns false if the coin is not known
+    pub fn add_tag(&mut self, point: &OutPoint, tag: &str) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("labels"));
+        }
+        if !self.is_own(point) {
+            return Ok(false);
+        }
+        let metadata = self.metadata.entry(*point).or_default();
+        if !metadata.tags.iter().any(|t| t == tag) {
+            metadata.tags.push(tag.to_string());
+        }
+        self.label_edited(point);
+        Ok(true)
+    }
+
+    pub fn remove_tag(&mut self, point: &OutPoint, tag: &str) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("labels"));
+        }
+        match self.metadata.get_mut(point) {
+            Some(metadata) => {
+                let before = metadata.tags.len();
+                metadata.tags.retain(|t| t != tag);
+                let removed = before != metadata.tags.len();
+                self.label_edited(point);
+                Ok(removed)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn label_edited(&mut self, point: &OutPoint) {
+        let time = edit_time(self.edits.labels.get(point));
+        self.edits.labels.insert(*point, time);
+        self.changes.labels.insert(*point);
+        self.changes.coins.insert(*point);
+    }
+
+    /// drop the metadata of a coin no longer known, an edited label is cleared on other devices
+    fn forget_label(&mut self, point: &OutPoint) {
+        self.metadata.remove(point);
+        if self.edits.labels.contains_key(point) {
+            self.label_edited(point);
+        }
+    }
+
+    pub fn metadata(&self, point: &OutPoint) -> Option<&CoinMetadata> {
+        self.metadata.get(point)
+    }
+
+    /// own coins with a tag
+    pub fn coins_tagged(&self, tag: &str) -> Vec<(OutPoint, Coin)> {
+        self.metadata
+            .iter()
+            .filter(|(_, m)| m.tags.iter().any(|t| t == tag))
+            .filter_map(|(p, _)| {
+                self.confirmed
+                    .get(p)
+                    .or_else(|| self.unconfirmed.get(p))
+                    .map(|c| (*p, c.clone()))
+            })
+            .collect()
+    }
+
+    pub fn set_metadata_propagation(&mut self, propagation: MetadataPropagation) {
+        self.propagation = propagation;
+    }
+
+    /// metadata own outputs of a transaction inherit from the coins it spends
+    fn inherited_metadata(&self, transaction: &Transaction) -> CoinMetadata {
+        let mut inherited = CoinMetadata::default();
+        for input in transaction.input.iter() {
+            if let Some(metadata) = self.metadata.get(&input.previous_output) {
+                inherited.inherit(metadata, self.propagation);
+            }
+        }
+        inherited
+    }
+
+    pub fn set_dust_policy(&mut self, policy: DustPolicy) {
+        self.dust_policy = policy;
+    }
+
+    pub fn dust_policy(&self) -> DustPolicy {
+        self.dust_policy
+    }
+
+    /// own coins suspected to be dust attacks
+    pub fn suspected_dust(&self) -> &HashSet<OutPoint> {
+        &self.dust
+    }
+
+    /// the user decided that a suspected coin is not dust, this also unfreezes it
+    pub fn dismiss_dust(&mut self, point: &OutPoint) -> Result<bool, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("freezing coins"));
+        }
+        if self.dust.remove(point) {
+            self.frozen.remove(point);
+            self.changes.coins.insert(*point);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// flag a newly seen own output if it is a probable dust attack
+    /// reused tells if the output's script was seen before
+    fn detect_dust(&mut self, point: OutPoint, value: u64, reused: bool, spends_own: bool) {
+        if spends_own || !reused || value > self.dust_policy.threshold {
+            return;
+        }
+        self.dust.insert(point);
+        let frozen = self.dust_policy.freeze;
+        if frozen {
+            self.frozen.insert(point);
+        }
+        self.events.push(CoinEvent::Dust { point, frozen });
+    }
+
+    /// evict a pending transaction a backend found replaced by an unknown transaction
+    /// Raises CoinEvent::Replaced, false if the transaction is not pending.
+    pub fn replaced(&mut self, txid: &Txid, by: Txid) -> bool {
+        let received = match self.pending.get(txid) {
+            Some(transaction) => (0..transaction.output.len() as u32)
+                .map(|vout| OutPoint { txid: *txid, vout })
+                .filter_map(|point| {
+                    self.unconfirmed
+                        .get(&point)
+                        .or_else(|| self.spent.get(&point).map(|s| &s.coin))
+                        .map(|coin| coin.output.value)
+                })
+                .sum(),
+            None => return false,
+        };
+        self.evict_with(
+            *txid,
+            by,
+            CoinEvent::Replaced {
+                evicted: *txid,
+                by,
+                received,
+            },
+        );
+        true
+    }
+
+    /// evicted transactions with the transaction that conflicted with them
+    pub fn conflicts(&self) -> &HashMap<Txid, Txid> {
+        &self.conflicts
+    }
+
+    /// take events since last call
+    pub fn take_events(&mut self) -> Vec<CoinEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// process an unconfirmed transaction. Useful eg. to process own spends.
+    /// A transaction spending coins that a pending transaction spends evicts the pending one.
+    pub fn process_unconfirmed_transaction(
+        &mut self,
+        master_account: &mut MasterAccount,
+        transaction: &Transaction,
+    ) -> bool {
+        master_account.index_scripts();
+        self.follow_archived(master_account);
+        let mut modified = false;
+        let txid = transaction.txid();
+        for evicted in self.find_conflicts(transaction) {
+            self.evict(evicted, txid);
+            modified = true;
+        }
+        let mut linked = self.spent_scripts(transaction);
+        let spends_own = !linked.is_empty();
+        let inherited = self.inherited_metadata(transaction);
+        modified |= self.settle_drafts(transaction);
+        for input in transaction.input.iter() {
+            modified |= self.stash_spent(&input.previous_output, txid);
+            modified |= self.remove_watched(&input.previous_output);
+        }
+        for (vout, output) in transaction.output.iter().enumerate() {
+            if master_account.is_watched(&output.script_pubkey) {
+                self.watched
+                    .entry(OutPoint {
+                        txid: transaction.txid(),
+                        vout: vout as u32,
+                    })
+                    .or_insert(WatchedOutput {
+                        output: output.clone(),
+                        confirmed: false,
+                    });
+                self.changes.coins.insert(OutPoint {
+                    txid: transaction.txid(),
+                    vout: vout as u32,
+                });
+                modified = true;
+            }
+            if let Some(d) = master_account.derivation(&output.script_pubkey).cloned() {
+                master_account
+                    .look_ahead((d.account, d.sub), d.kix)
+                    .unwrap();
+                let point = OutPoint {
+                    txid: transaction.txid(),
+                    vout: vout as u32,
+                };
+                let seen = self
+                    .unconfirmed
+                    .insert(
+                        point,
+                        Coin {
+                            output: output.clone(),
+                            derivation: d.clone(),
+                        },
+                    )
+                    .is_some();
+                if !seen {
+                    let reused = self.clusters.get(&output.script_pubkey).is_some();
+                    self.detect_dust(point, output.value, reused, spends_own);
+                }
+                if spends_own {
+                    self.trusted.insert(point);
+                }
+                // labels of an other device may precede the coin
+                if !inherited.is_empty() && !self.edits.labels.contains_key(&point) {
+                    self.metadata.insert(point, inherited.clone());
+                }
+                self.changes.coins.insert(point);
+                self.changes.clusters = true;
+                self.clusters.add(&output.script_pubkey);
+                if spends_own {
+                    linked.push(output.script_pubkey.clone());
+                }
+                modified = true;
+            }
+        }
+        if !linked.is_empty() {
+            self.changes.clusters = true;
+        }
+        self.clusters.link(linked.as_slice());
+        if modified {
+            self.pending.insert(transaction.txid(), transaction.clone());
+            self.changes.transactions.insert(txid);
+        }
+        modified
+    }
+
+    pub fn set_unconfirmed_policy(&mut self, policy: UnconfirmedPolicy) {
+        self.unconfirmed_policy = policy;
+    }
+
+    pub fn unconfirmed_policy(&self) -> UnconfirmedPolicy {
+        self.unconfirmed_policy
+    }
+
+    /// unconfirmed transactions creating or spending own coins
+    pub fn pending(&self) -> &HashMap<Txid, Transaction> {
+        &self.pending
+    }
+
+    /// number and total virtual size of a pending transaction and its unconfirmed ancestors
+    /// as far as they are known to this wallet
+    pub fn ancestors(&self, txid: &Txid) -> (usize, u64) {
+        let mut seen = HashSet::new();
+        let mut todo = vec![*txid];
+        let mut vsize = 0;
+        while let Some(txid) = todo.pop() {
+            if let Some(tx) = self.pending.get(&txid) {
+                if seen.insert(txid) {
+                    vsize += (tx.get_weight() as u64).div_ceil(4);
+                    todo.extend(tx.input.iter().map(|i| i.previous_output.txid));
+                }
+            }
+        }
+        (seen.len(), vsize)
+    }
+
+    /// pending transactions spending outputs of a pending transaction, directly or indirectly
+    pub fn descendants(&self, txid: &Txid) -> Vec<Txid> {
+        let mut found = Vec::new();
+        let mut todo = vec![*txid];
+        while let Some(parent) = todo.pop() {
+            for (child, tx) in self.pending.iter() {
+                if !found.contains(child)
+                    && tx.input.iter().any(|i| i.previous_output.txid == parent)
+                {
+                    found.push(*child);
+                    todo.push(*child);
+                }
+            }
+        }
+        found
+    }
+
+    /// own coins a pending transaction spends
+    pub fn spent_by(&self, txid: &Txid) -> Vec<(OutPoint, Coin)> {
+        self.spent
+            .iter()
+            .filter(|(_, s)| s.by == *txid)
+            .map(|(p, s)| (*p, s.coin.clone()))
+            .collect()
+    }
+
+    /// an output of a transaction known to the wallet
+    pub fn output(&self, point: &OutPoint) -> Option<TxOut> {
+        if let Some(coin) = self
+            .confirmed
+            .get(point)
+            .or_else(|| self.unconfirmed.get(point))
+            .or_else(|| self.spent.get(point).map(|s| &s.coin))
+        {
+            return Some(coin.output.clone());
+        }
+        if let Some(watched) = self.watched.get(point) {
+            return Some(watched.output.clone());
+        }
+        match self.pending.get(&point.txid) {
+            Some(tx) => tx.output.get(point.vout as usize).cloned(),
+            None => self
+                .proofs
+                .get(&point.txid)
+                .and_then(|p| p.get_transaction().output.get(point.vout as usize).cloned()),
+        }
+    }
+
+    /// value of an output of a transaction known to the wallet
+    pub fn output_value(&self, point: &OutPoint) -> Option<u64> {
+        self.output(point).map(|o| o.value)
+    }
+
+    /// fee of a transaction if the values of all its inputs are known
+    pub fn fee_of(&self, transaction: &Transaction) -> Option<u64> {
+        let mut spent = 0;
+        for input in transaction.input.iter() {
+            spent += self.output_value(&input.previous_output)?;
+        }
+        spent.checked_sub(transaction.output.iter().map(|o| o.value).sum::<u64>())
+    }
+
+    /// coins that may be spent by a transaction under the unconfirmed policy
+    /// unconfirmed coins have height 0
+    pub fn spendable_coins<H>(
+        &self,
+        self_transfer: bool,
+        height: u32,
+        block_height: H,
+    ) -> Vec<(OutPoint, Coin, u32)>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let mut coins = self.available_coins(height, block_height);
+        let allowed = |point: &OutPoint| match self.unconfirmed_policy {
+            UnconfirmedPolicy::Never => false,
+            UnconfirmedPolicy::SelfTransfersOnly => self_transfer,
+            UnconfirmedPolicy::Limited {
+                max_ancestors,
+                max_ancestor_vsize,
+            } => {
+                // coins of transactions not known can not be checked
+                self.pending.contains_key(&point.txid) && {
+                    let (count, vsize) = self.ancestors(&point.txid);
+                    count < max_ancestors && vsize <= max_ancestor_vsize
+                }
+            }
+        };
+        coins.extend(
+            self.unconfirmed
+                .iter()
+                .filter(|(p, c)| {
+                    !self.frozen.contains(p)
+                        && !self.is_reserved(p)
+                        && c.derivation.csv.is_none()
+                        && self.is_active(c)
+                })
+                .filter(|(p, _)| allowed(p))
+                .map(|(p, c)| (*p, c.clone(), 0)),
+        );
+        coins
+    }
+
+    pub fn confirmed(&self) -> &HashMap<OutPoint, Coin> {
+        &self.confirmed
+    }
+
+    pub fn unconfirmed(&self) -> &HashMap<OutPoint, Coin> {
+        &self.unconfirmed
+    }
+
+    pub fn proofs(&self) -> &HashMap<bitcoin::Txid, ProvedTransaction> {
+        &self.proofs
+    }
+
+    pub fn watched(&self) -> &HashMap<OutPoint, WatchedOutput> {
+        &self.watched
+    }
+
+    /// balance of confirmed outputs to watched scripts
+    pub fn watched_balance(&self) -> u64 {
+        self.watched
+            .values()
+            .filter(|w| w.confirmed)
+            .map(|w| w.output.value)
+            .sum::<u64>()
+    }
+
+    pub fn available_balance<H>(&self, height: u32, block_height: H) -> u64
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        self.available_coins(height, block_height)
+            .iter()
+            .map(|(_, c, _)| c.output.value)
+            .sum::<u64>()
+    }
+
+    /// mature confirmed coins neither frozen nor reserved by a draft, nor of archived accounts
+    pub fn available_coins<H>(&self, height: u32, block_height: H) -> Vec<(OutPoint, Coin, u32)>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        self.confirmed
+            .iter()
+            .filter(|(p, c)| !self.frozen.contains(p) && !self.is_reserved(p) && self.is_active(c))
+            .filter_map(|(p, c)| {
+                let (conf_height, mature_height) = self.maturity(p, c, &block_height);
+                if height >= mature_height {
+                    Some((*p, c.clone(), conf_height))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// own coins not yet spent that match a query
+    /// returns outpoints, coins and their confirmation height, 0 for unconfirmed coins
+    pub fn list_coins<H>(
+        &self,
+        query: &CoinQuery,
+        height: u32,
+        block_height: H,
+    ) -> Vec<(OutPoint, Coin, u32)>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let depth = |conf_height: u32| {
+            if conf_height == 0 {
+                0
+            } else {
+                (height + 1).saturating_sub(conf_height)
+            }
+        };
+        let labelled = |point: &OutPoint, label: &str| {
+            self.metadata
+                .get(point)
+                .map(|m| {
+                    m.tags.iter().any(|t| t == label)
+                        || m.memo.as_ref().map(|m| m.contains(label)).unwrap_or(false)
+                })
+                .unwrap_or(false)
+        };
+        let mut listed = self
+            .confirmed
+            .iter()
+            .map(|(p, c)| (*p, c.clone(), self.maturity(p, c, &block_height).0))
+            .chain(self.unconfirmed.iter().map(|(p, c)| (*p, c.clone(), 0)))
+            .filter(|(p, c, h)| {
+                let d = &c.derivation;
+                query.account.map(|a| a == d.account).unwrap_or(true)
+                    && query.sub.map(|s| s == d.sub).unwrap_or(true)
+                    && query.min_depth.map(|m| depth(*h) >= m).unwrap_or(true)
+                    && query.max_depth.map(|m| depth(*h) <= m).unwrap_or(true)
+                    && query.min_value.map(|m| c.output.value >= m).unwrap_or(true)
+                    && query.max_value.map(|m| c.output.value <= m).unwrap_or(true)
+                    && query.label.as_ref().map(|l| labelled(p, l)).unwrap_or(true)
+                    && query
+                        .frozen
+                        .map(|f| f == self.frozen.contains(p))
+                        .unwrap_or(true)
+                    && query
+                        .script_type
+                        .map(|t| t == ScriptType::of(&c.output.script_pubkey))
+                        .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        match query.order {
+            CoinOrder::Value => listed.sort_by_key(|(p, c, _)| (c.output.value, *p)),
+            CoinOrder::Depth => listed.sort_by_key(|(p, _, h)| (depth(*h), *p)),
+            CoinOrder::OutPoint => listed.sort_by_key(|(p, _, _)| *p),
+        }
+        if query.descending {
+            listed.reverse();
+        }
+        listed
+    }
+
+    /// confirmation height of a confirmed coin and the height from which on it can be spent
+    fn maturity<H>(&self, point: &OutPoint, coin: &Coin, block_height: &H) -> (u32, u32)
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let confirmed = self
+            .proofs
+            .get(&point.txid)
+            .expect("confirmed coin without proof");
+        let conf_height = block_height(confirmed.get_block_hash()).expect("proof not on trunk");
+        let mut mature_height = conf_height;
+        if let Some(csv) = coin.derivation.csv {
+            mature_height = conf_height + csv as u32;
+        }
+        if confirmed.get_transaction().is_coin_base() {
+            mature_height = std::cmp::max(mature_height, conf_height + COINBASE_MATURITY);
+        }
+        (conf_height, mature_height)
+    }
+
+    /// earliest a confirmed coin can be spent considering coinbase maturity and time locks,
+    /// see Timelocks::of_coin. None if the coin is not confirmed or locked until a time
+    /// relative to its confirmation.
+    pub fn earliest_spend<H>(
+        &self,
+        point: &OutPoint,
+        master: Option<&MasterAccount>,
+        block_height: H,
+    ) -> Option<Maturity>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let coin = self.confirmed.get(point)?;
+        let (conf_height, mut height) = self.maturity(point, coin, &block_height);
+        let locks = Timelocks::of_coin(coin, master);
+        if let Some(older) = locks.older {
+            if older & (1 << 22) != 0 {
+                return None;
+            }
+            height = max(height, conf_height + older);
+        }
+        let mut time = None;
+        match locks.after {
+            Some(after) if after < LOCKTIME_THRESHOLD => height = max(height, after),
+            Some(after) => time = Some(after),
+            None => {}
+        }
+        Some(Maturity { height, time })
+    }
+
+    /// own coinbase coins not yet seen mature by update_tip
+    pub fn immature_coinbase(&self) -> &HashSet<OutPoint> {
+        &self.immature
+    }
+
+    /// promote coinbase coins that matured at the new tip height
+    /// emits a Matured event for each of them
+    pub fn update_tip<H>(&mut self, height: u32, block_height: H) -> Vec<OutPoint>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let matured = self
+            .immature
+            .iter()
+            .filter(|p| match self.confirmed.get(p) {
+                Some(coin) => height >= self.maturity(p, coin, &block_height).1,
+                None => false,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for point in matured.iter() {
+            self.immature.remove(point);
+            self.events.push(CoinEvent::Matured { point: *point });
+        }
+        matured
+    }
+
+    pub fn confirmed_balance(&self) -> u64 {
+        self.confirmed.values().map(|c| c.output.value).sum::<u64>()
+    }
+
+    pub fn unconfirmed_balance(&self) -> u64 {
+        self.unconfirmed
+            .values()
+            .map(|c| c.output.value)
+            .sum::<u64>()
+    }
+
+    /// balance of own coins by state, without coins of archived accounts
+    pub fn balance<H>(&self, height: u32, block_height: H) -> Balance
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        self.balance_of(height, block_height, |c| self.is_active(c))
+    }
+
+    /// balance of the coins of an account
+    pub fn account_balance<H>(&self, account: (u32, u32), height: u32, block_height: H) -> Balance
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        self.balance_of(height, block_height, |c| {
+            (c.derivation.account, c.derivation.sub) == account
+        })
+    }
+
+    fn balance_of<H, F>(&self, height: u32, block_height: H, filter: F) -> Balance
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+        F: Fn(&Coin) -> bool,
+    {
+        let mut balance = Balance::default();
+        for (point, coin) in self.confirmed.iter().filter(|(_, c)| filter(c)) {
+            let value = coin.output.value;
+            if self.frozen.contains(point) {
+                balance.frozen += value;
+                continue;
+            }
+            if height < self.maturity(point, coin, &block_height).1 {
+                balance.immature += value;
+            } else {
+                balance.confirmed += value;
+            }
+        }
+        for (point, coin) in self.unconfirmed.iter().filter(|(_, c)| filter(c)) {
+            let value = coin.output.value;
+            if self.frozen.contains(point) {
+                balance.frozen += value;
+            } else if self.trusted.contains(point) {
+                balance.trusted_pending += value;
+            } else {
+                balance.untrusted_pending += value;
+            }
+        }
+        balance
+    }
+
+    /// unwind the tip of the trunk
+    /// Transactions of the block are pending again, coins they spent are restored as spent by
+    /// them and pending transactions double spending those coins are evicted.
+    pub fn unwind_tip(&mut self, block_hash: &bitcoin::BlockHash) {
+        let undo = self.undo.take(block_hash);
+        // this means we might have lost control of coins at least temporarily
+        let lost_coins = self
+            .proofs
+            .values()
+            .filter_map(|t| {
+                if *t.get_block_hash() == *block_hash {
+                    Some(t.get_transaction().txid())
+                } else {
+                    None
+                }
+            })
+            .flat_map(|txid| {
+                self.confirmed
+                    .keys()
+                    .filter(move |point| point.txid == txid)
+            })
+            .cloned()
+            .collect::<Vec<OutPoint>>();
+
+        for (point, watched) in self.watched.iter_mut() {
+            if let Some(t) = self.proofs.get(&point.txid) {
+                if *t.get_block_hash() == *block_hash {
+                    watched.confirmed = false;
+                    self.changes.coins.insert(*point);
+                }
+            }
+        }
+
+        for (point, spent) in self.spent.iter_mut() {
+            if let Some(t) = self.proofs.get(&point.txid) {
+                if *t.get_block_hash() == *block_hash {
+                    spent.confirmed = false;
+                    self.changes.coins.insert(*point);
+                }
+            }
+        }
+
+        for point in lost_coins {
+            if let Some(proof) = self.proofs.remove(&point.txid) {
+                self.pending
+                    .insert(point.txid, proof.get_transaction().clone());
+            }
+            let coin = self.confirmed.remove(&point).unwrap();
+            self.immature.remove(&point);
+            self.unconfirmed.insert(point, coin);
+            self.changes.coins.insert(point);
+        }
+        let changes = &mut self.changes;
+        self.proofs.retain(|txid, t| {
+            let keep = *t.get_block_hash() != *block_hash;
+            if !keep {
+                changes.transactions.insert(*txid);
+            }
+            keep
+        });
+        if let Some(undo) = undo {
+            self.restore(block_hash, undo);
+        }
+    }
+
+    /// give back what a disconnected block spent
+    fn restore(&mut self, block_hash: &bitcoin::BlockHash, undo: BlockUndo) {
+        let mut demoted = HashSet::new();
+        for transaction in undo.transactions {
+            let txid = transaction.txid();
+            self.pending.insert(txid, transaction);
+            self.changes.transactions.insert(txid);
+            demoted.insert(txid);
+        }
+        for (point, mut spent, proof) in undo.spent {
+            if spent.confirmed {
+                match proof {
+                    Some(proof) if *proof.get_block_hash() != *block_hash => {
+                        self.proofs.entry(point.txid).or_insert(proof);
+                        self.changes.transactions.insert(point.txid);
+                    }
+                    _ => spent.confirmed = false,
+                }
+            }
+            self.changes.coins.insert(point);
+            self.spent.insert(point, spent);
+        }
+        // seen while the coins were gone, so not recognized as double spends
+        let conflicting = self
+            .pending
+            .iter()
+            .filter(|(txid, _)| !demoted.contains(*txid))
+            .filter_map(|(txid, transaction)| {
+                transaction
+                    .input
+                    .iter()
+                    .filter_map(|i| self.spent.get(&i.previous_output))
+                    .find(|s| s.by != *txid && demoted.contains(&s.by))
+                    .map(|s| (*txid, s.by))
+            })
+            .collect::<Vec<_>>();
+        for (evicted, by) in conflicting {
+            self.evict(evicted, by);
+        }
+    }
+
+    /// process a block to find own coins
+    /// processing should be in ascending height order, it is fine to skip blocks  if you know
+    /// there is nothing in them you would care (this will be easy to tell with committed BIP158
+    /// filters, but we are not yet there)
+    pub fn process(&mut self, master_account: &mut MasterAccount, block: &Block) -> bool {
+        let prover = BlockProver::new(block);
+        self.process_transactions(
+            master_account,
+            block.block_hash(),
+            block.txdata.iter().enumerate(),
+            |txnr| prover.prove(txnr),
+        )
+    }
+
+    /// process the transactions of a block proved by a partial merkle tree, e.g. of a BIP37
+    /// filtered block, transactions of the block not given must not concern the wallet
+    pub fn process_proved(
+        &mut self,
+        master_account: &mut MasterAccount,
+        block_hash: &BlockHash,
+        proofs: &[ProvedTransaction],
+    ) -> bool {
+        let mut proofs = proofs
+            .iter()
+            .filter(|p| p.get_block_hash() == block_hash)
+            .collect::<Vec<_>>();
+        proofs.sort_by_key(|p| p.position());
+        let transactions = proofs
+            .iter()
+            .map(|p| (p.position(), p.get_transaction()))
+            .collect::<Vec<_>>();
+        self.process_transactions(
+            master_account,
+            *block_hash,
+            transactions.iter().map(|(txnr, tx)| (*txnr, tx)),
+            |txnr| {
+                proofs
+                    .iter()
+                    .find(|p| p.position() == txnr)
+                    .map(|p| (*p).clone())
+                    .expect("proved above")
+            },
+        )
+    }
+
+    /// process transactions of a block with their position, prove makes the proof of a position
+    fn process_transactions<'a, I, P>(
+        &mut self,
+        master_account: &mut MasterAccount,
+        block_hash: BlockHash,
+        transactions: I,
+        prove: P,
+    ) -> bool
+    where
+        I: Iterator<Item = (usize, &'a Transaction)>,
+        P: Fn(usize) -> ProvedTransaction,
+    {
+        master_account.index_scripts();
+        self.follow_archived(master_account);
+
+        let mut undo = BlockUndo::default();
+        let mut modified = false;
+        for (txnr, tx) in transactions {
+            let txid = tx.txid();
+            let was_pending = self.pending.remove(&txid).is_some();
+            if was_pending {
+                self.changes.transactions.insert(txid);
+                modified = true;
+            }
+            let mut linked = Vec::new();
+            let mut spent = Vec::new();
+            let inherited = self.inherited_metadata(tx);
+            if txnr > 0 {
+                // skip coinbase
+                for evicted in self.find_conflicts(tx) {
+                    self.evict(evicted, txid);
+                    modified = true;
+                }
+                spent = tx
+                    .input
+                    .iter()
+                    .filter_map(|i| self.undo_spent(&i.previous_output, txid))
+                    .collect();
+                linked = self.spent_scripts(tx);
+                modified |= self.settle_drafts(tx);
+                for input in tx.input.iter() {
+                    modified |= self.remove_confirmed(&input.previous_output);
+                    modified |= self.remove_watched(&input.previous_output);
+                    modified |= self.remove_unconfirmed(&input.previous_output);
+                }
+                self.settle(&txid);
+            }
+            let spends_own = !linked.is_empty();
+            for (vout, output) in tx.output.iter().enumerate() {
+                if master_account.is_watched(&output.script_pubkey) {
+                    self.watched.insert(
+                        OutPoint {
+                            txid: tx.txid(),
+                            vout: vout as u32,
+                        },
+                        WatchedOutput {
+                            output: output.clone(),
+                            confirmed: true,
+                        },
+                    );
+                    self.proofs.entry(tx.txid()).or_insert_with(|| prove(txnr));
+                    self.changes.coins.insert(OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    });
+                    self.changes.transactions.insert(txid);
+                    modified = true;
+                }
+                if let Some(d) = master_account.derivation(&output.script_pubkey).cloned() {
+                    master_account
+                        .look_ahead((d.account, d.sub), d.kix)
+                        .unwrap();
+                    let point = OutPoint {
+                        txid: tx.txid(),
+                        vout: vout as u32,
+                    };
+                    let seen = self.unconfirmed.remove(&point).is_some()
+                        || self.confirmed.contains_key(&point);
+                    if !seen {
+                        let reused = self.clusters.get(&output.script_pubkey).is_some();
+                        self.detect_dust(point, output.value, reused, spends_own);
+                    }
+                    self.trusted.remove(&point);
+                    if tx.is_coin_base() {
+                        self.immature.insert(point);
+                    }
+                    if !inherited.is_empty() {
+                        self.metadata
+                            .entry(point)
+                            .or_default()
+                            .inherit(&inherited, MetadataPropagation::TagsAndMemos);
+                    }
+                    self.confirmed.insert(
+                        point,
+                        Coin {
+                            output: output.clone(),
+                            derivation: d.clone(),
+                        },
+                    );
+                    self.proofs.entry(tx.txid()).or_insert_with(|| prove(txnr));
+                    self.changes.coins.insert(point);
+                    self.changes.transactions.insert(txid);
+                    self.changes.clusters = true;
+                    self.clusters.add(&output.script_pubkey);
+                    if spends_own {
+                        linked.push(output.script_pubkey.clone());
+                    }
+                    modified = true;
+                }
+            }
+            if !linked.is_empty() {
+                self.changes.clusters = true;
+            }
+            self.clusters.link(linked.as_slice());
+            let proved = self
+                .proofs
+                .get(&txid)
+                .is_some_and(|p| *p.get_block_hash() == block_hash);
+            if was_pending || proved || !spent.is_empty() {
+                undo.transactions.push(tx.clone());
+                undo.spent.extend(spent);
+            }
+        }
+        if !undo.transactions.is_empty() {
+            self.undo.record(block_hash, undo);
+        }
+        modified
+    }
+
+    /// an own coin about to be spent by a confirmed transaction, as restored by unwind_tip
+    fn undo_spent(
+        &self,
+        point: &OutPoint,
+        by: Txid,
+    ) -> Option<(OutPoint, SpentCoin, Option<ProvedTransaction>)> {
+        let (coin, confirmed) = if let Some(coin) = self.confirmed.get(point) {
+            (coin.clone(), true)
+        } else if let Some(coin) = self.unconfirmed.get(point) {
+            (coin.clone(), false)
+        } else if let Some(spent) = self.spent.get(point) {
+            (spent.coin.clone(), spent.confirmed)
+        } else {
+            return None;
+        };
+        Some((
+            *point,
+            SpentCoin {
+                coin,
+                confirmed,
+                by,
+            },
+            self.proofs.get(&point.txid).cloned(),
+        ))
+    }
+
+    /// block hash and height of the block confirming a wallet transaction
+    pub fn anchor<H>(&self, txid: &Txid, block_height: H) -> Option<(BlockHash, u32)>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let block_hash = match self.proofs.get(txid) {
+            Some(proof) => *proof.get_block_hash(),
+            None => self.undo.block_of(txid)?,
+        };
+        block_height(&block_hash).map(|height| (block_hash, height))
+    }
+
+    /// get random confirmed coins of sufficient amount, frozen coins are never chosen
+    /// returns a vector of spent outpoins, coins and their confirmation height
+    pub fn choose_inputs<H>(
+        &self,
+        minimum: u64,
+        height: u32,
+        block_height: H,
+    ) -> Vec<(OutPoint, Coin, u32)>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        Self::choose_from(self.available_coins(height, block_height), minimum)
+    }
+
+    /// get coins of sufficient amount under manual coin control
+    /// required coins are always spent, others are only added if required coins are not
+    /// sufficient. Fails if a required coin is not available (unknown, frozen or immature).
+    pub fn choose_inputs_with<H>(
+        &self,
+        control: &CoinControl,
+        minimum: u64,
+        height: u32,
+        block_height: H,
+    ) -> Result<Vec<(OutPoint, Coin, u32)>, Error>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        use rand::prelude::SliceRandom;
+        let available = self.spendable_coins(control.is_self_transfer(), height, block_height);
+        let mut inputs = Vec::new();
+        for point in control.required() {
+            inputs.push(
+                available
+                    .iter()
+                    .find(|(p, _, _)| p == point)
+                    .cloned()
+                    .ok_or(Error::CoinSelection("required coin is not available"))?,
+            );
+        }
+        let sum = inputs.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
+        if sum < minimum {
+            let rest = available
+                .into_iter()
+                .filter(|(p, _, _)| control.allows(p))
+                .collect();
+            inputs.extend(Self::choose_from(rest, minimum - sum));
+            inputs.shuffle(&mut thread_rng());
+        }
+        Ok(inputs)
+    }
+
+    /// start building a transaction that consolidates small coins
+    pub fn consolidate(&self) -> Consolidation<'_> {
+        Consolidation::new(self)
+    }
+
+    /// start building a payment from these coins
+    pub fn build_tx(&self) -> TransactionBuilder<'_> {
+        TransactionBuilder::new(self)
+    }
+
+    /// start building a replacement of a pending transaction that pays a higher fee
+    pub fn bump_fee(&self, txid: &Txid) -> FeeBump<'_> {
+        FeeBump::new(self, txid)
+    }
+
+    /// start building a replacement of a pending payment that pays everything back to the
+    /// wallet
+    pub fn cancel(&self, txid: &Txid) -> FeeBump<'_> {
+        FeeBump::cancel(self, txid)
+    }
+
+    /// start building a child of a pending transaction that pays for both
+    pub fn cpfp(&self, txid: &Txid) -> ChildPaysForParent<'_> {
+        ChildPaysForParent::new(self, txid)
+    }
+
+    /// the largest amount that can be sent to a script at a fee rate, spending all coins
+    /// worth spending without change. Frozen coins are not counted.
+    pub fn max_send<H>(&self, to: &Script, feerate: FeeRate, height: u32, block_height: H) -> u64
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let worth = self
+            .spendable_coins(false, height, block_height)
+            .into_iter()
+            .map(|(p, c, h)| Candidate::new(p, c, h).effective_value(feerate))
+            .filter(|v| *v > 0)
+            .collect::<Vec<_>>();
+        let fee = feerate.fee(transaction_base_weight(worth.len(), 1) + output_weight(to));
+        let max = worth.iter().sum::<i64>() - fee as i64;
+        if max < to.dust_value() as i64 {
+            0
+        } else {
+            max as u64
+        }
+    }
+
+    /// select coins with a strategy under manual coin control
+    /// the selector only tops up required coins if those do not reach the target
+    #[allow(clippy::too_many_arguments)]
+    pub fn select<S, H>(
+        &self,
+        selector: &S,
+        control: &CoinControl,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+        height: u32,
+        block_height: H,
+    ) -> Result<Selection, Error>
+    where
+        S: CoinSelector + ?Sized,
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let available = self.spendable_coins(control.is_self_transfer(), height, block_height);
+        let mut required = Vec::new();
+        for point in control.required() {
+            let (point, coin, height) = available
+                .iter()
+                .find(|(p, _, _)| p == point)
+                .cloned()
+                .ok_or(Error::CoinSelection("required coin is not available"))?;
+            let cluster = self.clusters.get(&coin.output.script_pubkey);
+            required.push(Candidate::new(point, coin, height).with_cluster(cluster));
+        }
+        let pinned = required
+            .iter()
+            .map(|c| c.effective_value(feerate))
+            .sum::<i64>();
+        if pinned >= target as i64 {
+            return Selection::new(required, target, feerate, drain_script);
+        }
+        let candidates = available
+            .into_iter()
+            .filter(|(p, _, _)| control.allows(p))
+            .map(|(p, c, h)| {
+                let cluster = self.clusters.get(&c.output.script_pubkey);
+                Candidate::new(p, c, h).with_cluster(cluster)
+            })
+            .collect();
+        Ok(selector
+            .select(
+                candidates,
+                (target as i64 - pinned) as u64,
+                feerate,
+                drain_script,
+            )?
+            .prepend(required, feerate))
+    }
+
+    fn choose_from(
+        mut have: Vec<(OutPoint, Coin, u32)>,
+        minimum: u64,
+    ) -> Vec<(OutPoint, Coin, u32)> {
+        use rand::prelude::SliceRandom;
+        // TODO: knapsack
+        let mut sum = 0u64;
+        have.sort_by_key(|(_, a, _)| a.output.value);
+        let mut inputs = Vec::new();
+        for (point, coin, height) in have.iter() {
+            sum += coin.output.value;
+            inputs.push((*point, coin.clone(), *height));
+            if sum >= minimum {
+                break;
+            }
+        }
+        if sum > minimum {
+            let mut change = sum - minimum;
+            // drop some if possible
+            while let Some(index) = inputs.iter().enumerate().find_map(|(i, (_, c, _))| {
+                if c.output.value <= change {
+                    Some(i)
+                } else {
+                    None
+                }
+            }) {
+                let removed = inputs[index].1.output.value;
+                change -= removed;
+                inputs.remove(index);
+            }
+        }
+        inputs.shuffle(&mut thread_rng());
+        inputs
+    }
+}
+
+/// A coin store in memory
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryCoinStore {
+    coins: HashMap<OutPoint, StoredCoin>,
+    proofs: HashMap<Txid, ProvedTransaction>,
+    clusters: Clusters,
+    pending: HashMap<Txid, Transaction>,
+    drafts: HashMap<String, Draft>,
+}
+
+impl MemoryCoinStore {
+    /// serialize behind a magic and a format version
+    fn encode(&self, magic: &[u8; 4], version: u8) -> Result<Vec<u8>, Error> {
+        let mut data = magic.to_vec();
+        data.push(version);
+        VarInt(self.coins.len() as u64).consensus_encode(&mut data)?;
+        for (point, coin) in self.coins.iter() {
+            point.consensus_encode(&mut data)?;
+            coin.consensus_encode(&mut data)?;
+        }
+        VarInt(self.proofs.len() as u64).consensus_encode(&mut data)?;
+        for proof in self.proofs.values() {
+            proof.consensus_encode(&mut data)?;
+        }
+        self.clusters.consensus_encode(&mut data)?;
+        VarInt(self.pending.len() as u64).consensus_encode(&mut data)?;
+        for transaction in self.pending.values() {
+            transaction.consensus_encode(&mut data)?;
+        }
+        VarInt(self.drafts.len() as u64).consensus_encode(&mut data)?;
+        for (name, draft) in self.drafts.iter() {
+            name.consensus_encode(&mut data)?;
+            draft.consensus_encode(&mut data)?;
+        }
+        Ok(data)
+    }
+
+    /// decode the format version, earlier ones are upgraded by the migration module
+    fn decode(mut data: &[u8], magic: &[u8; 4], version: u8) -> Result<MemoryCoinStore, Error> {
+        if data.len() < 5 || &data[..4] != magic {
+            return Err(Error::Unsupported("not a coin store or snapshot"));
+        }
+        if data[4] != version {
+            return Err(Error::Unsupported("unknown coin store or snapshot version"));
+        }
+        data = &data[5..];
+        let mut memory = MemoryCoinStore::default();
+        for _ in 0..VarInt::consensus_decode(&mut data)?.0 {
+            let point = OutPoint::consensus_decode(&mut data)?;
+            memory
+                .coins
+                .insert(point, StoredCoin::consensus_decode(&mut data)?);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut data)?.0 {
+            let proof = ProvedTransaction::consensus_decode(&mut data)?;
+            memory.proofs.insert(proof.get_transaction().txid(), proof);
+        }
+        memory.clusters = Clusters::consensus_decode(&mut data)?;
+        for _ in 0..VarInt::consensus_decode(&mut data)?.0 {
+            let transaction = Transaction::consensus_decode(&mut data)?;
+            memory.pending.insert(transaction.txid(), transaction);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut data)?.0 {
+            let name = String::consensus_decode(&mut data)?;
+            memory
+                .drafts
+                .insert(name, Draft::consensus_decode(&mut data)?);
+        }
+        if !data.is_empty() {
+            return Err(Error::Unsupported(
+                "trailing data in coin store or snapshot",
+            ));
+        }
+        Ok(memory)
+    }
+}
+
+impl CoinStore for MemoryCoinStore {
+    fn put_coin(&mut self, point: &OutPoint, coin: &StoredCoin) -> Result<(), Error> {
+        self.coins.insert(*point, coin.clone());
+        Ok(())
+    }
+
+    fn remove_coin(&mut self, point: &OutPoint) -> Result<(), Error> {
+        self.coins.remove(point);
+        Ok(())
+    }
+
+    fn coins(&self) -> Result<HashMap<OutPoint, StoredCoin>, Error> {
+        Ok(self.coins.clone())
+    }
+
+    fn put_proof(&mut self, proof: &ProvedTransaction) -> Result<(), Error> {
+        self.proofs
+            .insert(proof.get_transaction().txid(), proof.clone());
+        Ok(())
+    }
+
+    fn remove_proof(&mut self, txid: &Txid) -> Result<(), Error> {
+        self.proofs.remove(txid);
+        Ok(())
+    }
+
+    fn proofs(&self) -> Result<HashMap<Txid, ProvedTransaction>, Error> {
+        Ok(self.proofs.clone())
+    }
+
+    fn put_clusters(&mut self, clusters: &Clusters) -> Result<(), Error> {
+        self.clusters = clusters.clone();
+        Ok(())
+    }
+
+    fn clusters(&self) -> Result<Clusters, Error> {
+        Ok(self.clusters.clone())
+    }
+
+    fn put_pending(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        self.pending.insert(transaction.txid(), transaction.clone());
+        Ok(())
+    }
+
+    fn remove_pending(&mut self, txid: &Txid) -> Result<(), Error> {
+        self.pending.remove(txid);
+        Ok(())
+    }
+
+    fn pending(&self) -> Result<HashMap<Txid, Transaction>, Error> {
+        Ok(self.pending.clone())
+    }
+
+    fn put_draft(&mut self, name: &str, draft: &Draft) -> Result<(), Error> {
+        self.drafts.insert(name.to_string(), draft.clone());
+        Ok(())
+    }
+
+    fn remove_draft(&mut self, name: &str) -> Result<(), Error> {
+        self.drafts.remove(name);
+        Ok(())
+    }
+
+    fn drafts(&self) -> Result<HashMap<String, Draft>, Error> {
+        Ok(self.drafts.clone())
+    }
+}
+
+pub(crate) const COIN_FILE_MAGIC: &[u8; 4] = b"RWCS";
+pub(crate) const COIN_FILE_VERSION: u8 = 2;
+pub(crate) const SNAPSHOT_MAGIC: &[u8; 4] = b"RWSN";
+/// version of snapshots written by export_snapshot
+pub const SNAPSHOT_VERSION: u8 = 2;
+
+/// A coin store in a file
+/// The file is rewritten on flush through a temporary file, so a crash leaves either the old or
+/// the new content.
+pub struct FileCoinStore {
+    path: PathBuf,
+    memory: MemoryCoinStore,
+    dirty: bool,
+}
+
+impl FileCoinStore {
+    /// open or create a coin store file, a file of an earlier version is migrated first
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileCoinStore, Error> {
+        let path = path.as_ref().to_path_buf();
+        migration::coin_store().migrate_file(&path)?;
+        let memory = match fs::read(&path) {
+            Ok(content) => Self::decode(content.as_slice())?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => MemoryCoinStore::default(),
+            Err(e) => return Err(Error::IO(e)),
+        };
+        Ok(FileCoinStore {
+            path,
+            memory,
+            dirty: false,
+        })
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        self.memory.encode(COIN_FILE_MAGIC, COIN_FILE_VERSION)
+    }
+
+    fn decode(data: &[u8]) -> Result<MemoryCoinStore, Error> {
+        MemoryCoinStore::decode(data, COIN_FILE_MAGIC, COIN_FILE_VERSION)
+    }
+}
+
+impl CoinStore for FileCoinStore {
+    fn put_coin(&mut self, point: &OutPoint, coin: &StoredCoin) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.put_coin(point, coin)
+    }
+
+    fn remove_coin(&mut self, point: &OutPoint) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.remove_coin(point)
+    }
+
+    fn coins(&self) -> Result<HashMap<OutPoint, StoredCoin>, Error> {
+        self.memory.coins()
+    }
+
+    fn put_proof(&mut self, proof: &ProvedTransaction) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.put_proof(proof)
+    }
+
+    fn remove_proof(&mut self, txid: &Txid) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.remove_proof(txid)
+    }
+
+    fn proofs(&self) -> Result<HashMap<Txid, ProvedTransaction>, Error> {
+        self.memory.proofs()
+    }
+
+    fn put_clusters(&mut self, clusters: &Clusters) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.put_clusters(clusters)
+    }
+
+    fn clusters(&self) -> Result<Clusters, Error> {
+        self.memory.clusters()
+    }
+
+    fn put_pending(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.put_pending(transaction)
+    }
+
+    fn remove_pending(&mut self, txid: &Txid) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.remove_pending(txid)
+    }
+
+    fn pending(&self) -> Result<HashMap<Txid, Transaction>, Error> {
+        self.memory.pending()
+    }
+
+    fn put_draft(&mut self, name: &str, draft: &Draft) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.put_draft(name, draft)
+    }
+
+    fn remove_draft(&mut self, name: &str) -> Result<(), Error> {
+        self.dirty = true;
+        self.memory.remove_draft(name)
+    }
+
+    fn drafts(&self) -> Result<HashMap<String, Draft>, Error> {
+        self.memory.drafts()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        write_atomic(&self.path, self.encode()?.as_slice())?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        str::FromStr,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::util::bip32::ExtendedPubKey;
+    use bitcoin::{
+        network::constants::Network, Address, Block, BlockHeader, OutPoint, Script, Transaction,
+        TxIn, TxOut,
+    };
+
+    use account::{Account, AccountAddressType, MasterAccount, Unlocker};
+    use coins::{
+        Balance, CoinControl, CoinEvent, CoinMetadata, CoinOrder, CoinQuery, CoinStore, Coins,
+        DustPolicy, FileCoinStore, Inconsistency, MemoryCoinStore, MetadataPropagation, ScriptType,
+        UnconfirmedPolicy, COIN_FILE_MAGIC, COIN_FILE_VERSION, SNAPSHOT_VERSION,
+    };
+    use fee::FeeRate;
+    use selection::{BranchAndBound, LargestFirst};
+
+    const NEW_COINS: u64 = 5000000000;
+
+    fn new_block(prev: &bitcoin::BlockHash) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                time: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as u32,
+                nonce: 0,
+                bits: 0x1d00ffff,
+                prev_blockhash: *prev,
+                merkle_root: bitcoin::TxMerkleNode::default(),
+            },
+            txdata: Vec::new(),
+        }
+    }
+
+    fn coin_base(miner: Address, height: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                previous_output: OutPoint {
+                    txid: bitcoin::Txid::default(),
+                    vout: 0,
+                },
+                script_sig: Builder::new().push_int(height as i64).into_script(),
+            }],
+            output: vec![TxOut {
+                value: NEW_COINS,
+                script_pubkey: miner.script_pubkey(),
+            }],
+        }
+    }
+
+    fn add_tx(block: &mut Block, tx: Transaction) {
+        block.txdata.push(tx);
+        block.header.merkle_root = block.merkle_root();
     }
 
     fn new_master() -> MasterAccount {
@@ -385,17 +3297,200 @@ use bitcoin::hashes::hex::FromHex;
         master.add_account(
             Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
         );
-        master
+        master
+    }
+
+    fn mine(tip: &bitcoin::BlockHash, height: u32, miner: Address) -> Block {
+        let mut block = new_block(tip);
+        add_tx(&mut block, coin_base(miner, height));
+        block
+    }
+
+    #[test]
+    pub fn test_coins() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let miner = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let genesis = genesis_block(Network::Testnet);
+        let next = mine(&genesis.block_hash(), 1, miner);
+        coins.process(&mut master, &next);
+        assert_eq!(coins.confirmed_balance(), NEW_COINS);
+        coins.unwind_tip(&next.block_hash());
+        assert_eq!(coins.confirmed_balance(), 0);
+    }
+
+    #[test]
+    pub fn test_reorg() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let miner = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let other = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, miner.clone());
+        coins.process(&mut master, &first);
+        let coin = OutPoint {
+            txid: first.txdata[0].txid(),
+            vout: 0,
+        };
+        let spend = |value: u64| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: coin,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: other.script_pubkey(),
+            }],
+        };
+        // a payment without change, the spent coin is forgotten once confirmed
+        let payment = spend(NEW_COINS - 1000);
+        let mut second = mine(&first.block_hash(), 2, other.clone());
+        add_tx(&mut second, payment.clone());
+        coins.process(&mut master, &second);
+        assert_eq!(coins.confirmed_balance(), 0);
+        let height = |hash: &bitcoin::BlockHash| {
+            [&first, &second]
+                .iter()
+                .position(|b| b.block_hash() == *hash)
+                .map(|p| p as u32 + 1)
+        };
+        assert_eq!(
+            coins.anchor(&payment.txid(), height),
+            Some((second.block_hash(), 2))
+        );
+        // a double spend seen while the payment was confirmed
+        let double_spend = spend(NEW_COINS - 2000);
+        let mut doomed = double_spend.clone();
+        doomed.output.push(TxOut {
+            value: 1000,
+            script_pubkey: miner.script_pubkey(),
+        });
+        coins.process_unconfirmed_transaction(&mut master, &doomed);
+
+        coins.unwind_tip(&second.block_hash());
+        assert!(coins.pending().contains_key(&payment.txid()));
+        assert!(!coins.pending().contains_key(&doomed.txid()));
+        assert_eq!(coins.conflicts().get(&doomed.txid()), Some(&payment.txid()));
+        assert!(coins.is_own(&coin));
+        assert!(coins.proofs().contains_key(&coin.txid));
+        assert_eq!(coins.unconfirmed_balance(), 0);
+        assert_eq!(coins.anchor(&payment.txid(), height), None);
+
+        // a replacement evicts the pending payment, then the coin leaves the best chain too
+        let replacement = spend(NEW_COINS - 3000);
+        coins.process_unconfirmed_transaction(&mut master, &replacement);
+        assert_eq!(
+            coins.conflicts().get(&payment.txid()),
+            Some(&replacement.txid())
+        );
+        coins.unwind_tip(&first.block_hash());
+        assert!(coins.pending().contains_key(&first.txdata[0].txid()));
+        assert_eq!(coins.confirmed_balance(), 0);
+        assert!(coins.check(&master, |_| None).findings.is_empty());
     }
 
-    fn mine(tip: &bitcoin::BlockHash, height: u32, miner: Address) -> Block {
-        let mut block = new_block(tip);
-        add_tx(&mut block, coin_base(miner, height));
-        block
+    #[test]
+    pub fn test_watched() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let cold = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        master.add_watched_script(cold.script_pubkey()).unwrap();
+        let genesis = genesis_block(Network::Testnet);
+        let next = mine(&genesis.block_hash(), 1, cold);
+        assert!(coins.process(&mut master, &next));
+        assert_eq!(coins.confirmed_balance(), 0);
+        assert_eq!(coins.watched_balance(), NEW_COINS);
+        assert_eq!(coins.proofs().len(), 1);
+        coins.unwind_tip(&next.block_hash());
+        assert_eq!(coins.watched_balance(), 0);
+        assert_eq!(coins.watched().len(), 1);
+        assert!(coins.proofs().is_empty());
     }
 
     #[test]
-    pub fn test_coins() {
+    pub fn test_clusters() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let next_address = |master: &mut MasterAccount| {
+            master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone()
+        };
+        let a = next_address(&mut master);
+        let b = next_address(&mut master);
+        let c = next_address(&mut master);
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, a.clone());
+        coins.process(&mut master, &first);
+        let second = mine(&first.block_hash(), 2, b.clone());
+        coins.process(&mut master, &second);
+        assert_ne!(
+            coins.clusters().get(&a.script_pubkey()),
+            coins.clusters().get(&b.script_pubkey())
+        );
+
+        let foreign = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: [&first, &second]
+                .iter()
+                .map(|b| TxIn {
+                    previous_output: OutPoint {
+                        txid: b.txdata[0].txid(),
+                        vout: 0,
+                    },
+                    sequence: 0xffffffff,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                })
+                .collect(),
+            output: vec![
+                TxOut {
+                    value: NEW_COINS,
+                    script_pubkey: foreign.script_pubkey(),
+                },
+                TxOut {
+                    value: NEW_COINS - 1000,
+                    script_pubkey: c.script_pubkey(),
+                },
+            ],
+        };
+        let mut third = mine(&second.block_hash(), 3, foreign);
+        add_tx(&mut third, spend.clone());
+        coins.process(&mut master, &third);
+        let change = coins
+            .cluster_of(&OutPoint {
+                txid: spend.txid(),
+                vout: 1,
+            })
+            .unwrap();
+        assert_eq!(coins.clusters().get(&a.script_pubkey()), Some(change));
+        assert_eq!(coins.clusters().get(&b.script_pubkey()), Some(change));
+    }
+
+    #[test]
+    pub fn test_archived() {
         let mut coins = Coins::new();
         let mut master = new_master();
         let miner = master
@@ -405,11 +3500,875 @@ use bitcoin::hashes::hex::FromHex;
             .unwrap()
             .address
             .clone();
+        master.get_mut((0, 0)).unwrap().set_archived(true);
         let genesis = genesis_block(Network::Testnet);
         let next = mine(&genesis.block_hash(), 1, miner);
+        // archived accounts are still scanned
         coins.process(&mut master, &next);
         assert_eq!(coins.confirmed_balance(), NEW_COINS);
-        coins.unwind_tip(&next.block_hash());
+        assert_eq!(coins.balance(200, |_| Some(1)).total(), 0);
+        // restoring takes effect once followed
+        master.get_mut((0, 0)).unwrap().set_archived(false);
+        assert_eq!(coins.balance(200, |_| Some(1)).total(), 0);
+        coins.follow_archived(&master);
+        assert_eq!(coins.balance(200, |_| Some(1)).total(), NEW_COINS);
+    }
+
+    #[test]
+    pub fn test_store() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let miner = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let cold = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        master.add_watched_script(cold.script_pubkey()).unwrap();
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, miner);
+        coins.process(&mut master, &first);
+        let second = mine(&first.block_hash(), 2, cold);
+        coins.process(&mut master, &second);
+
+        let mut memory = MemoryCoinStore::default();
+        coins.save(&mut memory).unwrap();
+        assert_eq!(memory.coins().unwrap().len(), 2);
+        assert!(Coins::load(&memory).unwrap() == coins);
+
+        let path = std::env::temp_dir().join(format!("coins-{}.dat", std::process::id()));
+        let mut file = FileCoinStore::open(&path).unwrap();
+        coins.save(&mut file).unwrap();
+        coins.unwind_tip(&second.block_hash());
+        coins.save(&mut file).unwrap();
+        let loaded = Coins::load(&FileCoinStore::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded == coins);
+        assert_eq!(loaded.confirmed_balance(), NEW_COINS);
+        assert_eq!(loaded.watched_balance(), 0);
+        assert_eq!(loaded.watched().len(), 1);
+
+        let mut snapshot = coins.export_snapshot().unwrap();
+        assert!(Coins::import_snapshot(snapshot.as_slice()).unwrap() == coins);
+        snapshot[4] = SNAPSHOT_VERSION + 1;
+        assert!(Coins::import_snapshot(snapshot.as_slice()).is_err());
+        // a coin store file is not a snapshot
+        let stored = memory.encode(COIN_FILE_MAGIC, COIN_FILE_VERSION).unwrap();
+        assert!(Coins::import_snapshot(stored.as_slice()).is_err());
+    }
+
+    #[test]
+    pub fn test_frozen() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let miner = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, miner.clone());
+        coins.process(&mut master, &first);
+        let second = mine(&first.block_hash(), 2, miner);
+        coins.process(&mut master, &second);
+        let point = OutPoint {
+            txid: first.txdata[0].txid(),
+            vout: 0,
+        };
+        let heights = |h: &bitcoin::BlockHash| {
+            if *h == first.block_hash() {
+                Some(1)
+            } else {
+                Some(2)
+            }
+        };
+        assert!(coins.freeze(&point).unwrap());
+        assert!(!coins.freeze(&OutPoint::default()).unwrap());
+        assert_eq!(coins.frozen_balance(), NEW_COINS);
+        assert_eq!(coins.available_balance(2, heights), NEW_COINS);
+        let inputs = coins.choose_inputs(NEW_COINS / 2, 2, heights);
+        assert!(inputs.iter().all(|(p, _, _)| *p != point));
+
+        let mut memory = MemoryCoinStore::default();
+        coins.save(&mut memory).unwrap();
+        let mut loaded = Coins::load(&memory).unwrap();
+        assert!(loaded.is_frozen(&point));
+        assert!(loaded.unfreeze(&point).unwrap());
+        assert_eq!(loaded.available_balance(2, heights), 2 * NEW_COINS);
+    }
+
+    #[test]
+    pub fn test_drafts() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let miner = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, miner.clone());
+        coins.process(&mut master, &first);
+        let second = mine(&first.block_hash(), 2, miner.clone());
+        coins.process(&mut master, &second);
+        let point = OutPoint {
+            txid: first.txdata[0].txid(),
+            vout: 0,
+        };
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = miner.script_pubkey();
+        let (psbt, _) = coins
+            .build_tx()
+            .add_recipient(&to, NEW_COINS / 2)
+            .coin_control(CoinControl::new().add_utxo(point))
+            .build_psbt(&master, &change, 2, heights)
+            .unwrap();
+        coins.save_draft("cold", psbt.clone()).unwrap();
+        assert_eq!(coins.reserved_by(&point), Some("cold"));
+        assert_eq!(coins.draft("cold").unwrap().spends(), vec![point]);
+        assert_eq!(coins.available_balance(2, heights), NEW_COINS);
+        // reserved coins are not spent by other transactions
+        assert!(coins.save_draft("other", psbt.clone()).is_err());
+        assert!(coins
+            .build_tx()
+            .add_recipient(&to, NEW_COINS / 2)
+            .coin_control(CoinControl::new().add_utxo(point))
+            .build(&change, 2, heights)
+            .is_err());
+        // a signer added something, the draft is replaced
+        let mut signed = psbt.clone();
+        signed.inputs[0].partial_sigs.insert(
+            master.get((0, 0)).unwrap().get_key(0).unwrap().public,
+            vec![0x30],
+        );
+        coins.save_draft("cold", signed.clone()).unwrap();
+
+        let mut memory = MemoryCoinStore::default();
+        coins.save(&mut memory).unwrap();
+        let mut loaded = Coins::load(&memory).unwrap();
+        assert!(loaded == coins);
+        assert!(*loaded.draft("cold").unwrap().psbt() == signed);
+        assert!(
+            Coins::import_snapshot(coins.export_snapshot().unwrap().as_slice()).unwrap() == coins
+        );
+        assert!(loaded.discard_draft("cold").unwrap().is_some());
+        assert_eq!(loaded.available_balance(2, heights), 2 * NEW_COINS);
+        loaded.save(&mut memory).unwrap();
+        assert!(memory.drafts().unwrap().is_empty());
+
+        // the draft is done once its transaction is seen
+        coins.process_unconfirmed_transaction(&mut master, &psbt.global.unsigned_tx);
+        assert!(coins.drafts().is_empty());
+
+        // snapshots and stores of version 1 have no drafts, they are migrated
+        let mut snapshot = coins.export_snapshot().unwrap();
+        assert_eq!(snapshot.pop(), Some(0));
+        snapshot[4] = 1;
+        assert!(Coins::import_snapshot(snapshot.as_slice()).unwrap() == coins);
+        let mut stored = memory.encode(COIN_FILE_MAGIC, COIN_FILE_VERSION).unwrap();
+        assert_eq!(stored.pop(), Some(0));
+        stored[4] = 1;
+        assert!(
+            MemoryCoinStore::decode(stored.as_slice(), COIN_FILE_MAGIC, COIN_FILE_VERSION).is_err()
+        );
+        let path = std::env::temp_dir().join(format!("drafts-{}.dat", std::process::id()));
+        std::fs::write(&path, stored.as_slice()).unwrap();
+        let file = FileCoinStore::open(&path).unwrap();
+        assert!(file.memory == memory);
+        let mut backup = path.clone().into_os_string();
+        backup.push(".v1.bak");
+        assert_eq!(std::fs::read(&backup).unwrap(), stored);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    pub fn test_coin_control() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let mut tip = genesis_block(Network::Testnet).block_hash();
+        let mut points = Vec::new();
+        for height in 1..4 {
+            let miner = master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone();
+            let block = mine(&tip, height, miner);
+            coins.process(&mut master, &block);
+            points.push(OutPoint {
+                txid: block.txdata[0].txid(),
+                vout: 0,
+            });
+            tip = block.block_hash();
+        }
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let control = CoinControl::new().add_utxo(points[0]);
+        let inputs = coins
+            .choose_inputs_with(&control, NEW_COINS / 2, 3, heights)
+            .unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].0, points[0]);
+
+        let control = control.only_spend_from(&points[..2]).exclude(points[1]);
+        let inputs = coins
+            .choose_inputs_with(&control, NEW_COINS * 2, 3, heights)
+            .unwrap();
+        assert_eq!(inputs.len(), 1);
+        let control = CoinControl::new()
+            .add_utxo(points[0])
+            .only_spend_from(&points[1..2]);
+        let mut inputs = coins
+            .choose_inputs_with(&control, NEW_COINS * 2, 3, heights)
+            .unwrap()
+            .iter()
+            .map(|(p, _, _)| *p)
+            .collect::<Vec<_>>();
+        inputs.sort();
+        let mut expected = points[..2].to_vec();
+        expected.sort();
+        assert_eq!(inputs, expected);
+
+        coins.freeze(&points[2]).unwrap();
+        let control = CoinControl::new().add_utxo(points[2]);
+        assert!(coins
+            .choose_inputs_with(&control, NEW_COINS, 3, heights)
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_select() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let mut tip = genesis_block(Network::Testnet).block_hash();
+        let mut points = Vec::new();
+        for height in 1..4 {
+            let miner = master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone();
+            let block = mine(&tip, height, miner);
+            coins.process(&mut master, &block);
+            points.push(OutPoint {
+                txid: block.txdata[0].txid(),
+                vout: 0,
+            });
+            tip = block.block_hash();
+        }
+        let drain = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .script_pubkey();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let feerate = FeeRate::from_sat_per_vb(1);
+        let control = CoinControl::new().add_utxo(points[0]);
+        let selection = coins
+            .select(
+                &LargestFirst,
+                &control,
+                NEW_COINS + 1000,
+                feerate,
+                &drain,
+                3,
+                heights,
+            )
+            .unwrap();
+        assert_eq!(selection.selected.len(), 2);
+        assert_eq!(selection.selected[0].point, points[0]);
+        assert!(selection.change.is_some());
+        let selection = coins
+            .select(
+                &BranchAndBound::default(),
+                &CoinControl::new(),
+                NEW_COINS - 68,
+                feerate,
+                &drain,
+                3,
+                heights,
+            )
+            .unwrap();
+        assert_eq!(selection.selected.len(), 1);
+        assert_eq!(selection.change, None);
+    }
+
+    #[test]
+    pub fn test_max_send() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let mut tip = genesis_block(Network::Testnet).block_hash();
+        let mut points = Vec::new();
+        for height in 1..3 {
+            let miner = master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone();
+            let block = mine(&tip, height, miner);
+            coins.process(&mut master, &block);
+            points.push(OutPoint {
+                txid: block.txdata[0].txid(),
+                vout: 0,
+            });
+            tip = block.block_hash();
+        }
+        let to = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+            .unwrap()
+            .script_pubkey();
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        let feerate = FeeRate::from_sat_per_vb(1);
+        // two P2WPKH inputs of 68 vbytes, one output of 31 vbytes and 10.5 vbytes overhead
+        assert_eq!(
+            coins.max_send(&to, feerate, 2, heights),
+            2 * NEW_COINS - 2 * 68 - 31 - 11
+        );
+        coins.freeze(&points[0]).unwrap();
+        assert_eq!(
+            coins.max_send(&to, feerate, 2, heights),
+            NEW_COINS - 68 - 31 - 11
+        );
+    }
+
+    #[test]
+    pub fn test_unconfirmed_policy() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let next_address = |master: &mut MasterAccount| {
+            master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone()
+        };
+        let a = next_address(&mut master);
+        let b = next_address(&mut master);
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, a);
+        coins.process(&mut master, &first);
+        // a chain of two unconfirmed self transfers
+        let mut previous = first.txdata[0].txid();
+        let mut chain = Vec::new();
+        for _ in 0..2 {
+            let tx = Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint {
+                        txid: previous,
+                        vout: 0,
+                    },
+                    sequence: 0xffffffff,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                }],
+                output: vec![TxOut {
+                    value: NEW_COINS - 1000,
+                    script_pubkey: b.script_pubkey(),
+                }],
+            };
+            coins.process_unconfirmed_transaction(&mut master, &tx);
+            previous = tx.txid();
+            chain.push(tx);
+        }
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        assert_eq!(coins.ancestors(&previous).0, 2);
+        assert!(coins.spendable_coins(true, 1, heights).is_empty());
+        coins.set_unconfirmed_policy(UnconfirmedPolicy::SelfTransfersOnly);
+        assert!(coins.spendable_coins(false, 1, heights).is_empty());
+        let spendable = coins.spendable_coins(true, 1, heights);
+        assert_eq!(spendable.len(), 1);
+        assert_eq!(spendable[0].0.txid, previous);
+        // spending needs two ancestors plus the new transaction
+        coins.set_unconfirmed_policy(UnconfirmedPolicy::Limited {
+            max_ancestors: 2,
+            max_ancestor_vsize: 1000,
+        });
+        assert!(coins.spendable_coins(false, 1, heights).is_empty());
+        coins.set_unconfirmed_policy(UnconfirmedPolicy::mempool_limits());
+        assert_eq!(coins.spendable_coins(false, 1, heights).len(), 1);
+
+        let mut memory = MemoryCoinStore::default();
+        coins.save(&mut memory).unwrap();
+        assert_eq!(Coins::load(&memory).unwrap().pending().len(), 2);
+
+        let unconfirmed = OutPoint {
+            txid: previous,
+            vout: 0,
+        };
+        let balance = Balance {
+            trusted_pending: NEW_COINS - 1000,
+            ..Balance::default()
+        };
+        assert_eq!(coins.balance(1, heights), balance);
+        assert_eq!(coins.account_balance((0, 0), 1, heights), balance);
+        assert_eq!(
+            coins.account_balance((0, 1), 1, heights),
+            Balance::default()
+        );
+        coins.freeze(&unconfirmed).unwrap();
+        assert_eq!(coins.balance(1, heights).frozen, NEW_COINS - 1000);
+        coins.unfreeze(&unconfirmed).unwrap();
+        let mut second = mine(&first.block_hash(), 2, b);
+        add_tx(&mut second, chain[0].clone());
+        coins.process(&mut master, &second);
+        assert_eq!(coins.ancestors(&previous).0, 1);
+    }
+
+    #[test]
+    pub fn test_conflict() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let next_address = |master: &mut MasterAccount| {
+            master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone()
+        };
+        let a = next_address(&mut master);
+        let b = next_address(&mut master);
+        let c = next_address(&mut master);
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, a.clone());
+        coins.process(&mut master, &first);
+        let second = mine(&first.block_hash(), 2, a);
+        coins.process(&mut master, &second);
+        let spend = |inputs: &[&Block], to: &Address| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|b| TxIn {
+                    previous_output: OutPoint {
+                        txid: b.txdata[0].txid(),
+                        vout: 0,
+                    },
+                    sequence: 0xffffffff,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: NEW_COINS - 1000,
+                script_pubkey: to.script_pubkey(),
+            }],
+        };
+        let own = spend(&[&first, &second], &b);
+        coins.process_unconfirmed_transaction(&mut master, &own);
+        assert_eq!(coins.confirmed_balance(), 0);
+        let mut memory = MemoryCoinStore::default();
+        coins.save(&mut memory).unwrap();
+        let mut coins = Coins::load(&memory).unwrap();
+
+        // a double spend of the first coin only gets mined
+        let foreign = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        let double = spend(&[&first], &foreign);
+        let mut third = mine(&second.block_hash(), 3, c);
+        add_tx(&mut third, double.clone());
+        coins.process(&mut master, &third);
+        assert_eq!(
+            coins.take_events(),
+            vec![CoinEvent::Conflict {
+                evicted: own.txid(),
+                by: double.txid()
+            }]
+        );
+        assert!(coins.take_events().is_empty());
+        assert!(coins.pending().is_empty());
+        assert!(coins.unconfirmed().is_empty());
+        assert_eq!(coins.conflicts().get(&own.txid()), Some(&double.txid()));
+        // the second coin is restored, the third block paid to us
+        assert_eq!(coins.confirmed_balance(), 2 * NEW_COINS);
+        assert!(coins.confirmed().contains_key(&OutPoint {
+            txid: second.txdata[0].txid(),
+            vout: 0
+        }));
+    }
+
+    #[test]
+    pub fn test_coinbase_maturity() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let miner = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let genesis = genesis_block(Network::Testnet);
+        let mut block = new_block(&genesis.block_hash());
+        let mut coinbase = coin_base(miner, 1);
+        coinbase.input[0].previous_output = OutPoint::null();
+        add_tx(&mut block, coinbase.clone());
+        coins.process(&mut master, &block);
+        let point = OutPoint {
+            txid: coinbase.txid(),
+            vout: 0,
+        };
+        let heights = |_: &bitcoin::BlockHash| Some(1);
+        assert!(coins.immature_coinbase().contains(&point));
+        assert!(coins.available_coins(100, heights).is_empty());
+        assert_eq!(coins.balance(100, heights).immature, NEW_COINS);
+        assert!(coins.update_tip(100, heights).is_empty());
+        assert_eq!(coins.update_tip(101, heights), vec![point]);
+        assert_eq!(coins.take_events(), vec![CoinEvent::Matured { point }]);
+        assert_eq!(coins.available_coins(101, heights).len(), 1);
+        assert_eq!(coins.balance(101, heights).confirmed, NEW_COINS);
+    }
+
+    #[test]
+    pub fn test_metadata() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let next_address = |master: &mut MasterAccount| {
+            master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone()
+        };
+        let a = next_address(&mut master);
+        let b = next_address(&mut master);
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, a);
+        coins.process(&mut master, &first);
+        let point = OutPoint {
+            txid: first.txdata[0].txid(),
+            vout: 0,
+        };
+        assert!(!coins.set_memo(&OutPoint::null(), "unknown").unwrap());
+        assert!(coins.set_memo(&point, "from Bob").unwrap());
+        assert!(coins.add_tag(&point, "salary").unwrap());
+        assert!(coins.add_tag(&point, "salary").unwrap());
+        assert_eq!(
+            coins.metadata(&point).unwrap().tags,
+            vec!["salary".to_string()]
+        );
+        assert_eq!(coins.coins_tagged("salary").len(), 1);
+
+        let mut memory = MemoryCoinStore::default();
+        coins.save(&mut memory).unwrap();
+        assert_eq!(
+            Coins::load(&memory).unwrap().metadata(&point),
+            coins.metadata(&point)
+        );
+
+        coins.set_metadata_propagation(MetadataPropagation::TagsAndMemos);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: point,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: NEW_COINS - 1000,
+                script_pubkey: b.script_pubkey(),
+            }],
+        };
+        coins.process_unconfirmed_transaction(&mut master, &tx);
+        let change = OutPoint {
+            txid: tx.txid(),
+            vout: 0,
+        };
+        let inherited = coins.metadata(&change).unwrap().clone();
+        assert_eq!(inherited.memo, Some("from Bob".to_string()));
+        assert_eq!(inherited.tags, vec!["salary".to_string()]);
+        assert!(coins.remove_tag(&change, "salary").unwrap());
+        assert!(coins.coins_tagged("salary").is_empty());
+
+        // confirmation settles the spent coin and keeps the metadata of the change
+        let mut second = mine(&first.block_hash(), 2, next_address(&mut master));
+        add_tx(&mut second, tx);
+        coins.process(&mut master, &second);
+        assert!(coins.metadata(&point).is_none());
+        assert_eq!(coins.metadata(&change).unwrap().memo, inherited.memo);
+    }
+
+    #[test]
+    pub fn test_dust() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let next_address = |master: &mut MasterAccount| {
+            master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone()
+        };
+        let a = next_address(&mut master);
+        let b = next_address(&mut master);
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, a.clone());
+        coins.process(&mut master, &first);
+        let foreign = OutPoint {
+            txid: genesis.txdata[0].txid(),
+            vout: 0,
+        };
+        // tiny amounts to the used and to a fresh address
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: foreign,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: 546,
+                    script_pubkey: a.script_pubkey(),
+                },
+                TxOut {
+                    value: 546,
+                    script_pubkey: b.script_pubkey(),
+                },
+            ],
+        };
+        coins.process_unconfirmed_transaction(&mut master, &tx);
+        let dust = OutPoint {
+            txid: tx.txid(),
+            vout: 0,
+        };
+        assert_eq!(coins.suspected_dust().len(), 1);
+        assert!(coins.suspected_dust().contains(&dust));
+        assert!(coins.is_frozen(&dust));
+        assert_eq!(
+            coins.take_events(),
+            vec![CoinEvent::Dust {
+                point: dust,
+                frozen: true
+            }]
+        );
+        // confirmation does not flag again
+        let mut second = mine(&first.block_hash(), 2, next_address(&mut master));
+        add_tx(&mut second, tx.clone());
+        coins.process(&mut master, &second);
+        assert!(coins.take_events().is_empty());
+        assert!(coins.suspected_dust().contains(&dust));
+
+        let mut memory = MemoryCoinStore::default();
+        coins.save(&mut memory).unwrap();
+        assert!(Coins::load(&memory)
+            .unwrap()
+            .suspected_dust()
+            .contains(&dust));
+
+        assert!(coins.dismiss_dust(&dust).unwrap());
+        assert!(!coins.is_frozen(&dust));
+
+        // flag only
+        coins.set_dust_policy(DustPolicy {
+            threshold: 1000,
+            freeze: false,
+        });
+        let mut again = tx;
+        again.input[0].previous_output.vout = 1;
+        coins.process_unconfirmed_transaction(&mut master, &again);
+        let point = OutPoint {
+            txid: again.txid(),
+            vout: 0,
+        };
+        assert!(coins.suspected_dust().contains(&point));
+        assert!(!coins.is_frozen(&point));
+    }
+
+    #[test]
+    pub fn test_list_coins() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let next_address = |master: &mut MasterAccount| {
+            master
+                .get_mut((0, 0))
+                .unwrap()
+                .next_key()
+                .unwrap()
+                .address
+                .clone()
+        };
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, next_address(&mut master));
+        coins.process(&mut master, &first);
+        let second = mine(&first.block_hash(), 2, next_address(&mut master));
+        coins.process(&mut master, &second);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: genesis.txdata[0].txid(),
+                    vout: 0,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 5000,
+                script_pubkey: next_address(&mut master).script_pubkey(),
+            }],
+        };
+        coins.process_unconfirmed_transaction(&mut master, &tx);
+        let a = OutPoint {
+            txid: first.txdata[0].txid(),
+            vout: 0,
+        };
+        let b = OutPoint {
+            txid: second.txdata[0].txid(),
+            vout: 0,
+        };
+        let c = OutPoint {
+            txid: tx.txid(),
+            vout: 0,
+        };
+        coins.add_tag(&a, "salary").unwrap();
+        coins.freeze(&b).unwrap();
+        let heights = |h: &bitcoin::BlockHash| {
+            if *h == first.block_hash() {
+                Some(1)
+            } else {
+                Some(2)
+            }
+        };
+        let list = |query: CoinQuery| {
+            coins
+                .list_coins(&query, 2, heights)
+                .into_iter()
+                .map(|(p, _, _)| p)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(list(CoinQuery::new()).len(), 3);
+        assert_eq!(list(CoinQuery::new())[0], c);
+        assert_eq!(list(CoinQuery::new().min_depth(1)).len(), 2);
+        assert_eq!(list(CoinQuery::new().min_depth(2)), vec![a]);
+        assert_eq!(list(CoinQuery::new().max_depth(0)), vec![c]);
+        assert_eq!(list(CoinQuery::new().label("salary")), vec![a]);
+        assert_eq!(list(CoinQuery::new().frozen(true)), vec![b]);
+        assert_eq!(list(CoinQuery::new().max_value(10_000)), vec![c]);
+        assert_eq!(
+            list(CoinQuery::new().min_value(10_000).frozen(false)),
+            vec![a]
+        );
+        assert_eq!(list(CoinQuery::new().account(1)).len(), 0);
+        assert_eq!(list(CoinQuery::new().account(0).sub_account(0)).len(), 3);
+        assert_eq!(
+            list(CoinQuery::new().script_type(ScriptType::P2PKH)).len(),
+            0
+        );
+        assert_eq!(
+            list(CoinQuery::new().order_by(CoinOrder::Depth).descending()),
+            vec![a, b, c]
+        );
+    }
+
+    #[test]
+    pub fn test_check() {
+        let mut coins = Coins::new();
+        let mut master = new_master();
+        let miner = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let genesis = genesis_block(Network::Testnet);
+        let first = mine(&genesis.block_hash(), 1, miner.clone());
+        coins.process(&mut master, &first);
+        let second = mine(&first.block_hash(), 2, miner);
+        coins.process(&mut master, &second);
+        let a = OutPoint {
+            txid: first.txdata[0].txid(),
+            vout: 0,
+        };
+        let b = OutPoint {
+            txid: second.txdata[0].txid(),
+            vout: 0,
+        };
+        coins.set_memo(&a, "salary").unwrap();
+        let headers = |h: &bitcoin::BlockHash| {
+            if *h == first.block_hash() {
+                Some(first.header)
+            } else if *h == second.block_hash() {
+                Some(second.header)
+            } else {
+                None
+            }
+        };
+        assert!(coins.check(&master, headers).is_consistent());
+
+        coins.proofs.remove(&a.txid);
+        let orphan = OutPoint {
+            txid: b.txid,
+            vout: 7,
+        };
+        coins.metadata.insert(orphan, CoinMetadata::default());
+        let unknown = OutPoint {
+            txid: bitcoin::Txid::default(),
+            vout: 3,
+        };
+        coins.frozen.insert(unknown);
+        let forged = |h: &bitcoin::BlockHash| {
+            headers(h).map(|mut header| {
+                if *h == second.block_hash() {
+                    header.merkle_root = bitcoin::TxMerkleNode::default();
+                }
+                header
+            })
+        };
+        let report = coins.check(&master, forged);
+        assert_eq!(
+            report.findings,
+            vec![
+                (Inconsistency::InvalidProof(b.txid), false),
+                (Inconsistency::MissingProof(a), false),
+                (Inconsistency::OrphanedLabel(orphan), false),
+                (Inconsistency::DanglingMark(unknown), false),
+            ]
+        );
+
+        master.set_read_only(true);
+        assert!(coins.repair(&mut master, forged).is_err());
+        master.set_read_only(false);
+        let report = coins.repair(&mut master, forged).unwrap();
+        assert_eq!(report.findings.len(), 4);
+        assert_eq!(report.unrepaired().count(), 0);
+        assert!(coins.check(&master, forged).is_consistent());
         assert_eq!(coins.confirmed_balance(), 0);
+        // the label waits for the rescan
+        assert_eq!(coins.metadata(&a).unwrap().memo, Some("salary".to_string()));
+        coins.process(&mut master, &first);
+        assert_eq!(coins.confirmed_balance(), NEW_COINS);
+        assert!(coins.check(&master, headers).is_consistent());
     }
 }
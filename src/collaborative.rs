@@ -0,0 +1,454 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Collaborative transactions
+//!
+//! Transactions funded by two parties, e.g. dual funded channels or splices. The initiator
+//! proposes a PSBT with its inputs, the shared outputs and its change, the counterparty answers
+//! with a PSBT of its inputs and change. The merge checks the counterparty's inputs against the
+//! chain, splits the fee as negotiated and sets own change so the shared outputs are funded.
+//!
+use std::collections::HashSet;
+
+use bitcoin::{OutPoint, TxOut};
+
+use account::MasterAccount;
+use coins::Coins;
+use error::Error;
+use fee::FeeRate;
+use psbt::{analyze, preserve_global, spent_output, Psbt};
+use selection::output_weight;
+
+/// A source of unspent outputs, e.g. gettxout of bitcoind
+pub trait UtxoLookup {
+    /// the output at point, None if it does not exist or is spent
+    fn utxo(&self, point: &OutPoint) -> Result<Option<TxOut>, Error>;
+}
+
+/// Who pays for the parts of the transaction no party owns alone: version, lock time, counts
+/// and the shared outputs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FeeSplit {
+    /// the initiator pays, as dual funded channels do
+    Ours,
+    Theirs,
+    /// half each, an odd satoshi is ours
+    Even,
+}
+
+/// Terms negotiated with the counterparty
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Terms {
+    feerate: FeeRate,
+    max_contribution: u64,
+    fee_split: FeeSplit,
+}
+
+impl Terms {
+    /// every party pays for its inputs and outputs at feerate
+    pub fn new(feerate: FeeRate) -> Terms {
+        Terms {
+            feerate,
+            max_contribution: u64::MAX,
+            fee_split: FeeSplit::Ours,
+        }
+    }
+
+    /// most we pay into the shared outputs, fee not included
+    pub fn max_contribution(mut self, value: u64) -> Terms {
+        self.max_contribution = value;
+        self
+    }
+
+    pub fn fee_split(mut self, fee_split: FeeSplit) -> Terms {
+        self.fee_split = fee_split;
+        self
+    }
+}
+
+/// A merged transaction, sign own inputs with MasterAccount::sign_psbt and combine with the
+/// signatures of the counterparty
+#[derive(Clone, Debug)]
+pub struct Merged {
+    pub psbt: Psbt,
+    /// paid into the shared outputs by us
+    pub our_contribution: u64,
+    /// paid into the shared outputs by the counterparty
+    pub their_contribution: u64,
+    pub our_fee: u64,
+    pub their_fee: u64,
+}
+
+/// merge the counterparty's PSBT into ours
+/// Outputs of ours that are not own are shared. Our first own output is change, its value is
+/// set to what is left after our fee and contribution, it is dropped if that is dust. The
+/// counterparty must spend existing segwit outputs and pay its fee, what it pays beyond the
+/// shared outputs is left to miners.
+pub fn merge<L: UtxoLookup>(
+    ours: &Psbt,
+    theirs: &Psbt,
+    terms: &Terms,
+    coins: &Coins,
+    master: &MasterAccount,
+    lookup: &L,
+) -> Result<Merged, Error> {
+    let our_tx = &ours.global.unsigned_tx;
+    let their_tx = &theirs.global.unsigned_tx;
+    if our_tx.version != their_tx.version || our_tx.lock_time != their_tx.lock_time {
+        return Err(Error::Collaboration("version or lock time differ"));
+    }
+    let mut our_value = 0;
+    for (ix, input) in our_tx.input.iter().enumerate() {
+        if !coins.is_own(&input.previous_output) {
+            return Err(Error::Collaboration("input of ours is not own"));
+        }
+        our_value += spent_output(ours, ix)
+            .or_else(|| coins.output(&input.previous_output))
+            .ok_or(Error::Collaboration("spent output of own input not known"))?
+            .value;
+    }
+    let mut their_inputs = theirs.inputs.clone();
+    let mut their_value = 0;
+    let mut points = our_tx
+        .input
+        .iter()
+        .map(|i| i.previous_output)
+        .collect::<HashSet<_>>();
+    for (ix, input) in their_tx.input.iter().enumerate() {
+        let point = &input.previous_output;
+        if coins.is_own(point) {
+            return Err(Error::Collaboration("counterparty spends own coin"));
+        }
+        if !points.insert(*point) {
+            return Err(Error::Collaboration("input spent twice"));
+        }
+        let utxo = lookup
+            .utxo(point)?
+            .ok_or(Error::Collaboration("counterparty input does not exist"))?;
+        if spent_output(theirs, ix).is_some_and(|o| o != utxo) {
+            return Err(Error::Collaboration("counterparty misstates spent output"));
+        }
+        let wrapped = their_inputs[ix]
+            .redeem_script
+            .as_ref()
+            .is_some_and(|s| s.is_witness_program() && utxo.script_pubkey.is_p2sh());
+        if !utxo.script_pubkey.is_witness_program() && !wrapped {
+            return Err(Error::Collaboration("counterparty input is not segwit"));
+        }
+        their_value += utxo.value;
+        their_inputs[ix].witness_utxo = Some(utxo);
+    }
+    if their_tx
+        .output
+        .iter()
+        .any(|o| o.value < o.script_pubkey.dust_value())
+    {
+        return Err(Error::Collaboration("counterparty output below dust"));
+    }
+
+    let scripts = master.get_scripts().map(|(s, _)| s).collect::<HashSet<_>>();
+    let change = our_tx
+        .output
+        .iter()
+        .position(|o| scripts.contains(&o.script_pubkey));
+    let shared = our_tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(ix, _)| Some(*ix) != change)
+        .map(|(_, o)| o.value)
+        .sum::<u64>();
+
+    let mut transaction = our_tx.clone();
+    transaction.input.extend(their_tx.input.iter().cloned());
+    transaction.output.extend(their_tx.output.iter().cloned());
+    let mut psbt = Psbt::from_unsigned_tx(transaction)?;
+    psbt.inputs = ours.inputs.iter().cloned().chain(their_inputs).collect();
+    psbt.outputs = ours
+        .outputs
+        .iter()
+        .chain(theirs.outputs.iter())
+        .cloned()
+        .collect();
+    preserve_global(&mut psbt, ours);
+    preserve_global(&mut psbt, theirs);
+
+    let analysis = analyze(&psbt);
+    let our_weight = analysis.inputs[..our_tx.input.len()]
+        .iter()
+        .map(|i| i.weight)
+        .sum::<u64>()
+        + change
+            .map(|ix| output_weight(&our_tx.output[ix].script_pubkey))
+            .unwrap_or(0);
+    let their_weight = analysis.inputs[our_tx.input.len()..]
+        .iter()
+        .map(|i| i.weight)
+        .sum::<u64>()
+        + their_tx
+            .output
+            .iter()
+            .map(|o| output_weight(&o.script_pubkey))
+            .sum::<u64>();
+    let common = terms
+        .feerate
+        .fee(analysis.weight - our_weight - their_weight);
+    let (our_share, their_share) = match terms.fee_split {
+        FeeSplit::Ours => (common, 0),
+        FeeSplit::Theirs => (0, common),
+        FeeSplit::Even => (common - common / 2, common / 2),
+    };
+    let our_fee = terms.feerate.fee(our_weight) + our_share;
+    let their_fee = terms.feerate.fee(their_weight) + their_share;
+
+    let their_contribution = their_value
+        .checked_sub(their_tx.output.iter().map(|o| o.value).sum::<u64>())
+        .and_then(|v| v.checked_sub(their_fee))
+        .ok_or(Error::Collaboration("counterparty does not pay its fee"))?;
+    let our_contribution = shared.saturating_sub(their_contribution);
+    if our_contribution > terms.max_contribution {
+        return Err(Error::Collaboration("contribution above the maximum"));
+    }
+    let left = our_value
+        .checked_sub(our_fee + our_contribution)
+        .ok_or(Error::Collaboration(
+            "own inputs do not cover the contribution",
+        ))?;
+    if let Some(ix) = change {
+        let output = &mut psbt.global.unsigned_tx.output[ix];
+        if left < output.script_pubkey.dust_value() {
+            psbt.global.unsigned_tx.output.remove(ix);
+            psbt.outputs.remove(ix);
+        } else {
+            output.value = left;
+        }
+    }
+    Ok(Merged {
+        psbt,
+        our_contribution,
+        their_contribution,
+        our_fee,
+        their_fee,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use bitcoin::{Address, Network, Script, Transaction, TxIn};
+
+    use account::Unlocker;
+    use fixtures::{block, funding, master_account, next_script};
+    use psbt::{create, extract, finalize};
+
+    use super::*;
+
+    struct Chain(HashMap<OutPoint, TxOut>);
+
+    impl UtxoLookup for Chain {
+        fn utxo(&self, point: &OutPoint) -> Result<Option<TxOut>, Error> {
+            Ok(self.0.get(point).cloned())
+        }
+    }
+
+    fn spend(point: OutPoint, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: point,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output,
+        }
+    }
+
+    /// we fund 120_000 sat of a channel from a coin of 100_000 sat, they add a coin of 80_000
+    /// sat keeping 29_000 sat as change
+    struct Channel {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        other: MasterAccount,
+        other_unlocker: Unlocker,
+        funding: Transaction,
+        chain: Chain,
+        ours: Psbt,
+        theirs: Psbt,
+        their_change: TxOut,
+    }
+
+    impl Channel {
+        fn new() -> Channel {
+            let (mut master, unlocker) = master_account(Network::Testnet);
+            let (mut other, other_unlocker) = master_account(Network::Testnet);
+            let funding = funding(vec![
+                TxOut {
+                    value: 100_000,
+                    script_pubkey: next_script(&mut master, (0, 0)),
+                },
+                TxOut {
+                    value: 80_000,
+                    script_pubkey: next_script(&mut other, (0, 0)),
+                },
+            ]);
+            let block = block(Network::Testnet, vec![funding.clone()]);
+            let mut coins = Coins::new();
+            coins.process(&mut master, &block);
+            let mut other_coins = Coins::new();
+            other_coins.process(&mut other, &block);
+            let point = |vout| OutPoint {
+                txid: funding.txid(),
+                vout,
+            };
+            let chain = Chain(
+                (0..2)
+                    .map(|vout| (point(vout), funding.output[vout as usize].clone()))
+                    .collect(),
+            );
+
+            // the channel funding output, change is set by the merge
+            let shared =
+                Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                    .unwrap()
+                    .script_pubkey();
+            let ours = create(
+                spend(
+                    point(0),
+                    vec![
+                        TxOut {
+                            value: 120_000,
+                            script_pubkey: shared,
+                        },
+                        TxOut {
+                            value: 0,
+                            script_pubkey: next_script(&mut master, (0, 0)),
+                        },
+                    ],
+                ),
+                &coins,
+                &master,
+            )
+            .unwrap();
+            let their_change = TxOut {
+                value: 29_000,
+                script_pubkey: next_script(&mut other, (0, 0)),
+            };
+            let theirs = create(
+                spend(point(1), vec![their_change.clone()]),
+                &other_coins,
+                &other,
+            )
+            .unwrap();
+            Channel {
+                master,
+                unlocker,
+                coins,
+                other,
+                other_unlocker,
+                funding,
+                chain,
+                ours,
+                theirs,
+                their_change,
+            }
+        }
+    }
+
+    fn terms() -> Terms {
+        Terms::new(FeeRate::from_sat_per_vb(2)).fee_split(FeeSplit::Even)
+    }
+
+    #[test]
+    fn rejected() {
+        let Channel {
+            master,
+            coins,
+            chain,
+            ours,
+            theirs,
+            ..
+        } = Channel::new();
+        assert!(merge(
+            &ours,
+            &theirs,
+            &terms().max_contribution(60_000),
+            &coins,
+            &master,
+            &chain
+        )
+        .is_err());
+        // their coin is unknown
+        assert!(merge(
+            &ours,
+            &theirs,
+            &terms(),
+            &coins,
+            &master,
+            &Chain(HashMap::new())
+        )
+        .is_err());
+        let mut lying = theirs.clone();
+        lying.inputs[0].witness_utxo.as_mut().unwrap().value = 90_000;
+        assert!(merge(&ours, &lying, &terms(), &coins, &master, &chain).is_err());
+        assert!(merge(&ours, &ours, &terms(), &coins, &master, &chain).is_err());
+    }
+
+    #[test]
+    fn dual_funding() {
+        let Channel {
+            master,
+            mut unlocker,
+            coins,
+            other,
+            mut other_unlocker,
+            funding,
+            chain,
+            ours,
+            theirs,
+            their_change,
+        } = Channel::new();
+        let merged = merge(&ours, &theirs, &terms(), &coins, &master, &chain).unwrap();
+        assert_eq!(
+            merged.their_contribution,
+            80_000 - 29_000 - merged.their_fee
+        );
+        assert_eq!(merged.our_contribution + merged.their_contribution, 120_000);
+        let mut psbt = merged.psbt;
+        let transaction = &psbt.global.unsigned_tx;
+        assert_eq!(transaction.input.len(), 2);
+        assert_eq!(transaction.output[2], their_change);
+        assert_eq!(
+            transaction.output[1].value,
+            100_000 - merged.our_fee - merged.our_contribution
+        );
+        assert_eq!(analyze(&psbt).fee, Some(merged.our_fee + merged.their_fee));
+
+        assert_eq!(master.sign_psbt(&mut psbt, &mut unlocker).unwrap(), 1);
+        assert_eq!(other.sign_psbt(&mut psbt, &mut other_unlocker).unwrap(), 1);
+        assert_eq!(finalize(&mut psbt), 2);
+        let feerate = analyze(&psbt).feerate().unwrap().as_sat_per_vb();
+        assert!((2.0..2.1).contains(&feerate));
+        extract(psbt)
+            .unwrap()
+            .verify(|point| funding.output.get(point.vout as usize).cloned())
+            .unwrap();
+    }
+}
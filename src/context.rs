@@ -16,6 +16,8 @@
 //!
 //! # Key derivation
 //!
+use bitcoin::secp256k1::recovery::RecoverableSignature;
+use bitcoin::secp256k1::schnorrsig;
 use bitcoin::secp256k1::{All, Message, Secp256k1, Signature};
 use bitcoin::{
     network::constants::Network,
@@ -23,26 +25,85 @@ use bitcoin::{
     PrivateKey, PublicKey,
 };
 
+use rand::{thread_rng, RngCore};
+
 use account::Seed;
 use error::Error;
 
+/// Nonces of signatures
+/// ECDSA nonces are derived from key and message with RFC6979, signing the same message with
+/// the same key gives the same signature. BIP340 signatures mix in auxiliary random data
+/// unless it is fixed here.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SigningConfig {
+    /// grind ECDSA nonces for a low R as Bitcoin Core does, signatures are then at most 71 bytes
+    pub low_r: bool,
+    /// auxiliary data of BIP340 signatures, fresh random data for every signature if None
+    pub aux_rand: Option<[u8; 32]>,
+}
+
+impl SigningConfig {
+    /// byte identical signatures for the same key and message, e.g. for test vectors
+    pub fn deterministic() -> SigningConfig {
+        SigningConfig {
+            low_r: false,
+            aux_rand: Some([0u8; 32]),
+        }
+    }
+
+    /// all signatures are deterministic
+    pub fn is_deterministic(&self) -> bool {
+        self.aux_rand.is_some()
+    }
+}
+
 pub struct SecpContext {
     secp: Secp256k1<All>,
+    config: SigningConfig,
+}
+
+impl Default for SecpContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SecpContext {
     pub fn new() -> SecpContext {
         SecpContext {
             secp: Secp256k1::new(),
+            config: SigningConfig::default(),
+        }
+    }
+
+    /// a context signing with config
+    pub fn with_signing_config(config: SigningConfig) -> SecpContext {
+        SecpContext {
+            secp: Secp256k1::new(),
+            config,
         }
     }
 
+    pub fn signing_config(&self) -> &SigningConfig {
+        &self.config
+    }
+
     /// create a master private key from seed
+    /// A seed of a master key imported from an other wallet is the key itself.
     pub fn master_private_key(
         &self,
         network: Network,
         seed: &Seed,
     ) -> Result<ExtendedPrivKey, Error> {
+        if seed.is_master_key() {
+            let mut key = ExtendedPrivKey::decode(&seed.0)?;
+            if (key.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+                return Err(Error::Network);
+            }
+            // test networks share the serialization
+            key.network = network;
+            return Ok(key);
+        }
         Ok(ExtendedPrivKey::new_master(network, &seed.0)?)
     }
 
@@ -75,7 +136,89 @@ impl SecpContext {
     }
 
     pub fn sign(&self, digest: &[u8], key: &PrivateKey) -> Result<Signature, Error> {
-        Ok(self.secp.sign(&Message::from_slice(digest)?, &key.key))
+        let message = Message::from_slice(digest)?;
+        if self.config.low_r {
+            Ok(self.secp.sign_low_r(&message, &key.key))
+        } else {
+            Ok(self.secp.sign(&message, &key.key))
+        }
+    }
+
+    /// BIP340 signature of a digest
+    pub fn sign_schnorr(
+        &self,
+        digest: &[u8],
+        key: &PrivateKey,
+    ) -> Result<schnorrsig::Signature, Error> {
+        let message = Message::from_slice(digest)?;
+        let keypair = schnorrsig::KeyPair::from_secret_key(&self.secp, key.key);
+        let aux_rand = match self.config.aux_rand {
+            Some(aux_rand) => aux_rand,
+            None => {
+                let mut aux_rand = [0u8; 32];
+                thread_rng().fill_bytes(&mut aux_rand);
+                aux_rand
+            }
+        };
+        Ok(self
+            .secp
+            .schnorrsig_sign_with_aux_rand(&message, &keypair, &aux_rand))
+    }
+
+    /// x-only public key of a private key as used by BIP340
+    pub fn schnorr_public(&self, key: &PrivateKey) -> schnorrsig::PublicKey {
+        schnorrsig::PublicKey::from_keypair(
+            &self.secp,
+            &schnorrsig::KeyPair::from_secret_key(&self.secp, key.key),
+        )
+    }
+
+    pub fn verify_schnorr(
+        &self,
+        digest: &[u8],
+        signature: &schnorrsig::Signature,
+        key: &schnorrsig::PublicKey,
+    ) -> Result<(), Error> {
+        Ok(self
+            .secp
+            .schnorrsig_verify(signature, &Message::from_slice(digest)?, key)?)
+    }
+
+    pub fn verify(
+        &self,
+        digest: &[u8],
+        signature: &Signature,
+        key: &PublicKey,
+    ) -> Result<(), Error> {
+        Ok(self
+            .secp
+            .verify(&Message::from_slice(digest)?, signature, &key.key)?)
+    }
+
+    /// sign with a signature the public key can be recovered from
+    pub fn sign_recoverable(
+        &self,
+        digest: &[u8],
+        key: &PrivateKey,
+    ) -> Result<RecoverableSignature, Error> {
+        Ok(self
+            .secp
+            .sign_recoverable(&Message::from_slice(digest)?, &key.key))
+    }
+
+    /// recover the public key of a recoverable signature
+    pub fn recover(
+        &self,
+        digest: &[u8],
+        signature: &RecoverableSignature,
+        compressed: bool,
+    ) -> Result<PublicKey, Error> {
+        Ok(PublicKey {
+            key: self
+                .secp
+                .recover(&Message::from_slice(digest)?, signature)?,
+            compressed,
+        })
     }
 
     pub fn tweak_add(&self, key: &mut PrivateKey, tweak: &[u8]) -> Result<(), Error> {
@@ -87,4 +230,13 @@ impl SecpContext {
         key.key.add_exp_assign(&self.secp, tweak)?;
         Ok(())
     }
+
+    /// compute the additive tweak that turns base into target
+    pub fn tweak_between(&self, base: &PrivateKey, target: &PrivateKey) -> Result<Vec<u8>, Error> {
+        let mut negated = base.key;
+        negated.negate_assign();
+        let mut tweak = target.key;
+        tweak.add_assign(&negated[..])?;
+        Ok(tweak[..].to_vec())
+    }
 }
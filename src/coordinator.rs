@@ -0,0 +1,503 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Multisig coordinator import
+//!
+//! Multisig wallets are often set up in a coordinator such as Sparrow or Specter. Their wallet
+//! exports describe the cosigners of a k of n P2WSH wallet with the BIP32 origin and the
+//! extended public key of each:
+//!
+//! ```text
+//! wsh(sortedmulti(2,[fingerprint/48h/1h/0h/2h]tpub.../0/*,...))
+//! ```
+//!
+//! A MultisigConfig read from an export derives the same scripts and addresses as the
+//! coordinator and loads into a P2WSH account. The account derives from the keys of the first
+//! cosigner, so it is watch-only: spends are signed by the cosigners, e.g. collected in a
+//! MultisigSession with the origins of their keys.
+//!
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint, KeySource};
+use bitcoin::{Address, Network, PublicKey, Script};
+
+use account::{Account, AccountAddressType};
+use context::SecpContext;
+use descriptor::strip_checksum;
+use error::Error;
+use json::{parse_json, Json};
+use multisig::multisig_script;
+
+/// BIP48 purpose, used as the P2WSH type of imported accounts
+pub const MULTISIG_PURPOSE: u32 = 48;
+
+/// extended public key versions of main and test networks, SLIP-132 versions are normalized
+const XPUB: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+const MAINNET_VERSIONS: [[u8; 4]; 3] = [XPUB, [0x02, 0x95, 0xb4, 0x3f], [0x02, 0xaa, 0x7e, 0xd3]];
+const TESTNET_VERSIONS: [[u8; 4]; 3] = [TPUB, [0x02, 0x42, 0x89, 0xef], [0x02, 0x57, 0x54, 0x83]];
+
+/// A cosigner of a multisig wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CosignerKey {
+    /// name of the cosigner or its device in the coordinator
+    pub label: String,
+    /// BIP32 origin of the extended public key
+    pub origin: KeySource,
+    pub xpub: ExtendedPubKey,
+}
+
+impl CosignerKey {
+    /// key kix of a chain, chain 0 receives, chain 1 is change
+    pub fn key(&self, chain: u32, kix: u32) -> Result<PublicKey, Error> {
+        let context = SecpContext::new();
+        let chain = context.public_child(&self.xpub, ChildNumber::Normal { index: chain })?;
+        Ok(context
+            .public_child(&chain, ChildNumber::Normal { index: kix })?
+            .public_key)
+    }
+
+    /// BIP32 origin of key kix of a chain
+    pub fn origin(&self, chain: u32, kix: u32) -> KeySource {
+        let (fingerprint, ref path) = self.origin;
+        (
+            fingerprint,
+            path.child(ChildNumber::Normal { index: chain })
+                .child(ChildNumber::Normal { index: kix }),
+        )
+    }
+}
+
+/// A k of n P2WSH multisig wallet set up in a coordinator
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultisigConfig {
+    pub name: String,
+    pub network: Network,
+    /// signatures required to spend
+    pub required: usize,
+    /// keys of scripts are sorted as with sortedmulti, otherwise they are in cosigner order
+    pub sorted: bool,
+    pub cosigners: Vec<CosignerKey>,
+}
+
+impl MultisigConfig {
+    /// read the wallet JSON exported by Sparrow
+    pub fn from_sparrow(json: &str, network: Network) -> Result<MultisigConfig, Error> {
+        let wallet = match parse_json(json)? {
+            Json::Object(wallet) => wallet,
+            _ => return Err(Error::Descriptor("wallet is not an object")),
+        };
+        if string(&wallet, "scriptType") != Some("P2WSH") {
+            return Err(Error::Descriptor(
+                "only P2WSH multisig wallets are supported",
+            ));
+        }
+        let script = match wallet.get("defaultPolicy") {
+            Some(Json::Object(policy)) => match policy.get("miniscript") {
+                Some(Json::Object(miniscript)) => string(miniscript, "script"),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or(Error::Descriptor("wallet without policy"))?;
+        let (required, sorted, labels) = parse_policy(script)?;
+        let keystores = match wallet.get("keystores") {
+            Some(Json::Array(keystores)) => keystores,
+            _ => return Err(Error::Descriptor("wallet without keystores")),
+        };
+        let mut keys = BTreeMap::new();
+        for keystore in keystores {
+            let keystore = match keystore {
+                Json::Object(keystore) => keystore,
+                _ => return Err(Error::Descriptor("keystore is not an object")),
+            };
+            let (fingerprint, path) = match keystore.get("keyDerivation") {
+                Some(Json::Object(derivation)) => (
+                    string(derivation, "masterFingerprint"),
+                    string(derivation, "derivationPath"),
+                ),
+                _ => (None, None),
+            };
+            let origin = match (fingerprint, path) {
+                (Some(fingerprint), Some(path)) => parse_origin(fingerprint, path)?,
+                _ => return Err(Error::Descriptor("keystore without key derivation")),
+            };
+            let xpub = string(keystore, "extendedPublicKey")
+                .ok_or(Error::Descriptor("keystore without extended public key"))?;
+            let label = string(keystore, "label").unwrap_or_default();
+            let cosigner = CosignerKey {
+                label: label.to_string(),
+                origin,
+                xpub: parse_xpub(xpub, network)?,
+            };
+            if keys.insert(label.to_string(), cosigner).is_some() {
+                return Err(Error::Descriptor("keystore labels are not unique"));
+            }
+        }
+        // the policy names keystores by label, in script order
+        let cosigners = labels
+            .iter()
+            .map(|label| {
+                keys.remove(*label)
+                    .ok_or(Error::Descriptor("policy names an unknown keystore"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if !keys.is_empty() {
+            return Err(Error::Descriptor("keystore not used by the policy"));
+        }
+        MultisigConfig::new(
+            string(&wallet, "name").unwrap_or_default(),
+            network,
+            required,
+            sorted,
+            cosigners,
+        )
+    }
+
+    /// read the wallet export of Specter
+    pub fn from_specter(json: &str, network: Network) -> Result<MultisigConfig, Error> {
+        let wallet = match parse_json(json)? {
+            Json::Object(wallet) => wallet,
+            _ => return Err(Error::Descriptor("wallet is not an object")),
+        };
+        let descriptor =
+            string(&wallet, "descriptor").ok_or(Error::Descriptor("wallet without descriptor"))?;
+        let (required, sorted, keys) = parse_policy(strip_checksum(descriptor)?)?;
+        let devices = match wallet.get("devices") {
+            Some(Json::Array(devices)) => devices
+                .iter()
+                .map(|device| match device {
+                    Json::String(label) => Some(label.as_str()),
+                    Json::Object(device) => string(device, "label"),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut cosigners = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let (origin, rest) = match key.strip_prefix('[').and_then(|k| k.split_once(']')) {
+                Some(split) => split,
+                None => return Err(Error::Descriptor("key without origin")),
+            };
+            let (fingerprint, path) = origin.split_once('/').unwrap_or((origin, ""));
+            let origin = parse_origin(fingerprint, &format!("m/{}", path))?;
+            // keys of the receive chain are ranged over the chain of the extended key
+            let xpub = ["/0/*", "/<0;1>/*"]
+                .iter()
+                .find_map(|suffix| rest.strip_suffix(suffix))
+                .unwrap_or(rest);
+            if xpub.contains('/') {
+                return Err(Error::Descriptor("unsupported key derivation"));
+            }
+            cosigners.push(CosignerKey {
+                label: devices
+                    .get(i)
+                    .cloned()
+                    .flatten()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| origin.0.to_string()),
+                origin,
+                xpub: parse_xpub(xpub, network)?,
+            });
+        }
+        MultisigConfig::new(
+            string(&wallet, "label")
+                .or_else(|| string(&wallet, "name"))
+                .unwrap_or_default(),
+            network,
+            required,
+            sorted,
+            cosigners,
+        )
+    }
+
+    fn new(
+        name: &str,
+        network: Network,
+        required: usize,
+        sorted: bool,
+        cosigners: Vec<CosignerKey>,
+    ) -> Result<MultisigConfig, Error> {
+        if required == 0 || required > cosigners.len() || cosigners.len() > 15 {
+            return Err(Error::Descriptor("invalid multisig threshold"));
+        }
+        Ok(MultisigConfig {
+            name: name.to_string(),
+            network,
+            required,
+            sorted,
+            cosigners,
+        })
+    }
+
+    /// the cosigner whose keys derive from the master key with the fingerprint
+    pub fn cosigner(&self, fingerprint: Fingerprint) -> Option<&CosignerKey> {
+        self.cosigners.iter().find(|c| c.origin.0 == fingerprint)
+    }
+
+    /// keys of the cosigners for key kix of a chain, in script order
+    pub fn keys(&self, chain: u32, kix: u32) -> Result<Vec<PublicKey>, Error> {
+        let mut keys = self
+            .cosigners
+            .iter()
+            .map(|c| c.key(chain, kix))
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.sorted {
+            keys.sort_by_key(|k| k.to_bytes());
+        }
+        Ok(keys)
+    }
+
+    /// witness script of key kix of a chain
+    pub fn witness_script(&self, chain: u32, kix: u32) -> Result<Script, Error> {
+        Ok(multisig_script(self.required, &self.keys(chain, kix)?))
+    }
+
+    /// address of key kix of a chain, as the coordinator shows it
+    pub fn address(&self, chain: u32, kix: u32) -> Result<Address, Error> {
+        Ok(Address::p2wsh(
+            &self.witness_script(chain, kix)?,
+            self.network,
+        ))
+    }
+
+    /// a watch-only P2WSH account of a chain with its first count keys
+    /// Like other P2WSH accounts it does not look ahead, add_key adds further keys.
+    pub fn account(&self, account_number: u32, chain: u32, count: u32) -> Result<Account, Error> {
+        let master_public = SecpContext::new().public_child(
+            &self.cosigners[0].xpub,
+            ChildNumber::Normal { index: chain },
+        )?;
+        let mut account = Account::new_from_storage(
+            AccountAddressType::P2WSH(MULTISIG_PURPOSE),
+            account_number,
+            chain,
+            master_public,
+            Vec::new(),
+            0,
+            0,
+            self.network,
+        );
+        let mut metadata = account.metadata().clone();
+        metadata.name = self.name.clone();
+        account = account.with_metadata(metadata);
+        for _ in 0..count {
+            self.add_key(&mut account)?;
+        }
+        Ok(account)
+    }
+
+    /// add the next key to an account of this configuration, returns its kix
+    pub fn add_key(&self, account: &mut Account) -> Result<u32, Error> {
+        let chain = account.sub_account_number();
+        let kix = account.instantiated().len() as u32;
+        if account.address_type() != AccountAddressType::P2WSH(MULTISIG_PURPOSE)
+            || account.compute_base_public_key(kix)? != self.cosigners[0].key(chain, kix)?
+        {
+            return Err(Error::Unsupported("account is not of this configuration"));
+        }
+        let script = self.witness_script(chain, kix)?;
+        account.add_script_key(|_, _| script, None, None)
+    }
+}
+
+fn string<'a>(object: &'a BTreeMap<String, Json>, key: &str) -> Option<&'a str> {
+    match object.get(key) {
+        Some(Json::String(s)) => Some(s.as_str()),
+        _ => None,
+    }
+}
+
+/// threshold, sorting and keys of wsh(multi(..)) or wsh(sortedmulti(..))
+fn parse_policy(policy: &str) -> Result<(usize, bool, Vec<&str>), Error> {
+    let inner = policy
+        .strip_prefix("wsh(")
+        .and_then(|p| p.strip_suffix(')'))
+        .ok_or(Error::Descriptor(
+            "only P2WSH multisig wallets are supported",
+        ))?;
+    let (sorted, args) = if let Some(args) = inner.strip_prefix("sortedmulti(") {
+        (true, args)
+    } else if let Some(args) = inner.strip_prefix("multi(") {
+        (false, args)
+    } else {
+        return Err(Error::Descriptor("policy is not multisig"));
+    };
+    let mut args = args
+        .strip_suffix(')')
+        .ok_or(Error::Descriptor("malformed policy"))?
+        .split(',')
+        .map(str::trim);
+    let required = args
+        .next()
+        .and_then(|k| k.parse().ok())
+        .ok_or(Error::Descriptor("invalid multisig threshold"))?;
+    Ok((required, sorted, args.collect()))
+}
+
+fn parse_origin(fingerprint: &str, path: &str) -> Result<KeySource, Error> {
+    Ok((
+        Fingerprint::from_str(fingerprint)
+            .map_err(|_| Error::Descriptor("invalid master fingerprint"))?,
+        DerivationPath::from_str(path).map_err(|_| Error::Descriptor("invalid derivation path"))?,
+    ))
+}
+
+/// an extended public key in xpub, tpub or SLIP-132 encoding
+fn parse_xpub(xpub: &str, network: Network) -> Result<ExtendedPubKey, Error> {
+    let mut data =
+        base58::from_check(xpub).map_err(|_| Error::Descriptor("invalid extended public key"))?;
+    if data.len() != 78 {
+        return Err(Error::Descriptor("invalid extended public key"));
+    }
+    let version = if MAINNET_VERSIONS.iter().any(|v| data[..4] == v[..]) {
+        XPUB
+    } else if TESTNET_VERSIONS.iter().any(|v| data[..4] == v[..]) {
+        TPUB
+    } else {
+        return Err(Error::Descriptor("unknown extended public key version"));
+    };
+    if (version == XPUB) != (network == Network::Bitcoin) {
+        return Err(Error::Network);
+    }
+    data[..4].copy_from_slice(&version);
+    let mut xpub = ExtendedPubKey::decode(data.as_slice())
+        .map_err(|_| Error::Descriptor("invalid extended public key"))?;
+    xpub.network = network;
+    Ok(xpub)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::util::bip32::ExtendedPrivKey;
+
+    use descriptor::descriptor_checksum;
+
+    use super::*;
+
+    #[test]
+    fn sparrow_and_specter() {
+        let context = SecpContext::new();
+        let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+        let cosigners = ["Alice", "Bob", "Carol"]
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                let master = ExtendedPrivKey::new_master(Network::Testnet, &[i as u8; 32]).unwrap();
+                let mut key = master;
+                for child in path.as_ref() {
+                    key = context.private_child(&key, *child).unwrap();
+                }
+                CosignerKey {
+                    label: label.to_string(),
+                    origin: (
+                        context.extended_public_from_private(&master).fingerprint(),
+                        path.clone(),
+                    ),
+                    xpub: context.extended_public_from_private(&key),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Sparrow may show keys in SLIP-132 encoding
+        let mut vpub = cosigners[1].xpub.encode();
+        vpub[..4].copy_from_slice(&TESTNET_VERSIONS[2]);
+        let sparrow_xpubs = [
+            cosigners[0].xpub.to_string(),
+            base58::check_encode_slice(&vpub),
+            cosigners[2].xpub.to_string(),
+        ];
+        let keystores = cosigners
+            .iter()
+            .zip(sparrow_xpubs.iter())
+            .rev()
+            .map(|(c, xpub)| {
+                format!(
+                    r#"{{"label": "{}", "source": "HW_USB", "keyDerivation": {{"masterFingerprint": "{}", "derivationPath": "m/48'/1'/0'/2'"}}, "extendedPublicKey": "{}"}}"#,
+                    c.label, c.origin.0, xpub
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sparrow = |script_type: &str| {
+            format!(
+                r#"{{"name": "Family", "network": "TESTNET", "policyType": "MULTI", "scriptType": "{}", "defaultPolicy": {{"name": "Default", "miniscript": {{"script": "wsh(sortedmulti(2,Alice,Bob,Carol))"}}}}, "keystores": [{}]}}"#,
+                script_type, keystores
+            )
+        };
+        let from_sparrow =
+            MultisigConfig::from_sparrow(&sparrow("P2WSH"), Network::Testnet).unwrap();
+        assert_eq!(from_sparrow.cosigners, cosigners);
+        assert!(MultisigConfig::from_sparrow(&sparrow("P2SH_P2WSH"), Network::Testnet).is_err());
+        assert!(MultisigConfig::from_sparrow(&sparrow("P2WSH"), Network::Bitcoin).is_err());
+
+        let keys = cosigners
+            .iter()
+            .map(|c| format!("[{}/48h/1h/0h/2h]{}/0/*", c.origin.0, c.xpub))
+            .collect::<Vec<_>>()
+            .join(",");
+        let descriptor = format!("wsh(sortedmulti(2,{}))", keys);
+        let specter = format!(
+            r#"{{"label": "Family", "blockheight": 0, "descriptor": "{}#{}", "devices": [{{"type": "coldcard", "label": "Alice"}}, {{"type": "trezor", "label": "Bob"}}, {{"type": "ledger", "label": "Carol"}}]}}"#,
+            descriptor,
+            descriptor_checksum(&descriptor).unwrap()
+        );
+        let from_specter = MultisigConfig::from_specter(&specter, Network::Testnet).unwrap();
+        assert_eq!(from_sparrow, from_specter);
+        assert!(
+            MultisigConfig::from_specter(&specter.replace("#", "#x"), Network::Testnet).is_err()
+        );
+
+        let config = from_specter;
+        assert_eq!(config.required, 2);
+        assert!(config.sorted);
+        assert_eq!(config.cosigner(cosigners[1].origin.0).unwrap().label, "Bob");
+        let mut keys = cosigners
+            .iter()
+            .map(|c| c.key(0, 3).unwrap())
+            .collect::<Vec<_>>();
+        keys.sort_by_key(|k| k.to_bytes());
+        assert_eq!(
+            config.address(0, 3).unwrap(),
+            Address::p2wsh(&multisig_script(2, &keys), Network::Testnet)
+        );
+        assert_eq!(
+            cosigners[0].origin(0, 3).1,
+            DerivationPath::from_str("m/48'/1'/0'/2'/0/3").unwrap()
+        );
+
+        let mut account = config.account(1, 0, 3).unwrap();
+        assert_eq!(config.add_key(&mut account).unwrap(), 3);
+        for kix in 0..4 {
+            assert_eq!(
+                account.get_key(kix).unwrap().address,
+                config.address(0, kix).unwrap()
+            );
+        }
+        let mut change = config.account(1, 1, 0).unwrap();
+        config.add_key(&mut change).unwrap();
+        assert_eq!(
+            change.get_key(0).unwrap().address,
+            config.address(1, 0).unwrap()
+        );
+        let mut other = config.clone();
+        other.cosigners.swap(0, 1);
+        assert!(other.add_key(&mut account).is_err());
+    }
+}
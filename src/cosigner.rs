@@ -0,0 +1,360 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Second factor cosigning
+//!
+//! A 2 of 2 P2WSH account whose second key is held by a remote service reached over HTTPS.
+//! The service signs only spends that satisfy its policy, e.g. after the user confirmed them
+//! with a one-time code, so it acts as a second factor of spending.
+//!
+//! Key kix of the account pairs an own key with the key kix of the extended public key of the
+//! service. The wallet prepares and signs the PSBT, submits it with the context the service
+//! applies its policy to and merges the returned signature into the final transaction.
+//!
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey, KeySource};
+use bitcoin::{PublicKey, Script, Transaction};
+
+use account::{Account, MasterAccount, Unlocker};
+use coins::Coins;
+use context::SecpContext;
+use error::Error;
+use inspect::decode_psbt;
+use multisig::{multisig_script, MultisigSession};
+use psbt::{multisig, update_input, Psbt};
+
+/// A transport of cosigning requests, usually HTTPS
+pub trait CosignerTransport {
+    /// post the request to the endpoint and return the PSBT signed by the service
+    fn post(&self, endpoint: &str, request: &CosignRequest) -> Result<Psbt, Error>;
+}
+
+/// What the service applies its policy to
+#[derive(Clone, Debug, Default)]
+pub struct PolicyContext {
+    /// outputs paid to others
+    pub recipients: Vec<(Script, u64)>,
+    /// None if a spent output is not known
+    pub fee: Option<u64>,
+    /// one-time code confirming the spend
+    pub otp: Option<String>,
+}
+
+impl PolicyContext {
+    /// context of a PSBT spending own coins
+    pub fn new(master: &MasterAccount, coins: &Coins, psbt: &Psbt) -> PolicyContext {
+        let view = decode_psbt(master, coins, psbt);
+        PolicyContext {
+            recipients: view
+                .outputs
+                .iter()
+                .zip(psbt.global.unsigned_tx.output.iter())
+                .filter(|(v, _)| !v.is_own())
+                .map(|(_, o)| (o.script_pubkey.clone(), o.value))
+                .collect(),
+            fee: view.fee,
+            otp: None,
+        }
+    }
+
+    /// confirm the spend with a one-time code
+    pub fn otp(mut self, code: &str) -> PolicyContext {
+        self.otp = Some(code.to_string());
+        self
+    }
+
+    /// total paid to others
+    pub fn amount(&self) -> u64 {
+        self.recipients.iter().map(|(_, v)| *v).sum()
+    }
+}
+
+/// A request to cosign
+#[derive(Clone, Debug)]
+pub struct CosignRequest {
+    /// signed with the own keys, the keys of the service have their origin
+    pub psbt: Psbt,
+    pub context: PolicyContext,
+}
+
+/// A remote cosigning service
+pub struct Cosigner<T: CosignerTransport> {
+    endpoint: String,
+    xpub: ExtendedPubKey,
+    origin: KeySource,
+    transport: T,
+}
+
+impl<T: CosignerTransport> Cosigner<T> {
+    /// a service at an https or Tor hidden service endpoint signing with keys of the extended
+    /// public key, origin is the BIP32 origin of the extended public key
+    pub fn new(
+        endpoint: &str,
+        xpub: ExtendedPubKey,
+        origin: KeySource,
+        transport: T,
+    ) -> Result<Cosigner<T>, Error> {
+        let host = endpoint
+            .split("://")
+            .nth(1)
+            .and_then(|rest| rest.split(&['/', ':'][..]).next())
+            .unwrap_or("");
+        if !endpoint.starts_with("https://") && !host.ends_with(".onion") {
+            return Err(Error::Cosigner("endpoint must be https or onion"));
+        }
+        Ok(Cosigner {
+            endpoint: endpoint.to_string(),
+            xpub,
+            origin,
+            transport,
+        })
+    }
+
+    /// key kix of the service
+    pub fn key(&self, kix: u32) -> Result<PublicKey, Error> {
+        Ok(SecpContext::new()
+            .public_child(&self.xpub, ChildNumber::Normal { index: kix })?
+            .public_key)
+    }
+
+    /// BIP32 origin of key kix of the service
+    pub fn origin(&self, kix: u32) -> KeySource {
+        let (fingerprint, ref path) = self.origin;
+        (fingerprint, path.child(ChildNumber::Normal { index: kix }))
+    }
+
+    /// add the next 2 of 2 key to a P2WSH account, returns its kix
+    pub fn add_key(&self, account: &mut Account) -> Result<u32, Error> {
+        let theirs = self.key(account.instantiated().len() as u32)?;
+        account.add_script_key(|own, _| multisig_script(2, &[*own, theirs]), None, None)
+    }
+
+    /// update the inputs of a PSBT with what signers need, including the origin of the keys of
+    /// the service. Fails if an input is not a coin of a 2 of 2 account of this service.
+    pub fn prepare(
+        &self,
+        master: &MasterAccount,
+        coins: &Coins,
+        psbt: &mut Psbt,
+    ) -> Result<(), Error> {
+        for ix in 0..psbt.inputs.len() {
+            update_input(psbt, ix, coins, master)?;
+            let point = psbt.global.unsigned_tx.input[ix].previous_output;
+            let kix = coins
+                .confirmed()
+                .get(&point)
+                .or_else(|| coins.unconfirmed().get(&point))
+                .map(|c| c.derivation.kix)
+                .expect("checked in update_input");
+            let theirs = self.key(kix)?;
+            let input = &mut psbt.inputs[ix];
+            match input.witness_script.as_ref().and_then(multisig) {
+                Some((2, ref keys)) if keys.len() == 2 && keys.contains(&theirs) => {}
+                _ => return Err(Error::Cosigner("input is not cosigned by the service")),
+            }
+            input.bip32_derivation.insert(theirs, self.origin(kix));
+        }
+        Ok(())
+    }
+
+    /// sign with the own keys, have the service cosign and return the final transaction
+    pub fn cosign(
+        &self,
+        master: &MasterAccount,
+        unlocker: &mut Unlocker,
+        coins: &Coins,
+        mut psbt: Psbt,
+        context: PolicyContext,
+    ) -> Result<Transaction, Error> {
+        self.prepare(master, coins, &mut psbt)?;
+        master.sign_psbt(&mut psbt, unlocker)?;
+        let mut session = MultisigSession::new(psbt)?;
+        if (0..session.psbt().inputs.len()).any(|ix| session.missing(ix) != 1) {
+            return Err(Error::Cosigner("own signature missing"));
+        }
+        let request = CosignRequest {
+            psbt: session.psbt().clone(),
+            context,
+        };
+        let signed = self.transport.post(&self.endpoint, &request)?;
+        session.add(&signed)?;
+        if !session.is_ready() {
+            return Err(Error::Cosigner("service did not sign"));
+        }
+        session.finalize()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::util::bip32::DerivationPath;
+    use bitcoin::{Address, Network, OutPoint, TxIn, TxOut};
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    use account::AccountAddressType;
+    use fixtures::{block, funding, master_account};
+    use signer::{sign, MasterSigner};
+
+    use super::*;
+
+    struct Service {
+        master: MasterAccount,
+        unlocker: RefCell<Unlocker>,
+    }
+
+    impl CosignerTransport for Service {
+        fn post(&self, endpoint: &str, request: &CosignRequest) -> Result<Psbt, Error> {
+            assert_eq!(endpoint, "https://example.com/cosign");
+            if request.context.otp.as_deref() != Some("123456")
+                || request.context.amount() > 100_000
+            {
+                return Err(Error::Cosigner("refused"));
+            }
+            let mut psbt = request.psbt.clone();
+            let mut unlocker = self.unlocker.borrow_mut();
+            sign(
+                &mut psbt,
+                &mut MasterSigner::new(&self.master, &mut unlocker),
+            )?;
+            Ok(psbt)
+        }
+    }
+
+    struct Offline;
+
+    impl CosignerTransport for Offline {
+        fn post(&self, _: &str, _: &CosignRequest) -> Result<Psbt, Error> {
+            Err(Error::Cosigner("offline"))
+        }
+    }
+
+    /// a wallet with a coin of 200_000 sat of a 2 of 2 account with the service
+    struct Wallet {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        funding: Transaction,
+        cosigner: Cosigner<Service>,
+    }
+
+    impl Wallet {
+        fn new() -> Wallet {
+            let (mut master, mut unlocker) = master_account(Network::Testnet);
+            let (service, service_unlocker) = master_account(Network::Testnet);
+            let xpub = *service.get((0, 0)).unwrap().master_public();
+            let origin = (
+                service.master_public().fingerprint(),
+                DerivationPath::from_str("m/84'/1'/0'/0").unwrap(),
+            );
+            let service = Service {
+                master: service,
+                unlocker: RefCell::new(service_unlocker),
+            };
+            let cosigner =
+                Cosigner::new("https://example.com/cosign", xpub, origin, service).unwrap();
+
+            let mut account =
+                Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 1, 0, 0).unwrap();
+            assert_eq!(cosigner.add_key(&mut account).unwrap(), 0);
+            assert_eq!(cosigner.add_key(&mut account).unwrap(), 1);
+            let funded = account.get_key(1).unwrap().address.script_pubkey();
+            master.add_account(account);
+            let funding = funding(vec![TxOut {
+                value: 200_000,
+                script_pubkey: funded,
+            }]);
+            let mut coins = Coins::new();
+            coins.process(&mut master, &block(Network::Testnet, vec![funding.clone()]));
+            Wallet {
+                master,
+                unlocker,
+                coins,
+                funding,
+                cosigner,
+            }
+        }
+
+        /// a PSBT spending the coin to the payee
+        fn spend(&self, value: u64) -> Psbt {
+            Psbt::from_unsigned_tx(Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint {
+                        txid: self.funding.txid(),
+                        vout: 0,
+                    },
+                    sequence: 0xffff_fffd,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                }],
+                output: vec![TxOut {
+                    value,
+                    script_pubkey: payee(),
+                }],
+            })
+            .unwrap()
+        }
+
+        fn cosign(&mut self, psbt: Psbt, otp: Option<&str>) -> Result<Transaction, Error> {
+            let mut context = PolicyContext::new(&self.master, &self.coins, &psbt);
+            if let Some(otp) = otp {
+                context = context.otp(otp);
+            }
+            self.cosigner
+                .cosign(&self.master, &mut self.unlocker, &self.coins, psbt, context)
+        }
+    }
+
+    fn payee() -> Script {
+        Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+            .unwrap()
+            .script_pubkey()
+    }
+
+    #[test]
+    fn policy_context() {
+        let wallet = Wallet::new();
+        let psbt = wallet.spend(99_000);
+        let context = PolicyContext::new(&wallet.master, &wallet.coins, &psbt);
+        assert_eq!(context.recipients, vec![(payee(), 99_000)]);
+        assert_eq!(context.fee, Some(101_000));
+    }
+
+    #[test]
+    fn refused() {
+        let mut wallet = Wallet::new();
+        let (xpub, origin) = (wallet.cosigner.xpub, wallet.cosigner.origin.clone());
+        assert!(Cosigner::new("http://example.com/cosign", xpub, origin, Offline).is_err());
+        // no second factor
+        let psbt = wallet.spend(99_000);
+        assert!(wallet.cosign(psbt, None).is_err());
+        // above the limit of the service
+        let large = wallet.spend(150_000);
+        assert!(wallet.cosign(large, Some("123456")).is_err());
+    }
+
+    #[test]
+    fn second_factor() {
+        let mut wallet = Wallet::new();
+        let psbt = wallet.spend(99_000);
+        let transaction = wallet.cosign(psbt, Some("123456")).unwrap();
+        assert_eq!(transaction.input[0].witness.len(), 4);
+        transaction
+            .verify(|_| Some(wallet.funding.output[0].clone()))
+            .unwrap();
+    }
+}
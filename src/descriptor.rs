@@ -0,0 +1,433 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Bitcoin Core descriptor import
+//!
+//! Accounts equivalent to the descriptors of a Bitcoin Core descriptor wallet, read from the
+//! output of `listdescriptors true` or a file it was saved to. Core lists the master private
+//! key of the wallet with the derivation path of each descriptor:
+//!
+//! ```text
+//! wpkh(tprv8ZgxMBicQKsPd.../84h/1h/0h/0/*)#checksum
+//! ```
+//!
+//! The master key takes the place of the seed of the master account, so the imported accounts
+//! derive the same addresses and keys as Core did. Descriptors of other shapes, e.g. taproot or
+//! multisig, have no equivalent account and are reported as skipped.
+//!
+use std::str::FromStr;
+
+use bitcoin::util::bip32::ExtendedPrivKey;
+use bitcoin::Network;
+
+use account::{Account, AccountAddressType, MasterAccount, Unlocker};
+use error::Error;
+use json::{parse_json, Json};
+
+/// A descriptor as listed by Bitcoin Core
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CoreDescriptor {
+    /// the descriptor with its checksum
+    pub desc: String,
+    /// seconds since epoch of the earliest use
+    pub timestamp: u64,
+    /// Core derives new addresses from active descriptors
+    pub active: bool,
+    /// change descriptor
+    pub internal: bool,
+    /// range of derived keys, inclusive
+    pub range: Option<(u32, u32)>,
+    /// next index Core would use
+    pub next: Option<u32>,
+}
+
+/// accounts imported from Core and the descriptors without equivalent account
+pub struct CoreImport {
+    pub master: MasterAccount,
+    pub skipped: Vec<String>,
+}
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn polymod(symbols: &[u64]) -> u64 {
+    const GENERATOR: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+    let mut chk = 1u64;
+    for value in symbols {
+        let top = chk >> 35;
+        chk = ((chk & 0x7ffffffff) << 5) ^ value;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+/// the checksum of a descriptor as defined in BIP380, None for characters descriptors do not use
+pub fn descriptor_checksum(desc: &str) -> Option<String> {
+    let mut symbols = Vec::new();
+    let mut groups = Vec::new();
+    for c in desc.chars() {
+        let value = INPUT_CHARSET.find(c)? as u64;
+        symbols.push(value & 31);
+        groups.push(value >> 5);
+        if groups.len() == 3 {
+            symbols.push(groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups.len() {
+        1 => symbols.push(groups[0]),
+        2 => symbols.push(groups[0] * 3 + groups[1]),
+        _ => {}
+    }
+    symbols.extend_from_slice(&[0; 8]);
+    let checksum = polymod(&symbols) ^ 1;
+    Some(
+        (0..8)
+            .map(|i| CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+/// a descriptor without its checksum, the checksum is verified if present
+pub(crate) fn strip_checksum(desc: &str) -> Result<&str, Error> {
+    match desc.rfind('#') {
+        Some(pos) => {
+            let (body, checksum) = (&desc[..pos], &desc[pos + 1..]);
+            if descriptor_checksum(body).as_deref() != Some(checksum) {
+                return Err(Error::Descriptor("checksum mismatch"));
+            }
+            Ok(body)
+        }
+        None => Ok(desc),
+    }
+}
+
+/// the descriptors of `listdescriptors` output
+pub fn parse_listdescriptors(json: &str) -> Result<Vec<CoreDescriptor>, Error> {
+    let descriptors = match parse_json(json)? {
+        Json::Object(mut object) => object.remove("descriptors"),
+        _ => None,
+    };
+    let descriptors = match descriptors {
+        Some(Json::Array(descriptors)) => descriptors,
+        _ => return Err(Error::Descriptor("no descriptors listed")),
+    };
+    let index = |value: &Json| match value {
+        Json::Number(n) if *n >= 0.0 && *n <= u32::MAX as f64 => Ok(*n as u32),
+        _ => Err(Error::Descriptor("invalid index")),
+    };
+    let mut result = Vec::new();
+    for descriptor in descriptors {
+        let mut fields = match descriptor {
+            Json::Object(fields) => fields,
+            _ => return Err(Error::Descriptor("descriptor is not an object")),
+        };
+        let desc = match fields.remove("desc") {
+            Some(Json::String(desc)) => desc,
+            _ => return Err(Error::Descriptor("descriptor without desc")),
+        };
+        let timestamp = match fields.get("timestamp") {
+            Some(Json::Number(n)) if *n >= 0.0 => *n as u64,
+            // "now" of an import, nothing to scan before
+            _ => 0,
+        };
+        let flag = |name: &str| fields.get(name) == Some(&Json::Bool(true));
+        let range = match fields.get("range") {
+            Some(Json::Array(range)) if range.len() == 2 => {
+                Some((index(&range[0])?, index(&range[1])?))
+            }
+            Some(Json::Number(end)) => Some((0, index(&Json::Number(*end))?)),
+            _ => None,
+        };
+        let next = match fields.get("next") {
+            Some(next) => Some(index(next)?),
+            None => None,
+        };
+        result.push(CoreDescriptor {
+            desc,
+            timestamp,
+            active: flag("active"),
+            internal: flag("internal"),
+            range,
+            next,
+        });
+    }
+    Ok(result)
+}
+
+/// address type, master key and path after it of a single key ranged descriptor
+fn parse_descriptor(desc: &str) -> Result<(AccountAddressType, &str, Vec<&str>), Error> {
+    let desc = strip_checksum(desc)?;
+    let (address_type, key) = if let Some(inner) = desc.strip_prefix("sh(wpkh(") {
+        (AccountAddressType::P2SHWPKH, inner.strip_suffix("))"))
+    } else if let Some(inner) = desc.strip_prefix("wpkh(") {
+        (AccountAddressType::P2WPKH, inner.strip_suffix(')'))
+    } else if let Some(inner) = desc.strip_prefix("pkh(") {
+        (AccountAddressType::P2PKH, inner.strip_suffix(')'))
+    } else {
+        return Err(Error::Descriptor("no equivalent account type"));
+    };
+    let key = key.ok_or(Error::Descriptor("malformed descriptor"))?;
+    if key.starts_with('[') {
+        return Err(Error::Descriptor("descriptor without master private key"));
+    }
+    let mut parts = key.split('/');
+    let master = parts.next().unwrap_or_default();
+    Ok((address_type, master, parts.collect()))
+}
+
+fn hardened(step: &str) -> Option<u32> {
+    step.strip_suffix('h')
+        .or_else(|| step.strip_suffix('\''))
+        .and_then(|n| n.parse().ok())
+}
+
+/// Imports descriptors of Bitcoin Core
+pub struct CoreImporter {
+    network: Network,
+    look_ahead: u32,
+}
+
+impl CoreImporter {
+    /// Core writes the same keys for all test networks, the network tells them apart
+    pub fn new(network: Network) -> CoreImporter {
+        CoreImporter {
+            network,
+            look_ahead: 20,
+        }
+    }
+
+    /// addresses watched beyond the last used, at least the range Core derived is watched
+    pub fn look_ahead(mut self, look_ahead: u32) -> CoreImporter {
+        self.look_ahead = look_ahead;
+        self
+    }
+
+    /// import the output of `listdescriptors true`
+    /// The master key is stored encrypted with passphrase.
+    pub fn import(&self, json: &str, passphrase: &str) -> Result<CoreImport, Error> {
+        self.import_descriptors(&parse_listdescriptors(json)?, passphrase)
+    }
+
+    /// import listed descriptors
+    /// All accounts derive from the master key of the first descriptor with an equivalent
+    /// account, descriptors of other master keys are skipped.
+    pub fn import_descriptors(
+        &self,
+        descriptors: &[CoreDescriptor],
+        passphrase: &str,
+    ) -> Result<CoreImport, Error> {
+        let mut skipped = Vec::new();
+        let mut accepted = Vec::new();
+        for descriptor in descriptors {
+            match parse_descriptor(&descriptor.desc) {
+                Ok((address_type, key, path)) => {
+                    let mut key = ExtendedPrivKey::from_str(key)
+                        .map_err(|_| Error::Descriptor("invalid master private key"))?;
+                    if (key.network == Network::Bitcoin) != (self.network == Network::Bitcoin) {
+                        return Err(Error::Network);
+                    }
+                    key.network = self.network;
+                    accepted.push((descriptor, address_type, key, path));
+                }
+                Err(Error::Descriptor(_)) if strip_checksum(&descriptor.desc).is_ok() => {
+                    skipped.push(descriptor.desc.clone())
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let master_key = match accepted.first() {
+            Some((_, _, key, _)) => *key,
+            None => return Err(Error::Descriptor("no descriptor with equivalent account")),
+        };
+        let birth = accepted
+            .iter()
+            .map(|(d, _, _, _)| d.timestamp)
+            .min()
+            .unwrap_or_default();
+        let mut master = MasterAccount::from_master_key(&master_key, birth, passphrase)?;
+        let mut unlocker = Unlocker::new_for_master(&master, passphrase)?;
+        let coin_type = match master_key.network {
+            Network::Bitcoin => 0,
+            _ => 1,
+        };
+        for (descriptor, address_type, key, path) in accepted {
+            let account = match path.as_slice() {
+                [purpose, coin, account, sub, "*"]
+                    if key == master_key
+                        && hardened(purpose) == Some(address_type.as_u32())
+                        && hardened(coin) == Some(coin_type) =>
+                {
+                    match (hardened(account), sub.parse::<u32>().ok()) {
+                        (Some(account), Some(sub)) => Some((account, sub)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            let (account_number, sub_account_number) = match account {
+                Some(id) if master.get(id).is_none() => id,
+                _ => {
+                    skipped.push(descriptor.desc.clone());
+                    continue;
+                }
+            };
+            let next = descriptor.next.unwrap_or_default();
+            let end = descriptor.range.map(|(_, end)| end + 1).unwrap_or_default();
+            let mut account = Account::new(
+                &mut unlocker,
+                address_type,
+                account_number,
+                sub_account_number,
+                self.look_ahead
+                    .max(end.saturating_sub(next.saturating_sub(1))),
+            )?;
+            if next > 0 {
+                account.do_look_ahead(Some(next - 1))?;
+            }
+            let mut metadata = account.metadata().clone();
+            metadata.created = descriptor.timestamp;
+            metadata.archived = !descriptor.active;
+            master.add_account(account.with_metadata(metadata));
+        }
+        Ok(CoreImport { master, skipped })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use account::{MasterKeyEntropy, Unlocker};
+    use fixtures::PASSPHRASE;
+
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        // BIP380 test vector
+        assert_eq!(
+            descriptor_checksum("raw(deadbeef)"),
+            Some("89f8spxm".to_string())
+        );
+        assert!(strip_checksum("raw(deadbeef)#89f8spxm").is_ok());
+        assert!(strip_checksum("raw(deedbeef)#89f8spxm").is_err());
+    }
+
+    #[test]
+    fn import() {
+        let core =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Regtest, PASSPHRASE).unwrap();
+        let mut core_unlocker = Unlocker::new_for_master(&core, PASSPHRASE).unwrap();
+        let xprv = core_unlocker.master_private().to_string();
+        let with_checksum = |desc: String| {
+            let checksum = descriptor_checksum(&desc).unwrap();
+            format!("{}#{}", desc, checksum)
+        };
+        let json = format!(
+            r#"{{
+  "wallet_name": "core",
+  "descriptors": [
+    {{
+      "desc": "{}",
+      "timestamp": 1600000000,
+      "active": true,
+      "internal": false,
+      "range": [0, 999],
+      "next": 12
+    }},
+    {{
+      "desc": "{}",
+      "timestamp": 1600000000,
+      "active": true,
+      "internal": true,
+      "range": [0, 999],
+      "next": 3
+    }},
+    {{
+      "desc": "{}",
+      "timestamp": 1500000000,
+      "active": false,
+      "internal": false,
+      "range": [0, 999],
+      "next": 0
+    }},
+    {{
+      "desc": "{}",
+      "timestamp": 1600000000,
+      "active": true,
+      "internal": false,
+      "range": [0, 999],
+      "next": 0
+    }}
+  ]
+}}"#,
+            with_checksum(format!("wpkh({}/84h/1h/0h/0/*)", xprv)),
+            with_checksum(format!("wpkh({}/84h/1h/0h/1/*)", xprv)),
+            with_checksum(format!("sh(wpkh({}/49'/1'/2'/0/*))", xprv)),
+            with_checksum(format!("tr({}/86h/1h/0h/0/*)", xprv)),
+        );
+
+        let import = CoreImporter::new(Network::Regtest)
+            .import(&json, "new passphrase")
+            .unwrap();
+        assert_eq!(import.skipped.len(), 1);
+        assert!(import.skipped[0].starts_with("tr("));
+        let master = import.master;
+        assert_eq!(master.birth(), 1500000000);
+        assert_eq!(master.master_public(), core.master_public());
+        assert!(Unlocker::new_for_master(&master, "new passphrase").is_ok());
+
+        let receive = master.get((0, 0)).unwrap();
+        assert_eq!(receive.next(), 12);
+        assert_eq!(receive.instantiated().len(), 1000);
+        assert_eq!(master.get((0, 1)).unwrap().next(), 3);
+        let nested = master.get((2, 0)).unwrap();
+        assert!(nested.is_archived());
+        assert_eq!(nested.metadata().created, 1500000000);
+
+        // the same addresses as derived from the original master key
+        let expected =
+            Account::new(&mut core_unlocker, AccountAddressType::P2WPKH, 0, 0, 13).unwrap();
+        assert_eq!(
+            receive.get_key(12).unwrap().address,
+            expected.get_key(12).unwrap().address
+        );
+        let expected =
+            Account::new(&mut core_unlocker, AccountAddressType::P2SHWPKH, 2, 0, 1).unwrap();
+        assert_eq!(
+            nested.get_key(0).unwrap().address,
+            expected.get_key(0).unwrap().address
+        );
+
+        let tampered = json.replacen("84h/1h/0h/0/*", "84h/1h/0h/5/*", 1);
+        assert!(CoreImporter::new(Network::Regtest)
+            .import(&tampered, PASSPHRASE)
+            .is_err());
+        assert!(CoreImporter::new(Network::Regtest)
+            .import(r#"{"descriptors": []}"#, PASSPHRASE)
+            .is_err());
+    }
+}
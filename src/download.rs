@@ -0,0 +1,385 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Parallel block download
+//!
+//! A restore fetches many blocks, one peer at a time the latency of each request adds up.
+//! BlockDownloader asks several peers at once, each on a thread of its own, and delivers the
+//! blocks in the order requested, so they are processed in order of height.
+//!
+//! Requests run ahead of delivery by at most a window of blocks, which bounds the memory
+//! held by blocks waiting for an earlier one. A block is checked against the hash it was
+//! requested by and its merkle root. A peer that fails, serves an invalid block or does not
+//! answer within the timeout is dropped and its request given to another peer. A dropped peer
+//! blocked in a request still holds up the end of the download until its own timeout, e.g.
+//! of its socket, returns.
+//!
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::{Block, BlockHash};
+
+use backend::ChainBackend;
+use error::Error;
+
+/// Outcome of a download
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Download {
+    /// blocks delivered
+    pub delivered: usize,
+    /// blocks served by each peer
+    pub served: Vec<usize>,
+    /// peers dropped as they failed, served an invalid block or timed out
+    pub dropped: Vec<usize>,
+}
+
+/// Fetches blocks from several peers at once
+#[derive(Clone, Copy, Debug)]
+pub struct BlockDownloader {
+    window: usize,
+    timeout: Duration,
+}
+
+impl Default for BlockDownloader {
+    fn default() -> BlockDownloader {
+        BlockDownloader::new()
+    }
+}
+
+/// requests not yet given to a peer and the peers still asked
+struct Schedule {
+    queue: VecDeque<usize>,
+    /// first block not yet delivered
+    delivered: usize,
+    dropped: Vec<bool>,
+    done: bool,
+}
+
+enum Message {
+    Started(usize, usize),
+    Fetched(usize, usize, Result<Block, Error>),
+}
+
+impl BlockDownloader {
+    /// a window of 16 blocks, peers are dropped after 30 seconds without a block
+    pub fn new() -> BlockDownloader {
+        BlockDownloader {
+            window: 16,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// how many blocks requests may run ahead of delivery, at least 1
+    pub fn window(mut self, window: usize) -> BlockDownloader {
+        self.window = window.max(1);
+        self
+    }
+
+    /// time a peer has to serve a block
+    pub fn timeout(mut self, timeout: Duration) -> BlockDownloader {
+        self.timeout = timeout;
+        self
+    }
+
+    /// fetch the blocks with the hashes from the peers and deliver them in order with their
+    /// position in hashes, until deliver returns false
+    pub fn fetch<P, F>(
+        &self,
+        peers: &mut [P],
+        hashes: &[BlockHash],
+        mut deliver: F,
+    ) -> Result<Download, Error>
+    where
+        P: ChainBackend + Send,
+        F: FnMut(usize, Block) -> Result<bool, Error>,
+    {
+        let mut download = Download {
+            delivered: 0,
+            served: vec![0; peers.len()],
+            dropped: Vec::new(),
+        };
+        if hashes.is_empty() {
+            return Ok(download);
+        }
+        if peers.is_empty() {
+            return Err(Error::Peer("no peer to download from"));
+        }
+        let schedule = Mutex::new(Schedule {
+            queue: (0..hashes.len()).collect(),
+            delivered: 0,
+            dropped: vec![false; peers.len()],
+            done: false,
+        });
+        let changed = Condvar::new();
+        let (sender, receiver) = channel();
+        let window = self.window;
+        thread::scope(|scope| {
+            for (id, peer) in peers.iter_mut().enumerate() {
+                let sender = sender.clone();
+                let (schedule, changed) = (&schedule, &changed);
+                scope.spawn(move || work(id, peer, hashes, window, schedule, changed, sender));
+            }
+            drop(sender);
+            let finish = |result: Result<Download, Error>| {
+                lock(&schedule).done = true;
+                changed.notify_all();
+                result
+            };
+            let mut requested: HashMap<usize, Vec<(usize, Instant)>> = HashMap::new();
+            let mut fetched = BTreeMap::new();
+            loop {
+                match receiver.recv_timeout(self.timeout / 4) {
+                    Ok(Message::Started(id, n)) => {
+                        requested.entry(n).or_default().push((id, Instant::now()));
+                    }
+                    Ok(Message::Fetched(id, n, result)) => {
+                        if let Some(asked) = requested.get_mut(&n) {
+                            asked.retain(|(i, _)| *i != id);
+                        }
+                        match result {
+                            Ok(ref block)
+                                if block.block_hash() == hashes[n] && block.check_merkle_root() =>
+                            {
+                                download.served[id] += 1;
+                                if n >= lock(&schedule).delivered {
+                                    fetched.insert(n, result.unwrap());
+                                }
+                            }
+                            _ => {
+                                let mut schedule = lock(&schedule);
+                                if !schedule.dropped[id] {
+                                    schedule.dropped[id] = true;
+                                    download.dropped.push(id);
+                                }
+                                if n >= schedule.delivered && !fetched.contains_key(&n) {
+                                    schedule.queue.push_front(n);
+                                }
+                            }
+                        }
+                        changed.notify_all();
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return finish(Err(Error::Peer("all peers failed")));
+                    }
+                }
+                // requests of peers that did not answer in time go to others
+                for (n, asked) in requested.iter_mut() {
+                    let late = asked
+                        .iter()
+                        .filter(|(_, at)| at.elapsed() > self.timeout)
+                        .map(|(id, _)| *id)
+                        .collect::<Vec<_>>();
+                    if late.is_empty() {
+                        continue;
+                    }
+                    asked.retain(|(id, _)| !late.contains(id));
+                    download.dropped.extend(late.iter().cloned());
+                    let mut schedule = lock(&schedule);
+                    for id in late {
+                        schedule.dropped[id] = true;
+                    }
+                    if asked.is_empty() && !fetched.contains_key(n) {
+                        schedule.queue.push_front(*n);
+                    }
+                    changed.notify_all();
+                }
+                while let Some(block) = fetched.remove(&download.delivered) {
+                    let n = download.delivered;
+                    download.delivered += 1;
+                    lock(&schedule).delivered = download.delivered;
+                    changed.notify_all();
+                    match deliver(n, block) {
+                        Ok(true) => {}
+                        Ok(false) => return finish(Ok(download.clone())),
+                        Err(e) => return finish(Err(e)),
+                    }
+                }
+                if download.delivered == hashes.len() {
+                    return finish(Ok(download.clone()));
+                }
+                if lock(&schedule).dropped.iter().all(|d| *d) {
+                    return finish(Err(Error::Peer("all peers failed")));
+                }
+            }
+        })
+    }
+}
+
+/// ask a peer for blocks until the download is done or the peer dropped
+fn work<P: ChainBackend>(
+    id: usize,
+    peer: &mut P,
+    hashes: &[BlockHash],
+    window: usize,
+    schedule: &Mutex<Schedule>,
+    changed: &Condvar,
+    sender: Sender<Message>,
+) {
+    loop {
+        let n = {
+            let mut schedule = lock(schedule);
+            loop {
+                if schedule.done || schedule.dropped[id] {
+                    return;
+                }
+                let limit = schedule.delivered + window;
+                if let Some(n) = schedule.queue.front().cloned().filter(|n| *n < limit) {
+                    schedule.queue.pop_front();
+                    break n;
+                }
+                schedule = changed
+                    .wait(schedule)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+        };
+        if sender.send(Message::Started(id, n)).is_err() {
+            return;
+        }
+        let result = peer.block(&hashes[n]);
+        if sender.send(Message::Fetched(id, n, result)).is_err() {
+            return;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::bip158::BlockFilter;
+    use bitcoin::{BlockHeader, Network, Transaction, Txid};
+
+    use backend::TxStatus;
+    use fee::FeeRate;
+
+    use super::*;
+
+    enum Behaviour {
+        Honest,
+        Slow,
+        Lying,
+    }
+
+    struct Peer {
+        blocks: Vec<Block>,
+        behaviour: Behaviour,
+    }
+
+    impl ChainBackend for Peer {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(self.blocks.get(height as usize).map(|b| b.header))
+        }
+
+        fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+            let block = self
+                .blocks
+                .iter()
+                .find(|b| b.block_hash() == *hash)
+                .cloned()
+                .ok_or(Error::Backend("unknown block"))?;
+            match self.behaviour {
+                Behaviour::Honest => Ok(block),
+                Behaviour::Slow => {
+                    thread::sleep(Duration::from_millis(400));
+                    Ok(block)
+                }
+                Behaviour::Lying => Ok(self.blocks[0].clone()),
+            }
+        }
+
+        fn filter(&self, _: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            Ok(None)
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    #[test]
+    fn block_downloader() {
+        let mut blocks = vec![genesis_block(Network::Testnet)];
+        for nonce in 1..40 {
+            let mut block = blocks[0].clone();
+            block.header.prev_blockhash = blocks.last().unwrap().block_hash();
+            block.header.nonce = nonce;
+            blocks.push(block);
+        }
+        let hashes = blocks[1..]
+            .iter()
+            .map(|b| b.block_hash())
+            .collect::<Vec<_>>();
+        let peer = |behaviour| Peer {
+            blocks: blocks.clone(),
+            behaviour,
+        };
+        let mut peers = vec![
+            peer(Behaviour::Slow),
+            peer(Behaviour::Honest),
+            peer(Behaviour::Lying),
+            peer(Behaviour::Honest),
+        ];
+        let downloader = BlockDownloader::new()
+            .window(4)
+            .timeout(Duration::from_millis(100));
+        let mut delivered = Vec::new();
+        let download = downloader
+            .fetch(&mut peers, &hashes, |n, block| {
+                assert_eq!(n, delivered.len());
+                delivered.push(block.block_hash());
+                Ok(true)
+            })
+            .unwrap();
+        assert_eq!(delivered, hashes);
+        assert_eq!(download.delivered, hashes.len());
+        assert!(download.dropped.contains(&0));
+        assert!(download.dropped.contains(&2));
+        assert_eq!(download.served[2], 0);
+        assert!(download.served[1] > 0 && download.served[3] > 0);
+
+        // delivery stops when asked to
+        let download = downloader
+            .fetch(&mut peers[1..2], &hashes, |n, _| Ok(n < 9))
+            .unwrap();
+        assert_eq!(download.delivered, 10);
+
+        let mut liars = vec![peer(Behaviour::Lying)];
+        assert!(downloader
+            .fetch(&mut liars, &hashes, |_, _| Ok(true))
+            .is_err());
+    }
+}
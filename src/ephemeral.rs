@@ -0,0 +1,136 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Ephemeral wallet
+//!
+//! A wallet that lives in memory only, e.g. in a hot signing service or a test. Nothing is
+//! written until it is explicitly materialized with persist_to.
+//!
+use bitcoin::{Block, BlockHash, Transaction};
+
+use account::MasterAccount;
+use coins::Coins;
+use error::Error;
+use storage::WalletStorage;
+
+/// A master account and its coins in memory
+pub struct EphemeralWallet {
+    master: MasterAccount,
+    coins: Coins,
+}
+
+impl EphemeralWallet {
+    /// a wallet of a master account without coins
+    pub fn new(master: MasterAccount) -> EphemeralWallet {
+        EphemeralWallet::with_coins(master, Coins::new())
+    }
+
+    pub fn with_coins(master: MasterAccount, coins: Coins) -> EphemeralWallet {
+        EphemeralWallet { master, coins }
+    }
+
+    pub fn master(&self) -> &MasterAccount {
+        &self.master
+    }
+
+    pub fn master_mut(&mut self) -> &mut MasterAccount {
+        &mut self.master
+    }
+
+    pub fn coins(&self) -> &Coins {
+        &self.coins
+    }
+
+    pub fn coins_mut(&mut self) -> &mut Coins {
+        &mut self.coins
+    }
+
+    /// process a block, see Coins::process
+    pub fn process(&mut self, block: &Block) -> bool {
+        self.coins.process(&mut self.master, block)
+    }
+
+    /// process an unconfirmed transaction, see Coins::process_unconfirmed_transaction
+    pub fn process_unconfirmed_transaction(&mut self, transaction: &Transaction) -> bool {
+        self.coins
+            .process_unconfirmed_transaction(&mut self.master, transaction)
+    }
+
+    /// forget the tip block, see Coins::unwind_tip
+    pub fn unwind_tip(&mut self, block_hash: &BlockHash) {
+        self.coins.unwind_tip(block_hash)
+    }
+
+    /// write the wallet to storage, it stays in memory and is not written again unless asked
+    pub fn persist_to<S: WalletStorage>(
+        &self,
+        storage: &mut S,
+        passphrase: &str,
+    ) -> Result<(), Error> {
+        storage.store(&self.master, &self.coins, passphrase)
+    }
+
+    pub fn into_parts(self) -> (MasterAccount, Coins) {
+        (self.master, self.coins)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::consensus::serialize;
+    use bitcoin::{Network, TxOut};
+
+    use fixtures::{block, funding, master_account, PASSPHRASE};
+    use storage::{KdfParams, WalletFile};
+
+    use super::*;
+
+    #[test]
+    fn persist_to() {
+        let (master, _) = master_account(Network::Regtest);
+        let mut wallet = EphemeralWallet::new(master);
+        let funded = wallet
+            .master_mut()
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .script_pubkey();
+        let block = block(
+            Network::Regtest,
+            vec![funding(vec![TxOut {
+                value: 100_000,
+                script_pubkey: funded,
+            }])],
+        );
+        assert!(wallet.process(&block));
+        assert_eq!(wallet.coins().available_balance(1, |_| Some(1)), 100_000);
+
+        let path = std::env::temp_dir().join(format!("ephemeral-{}.dat", std::process::id()));
+        let mut file = WalletFile::new(&path).with_kdf(KdfParams {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        });
+        assert!(!file.exists());
+        wallet.persist_to(&mut file, PASSPHRASE).unwrap();
+        let (master, coins, _) = file.load(PASSPHRASE).unwrap();
+        assert_eq!(serialize(&master), serialize(wallet.master()));
+        assert!(coins == *wallet.coins());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
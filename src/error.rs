@@ -21,9 +21,15 @@
 
 use std::{convert, error, fmt, io};
 
+use bitcoin::consensus::encode;
 use bitcoin::util::bip32;
+use bitcoin::util::psbt;
 use crypto::symmetriccipher;
 
+use broadcast::Rejection;
+use fee::RpcError;
+use policy::Violation;
+
 /// An error class to offer a unified error interface upstream
 pub enum Error {
     /// Unsupported
@@ -42,6 +48,66 @@ pub enum Error {
     SecpError(bitcoin::secp256k1::Error),
     /// cipher error
     SymmetricCipherError(symmetriccipher::SymmetricCipherError),
+    /// payment URI error
+    Uri(&'static str),
+    /// DNS payment instruction error
+    Dns(&'static str),
+    /// serialization error
+    Serialize(encode::Error),
+    /// coin selection error
+    CoinSelection(&'static str),
+    /// partially signed transaction error
+    Psbt(psbt::Error),
+    /// replacement would violate BIP125 rules
+    Replacement(&'static str),
+    /// payjoin protocol error
+    Payjoin(&'static str),
+    /// coinjoin transaction does not match the registration
+    Coinjoin(&'static str),
+    /// fee estimator has no estimate
+    FeeEstimation(&'static str),
+    /// external signer failed or returned an invalid signature
+    Signer(&'static str),
+    /// malformed signed message
+    Message(&'static str),
+    /// a backend did not accept a transaction
+    Broadcast(&'static str),
+    /// contribution of a counterparty to a collaborative transaction violates the terms
+    Collaboration(&'static str),
+    /// pre-signed recovery transactions can not be built
+    Recovery(&'static str),
+    /// vault templates can not be built or spent
+    Vault(&'static str),
+    /// signing refused by the spending policy
+    Policy(Violation),
+    /// the remote cosigner refused or returned an unusable PSBT
+    Cosigner(&'static str),
+    /// fee or fee rate outside the sanity bounds of the builder
+    FeeBounds(&'static str),
+    /// a wallet file can not be read or written
+    Storage(&'static str),
+    /// descriptors of an other wallet can not be imported
+    Descriptor(&'static str),
+    /// a JSON document can not be read
+    Json(&'static str),
+    /// a mutating operation on a wallet opened read only
+    ReadOnly(&'static str),
+    /// a legacy Bitcoin Core wallet file can not be read
+    Legacy(&'static str),
+    /// a chain backend failed or returned unusable data
+    Backend(&'static str),
+    /// a peer of the bitcoin network misbehaved or lacks a service
+    Peer(&'static str),
+    /// a proxy refused or failed to connect
+    Proxy(&'static str),
+    /// a ZMQ publisher violated the protocol
+    Zmq(&'static str),
+    /// a task run on a runtime panicked or was dropped before it completed
+    Task(&'static str),
+    /// the mempool would not accept a transaction
+    Rejected(Rejection),
+    /// a JSON-RPC server replied with an error
+    Rpc(RpcError),
 }
 
 impl error::Error for Error {
@@ -59,6 +125,36 @@ impl error::Error for Error {
             Error::KeyDerivation(ref err) => Some(err),
             Error::SecpError(ref err) => Some(err),
             Error::SymmetricCipherError(_) => None,
+            Error::Uri(_) => None,
+            Error::Dns(_) => None,
+            Error::Serialize(ref err) => Some(err),
+            Error::CoinSelection(_) => None,
+            Error::Psbt(ref err) => Some(err),
+            Error::Replacement(_) => None,
+            Error::Payjoin(_) => None,
+            Error::Coinjoin(_) => None,
+            Error::FeeEstimation(_) => None,
+            Error::Signer(_) => None,
+            Error::Message(_) => None,
+            Error::Broadcast(_) => None,
+            Error::Collaboration(_) => None,
+            Error::Recovery(_) => None,
+            Error::Vault(_) => None,
+            Error::Policy(_) => None,
+            Error::Cosigner(_) => None,
+            Error::FeeBounds(_) => None,
+            Error::Storage(_) => None,
+            Error::Descriptor(_) => None,
+            Error::Json(_) => None,
+            Error::ReadOnly(_) => None,
+            Error::Legacy(_) => None,
+            Error::Backend(_) => None,
+            Error::Peer(_) => None,
+            Error::Proxy(_) => None,
+            Error::Zmq(_) => None,
+            Error::Task(_) => None,
+            Error::Rejected(_) => None,
+            Error::Rpc(_) => None,
         }
     }
 }
@@ -78,11 +174,41 @@ impl fmt::Display for Error {
             Error::SymmetricCipherError(ref err) => write!(
                 f,
                 "Cipher error: {}",
-                match err {
-                    &symmetriccipher::SymmetricCipherError::InvalidLength => "invalid length",
-                    &symmetriccipher::SymmetricCipherError::InvalidPadding => "invalid padding",
+                match *err {
+                    symmetriccipher::SymmetricCipherError::InvalidLength => "invalid length",
+                    symmetriccipher::SymmetricCipherError::InvalidPadding => "invalid padding",
                 }
             ),
+            Error::Uri(ref s) => write!(f, "URI: {}", s),
+            Error::Dns(ref s) => write!(f, "DNS: {}", s),
+            Error::Serialize(ref err) => write!(f, "Serialization error: {}", err),
+            Error::CoinSelection(ref s) => write!(f, "Coin selection: {}", s),
+            Error::Psbt(ref err) => write!(f, "PSBT error: {}", err),
+            Error::Replacement(ref s) => write!(f, "Replacement: {}", s),
+            Error::Payjoin(ref s) => write!(f, "Payjoin: {}", s),
+            Error::Coinjoin(ref s) => write!(f, "Coinjoin: {}", s),
+            Error::FeeEstimation(ref s) => write!(f, "Fee estimation: {}", s),
+            Error::Signer(ref s) => write!(f, "Signer: {}", s),
+            Error::Message(ref s) => write!(f, "Message: {}", s),
+            Error::Broadcast(ref s) => write!(f, "Broadcast: {}", s),
+            Error::Collaboration(ref s) => write!(f, "Collaboration: {}", s),
+            Error::Recovery(ref s) => write!(f, "Recovery: {}", s),
+            Error::Vault(ref s) => write!(f, "Vault: {}", s),
+            Error::Policy(ref violation) => write!(f, "Policy: {}", violation),
+            Error::Cosigner(ref s) => write!(f, "Cosigner: {}", s),
+            Error::FeeBounds(ref s) => write!(f, "Fee bounds: {}", s),
+            Error::Storage(ref s) => write!(f, "Storage: {}", s),
+            Error::Descriptor(ref s) => write!(f, "Descriptor: {}", s),
+            Error::Json(ref s) => write!(f, "JSON: {}", s),
+            Error::ReadOnly(ref s) => write!(f, "Read only: {}", s),
+            Error::Legacy(ref s) => write!(f, "Legacy wallet: {}", s),
+            Error::Backend(ref s) => write!(f, "Backend: {}", s),
+            Error::Peer(ref s) => write!(f, "Peer: {}", s),
+            Error::Proxy(ref s) => write!(f, "Proxy: {}", s),
+            Error::Zmq(ref s) => write!(f, "Zmq: {}", s),
+            Error::Task(ref s) => write!(f, "Task: {}", s),
+            Error::Rejected(ref rejection) => write!(f, "Rejected: {}", rejection),
+            Error::Rpc(ref err) => write!(f, "RPC: {}", err),
         }
     }
 }
@@ -97,9 +223,7 @@ impl convert::From<Error> for io::Error {
     fn from(err: Error) -> io::Error {
         match err {
             Error::IO(e) => e,
-            _ => {
-                io::Error::new(io::ErrorKind::Other, err.to_string())
-            }
+            _ => io::Error::other(err.to_string()),
         }
     }
 }
@@ -110,6 +234,18 @@ impl convert::From<io::Error> for Error {
     }
 }
 
+impl convert::From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Error {
+        Error::Serialize(err)
+    }
+}
+
+impl convert::From<psbt::Error> for Error {
+    fn from(err: psbt::Error) -> Error {
+        Error::Psbt(err)
+    }
+}
+
 impl convert::From<bip32::Error> for Error {
     fn from(err: bip32::Error) -> Error {
         Error::KeyDerivation(err)
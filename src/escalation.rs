@@ -0,0 +1,281 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Fee escalation
+//!
+//! A policy that raises the fee of pending outgoing transactions on a schedule, e.g. by 20%
+//! every 3 blocks up to a cap. The policy only proposes replacements, the caller decides to
+//! sign and broadcast them and tells the policy about the replacement with track.
+//!
+use std::cmp::{max, min};
+use std::collections::HashMap;
+
+use bitcoin::{Script, Transaction, Txid};
+
+use coins::Coins;
+use fee::FeeRate;
+use selection::Selection;
+
+/// A pending transaction watched by the policy
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Tracked {
+    /// height at which the transaction was broadcast
+    since: u32,
+    feerate: FeeRate,
+}
+
+/// A proposed replacement
+pub struct Escalation {
+    /// the transaction to replace
+    pub replaces: Txid,
+    /// the unsigned replacement
+    pub transaction: Transaction,
+    pub selection: Selection,
+    pub feerate: FeeRate,
+}
+
+/// Fee escalation schedule for pending transactions
+#[derive(Clone, Debug)]
+pub struct FeeEscalation {
+    interval: u32,
+    step_percent: u64,
+    max_feerate: FeeRate,
+    tracked: HashMap<Txid, Tracked>,
+}
+
+impl FeeEscalation {
+    /// raise fee rates by step_percent every interval blocks, up to max_feerate
+    pub fn new(interval: u32, step_percent: u64, max_feerate: FeeRate) -> FeeEscalation {
+        FeeEscalation {
+            interval: max(interval, 1),
+            step_percent,
+            max_feerate,
+            tracked: HashMap::new(),
+        }
+    }
+
+    /// watch a transaction broadcast at height with a fee rate, e.g. an accepted replacement
+    pub fn track(&mut self, txid: Txid, height: u32, feerate: FeeRate) {
+        self.tracked.insert(
+            txid,
+            Tracked {
+                since: height,
+                feerate,
+            },
+        );
+    }
+
+    /// stop watching a transaction
+    pub fn forget(&mut self, txid: &Txid) -> bool {
+        self.tracked.remove(txid).is_some()
+    }
+
+    pub fn is_tracked(&self, txid: &Txid) -> bool {
+        self.tracked.contains_key(txid)
+    }
+
+    /// watch pending transactions spending own coins that are not yet watched, as broadcast
+    /// at height. Transactions no longer pending are forgotten.
+    pub fn watch(&mut self, coins: &Coins, height: u32) {
+        self.tracked
+            .retain(|txid, _| coins.pending().contains_key(txid));
+        for (txid, transaction) in coins.pending() {
+            if self.tracked.contains_key(txid) || coins.spent_by(txid).is_empty() {
+                continue;
+            }
+            if let Some(fee) = coins.fee_of(transaction) {
+                let feerate =
+                    FeeRate::from_sat_per_kwu(fee * 1000 / transaction.get_weight() as u64);
+                self.track(*txid, height, feerate);
+            }
+        }
+    }
+
+    /// fee rate the schedule asks for at height, None if it is not yet time or the cap is
+    /// reached
+    fn next_feerate(&self, tracked: &Tracked, height: u32) -> Option<FeeRate> {
+        if height < tracked.since + self.interval || tracked.feerate >= self.max_feerate {
+            return None;
+        }
+        let current = tracked.feerate.as_sat_per_kwu();
+        let raised = (current * (100 + self.step_percent)).div_ceil(100);
+        Some(FeeRate::from_sat_per_kwu(min(
+            max(raised, current + 1),
+            self.max_feerate.as_sat_per_kwu(),
+        )))
+    }
+
+    /// replacements of watched transactions that are due at height
+    /// Change is paid as in Coins::bump_fee. Transactions that can not be replaced, e.g. since
+    /// they do not signal replaceability or the wallet lacks funds, are skipped.
+    pub fn escalate<H>(
+        &self,
+        coins: &Coins,
+        change: &Script,
+        height: u32,
+        block_height: H,
+    ) -> Vec<Escalation>
+    where
+        H: Fn(&bitcoin::BlockHash) -> Option<u32>,
+    {
+        let mut escalations = Vec::new();
+        for (txid, tracked) in self.tracked.iter() {
+            if !coins.pending().contains_key(txid) {
+                continue;
+            }
+            if let Some(feerate) = self.next_feerate(tracked, height) {
+                if let Ok((transaction, selection)) =
+                    coins
+                        .bump_fee(txid)
+                        .feerate(feerate)
+                        .build(change, height, &block_height)
+                {
+                    escalations.push(Escalation {
+                        replaces: *txid,
+                        transaction,
+                        selection,
+                        feerate,
+                    });
+                }
+            }
+        }
+        escalations.sort_by_key(|e| e.replaces);
+        escalations
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network, OutPoint, SigHashType};
+    use std::str::FromStr;
+
+    use account::{MasterAccount, Unlocker};
+    use builder::ChangePosition;
+    use fixtures::{add_change_account, funded, master_account, next_script};
+
+    use super::*;
+
+    fn heights(_: &bitcoin::BlockHash) -> Option<u32> {
+        Some(1)
+    }
+
+    /// a wallet with a signed replaceable payment at 10 sat/vB broadcast at height 10
+    struct Broadcast {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        funding: Transaction,
+        original: Transaction,
+        change: Script,
+    }
+
+    fn broadcast() -> Broadcast {
+        let (mut master, mut unlocker) = master_account(Network::Testnet);
+        add_change_account(&mut master, &mut unlocker);
+        let (mut coins, funding) = funded(&mut master, Network::Testnet, &[100_000]);
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = next_script(&mut master, (0, 1));
+        let resolve = |point: &OutPoint| funding.output.get(point.vout as usize).cloned();
+        let (mut original, _) = coins
+            .build_tx()
+            .add_recipient(&to, 50_000)
+            .feerate(FeeRate::from_sat_per_vb(10))
+            .enable_rbf()
+            .change_position(ChangePosition::Last)
+            .build(&change, 10, heights)
+            .unwrap();
+        master
+            .sign(&mut original, SigHashType::All, &resolve, &mut unlocker)
+            .unwrap();
+        coins.process_unconfirmed_transaction(&mut master, &original);
+        Broadcast {
+            master,
+            unlocker,
+            coins,
+            funding,
+            original,
+            change,
+        }
+    }
+
+    #[test]
+    fn watch() {
+        let Broadcast {
+            coins, original, ..
+        } = broadcast();
+        let mut policy = FeeEscalation::new(3, 20, FeeRate::from_sat_per_vb(14));
+        policy.watch(&coins, 10);
+        assert!(policy.is_tracked(&original.txid()));
+        let feerate = policy.tracked[&original.txid()].feerate;
+        assert!((10.0..10.1).contains(&feerate.as_sat_per_vb()));
+        assert!(policy.forget(&original.txid()));
+        assert!(!policy.is_tracked(&original.txid()));
+    }
+
+    #[test]
+    fn escalate() {
+        let Broadcast {
+            mut master,
+            mut unlocker,
+            mut coins,
+            funding,
+            original,
+            change,
+        } = broadcast();
+        let mut policy = FeeEscalation::new(3, 20, FeeRate::from_sat_per_vb(14));
+        policy.watch(&coins, 10);
+        let feerate = policy.tracked[&original.txid()].feerate;
+        // not yet due
+        assert!(policy.escalate(&coins, &change, 12, heights).is_empty());
+        let escalations = policy.escalate(&coins, &change, 13, heights);
+        assert_eq!(escalations.len(), 1);
+        assert_eq!(escalations[0].replaces, original.txid());
+        assert_eq!(
+            escalations[0].feerate.as_sat_per_kwu(),
+            (feerate.as_sat_per_kwu() * 120).div_ceil(100)
+        );
+        // the caller broadcasts the replacement
+        let mut replacement = escalations[0].transaction.clone();
+        let resolve = |point: &OutPoint| funding.output.get(point.vout as usize).cloned();
+        master
+            .sign(&mut replacement, SigHashType::All, &resolve, &mut unlocker)
+            .unwrap();
+        coins.process_unconfirmed_transaction(&mut master, &replacement);
+        policy.track(replacement.txid(), 13, escalations[0].feerate);
+        policy.watch(&coins, 13);
+        assert!(!policy.is_tracked(&original.txid()));
+        assert!(policy.escalate(&coins, &change, 15, heights).is_empty());
+    }
+
+    #[test]
+    fn capped() {
+        let Broadcast {
+            coins,
+            original,
+            change,
+            ..
+        } = broadcast();
+        let mut policy = FeeEscalation::new(3, 20, FeeRate::from_sat_per_vb(14));
+        policy.track(original.txid(), 10, FeeRate::from_sat_per_vb(13));
+        // the next step is capped
+        let escalations = policy.escalate(&coins, &change, 13, heights);
+        assert_eq!(escalations[0].feerate, FeeRate::from_sat_per_vb(14));
+        policy.track(original.txid(), 13, escalations[0].feerate);
+        assert!(policy.escalate(&coins, &change, 30, heights).is_empty());
+    }
+}
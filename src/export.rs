@@ -0,0 +1,476 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # History export
+//!
+//! CSV and OFX files of the transaction history for bookkeeping. Amounts are in bitcoin, the
+//! fiat value of a transaction is looked up with a FiatRates implementation at the time of the
+//! transaction. Labels and counterparties of CSV files starting with =, +, - or @ are prefixed
+//! with ' so spreadsheets do not evaluate them as formulas.
+//!
+use bitcoin::Network;
+
+use error::Error;
+use history::{Direction, History, HistoryEntry, HistoryQuery};
+
+/// Exchange rates of bitcoin, e.g. from a price service
+pub trait FiatRates {
+    /// ISO 4217 code of the currency, e.g. "USD"
+    fn currency(&self) -> &str;
+    /// price of one bitcoin at a time, seconds since epoch, None if it is not known
+    fn rate(&self, time: u64) -> Result<Option<f64>, Error>;
+}
+
+/// A column of a CSV export
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Column {
+    /// time of the confirming block or when the transaction was first seen, ISO 8601 in UTC
+    Date,
+    Txid,
+    Direction,
+    /// effect on the balance in bitcoin, negative for outgoing transactions
+    Amount,
+    /// fee in bitcoin, empty if not paid by the wallet
+    Fee,
+    /// value of the amount in the currency of the rates, empty without rate
+    FiatValue,
+    /// labels separated by "; "
+    Labels,
+    /// addresses of counterparties separated by "; "
+    Counterparties,
+    /// height of the confirming block, empty while pending
+    Height,
+}
+
+impl Column {
+    fn header(&self, currency: Option<&str>) -> String {
+        match self {
+            Column::Date => "Date".to_string(),
+            Column::Txid => "Txid".to_string(),
+            Column::Direction => "Direction".to_string(),
+            Column::Amount => "Amount (BTC)".to_string(),
+            Column::Fee => "Fee (BTC)".to_string(),
+            Column::FiatValue => format!("Value ({})", currency.unwrap_or("fiat")),
+            Column::Labels => "Labels".to_string(),
+            Column::Counterparties => "Counterparties".to_string(),
+            Column::Height => "Height".to_string(),
+        }
+    }
+}
+
+/// signed amount of satoshis in bitcoin with 8 decimals
+fn format_btc(sats: i64) -> String {
+    let sign = if sats < 0 { "-" } else { "" };
+    let sats = sats.unsigned_abs();
+    format!("{}{}.{:08}", sign, sats / 100_000_000, sats % 100_000_000)
+}
+
+/// date and time of seconds since epoch in UTC
+fn civil_time(time: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = (time / 86400) as i64;
+    let seconds = (time % 86400) as u32;
+    // days to civil date, proleptic gregorian calendar
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+fn iso_date(time: u64) -> String {
+    let (y, mo, d, h, mi, s) = civil_time(time);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, mo, d, h, mi, s)
+}
+
+fn ofx_date(time: u64) -> String {
+    let (y, mo, d, h, mi, s) = civil_time(time);
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}", y, mo, d, h, mi, s)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// text a spreadsheet would not evaluate as a formula
+fn csv_text(text: String) -> String {
+    if text.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", text)
+    } else {
+        text
+    }
+}
+
+fn xml_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Export of the transaction history
+pub struct HistoryExport<'a> {
+    network: Network,
+    columns: Vec<Column>,
+    rates: Option<&'a dyn FiatRates>,
+    query: HistoryQuery,
+    account_id: String,
+}
+
+impl<'a> HistoryExport<'a> {
+    /// all transactions with date, txid, direction, amount, fee and labels
+    pub fn new(network: Network) -> HistoryExport<'a> {
+        HistoryExport {
+            network,
+            columns: vec![
+                Column::Date,
+                Column::Txid,
+                Column::Direction,
+                Column::Amount,
+                Column::Fee,
+                Column::Labels,
+            ],
+            rates: None,
+            query: HistoryQuery::new(),
+            account_id: "wallet".to_string(),
+        }
+    }
+
+    /// columns of CSV exports in this order
+    pub fn columns(mut self, columns: &[Column]) -> HistoryExport<'a> {
+        self.columns = columns.to_vec();
+        self
+    }
+
+    /// rates for fiat values, OFX exports are in their currency
+    pub fn fiat_rates(mut self, rates: &'a dyn FiatRates) -> HistoryExport<'a> {
+        self.rates = Some(rates);
+        self
+    }
+
+    /// export only the transactions of a query
+    pub fn query(mut self, query: HistoryQuery) -> HistoryExport<'a> {
+        self.query = query;
+        self
+    }
+
+    /// account identifier of OFX exports
+    pub fn account_id(mut self, account_id: &str) -> HistoryExport<'a> {
+        self.account_id = account_id.to_string();
+        self
+    }
+
+    /// fiat value of the amount of an entry, None without rates or rate
+    fn fiat_value(&self, entry: &HistoryEntry) -> Result<Option<f64>, Error> {
+        match self.rates {
+            Some(rates) => Ok(rates
+                .rate(entry.time)?
+                .map(|rate| entry.net() as f64 / 100_000_000.0 * rate)),
+            None => Ok(None),
+        }
+    }
+
+    /// oldest first as bookkeeping expects
+    fn entries<'h>(&self, history: &'h History) -> Vec<&'h HistoryEntry> {
+        let mut entries = history.query(&self.query);
+        entries.reverse();
+        entries
+    }
+
+    /// comma separated values with a header line
+    pub fn to_csv(&self, history: &History) -> Result<String, Error> {
+        let currency = self.rates.map(|r| r.currency());
+        let mut csv = self
+            .columns
+            .iter()
+            .map(|c| csv_field(&c.header(currency)))
+            .collect::<Vec<_>>()
+            .join(",");
+        csv.push_str("\r\n");
+        for entry in self.entries(history) {
+            let mut fields = Vec::new();
+            for column in self.columns.iter() {
+                fields.push(match column {
+                    Column::Date => iso_date(entry.time),
+                    Column::Txid => entry.txid.to_string(),
+                    Column::Direction => match entry.direction {
+                        Direction::Incoming => "incoming",
+                        Direction::Outgoing => "outgoing",
+                        Direction::SelfTransfer => "self",
+                    }
+                    .to_string(),
+                    Column::Amount => format_btc(entry.net()),
+                    Column::Fee => match (entry.direction, entry.fee) {
+                        (Direction::Incoming, _) | (_, None) => String::new(),
+                        (_, Some(fee)) => format_btc(fee as i64),
+                    },
+                    Column::FiatValue => self
+                        .fiat_value(entry)?
+                        .map(|v| format!("{:.2}", v))
+                        .unwrap_or_default(),
+                    Column::Labels => csv_text(entry.labels.join("; ")),
+                    Column::Counterparties => csv_text(
+                        entry
+                            .counterparties
+                            .iter()
+                            .filter_map(|o| {
+                                bitcoin::Address::from_script(&o.script_pubkey, self.network)
+                            })
+                            .map(|a| a.to_string())
+                            .collect::<Vec<_>>()
+                            .join("; "),
+                    ),
+                    Column::Height => entry
+                        .confirmation
+                        .map(|(_, h)| h.to_string())
+                        .unwrap_or_default(),
+                });
+            }
+            csv.push_str(
+                &fields
+                    .iter()
+                    .map(|f| csv_field(f))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+            csv.push_str("\r\n");
+        }
+        Ok(csv)
+    }
+
+    /// an OFX 2.2 bank statement
+    /// Amounts are in the currency of the rates if given and in bitcoin otherwise, transactions
+    /// without rate are then left out.
+    pub fn to_ofx(&self, history: &History) -> Result<String, Error> {
+        let entries = self.entries(history);
+        let currency = self.rates.map(|r| r.currency()).unwrap_or("XBT");
+        let start = entries.first().map(|e| e.time).unwrap_or_default();
+        let end = entries.last().map(|e| e.time).unwrap_or_default();
+        let mut ofx = String::new();
+        ofx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+        ofx.push_str(
+            "<?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" \
+             NEWFILEUID=\"NONE\"?>\n",
+        );
+        ofx.push_str("<OFX>\n<BANKMSGSRSV1>\n<STMTTRNRS>\n<TRNUID>0</TRNUID>\n");
+        ofx.push_str("<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n<STMTRS>\n");
+        ofx.push_str(&format!("<CURDEF>{}</CURDEF>\n", xml_text(currency)));
+        ofx.push_str(&format!(
+            "<BANKACCTFROM><BANKID>bitcoin</BANKID><ACCTID>{}</ACCTID>\
+             <ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>\n",
+            xml_text(&self.account_id)
+        ));
+        ofx.push_str(&format!(
+            "<BANKTRANLIST>\n<DTSTART>{}</DTSTART>\n<DTEND>{}</DTEND>\n",
+            ofx_date(start),
+            ofx_date(end)
+        ));
+        for entry in entries {
+            let amount = match self.rates {
+                Some(_) => match self.fiat_value(entry)? {
+                    Some(value) => format!("{:.2}", value),
+                    None => continue,
+                },
+                None => format_btc(entry.net()),
+            };
+            let kind = match entry.direction {
+                Direction::Incoming => "CREDIT",
+                Direction::Outgoing => "DEBIT",
+                Direction::SelfTransfer => "XFER",
+            };
+            ofx.push_str("<STMTTRN>\n");
+            ofx.push_str(&format!("<TRNTYPE>{}</TRNTYPE>\n", kind));
+            ofx.push_str(&format!("<DTPOSTED>{}</DTPOSTED>\n", ofx_date(entry.time)));
+            ofx.push_str(&format!("<TRNAMT>{}</TRNAMT>\n", amount));
+            ofx.push_str(&format!("<FITID>{}</FITID>\n", entry.txid));
+            if let Some(label) = entry.labels.first() {
+                // NAME is limited to 32 characters
+                let name = label.chars().take(32).collect::<String>();
+                ofx.push_str(&format!("<NAME>{}</NAME>\n", xml_text(&name)));
+            }
+            if !entry.labels.is_empty() {
+                ofx.push_str(&format!(
+                    "<MEMO>{}</MEMO>\n",
+                    xml_text(&entry.labels.join("; "))
+                ));
+            }
+            ofx.push_str("</STMTTRN>\n");
+        }
+        ofx.push_str("</BANKTRANLIST>\n</STMTRS>\n</STMTTRNRS>\n</BANKMSGSRSV1>\n</OFX>\n");
+        Ok(ofx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::consensus::encode;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BlockHash, Script, TxOut, Txid};
+
+    use super::*;
+
+    struct FixedRate;
+
+    impl FiatRates for FixedRate {
+        fn currency(&self) -> &str {
+            "USD"
+        }
+
+        fn rate(&self, time: u64) -> Result<Option<f64>, Error> {
+            Ok(if time < 1_600_000_000 {
+                None
+            } else {
+                Some(10_000.0)
+            })
+        }
+    }
+
+    fn history() -> History {
+        history_labelled(&["salary, january"], &["rent <flat>"])
+    }
+
+    /// an incoming and an outgoing transaction with these labels
+    fn history_labelled(incoming: &[&str], outgoing: &[&str]) -> History {
+        let entry = |n: u8, direction, sent, received, fee, time, labels: &[&str]| HistoryEntry {
+            txid: Txid::from_slice(&[n; 32]).unwrap(),
+            direction,
+            sent,
+            received,
+            fee,
+            own: vec![],
+            counterparties: vec![TxOut {
+                value: 1,
+                script_pubkey: Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default()),
+            }],
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            confirmation: Some((BlockHash::default(), n as u32)),
+            time,
+        };
+        let mut data = encode::serialize(&bitcoin::VarInt(2));
+        data.extend(encode::serialize(&entry(
+            1,
+            Direction::Incoming,
+            0,
+            150_000_000,
+            None,
+            1_600_000_000,
+            incoming,
+        )));
+        data.extend(encode::serialize(&entry(
+            2,
+            Direction::Outgoing,
+            150_000_000,
+            99_000_000,
+            Some(1_000),
+            1_600_086_400,
+            outgoing,
+        )));
+        encode::deserialize(&data).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(iso_date(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso_date(951_782_400), "2000-02-29T00:00:00Z");
+        let rates = FixedRate;
+        let csv = HistoryExport::new(Network::Regtest)
+            .columns(&[
+                Column::Date,
+                Column::Amount,
+                Column::Fee,
+                Column::FiatValue,
+                Column::Labels,
+                Column::Height,
+            ])
+            .fiat_rates(&rates)
+            .to_csv(&history())
+            .unwrap();
+        let lines = csv.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "Date,Amount (BTC),Fee (BTC),Value (USD),Labels,Height"
+        );
+        assert_eq!(
+            lines[1],
+            "2020-09-13T12:26:40Z,1.50000000,,15000.00,\"salary, january\",1"
+        );
+        assert_eq!(
+            lines[2],
+            "2020-09-14T12:26:40Z,-0.51000000,0.00001000,-5100.00,rent <flat>,2"
+        );
+        assert_eq!(lines[3], "");
+    }
+
+    #[test]
+    fn csv_formula() {
+        let csv = HistoryExport::new(Network::Regtest)
+            .columns(&[Column::Amount, Column::Labels])
+            .to_csv(&history_labelled(
+                &["=HYPERLINK(\"http://evil\")", "salary"],
+                &["@SUM(A1)"],
+            ))
+            .unwrap();
+        let lines = csv.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(
+            lines[1],
+            "1.50000000,\"'=HYPERLINK(\"\"http://evil\"\"); salary\""
+        );
+        // amounts are numbers, their sign stays
+        assert_eq!(lines[2], "-0.51000000,'@SUM(A1)");
+        for text in ["+1", "-1", "=1", "@1"].iter() {
+            assert_eq!(csv_text(text.to_string()), format!("'{}", text));
+        }
+        assert_eq!(csv_text("rent".to_string()), "rent");
+    }
+
+    #[test]
+    fn ofx() {
+        let ofx = HistoryExport::new(Network::Regtest)
+            .account_id("savings")
+            .to_ofx(&history())
+            .unwrap();
+        assert!(ofx.contains("<CURDEF>XBT</CURDEF>"));
+        assert!(ofx.contains("<ACCTID>savings</ACCTID>"));
+        assert!(ofx.contains("<DTSTART>20200913122640</DTSTART>"));
+        assert!(ofx.contains("<TRNTYPE>DEBIT</TRNTYPE>"));
+        assert!(ofx.contains("<TRNAMT>-0.51000000</TRNAMT>"));
+        assert!(ofx.contains("<MEMO>rent &lt;flat&gt;</MEMO>"));
+
+        let rates = FixedRate;
+        let ofx = HistoryExport::new(Network::Regtest)
+            .fiat_rates(&rates)
+            .query(HistoryQuery::new().direction(Direction::Outgoing))
+            .to_ofx(&history())
+            .unwrap();
+        assert!(ofx.contains("<CURDEF>USD</CURDEF>"));
+        assert!(ofx.contains("<TRNAMT>-5100.00</TRNAMT>"));
+        assert_eq!(ofx.matches("<STMTTRN>").count(), 1);
+    }
+}
@@ -0,0 +1,371 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Backend failover
+//!
+//! FailoverBackend asks backends in the order configured, e.g. a personal Electrum server
+//! before a public Esplora. A backend that fails a request is passed over for the next one
+//! until the retry interval passed, then the preferred backend is asked again.
+//!
+//! probe asks every backend for its tip and measures its latency. A backend lagging behind the
+//! best tip by more than max_lag blocks is passed over as if it failed.
+//!
+//! Switching in the middle of a sync, the next backend must know the header served last, or
+//! the sync would mistake a backend behind or on an other chain for a reorg. A backend that
+//! does not is passed over.
+//!
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHeader, OutPoint, Transaction, Txid};
+
+use backend::{ChainBackend, TxStatus};
+use error::Error;
+use fee::{FeeHistogram, FeeRate};
+
+/// Health of a backend
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    /// tip of the last probe
+    pub tip: Option<u32>,
+    /// latency of the last probe
+    pub latency: Option<Duration>,
+    /// requests failed since the last success
+    pub failures: u32,
+    /// last failure, or last probe that found the backend lagging
+    failed: Option<Instant>,
+}
+
+/// Backends asked in order, passing over those that fail
+pub struct FailoverBackend {
+    backends: Vec<(String, Box<dyn ChainBackend>)>,
+    health: RefCell<Vec<Health>>,
+    active: Cell<usize>,
+    retry: Duration,
+    max_lag: u32,
+    /// height and hash of the header served last
+    served: Cell<Option<(u32, BlockHash)>>,
+}
+
+impl Default for FailoverBackend {
+    fn default() -> FailoverBackend {
+        FailoverBackend::new()
+    }
+}
+
+impl FailoverBackend {
+    /// no backends yet, failed ones are retried after a minute
+    pub fn new() -> FailoverBackend {
+        FailoverBackend {
+            backends: Vec::new(),
+            health: RefCell::new(Vec::new()),
+            active: Cell::new(0),
+            retry: Duration::from_secs(60),
+            max_lag: 2,
+            served: Cell::new(None),
+        }
+    }
+
+    /// add a backend, asked after those added before
+    pub fn backend<B: ChainBackend + 'static>(mut self, name: &str, backend: B) -> FailoverBackend {
+        self.backends.push((name.to_string(), Box::new(backend)));
+        self.health.borrow_mut().push(Health::default());
+        self
+    }
+
+    /// time a failed backend is passed over
+    pub fn retry_after(mut self, retry: Duration) -> FailoverBackend {
+        self.retry = retry;
+        self
+    }
+
+    /// blocks a backend may lag behind the best tip probed
+    pub fn max_lag(mut self, max_lag: u32) -> FailoverBackend {
+        self.max_lag = max_lag;
+        self
+    }
+
+    /// name of the backend that served the last request
+    pub fn active(&self) -> Option<&str> {
+        self.backends
+            .get(self.active.get())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// health of the backends in the order configured
+    pub fn health(&self) -> Vec<(String, Health)> {
+        self.backends
+            .iter()
+            .map(|(name, _)| name.clone())
+            .zip(self.health.borrow().iter().cloned())
+            .collect()
+    }
+
+    /// ask every backend for its tip, returns their health
+    pub fn probe(&self) -> Vec<(String, Health)> {
+        for (index, (_, backend)) in self.backends.iter().enumerate() {
+            let started = Instant::now();
+            let tip = backend.tip();
+            let mut health = self.health.borrow_mut();
+            match tip {
+                Ok(tip) => {
+                    health[index].tip = Some(tip);
+                    health[index].latency = Some(started.elapsed());
+                    health[index].failures = 0;
+                    health[index].failed = None;
+                }
+                Err(_) => {
+                    health[index].tip = None;
+                    health[index].latency = None;
+                    health[index].failures += 1;
+                    health[index].failed = Some(Instant::now());
+                }
+            }
+        }
+        {
+            let mut health = self.health.borrow_mut();
+            if let Some(best) = health.iter().filter_map(|h| h.tip).max() {
+                for health in health.iter_mut() {
+                    if health.tip.is_some_and(|tip| tip + self.max_lag < best) {
+                        health.failed = Some(Instant::now());
+                    }
+                }
+            }
+        }
+        self.health()
+    }
+
+    /// backends in the order to ask, those passed over last
+    fn order(&self) -> Vec<usize> {
+        let health = self.health.borrow();
+        let (mut order, passed): (Vec<usize>, Vec<usize>) =
+            (0..self.backends.len()).partition(|i| {
+                health[*i]
+                    .failed
+                    .is_none_or(|at| at.elapsed() >= self.retry)
+            });
+        order.extend(passed);
+        order
+    }
+
+    /// false if the backend does not know the header served last
+    fn consistent(&self, index: usize) -> bool {
+        match self.served.get() {
+            Some((height, hash)) => match self.backends[index].1.header(height) {
+                Ok(Some(header)) => header.block_hash() == hash,
+                _ => false,
+            },
+            None => true,
+        }
+    }
+
+    fn call<T, F>(&self, request: F) -> Result<T, Error>
+    where
+        F: Fn(&dyn ChainBackend) -> Result<T, Error>,
+    {
+        let mut last = Error::Backend("no backend configured");
+        for index in self.order() {
+            if index != self.active.get() && !self.consistent(index) {
+                last = Error::Backend("backend disagrees with the chain served before");
+                continue;
+            }
+            match request(self.backends[index].1.as_ref()) {
+                Ok(result) => {
+                    let mut health = self.health.borrow_mut();
+                    health[index].failures = 0;
+                    self.active.set(index);
+                    return Ok(result);
+                }
+                Err(error) => {
+                    let mut health = self.health.borrow_mut();
+                    health[index].failures += 1;
+                    health[index].failed = Some(Instant::now());
+                    last = error;
+                }
+            }
+        }
+        Err(last)
+    }
+}
+
+impl ChainBackend for FailoverBackend {
+    fn tip(&self) -> Result<u32, Error> {
+        self.call(|b| b.tip())
+    }
+
+    fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        let header = self.call(|b| b.header(height))?;
+        if let Some(ref header) = header {
+            self.served.set(Some((height, header.block_hash())));
+        }
+        Ok(header)
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.call(|b| b.block(hash))
+    }
+
+    fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+        self.call(|b| b.filter(hash))
+    }
+
+    fn filter_header(&self, hash: &BlockHash) -> Result<Option<FilterHeader>, Error> {
+        self.call(|b| b.filter_header(hash))
+    }
+
+    fn tx_status(&self, txid: &Txid) -> Result<TxStatus, Error> {
+        self.call(|b| b.tx_status(txid))
+    }
+
+    fn spender(&self, point: &OutPoint) -> Result<Option<Txid>, Error> {
+        self.call(|b| b.spender(point))
+    }
+
+    fn mempool_accept(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Option<Vec<Option<String>>>, Error> {
+        self.call(|b| b.mempool_accept(transactions))
+    }
+
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+        self.call(|b| b.broadcast(transaction))
+    }
+
+    fn broadcast_package(&self, transactions: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        self.call(|b| b.broadcast_package(transactions))
+    }
+
+    fn feerate(&self, target: u16) -> Result<FeeRate, Error> {
+        self.call(|b| b.feerate(target))
+    }
+
+    fn fee_histogram(&self) -> Result<Option<FeeHistogram>, Error> {
+        self.call(|b| b.fee_histogram())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct Server {
+        blocks: Vec<Block>,
+        down: Rc<Cell<bool>>,
+    }
+
+    impl ChainBackend for Server {
+        fn tip(&self) -> Result<u32, Error> {
+            if self.down.get() {
+                return Err(Error::Backend("down"));
+            }
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            self.tip()?;
+            Ok(self.blocks.get(height as usize).map(|b| b.header))
+        }
+
+        fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+            self.tip()?;
+            self.blocks
+                .iter()
+                .find(|b| b.block_hash() == *hash)
+                .cloned()
+                .ok_or(Error::Backend("unknown block"))
+        }
+
+        fn filter(&self, _: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            Ok(None)
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    fn chain(length: usize, fork: u32) -> Vec<Block> {
+        let mut blocks = vec![genesis_block(Network::Testnet)];
+        for height in 1..length {
+            let mut block = blocks[0].clone();
+            block.header.prev_blockhash = blocks.last().unwrap().block_hash();
+            block.header.nonce = height as u32 + fork;
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn failover_backend() {
+        let down = Rc::new(Cell::new(false));
+        let server = |blocks, down: &Rc<Cell<bool>>| Server {
+            blocks,
+            down: down.clone(),
+        };
+        let backend = FailoverBackend::new()
+            .backend("personal", server(chain(5, 0), &down))
+            .backend("forked", server(chain(5, 100), &Rc::new(Cell::new(false))))
+            .backend("lagging", server(chain(2, 0), &Rc::new(Cell::new(false))))
+            .backend("public", server(chain(5, 0), &Rc::new(Cell::new(false))))
+            .retry_after(Duration::from_millis(50));
+        let probed = backend.probe();
+        assert_eq!(probed[0].1.tip, Some(4));
+        assert!(probed[0].1.latency.is_some());
+
+        assert_eq!(backend.tip().unwrap(), 4);
+        let header = backend.header(3).unwrap().unwrap();
+        assert_eq!(backend.active(), Some("personal"));
+
+        // the forked backend does not know the header served, the lagging one not its height
+        down.set(true);
+        assert_eq!(
+            backend.header(4).unwrap().unwrap().prev_blockhash,
+            header.block_hash()
+        );
+        assert_eq!(backend.active(), Some("public"));
+        assert_eq!(backend.health()[0].1.failures, 1);
+
+        // the preferred backend is asked again after the retry interval
+        down.set(false);
+        backend.tip().unwrap();
+        assert_eq!(backend.active(), Some("public"));
+        std::thread::sleep(Duration::from_millis(60));
+        backend.tip().unwrap();
+        assert_eq!(backend.active(), Some("personal"));
+
+        let lagging = FailoverBackend::new()
+            .backend("lagging", server(chain(2, 0), &Rc::new(Cell::new(false))))
+            .backend("public", server(chain(5, 0), &Rc::new(Cell::new(false))));
+        lagging.probe();
+        assert_eq!(lagging.tip().unwrap(), 4);
+        assert_eq!(lagging.active(), Some("public"));
+        assert!(FailoverBackend::new().tip().is_err());
+    }
+}
@@ -0,0 +1,746 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Fees
+//!
+//! Fee rates and estimators of the fee rate needed to confirm within a number of blocks.
+//! Estimators that ask a node or a server do so through a user supplied client.
+//!
+//! AggregateFeeEstimator asks several estimators, e.g. the own node, mempool.space and Esplora,
+//! and takes the median of their estimates after rejecting outliers, so a single faulty or
+//! malicious source can not make the wallet overpay or get stuck.
+//!
+//! FeeHistogram is the mempool by fee rate as a backend serves it. It estimates the fee rate
+//! to get into the next blocks from the current mempool, and user interfaces chart it to show
+//! congestion.
+//!
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use error::Error;
+use json::{parse_json, Json};
+
+/// Fee rate in satoshis per 1000 weight units
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub fn from_sat_per_kwu(sat_per_kwu: u64) -> FeeRate {
+        FeeRate(sat_per_kwu)
+    }
+
+    /// the unit of most fee estimators and user interfaces
+    pub fn from_sat_per_vb(sat_per_vb: u64) -> FeeRate {
+        FeeRate(sat_per_vb * 250)
+    }
+
+    /// the unit of bitcoind
+    pub fn from_sat_per_kvb(sat_per_kvb: u64) -> FeeRate {
+        FeeRate(sat_per_kvb / 4)
+    }
+
+    pub fn as_sat_per_kwu(&self) -> u64 {
+        self.0
+    }
+
+    pub fn as_sat_per_vb(&self) -> f64 {
+        self.0 as f64 / 250.0
+    }
+
+    /// fee for a weight, rounded up
+    pub fn fee(&self, weight: u64) -> u64 {
+        (self.0 * weight).div_ceil(1000)
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.2} sat/vB", self.as_sat_per_vb())
+    }
+}
+
+/// Sanity bounds of the fees of built transactions
+/// Protect against mistyped fee rates and broken estimators. The ceilings can be lifted for a
+/// transaction, the minimum relay fee rate not, as nodes would not relay the transaction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FeeBounds {
+    /// lowest fee rate nodes relay
+    pub min_relay_feerate: FeeRate,
+    pub max_feerate: FeeRate,
+    /// highest absolute fee
+    pub max_fee: u64,
+    /// highest fee in percent of the amount paid
+    pub max_fee_percent: u64,
+}
+
+impl Default for FeeBounds {
+    /// 1 sat/vB relay minimum, at most 1000 sat/vB, 0.1 BTC and 10% of the amount
+    fn default() -> FeeBounds {
+        FeeBounds {
+            min_relay_feerate: FeeRate::from_sat_per_vb(1),
+            max_feerate: FeeRate::from_sat_per_vb(1000),
+            max_fee: 10_000_000,
+            max_fee_percent: 10,
+        }
+    }
+}
+
+impl FeeBounds {
+    /// check a fee rate before coins are selected
+    pub fn check_feerate(&self, feerate: FeeRate, ceilings: bool) -> Result<(), Error> {
+        if feerate < self.min_relay_feerate {
+            return Err(Error::FeeBounds(
+                "fee rate below the minimum relay fee rate",
+            ));
+        }
+        if ceilings && feerate > self.max_feerate {
+            return Err(Error::FeeBounds("fee rate above the maximum"));
+        }
+        Ok(())
+    }
+
+    /// check the fee of a transaction of weight paying amount to others
+    /// The share of the amount is not limited for fees at the minimum relay fee rate.
+    pub fn check_fee(&self, fee: u64, weight: u64, amount: u64) -> Result<(), Error> {
+        if fee > self.max_fee {
+            return Err(Error::FeeBounds("fee above the maximum"));
+        }
+        // paying the least nodes relay is never absurd, even for small amounts
+        if fee > self.min_relay_feerate.fee(weight)
+            && fee as u128 * 100 > amount as u128 * self.max_fee_percent as u128
+        {
+            return Err(Error::FeeBounds(
+                "fee above the maximum share of the amount",
+            ));
+        }
+        if fee as u128 * 1000 > self.max_feerate.as_sat_per_kwu() as u128 * weight as u128 {
+            return Err(Error::FeeBounds("fee rate above the maximum"));
+        }
+        Ok(())
+    }
+}
+
+/// Estimator of fee rates
+pub trait FeeEstimator {
+    /// fee rate to confirm within target blocks
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error>;
+}
+
+/// Manually set fee rates
+/// A target without its own rate uses the rate of the next lower target, or of the lowest target
+/// if there is none lower.
+#[derive(Clone, Debug)]
+pub struct StaticFeeEstimator {
+    rates: BTreeMap<u16, FeeRate>,
+}
+
+impl StaticFeeEstimator {
+    /// the same rate for all targets
+    pub fn new(feerate: FeeRate) -> StaticFeeEstimator {
+        let mut rates = BTreeMap::new();
+        rates.insert(1, feerate);
+        StaticFeeEstimator { rates }
+    }
+
+    /// set the rate for a target
+    pub fn with_target(mut self, target: u16, feerate: FeeRate) -> StaticFeeEstimator {
+        self.rates.insert(target, feerate);
+        self
+    }
+}
+
+impl FeeEstimator for StaticFeeEstimator {
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+        self.rates
+            .range(..=target)
+            .next_back()
+            .or_else(|| self.rates.iter().next())
+            .map(|(_, r)| *r)
+            .ok_or(Error::FeeEstimation("no fee rate"))
+    }
+}
+
+/// virtual size of a block
+const BLOCK_VSIZE: u64 = 1_000_000;
+
+/// The mempool by fee rate, as Electrum's mempool.get_fee_histogram
+/// Buckets of fee rate and virtual size, by descending fee rate. A bucket holds the virtual size
+/// of transactions paying at least its fee rate but less than that of the bucket before.
+/// Estimates assume miners fill blocks by fee rate from the top of the mempool.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeeHistogram {
+    buckets: Vec<(FeeRate, u64)>,
+}
+
+impl FeeHistogram {
+    /// buckets in any order, those of the same fee rate are merged
+    pub fn new(buckets: Vec<(FeeRate, u64)>) -> FeeHistogram {
+        let mut merged = BTreeMap::new();
+        for (feerate, vsize) in buckets {
+            *merged.entry(feerate).or_insert(0) += vsize;
+        }
+        FeeHistogram {
+            buckets: merged.into_iter().rev().collect(),
+        }
+    }
+
+    /// histogram of fee and virtual size of each transaction, binned by whole sat/vB
+    pub fn from_transactions<I: IntoIterator<Item = (u64, u64)>>(transactions: I) -> FeeHistogram {
+        FeeHistogram::new(
+            transactions
+                .into_iter()
+                .filter(|(_, vsize)| *vsize > 0)
+                .map(|(fee, vsize)| (FeeRate::from_sat_per_vb(fee / vsize), vsize))
+                .collect(),
+        )
+    }
+
+    /// parse `[[fee rate, vsize], ...]` with fee rates in sat/vB, as served by Electrum and
+    /// by the /mempool endpoint of Esplora
+    pub fn from_json(json: &str) -> Result<FeeHistogram, Error> {
+        match parse_json(json) {
+            Ok(histogram) => FeeHistogram::from_array(&histogram),
+            Err(_) => Err(Error::FeeEstimation("invalid fee histogram")),
+        }
+    }
+
+    fn from_array(histogram: &Json) -> Result<FeeHistogram, Error> {
+        let buckets = match histogram {
+            Json::Array(buckets) => buckets,
+            _ => return Err(Error::FeeEstimation("invalid fee histogram")),
+        };
+        let mut parsed = Vec::new();
+        for bucket in buckets {
+            match bucket {
+                Json::Array(pair) => match pair.as_slice() {
+                    [Json::Number(feerate), Json::Number(vsize)]
+                        if *feerate >= 0.0 && *vsize >= 0.0 =>
+                    {
+                        parsed.push((
+                            FeeRate::from_sat_per_kwu((feerate * 250.0).round() as u64),
+                            *vsize as u64,
+                        ))
+                    }
+                    _ => return Err(Error::FeeEstimation("invalid fee histogram")),
+                },
+                _ => return Err(Error::FeeEstimation("invalid fee histogram")),
+            }
+        }
+        Ok(FeeHistogram::new(parsed))
+    }
+
+    /// buckets by descending fee rate
+    pub fn buckets(&self) -> &[(FeeRate, u64)] {
+        &self.buckets
+    }
+
+    /// virtual size of the mempool
+    pub fn vsize(&self) -> u64 {
+        self.buckets.iter().map(|(_, vsize)| vsize).sum()
+    }
+
+    /// virtual size paying at least the fee rate of each bucket, for charts of congestion
+    pub fn cumulative(&self) -> Vec<(FeeRate, u64)> {
+        let mut total = 0;
+        self.buckets
+            .iter()
+            .map(|(feerate, vsize)| {
+                total += vsize;
+                (*feerate, total)
+            })
+            .collect()
+    }
+
+    /// fee rate to be within the first vsize of the mempool, the minimum relay fee rate if
+    /// the mempool is smaller
+    pub fn feerate_within(&self, vsize: u64) -> FeeRate {
+        self.cumulative()
+            .into_iter()
+            .find(|(_, total)| *total >= vsize)
+            .map_or(FeeRate::from_sat_per_vb(1), |(feerate, _)| feerate)
+            .max(FeeRate::from_sat_per_vb(1))
+    }
+}
+
+impl FeeEstimator for FeeHistogram {
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+        Ok(self.feerate_within(u64::from(target.max(1)) * BLOCK_VSIZE))
+    }
+}
+
+/// error code of JSON-RPC for a method the server does not know
+pub const RPC_METHOD_NOT_FOUND: i64 = -32601;
+
+/// The error a JSON-RPC server replied with
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+/// A JSON-RPC client of bitcoind
+/// Errors the server replies with are returned as Error::Rpc.
+pub trait JsonRpc {
+    /// call method with params given as JSON array, returns the JSON result
+    fn call(&self, method: &str, params: &str) -> Result<String, Error>;
+}
+
+impl<R: JsonRpc> JsonRpc for &R {
+    fn call(&self, method: &str, params: &str) -> Result<String, Error> {
+        (*self).call(method, params)
+    }
+}
+
+/// Estimates with estimatesmartfee of bitcoind
+pub struct BitcoindFeeEstimator<R: JsonRpc> {
+    rpc: R,
+}
+
+impl<R: JsonRpc> BitcoindFeeEstimator<R> {
+    pub fn new(rpc: R) -> BitcoindFeeEstimator<R> {
+        BitcoindFeeEstimator { rpc }
+    }
+}
+
+impl<R: JsonRpc> FeeEstimator for BitcoindFeeEstimator<R> {
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+        let result = self
+            .rpc
+            .call("estimatesmartfee", format!("[{}]", target).as_str())?;
+        // BTC per kvB
+        let btc_per_kvb = *numbers(result.as_str())?
+            .get("feerate")
+            .ok_or(Error::FeeEstimation("bitcoind has no estimate"))?;
+        Ok(FeeRate::from_sat_per_kvb((btc_per_kvb * 1e8).round() as u64))
+    }
+}
+
+/// An HTTP client
+pub trait HttpGet {
+    /// GET the url and return the body
+    fn get(&self, url: &str) -> Result<String, Error>;
+}
+
+/// Estimates by the /fee-estimates endpoint of an Esplora server
+/// Esplora gives estimates for some targets only, the next lower target is used for others.
+pub struct EsploraFeeEstimator<H: HttpGet> {
+    url: String,
+    http: H,
+}
+
+impl<H: HttpGet> EsploraFeeEstimator<H> {
+    /// url of the API e.g. <https://blockstream.info/api>
+    pub fn new(url: &str, http: H) -> EsploraFeeEstimator<H> {
+        EsploraFeeEstimator {
+            url: url.trim_end_matches('/').to_string(),
+            http,
+        }
+    }
+
+    /// histogram of the mempool by the /mempool endpoint
+    pub fn histogram(&self) -> Result<FeeHistogram, Error> {
+        let body = self.http.get(format!("{}/mempool", self.url).as_str())?;
+        match parse_json(body.as_str()) {
+            Ok(Json::Object(mempool)) => match mempool.get("fee_histogram") {
+                Some(histogram) => FeeHistogram::from_array(histogram),
+                None => Err(Error::FeeEstimation("Esplora has no fee histogram")),
+            },
+            _ => Err(Error::FeeEstimation("invalid fee histogram")),
+        }
+    }
+}
+
+impl<H: HttpGet> FeeEstimator for EsploraFeeEstimator<H> {
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+        let body = self
+            .http
+            .get(format!("{}/fee-estimates", self.url).as_str())?;
+        // sat per vB by target
+        let mut rates = BTreeMap::new();
+        for (key, value) in numbers(body.as_str())? {
+            if let Ok(t) = key.parse::<u16>() {
+                rates.insert(t, FeeRate::from_sat_per_kwu((value * 250.0).round() as u64));
+            }
+        }
+        if rates.is_empty() {
+            return Err(Error::FeeEstimation("Esplora has no estimate"));
+        }
+        StaticFeeEstimator { rates }.estimate(target)
+    }
+}
+
+/// Estimates by the /v1/fees/recommended endpoint of a mempool.space server
+/// The recommendations are mapped to targets 1, 3, 6, 144 and 1008, the next lower target
+/// is used for others.
+pub struct MempoolSpaceFeeEstimator<H: HttpGet> {
+    url: String,
+    http: H,
+}
+
+impl<H: HttpGet> MempoolSpaceFeeEstimator<H> {
+    /// url of the API e.g. <https://mempool.space/api>
+    pub fn new(url: &str, http: H) -> MempoolSpaceFeeEstimator<H> {
+        MempoolSpaceFeeEstimator {
+            url: url.trim_end_matches('/').to_string(),
+            http,
+        }
+    }
+}
+
+impl<H: HttpGet> FeeEstimator for MempoolSpaceFeeEstimator<H> {
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+        let body = self
+            .http
+            .get(format!("{}/v1/fees/recommended", self.url).as_str())?;
+        let mut rates = BTreeMap::new();
+        for (key, value) in numbers(body.as_str())? {
+            let t = match key.as_str() {
+                "fastestFee" => 1,
+                "halfHourFee" => 3,
+                "hourFee" => 6,
+                "economyFee" => 144,
+                "minimumFee" => 1008,
+                _ => continue,
+            };
+            rates.insert(t, FeeRate::from_sat_per_kwu((value * 250.0).round() as u64));
+        }
+        if rates.is_empty() {
+            return Err(Error::FeeEstimation("mempool.space has no estimate"));
+        }
+        StaticFeeEstimator { rates }.estimate(target)
+    }
+}
+
+/// Median of the estimates of several estimators
+/// Estimates more than max_deviation times above or below the median of all are rejected and
+/// the median of the rest is taken. Results are cached per target for the ttl.
+pub struct AggregateFeeEstimator<'a> {
+    sources: Vec<Box<dyn FeeEstimator + 'a>>,
+    min_sources: usize,
+    max_deviation: f64,
+    ttl: Duration,
+    cache: RefCell<HashMap<u16, (Instant, FeeRate)>>,
+}
+
+impl<'a> Default for AggregateFeeEstimator<'a> {
+    fn default() -> AggregateFeeEstimator<'a> {
+        AggregateFeeEstimator::new()
+    }
+}
+
+impl<'a> AggregateFeeEstimator<'a> {
+    /// without sources, one estimate suffices, reject estimates off by a factor of 2,
+    /// cache for a minute
+    pub fn new() -> AggregateFeeEstimator<'a> {
+        AggregateFeeEstimator {
+            sources: Vec::new(),
+            min_sources: 1,
+            max_deviation: 2.0,
+            ttl: Duration::from_secs(60),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// add an estimator to ask
+    pub fn source<E: FeeEstimator + 'a>(mut self, estimator: E) -> AggregateFeeEstimator<'a> {
+        self.sources.push(Box::new(estimator));
+        self
+    }
+
+    /// estimates that must remain after outlier rejection
+    pub fn min_sources(mut self, min_sources: usize) -> AggregateFeeEstimator<'a> {
+        self.min_sources = min_sources;
+        self
+    }
+
+    /// factor by which an estimate may deviate from the median, at least 1
+    pub fn max_deviation(mut self, max_deviation: f64) -> AggregateFeeEstimator<'a> {
+        self.max_deviation = max_deviation.max(1.0);
+        self
+    }
+
+    /// how long an estimate is cached
+    pub fn ttl(mut self, ttl: Duration) -> AggregateFeeEstimator<'a> {
+        self.ttl = ttl;
+        self
+    }
+
+    /// forget cached estimates
+    pub fn clear(&self) {
+        self.cache.borrow_mut().clear();
+    }
+
+    fn aggregate(&self, target: u16) -> Result<FeeRate, Error> {
+        let mut rates = self
+            .sources
+            .iter()
+            .filter_map(|s| s.estimate(target).ok())
+            .map(|r| r.as_sat_per_kwu())
+            .collect::<Vec<_>>();
+        rates.sort_unstable();
+        if let Some(all) = median(&rates) {
+            let all = all as f64;
+            let deviation = self.max_deviation;
+            rates.retain(|r| *r as f64 <= all * deviation && *r as f64 * deviation >= all);
+        }
+        if rates.is_empty() || rates.len() < self.min_sources {
+            return Err(Error::FeeEstimation("too few estimates agree"));
+        }
+        Ok(FeeRate::from_sat_per_kwu(median(&rates).unwrap_or(0)))
+    }
+}
+
+impl<'a> FeeEstimator for AggregateFeeEstimator<'a> {
+    fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+        if let Some((at, rate)) = self.cache.borrow().get(&target) {
+            if at.elapsed() < self.ttl {
+                return Ok(*rate);
+            }
+        }
+        let rate = self.aggregate(target)?;
+        self.cache
+            .borrow_mut()
+            .insert(target, (Instant::now(), rate));
+        Ok(rate)
+    }
+}
+
+// median of sorted values, the mean of the middle two of an even number
+fn median(sorted: &[u64]) -> Option<u64> {
+    let middle = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(sorted[middle]),
+        _ => Some((sorted[middle - 1] + sorted[middle]).div_ceil(2)),
+    }
+}
+
+// numeric members of a JSON object
+fn numbers(json: &str) -> Result<BTreeMap<String, f64>, Error> {
+    match parse_json(json)? {
+        Json::Object(object) => Ok(object
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Json::Number(number) => Some((key, number)),
+                _ => None,
+            })
+            .collect()),
+        _ => Err(Error::FeeEstimation("estimates are not a JSON object")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn fee_rate() {
+        let rate = FeeRate::from_sat_per_vb(2);
+        assert_eq!(rate, FeeRate::from_sat_per_kvb(2000));
+        assert_eq!(rate.fee(4), 2);
+        assert_eq!(rate.fee(5), 3);
+        assert_eq!(rate.to_string(), "2.00 sat/vB");
+    }
+
+    #[test]
+    fn static_estimator() {
+        let estimator = StaticFeeEstimator::new(FeeRate::from_sat_per_vb(20))
+            .with_target(6, FeeRate::from_sat_per_vb(10))
+            .with_target(144, FeeRate::from_sat_per_vb(1));
+        assert_eq!(estimator.estimate(1).unwrap(), FeeRate::from_sat_per_vb(20));
+        assert_eq!(estimator.estimate(5).unwrap(), FeeRate::from_sat_per_vb(20));
+        assert_eq!(estimator.estimate(6).unwrap(), FeeRate::from_sat_per_vb(10));
+        assert_eq!(
+            estimator.estimate(1000).unwrap(),
+            FeeRate::from_sat_per_vb(1)
+        );
+        let later = StaticFeeEstimator {
+            rates: vec![(3, FeeRate::from_sat_per_vb(5))].into_iter().collect(),
+        };
+        assert_eq!(later.estimate(1).unwrap(), FeeRate::from_sat_per_vb(5));
+    }
+
+    struct Bitcoind;
+
+    impl JsonRpc for Bitcoind {
+        fn call(&self, method: &str, params: &str) -> Result<String, Error> {
+            assert_eq!(method, "estimatesmartfee");
+            match params {
+                "[2]" => Ok(r#"{"feerate": 0.00012345, "blocks": 2}"#.to_string()),
+                "[3]" => {
+                    Ok(r#"{"blocks": 3, "debug": {"feerate": 1}, "feerate": 0.0002}"#.to_string())
+                }
+                _ => Ok(
+                    r#"{"errors": ["Insufficient data or no feerate found"], "blocks": 0}"#
+                        .to_string(),
+                ),
+            }
+        }
+    }
+
+    struct Esplora;
+
+    impl HttpGet for Esplora {
+        fn get(&self, url: &str) -> Result<String, Error> {
+            match url {
+                "https://blockstream.info/api/mempool" => Ok(
+                    r#"{"count":3,"vsize":1250,"total_fee":3250,"fee_histogram":[[3,1000],[1,250]]}"#
+                        .to_string(),
+                ),
+                _ => {
+                    assert_eq!(url, "https://blockstream.info/api/fee-estimates");
+                    Ok(r#"{"1":87.882,"2":87.882,"3":87.882,"6":68.285,"144":1.027}"#.to_string())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn remote_estimators() {
+        let bitcoind = BitcoindFeeEstimator::new(Bitcoind);
+        assert_eq!(
+            bitcoind.estimate(2).unwrap(),
+            FeeRate::from_sat_per_kvb(12345)
+        );
+        assert!(bitcoind.estimate(1).is_err());
+        // members of nested objects are not mistaken for those of the reply
+        assert_eq!(
+            bitcoind.estimate(3).unwrap(),
+            FeeRate::from_sat_per_kvb(20_000)
+        );
+
+        let esplora = EsploraFeeEstimator::new("https://blockstream.info/api/", Esplora);
+        assert_eq!(
+            esplora.estimate(1).unwrap(),
+            FeeRate::from_sat_per_kwu(21971)
+        );
+        assert_eq!(
+            esplora.estimate(10).unwrap(),
+            FeeRate::from_sat_per_kwu(17071)
+        );
+        assert_eq!(
+            esplora.estimate(200).unwrap(),
+            FeeRate::from_sat_per_kwu(257)
+        );
+
+        let mempool = MempoolSpaceFeeEstimator::new("https://mempool.space/api", MempoolSpace);
+        assert_eq!(mempool.estimate(2).unwrap(), FeeRate::from_sat_per_vb(21));
+        assert_eq!(mempool.estimate(6).unwrap(), FeeRate::from_sat_per_vb(15));
+        assert_eq!(mempool.estimate(5000).unwrap(), FeeRate::from_sat_per_vb(1));
+    }
+
+    struct MempoolSpace;
+
+    impl HttpGet for MempoolSpace {
+        fn get(&self, url: &str) -> Result<String, Error> {
+            assert_eq!(url, "https://mempool.space/api/v1/fees/recommended");
+            Ok(
+                r#"{"fastestFee":21,"halfHourFee":18,"hourFee":15,"economyFee":4,"minimumFee":1}"#
+                    .to_string(),
+            )
+        }
+    }
+
+    #[test]
+    fn fee_histogram() {
+        let histogram =
+            FeeHistogram::from_json("[[20, 300000], [5.5, 900000], [20, 100000], [2, 2000000]]")
+                .unwrap();
+        assert_eq!(
+            histogram.cumulative(),
+            vec![
+                (FeeRate::from_sat_per_vb(20), 400_000),
+                (FeeRate::from_sat_per_kwu(1375), 1_300_000),
+                (FeeRate::from_sat_per_vb(2), 3_300_000),
+            ]
+        );
+        assert_eq!(histogram.vsize(), 3_300_000);
+        assert_eq!(
+            histogram.estimate(1).unwrap(),
+            FeeRate::from_sat_per_kwu(1375)
+        );
+        assert_eq!(histogram.estimate(3).unwrap(), FeeRate::from_sat_per_vb(2));
+        assert_eq!(histogram.estimate(4).unwrap(), FeeRate::from_sat_per_vb(1));
+        assert!(FeeHistogram::from_json("[[20]]").is_err());
+
+        let transactions = FeeHistogram::from_transactions(vec![(2250, 150), (1000, 100), (0, 0)]);
+        assert_eq!(
+            transactions.buckets(),
+            &[
+                (FeeRate::from_sat_per_vb(15), 150),
+                (FeeRate::from_sat_per_vb(10), 100)
+            ][..]
+        );
+
+        let esplora = EsploraFeeEstimator::new("https://blockstream.info/api", Esplora);
+        assert_eq!(
+            esplora.histogram().unwrap().buckets(),
+            &[
+                (FeeRate::from_sat_per_vb(3), 1000),
+                (FeeRate::from_sat_per_vb(1), 250)
+            ][..]
+        );
+    }
+
+    /// counts its estimates
+    struct Counting<'a>(StaticFeeEstimator, &'a Cell<usize>);
+
+    impl<'a> FeeEstimator for Counting<'a> {
+        fn estimate(&self, target: u16) -> Result<FeeRate, Error> {
+            self.1.set(self.1.get() + 1);
+            self.0.estimate(target)
+        }
+    }
+
+    #[test]
+    fn aggregate_estimator() {
+        let calls = Cell::new(0);
+        let rate = |sat_per_vb| StaticFeeEstimator::new(FeeRate::from_sat_per_vb(sat_per_vb));
+        let aggregate = AggregateFeeEstimator::new()
+            .source(Counting(rate(10), &calls))
+            .source(rate(12))
+            .source(rate(11))
+            .source(rate(500))
+            .source(BitcoindFeeEstimator::new(Bitcoind));
+        // the outlier is rejected, the failing node ignored
+        assert_eq!(aggregate.estimate(1).unwrap(), FeeRate::from_sat_per_vb(11));
+        assert_eq!(aggregate.estimate(1).unwrap(), FeeRate::from_sat_per_vb(11));
+        assert_eq!(calls.get(), 1);
+        aggregate.clear();
+        aggregate.estimate(1).unwrap();
+        assert_eq!(calls.get(), 2);
+
+        let disagreeing = AggregateFeeEstimator::new()
+            .source(rate(1))
+            .source(rate(100))
+            .min_sources(2)
+            .ttl(Duration::from_secs(0));
+        assert!(disagreeing.estimate(1).is_err());
+        assert!(AggregateFeeEstimator::new().estimate(1).is_err());
+    }
+}
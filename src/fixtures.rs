@@ -0,0 +1,112 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Test fixtures
+//!
+//! A master account with an account, and coins funded in the block after genesis, as most
+//! tests of the wallet start from.
+//!
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::{Block, BlockHeader, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+
+use account::{Account, AccountAddressType, MasterAccount, MasterKeyEntropy, Unlocker};
+use coins::Coins;
+
+pub const PASSPHRASE: &str = "correct horse battery staple";
+
+/// a new master account with a P2WPKH account (0, 0) looking ahead 10 keys, and its unlocker
+pub fn master_account(network: Network) -> (MasterAccount, Unlocker) {
+    let mut master = MasterAccount::new(MasterKeyEntropy::Sufficient, network, PASSPHRASE).unwrap();
+    let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+    master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap());
+    (master, unlocker)
+}
+
+/// add a P2WPKH change account (0, 1) looking ahead 10 keys
+pub fn add_change_account(master: &mut MasterAccount, unlocker: &mut Unlocker) {
+    master.add_account(Account::new(unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap());
+}
+
+/// script of the next key of an account
+pub fn next_script(master: &mut MasterAccount, account: (u32, u32)) -> Script {
+    master
+        .get_mut(account)
+        .unwrap()
+        .next_key()
+        .unwrap()
+        .address
+        .script_pubkey()
+}
+
+/// a transaction paying these outputs from a coin that does not exist
+pub fn funding(output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: bitcoin::Txid::default(),
+                vout: 1,
+            },
+            sequence: 0xffffffff,
+            witness: Vec::new(),
+            script_sig: Script::new(),
+        }],
+        output,
+    }
+}
+
+/// the block after genesis with these transactions, its proof of work is not valid
+pub fn block(network: Network, txdata: Vec<Transaction>) -> Block {
+    block_after(&genesis_block(network), txdata)
+}
+
+/// the block after prev with these transactions, its proof of work is not valid
+pub fn block_after(prev: &Block, txdata: Vec<Transaction>) -> Block {
+    let mut block = Block {
+        header: BlockHeader {
+            version: 1,
+            time: prev.header.time + 600,
+            nonce: 0,
+            bits: prev.header.bits,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: bitcoin::TxMerkleNode::default(),
+        },
+        txdata,
+    };
+    block.header.merkle_root = block.merkle_root();
+    block
+}
+
+/// coins of a funding transaction paying these values to new keys of account (0, 0), confirmed
+/// at height 1
+pub fn funded(
+    master: &mut MasterAccount,
+    network: Network,
+    values: &[u64],
+) -> (Coins, Transaction) {
+    let output = values
+        .iter()
+        .map(|value| TxOut {
+            value: *value,
+            script_pubkey: next_script(master, (0, 0)),
+        })
+        .collect();
+    let funding = funding(output);
+    let mut coins = Coins::new();
+    coins.process(master, &block(network, vec![funding.clone()]));
+    (coins, funding)
+}
@@ -0,0 +1,801 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Transaction history
+//!
+//! Transactions of the wallet with their effect on the balance, fee, direction, counterparties,
+//! labels and confirmation. Coins forget the coins a transaction spends once it confirms, so
+//! History::process and History::process_unconfirmed_transaction are called before the methods
+//! of Coins with the same name.
+//!
+//! Long-lived wallets may prune the history: entries confirmed deeper than a depth keep their
+//! summary, i.e. direction, amounts, fee, labels and confirmation, but forget their outputs.
+//! Coins already forget spent coins and their transactions once the spend confirms, so the
+//! pruned history and the current coins bound the growth of wallet files.
+//!
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::{Address, Block, BlockHash, Network, OutPoint, Transaction, TxOut, Txid, VarInt};
+
+use account::MasterAccount;
+use coins::Coins;
+use error::Error;
+use proved::ProvedTransaction;
+use storage::write_atomic;
+
+const HISTORY_FILE_MAGIC: &[u8; 4] = b"RWHS";
+const HISTORY_FILE_VERSION: u8 = 1;
+
+/// Direction of a wallet transaction
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    /// spends no own coin
+    Incoming,
+    /// spends own coins and pays others
+    Outgoing,
+    /// spends own coins and pays only own addresses
+    SelfTransfer,
+}
+
+impl Direction {
+    fn as_u8(&self) -> u8 {
+        match self {
+            Direction::Incoming => 0,
+            Direction::Outgoing => 1,
+            Direction::SelfTransfer => 2,
+        }
+    }
+
+    fn from_u8(n: u8) -> Result<Direction, encode::Error> {
+        match n {
+            0 => Ok(Direction::Incoming),
+            1 => Ok(Direction::Outgoing),
+            2 => Ok(Direction::SelfTransfer),
+            _ => Err(encode::Error::ParseFailed("unknown direction")),
+        }
+    }
+}
+
+/// A transaction in the history of the wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: Txid,
+    pub direction: Direction,
+    /// value of own coins spent
+    pub sent: u64,
+    /// value of own outputs
+    pub received: u64,
+    /// None if the value of a spent output is not known, e.g. of incoming transactions
+    pub fee: Option<u64>,
+    /// own outputs
+    pub own: Vec<TxOut>,
+    /// outputs to others
+    pub counterparties: Vec<TxOut>,
+    /// memos and tags of own coins spent and added since
+    pub labels: Vec<String>,
+    /// block and height of the confirmation, None while pending
+    pub confirmation: Option<(BlockHash, u32)>,
+    /// time of the confirming block or when the transaction was first seen, seconds since epoch
+    pub time: u64,
+}
+
+impl HistoryEntry {
+    /// effect on the balance
+    pub fn net(&self) -> i64 {
+        self.received as i64 - self.sent as i64
+    }
+
+    /// addresses of own outputs and of counterparties
+    pub fn addresses(&self, network: Network) -> Vec<Address> {
+        self.own
+            .iter()
+            .chain(self.counterparties.iter())
+            .filter_map(|o| Address::from_script(&o.script_pubkey, network))
+            .collect()
+    }
+
+    /// true if the outputs of the entry were pruned, see History::prune
+    pub fn is_pruned(&self) -> bool {
+        self.own.is_empty() && self.counterparties.is_empty()
+    }
+
+    /// the SPV proof coins keep while the transaction has own unspent outputs
+    pub fn proof<'a>(&self, coins: &'a Coins) -> Option<&'a ProvedTransaction> {
+        coins.proofs().get(&self.txid)
+    }
+
+    fn add_label(&mut self, label: &str) {
+        if !self.labels.iter().any(|l| l == label) {
+            self.labels.push(label.to_string());
+        }
+    }
+}
+
+impl Encodable for HistoryEntry {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.txid.consensus_encode(&mut w)?;
+        len += self.direction.as_u8().consensus_encode(&mut w)?;
+        len += self.sent.consensus_encode(&mut w)?;
+        len += self.received.consensus_encode(&mut w)?;
+        match self.fee {
+            Some(fee) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += fee.consensus_encode(&mut w)?;
+            }
+            None => len += 0u8.consensus_encode(&mut w)?,
+        }
+        len += self.own.consensus_encode(&mut w)?;
+        len += self.counterparties.consensus_encode(&mut w)?;
+        len += VarInt(self.labels.len() as u64).consensus_encode(&mut w)?;
+        for label in self.labels.iter() {
+            len += label.consensus_encode(&mut w)?;
+        }
+        match self.confirmation {
+            Some((block_hash, height)) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += block_hash.consensus_encode(&mut w)?;
+                len += height.consensus_encode(&mut w)?;
+            }
+            None => len += 0u8.consensus_encode(&mut w)?,
+        }
+        len += self.time.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for HistoryEntry {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<HistoryEntry, encode::Error> {
+        let txid = Txid::consensus_decode(&mut d)?;
+        let direction = Direction::from_u8(u8::consensus_decode(&mut d)?)?;
+        let sent = u64::consensus_decode(&mut d)?;
+        let received = u64::consensus_decode(&mut d)?;
+        let fee = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(u64::consensus_decode(&mut d)?),
+        };
+        let own = Vec::<TxOut>::consensus_decode(&mut d)?;
+        let counterparties = Vec::<TxOut>::consensus_decode(&mut d)?;
+        let mut labels = Vec::new();
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            labels.push(String::consensus_decode(&mut d)?);
+        }
+        let confirmation = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some((
+                BlockHash::consensus_decode(&mut d)?,
+                u32::consensus_decode(&mut d)?,
+            )),
+        };
+        let time = u64::consensus_decode(&mut d)?;
+        Ok(HistoryEntry {
+            txid,
+            direction,
+            sent,
+            received,
+            fee,
+            own,
+            counterparties,
+            labels,
+            confirmation,
+            time,
+        })
+    }
+}
+
+/// Filters and page of History::query
+/// Entries are listed newest first, pending transactions before confirmed ones.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HistoryQuery {
+    direction: Option<Direction>,
+    confirmed: Option<bool>,
+    since: Option<u64>,
+    until: Option<u64>,
+    label: Option<String>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    pub fn new() -> HistoryQuery {
+        HistoryQuery::default()
+    }
+
+    pub fn direction(mut self, direction: Direction) -> HistoryQuery {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// only confirmed or only pending transactions
+    pub fn confirmed(mut self, confirmed: bool) -> HistoryQuery {
+        self.confirmed = Some(confirmed);
+        self
+    }
+
+    /// transactions of this time or later, seconds since epoch
+    pub fn since(mut self, time: u64) -> HistoryQuery {
+        self.since = Some(time);
+        self
+    }
+
+    /// transactions before this time, seconds since epoch
+    pub fn until(mut self, time: u64) -> HistoryQuery {
+        self.until = Some(time);
+        self
+    }
+
+    /// transactions with a label or a memo containing it
+    pub fn label(mut self, label: &str) -> HistoryQuery {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// skip this many matching transactions
+    pub fn offset(mut self, offset: usize) -> HistoryQuery {
+        self.offset = offset;
+        self
+    }
+
+    /// list at most this many transactions
+    pub fn limit(mut self, limit: usize) -> HistoryQuery {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// History of wallet transactions
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct History {
+    entries: HashMap<Txid, HistoryEntry>,
+    /// prune entries confirmed deeper while processing blocks, not serialized
+    prune_depth: Option<u32>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// prune entries confirmed at least depth blocks deep as blocks are processed
+    pub fn with_pruning(mut self, depth: u32) -> History {
+        self.prune_depth = Some(depth);
+        self
+    }
+
+    /// forget the outputs of entries confirmed at least depth blocks deep at height, returns the
+    /// number of entries pruned
+    pub fn prune(&mut self, depth: u32, height: u32) -> usize {
+        let mut pruned = 0;
+        for entry in self.entries.values_mut() {
+            let deep = match entry.confirmation {
+                Some((_, confirmed)) => height + 1 >= confirmed + depth.max(1),
+                None => false,
+            };
+            if deep && !entry.is_pruned() {
+                entry.own = Vec::new();
+                entry.counterparties = Vec::new();
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&HistoryEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// record wallet transactions of a block, call before Coins::process
+    /// Returns true if the history changed.
+    pub fn process(
+        &mut self,
+        master_account: &MasterAccount,
+        coins: &Coins,
+        block: &Block,
+        height: u32,
+    ) -> bool {
+        let block_hash = block.block_hash();
+        let confirmation = Some((block_hash, height));
+        let mut earlier = HashMap::new();
+        let mut modified = false;
+        for transaction in block.txdata.iter() {
+            let txid = transaction.txid();
+            let entry = match self.entries.remove(&txid) {
+                Some(mut entry) => {
+                    modified |= entry.confirmation != confirmation;
+                    entry.confirmation = confirmation;
+                    entry.time = block.header.time as u64;
+                    Some(entry)
+                }
+                None => Self::entry(
+                    master_account,
+                    coins,
+                    &earlier,
+                    transaction,
+                    confirmation,
+                    block.header.time as u64,
+                ),
+            };
+            if let Some(entry) = entry {
+                for (vout, output) in transaction.output.iter().enumerate() {
+                    let point = OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    };
+                    earlier.insert(point, output.clone());
+                }
+                modified = true;
+                self.entries.insert(txid, entry);
+            }
+        }
+        if let Some(depth) = self.prune_depth {
+            modified |= self.prune(depth, height) > 0;
+        }
+        modified
+    }
+
+    /// record a wallet transaction seen unconfirmed, call before
+    /// Coins::process_unconfirmed_transaction
+    pub fn process_unconfirmed_transaction(
+        &mut self,
+        master_account: &MasterAccount,
+        coins: &Coins,
+        transaction: &Transaction,
+    ) -> bool {
+        let txid = transaction.txid();
+        if self.entries.contains_key(&txid) {
+            return false;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        match Self::entry(
+            master_account,
+            coins,
+            &HashMap::new(),
+            transaction,
+            None,
+            now,
+        ) {
+            Some(entry) => {
+                self.entries.insert(txid, entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// the entry of a transaction that spends or creates own coins
+    /// earlier are outputs of wallet transactions of the same block
+    fn entry(
+        master_account: &MasterAccount,
+        coins: &Coins,
+        earlier: &HashMap<OutPoint, TxOut>,
+        transaction: &Transaction,
+        confirmation: Option<(BlockHash, u32)>,
+        time: u64,
+    ) -> Option<HistoryEntry> {
+        let scripts = master_account
+            .get_scripts()
+            .map(|(s, _)| s)
+            .collect::<HashSet<_>>();
+        let mut sent = 0;
+        let mut spent = Some(0u64);
+        let mut labels = Vec::new();
+        if !transaction.is_coin_base() {
+            for input in transaction.input.iter() {
+                let point = &input.previous_output;
+                let output = coins.output(point).or_else(|| earlier.get(point).cloned());
+                spent = match (spent, output.as_ref()) {
+                    (Some(spent), Some(output)) => Some(spent + output.value),
+                    _ => None,
+                };
+                let own = coins.is_own(point)
+                    || output
+                        .as_ref()
+                        .is_some_and(|o| scripts.contains(&o.script_pubkey));
+                if let (true, Some(output)) = (own, output) {
+                    sent += output.value;
+                }
+                if let Some(metadata) = coins.metadata(point) {
+                    labels.extend(metadata.memo.iter().cloned());
+                    labels.extend(metadata.tags.iter().cloned());
+                }
+            }
+        } else {
+            spent = None;
+        }
+        let (own, counterparties): (Vec<TxOut>, Vec<TxOut>) = transaction
+            .output
+            .iter()
+            .cloned()
+            .partition(|o| scripts.contains(&o.script_pubkey));
+        if sent == 0 && own.is_empty() {
+            return None;
+        }
+        let paid = transaction.output.iter().map(|o| o.value).sum::<u64>();
+        let mut entry = HistoryEntry {
+            txid: transaction.txid(),
+            direction: if sent == 0 {
+                Direction::Incoming
+            } else if counterparties.is_empty() {
+                Direction::SelfTransfer
+            } else {
+                Direction::Outgoing
+            },
+            sent,
+            received: own.iter().map(|o| o.value).sum(),
+            fee: spent.and_then(|spent| spent.checked_sub(paid)),
+            own,
+            counterparties,
+            labels: Vec::new(),
+            confirmation,
+            time,
+        };
+        for label in labels {
+            entry.add_label(&label);
+        }
+        Some(entry)
+    }
+
+    /// label a transaction, returns false if it is not in the history
+    pub fn add_label(&mut self, txid: &Txid, label: &str) -> bool {
+        match self.entries.get_mut(txid) {
+            Some(entry) => {
+                entry.add_label(label);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// transactions of a block no longer on the trunk are pending again
+    pub fn unwind_tip(&mut self, block_hash: &BlockHash) {
+        for entry in self.entries.values_mut() {
+            if entry.confirmation.map(|(b, _)| b) == Some(*block_hash) {
+                entry.confirmation = None;
+            }
+        }
+    }
+
+    /// forget a transaction, e.g. evicted by a conflict
+    pub fn remove(&mut self, txid: &Txid) -> Option<HistoryEntry> {
+        self.entries.remove(txid)
+    }
+
+    /// a page of transactions passing the filters of query
+    pub fn query(&self, query: &HistoryQuery) -> Vec<&HistoryEntry> {
+        let labelled =
+            |entry: &HistoryEntry, label: &str| entry.labels.iter().any(|l| l.contains(label));
+        let mut listed = self
+            .entries
+            .values()
+            .filter(|e| {
+                query.direction.map(|d| d == e.direction).unwrap_or(true)
+                    && query
+                        .confirmed
+                        .map(|c| c == e.confirmation.is_some())
+                        .unwrap_or(true)
+                    && query.since.map(|t| e.time >= t).unwrap_or(true)
+                    && query.until.map(|t| e.time < t).unwrap_or(true)
+                    && query.label.as_ref().map(|l| labelled(e, l)).unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        listed.sort_by_key(|e| {
+            (
+                e.confirmation.map(|(_, h)| h).unwrap_or(u32::MAX),
+                e.time,
+                e.txid,
+            )
+        });
+        listed.reverse();
+        listed
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// write the history to a file, replacing it atomically
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut data = HISTORY_FILE_MAGIC.to_vec();
+        data.push(HISTORY_FILE_VERSION);
+        self.consensus_encode(&mut data)?;
+        write_atomic(path, data.as_slice())
+    }
+
+    /// read a history file written by save
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<History, Error> {
+        let data = fs::read(path)?;
+        if data.len() < 5 || &data[..4] != HISTORY_FILE_MAGIC {
+            return Err(Error::Storage("not a history file"));
+        }
+        if data[4] == 0 || data[4] > HISTORY_FILE_VERSION {
+            return Err(Error::Storage("unknown history file version"));
+        }
+        Ok(encode::deserialize(&data[5..])?)
+    }
+}
+
+impl Encodable for History {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut entries = self.entries.values().collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.txid);
+        let mut len = VarInt(entries.len() as u64).consensus_encode(&mut w)?;
+        for entry in entries {
+            len += entry.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for History {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<History, encode::Error> {
+        let mut history = History::new();
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let entry = HistoryEntry::consensus_decode(&mut d)?;
+            history.entries.insert(entry.txid, entry);
+        }
+        Ok(history)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Script, TxIn};
+
+    use fixtures::{block, block_after, master_account, next_script};
+
+    use super::*;
+
+    fn spend(point: OutPoint, outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: point,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: outputs,
+        }
+    }
+
+    /// a wallet funded in the first block, paying and transferring in the second
+    struct Wallet {
+        master: MasterAccount,
+        coins: Coins,
+        history: History,
+        other: Script,
+        funding: Transaction,
+        payment: Transaction,
+        transfer: Transaction,
+        first: Block,
+        second: Block,
+    }
+
+    impl Wallet {
+        fn new() -> Wallet {
+            let (mut master, _) = master_account(Network::Regtest);
+            let mut next = || next_script(&mut master, (0, 0));
+            let (funded, change, own) = (next(), next(), next());
+            let other = Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default());
+            let funding = spend(
+                OutPoint {
+                    txid: Txid::default(),
+                    vout: 1,
+                },
+                vec![TxOut {
+                    value: 100_000,
+                    script_pubkey: funded,
+                }],
+            );
+            let coin = OutPoint {
+                txid: funding.txid(),
+                vout: 0,
+            };
+            let payment = spend(
+                coin,
+                vec![
+                    TxOut {
+                        value: 30_000,
+                        script_pubkey: other.clone(),
+                    },
+                    TxOut {
+                        value: 69_000,
+                        script_pubkey: change,
+                    },
+                ],
+            );
+            // an own transfer in the same block spends the change
+            let transfer = spend(
+                OutPoint {
+                    txid: payment.txid(),
+                    vout: 1,
+                },
+                vec![TxOut {
+                    value: 68_500,
+                    script_pubkey: own,
+                }],
+            );
+            // an unrelated transaction is not recorded
+            let unrelated = spend(
+                OutPoint {
+                    txid: Txid::default(),
+                    vout: 7,
+                },
+                vec![TxOut {
+                    value: 1,
+                    script_pubkey: other.clone(),
+                }],
+            );
+            let first = block(Network::Regtest, vec![funding.clone()]);
+            let second = block_after(&first, vec![payment.clone(), transfer.clone(), unrelated]);
+
+            let mut coins = Coins::new();
+            let mut history = History::new();
+            assert!(history.process(&master, &coins, &first, 1));
+            coins.process(&mut master, &first);
+            coins.set_memo(&coin, "salary").unwrap();
+            Wallet {
+                master,
+                coins,
+                history,
+                other,
+                funding,
+                payment,
+                transfer,
+                first,
+                second,
+            }
+        }
+
+        fn pay(&mut self) {
+            assert!(self.history.process_unconfirmed_transaction(
+                &self.master,
+                &self.coins,
+                &self.payment
+            ));
+            self.coins
+                .process_unconfirmed_transaction(&mut self.master, &self.payment);
+        }
+
+        fn confirm(&mut self) {
+            assert!(self
+                .history
+                .process(&self.master, &self.coins, &self.second, 2));
+            self.coins.process(&mut self.master, &self.second);
+        }
+    }
+
+    #[test]
+    fn pending() {
+        let mut wallet = Wallet::new();
+        wallet.pay();
+        assert!(!wallet.history.process_unconfirmed_transaction(
+            &wallet.master,
+            &wallet.coins,
+            &wallet.payment
+        ));
+        let pending = wallet.history.get(&wallet.payment.txid()).unwrap();
+        assert_eq!(pending.confirmation, None);
+        assert_eq!(pending.direction, Direction::Outgoing);
+        assert_eq!(pending.fee, Some(1_000));
+        assert_eq!(pending.net(), -31_000);
+        assert_eq!(pending.labels, vec!["salary".to_string()]);
+        assert_eq!(
+            pending.addresses(Network::Regtest)[1],
+            Address::from_script(&wallet.other, Network::Regtest).unwrap()
+        );
+    }
+
+    #[test]
+    fn confirmed() {
+        let mut wallet = Wallet::new();
+        wallet.pay();
+        wallet.confirm();
+        let history = &wallet.history;
+        assert_eq!(history.len(), 3);
+        let confirmed = history.get(&wallet.payment.txid()).unwrap();
+        assert_eq!(
+            confirmed.confirmation,
+            Some((wallet.second.block_hash(), 2))
+        );
+        assert_eq!(confirmed.time, wallet.second.header.time as u64);
+        let transfer = history.get(&wallet.transfer.txid()).unwrap();
+        assert_eq!(transfer.direction, Direction::SelfTransfer);
+        assert_eq!(transfer.fee, Some(500));
+        assert!(transfer.proof(&wallet.coins).is_some());
+        let incoming = history.get(&wallet.funding.txid()).unwrap();
+        assert_eq!(incoming.direction, Direction::Incoming);
+        assert_eq!(incoming.fee, None);
+    }
+
+    #[test]
+    fn query() {
+        let mut wallet = Wallet::new();
+        wallet.pay();
+        wallet.confirm();
+        let history = &mut wallet.history;
+        // newest first, in pages
+        let all = history.query(&HistoryQuery::new());
+        assert_eq!(all[2].txid, wallet.funding.txid());
+        let page = history.query(&HistoryQuery::new().offset(1).limit(1));
+        assert_eq!(page, vec![all[1]]);
+        assert_eq!(
+            history
+                .query(&HistoryQuery::new().direction(Direction::Incoming))
+                .len(),
+            1
+        );
+        assert_eq!(history.query(&HistoryQuery::new().label("sal")).len(), 1);
+        let funding = wallet.funding.txid();
+        assert!(history.add_label(&funding, "january"));
+        assert_eq!(history.query(&HistoryQuery::new().label("jan")).len(), 1);
+    }
+
+    #[test]
+    fn unwind_save_load() {
+        let mut wallet = Wallet::new();
+        wallet.pay();
+        wallet.confirm();
+        let history = &mut wallet.history;
+        history.unwind_tip(&wallet.second.block_hash());
+        assert_eq!(
+            history.query(&HistoryQuery::new().confirmed(false)).len(),
+            2
+        );
+
+        let path = std::env::temp_dir().join(format!("history-{}.dat", std::process::id()));
+        history.save(&path).unwrap();
+        let loaded = History::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&loaded, history);
+    }
+
+    #[test]
+    fn pruning() {
+        let mut wallet = Wallet::new();
+        wallet.pay();
+        // only the summary of deep entries is kept
+        let mut pruned = History::new().with_pruning(2);
+        assert!(pruned.process(&wallet.master, &wallet.coins, &wallet.first, 1));
+        let funding = wallet.funding.txid();
+        assert!(!pruned.get(&funding).unwrap().is_pruned());
+        assert!(pruned.process(&wallet.master, &wallet.coins, &wallet.second, 2));
+        let incoming = pruned.get(&funding).unwrap();
+        assert!(incoming.is_pruned());
+        assert_eq!(incoming.received, wallet.funding.output[0].value);
+        assert!(!pruned.get(&wallet.payment.txid()).unwrap().is_pruned());
+
+        wallet.confirm();
+        assert_eq!(wallet.history.prune(2, 2), 1);
+        assert_eq!(wallet.history.prune(2, 2), 0);
+    }
+}
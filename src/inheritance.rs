@@ -0,0 +1,354 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Inheritance
+//!
+//! Pre-signed transactions sweeping all coins of the wallet to heirs, valid only after a delay
+//! through their lock time. Heirs keep the transactions and broadcast them once the lock time
+//! passed. The kit has to be refreshed whenever the coins of the wallet change and before the
+//! lock time is reached.
+//!
+//! A transaction handed out stays valid until one of the coins it spends is spent, so a kit
+//! is revoked by spending its coins, e.g. with a transfer to the wallet itself.
+//!
+use std::collections::HashSet;
+
+use bitcoin::{OutPoint, Script, SigHashType, Transaction, TxIn, TxOut};
+
+use account::{MasterAccount, Unlocker};
+use builder::{transaction_base_weight, LOCKTIME_THRESHOLD, NO_RBF_SEQUENCE};
+use coins::{Coin, Coins};
+use error::Error;
+use fee::FeeRate;
+use selection::{estimate_input_weight, output_weight};
+
+/// most coins swept by a recovery transaction, well below the standard weight limit
+pub const MAX_RECOVERY_INPUTS: usize = 200;
+
+/// Pre-signed recovery transactions paying heirs
+#[derive(Clone, Debug)]
+pub struct RecoveryKit {
+    /// scripts of the heirs and their shares
+    heirs: Vec<(Script, u64)>,
+    delay: u32,
+    feerate: FeeRate,
+    lock_time: Option<u32>,
+    transactions: Vec<Transaction>,
+}
+
+impl RecoveryKit {
+    /// a kit valid delay blocks after it was last refreshed, paying feerate
+    pub fn new(delay: u32, feerate: FeeRate) -> RecoveryKit {
+        RecoveryKit {
+            heirs: Vec::new(),
+            delay,
+            feerate,
+            lock_time: None,
+            transactions: Vec::new(),
+        }
+    }
+
+    /// add an heir receiving share parts of the funds
+    pub fn heir(mut self, script: Script, share: u64) -> RecoveryKit {
+        self.heirs.push((script, share));
+        self
+    }
+
+    /// the signed transactions to hand to heirs
+    pub fn transactions(&self) -> &[Transaction] {
+        self.transactions.as_slice()
+    }
+
+    /// block height the transactions are valid at, None if never refreshed
+    pub fn lock_time(&self) -> Option<u32> {
+        self.lock_time
+    }
+
+    /// coins swept by the transactions
+    pub fn covered(&self) -> HashSet<OutPoint> {
+        self.transactions
+            .iter()
+            .flat_map(|t| t.input.iter().map(|i| i.previous_output))
+            .collect()
+    }
+
+    /// the coins of the wallet differ from those swept
+    pub fn is_stale(&self, coins: &Coins) -> bool {
+        self.lock_time.is_none() || self.covered() != own_coins(coins).map(|(p, _)| p).collect()
+    }
+
+    /// stale or valid within margin blocks of height
+    pub fn is_due(&self, coins: &Coins, height: u32, margin: u32) -> bool {
+        self.is_stale(coins)
+            || self
+                .lock_time
+                .is_some_and(|lock_time| lock_time <= height + margin)
+    }
+
+    /// sign new transactions sweeping all coins, valid delay blocks after height
+    /// Returns the transactions replaced, they remain valid until their coins are spent.
+    pub fn refresh(
+        &mut self,
+        master: &MasterAccount,
+        unlocker: &mut Unlocker,
+        coins: &Coins,
+        height: u32,
+    ) -> Result<Vec<Transaction>, Error> {
+        if self.heirs.is_empty() || self.heirs.iter().all(|(_, s)| *s == 0) {
+            return Err(Error::Recovery("no heir"));
+        }
+        let lock_time = height + self.delay;
+        if lock_time >= LOCKTIME_THRESHOLD {
+            return Err(Error::Recovery("lock time is not a block height"));
+        }
+        let mut spent = own_coins(coins).collect::<Vec<_>>();
+        spent.sort_by_key(|(p, _)| *p);
+        let mut transactions = Vec::new();
+        for chunk in spent.chunks(MAX_RECOVERY_INPUTS) {
+            let mut transaction = self.sweep(chunk, lock_time)?;
+            let resolver = |point: &OutPoint| coins.output(point);
+            if master.sign(&mut transaction, SigHashType::All, &resolver, unlocker)?
+                < transaction.input.len()
+            {
+                return Err(Error::Recovery("can not sign all coins"));
+            }
+            transactions.push(transaction);
+        }
+        self.lock_time = Some(lock_time);
+        Ok(std::mem::replace(&mut self.transactions, transactions))
+    }
+
+    /// refresh if due, returns true if refreshed
+    pub fn refresh_if_due(
+        &mut self,
+        master: &MasterAccount,
+        unlocker: &mut Unlocker,
+        coins: &Coins,
+        height: u32,
+        margin: u32,
+    ) -> Result<bool, Error> {
+        if !self.is_due(coins, height, margin) {
+            return Ok(false);
+        }
+        self.refresh(master, unlocker, coins, height)?;
+        Ok(true)
+    }
+
+    /// unsigned transaction spending coins to the heirs
+    fn sweep(&self, coins: &[(OutPoint, Coin)], lock_time: u32) -> Result<Transaction, Error> {
+        let input = coins
+            .iter()
+            .map(|(point, coin)| TxIn {
+                previous_output: *point,
+                script_sig: Script::new(),
+                // a relative lock also enables the lock time
+                sequence: coin
+                    .derivation
+                    .csv
+                    .map(|csv| csv as u32)
+                    .unwrap_or(NO_RBF_SEQUENCE),
+                witness: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let weight = transaction_base_weight(input.len(), self.heirs.len())
+            + coins
+                .iter()
+                .map(|(_, c)| estimate_input_weight(&c.output.script_pubkey))
+                .sum::<u64>()
+            + self
+                .heirs
+                .iter()
+                .map(|(s, _)| output_weight(s))
+                .sum::<u64>();
+        let value = coins
+            .iter()
+            .map(|(_, c)| c.output.value)
+            .sum::<u64>()
+            .checked_sub(self.feerate.fee(weight))
+            .ok_or(Error::Recovery("coins do not pay the fee"))?;
+        let shares = self.heirs.iter().map(|(_, s)| *s).sum::<u64>();
+        let mut output = self
+            .heirs
+            .iter()
+            .map(|(script, share)| TxOut {
+                value: (value as u128 * *share as u128 / shares as u128) as u64,
+                script_pubkey: script.clone(),
+            })
+            .collect::<Vec<_>>();
+        // rounding goes to the largest share
+        let largest = (0..output.len())
+            .max_by_key(|ix| (self.heirs[*ix].1, std::cmp::Reverse(*ix)))
+            .unwrap();
+        output[largest].value += value - output.iter().map(|o| o.value).sum::<u64>();
+        output.retain(|o| o.value > 0);
+        if output
+            .iter()
+            .any(|o| o.value < o.script_pubkey.dust_value())
+        {
+            return Err(Error::Recovery("share of an heir is dust"));
+        }
+        Ok(Transaction {
+            version: 2,
+            lock_time,
+            input,
+            output,
+        })
+    }
+}
+
+/// unspent own coins, confirmed or not
+fn own_coins<'a>(coins: &'a Coins) -> impl Iterator<Item = (OutPoint, Coin)> + 'a {
+    coins
+        .confirmed()
+        .iter()
+        .chain(coins.unconfirmed().iter())
+        .map(|(p, c)| (*p, c.clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network};
+    use std::str::FromStr;
+
+    use account::{Account, AccountAddressType};
+    use fixtures::{block, funding, master_account, next_script};
+
+    use super::*;
+
+    /// coins of 100_000 and 50_000 sat of a P2WPKH and a P2SHWPKH account
+    struct Wallet {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        funding: Transaction,
+    }
+
+    impl Wallet {
+        fn new() -> Wallet {
+            let (mut master, mut unlocker) = master_account(Network::Testnet);
+            master.add_account(
+                Account::new(&mut unlocker, AccountAddressType::P2SHWPKH, 1, 0, 10).unwrap(),
+            );
+            let funding = funding(vec![
+                TxOut {
+                    value: 100_000,
+                    script_pubkey: next_script(&mut master, (0, 0)),
+                },
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: next_script(&mut master, (1, 0)),
+                },
+            ]);
+            let mut coins = Coins::new();
+            coins.process(&mut master, &block(Network::Testnet, vec![funding.clone()]));
+            Wallet {
+                master,
+                unlocker,
+                coins,
+                funding,
+            }
+        }
+
+        /// a kit refreshed at 10 leaving a quarter to the first heir
+        fn kit(&mut self) -> RecoveryKit {
+            let mut kit = RecoveryKit::new(1000, FeeRate::from_sat_per_vb(2))
+                .heir(heir(), 1)
+                .heir(Script::new_p2sh(&bitcoin::ScriptHash::default()), 3);
+            assert!(kit
+                .refresh(&self.master, &mut self.unlocker, &self.coins, 10)
+                .unwrap()
+                .is_empty());
+            kit
+        }
+    }
+
+    fn heir() -> Script {
+        Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+            .unwrap()
+            .script_pubkey()
+    }
+
+    #[test]
+    fn recovery_kit() {
+        let mut wallet = Wallet::new();
+        let mut kit = RecoveryKit::new(1000, FeeRate::from_sat_per_vb(2));
+        assert!(kit
+            .refresh(&wallet.master, &mut wallet.unlocker, &wallet.coins, 10)
+            .is_err());
+        assert!(kit.heir(heir(), 1).is_stale(&wallet.coins));
+        let kit = wallet.kit();
+        assert!(!kit.is_stale(&wallet.coins));
+        assert_eq!(kit.lock_time(), Some(1010));
+        let transaction = kit.transactions()[0].clone();
+        assert_eq!(transaction.lock_time, 1010);
+        assert_eq!(transaction.input.len(), 2);
+        let paid = transaction.output.iter().map(|o| o.value).sum::<u64>();
+        assert!(paid < 150_000);
+        assert_eq!(transaction.output[0].value, paid / 4);
+        assert_eq!(transaction.output[0].script_pubkey, heir());
+        transaction
+            .verify(|point| wallet.funding.output.get(point.vout as usize).cloned())
+            .unwrap();
+        let fee = 150_000 - paid;
+        let feerate = fee as f64 * 4.0 / transaction.get_weight() as f64;
+        assert!((1.9..2.1).contains(&feerate));
+    }
+
+    #[test]
+    fn due() {
+        let mut wallet = Wallet::new();
+        let mut kit = wallet.kit();
+        assert!(!kit.is_due(&wallet.coins, 500, 100));
+        assert!(kit.is_due(&wallet.coins, 950, 100));
+        assert!(!kit
+            .refresh_if_due(
+                &wallet.master,
+                &mut wallet.unlocker,
+                &wallet.coins,
+                500,
+                100
+            )
+            .unwrap());
+    }
+
+    #[test]
+    fn stale() {
+        // a new coin makes the kit stale
+        let mut wallet = Wallet::new();
+        let mut kit = wallet.kit();
+        let mut received = wallet.funding.clone();
+        received.input[0].previous_output.vout = 2;
+        received.output = vec![TxOut {
+            value: 20_000,
+            script_pubkey: next_script(&mut wallet.master, (0, 0)),
+        }];
+        wallet
+            .coins
+            .process_unconfirmed_transaction(&mut wallet.master, &received);
+        assert!(kit.is_stale(&wallet.coins));
+        assert!(kit
+            .refresh_if_due(
+                &wallet.master,
+                &mut wallet.unlocker,
+                &wallet.coins,
+                500,
+                100
+            )
+            .unwrap());
+        assert_eq!(kit.lock_time(), Some(1500));
+        assert_eq!(kit.covered().len(), 3);
+    }
+}
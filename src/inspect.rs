@@ -0,0 +1,382 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Transaction inspector
+//!
+//! An annotated view of a transaction or PSBT as seen by the wallet: which inputs and outputs
+//! are own, the effect on the balance, fee, fee rate, replaceability, lock times and script
+//! types. Enough to render a confirmation screen before signing.
+//!
+use std::collections::HashMap;
+
+use bitcoin::{Address, OutPoint, Transaction, Txid};
+
+use account::{KeyDerivation, MasterAccount};
+use builder::{
+    LOCKTIME_THRESHOLD, NO_RBF_SEQUENCE, SEQUENCE_DISABLE_FLAG, SEQUENCE_TYPE_FLAG, TRUC_VERSION,
+};
+use coins::{Coins, ScriptType};
+use error::Error;
+use fee::FeeRate;
+use psbt::{analyze, annex, spent_output, Psbt};
+use selection::InputType;
+
+/// Absolute lock time of a transaction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockTime {
+    /// not valid before this block height
+    Height(u32),
+    /// not valid before this unix time, judged by the median time past
+    Time(u32),
+}
+
+/// Relative lock time of an input (BIP68)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RelativeLock {
+    /// blocks after the confirmation of the spent coin
+    Blocks(u16),
+    /// seconds after the confirmation of the spent coin, a multiple of 512
+    Time(u32),
+}
+
+/// An input as seen by the wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InputView {
+    pub previous_output: OutPoint,
+    /// None if the spent output is not known
+    pub value: Option<u64>,
+    /// type of the spent output, None if it is not known
+    pub script_type: Option<ScriptType>,
+    /// spends an own coin
+    pub is_own: bool,
+    pub sequence: u32,
+    pub relative_lock: Option<RelativeLock>,
+    /// annex of a final taproot input, kept but not interpreted
+    pub annex: Option<Vec<u8>>,
+}
+
+/// An output as seen by the wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputView {
+    pub value: u64,
+    pub script_type: ScriptType,
+    /// None for scripts without address, e.g. OP_RETURN
+    pub address: Option<Address>,
+    /// key of an own output
+    pub derivation: Option<KeyDerivation>,
+}
+
+impl OutputView {
+    pub fn is_own(&self) -> bool {
+        self.derivation.is_some()
+    }
+}
+
+/// A transaction as seen by the wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransactionView {
+    pub txid: Txid,
+    pub version: i32,
+    pub inputs: Vec<InputView>,
+    pub outputs: Vec<OutputView>,
+    /// value of own inputs
+    pub sent: u64,
+    /// value of own outputs
+    pub received: u64,
+    /// None if the value of a spent output is not known
+    pub fee: Option<u64>,
+    /// weight once signed, estimated for inputs that are not final
+    pub weight: u64,
+    /// signals replaceability with BIP125 or is a TRUC transaction
+    pub signals_rbf: bool,
+    /// None if the transaction is not locked
+    pub lock_time: Option<LockTime>,
+}
+
+impl TransactionView {
+    /// effect of the transaction on the balance
+    pub fn net(&self) -> i64 {
+        self.received as i64 - self.sent as i64
+    }
+
+    /// all inputs spend own coins
+    pub fn is_outgoing(&self) -> bool {
+        !self.inputs.is_empty() && self.inputs.iter().all(|i| i.is_own)
+    }
+
+    /// fee rate of the signed transaction
+    pub fn feerate(&self) -> Option<FeeRate> {
+        self.fee
+            .map(|fee| FeeRate::from_sat_per_kwu(fee * 1000 / self.weight))
+    }
+
+    /// value paid to outputs that are not own
+    pub fn paid(&self) -> u64 {
+        self.outputs
+            .iter()
+            .filter(|o| !o.is_own())
+            .map(|o| o.value)
+            .sum()
+    }
+}
+
+/// annotate a transaction, signed or not
+/// Spent outputs are looked up in coins.
+pub fn decode(
+    master: &MasterAccount,
+    coins: &Coins,
+    transaction: &Transaction,
+) -> Result<TransactionView, Error> {
+    let mut unsigned = transaction.clone();
+    for input in unsigned.input.iter_mut() {
+        input.script_sig = bitcoin::Script::new();
+        input.witness = Vec::new();
+    }
+    let mut psbt = Psbt::from_unsigned_tx(unsigned)?;
+    for (input, txin) in psbt.inputs.iter_mut().zip(transaction.input.iter()) {
+        if !txin.script_sig.is_empty() {
+            input.final_script_sig = Some(txin.script_sig.clone());
+        }
+        if !txin.witness.is_empty() {
+            input.final_script_witness = Some(txin.witness.clone());
+        }
+    }
+    Ok(decode_psbt(master, coins, &psbt))
+}
+
+/// annotate a PSBT
+/// Spent outputs missing in the PSBT are looked up in coins.
+pub fn decode_psbt(master: &MasterAccount, coins: &Coins, psbt: &Psbt) -> TransactionView {
+    let mut psbt = psbt.clone();
+    for ix in 0..psbt.inputs.len() {
+        if spent_output(&psbt, ix).is_none() {
+            let point = psbt.global.unsigned_tx.input[ix].previous_output;
+            psbt.inputs[ix].witness_utxo = coins.output(&point);
+        }
+    }
+    let scripts = master.get_scripts().collect::<HashMap<_, _>>();
+    let transaction = &psbt.global.unsigned_tx;
+    let relative = transaction.version >= 2;
+    let inputs = transaction
+        .input
+        .iter()
+        .enumerate()
+        .map(|(ix, input)| {
+            let spent = spent_output(&psbt, ix);
+            let sequence = input.sequence;
+            InputView {
+                previous_output: input.previous_output,
+                value: spent.as_ref().map(|o| o.value),
+                script_type: spent.as_ref().map(|o| ScriptType::of(&o.script_pubkey)),
+                is_own: coins.is_own(&input.previous_output)
+                    || spent
+                        .as_ref()
+                        .is_some_and(|o| scripts.contains_key(&o.script_pubkey)),
+                sequence,
+                relative_lock: if relative && sequence & SEQUENCE_DISABLE_FLAG == 0 {
+                    if sequence & SEQUENCE_TYPE_FLAG == 0 {
+                        Some(RelativeLock::Blocks(sequence as u16))
+                    } else {
+                        Some(RelativeLock::Time((sequence & 0xffff) * 512))
+                    }
+                } else {
+                    None
+                },
+                annex: psbt.inputs[ix]
+                    .final_script_witness
+                    .as_ref()
+                    .filter(|_| {
+                        spent.as_ref().is_some_and(|o| {
+                            InputType::of(&o.script_pubkey) == InputType::P2TRKeyPath
+                        })
+                    })
+                    .and_then(|w| annex(w))
+                    .map(|a| a.to_vec()),
+            }
+        })
+        .collect::<Vec<_>>();
+    let network = master.master_public().network;
+    let outputs = transaction
+        .output
+        .iter()
+        .map(|output| OutputView {
+            value: output.value,
+            script_type: ScriptType::of(&output.script_pubkey),
+            address: Address::from_script(&output.script_pubkey, network),
+            derivation: scripts.get(&output.script_pubkey).cloned(),
+        })
+        .collect::<Vec<_>>();
+    let spent = inputs.iter().map(|i| i.value).sum::<Option<u64>>();
+    let paid = outputs.iter().map(|o| o.value).sum::<u64>();
+    let locked = transaction.input.iter().any(|i| i.sequence != 0xffff_ffff);
+    TransactionView {
+        txid: transaction.txid(),
+        version: transaction.version,
+        sent: inputs
+            .iter()
+            .filter(|i| i.is_own)
+            .map(|i| i.value.unwrap_or(0))
+            .sum(),
+        received: outputs.iter().filter(|o| o.is_own()).map(|o| o.value).sum(),
+        fee: spent.and_then(|spent| spent.checked_sub(paid)),
+        weight: analyze(&psbt).weight,
+        signals_rbf: transaction.version == TRUC_VERSION
+            || transaction
+                .input
+                .iter()
+                .any(|i| i.sequence < NO_RBF_SEQUENCE),
+        lock_time: match transaction.lock_time {
+            0 => None,
+            _ if !locked => None,
+            n if n < LOCKTIME_THRESHOLD => Some(LockTime::Height(n)),
+            n => Some(LockTime::Time(n)),
+        },
+        inputs,
+        outputs,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, SigHashType};
+    use std::str::FromStr;
+
+    use account::Unlocker;
+    use builder::ChangePosition;
+    use fixtures::{funded, master_account, next_script};
+    use psbt::Psbt;
+
+    use super::*;
+
+    /// a wallet with a coin of 100_000 sat and a PSBT paying 50_000 sat of it to another
+    struct Wallet {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        funding: Transaction,
+        to: Address,
+        psbt: Psbt,
+    }
+
+    impl Wallet {
+        fn new() -> Wallet {
+            let (mut master, unlocker) = master_account(Network::Testnet);
+            let (coins, funding) = funded(&mut master, Network::Testnet, &[100_000]);
+            let to =
+                Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                    .unwrap();
+            let change = next_script(&mut master, (0, 0));
+            let (psbt, _) = coins
+                .build_tx()
+                .add_recipient(&to, 50_000)
+                .feerate(FeeRate::from_sat_per_vb(5))
+                .enable_rbf()
+                .lock_time(100)
+                .change_position(ChangePosition::Last)
+                .build_psbt(&master, &change, 100, |_| Some(1))
+                .unwrap();
+            Wallet {
+                master,
+                unlocker,
+                coins,
+                funding,
+                to,
+                psbt,
+            }
+        }
+
+        fn signed(&mut self) -> Transaction {
+            let mut transaction = self.psbt.global.unsigned_tx.clone();
+            let funding = &self.funding;
+            let resolve = |point: &OutPoint| funding.output.get(point.vout as usize).cloned();
+            self.master
+                .sign(
+                    &mut transaction,
+                    SigHashType::All,
+                    &resolve,
+                    &mut self.unlocker,
+                )
+                .unwrap();
+            transaction
+        }
+    }
+
+    #[test]
+    fn incoming() {
+        let Wallet {
+            master,
+            coins,
+            funding,
+            ..
+        } = Wallet::new();
+        let incoming = decode(&master, &coins, &funding).unwrap();
+        assert_eq!(incoming.net(), 100_000);
+        assert_eq!(incoming.fee, None);
+        assert!(!incoming.is_outgoing());
+        assert!(!incoming.signals_rbf);
+        assert_eq!(incoming.lock_time, None);
+    }
+
+    #[test]
+    fn outgoing() {
+        let mut wallet = Wallet::new();
+        let transaction = wallet.signed();
+        let Wallet {
+            master,
+            coins,
+            to,
+            psbt,
+            ..
+        } = wallet;
+        let view = decode_psbt(&master, &coins, &psbt);
+        assert!(view.is_outgoing());
+        assert!(view.signals_rbf);
+        assert_eq!(view.lock_time, Some(LockTime::Height(100)));
+        assert_eq!(view.inputs[0].script_type, Some(ScriptType::P2WPKH));
+        assert_eq!(view.inputs[0].relative_lock, None);
+        assert_eq!(view.outputs[0].address, Some(to));
+        assert!(!view.outputs[0].is_own());
+        assert!(view.outputs[1].is_own());
+        assert_eq!(view.outputs[1].script_type, ScriptType::P2WPKH);
+        assert_eq!(view.paid(), 50_000);
+        assert_eq!(view.fee, coins.fee_of(&psbt.global.unsigned_tx));
+        assert_eq!(view.net(), -((50_000 + view.fee.unwrap()) as i64));
+        let feerate = view.feerate().unwrap().as_sat_per_vb();
+        assert!((5.0..5.1).contains(&feerate));
+
+        // the signed transaction has the same fee rate
+        let signed = decode(&master, &coins, &transaction).unwrap();
+        assert_eq!(signed.weight, transaction.get_weight() as u64);
+        assert!(signed.weight <= view.weight);
+        assert_eq!(signed.fee, view.fee);
+        assert_eq!(signed.net(), view.net());
+    }
+
+    #[test]
+    fn foreign_input() {
+        // a foreign input with a relative lock
+        let mut wallet = Wallet::new();
+        let mut foreign = wallet.signed();
+        foreign.input[0].previous_output.vout = 7;
+        foreign.input[0].sequence = SEQUENCE_TYPE_FLAG | 2;
+        let view = decode(&wallet.master, &wallet.coins, &foreign).unwrap();
+        assert!(!view.inputs[0].is_own);
+        assert_eq!(view.inputs[0].value, None);
+        assert_eq!(view.inputs[0].relative_lock, Some(RelativeLock::Time(1024)));
+        assert_eq!(view.fee, None);
+        assert_eq!(view.net(), view.received as i64);
+    }
+}
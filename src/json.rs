@@ -0,0 +1,256 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # JSON
+//!
+//! A minimal JSON reader and writer for the documents the wallet exchanges: replies of
+//! bitcoind and Esplora, Bitcoin Core descriptor lists and wallet coordinator exports.
+//!
+//! Numbers are read as f64, strings may only use the escapes of ASCII text.
+//!
+use std::collections::BTreeMap;
+use std::fmt;
+
+use error::Error;
+
+/// Minimal JSON as written by Bitcoin Core and wallet coordinators
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Json::Array(array) => {
+                write!(f, "[")?;
+                for (i, value) in array.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(object) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in object.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Json::String(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct JsonReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonReader<'a> {
+    fn skip_space(&mut self) {
+        while self.pos < self.data.len() && self.data[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<u8, Error> {
+        self.skip_space();
+        let c = *self
+            .data
+            .get(self.pos)
+            .ok_or(Error::Json("unexpected end of JSON"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, token: &[u8]) -> Result<(), Error> {
+        if self.data[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(Error::Json("invalid JSON"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, Error> {
+        match self.next()? {
+            b'n' => self.expect(b"ull").map(|_| Json::Null),
+            b't' => self.expect(b"rue").map(|_| Json::Bool(true)),
+            b'f' => self.expect(b"alse").map(|_| Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                let mut array = Vec::new();
+                self.skip_space();
+                if self.data.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(array));
+                }
+                loop {
+                    array.push(self.value()?);
+                    match self.next()? {
+                        b',' => {}
+                        b']' => return Ok(Json::Array(array)),
+                        _ => return Err(Error::Json("invalid JSON array")),
+                    }
+                }
+            }
+            b'{' => {
+                let mut object = BTreeMap::new();
+                self.skip_space();
+                if self.data.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(object));
+                }
+                loop {
+                    if self.next()? != b'"' {
+                        return Err(Error::Json("invalid JSON object"));
+                    }
+                    let key = self.string()?;
+                    if self.next()? != b':' {
+                        return Err(Error::Json("invalid JSON object"));
+                    }
+                    object.insert(key, self.value()?);
+                    match self.next()? {
+                        b',' => {}
+                        b'}' => return Ok(Json::Object(object)),
+                        _ => return Err(Error::Json("invalid JSON object")),
+                    }
+                }
+            }
+            c if c == b'-' || c.is_ascii_digit() => {
+                let start = self.pos - 1;
+                while self.pos < self.data.len()
+                    && (self.data[self.pos].is_ascii_digit()
+                        || b".eE+-".contains(&self.data[self.pos]))
+                {
+                    self.pos += 1;
+                }
+                std::str::from_utf8(&self.data[start..self.pos])
+                    .ok()
+                    .and_then(|n| n.parse().ok())
+                    .map(Json::Number)
+                    .ok_or(Error::Json("invalid JSON number"))
+            }
+            _ => Err(Error::Json("invalid JSON")),
+        }
+    }
+
+    /// the rest of a string after its opening quote
+    fn string(&mut self) -> Result<String, Error> {
+        let mut bytes = Vec::new();
+        loop {
+            let c = *self
+                .data
+                .get(self.pos)
+                .ok_or(Error::Json("unterminated JSON string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self
+                        .data
+                        .get(self.pos)
+                        .ok_or(Error::Json("unterminated JSON string"))?;
+                    self.pos += 1;
+                    bytes.push(match escaped {
+                        b'n' => b'\n',
+                        b't' => b'\t',
+                        b'r' => b'\r',
+                        b'b' => 8,
+                        b'f' => 12,
+                        b'"' | b'\\' | b'/' => escaped,
+                        // descriptors and the fields used here are ASCII
+                        _ => return Err(Error::Json("unsupported JSON escape")),
+                    });
+                }
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| Error::Json("invalid JSON string"))
+    }
+}
+
+pub(crate) fn parse_json(json: &str) -> Result<Json, Error> {
+    let mut reader = JsonReader {
+        data: json.as_bytes(),
+        pos: 0,
+    };
+    let value = reader.value()?;
+    reader.skip_space();
+    if reader.pos != reader.data.len() {
+        return Err(Error::Json("trailing data after JSON"));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let text = r#" {"a": [1, -2.5e1, true, null], "b": {"c": "d\"e"}} "#;
+        let json = parse_json(text).unwrap();
+        match json {
+            Json::Object(ref object) => {
+                assert_eq!(
+                    object["a"],
+                    Json::Array(vec![
+                        Json::Number(1.0),
+                        Json::Number(-25.0),
+                        Json::Bool(true),
+                        Json::Null
+                    ])
+                );
+            }
+            _ => panic!("not an object"),
+        }
+        assert_eq!(parse_json(json.to_string().as_str()).unwrap(), json);
+        assert_eq!(
+            json.to_string(),
+            r#"{"a":[1,-25,true,null],"b":{"c":"d\"e"}}"#
+        );
+        assert!(parse_json("[1, 2").is_err());
+        assert!(parse_json("{} {}").is_err());
+    }
+}
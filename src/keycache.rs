@@ -0,0 +1,240 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Derived key cache
+//!
+//! Deriving a public key costs an EC point multiplication. Keys of an account beyond those
+//! instantiated are derived again and again, by address searches, by checks of cosigner keys
+//! and when a look ahead reaches keys searched before. KeyCache keeps derived public keys and
+//! their scripts by account, chain and index and evicts the least recently used beyond its
+//! capacity.
+//!
+//! A cache may be shared by the accounts of one master account, an entry does not tell which
+//! master its key belongs to.
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use bitcoin::{PublicKey, Script};
+
+use error::Error;
+
+/// keys cached unless configured otherwise
+const DEFAULT_CAPACITY: usize = 4096;
+
+/// account, chain and index of a key, the chain is the sub account number
+pub type KeyIndex = (u32, u32, u32);
+
+/// A derived public key with its script pubkey if that was computed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CachedKey {
+    pub public: PublicKey,
+    pub script: Option<Script>,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// keys with their last use
+    keys: HashMap<KeyIndex, (CachedKey, u64)>,
+    /// keys by last use
+    uses: BTreeMap<u64, KeyIndex>,
+    /// counts uses
+    clock: u64,
+    hits: usize,
+}
+
+/// Least recently used derived keys
+pub struct KeyCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for KeyCache {
+    fn default() -> KeyCache {
+        KeyCache::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl KeyCache {
+    /// a cache of up to capacity keys
+    pub fn new(capacity: usize) -> KeyCache {
+        KeyCache {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// keys cached
+    pub fn len(&self) -> usize {
+        self.lock().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// lookups served from the cache
+    pub fn hits(&self) -> usize {
+        self.lock().hits
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.lock();
+        entries.keys.clear();
+        entries.uses.clear();
+    }
+
+    /// a cached key, marked as used
+    pub fn get(&self, index: KeyIndex) -> Option<CachedKey> {
+        let mut entries = self.lock();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let used = match entries.keys.get_mut(&index) {
+            Some((_, used)) => std::mem::replace(used, clock),
+            None => return None,
+        };
+        entries.uses.remove(&used);
+        entries.uses.insert(clock, index);
+        entries.hits += 1;
+        entries.keys.get(&index).map(|(key, _)| key.clone())
+    }
+
+    /// cache a key, evicting the least recently used beyond capacity
+    pub fn insert(&self, index: KeyIndex, key: CachedKey) {
+        let mut entries = self.lock();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if let Some((_, used)) = entries.keys.insert(index, (key, clock)) {
+            entries.uses.remove(&used);
+        }
+        entries.uses.insert(clock, index);
+        while entries.keys.len() > self.capacity {
+            let oldest = match entries.uses.iter().next() {
+                Some((used, index)) => (*used, *index),
+                None => break,
+            };
+            entries.uses.remove(&oldest.0);
+            entries.keys.remove(&oldest.1);
+        }
+    }
+
+    /// the cached public key or the one derive computes, which is then cached
+    pub fn public_key<F>(&self, index: KeyIndex, derive: F) -> Result<PublicKey, Error>
+    where
+        F: FnOnce() -> Result<PublicKey, Error>,
+    {
+        if let Some(key) = self.get(index) {
+            return Ok(key.public);
+        }
+        let public = derive()?;
+        self.insert(
+            index,
+            CachedKey {
+                public,
+                script: None,
+            },
+        );
+        Ok(public)
+    }
+
+    /// the cached public key and script, missing ones computed by derive and scripter
+    pub fn script<F, S>(
+        &self,
+        index: KeyIndex,
+        derive: F,
+        scripter: S,
+    ) -> Result<(PublicKey, Script), Error>
+    where
+        F: FnOnce() -> Result<PublicKey, Error>,
+        S: FnOnce(&PublicKey) -> Script,
+    {
+        let public = match self.get(index) {
+            Some(CachedKey {
+                public,
+                script: Some(script),
+            }) => return Ok((public, script)),
+            Some(key) => key.public,
+            None => derive()?,
+        };
+        let script = scripter(&public);
+        self.insert(
+            index,
+            CachedKey {
+                public,
+                script: Some(script.clone()),
+            },
+        );
+        Ok((public, script))
+    }
+
+    // a panic while holding the lock leaves consistent entries
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn key_cache() {
+        let public = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let derived = Cell::new(0);
+        let derive = || {
+            derived.set(derived.get() + 1);
+            Ok(public)
+        };
+        let cache = KeyCache::new(2);
+        assert_eq!(cache.public_key((0, 0, 1), derive).unwrap(), public);
+        assert_eq!(cache.public_key((0, 0, 1), derive).unwrap(), public);
+        assert_eq!((derived.get(), cache.hits()), (1, 1));
+
+        // a script is computed once for a cached key
+        let scripted = Cell::new(0);
+        let scripter = |public: &PublicKey| {
+            scripted.set(scripted.get() + 1);
+            Script::new_p2pk(public)
+        };
+        let (_, script) = cache.script((0, 0, 1), derive, scripter).unwrap();
+        assert_eq!(script, Script::new_p2pk(&public));
+        cache.script((0, 0, 1), derive, scripter).unwrap();
+        assert_eq!((derived.get(), scripted.get()), (1, 1));
+
+        // the least recently used is evicted
+        cache.public_key((0, 1, 0), derive).unwrap();
+        cache.public_key((0, 0, 1), derive).unwrap();
+        cache.public_key((1, 0, 0), derive).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get((0, 1, 0)).is_none());
+        assert!(cache.get((0, 0, 1)).unwrap().script.is_some());
+        assert_eq!(derived.get(), 3);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
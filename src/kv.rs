@@ -0,0 +1,482 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Key-value coin store
+//!
+//! A coin store on top of an ordered key-value store, writing only what changed. Records are
+//! encoded as in coin store files, keyed by a record type prefix.
+//!
+//! The KeyValue trait follows the API of embedded databases such as sled or redb, so their
+//! trees adapt in a few lines. LogKeyValue is a pure Rust store of its own in an append-only
+//! file that needs no database.
+//!
+//! KvCoinStore and LogKeyValue are built with the kv-store feature, the KeyValue trait is also
+//! used by the peer and broadcast stores and always built.
+//!
+use std::collections::BTreeMap;
+
+use error::Error;
+
+#[cfg(feature = "kv-store")]
+pub use self::store::{KvCoinStore, LogKeyValue};
+
+/// key-value pairs in key order
+pub type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// An ordered key-value store
+pub trait KeyValue {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+    fn remove(&mut self, key: &[u8]) -> Result<(), Error>;
+    /// all pairs with keys starting with prefix
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Pairs, Error>;
+    /// make changes durable
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl KeyValue for BTreeMap<Vec<u8>, Vec<u8>> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(BTreeMap::get(self, key).cloned())
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        BTreeMap::insert(self, key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<(), Error> {
+        BTreeMap::remove(self, key);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Pairs, Error> {
+        Ok(self
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+}
+
+#[cfg(feature = "kv-store")]
+mod store {
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use bitcoin::consensus::{deserialize, serialize, Decodable, Encodable};
+    use bitcoin::hashes::{sha256d, Hash};
+    use bitcoin::{OutPoint, Script, Transaction, Txid};
+
+    use cluster::{ClusterId, Clusters};
+    use coins::{CoinStore, Draft, StoredCoin};
+    use error::Error;
+    use proved::ProvedTransaction;
+    use storage::write_atomic;
+
+    use super::{KeyValue, Pairs};
+
+    /// version of the record layout
+    const KV_VERSION: u8 = 1;
+    const VERSION_KEY: &[u8] = b"v";
+    const COIN: u8 = b'c';
+    const PROOF: u8 = b'p';
+    const CLUSTER: u8 = b'l';
+    const PENDING: u8 = b't';
+    const DRAFT: u8 = b'd';
+
+    const LOG_MAGIC: &[u8; 4] = b"RWKV";
+    const LOG_VERSION: u8 = 1;
+    const LOG_PUT: u8 = 1;
+    const LOG_REMOVE: u8 = 2;
+
+    /// A key-value store in an append-only file
+    /// Changes are appended on flush and the file is compacted once most of it is superseded. A
+    /// record torn by a crash is cut off when the file is opened.
+    pub struct LogKeyValue {
+        path: PathBuf,
+        map: BTreeMap<Vec<u8>, Vec<u8>>,
+        /// changes since the last flush, None value for removals
+        changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        /// records in the file
+        records: usize,
+    }
+
+    impl LogKeyValue {
+        /// open or create a log file
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<LogKeyValue, Error> {
+            let path = path.as_ref().to_path_buf();
+            let mut store = LogKeyValue {
+                path,
+                map: BTreeMap::new(),
+                changes: Vec::new(),
+                records: 0,
+            };
+            match fs::read(&store.path) {
+                Ok(content) => {
+                    let valid = store.replay(content.as_slice())?;
+                    // records appended after a torn one could not be read again
+                    if valid < content.len() {
+                        let file = fs::OpenOptions::new().write(true).open(&store.path)?;
+                        file.set_len(valid as u64)?;
+                        file.sync_all()?;
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => store.compact()?,
+                Err(e) => return Err(Error::IO(e)),
+            }
+            Ok(store)
+        }
+
+        /// replay records up to the first torn one, returns the length of the records read
+        fn replay(&mut self, data: &[u8]) -> Result<usize, Error> {
+            if data.len() < 5 || &data[..4] != LOG_MAGIC {
+                return Err(Error::Storage("not a key-value log"));
+            }
+            if data[4] == 0 || data[4] > LOG_VERSION {
+                return Err(Error::Storage("unknown key-value log version"));
+            }
+            let mut rest = &data[5..];
+            let mut valid = 5;
+            while !rest.is_empty() {
+                match Self::record(&mut rest) {
+                    Some((key, value)) => {
+                        match value {
+                            Some(value) => self.map.insert(key, value),
+                            None => self.map.remove(&key),
+                        };
+                        self.records += 1;
+                        valid = data.len() - rest.len();
+                    }
+                    // a torn write at the end
+                    None => break,
+                }
+            }
+            Ok(valid)
+        }
+
+        /// decode a record, None if it is incomplete or does not match its checksum
+        fn record(data: &mut &[u8]) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+            let start = *data;
+            let op = u8::consensus_decode(&mut *data).ok()?;
+            let key = Vec::<u8>::consensus_decode(&mut *data).ok()?;
+            let value = match op {
+                LOG_PUT => Some(Vec::<u8>::consensus_decode(&mut *data).ok()?),
+                LOG_REMOVE => None,
+                _ => return None,
+            };
+            let body = &start[..start.len() - data.len()];
+            let checksum = <[u8; 4]>::consensus_decode(&mut *data).ok()?;
+            if checksum[..] != sha256d::Hash::hash(body)[..4] {
+                return None;
+            }
+            Some((key, value))
+        }
+
+        fn encode_record(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+            let mut record = Vec::new();
+            match value {
+                Some(value) => {
+                    record.push(LOG_PUT);
+                    key.to_vec()
+                        .consensus_encode(&mut record)
+                        .expect("in memory");
+                    value
+                        .to_vec()
+                        .consensus_encode(&mut record)
+                        .expect("in memory");
+                }
+                None => {
+                    record.push(LOG_REMOVE);
+                    key.to_vec()
+                        .consensus_encode(&mut record)
+                        .expect("in memory");
+                }
+            }
+            let checksum = sha256d::Hash::hash(record.as_slice());
+            record.extend_from_slice(&checksum[..4]);
+            record
+        }
+
+        /// rewrite the file with the current pairs only
+        pub fn compact(&mut self) -> Result<(), Error> {
+            let mut data = LOG_MAGIC.to_vec();
+            data.push(LOG_VERSION);
+            for (key, value) in self.map.iter() {
+                data.extend(Self::encode_record(key, Some(value)));
+            }
+            write_atomic(&self.path, data.as_slice())?;
+            self.records = self.map.len();
+            self.changes.clear();
+            Ok(())
+        }
+    }
+
+    impl KeyValue for LogKeyValue {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+            Ok(self.map.get(key).cloned())
+        }
+
+        fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+            self.map.insert(key.to_vec(), value.to_vec());
+            self.changes.push((key.to_vec(), Some(value.to_vec())));
+            Ok(())
+        }
+
+        fn remove(&mut self, key: &[u8]) -> Result<(), Error> {
+            if self.map.remove(key).is_some() {
+                self.changes.push((key.to_vec(), None));
+            }
+            Ok(())
+        }
+
+        fn scan_prefix(&self, prefix: &[u8]) -> Result<Pairs, Error> {
+            self.map.scan_prefix(prefix)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            if self.changes.is_empty() {
+                return Ok(());
+            }
+            if self.records + self.changes.len() > 2 * self.map.len() + 64 {
+                return self.compact();
+            }
+            let mut data = Vec::new();
+            for (key, value) in self.changes.iter() {
+                data.extend(Self::encode_record(key, value.as_deref()));
+            }
+            let mut file = fs::OpenOptions::new().append(true).open(&self.path)?;
+            file.write_all(data.as_slice())?;
+            file.sync_all()?;
+            self.records += self.changes.len();
+            self.changes.clear();
+            Ok(())
+        }
+    }
+
+    /// A coin store in a key-value store
+    pub struct KvCoinStore<K: KeyValue> {
+        kv: K,
+    }
+
+    impl<K: KeyValue> KvCoinStore<K> {
+        /// use a key-value store that is empty or holds a coin store
+        pub fn new(mut kv: K) -> Result<KvCoinStore<K>, Error> {
+            match kv.get(VERSION_KEY)? {
+                Some(ref version) if version.as_slice() == [KV_VERSION] => {}
+                Some(_) => return Err(Error::Storage("unknown key-value coin store version")),
+                None => {
+                    if !kv.scan_prefix(&[])?.is_empty() {
+                        return Err(Error::Storage("not a key-value coin store"));
+                    }
+                    kv.insert(VERSION_KEY, &[KV_VERSION])?;
+                }
+            }
+            Ok(KvCoinStore { kv })
+        }
+
+        /// the underlying key-value store
+        pub fn into_inner(self) -> K {
+            self.kv
+        }
+
+        fn key(prefix: u8, id: &[u8]) -> Vec<u8> {
+            let mut key = vec![prefix];
+            key.extend_from_slice(id);
+            key
+        }
+
+        fn scan<T: Decodable>(&self, prefix: u8) -> Result<Vec<(Vec<u8>, T)>, Error> {
+            self.kv
+                .scan_prefix(&[prefix])?
+                .into_iter()
+                .map(|(k, v)| Ok((k[1..].to_vec(), deserialize(v.as_slice())?)))
+                .collect()
+        }
+    }
+
+    impl<K: KeyValue> CoinStore for KvCoinStore<K> {
+        fn put_coin(&mut self, point: &OutPoint, coin: &StoredCoin) -> Result<(), Error> {
+            self.kv
+                .insert(&Self::key(COIN, &serialize(point)), &serialize(coin))
+        }
+
+        fn remove_coin(&mut self, point: &OutPoint) -> Result<(), Error> {
+            self.kv.remove(&Self::key(COIN, &serialize(point)))
+        }
+
+        fn coins(&self) -> Result<HashMap<OutPoint, StoredCoin>, Error> {
+            self.scan(COIN)?
+                .into_iter()
+                .map(|(k, coin)| Ok((deserialize(k.as_slice())?, coin)))
+                .collect()
+        }
+
+        fn put_proof(&mut self, proof: &ProvedTransaction) -> Result<(), Error> {
+            let txid = proof.get_transaction().txid();
+            self.kv
+                .insert(&Self::key(PROOF, &txid[..]), &serialize(proof))
+        }
+
+        fn remove_proof(&mut self, txid: &Txid) -> Result<(), Error> {
+            self.kv.remove(&Self::key(PROOF, &txid[..]))
+        }
+
+        fn proofs(&self) -> Result<HashMap<Txid, ProvedTransaction>, Error> {
+            Ok(self
+                .scan::<ProvedTransaction>(PROOF)?
+                .into_iter()
+                .map(|(_, proof)| (proof.get_transaction().txid(), proof))
+                .collect())
+        }
+
+        fn put_clusters(&mut self, clusters: &Clusters) -> Result<(), Error> {
+            for (key, _) in self.kv.scan_prefix(&[CLUSTER])? {
+                self.kv.remove(&key)?;
+            }
+            for (script, id) in clusters.map().iter() {
+                self.kv
+                    .insert(&Self::key(CLUSTER, script.as_bytes()), &serialize(id))?;
+            }
+            Ok(())
+        }
+
+        fn clusters(&self) -> Result<Clusters, Error> {
+            Ok(Clusters::from_map(
+                self.scan::<ClusterId>(CLUSTER)?
+                    .into_iter()
+                    .map(|(k, id)| (Script::from(k), id))
+                    .collect(),
+            ))
+        }
+
+        fn put_pending(&mut self, transaction: &Transaction) -> Result<(), Error> {
+            self.kv.insert(
+                &Self::key(PENDING, &transaction.txid()[..]),
+                &serialize(transaction),
+            )
+        }
+
+        fn remove_pending(&mut self, txid: &Txid) -> Result<(), Error> {
+            self.kv.remove(&Self::key(PENDING, &txid[..]))
+        }
+
+        fn pending(&self) -> Result<HashMap<Txid, Transaction>, Error> {
+            Ok(self
+                .scan::<Transaction>(PENDING)?
+                .into_iter()
+                .map(|(_, t)| (t.txid(), t))
+                .collect())
+        }
+
+        fn put_draft(&mut self, name: &str, draft: &Draft) -> Result<(), Error> {
+            self.kv
+                .insert(&Self::key(DRAFT, name.as_bytes()), &serialize(draft))
+        }
+
+        fn remove_draft(&mut self, name: &str) -> Result<(), Error> {
+            self.kv.remove(&Self::key(DRAFT, name.as_bytes()))
+        }
+
+        fn drafts(&self) -> Result<HashMap<String, Draft>, Error> {
+            self.scan::<Draft>(DRAFT)?
+                .into_iter()
+                .map(|(k, draft)| {
+                    Ok((
+                        String::from_utf8(k).map_err(|_| Error::Storage("invalid draft name"))?,
+                        draft,
+                    ))
+                })
+                .collect()
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.kv.flush()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use bitcoin::Network;
+
+        use coins::Coins;
+        use fixtures::{funded, master_account};
+
+        use super::*;
+
+        /// coins of one confirmed output
+        fn coins() -> Coins {
+            let (mut master, _) = master_account(Network::Regtest);
+            funded(&mut master, Network::Regtest, &[100_000]).0
+        }
+
+        #[test]
+        fn kv_store() {
+            let coins = coins();
+            let mut memory = KvCoinStore::new(BTreeMap::new()).unwrap();
+            coins.save(&mut memory).unwrap();
+            assert_eq!(memory.coins().unwrap().len(), 1);
+            assert!(Coins::load(&memory).unwrap() == coins);
+            // only coin stores are opened
+            let mut foreign = BTreeMap::new();
+            foreign.insert(b"x".to_vec(), vec![]);
+            assert!(KvCoinStore::new(foreign).is_err());
+        }
+
+        #[test]
+        fn log_store() {
+            let coins = coins();
+            let path = std::env::temp_dir().join(format!("coins-{}.kv", std::process::id()));
+            let mut log = KvCoinStore::new(LogKeyValue::open(&path).unwrap()).unwrap();
+            coins.save(&mut log).unwrap();
+            let log = KvCoinStore::new(LogKeyValue::open(&path).unwrap()).unwrap();
+            assert!(Coins::load(&log).unwrap() == coins);
+            let mut kv = log.into_inner();
+            kv.compact().unwrap();
+            let log = KvCoinStore::new(LogKeyValue::open(&path).unwrap()).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert!(Coins::load(&log).unwrap() == coins);
+        }
+
+        #[test]
+        fn torn_record() {
+            let path = std::env::temp_dir().join(format!("torn-{}.kv", std::process::id()));
+            let mut kv = LogKeyValue::open(&path).unwrap();
+            kv.insert(b"a", &[1]).unwrap();
+            kv.flush().unwrap();
+            let mut content = fs::read(&path).unwrap();
+            let length = content.len();
+            let mut torn = LogKeyValue::encode_record(b"b", Some(&[2, 3, 4]));
+            torn.pop();
+            content.extend(torn);
+            fs::write(&path, content).unwrap();
+
+            // the torn record is cut off, records flushed after it are read again
+            let mut kv = LogKeyValue::open(&path).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), length as u64);
+            assert_eq!(kv.get(b"b").unwrap(), None);
+            kv.insert(b"c", &[5]).unwrap();
+            kv.flush().unwrap();
+            let kv = LogKeyValue::open(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(kv.get(b"a").unwrap(), Some(vec![1]));
+            assert_eq!(kv.get(b"c").unwrap(), Some(vec![5]));
+        }
+    }
+}
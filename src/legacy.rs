@@ -0,0 +1,619 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Legacy Bitcoin Core wallet import
+//!
+//! Bitcoin Core wallets created before descriptor wallets are Berkeley DB files, usually named
+//! wallet.dat. The importer reads the leaf pages of the database and picks the records that
+//! hold keys:
+//!
+//! ```text
+//! key      public key -> DER encoded private key
+//! ckey     public key -> private key encrypted with the master key
+//! mkey     id         -> master key encrypted with the passphrase of the wallet
+//! hdchain             -> id of the key whose secret is the HD seed
+//! ```
+//!
+//! This is best effort: records moved to overflow pages and databases of other layouts are not
+//! read. Extracted keys are re-homed into single key accounts of a master account or the coins
+//! they control are swept to the wallet. Uncompressed keys can only be swept.
+//!
+use std::io;
+
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::{hash160, sha256d, sha512, Hash};
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::util::bip143;
+use bitcoin::util::bip32::ExtendedPrivKey;
+use bitcoin::{
+    Address, Network, OutPoint, PrivateKey, PublicKey, Script, SigHashType, Transaction, TxIn,
+    TxOut,
+};
+use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
+use crypto::{aes, blockmodes, buffer};
+
+use account::{Account, AccountAddressType, MasterAccount, Unlocker};
+use builder::RBF_SEQUENCE;
+use context::SecpContext;
+use error::Error;
+use fee::FeeRate;
+use selection::{estimate_weight, InputType};
+
+/// magic of Berkeley DB btree files
+const BTREE_MAGIC: u32 = 0x0005_3162;
+const PAGE_HEADER_LEN: usize = 26;
+/// page type of btree leaf pages
+const LEAF_PAGE: u8 = 5;
+/// item type of data stored in the page
+const KEY_DATA: u8 = 1;
+/// DER encoding of the version and the octet string header preceding the secret of a key
+const DER_SECRET_PREFIX: [u8; 5] = [0x02, 0x01, 0x01, 0x04, 0x20];
+
+fn le16(data: &[u8]) -> usize {
+    u16::from_le_bytes([data[0], data[1]]) as usize
+}
+
+/// data of item ix of a leaf page, None if it is stored elsewhere
+fn item(page: &[u8], ix: usize) -> Option<&[u8]> {
+    let offset = le16(page.get(PAGE_HEADER_LEN + 2 * ix..PAGE_HEADER_LEN + 2 * ix + 2)?);
+    let header = page.get(offset..offset + 3)?;
+    if header[2] & 0x7f != KEY_DATA {
+        return None;
+    }
+    page.get(offset + 3..offset + 3 + le16(header))
+}
+
+/// key and value of a database record
+type Record<'a> = (&'a [u8], &'a [u8]);
+
+/// records in the leaf pages of a Berkeley DB btree file
+fn records(data: &[u8]) -> Result<Vec<Record<'_>>, Error> {
+    let u32_at =
+        |pos: usize| u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+    if data.len() < 512 || u32_at(12) != BTREE_MAGIC {
+        return Err(Error::Legacy("not a Berkeley DB btree file"));
+    }
+    let page_size = u32_at(20) as usize;
+    if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+        return Err(Error::Legacy("invalid page size"));
+    }
+    let mut records = Vec::new();
+    for page in data.chunks_exact(page_size).skip(1) {
+        if page[25] != LEAF_PAGE {
+            continue;
+        }
+        let entries = le16(&page[20..22]);
+        for ix in (0..entries / 2).map(|i| 2 * i) {
+            if let (Some(key), Some(value)) = (item(page, ix), item(page, ix + 1)) {
+                records.push((key, value));
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// the secret of a DER encoded private key
+fn der_secret(der: &[u8]) -> Option<&[u8]> {
+    let pos = der
+        .windows(DER_SECRET_PREFIX.len())
+        .position(|w| w == DER_SECRET_PREFIX)?;
+    der.get(pos + DER_SECRET_PREFIX.len()..pos + DER_SECRET_PREFIX.len() + 32)
+}
+
+fn aes_cbc_decrypt(key: &[u8], iv: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decrypted = Vec::new();
+    let mut reader = buffer::RefReadBuffer::new(encrypted);
+    let mut buffer = [0u8; 1024];
+    let mut writer = buffer::RefWriteBuffer::new(&mut buffer);
+    let mut decryptor = aes::cbc_decryptor(
+        aes::KeySize::KeySize256,
+        key,
+        iv,
+        blockmodes::PkcsPadding {},
+    );
+    loop {
+        // a wrong key shows as invalid padding
+        let result = decryptor
+            .decrypt(&mut reader, &mut writer, true)
+            .map_err(|_| Error::Passphrase)?;
+        decrypted.extend(writer.take_read_buffer().take_remaining().iter().copied());
+        match result {
+            BufferResult::BufferUnderflow => break,
+            BufferResult::BufferOverflow => {}
+        }
+    }
+    Ok(decrypted)
+}
+
+/// key and iv derived from a passphrase as by OpenSSL EVP_BytesToKey with SHA512
+fn passphrase_key(passphrase: &str, salt: &[u8], rounds: u32) -> ([u8; 32], [u8; 16]) {
+    let mut hash = sha512::Hash::hash(&[passphrase.as_bytes(), salt].concat());
+    for _ in 1..rounds {
+        hash = sha512::Hash::hash(&hash[..]);
+    }
+    let mut key = [0u8; 32];
+    let mut iv = [0u8; 16];
+    key.copy_from_slice(&hash[..32]);
+    iv.copy_from_slice(&hash[32..48]);
+    (key, iv)
+}
+
+/// the private key of a secret, if it matches the public key
+fn private_key(public: &[u8], secret: &[u8], network: Network) -> Result<PrivateKey, Error> {
+    let key = PrivateKey {
+        compressed: public.len() == 33,
+        network,
+        key: SecretKey::from_slice(secret).map_err(|_| Error::Legacy("invalid private key"))?,
+    };
+    if SecpContext::new().public_from_private(&key).to_bytes() != public {
+        return Err(Error::Legacy("private key does not match its public key"));
+    }
+    Ok(key)
+}
+
+/// Keys extracted from a legacy wallet
+#[derive(Clone)]
+pub struct LegacyWallet {
+    network: Network,
+    keys: Vec<PrivateKey>,
+    hd_seed: Option<PrivateKey>,
+}
+
+impl LegacyWallet {
+    /// read the keys of a wallet.dat file, the passphrase unlocks the keys of an encrypted wallet
+    pub fn read(
+        data: &[u8],
+        passphrase: Option<&str>,
+        network: Network,
+    ) -> Result<LegacyWallet, Error> {
+        let mut keys = Vec::new();
+        let mut encrypted = Vec::new();
+        let mut master_keys = Vec::new();
+        let mut seed_id = None;
+        for (key, value) in records(data)? {
+            let mut key = io::Cursor::new(key);
+            let mut value = io::Cursor::new(value);
+            let kind = match String::consensus_decode(&mut key) {
+                Ok(kind) => kind,
+                Err(_) => continue,
+            };
+            match kind.as_str() {
+                "key" => {
+                    let public = Vec::<u8>::consensus_decode(&mut key)?;
+                    let der = Vec::<u8>::consensus_decode(&mut value)?;
+                    let secret =
+                        der_secret(der.as_slice()).ok_or(Error::Legacy("invalid private key"))?;
+                    keys.push(private_key(public.as_slice(), secret, network)?);
+                }
+                "ckey" => encrypted.push((
+                    Vec::<u8>::consensus_decode(&mut key)?,
+                    Vec::<u8>::consensus_decode(&mut value)?,
+                )),
+                "mkey" => master_keys.push((
+                    Vec::<u8>::consensus_decode(&mut value)?,
+                    Vec::<u8>::consensus_decode(&mut value)?,
+                    u32::consensus_decode(&mut value)?,
+                    u32::consensus_decode(&mut value)?,
+                )),
+                "hdchain" => {
+                    // after version and external chain counter
+                    seed_id = value
+                        .into_inner()
+                        .get(8..28)
+                        .and_then(|id| hash160::Hash::from_slice(id).ok());
+                }
+                _ => {}
+            }
+        }
+        if !encrypted.is_empty() {
+            let passphrase = passphrase.ok_or(Error::Passphrase)?;
+            let (crypted, salt, method, rounds) = master_keys
+                .first()
+                .ok_or(Error::Legacy("encrypted keys without master key"))?;
+            if *method != 0 {
+                return Err(Error::Legacy("unsupported passphrase derivation"));
+            }
+            let (key, iv) = passphrase_key(passphrase, salt.as_slice(), *rounds);
+            let master_key = aes_cbc_decrypt(&key, &iv, crypted.as_slice())?;
+            if master_key.len() != 32 {
+                return Err(Error::Passphrase);
+            }
+            for (public, crypted) in encrypted {
+                let iv = sha256d::Hash::hash(public.as_slice());
+                let secret = aes_cbc_decrypt(master_key.as_slice(), &iv[..16], crypted.as_slice())?;
+                keys.push(
+                    private_key(public.as_slice(), secret.as_slice(), network)
+                        .map_err(|_| Error::Passphrase)?,
+                );
+            }
+        }
+        let context = SecpContext::new();
+        keys.sort_by_key(|k| context.public_from_private(k).to_bytes());
+        keys.dedup_by_key(|k| context.public_from_private(k).to_bytes());
+        let hd_seed = seed_id.and_then(|id| {
+            keys.iter()
+                .find(|k| hash160::Hash::hash(&context.public_from_private(k).to_bytes()) == id)
+                .copied()
+        });
+        Ok(LegacyWallet {
+            network,
+            keys,
+            hd_seed,
+        })
+    }
+
+    /// all keys of the wallet, including those derived from the HD seed
+    pub fn keys(&self) -> &[PrivateKey] {
+        &self.keys
+    }
+
+    /// the master key of a HD wallet, its keys derive at m/0'/0'/k' and m/0'/1'/k'
+    pub fn hd_master(&self) -> Option<ExtendedPrivKey> {
+        self.hd_seed
+            .and_then(|seed| ExtendedPrivKey::new_master(self.network, &seed.key[..]).ok())
+    }
+
+    /// add a single key account of each address type for every compressed key, account
+    /// numbers count up from first with a sub account for each address type.
+    /// Returns the ids of the new accounts.
+    pub fn rehome(
+        &self,
+        master: &mut MasterAccount,
+        unlocker: &mut Unlocker,
+        address_types: &[AccountAddressType],
+        first: u32,
+    ) -> Result<Vec<(u32, u32)>, Error> {
+        let mut ids = Vec::new();
+        for (i, key) in self.keys.iter().filter(|k| k.compressed).enumerate() {
+            for (sub, address_type) in address_types.iter().enumerate() {
+                ids.push((key, *address_type, (first + i as u32, sub as u32)));
+            }
+        }
+        if ids.iter().any(|(_, _, id)| master.get(*id).is_some()) {
+            return Err(Error::Legacy("account number in use"));
+        }
+        for (key, address_type, (account, sub)) in ids.iter() {
+            master.add_account(Account::new_single_key(
+                unlocker,
+                *address_type,
+                *account,
+                *sub,
+                key,
+            )?);
+        }
+        Ok(ids.into_iter().map(|(_, _, id)| id).collect())
+    }
+
+    /// a signed transaction paying the outputs controlled by the keys to script, less the fee
+    pub fn sweep(
+        &self,
+        outputs: &[(OutPoint, TxOut)],
+        script: Script,
+        feerate: FeeRate,
+    ) -> Result<Transaction, Error> {
+        let context = SecpContext::new();
+        let mut spends = Vec::new();
+        for (_, output) in outputs {
+            let spend = self
+                .keys
+                .iter()
+                .find_map(|key| {
+                    let public = context.public_from_private(key);
+                    [
+                        AccountAddressType::P2PKH,
+                        AccountAddressType::P2WPKH,
+                        AccountAddressType::P2SHWPKH,
+                    ]
+                    .iter()
+                    .find(|t| {
+                        self.address(**t, &public).map(|a| a.script_pubkey())
+                            == Some(output.script_pubkey.clone())
+                    })
+                    .map(|t| (key, public, *t))
+                })
+                .ok_or(Error::Legacy("output is not controlled by the keys"))?;
+            spends.push(spend);
+        }
+        let inputs = outputs
+            .iter()
+            .map(|(_, o)| InputType::of(&o.script_pubkey))
+            .collect::<Vec<_>>();
+        // estimates assume compressed keys
+        let weight = estimate_weight(&inputs, std::slice::from_ref(&script))
+            + 4 * 32 * spends.iter().filter(|(k, _, _)| !k.compressed).count() as u64;
+        let value = outputs
+            .iter()
+            .map(|(_, o)| o.value)
+            .sum::<u64>()
+            .checked_sub(feerate.fee(weight))
+            .filter(|v| *v >= script.dust_value())
+            .ok_or(Error::Legacy("outputs do not pay the fee"))?;
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: outputs
+                .iter()
+                .map(|(point, _)| TxIn {
+                    previous_output: *point,
+                    script_sig: Script::new(),
+                    sequence: RBF_SEQUENCE,
+                    witness: Vec::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value,
+                script_pubkey: script,
+            }],
+        };
+        let unsigned = transaction.clone();
+        let mut bip143hasher = bip143::SigHashCache::new(&unsigned);
+        for (ix, ((_, output), (key, public, address_type))) in
+            outputs.iter().zip(spends.iter()).enumerate()
+        {
+            let script_code = Address::p2pkh(public, self.network).script_pubkey();
+            let sighash = match address_type {
+                AccountAddressType::P2PKH => {
+                    unsigned.signature_hash(ix, &script_code, SigHashType::All.as_u32())
+                }
+                _ => bip143hasher.signature_hash(ix, &script_code, output.value, SigHashType::All),
+            };
+            let mut signature = context.sign(&sighash[..], key)?.serialize_der().to_vec();
+            signature.push(SigHashType::All.as_u32() as u8);
+            let input = &mut transaction.input[ix];
+            match address_type {
+                AccountAddressType::P2PKH => {
+                    input.script_sig = Builder::new()
+                        .push_slice(signature.as_slice())
+                        .push_slice(public.to_bytes().as_slice())
+                        .into_script();
+                }
+                _ => {
+                    if *address_type == AccountAddressType::P2SHWPKH {
+                        let redeem = self.address(AccountAddressType::P2WPKH, public);
+                        input.script_sig = Builder::new()
+                            .push_slice(redeem.expect("compressed").script_pubkey().as_bytes())
+                            .into_script();
+                    }
+                    input.witness = vec![signature, public.to_bytes()];
+                }
+            }
+        }
+        Ok(transaction)
+    }
+
+    /// address of a key, None if the type needs a compressed key
+    fn address(&self, address_type: AccountAddressType, public: &PublicKey) -> Option<Address> {
+        match address_type {
+            AccountAddressType::P2PKH => Some(Address::p2pkh(public, self.network)),
+            AccountAddressType::P2WPKH => Address::p2wpkh(public, self.network).ok(),
+            AccountAddressType::P2SHWPKH => Address::p2shwpkh(public, self.network).ok(),
+            AccountAddressType::P2WSH(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::consensus::serialize;
+    use bitcoin::WPubkeyHash;
+    use rand::{thread_rng, RngCore};
+
+    use fixtures::{master_account, PASSPHRASE};
+
+    use super::*;
+
+    /// a database with a single leaf page of records
+    fn database(records: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        const PAGE_SIZE: usize = 4096;
+        let mut meta = vec![0u8; PAGE_SIZE];
+        meta[12..16].copy_from_slice(&BTREE_MAGIC.to_le_bytes());
+        meta[20..24].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        let mut leaf = vec![0u8; PAGE_SIZE];
+        leaf[25] = LEAF_PAGE;
+        let items = records
+            .iter()
+            .flat_map(|(k, v)| vec![k, v])
+            .collect::<Vec<_>>();
+        leaf[20..22].copy_from_slice(&(items.len() as u16).to_le_bytes());
+        let mut end = PAGE_SIZE;
+        for (ix, item) in items.iter().enumerate() {
+            end -= item.len() + 3;
+            let offset = PAGE_HEADER_LEN + 2 * ix;
+            leaf[offset..offset + 2].copy_from_slice(&(end as u16).to_le_bytes());
+            leaf[end..end + 2].copy_from_slice(&(item.len() as u16).to_le_bytes());
+            leaf[end + 2] = KEY_DATA;
+            leaf[end + 3..end + 3 + item.len()].copy_from_slice(item);
+        }
+        [meta, leaf].concat()
+    }
+
+    fn aes_cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut encryptor = aes::cbc_encryptor(
+            aes::KeySize::KeySize256,
+            key,
+            iv,
+            blockmodes::PkcsPadding {},
+        );
+        let mut reader = buffer::RefReadBuffer::new(data);
+        let mut buffer = [0u8; 1024];
+        let mut writer = buffer::RefWriteBuffer::new(&mut buffer);
+        encryptor.encrypt(&mut reader, &mut writer, true).unwrap();
+        writer.take_read_buffer().take_remaining().to_vec()
+    }
+
+    fn random_key(compressed: bool) -> PrivateKey {
+        let mut secret = [0u8; 32];
+        thread_rng().fill_bytes(&mut secret);
+        PrivateKey {
+            compressed,
+            network: Network::Testnet,
+            key: SecretKey::from_slice(&secret).unwrap(),
+        }
+    }
+
+    fn record(kind: &str, public: &PublicKey) -> Vec<u8> {
+        [serialize(&kind.to_string()), serialize(&public.to_bytes())].concat()
+    }
+
+    /// an HD seed, a compressed and an uncompressed key
+    fn keys() -> [PrivateKey; 3] {
+        [random_key(true), random_key(true), random_key(false)]
+    }
+
+    /// a wallet of unencrypted keys, the first is the HD seed
+    fn plain(keys: &[PrivateKey; 3]) -> LegacyWallet {
+        let context = SecpContext::new();
+        let mut records = keys
+            .iter()
+            .map(|k| {
+                let der = [
+                    &[0x30, 0x81, 0xd3][..],
+                    &DER_SECRET_PREFIX,
+                    &k.key[..],
+                    &[0xa0],
+                ]
+                .concat();
+                (
+                    record("key", &context.public_from_private(k)),
+                    [serialize(&der), [0u8; 32].to_vec()].concat(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let seed_id = hash160::Hash::hash(&context.public_from_private(&keys[0]).to_bytes());
+        records.push((
+            serialize(&"hdchain".to_string()),
+            [&[1, 0, 0, 0, 7, 0, 0, 0][..], &seed_id[..]].concat(),
+        ));
+        LegacyWallet::read(&database(&records), None, Network::Testnet).unwrap()
+    }
+
+    #[test]
+    fn extract() {
+        let keys = keys();
+        let plain = plain(&keys);
+        assert_eq!(plain.keys().len(), 3);
+        assert!(keys.iter().all(|k| plain.keys().contains(k)));
+        assert_eq!(
+            plain.hd_master(),
+            Some(ExtendedPrivKey::new_master(Network::Testnet, &keys[0].key[..]).unwrap())
+        );
+        assert!(LegacyWallet::read(&[0u8; 8192], None, Network::Testnet).is_err());
+    }
+
+    #[test]
+    fn encrypted() {
+        let context = SecpContext::new();
+        let keys = keys();
+        let mut master_key = [0u8; 32];
+        thread_rng().fill_bytes(&mut master_key);
+        let salt = [7u8; 8];
+        let (key, iv) = passphrase_key(PASSPHRASE, &salt, 100);
+        let mut mkey = serialize(&aes_cbc_encrypt(&key, &iv, &master_key));
+        mkey.extend(serialize(&salt.to_vec()));
+        mkey.extend(serialize(&0u32));
+        mkey.extend(serialize(&100u32));
+        let mut records = keys
+            .iter()
+            .map(|k| {
+                let public = context.public_from_private(k);
+                let iv = sha256d::Hash::hash(&public.to_bytes());
+                (
+                    record("ckey", &public),
+                    serialize(&aes_cbc_encrypt(&master_key, &iv[..16], &k.key[..])),
+                )
+            })
+            .collect::<Vec<_>>();
+        records.push((
+            [serialize(&"mkey".to_string()), serialize(&1u32)].concat(),
+            mkey,
+        ));
+        let data = database(&records);
+        assert!(LegacyWallet::read(&data, None, Network::Testnet).is_err());
+        assert!(LegacyWallet::read(&data, Some("wrong"), Network::Testnet).is_err());
+        let encrypted = LegacyWallet::read(&data, Some(PASSPHRASE), Network::Testnet).unwrap();
+        assert_eq!(encrypted.keys(), plain(&keys).keys());
+        assert!(encrypted.hd_master().is_none());
+    }
+
+    #[test]
+    fn rehome() {
+        // compressed keys only
+        let context = SecpContext::new();
+        let plain = plain(&keys());
+        let (mut master, mut unlocker) = master_account(Network::Testnet);
+        let types = [AccountAddressType::P2WPKH, AccountAddressType::P2PKH];
+        let ids = plain
+            .rehome(&mut master, &mut unlocker, &types, 10)
+            .unwrap();
+        assert_eq!(ids, vec![(10, 0), (10, 1), (11, 0), (11, 1)]);
+        let compressed = plain.keys().iter().find(|k| k.compressed).unwrap();
+        assert_eq!(
+            master.get((10, 0)).unwrap().get_key(0).unwrap().address,
+            Address::p2wpkh(&context.public_from_private(compressed), Network::Testnet).unwrap()
+        );
+        assert!(plain
+            .rehome(&mut master, &mut unlocker, &types, 11)
+            .is_err());
+    }
+
+    #[test]
+    fn sweep() {
+        // coins of all key types
+        let context = SecpContext::new();
+        let keys = keys();
+        let plain = plain(&keys);
+        let outputs = [
+            Address::p2pkh(&context.public_from_private(&keys[2]), Network::Testnet),
+            Address::p2wpkh(&context.public_from_private(&keys[1]), Network::Testnet).unwrap(),
+            Address::p2shwpkh(&context.public_from_private(&keys[0]), Network::Testnet).unwrap(),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(vout, a)| {
+            (
+                OutPoint {
+                    txid: bitcoin::Txid::default(),
+                    vout: vout as u32,
+                },
+                TxOut {
+                    value: 100_000,
+                    script_pubkey: a.script_pubkey(),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+        let to = Script::new_v0_wpkh(&WPubkeyHash::hash(&[1]));
+        let sweep = plain
+            .sweep(&outputs, to.clone(), FeeRate::from_sat_per_vb(2))
+            .unwrap();
+        assert_eq!(sweep.output[0].script_pubkey, to);
+        assert!(sweep.output[0].value < 300_000 && sweep.output[0].value > 299_000);
+        sweep
+            .verify(|point| outputs.get(point.vout as usize).map(|(_, o)| o.clone()))
+            .unwrap();
+        let mut foreign = outputs.clone();
+        foreign[0].1.script_pubkey = Address::p2wpkh(
+            &context.public_from_private(&random_key(true)),
+            Network::Testnet,
+        )
+        .unwrap()
+        .script_pubkey();
+        assert!(plain
+            .sweep(&foreign, Script::new(), FeeRate::from_sat_per_vb(2))
+            .is_err());
+    }
+}
@@ -33,9 +33,60 @@ extern crate serde;
 extern crate serde_json;
 
 pub mod account;
+pub mod asynchronous;
+pub mod backend;
+pub mod backup;
+pub mod bip21;
+pub mod bip324;
+pub mod bip353;
+pub mod bloom;
+pub mod broadcast;
+pub mod builder;
+pub mod cache;
+pub mod changeset;
+pub mod cluster;
+pub mod coinjoin;
 pub mod coins;
+pub mod collaborative;
 pub mod context;
+pub mod coordinator;
+pub mod cosigner;
+pub mod descriptor;
+pub mod download;
+pub mod ephemeral;
 pub mod error;
+pub mod escalation;
+pub mod export;
+pub mod failover;
+pub mod fee;
+#[cfg(test)]
+mod fixtures;
+pub mod history;
+pub mod inheritance;
+pub mod inspect;
+pub mod json;
+pub mod keycache;
+pub mod kv;
+pub mod legacy;
+pub mod message;
+pub mod metered;
+pub mod migration;
 pub mod mnemonic;
+pub mod multisig;
+pub mod neutrino;
+pub mod offline;
+pub mod p2p;
+pub mod package;
+pub mod payjoin;
+pub mod peers;
+pub mod policy;
+pub mod proxy;
 pub mod proved;
+pub mod psbt;
+pub mod scan;
+pub mod selection;
+pub mod signer;
 pub mod sss;
+pub mod storage;
+pub mod vault;
+pub mod zmq;
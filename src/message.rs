@@ -0,0 +1,236 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Signed messages
+//!
+//! Proof of ownership of an address in the format of Bitcoin Core's signmessage, a base64
+//! encoded recoverable signature of the message. The header byte of the signature tells the
+//! address type as in BIP137, so P2SH-P2WPKH and P2WPKH addresses can be signed too.
+//!
+use bitcoin::secp256k1::recovery::{RecoverableSignature, RecoveryId};
+use bitcoin::util::address::AddressType;
+use bitcoin::util::misc::signed_msg_hash;
+use bitcoin::Address;
+
+use account::{AccountAddressType, MasterAccount, Unlocker};
+use context::SecpContext;
+use error::Error;
+
+/// header of an uncompressed P2PKH signature, followed by compressed P2PKH, P2SH-P2WPKH, P2WPKH
+const HEADER_BASE: u8 = 27;
+
+/// sign a message with the key of an address of the wallet
+/// returns the base64 encoded signature
+pub fn sign_message(
+    master: &MasterAccount,
+    unlocker: &mut Unlocker,
+    address: &Address,
+    message: &str,
+) -> Result<String, Error> {
+    let script = address.script_pubkey();
+    let derivation = master
+        .get_scripts()
+        .find(|(s, _)| *s == script)
+        .map(|(_, d)| d)
+        .ok_or(Error::Message("address is not of this wallet"))?;
+    let account = master
+        .get((derivation.account, derivation.sub))
+        .expect("account of script");
+    let kind = match account.address_type() {
+        AccountAddressType::P2PKH => 1,
+        AccountAddressType::P2SHWPKH => 2,
+        AccountAddressType::P2WPKH => 3,
+        AccountAddressType::P2WSH(_) => {
+            return Err(Error::Message("can not sign for script addresses"))
+        }
+    };
+    let private = unlocker.unlock(
+        account.address_type(),
+        derivation.account,
+        derivation.sub,
+        derivation.kix,
+        derivation.tweak,
+    )?;
+    let signature = unlocker
+        .context()
+        .sign_recoverable(&signed_msg_hash(message)[..], &private)?;
+    let (recid, compact) = signature.serialize_compact();
+    let mut serialized = vec![HEADER_BASE + 4 * kind + recid.to_i32() as u8];
+    serialized.extend_from_slice(&compact[..]);
+    Ok(base64_encode(serialized.as_slice()))
+}
+
+/// verify a base64 encoded signature of a message by the key of an address
+/// Segwit addresses also accept signatures with the header of compressed P2PKH as some
+/// wallets create those for all single key addresses.
+pub fn verify_message(address: &Address, signature: &str, message: &str) -> Result<bool, Error> {
+    let signature =
+        base64_decode(signature).ok_or(Error::Message("signature is not base64 encoded"))?;
+    if signature.len() != 65 || signature[0] < HEADER_BASE || signature[0] > HEADER_BASE + 15 {
+        return Err(Error::Message("invalid signature"));
+    }
+    let kind = (signature[0] - HEADER_BASE) / 4;
+    let recid = RecoveryId::from_i32(((signature[0] - HEADER_BASE) % 4) as i32)?;
+    let recoverable = RecoverableSignature::from_compact(&signature[1..], recid)?;
+    let public =
+        SecpContext::new().recover(&signed_msg_hash(message)[..], &recoverable, kind > 0)?;
+    let network = address.network;
+    Ok(match (address.address_type(), kind) {
+        (Some(AddressType::P2pkh), 0) | (Some(AddressType::P2pkh), 1) => {
+            *address == Address::p2pkh(&public, network)
+        }
+        (Some(AddressType::P2sh), 1) | (Some(AddressType::P2sh), 2) => {
+            Address::p2shwpkh(&public, network).ok().as_ref() == Some(address)
+        }
+        (Some(AddressType::P2wpkh), 1) | (Some(AddressType::P2wpkh), 3) => {
+            Address::p2wpkh(&public, network).ok().as_ref() == Some(address)
+        }
+        _ => false,
+    })
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |b, (i, c)| b | (*c as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim().as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut data = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (n, chunk) in encoded.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && n + 1 != encoded.len() / 4) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, c) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64.iter().position(|b| b == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+        for i in 0..3 - padding {
+            data.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::misc::MessageSignature;
+    use bitcoin::Network;
+    use std::str::FromStr;
+
+    use account::{Account, MasterKeyEntropy};
+    use fixtures::PASSPHRASE;
+
+    use super::*;
+
+    #[test]
+    fn base64() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ]
+        .iter()
+        {
+            assert_eq!(base64_encode(data.as_bytes()), *encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), data.as_bytes());
+        }
+        assert!(base64_decode("Zg=").is_none());
+        assert!(base64_decode("Zg==Zm9v").is_none());
+        assert!(base64_decode("Z!==").is_none());
+    }
+
+    #[test]
+    fn sign_verify() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let types = [
+            AccountAddressType::P2PKH,
+            AccountAddressType::P2SHWPKH,
+            AccountAddressType::P2WPKH,
+        ];
+        for (n, address_type) in types.iter().enumerate() {
+            master.add_account(Account::new(&mut unlocker, *address_type, n as u32, 0, 1).unwrap());
+        }
+        let message = "I own this address";
+        let addresses = (0..3)
+            .map(|n| {
+                master
+                    .get_mut((n, 0))
+                    .unwrap()
+                    .next_key()
+                    .unwrap()
+                    .address
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        for (n, address) in addresses.iter().enumerate() {
+            let signature = sign_message(&master, &mut unlocker, address, message).unwrap();
+            let header = base64_decode(&signature).unwrap()[0];
+            assert_eq!((header - HEADER_BASE) / 4, n as u8 + 1);
+            assert!(verify_message(address, &signature, message).unwrap());
+            assert!(!verify_message(address, &signature, "I do not").unwrap());
+            assert!(!verify_message(&addresses[(n + 1) % 3], &signature, message).unwrap());
+        }
+        // as rust-bitcoin reads P2PKH signatures
+        let signature =
+            base64_decode(&sign_message(&master, &mut unlocker, &addresses[0], message).unwrap())
+                .unwrap();
+        assert!(MessageSignature::from_slice(signature.as_slice())
+            .unwrap()
+            .is_signed_by_address(&Secp256k1::new(), &addresses[0], signed_msg_hash(message))
+            .unwrap());
+        // a P2WPKH signature with the header of compressed P2PKH
+        let mut signature =
+            base64_decode(&sign_message(&master, &mut unlocker, &addresses[2], message).unwrap())
+                .unwrap();
+        signature[0] -= 8;
+        let signature = base64_encode(signature.as_slice());
+        assert!(verify_message(&addresses[2], &signature, message).unwrap());
+        assert!(!verify_message(&addresses[1], &signature, message).unwrap());
+        assert!(verify_message(&addresses[0], "bm90IGEgc2lnbmF0dXJl", message).is_err());
+        let foreign =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        assert!(sign_message(&master, &mut unlocker, &foreign, message).is_err());
+    }
+}
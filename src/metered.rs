@@ -0,0 +1,318 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Metered sync
+//!
+//! Mobile wallets sync over connections that are slow or paid by the byte. MeteredBackend
+//! wraps a backend, counts the bytes of headers, filters, blocks and relayed transactions of
+//! a session and throttles downloads to a rate, so a sync does not saturate the link.
+//!
+//! With filters_only a block is fetched only after its filter was served, a sync then never
+//! falls back to downloading every block from a backend that serves no filters, it fails.
+//! Apps read bandwidth to warn users before a restore on a metered connection.
+//!
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::consensus::serialize;
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHeader, OutPoint, Transaction, Txid};
+
+use backend::{ChainBackend, TxStatus};
+use error::Error;
+use fee::{FeeHistogram, FeeRate};
+
+/// size of a serialized block header
+const HEADER_SIZE: u64 = 80;
+
+/// Bytes moved in a session
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Bandwidth {
+    /// headers and filter headers downloaded
+    pub headers: u64,
+    /// filters downloaded
+    pub filters: u64,
+    /// blocks downloaded
+    pub blocks: u64,
+    /// transactions relayed
+    pub uploaded: u64,
+}
+
+impl Bandwidth {
+    /// bytes downloaded
+    pub fn downloaded(&self) -> u64 {
+        self.headers + self.filters + self.blocks
+    }
+
+    /// bytes downloaded and uploaded
+    pub fn total(&self) -> u64 {
+        self.downloaded() + self.uploaded
+    }
+}
+
+/// A backend that counts and throttles its traffic
+pub struct MeteredBackend<B> {
+    backend: B,
+    /// bytes per second downloads are throttled to
+    rate: Option<u64>,
+    filters_only: bool,
+    bandwidth: Cell<Bandwidth>,
+    session: Cell<Instant>,
+    /// blocks whose filter was served
+    filtered: RefCell<HashSet<BlockHash>>,
+}
+
+impl<B: ChainBackend> MeteredBackend<B> {
+    /// count traffic of the backend, unthrottled
+    pub fn new(backend: B) -> MeteredBackend<B> {
+        MeteredBackend {
+            backend,
+            rate: None,
+            filters_only: false,
+            bandwidth: Cell::new(Bandwidth::default()),
+            session: Cell::new(Instant::now()),
+            filtered: RefCell::new(HashSet::new()),
+        }
+    }
+
+    /// throttle downloads to bytes per second
+    pub fn rate(mut self, bytes_per_second: u64) -> MeteredBackend<B> {
+        self.rate = Some(bytes_per_second.max(1));
+        self
+    }
+
+    /// fetch a block only after its filter was served
+    pub fn filters_only(mut self) -> MeteredBackend<B> {
+        self.filters_only = true;
+        self
+    }
+
+    /// bytes moved since the session started
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.bandwidth.get()
+    }
+
+    /// start a new session, counters and throttle start from zero
+    pub fn reset(&self) {
+        self.bandwidth.set(Bandwidth::default());
+        self.session.set(Instant::now());
+        self.filtered.borrow_mut().clear();
+    }
+
+    /// the wrapped backend
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    /// count bytes and wait until downloads are back within the rate
+    fn meter<F: FnOnce(&mut Bandwidth)>(&self, count: F) {
+        let mut bandwidth = self.bandwidth.get();
+        count(&mut bandwidth);
+        self.bandwidth.set(bandwidth);
+        if let Some(rate) = self.rate {
+            let due = Duration::from_secs_f64(bandwidth.downloaded() as f64 / rate as f64);
+            let elapsed = self.session.get().elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
+
+impl<B: ChainBackend> ChainBackend for MeteredBackend<B> {
+    fn tip(&self) -> Result<u32, Error> {
+        self.backend.tip()
+    }
+
+    fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        let header = self.backend.header(height)?;
+        if header.is_some() {
+            self.meter(|b| b.headers += HEADER_SIZE);
+        }
+        Ok(header)
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        if self.filters_only && !self.filtered.borrow().contains(hash) {
+            return Err(Error::Backend("block requested before its filter"));
+        }
+        let block = self.backend.block(hash)?;
+        self.meter(|b| b.blocks += block.get_size() as u64);
+        Ok(block)
+    }
+
+    fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+        let filter = self.backend.filter(hash)?;
+        match filter {
+            Some(ref filter) => {
+                self.filtered.borrow_mut().insert(*hash);
+                self.meter(|b| b.filters += filter.content.len() as u64);
+            }
+            None if self.filters_only => {
+                return Err(Error::Backend("backend serves no filters"));
+            }
+            None => {}
+        }
+        Ok(filter)
+    }
+
+    fn filter_header(&self, hash: &BlockHash) -> Result<Option<FilterHeader>, Error> {
+        let header = self.backend.filter_header(hash)?;
+        if header.is_some() {
+            self.meter(|b| b.headers += 32);
+        }
+        Ok(header)
+    }
+
+    fn tx_status(&self, txid: &Txid) -> Result<TxStatus, Error> {
+        self.backend.tx_status(txid)
+    }
+
+    fn spender(&self, point: &OutPoint) -> Result<Option<Txid>, Error> {
+        self.backend.spender(point)
+    }
+
+    fn mempool_accept(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Option<Vec<Option<String>>>, Error> {
+        self.backend.mempool_accept(transactions)
+    }
+
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+        let txid = self.backend.broadcast(transaction)?;
+        self.meter(|b| b.uploaded += serialize(transaction).len() as u64);
+        Ok(txid)
+    }
+
+    fn broadcast_package(&self, transactions: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        let txids = self.backend.broadcast_package(transactions)?;
+        let size = transactions
+            .iter()
+            .map(|t| serialize(t).len() as u64)
+            .sum::<u64>();
+        self.meter(|b| b.uploaded += size);
+        Ok(txids)
+    }
+
+    fn feerate(&self, target: u16) -> Result<FeeRate, Error> {
+        self.backend.feerate(target)
+    }
+
+    fn fee_histogram(&self) -> Result<Option<FeeHistogram>, Error> {
+        self.backend.fee_histogram()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::bip158::BlockFilterWriter;
+    use bitcoin::Network;
+
+    use super::*;
+
+    struct Genesis {
+        block: Block,
+        filters: bool,
+    }
+
+    impl ChainBackend for Genesis {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(0)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(Some(self.block.header).filter(|_| height == 0))
+        }
+
+        fn block(&self, _: &BlockHash) -> Result<Block, Error> {
+            Ok(self.block.clone())
+        }
+
+        fn filter(&self, _: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            if !self.filters {
+                return Ok(None);
+            }
+            let mut content = Vec::new();
+            {
+                let mut writer = BlockFilterWriter::new(&mut content, &self.block);
+                writer.add_output_scripts();
+                writer.finish().unwrap();
+            }
+            Ok(Some(BlockFilter::new(content.as_slice())))
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    #[test]
+    fn metered_backend() {
+        let block = genesis_block(Network::Testnet);
+        let hash = block.block_hash();
+        let size = block.get_size() as u64;
+        let backend = MeteredBackend::new(Genesis {
+            block: block.clone(),
+            filters: true,
+        })
+        .filters_only();
+
+        assert!(backend.block(&hash).is_err());
+        let filter = backend.filter(&hash).unwrap().unwrap();
+        backend.header(0).unwrap();
+        backend.block(&hash).unwrap();
+        backend.broadcast(&block.txdata[0]).unwrap();
+        let bandwidth = backend.bandwidth();
+        assert_eq!(bandwidth.filters, filter.content.len() as u64);
+        assert_eq!(bandwidth.headers, HEADER_SIZE);
+        assert_eq!(bandwidth.blocks, size);
+        assert_eq!(bandwidth.uploaded, serialize(&block.txdata[0]).len() as u64);
+        backend.reset();
+        assert_eq!(backend.bandwidth().total(), 0);
+        assert!(backend.block(&hash).is_err());
+
+        let unfiltered = MeteredBackend::new(Genesis {
+            block: block.clone(),
+            filters: false,
+        });
+        assert!(unfiltered.filter(&hash).unwrap().is_none());
+        assert!(unfiltered.filters_only().filter(&hash).is_err());
+
+        // two blocks at twice their size per second take a second
+        let throttled = MeteredBackend::new(Genesis {
+            block,
+            filters: false,
+        })
+        .rate(size * 2);
+        let started = Instant::now();
+        throttled.block(&hash).unwrap();
+        throttled.block(&hash).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        assert_eq!(throttled.bandwidth().downloaded(), 2 * size);
+    }
+}
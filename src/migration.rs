@@ -0,0 +1,214 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Format migration
+//!
+//! Stored formats start with a magic and a version byte:
+//!
+//! ```text
+//! magic | version | body
+//! ```
+//!
+//! A Migrations registry knows the steps upgrading the body of a format from one version to the
+//! next. Older data is upgraded step by step to the current version, files are backed up to
+//! `<path>.v<version>.bak` before they are replaced with the upgraded content.
+//!
+//! Steps of encrypted wallet files upgrade the decrypted payload, the wallet file seals the
+//! upgraded payload again in the current format.
+//!
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bitcoin::consensus::Encodable;
+use bitcoin::VarInt;
+
+use coins::{COIN_FILE_MAGIC, COIN_FILE_VERSION, SNAPSHOT_MAGIC, SNAPSHOT_VERSION};
+use error::Error;
+use storage::{write_atomic, WALLET_FILE_MAGIC, WALLET_FILE_VERSION};
+
+/// upgrade the body of a format version to the next version
+pub type Step = fn(&[u8]) -> Result<Vec<u8>, Error>;
+
+/// Migrations of a stored format
+#[derive(Clone)]
+pub struct Migrations {
+    magic: [u8; 4],
+    current: u8,
+    steps: BTreeMap<u8, Step>,
+}
+
+impl Migrations {
+    /// no migrations yet for a format at its current version
+    pub fn new(magic: &[u8; 4], current: u8) -> Migrations {
+        Migrations {
+            magic: *magic,
+            current,
+            steps: BTreeMap::new(),
+        }
+    }
+
+    /// register the step from version to version + 1
+    pub fn step(mut self, from: u8, step: Step) -> Migrations {
+        self.steps.insert(from, step);
+        self
+    }
+
+    pub fn current(&self) -> u8 {
+        self.current
+    }
+
+    /// the stored version of data
+    pub fn version(&self, data: &[u8]) -> Result<u8, Error> {
+        if data.len() < 5 || data[..4] != self.magic {
+            return Err(Error::Unsupported("unexpected format magic"));
+        }
+        if data[4] == 0 || data[4] > self.current {
+            return Err(Error::Unsupported(
+                "format version is newer than this release",
+            ));
+        }
+        Ok(data[4])
+    }
+
+    /// true if data is stored in an older version
+    pub fn needs_migration(&self, data: &[u8]) -> Result<bool, Error> {
+        Ok(self.version(data)? < self.current)
+    }
+
+    /// data upgraded to the current version
+    pub fn migrate(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut version = self.version(data)?;
+        let mut body = data[5..].to_vec();
+        while version < self.current {
+            let step = self
+                .steps
+                .get(&version)
+                .ok_or(Error::Unsupported("no migration from stored version"))?;
+            body = step(body.as_slice())?;
+            version += 1;
+        }
+        let mut migrated = self.magic.to_vec();
+        migrated.push(version);
+        migrated.extend(body);
+        Ok(migrated)
+    }
+
+    /// upgrade the file at path to the current version, the prior file is kept as a backup
+    /// returns the path of the backup if the file was migrated, a missing file is not an error
+    pub fn migrate_file<P: AsRef<Path>>(&self, path: P) -> Result<Option<PathBuf>, Error> {
+        let path = path.as_ref();
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::IO(e)),
+        };
+        if !self.needs_migration(data.as_slice())? {
+            return Ok(None);
+        }
+        let migrated = self.migrate(data.as_slice())?;
+        let backup = self.backup(path, data.as_slice())?;
+        write_atomic(path, migrated.as_slice())?;
+        Ok(Some(backup))
+    }
+
+    /// keep data stored at path before an upgrade replaces it, returns the path of the backup
+    pub fn backup<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<PathBuf, Error> {
+        let version = self.version(data)?;
+        let mut backup = path.as_ref().to_path_buf().into_os_string();
+        backup.push(format!(".v{}.bak", version));
+        let backup = PathBuf::from(backup);
+        write_atomic(&backup, data)?;
+        Ok(backup)
+    }
+}
+
+/// an empty list appended, version 2 added drafts to coin stores and snapshots and notes to
+/// wallet files, version 4 vaults to wallet files
+fn append_empty_list(body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut body = body.to_vec();
+    VarInt(0).consensus_encode(&mut body)?;
+    Ok(body)
+}
+
+/// the payload of wallet files stayed in version 3, the encryption changed
+fn unchanged(body: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(body.to_vec())
+}
+
+/// migrations of coin store files
+pub fn coin_store() -> Migrations {
+    Migrations::new(COIN_FILE_MAGIC, COIN_FILE_VERSION).step(1, append_empty_list)
+}
+
+/// migrations of coin snapshots
+pub fn snapshot() -> Migrations {
+    Migrations::new(SNAPSHOT_MAGIC, SNAPSHOT_VERSION).step(1, append_empty_list)
+}
+
+/// migrations of the payload of wallet files
+pub fn wallet_file() -> Migrations {
+    Migrations::new(WALLET_FILE_MAGIC, WALLET_FILE_VERSION)
+        .step(1, append_empty_list)
+        .step(2, unchanged)
+        .step(3, append_empty_list)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn one(body: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok([body, &[1]].concat())
+    }
+
+    fn two(body: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok([body, &[2]].concat())
+    }
+
+    #[test]
+    fn migrate() {
+        let migrations = Migrations::new(b"TEST", 3).step(1, one).step(2, two);
+        assert_eq!(
+            migrations.migrate(b"TEST\x01\x00").unwrap(),
+            b"TEST\x03\x00\x01\x02".to_vec()
+        );
+        assert_eq!(
+            migrations.migrate(b"TEST\x02\x00").unwrap(),
+            b"TEST\x03\x00\x02".to_vec()
+        );
+        assert_eq!(
+            migrations.migrate(b"TEST\x03\x00").unwrap(),
+            b"TEST\x03\x00".to_vec()
+        );
+        assert!(migrations.migrate(b"TEST\x04\x00").is_err());
+        assert!(migrations.migrate(b"BEST\x01\x00").is_err());
+        assert!(Migrations::new(b"TEST", 3)
+            .step(2, two)
+            .migrate(b"TEST\x01\x00")
+            .is_err());
+
+        let path = std::env::temp_dir().join(format!("migrate-{}.dat", std::process::id()));
+        fs::write(&path, b"TEST\x01\x00").unwrap();
+        let backup = migrations.migrate_file(&path).unwrap().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"TEST\x03\x00\x01\x02".to_vec());
+        assert_eq!(fs::read(&backup).unwrap(), b"TEST\x01\x00".to_vec());
+        assert!(migrations.migrate_file(&path).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+    }
+}
@@ -28,19 +28,24 @@ use crypto::{
 };
 use error::Error;
 use rand::{thread_rng, RngCore};
+use std::fmt;
 use std::io::Cursor;
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Mnemonic(Vec<usize>);
 
-impl ToString for Mnemonic {
-    fn to_string(&self) -> String {
-        self.0
-            .iter()
-            .map(|i| WORDS[*i])
-            .collect::<Vec<_>>()
-            .as_slice()
-            .join(" ")
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.0
+                .iter()
+                .map(|i| WORDS[*i])
+                .collect::<Vec<_>>()
+                .as_slice()
+                .join(" ")
+        )
     }
 }
 
@@ -59,6 +64,7 @@ impl Mnemonic {
         self.0.iter().map(|s| WORDS[*s])
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Mnemonic, Error> {
         let words: Vec<_> = s.split(' ').collect();
         if words.len() < 6 || words.len() % 6 != 0 {
@@ -100,7 +106,7 @@ impl Mnemonic {
 
     /// create a mnemonic for some data
     pub fn new(data: &[u8]) -> Result<Mnemonic, Error> {
-        if data.len() % 4 != 0 {
+        if !data.len().is_multiple_of(4) {
             return Err(Error::Mnemonic(
                 "Data for mnemonic should have a length divisible by 4",
             ));
@@ -162,6 +168,7 @@ impl Mnemonic {
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod test {
     use std::fs::File;
     use std::io::Read;
@@ -189,8 +196,8 @@ mod test {
         let context: SecpContext = SecpContext::new();
         let mut test_count = 0;
 
-        for t in 0..tests.len() {
-            let values = tests[t].as_array().unwrap();
+        for test in tests {
+            let values = test.as_array().unwrap();
             let data = Vec::<u8>::from_hex(values[0].as_str().unwrap()).unwrap();
             let m = values[1].as_str().unwrap();
             let mnemonic = Mnemonic::from_str(m).unwrap();
@@ -199,14 +206,17 @@ mod test {
                 mnemonic.to_string(),
                 Mnemonic::new(data.as_slice()).unwrap().to_string()
             );
-            assert_eq!(seed.0, Vec::<u8>::from_hex(values[2].as_str().unwrap()).unwrap());
+            assert_eq!(
+                seed.0,
+                Vec::<u8>::from_hex(values[2].as_str().unwrap()).unwrap()
+            );
 
             if values.len() == 4 {
                 let pk = values[3].as_str().unwrap();
 
                 let private_key =
                     SecpContext::master_private_key(&context, Network::Bitcoin, &seed).unwrap();
-                let key = private_key.clone();
+                let key = private_key;
 
                 assert_eq!(key.to_string(), pk);
                 test_count += 1;
@@ -237,7 +247,7 @@ mod test {
     }
 }
 
-const WORDS: [&str; 2048] = [
+static WORDS: [&str; 2048] = [
     "abandon", "ability", "able", "about", "above", "absent", "absorb", "abstract", "absurd",
     "abuse", "access", "accident", "account", "accuse", "achieve", "acid", "acoustic", "acquire",
     "across", "act", "action", "actor", "actress", "actual", "adapt", "add", "addict", "address",
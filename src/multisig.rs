@@ -0,0 +1,369 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Multisig signature collection
+//!
+//! A session collects the signatures of cosigners of a k of n spend. The PSBT of the session is
+//! sent to cosigners, their partially signed PSBTs are added back, every signature is checked
+//! against the keys of the script and the sighash. The spend is finalized once each input has
+//! the signatures it requires.
+//!
+//! Cosigners are identified by the fingerprint of the BIP32 origin of their keys, so every key
+//! of the multisig scripts needs its origin in the PSBT.
+//!
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::secp256k1::Signature;
+use bitcoin::util::bip32::Fingerprint;
+use bitcoin::{PublicKey, Script, SigHashType, Transaction};
+
+use context::SecpContext;
+use error::Error;
+use psbt::{extract, multisig, Psbt};
+use signer::sighash;
+
+/// version of the serialized session
+const SESSION_VERSION: u8 = 1;
+
+/// Collection of cosigner signatures for a multisig spend
+#[derive(Clone, Debug)]
+pub struct MultisigSession {
+    psbt: Psbt,
+}
+
+impl MultisigSession {
+    /// start collecting signatures for a PSBT spending multisig coins
+    /// Every input needs a bare multisig witness or redeem script and the origin of its keys.
+    pub fn new(psbt: Psbt) -> Result<MultisigSession, Error> {
+        for ix in 0..psbt.inputs.len() {
+            let (_, keys) = Self::script(&psbt, ix)?;
+            if keys
+                .iter()
+                .any(|k| !psbt.inputs[ix].bip32_derivation.contains_key(k))
+            {
+                return Err(Error::Signer("multisig key without origin"));
+            }
+        }
+        Ok(MultisigSession { psbt })
+    }
+
+    /// threshold and keys of input ix
+    fn script(psbt: &Psbt, ix: usize) -> Result<(usize, Vec<PublicKey>), Error> {
+        let input = &psbt.inputs[ix];
+        input
+            .witness_script
+            .as_ref()
+            .or(input.redeem_script.as_ref())
+            .and_then(multisig)
+            .ok_or(Error::Signer("input does not spend a multisig script"))
+    }
+
+    fn cosigner(&self, ix: usize, key: &PublicKey) -> Fingerprint {
+        self.psbt.inputs[ix].bip32_derivation[key].0
+    }
+
+    /// the PSBT to send to cosigners
+    pub fn psbt(&self) -> &Psbt {
+        &self.psbt
+    }
+
+    /// all cosigners of the spend
+    pub fn cosigners(&self) -> Vec<Fingerprint> {
+        let mut cosigners = Vec::new();
+        for input in self.psbt.inputs.iter() {
+            for (fingerprint, _) in input.bip32_derivation.values() {
+                if !cosigners.contains(fingerprint) {
+                    cosigners.push(*fingerprint);
+                }
+            }
+        }
+        cosigners
+    }
+
+    /// cosigners that signed at least one input
+    pub fn signed_by(&self) -> Vec<Fingerprint> {
+        let mut signed = Vec::new();
+        for (ix, input) in self.psbt.inputs.iter().enumerate() {
+            for key in input.partial_sigs.keys() {
+                let cosigner = self.cosigner(ix, key);
+                if !signed.contains(&cosigner) {
+                    signed.push(cosigner);
+                }
+            }
+        }
+        signed
+    }
+
+    /// signatures an input still needs
+    pub fn missing(&self, ix: usize) -> usize {
+        let (required, _) = Self::script(&self.psbt, ix).expect("checked in new");
+        required.saturating_sub(self.psbt.inputs[ix].partial_sigs.len())
+    }
+
+    /// every input has the signatures it needs
+    pub fn is_ready(&self) -> bool {
+        (0..self.psbt.inputs.len()).all(|ix| self.missing(ix) == 0)
+    }
+
+    /// add the signatures of a PSBT returned by a cosigner
+    /// Signatures must be of keys of the script and verify, otherwise nothing is added.
+    /// Returns the cosigners that added signatures.
+    pub fn add(&mut self, signed: &Psbt) -> Result<Vec<Fingerprint>, Error> {
+        if signed.global.unsigned_tx != self.psbt.global.unsigned_tx
+            || signed.inputs.len() != self.psbt.inputs.len()
+        {
+            return Err(Error::Signer("PSBT of an other transaction"));
+        }
+        let context = SecpContext::new();
+        let mut added = Vec::new();
+        for (ix, input) in signed.inputs.iter().enumerate() {
+            let (_, keys) = Self::script(&self.psbt, ix)?;
+            for (key, signature) in input.partial_sigs.iter() {
+                if self.psbt.inputs[ix].partial_sigs.contains_key(key) {
+                    continue;
+                }
+                if !keys.contains(key) {
+                    return Err(Error::Signer("signature of a key not in the script"));
+                }
+                let (hash_type, der) = signature
+                    .split_last()
+                    .ok_or(Error::Signer("empty signature"))?;
+                let hash_type = SigHashType::from_u32_consensus(*hash_type as u32);
+                if hash_type
+                    != self.psbt.inputs[ix]
+                        .sighash_type
+                        .unwrap_or(SigHashType::All)
+                {
+                    return Err(Error::Signer("signature with unexpected sighash type"));
+                }
+                let sighash = sighash(&self.psbt, ix, key, hash_type)
+                    .ok_or(Error::Signer("input without spent output"))?;
+                let valid = Signature::from_der(der)
+                    .ok()
+                    .map(|s| context.verify(&sighash[..], &s, key).is_ok())
+                    .unwrap_or(false);
+                if !valid {
+                    return Err(Error::Signer("invalid signature"));
+                }
+                added.push((ix, *key, signature.clone()));
+            }
+        }
+        let mut cosigners = Vec::new();
+        for (ix, key, signature) in added {
+            let cosigner = self.cosigner(ix, &key);
+            if !cosigners.contains(&cosigner) {
+                cosigners.push(cosigner);
+            }
+            self.psbt.inputs[ix].partial_sigs.insert(key, signature);
+        }
+        Ok(cosigners)
+    }
+
+    /// assemble the signed transaction once the session is ready
+    /// signatures are ordered as the keys of the script, surplus signatures are not used
+    pub fn finalize(mut self) -> Result<Transaction, Error> {
+        if !self.is_ready() {
+            return Err(Error::Signer("signatures missing"));
+        }
+        for ix in 0..self.psbt.inputs.len() {
+            let (required, keys) = Self::script(&self.psbt, ix)?;
+            let input = &mut self.psbt.inputs[ix];
+            let signatures = keys
+                .iter()
+                .filter_map(|k| input.partial_sigs.get(k).cloned())
+                .take(required)
+                .collect::<Vec<_>>();
+            match (input.witness_script.clone(), input.redeem_script.clone()) {
+                (Some(witness_script), redeem_script) => {
+                    let mut witness = vec![Vec::new()];
+                    witness.extend(signatures);
+                    witness.push(witness_script.to_bytes());
+                    input.final_script_witness = Some(witness);
+                    // P2SH wrapped P2WSH
+                    input.final_script_sig =
+                        redeem_script.map(|r| Builder::new().push_slice(&r[..]).into_script());
+                }
+                (None, Some(redeem_script)) => {
+                    let mut builder = Builder::new().push_opcode(all::OP_PUSHBYTES_0);
+                    for signature in signatures.iter() {
+                        builder = builder.push_slice(signature.as_slice());
+                    }
+                    input.final_script_sig =
+                        Some(builder.push_slice(&redeem_script[..]).into_script());
+                }
+                (None, None) => unreachable!("checked in new"),
+            }
+            input.partial_sigs.clear();
+            input.bip32_derivation.clear();
+            input.witness_script = None;
+            input.redeem_script = None;
+            input.sighash_type = None;
+        }
+        extract(self.psbt)
+    }
+
+    /// serialize the session to continue it later
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![SESSION_VERSION];
+        data.extend(serialize(&self.psbt));
+        data
+    }
+
+    /// continue a serialized session
+    pub fn decode(data: &[u8]) -> Result<MultisigSession, Error> {
+        match data.split_first() {
+            Some((&SESSION_VERSION, psbt)) => MultisigSession::new(
+                deserialize(psbt).map_err(|_| Error::Signer("invalid session"))?,
+            ),
+            _ => Err(Error::Signer("unknown session version")),
+        }
+    }
+}
+
+/// bare k of n multisig script with keys in the given order
+pub fn multisig_script(required: usize, keys: &[PublicKey]) -> Script {
+    let mut builder = Builder::new().push_int(required as i64);
+    for key in keys {
+        builder = builder.push_key(key);
+    }
+    builder
+        .push_int(keys.len() as i64)
+        .push_opcode(all::OP_CHECKMULTISIG)
+        .into_script()
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, OutPoint, TxIn, TxOut};
+
+    use account::{
+        Account, AccountAddressType, KeyDerivation, MasterAccount, MasterKeyEntropy, Unlocker,
+    };
+    use fixtures::PASSPHRASE;
+    use psbt::key_source;
+    use signer::{sign, MasterSigner};
+
+    use super::*;
+
+    #[test]
+    fn collect_signatures() {
+        let mut cosigners = (0..3)
+            .map(|_| {
+                let mut master =
+                    MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE)
+                        .unwrap();
+                let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+                master.add_account(
+                    Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 1).unwrap(),
+                );
+                (master, unlocker)
+            })
+            .collect::<Vec<_>>();
+        let derivation = KeyDerivation {
+            account: 0,
+            sub: 0,
+            kix: 0,
+            tweak: None,
+            csv: None,
+        };
+        let keys = cosigners
+            .iter()
+            .map(|(m, _)| key_source(m, &derivation).unwrap())
+            .collect::<Vec<_>>();
+        let script = multisig_script(2, &keys.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: Script::new_v0_wsh(&script.wscript_hash()),
+            }],
+        };
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: funding.txid(),
+                    vout: 0,
+                },
+                sequence: 0xffff_fffd,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 99_000,
+                script_pubkey: Script::new_v0_wpkh(&Default::default()),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(spend).unwrap();
+        psbt.inputs[0].witness_utxo = Some(funding.output[0].clone());
+        psbt.inputs[0].witness_script = Some(script.clone());
+        let mut incomplete = psbt.clone();
+        for (key, source) in keys.iter() {
+            psbt.inputs[0].bip32_derivation.insert(*key, source.clone());
+        }
+        assert!(MultisigSession::new(incomplete.clone()).is_err());
+        incomplete.inputs[0].witness_script = None;
+        assert!(MultisigSession::new(incomplete).is_err());
+
+        let mut session = MultisigSession::new(psbt).unwrap();
+        assert_eq!(session.cosigners().len(), 3);
+        assert_eq!(session.missing(0), 2);
+
+        let mut signed = Vec::new();
+        for (master, unlocker) in cosigners.iter_mut() {
+            let mut copy = session.psbt().clone();
+            assert_eq!(
+                sign(&mut copy, &mut MasterSigner::new(master, unlocker)).unwrap(),
+                1
+            );
+            signed.push(copy);
+        }
+        assert_eq!(session.add(&signed[2]).unwrap(), vec![keys[2].1 .0]);
+        assert!(!session.is_ready());
+        assert!(session.clone().finalize().is_err());
+
+        // a signature moved to an other key
+        let mut forged = signed[0].clone();
+        let signature = forged.inputs[0]
+            .partial_sigs
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        forged.inputs[0].partial_sigs.clear();
+        forged.inputs[0].partial_sigs.insert(keys[1].0, signature);
+        assert!(session.add(&forged).is_err());
+        assert_eq!(session.signed_by(), vec![keys[2].1 .0]);
+
+        // continue later
+        let mut session = MultisigSession::decode(session.encode().as_slice()).unwrap();
+        assert_eq!(session.add(&signed[0]).unwrap(), vec![keys[0].1 .0]);
+        assert!(session.is_ready());
+        let transaction = session.finalize().unwrap();
+        transaction
+            .verify(|_| Some(funding.output[0].clone()))
+            .unwrap();
+    }
+}
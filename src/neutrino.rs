@@ -0,0 +1,643 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Compact block filter sync
+//!
+//! A light client following BIP157 and BIP158. The filter header chain commits to the filter
+//! of every block, so a backend can not hide a block paying the wallet by serving a doctored
+//! filter without breaking the chain:
+//!
+//! ```text
+//! filter header = sha256d(sha256d(filter) | previous filter header)
+//! ```
+//!
+//! NeutrinoSync downloads filter headers and filters, checks each filter against its header,
+//! matches it with the scripts of the wallet and fetches only the blocks that match. Wallet
+//! transactions of those blocks are processed into coins with the merkle proof of their block.
+//!
+//! Filter headers before the start are trusted unless a checkpoint pins them. sync_parallel
+//! checks filters with the backend but fetches the matching blocks from several peers at once.
+//!
+//! A backend forging a filter together with its filter header chain goes unnoticed by a single
+//! peer sync. cross_check asks other peers for filters of a sample of synced blocks and settles
+//! disputes with the block itself, as an honest filter holds every output script of it.
+//!
+use std::collections::HashMap;
+
+use bitcoin::hashes::Hash;
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::{Block, BlockHash, FilterHeader, Script};
+use rand::seq::index;
+use rand::thread_rng;
+
+use account::MasterAccount;
+use backend::{wallet_scripts, ChainBackend};
+use coins::Coins;
+use download::BlockDownloader;
+use error::Error;
+use proved::ProvedTransaction;
+
+/// heights whose filters are checked before their matching blocks are fetched
+const BATCH: u32 = 500;
+
+/// Outcome of a sync
+#[derive(Clone, Debug, Default)]
+pub struct NeutrinoUpdate {
+    /// height of the best chain after the sync
+    pub height: u32,
+    /// blocks unwound as they left the best chain
+    pub unwound: usize,
+    /// filters checked and matched
+    pub filters: usize,
+    /// blocks fetched as their filter matched
+    pub blocks: usize,
+    /// proved transactions of the wallet in fetched blocks
+    pub proofs: Vec<ProvedTransaction>,
+}
+
+/// Outcome of a cross check of filters with other peers
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CrossCheck {
+    /// heights of the blocks sampled
+    pub heights: Vec<u32>,
+    /// peers serving filters inconsistent with their headers or forged
+    pub rejected: Vec<usize>,
+}
+
+/// Follows the best chain of a backend serving compact block filters
+#[derive(Clone, Debug, Default)]
+pub struct NeutrinoSync {
+    start: u32,
+    /// filter header of the block before start
+    anchor: Option<FilterHeader>,
+    /// hashes and filter headers of blocks from start
+    chain: Vec<(BlockHash, FilterHeader)>,
+    checkpoints: HashMap<u32, FilterHeader>,
+}
+
+impl NeutrinoSync {
+    /// sync from height start, e.g. the height at the birth of the wallet
+    pub fn new(start: u32) -> NeutrinoSync {
+        NeutrinoSync {
+            start,
+            ..Default::default()
+        }
+    }
+
+    /// the filter header expected at height, e.g. from a source other than the backend
+    pub fn with_checkpoint(mut self, height: u32, header: FilterHeader) -> NeutrinoSync {
+        self.checkpoints.insert(height, header);
+        self
+    }
+
+    /// height and hash of the last synced block
+    pub fn tip(&self) -> Option<(u32, BlockHash)> {
+        self.chain
+            .last()
+            .map(|(hash, _)| (self.start + self.chain.len() as u32 - 1, *hash))
+    }
+
+    /// height of a synced block
+    pub fn height(&self, hash: &BlockHash) -> Option<u32> {
+        self.chain
+            .iter()
+            .rposition(|(h, _)| h == hash)
+            .map(|p| self.start + p as u32)
+    }
+
+    /// checked filter header of the synced block at height
+    pub fn filter_header(&self, height: u32) -> Option<FilterHeader> {
+        height
+            .checked_sub(self.start)
+            .and_then(|i| self.chain.get(i as usize))
+            .map(|(_, header)| *header)
+    }
+
+    /// unwind blocks that left the best chain, then check the filters of new blocks and
+    /// process the blocks that match the wallet into coins
+    pub fn sync<B: ChainBackend>(
+        &mut self,
+        backend: &B,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+    ) -> Result<NeutrinoUpdate, Error> {
+        self.run(backend, master, coins, |hashes, deliver| {
+            for (n, hash) in hashes.iter().enumerate() {
+                let block = backend.block(hash)?;
+                if block.block_hash() != *hash || !block.check_merkle_root() {
+                    return Err(Error::Backend("block does not match its header"));
+                }
+                if !deliver(n, block)? {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// sync with filters of the backend, but fetch matching blocks from several peers at once
+    pub fn sync_parallel<B: ChainBackend, P: ChainBackend + Send>(
+        &mut self,
+        backend: &B,
+        peers: &mut [P],
+        downloader: &BlockDownloader,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+    ) -> Result<NeutrinoUpdate, Error> {
+        self.run(backend, master, coins, |hashes, deliver| {
+            downloader.fetch(peers, hashes, deliver).map(|_| ())
+        })
+    }
+
+    /// compare filters of a sample of synced blocks with those of other peers
+    /// A peer whose filter does not commit to its filter header is rejected. Where a peer
+    /// disputes a filter header the block decides: the filter omitting outputs of the block is
+    /// forged, a peer serving it is rejected, the backend serving it is an error.
+    pub fn cross_check<B: ChainBackend, P: ChainBackend>(
+        &self,
+        backend: &B,
+        peers: &[P],
+        sample: usize,
+    ) -> Result<CrossCheck, Error> {
+        let mut check = CrossCheck::default();
+        let mut heights = index::sample(
+            &mut thread_rng(),
+            self.chain.len(),
+            sample.min(self.chain.len()),
+        )
+        .into_iter()
+        .map(|i| self.start + i as u32)
+        .collect::<Vec<_>>();
+        heights.sort_unstable();
+        for height in heights.iter().cloned() {
+            let (hash, ours) = self.chain[(height - self.start) as usize];
+            let previous = match height.checked_sub(1).and_then(|h| self.filter_header(h)) {
+                Some(previous) => previous,
+                None => self
+                    .anchor
+                    .ok_or(Error::Backend("filter header before start unknown"))?,
+            };
+            let mut block = None;
+            for (id, peer) in peers.iter().enumerate() {
+                if check.rejected.contains(&id) {
+                    continue;
+                }
+                let (filter, header) = match (peer.filter(&hash), peer.filter_header(&hash)) {
+                    (Ok(Some(filter)), Ok(Some(header))) => (filter, header),
+                    // peers serving no filters have nothing to check
+                    _ => continue,
+                };
+                if filter.filter_header(&previous) != header {
+                    check.rejected.push(id);
+                    continue;
+                }
+                if header == ours {
+                    continue;
+                }
+                if block.is_none() {
+                    let fetched = backend.block(&hash)?;
+                    if fetched.block_hash() != hash || !fetched.check_merkle_root() {
+                        return Err(Error::Backend("block does not match its header"));
+                    }
+                    let own = backend
+                        .filter(&hash)?
+                        .filter(|f| f.filter_header(&previous) == ours)
+                        .ok_or(Error::Backend("filter does not match its header"))?;
+                    if !complete(&own, &fetched)? {
+                        return Err(Error::Backend("backend serves forged filters"));
+                    }
+                    block = Some(fetched);
+                }
+                if !complete(&filter, block.as_ref().unwrap())? {
+                    check.rejected.push(id);
+                }
+            }
+        }
+        check.heights = heights;
+        check.rejected.sort_unstable();
+        Ok(check)
+    }
+
+    /// check filters in batches and process matching blocks as fetch delivers them in order
+    /// Processing a block may add lookahead scripts, if one of them matches a later filter of
+    /// the batch that did not match before, the rest of the batch is checked again.
+    fn run<B, F>(
+        &mut self,
+        backend: &B,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+        mut fetch: F,
+    ) -> Result<NeutrinoUpdate, Error>
+    where
+        B: ChainBackend,
+        F: FnMut(
+            &[BlockHash],
+            &mut dyn FnMut(usize, Block) -> Result<bool, Error>,
+        ) -> Result<(), Error>,
+    {
+        let mut update = NeutrinoUpdate::default();
+        while let Some((height, hash)) = self.tip() {
+            match backend.header(height)? {
+                Some(ref header) if header.block_hash() == hash => break,
+                _ => {
+                    coins.unwind_tip(&hash);
+                    self.chain.pop();
+                    update.unwound += 1;
+                }
+            }
+        }
+        let tip = backend.tip()?;
+        let mut next = self.start + self.chain.len() as u32;
+        while next <= tip {
+            let batch = self.filters(backend, next, tip.min(next + BATCH - 1))?;
+            let mut scripts = wallet_scripts(master);
+            let matches =
+                |scripts: &[Script], (hash, filter, _): &(BlockHash, BlockFilter, FilterHeader)| {
+                    filter
+                        .match_any(hash, &mut scripts.iter().map(|s| s.as_bytes()))
+                        .map_err(|_| Error::Backend("invalid block filter"))
+                };
+            let mut matched = Vec::new();
+            for (i, entry) in batch.iter().enumerate() {
+                if matches(&scripts, entry)? {
+                    matched.push(i);
+                }
+            }
+            let hashes = matched.iter().map(|i| batch[*i].0).collect::<Vec<_>>();
+            let mut committed = 0;
+            let mut complete = true;
+            {
+                let chain = &mut self.chain;
+                let mut deliver = |n: usize, block: Block| -> Result<bool, Error> {
+                    let i = matched[n];
+                    let hash = batch[i].0;
+                    chain.extend(batch[committed..=i].iter().map(|(h, _, f)| (*h, *f)));
+                    committed = i + 1;
+                    coins.process(master, &block);
+                    update.blocks += 1;
+                    update.proofs.extend(
+                        block
+                            .txdata
+                            .iter()
+                            .filter_map(|t| coins.proofs().get(&t.txid()))
+                            .filter(|p| *p.get_block_hash() == hash)
+                            .cloned(),
+                    );
+                    let grown = wallet_scripts(master);
+                    if grown.len() != scripts.len() {
+                        scripts = grown;
+                        for (j, entry) in batch.iter().enumerate().skip(committed) {
+                            if !matched.contains(&j) && matches(&scripts, entry)? {
+                                complete = false;
+                                return Ok(false);
+                            }
+                        }
+                    }
+                    Ok(true)
+                };
+                fetch(&hashes, &mut deliver)?;
+            }
+            if complete && committed < batch.len() {
+                self.chain
+                    .extend(batch[committed..].iter().map(|(h, _, f)| (*h, *f)));
+            }
+            update.filters += self.start as usize + self.chain.len() - next as usize;
+            next = self.start + self.chain.len() as u32;
+        }
+        coins.update_tip(tip, |hash| self.height(hash));
+        update.height = tip;
+        Ok(update)
+    }
+
+    /// blocks from height first to last with their checked filters and filter headers
+    fn filters<B: ChainBackend>(
+        &mut self,
+        backend: &B,
+        first: u32,
+        last: u32,
+    ) -> Result<Vec<(BlockHash, BlockFilter, FilterHeader)>, Error> {
+        let mut previous = match self.chain.last() {
+            Some((_, header)) => *header,
+            None => self.anchor(backend)?,
+        };
+        let mut prev_hash = self.chain.last().map(|(h, _)| *h);
+        let mut batch = Vec::new();
+        for height in first..=last {
+            let header = backend
+                .header(height)?
+                .ok_or(Error::Backend("header of the best chain missing"))?;
+            if let Some(last) = prev_hash {
+                if last != header.prev_blockhash {
+                    return Err(Error::Backend("best chain changed during sync"));
+                }
+            }
+            let hash = header.block_hash();
+            let filter = backend
+                .filter(&hash)?
+                .ok_or(Error::Backend("backend serves no filters"))?;
+            let filter_header = filter.filter_header(&previous);
+            if backend.filter_header(&hash)? != Some(filter_header) {
+                return Err(Error::Backend("filter does not match its header"));
+            }
+            self.check(height, &filter_header)?;
+            batch.push((hash, filter, filter_header));
+            previous = filter_header;
+            prev_hash = Some(hash);
+        }
+        Ok(batch)
+    }
+
+    /// filter header of the block before start
+    fn anchor<B: ChainBackend>(&mut self, backend: &B) -> Result<FilterHeader, Error> {
+        if let Some(anchor) = self.anchor {
+            return Ok(anchor);
+        }
+        let anchor = match self.start.checked_sub(1) {
+            // the genesis filter commits to an all zero header
+            None => FilterHeader::from_inner([0u8; 32]),
+            Some(height) => {
+                let hash = backend
+                    .header(height)?
+                    .ok_or(Error::Backend("header of the best chain missing"))?
+                    .block_hash();
+                let anchor = backend
+                    .filter_header(&hash)?
+                    .ok_or(Error::Backend("backend serves no filter headers"))?;
+                self.check(height, &anchor)?;
+                anchor
+            }
+        };
+        self.anchor = Some(anchor);
+        Ok(anchor)
+    }
+
+    fn check(&self, height: u32, header: &FilterHeader) -> Result<(), Error> {
+        match self.checkpoints.get(&height) {
+            Some(checkpoint) if checkpoint != header => {
+                Err(Error::Backend("filter header differs from checkpoint"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// false if the filter omits output scripts of the block, filters hold no empty scripts
+fn complete(filter: &BlockFilter, block: &Block) -> Result<bool, Error> {
+    let hash = block.block_hash();
+    filter
+        .match_all(
+            &hash,
+            &mut block
+                .txdata
+                .iter()
+                .flat_map(|t| t.output.iter())
+                .filter(|o| !o.script_pubkey.is_empty() && !o.script_pubkey.is_op_return())
+                .map(|o| o.script_pubkey.as_bytes()),
+        )
+        .map_err(|_| Error::Backend("invalid block filter"))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::bip158::{BlockFilter, BlockFilterWriter};
+    use bitcoin::{Block, BlockHeader, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+    use std::cell::RefCell;
+
+    use backend::TxStatus;
+    use fee::FeeRate;
+    use fixtures::{block_after, master_account, next_script};
+
+    use super::*;
+
+    /// serves filters of outputs, the filter at height lie omits them
+    /// Forged filter headers commit to that filter, others to the honest one.
+    struct Peer {
+        blocks: Vec<Block>,
+        lie: Option<usize>,
+        forged: bool,
+        fetched: RefCell<usize>,
+    }
+
+    impl Peer {
+        fn new(blocks: Vec<Block>, lie: Option<usize>, forged: bool) -> Peer {
+            Peer {
+                blocks,
+                lie,
+                forged,
+                fetched: RefCell::new(0),
+            }
+        }
+
+        fn filter_of(&self, height: usize, honest: bool) -> BlockFilter {
+            let mut content = Vec::new();
+            {
+                let mut writer = BlockFilterWriter::new(&mut content, &self.blocks[height]);
+                if honest || self.lie != Some(height) {
+                    writer.add_output_scripts();
+                }
+                writer.finish().unwrap();
+            }
+            BlockFilter::new(content.as_slice())
+        }
+
+        fn position(&self, hash: &BlockHash) -> Result<usize, Error> {
+            self.blocks
+                .iter()
+                .position(|b| b.block_hash() == *hash)
+                .ok_or(Error::Backend("unknown block"))
+        }
+    }
+
+    impl ChainBackend for Peer {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(self.blocks.get(height as usize).map(|b| b.header))
+        }
+
+        fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+            *self.fetched.borrow_mut() += 1;
+            Ok(self.blocks[self.position(hash)?].clone())
+        }
+
+        fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            Ok(Some(self.filter_of(self.position(hash)?, false)))
+        }
+
+        fn filter_header(&self, hash: &BlockHash) -> Result<Option<FilterHeader>, Error> {
+            let mut header = FilterHeader::from_inner([0u8; 32]);
+            for height in 0..=self.position(hash)? {
+                header = self.filter_of(height, !self.forged).filter_header(&header);
+            }
+            Ok(Some(header))
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    fn transaction(script: Script, vout: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::default(),
+                    vout,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script,
+            }],
+        }
+    }
+
+    /// a wallet and an honest peer of a chain funding it at height 2 of 3
+    fn chain() -> (MasterAccount, Peer, Transaction) {
+        let (mut master, _) = master_account(Network::Testnet);
+        let genesis = genesis_block(Network::Testnet);
+        let funding = transaction(next_script(&mut master, (0, 0)), 0);
+        let one = block_after(&genesis, vec![transaction(Script::new(), 1)]);
+        let two = block_after(&one, vec![transaction(Script::new(), 2), funding.clone()]);
+        let three = block_after(&two, Vec::new());
+        let peer = Peer::new(vec![genesis, one, two, three], None, false);
+        (master, peer, funding)
+    }
+
+    #[test]
+    fn sync() {
+        let (mut master, peer, funding) = chain();
+        let mut coins = Coins::new();
+        let mut sync = NeutrinoSync::new(0);
+        let update = sync.sync(&peer, &mut master, &mut coins).unwrap();
+        assert_eq!((update.height, update.filters, update.blocks), (3, 4, 1));
+        assert_eq!(*peer.fetched.borrow(), 1);
+        assert_eq!(update.proofs.len(), 1);
+        assert_eq!(update.proofs[0].get_transaction().txid(), funding.txid());
+        assert_eq!(
+            update.proofs[0].merkle_root(),
+            peer.blocks[2].header.merkle_root
+        );
+        assert_eq!(coins.confirmed_balance(), 100_000);
+        assert_eq!(
+            sync.filter_header(3),
+            peer.filter_header(&peer.blocks[3].block_hash()).unwrap()
+        );
+    }
+
+    #[test]
+    fn sync_parallel() {
+        let (mut master, peer, _) = chain();
+        // blocks fetched from several peers are processed alike
+        let mut peers = (0..2)
+            .map(|_| Peer::new(peer.blocks.clone(), None, false))
+            .collect::<Vec<_>>();
+        let mut coins = Coins::new();
+        let update = NeutrinoSync::new(0)
+            .sync_parallel(
+                &peer,
+                &mut peers,
+                &BlockDownloader::new(),
+                &mut master,
+                &mut coins,
+            )
+            .unwrap();
+        assert_eq!((update.height, update.filters, update.blocks), (3, 4, 1));
+        assert_eq!(*peer.fetched.borrow(), 0);
+        assert_eq!(coins.confirmed_balance(), 100_000);
+    }
+
+    #[test]
+    fn cross_check() {
+        let (mut master, peer, _) = chain();
+        let mut sync = NeutrinoSync::new(0);
+        sync.sync(&peer, &mut master, &mut Coins::new()).unwrap();
+        // peers forging filters are caught by the filter header chain or the block
+        let other = |lie, forged| Peer::new(peer.blocks.clone(), lie, forged);
+        let peers = vec![
+            other(None, false),
+            other(Some(2), true),
+            other(Some(2), false),
+        ];
+        let check = sync.cross_check(&peer, &peers, 10).unwrap();
+        assert_eq!(check.heights, vec![0, 1, 2, 3]);
+        assert_eq!(check.rejected, vec![1, 2]);
+        let forger = other(Some(2), true);
+        let mut forged = NeutrinoSync::new(0);
+        forged
+            .sync(&forger, &mut master, &mut Coins::new())
+            .unwrap();
+        assert!(forged.cross_check(&forger, &peers[..1], 4).is_err());
+    }
+
+    #[test]
+    fn hidden_block() {
+        let (mut master, mut peer, _) = chain();
+        // a peer hiding the funding block is caught by the filter header chain
+        peer.lie = Some(2);
+        assert!(NeutrinoSync::new(0)
+            .sync(&peer, &mut master, &mut Coins::new())
+            .is_err());
+    }
+
+    #[test]
+    fn checkpoint() {
+        let (mut master, peer, _) = chain();
+        let mut sync = NeutrinoSync::new(0);
+        sync.sync(&peer, &mut master, &mut Coins::new()).unwrap();
+        // checkpoints pin filter headers before the start
+        let mut coins = Coins::new();
+        assert!(NeutrinoSync::new(2)
+            .with_checkpoint(1, FilterHeader::from_inner([1u8; 32]))
+            .sync(&peer, &mut master, &mut coins)
+            .is_err());
+        let mut late = NeutrinoSync::new(2).with_checkpoint(1, sync.filter_header(1).unwrap());
+        assert_eq!(late.sync(&peer, &mut master, &mut coins).unwrap().blocks, 1);
+        assert_eq!(coins.confirmed_balance(), 100_000);
+    }
+
+    #[test]
+    fn reorg() {
+        let (mut master, mut peer, _) = chain();
+        let mut coins = Coins::new();
+        let mut sync = NeutrinoSync::new(0);
+        sync.sync(&peer, &mut master, &mut coins).unwrap();
+        // a reorg unwinds the funding
+        let one = block_after(&peer.blocks[0], vec![transaction(Script::new(), 3)]);
+        let two = block_after(&one, Vec::new());
+        peer.blocks.truncate(1);
+        peer.blocks.extend(vec![one, two]);
+        let update = sync.sync(&peer, &mut master, &mut coins).unwrap();
+        assert_eq!((update.height, update.unwound, update.filters), (2, 3, 2));
+        assert_eq!(coins.confirmed_balance(), 0);
+    }
+}
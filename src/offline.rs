@@ -0,0 +1,294 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Offline broadcast queue
+//!
+//! Building and signing need no backend, a fee rate given to the builder replaces estimation.
+//! A wallet without connection queues the signed transactions in a BroadcastQueue, which
+//! processes them into coins, so the coins they spend are not spent twice, and keeps them in a
+//! key-value store of its own until they are relayed.
+//!
+//! Once a backend is available flush relays them in the order queued. A transaction replaced
+//! in the meantime, e.g. by one of an other device, or already known to the backend is dropped
+//! instead. A transaction the backend refuses stays queued with those spending its outputs.
+//!
+use std::collections::HashSet;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::{Transaction, Txid};
+
+use account::MasterAccount;
+use backend::{ChainBackend, TxStatus};
+use coins::Coins;
+use error::Error;
+use kv::KeyValue;
+
+const QUEUED: u8 = b'q';
+
+/// Outcome of a flush
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Flush {
+    /// relayed to the backend
+    pub broadcast: Vec<Txid>,
+    /// dropped as the backend knew them already
+    pub known: Vec<Txid>,
+    /// dropped as a conflicting transaction replaced them
+    pub conflicted: Vec<Txid>,
+    /// refused by the backend or spending a refused one, still queued
+    pub failed: Vec<Txid>,
+}
+
+/// Signed transactions waiting for a backend
+pub struct BroadcastQueue<K: KeyValue> {
+    kv: K,
+    /// sequence number of the next queued transaction
+    next: u64,
+}
+
+impl<K: KeyValue> BroadcastQueue<K> {
+    /// use a key-value store that is empty or holds a queue
+    pub fn new(kv: K) -> Result<BroadcastQueue<K>, Error> {
+        let next = match kv.scan_prefix(&[QUEUED])?.last() {
+            Some((key, _)) => Self::sequence(key)? + 1,
+            None => 0,
+        };
+        Ok(BroadcastQueue { kv, next })
+    }
+
+    /// the underlying key-value store
+    pub fn into_inner(self) -> K {
+        self.kv
+    }
+
+    /// process a signed transaction into coins and queue it
+    pub fn queue(
+        &mut self,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+        transaction: &Transaction,
+    ) -> Result<Txid, Error> {
+        let txid = transaction.txid();
+        if self.queued()?.iter().any(|t| t.txid() == txid) {
+            return Ok(txid);
+        }
+        let mut key = vec![QUEUED];
+        key.extend_from_slice(&self.next.to_be_bytes());
+        self.kv.insert(&key, &serialize(transaction))?;
+        self.kv.flush()?;
+        self.next += 1;
+        coins.process_unconfirmed_transaction(master, transaction);
+        Ok(txid)
+    }
+
+    /// queued transactions in the order queued
+    pub fn queued(&self) -> Result<Vec<Transaction>, Error> {
+        self.kv
+            .scan_prefix(&[QUEUED])?
+            .into_iter()
+            .map(|(_, v)| Ok(deserialize(v.as_slice())?))
+            .collect()
+    }
+
+    /// forget a queued transaction, e.g. one the user abandoned
+    pub fn remove(&mut self, txid: &Txid) -> Result<bool, Error> {
+        for (key, value) in self.kv.scan_prefix(&[QUEUED])? {
+            if deserialize::<Transaction>(value.as_slice())?.txid() == *txid {
+                self.kv.remove(&key)?;
+                self.kv.flush()?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// relay queued transactions through the backend, dropping those replaced or known
+    pub fn flush<B: ChainBackend>(&mut self, backend: &B, coins: &Coins) -> Result<Flush, Error> {
+        let mut flush = Flush::default();
+        let mut failed = HashSet::new();
+        for (key, value) in self.kv.scan_prefix(&[QUEUED])? {
+            let transaction: Transaction = deserialize(value.as_slice())?;
+            let txid = transaction.txid();
+            if coins.conflicts().contains_key(&txid) {
+                flush.conflicted.push(txid);
+            } else if transaction
+                .input
+                .iter()
+                .any(|i| failed.contains(&i.previous_output.txid))
+            {
+                failed.insert(txid);
+                flush.failed.push(txid);
+                continue;
+            } else if coins.proofs().contains_key(&txid) {
+                flush.known.push(txid);
+            } else {
+                match backend.tx_status(&txid) {
+                    Ok(TxStatus::Unknown) | Err(_) => match backend.broadcast(&transaction) {
+                        Ok(_) => flush.broadcast.push(txid),
+                        Err(_) => {
+                            failed.insert(txid);
+                            flush.failed.push(txid);
+                            continue;
+                        }
+                    },
+                    Ok(_) => flush.known.push(txid),
+                }
+            }
+            self.kv.remove(&key)?;
+        }
+        self.kv.flush()?;
+        Ok(flush)
+    }
+
+    fn sequence(key: &[u8]) -> Result<u64, Error> {
+        let mut sequence = [0u8; 8];
+        if key.len() != 9 {
+            return Err(Error::Storage("invalid broadcast queue key"));
+        }
+        sequence.copy_from_slice(&key[1..]);
+        Ok(u64::from_be_bytes(sequence))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::util::bip158::BlockFilter;
+    use bitcoin::{Block, BlockHash, BlockHeader, Network, OutPoint, Script, TxIn, TxOut};
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+
+    use fee::FeeRate;
+    use fixtures::{master_account, next_script};
+
+    use super::*;
+
+    struct Node {
+        online: Cell<bool>,
+        relayed: RefCell<Vec<Txid>>,
+    }
+
+    impl ChainBackend for Node {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(0)
+        }
+
+        fn header(&self, _: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(None)
+        }
+
+        fn block(&self, _: &BlockHash) -> Result<Block, Error> {
+            Err(Error::Backend("no blocks"))
+        }
+
+        fn filter(&self, _: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            Ok(None)
+        }
+
+        fn tx_status(&self, txid: &Txid) -> Result<TxStatus, Error> {
+            if self.relayed.borrow().contains(txid) {
+                Ok(TxStatus::Unconfirmed)
+            } else {
+                Ok(TxStatus::Unknown)
+            }
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            if !self.online.get() {
+                return Err(Error::Backend("offline"));
+            }
+            self.relayed.borrow_mut().push(transaction.txid());
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    fn transaction(previous_output: OutPoint, script_pubkey: Script, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output,
+                sequence: 0xfffffffd,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey,
+            }],
+        }
+    }
+
+    #[test]
+    fn broadcast_queue() {
+        let (mut master, _) = master_account(Network::Testnet);
+        let script = next_script(&mut master, (0, 0));
+        let mut coins = Coins::new();
+        let funding = transaction(OutPoint::default(), script.clone(), 100_000);
+        coins.process_unconfirmed_transaction(&mut master, &funding);
+        let coin = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        let first = transaction(coin, Script::new(), 90_000);
+        let replacement = transaction(coin, script, 80_000);
+        let child = transaction(
+            OutPoint {
+                txid: replacement.txid(),
+                vout: 0,
+            },
+            Script::new(),
+            70_000,
+        );
+
+        let mut queue = BroadcastQueue::new(BTreeMap::new()).unwrap();
+        queue.queue(&mut master, &mut coins, &first).unwrap();
+        queue.queue(&mut master, &mut coins, &replacement).unwrap();
+        queue.queue(&mut master, &mut coins, &child).unwrap();
+        queue.queue(&mut master, &mut coins, &child).unwrap();
+        assert!(coins.pending().contains_key(&replacement.txid()));
+
+        let node = Node {
+            online: Cell::new(false),
+            relayed: RefCell::new(Vec::new()),
+        };
+        let flush = queue.flush(&node, &coins).unwrap();
+        assert_eq!(flush.conflicted, vec![first.txid()]);
+        assert_eq!(flush.failed, vec![replacement.txid(), child.txid()]);
+
+        // the queue survives a restart
+        let mut queue = BroadcastQueue::new(queue.into_inner()).unwrap();
+        assert_eq!(
+            queue.queued().unwrap(),
+            vec![replacement.clone(), child.clone()]
+        );
+        node.online.set(true);
+        let flush = queue.flush(&node, &coins).unwrap();
+        assert_eq!(flush.broadcast, vec![replacement.txid(), child.txid()]);
+        assert!(queue.queued().unwrap().is_empty());
+
+        queue.queue(&mut master, &mut coins, &child).unwrap();
+        assert_eq!(
+            queue.flush(&node, &coins).unwrap().known,
+            vec![child.txid()]
+        );
+        queue.queue(&mut master, &mut coins, &first).unwrap();
+        assert!(queue.remove(&first.txid()).unwrap());
+        assert!(queue.queued().unwrap().is_empty());
+    }
+}
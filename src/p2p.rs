@@ -0,0 +1,877 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Peer to peer network
+//!
+//! A minimal client of the bitcoin network protocol: version handshake, header sync and
+//! download of blocks and compact block filters from a single full node, so NeutrinoSync and
+//! ChainSync need no indexing server.
+//!
+//! Headers are checked for links and proof of work below the limit of the network, but not
+//! for difficulty adjustments. A branch replaces synced headers only if it has more work.
+//!
+//! Connections are encrypted with the BIP324 transport if the peer supports it. A node that
+//! only speaks the unencrypted protocol drops the connection at the handshake, which is then
+//! retried unencrypted. An active attacker can force that downgrade, check session_id to tell.
+//!
+//! A node without compact block filters may serve BIP37 filtered blocks to BloomSync instead.
+//!
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::consensus::encode;
+use bitcoin::consensus::params::Params;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{CommandString, NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_filter::{GetCFHeaders, GetCFilters};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHeader, Network, Transaction, Txid};
+use rand::{thread_rng, RngCore};
+
+use backend::{ChainBackend, TxStatus};
+use bip324::{Cipher, Handshake, ELLSWIFT_LEN, MAX_GARBAGE_LEN, TERMINATOR_LEN};
+use bloom::{BloomFilter, BloomPeer};
+use error::Error;
+use fee::FeeRate;
+use proxy::Connector;
+
+/// protocol version of BIP339, the first with compact block filters
+const PROTOCOL_VERSION: u32 = 70016;
+/// peers send at most this many headers at once
+const MAX_HEADERS: usize = 2000;
+/// the basic filter type of BIP158
+const BASIC_FILTER: u8 = 0;
+/// inventory type of a BIP37 filtered block
+const MSG_FILTERED_BLOCK: u32 = 3;
+/// commands of BIP324 short message ids from 1
+const SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+/// Framing of messages on the wire
+enum Transport {
+    /// unencrypted messages with magic and checksum
+    V1,
+    /// packets of the BIP324 encrypted transport
+    V2(Box<Cipher>),
+}
+
+impl Transport {
+    /// establish an encrypted session as the side that connected or accepted
+    fn v2<S: Read + Write>(
+        stream: &mut S,
+        network: Network,
+        initiator: bool,
+    ) -> Result<Transport, Error> {
+        let handshake = Handshake::new(network, initiator);
+        stream.write_all(handshake.hello().as_slice())?;
+        stream.flush()?;
+        let mut theirs = [0u8; ELLSWIFT_LEN];
+        stream.read_exact(&mut theirs)?;
+        let garbage = handshake.garbage().to_vec();
+        let mut cipher = handshake.complete(&theirs, network)?;
+        // the version packet authenticates our garbage, its contents are reserved
+        let mut hello = cipher.terminator().to_vec();
+        hello.extend(cipher.encrypt(&[], garbage.as_slice(), false));
+        stream.write_all(hello.as_slice())?;
+        stream.flush()?;
+        let mut garbage = Vec::new();
+        while !garbage.ends_with(cipher.their_terminator()) {
+            if garbage.len() >= MAX_GARBAGE_LEN + TERMINATOR_LEN {
+                return Err(Error::Peer("garbage terminator missing"));
+            }
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte)?;
+            garbage.push(byte[0]);
+        }
+        garbage.truncate(garbage.len() - TERMINATOR_LEN);
+        // decoys may precede the version packet, only the first packet authenticates garbage
+        while read_packet(stream, &mut cipher, garbage.as_slice())?.is_none() {
+            garbage.clear();
+        }
+        Ok(Transport::V2(Box::new(cipher)))
+    }
+
+    fn write<W: Write>(
+        &mut self,
+        writer: &mut W,
+        network: Network,
+        payload: NetworkMessage,
+    ) -> Result<(), Error> {
+        let data = frame(network, payload);
+        match self {
+            Transport::V1 => writer.write_all(data.as_slice())?,
+            Transport::V2(cipher) => {
+                let command = &data[4..16];
+                let name = command.split(|b| *b == 0).next().unwrap_or_default();
+                let mut contents = match SHORT_IDS.iter().position(|c| c.as_bytes() == name) {
+                    Some(id) => vec![id as u8 + 1],
+                    None => {
+                        let mut contents = vec![0u8];
+                        contents.extend_from_slice(command);
+                        contents
+                    }
+                };
+                contents.extend_from_slice(&data[24..]);
+                writer.write_all(cipher.encrypt(contents.as_slice(), &[], false).as_slice())?
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn read<R: Read>(&mut self, reader: &mut R, network: Network) -> Result<NetworkMessage, Error> {
+        let cipher = match self {
+            Transport::V1 => return Ok(read_message(reader, network)?.payload),
+            Transport::V2(cipher) => cipher,
+        };
+        let contents = loop {
+            if let Some(contents) = read_packet(reader, cipher, &[])? {
+                break contents;
+            }
+        };
+        let (command, payload) = match contents.first() {
+            Some(0) if contents.len() >= 13 => (contents[1..13].to_vec(), &contents[13..]),
+            Some(id) if *id > 0 && (*id as usize) <= SHORT_IDS.len() => {
+                let mut command = SHORT_IDS[*id as usize - 1].as_bytes().to_vec();
+                command.resize(12, 0);
+                (command, &contents[1..])
+            }
+            _ => return Err(Error::Peer("unknown message id")),
+        };
+        // rebuild the unencrypted framing to decode
+        let mut data = network.magic().to_le_bytes().to_vec();
+        data.extend(command);
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&sha256d::Hash::hash(payload)[..4]);
+        data.extend_from_slice(payload);
+        Ok(encode::deserialize::<RawNetworkMessage>(data.as_slice())?.payload)
+    }
+}
+
+/// unencrypted framing of a message
+/// rust-bitcoin prefixes the payload of unknown messages, e.g. BIP37 ones, with its length.
+fn frame(network: Network, payload: NetworkMessage) -> Vec<u8> {
+    match payload {
+        NetworkMessage::Unknown { command, payload } => {
+            let mut data = network.magic().to_le_bytes().to_vec();
+            data.extend(encode::serialize(&command));
+            data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            data.extend_from_slice(&sha256d::Hash::hash(&payload)[..4]);
+            data.extend(payload);
+            data
+        }
+        payload => encode::serialize(&RawNetworkMessage {
+            magic: network.magic(),
+            payload,
+        }),
+    }
+}
+
+/// read a BIP324 packet, None if it is a decoy
+fn read_packet<R: Read>(
+    reader: &mut R,
+    cipher: &mut Cipher,
+    aad: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut length = [0u8; 3];
+    reader.read_exact(&mut length)?;
+    let length = cipher.decrypt_length(&length);
+    if length > encode::MAX_VEC_SIZE {
+        return Err(Error::Peer("message too large"));
+    }
+    let mut sealed = vec![0u8; length + 1 + 16];
+    reader.read_exact(sealed.as_mut_slice())?;
+    cipher.decrypt(sealed.as_slice(), aad)
+}
+
+/// A full node of the bitcoin network
+pub struct Peer<S: Read + Write> {
+    stream: RefCell<S>,
+    transport: RefCell<Transport>,
+    network: Network,
+    version: VersionMessage,
+    /// height of the first header
+    base: u32,
+    /// best chain of headers from base
+    headers: RefCell<Vec<BlockHeader>>,
+}
+
+impl Peer<TcpStream> {
+    /// connect to a full node and shake hands, encrypted if the node supports it
+    pub fn connect(
+        address: &SocketAddr,
+        network: Network,
+        timeout: Duration,
+    ) -> Result<Peer<TcpStream>, Error> {
+        Peer::connect_with(
+            &Connector::direct().timeout(timeout),
+            address.ip().to_string().as_str(),
+            address.port(),
+            network,
+        )
+    }
+
+    /// connect to a full node at host, e.g. an .onion address, through a connector
+    pub fn connect_with(
+        connector: &Connector,
+        host: &str,
+        port: u16,
+        network: Network,
+    ) -> Result<Peer<TcpStream>, Error> {
+        match Peer::new_v2(connector.connect(host, port)?, network) {
+            // a node without BIP324 drops the connection, timeouts are not retried unencrypted
+            Err(Error::IO(ref e))
+                if e.kind() == io::ErrorKind::UnexpectedEof
+                    || e.kind() == io::ErrorKind::ConnectionReset
+                    || e.kind() == io::ErrorKind::ConnectionAborted =>
+            {
+                Peer::new(connector.connect(host, port)?, network)
+            }
+            result => result,
+        }
+    }
+}
+
+impl<S: Read + Write> Peer<S> {
+    /// shake hands with a full node over a connected stream, unencrypted
+    pub fn new(stream: S, network: Network) -> Result<Peer<S>, Error> {
+        Peer::shake(stream, network, Transport::V1)
+    }
+
+    /// shake hands with a full node over a connected stream, encrypted with BIP324
+    pub fn new_v2(mut stream: S, network: Network) -> Result<Peer<S>, Error> {
+        let transport = Transport::v2(&mut stream, network, true)?;
+        Peer::shake(stream, network, transport)
+    }
+
+    fn shake(stream: S, network: Network, transport: Transport) -> Result<Peer<S>, Error> {
+        let nobody = Address::new(&SocketAddr::from(([0, 0, 0, 0], 0)), ServiceFlags::NONE);
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64),
+            nobody.clone(),
+            nobody,
+            thread_rng().next_u64(),
+            format!("/rust-wallet:{}/", env!("CARGO_PKG_VERSION")),
+            0,
+        );
+        version.version = PROTOCOL_VERSION;
+        let genesis = bitcoin::blockdata::constants::genesis_block(network).header;
+        let mut peer = Peer {
+            stream: RefCell::new(stream),
+            transport: RefCell::new(transport),
+            network,
+            version: version.clone(),
+            base: 0,
+            headers: RefCell::new(vec![genesis]),
+        };
+        peer.send(NetworkMessage::Version(version))?;
+        let mut remote = None;
+        let mut verack = false;
+        while remote.is_none() || !verack {
+            match peer.receive()? {
+                NetworkMessage::Version(version) => {
+                    peer.send(NetworkMessage::Verack)?;
+                    remote = Some(version);
+                }
+                NetworkMessage::Verack => verack = true,
+                _ => {}
+            }
+        }
+        peer.version = remote.expect("received above");
+        if !peer.version.services.has(ServiceFlags::WITNESS) {
+            return Err(Error::Peer("peer does not serve witness blocks"));
+        }
+        Ok(peer)
+    }
+
+    /// sync headers from a trusted header at height instead of genesis
+    pub fn with_checkpoint(mut self, height: u32, header: BlockHeader) -> Peer<S> {
+        self.base = height;
+        self.headers = RefCell::new(vec![header]);
+        self
+    }
+
+    /// version message of the peer
+    pub fn version(&self) -> &VersionMessage {
+        &self.version
+    }
+
+    /// id of an encrypted session, the node shows the same unless intercepted
+    pub fn session_id(&self) -> Option<[u8; 32]> {
+        match *self.transport.borrow() {
+            Transport::V1 => None,
+            Transport::V2(ref cipher) => Some(*cipher.session_id()),
+        }
+    }
+
+    /// height of the last synced header
+    pub fn height(&self) -> u32 {
+        self.base + self.headers.borrow().len() as u32 - 1
+    }
+
+    /// height of a synced header
+    pub fn header_height(&self, hash: &BlockHash) -> Option<u32> {
+        self.headers
+            .borrow()
+            .iter()
+            .rposition(|h| h.block_hash() == *hash)
+            .map(|p| self.base + p as u32)
+    }
+
+    /// sync headers of the best chain of the peer, returns the height of its tip
+    pub fn sync_headers(&self) -> Result<u32, Error> {
+        loop {
+            let locator = self.locator();
+            self.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+                locator,
+                BlockHash::default(),
+            )))?;
+            let headers = self.expect(|message| match message {
+                NetworkMessage::Headers(headers) => Some(headers),
+                _ => None,
+            })?;
+            let count = headers.len();
+            self.extend(headers)?;
+            if count < MAX_HEADERS {
+                return Ok(self.height());
+            }
+        }
+    }
+
+    /// ask the peer for addresses of other peers with their last seen time
+    pub fn addresses(&self) -> Result<Vec<(u32, Address)>, Error> {
+        self.send(NetworkMessage::GetAddr)?;
+        self.expect(|message| match message {
+            NetworkMessage::Addr(addresses) => Some(addresses),
+            _ => None,
+        })
+    }
+
+    /// hashes of synced headers, dense at the tip and sparse towards base
+    fn locator(&self) -> Vec<BlockHash> {
+        let headers = self.headers.borrow();
+        let mut locator = Vec::new();
+        let mut index = headers.len() - 1;
+        let mut step = 1;
+        while index > 0 {
+            locator.push(headers[index].block_hash());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator.push(headers[0].block_hash());
+        locator
+    }
+
+    /// connect headers to the synced chain
+    fn extend(&self, branch: Vec<BlockHeader>) -> Result<(), Error> {
+        let first = match branch.first() {
+            Some(first) => first.prev_blockhash,
+            None => return Ok(()),
+        };
+        let mut headers = self.headers.borrow_mut();
+        let fork = headers
+            .iter()
+            .rposition(|h| h.block_hash() == first)
+            .ok_or(Error::Peer("headers do not connect"))?;
+        let limit = Params::new(self.network).pow_limit;
+        let mut prev = first;
+        for header in &branch {
+            if header.prev_blockhash != prev {
+                return Err(Error::Peer("headers do not form a chain"));
+            }
+            if header.target() > limit {
+                return Err(Error::Peer("header below minimum difficulty"));
+            }
+            prev = header
+                .validate_pow(&header.target())
+                .map_err(|_| Error::Peer("header without proof of work"))?;
+        }
+        let work = |headers: &[BlockHeader]| {
+            headers
+                .iter()
+                .fold(Uint256::default(), |sum, h| sum + h.work())
+        };
+        if work(&branch) <= work(&headers[fork + 1..]) {
+            return Err(Error::Peer("branch has less work than the synced chain"));
+        }
+        headers.truncate(fork + 1);
+        headers.extend(branch);
+        Ok(())
+    }
+
+    fn send(&self, payload: NetworkMessage) -> Result<(), Error> {
+        self.transport
+            .borrow_mut()
+            .write(&mut *self.stream.borrow_mut(), self.network, payload)
+    }
+
+    fn receive(&self) -> Result<NetworkMessage, Error> {
+        self.transport
+            .borrow_mut()
+            .read(&mut *self.stream.borrow_mut(), self.network)
+    }
+
+    /// receive until accept takes a message, answering pings meanwhile
+    fn expect<T, F: FnMut(NetworkMessage) -> Option<T>>(&self, mut accept: F) -> Result<T, Error> {
+        loop {
+            match self.receive()? {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                NetworkMessage::NotFound(_) => {
+                    return Err(Error::Peer("peer does not have the data"))
+                }
+                message => {
+                    if let Some(t) = accept(message) {
+                        return Ok(t);
+                    }
+                }
+            }
+        }
+    }
+
+    fn filter_height(&self, hash: &BlockHash) -> Result<Option<u32>, Error> {
+        if !self.version.services.has(ServiceFlags::COMPACT_FILTERS) {
+            return Ok(None);
+        }
+        self.header_height(hash)
+            .map(Some)
+            .ok_or(Error::Peer("filter of a block not synced"))
+    }
+}
+
+/// read an unencrypted message of the network protocol
+fn read_message<R: Read>(reader: &mut R, network: Network) -> Result<RawNetworkMessage, Error> {
+    let mut data = vec![0u8; 24];
+    reader.read_exact(data.as_mut_slice())?;
+    if data[..4] != network.magic().to_le_bytes() {
+        return Err(Error::Network);
+    }
+    let length: u32 = encode::deserialize(&data[16..20])?;
+    if length as usize > encode::MAX_VEC_SIZE {
+        return Err(Error::Peer("message too large"));
+    }
+    data.resize(24 + length as usize, 0);
+    reader.read_exact(&mut data[24..])?;
+    Ok(encode::deserialize(data.as_slice())?)
+}
+
+impl<S: Read + Write> ChainBackend for Peer<S> {
+    fn tip(&self) -> Result<u32, Error> {
+        self.sync_headers()
+    }
+
+    fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        Ok(height
+            .checked_sub(self.base)
+            .and_then(|i| self.headers.borrow().get(i as usize).cloned()))
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(
+            *hash,
+        )]))?;
+        self.expect(|message| match message {
+            NetworkMessage::Block(block) if block.block_hash() == *hash => Some(block),
+            _ => None,
+        })
+    }
+
+    fn filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+        let height = match self.filter_height(hash)? {
+            Some(height) => height,
+            None => return Ok(None),
+        };
+        self.send(NetworkMessage::GetCFilters(GetCFilters {
+            filter_type: BASIC_FILTER,
+            start_height: height,
+            stop_hash: *hash,
+        }))?;
+        self.expect(|message| match message {
+            NetworkMessage::CFilter(filter) if filter.block_hash == *hash => {
+                Some(Some(BlockFilter::new(filter.filter.as_slice())))
+            }
+            _ => None,
+        })
+    }
+
+    fn filter_header(&self, hash: &BlockHash) -> Result<Option<FilterHeader>, Error> {
+        let height = match self.filter_height(hash)? {
+            Some(height) => height,
+            None => return Ok(None),
+        };
+        self.send(NetworkMessage::GetCFHeaders(GetCFHeaders {
+            filter_type: BASIC_FILTER,
+            start_height: height,
+            stop_hash: *hash,
+        }))?;
+        let headers = self.expect(|message| match message {
+            NetworkMessage::CFHeaders(headers) if headers.stop_hash == *hash => Some(headers),
+            _ => None,
+        })?;
+        match headers.filter_hashes.as_slice() {
+            [filter_hash] => Ok(Some(
+                filter_hash.filter_header(&headers.previous_filter_header),
+            )),
+            _ => Err(Error::Peer("unexpected number of filter hashes")),
+        }
+    }
+
+    fn tx_status(&self, _txid: &Txid) -> Result<TxStatus, Error> {
+        // peers do not index transactions
+        Ok(TxStatus::Unknown)
+    }
+
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+        self.send(NetworkMessage::Tx(transaction.clone()))?;
+        Ok(transaction.txid())
+    }
+
+    fn feerate(&self, _target: u16) -> Result<FeeRate, Error> {
+        Err(Error::Peer("peers do not estimate fees"))
+    }
+}
+
+impl<S: Read + Write> BloomPeer for Peer<S> {
+    fn load_filter(&self, filter: &BloomFilter) -> Result<(), Error> {
+        if !self.version.services.has(ServiceFlags::BLOOM) {
+            return Err(Error::Peer("peer does not serve filtered blocks"));
+        }
+        self.send(NetworkMessage::Unknown {
+            command: CommandString::try_from("filterload").expect("valid command"),
+            payload: encode::serialize(filter),
+        })
+    }
+
+    fn merkle_block(&self, hash: &BlockHash) -> Result<(MerkleBlock, Vec<Transaction>), Error> {
+        self.send(NetworkMessage::GetData(vec![Inventory::Unknown {
+            inv_type: MSG_FILTERED_BLOCK,
+            hash: hash.into_inner(),
+        }]))?;
+        // matching transactions follow the merkle block, the pong tells they are complete
+        let nonce = thread_rng().next_u64();
+        self.send(NetworkMessage::Ping(nonce))?;
+        let block = self.expect(|message| match message {
+            NetworkMessage::Unknown { command, payload } if command.as_ref() == "merkleblock" => {
+                Some(encode::deserialize::<MerkleBlock>(payload.as_slice()))
+            }
+            _ => None,
+        })??;
+        let mut transactions = Vec::new();
+        while let Some(transaction) = self.expect(|message| match message {
+            NetworkMessage::Tx(transaction) => Some(Some(transaction)),
+            NetworkMessage::Pong(n) if n == nonce => Some(None),
+            _ => None,
+        })? {
+            transactions.push(transaction);
+        }
+        Ok((block, transactions))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::message_filter::{CFHeaders, CFilter};
+    use bitcoin::util::bip158::BlockFilterWriter;
+    use bitcoin::{OutPoint, Script, TxIn, TxOut};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use bitcoin::hashes::Hash;
+    use bloom::BloomSync;
+    use coins::Coins;
+    use fixtures::{block_after, master_account, next_script};
+    use neutrino::NeutrinoSync;
+
+    use super::*;
+
+    fn mine(prev: &Block, txdata: Vec<Transaction>) -> Block {
+        let mut block = block_after(prev, txdata);
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    fn filter(block: &Block) -> Vec<u8> {
+        let mut content = Vec::new();
+        {
+            let mut writer = BlockFilterWriter::new(&mut content, block);
+            writer.add_output_scripts();
+            writer.finish().unwrap();
+        }
+        content
+    }
+
+    /// a full node serving blocks until the connection closes
+    fn serve(listener: TcpListener, blocks: Vec<Block>, relayed: mpsc::Sender<Txid>, v2: bool) {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut transport = if v2 {
+                Transport::v2(&mut stream, Network::Regtest, false).unwrap()
+            } else {
+                Transport::V1
+            };
+            serve_connection(&mut stream, &mut transport, &blocks, &relayed);
+        }
+    }
+
+    fn serve_connection(
+        stream: &mut TcpStream,
+        transport: &mut Transport,
+        blocks: &[Block],
+        relayed: &mpsc::Sender<Txid>,
+    ) {
+        let position = |hash: &BlockHash| blocks.iter().position(|b| b.block_hash() == *hash);
+        let mut bloom: Option<BloomFilter> = None;
+        let send = |stream: &mut TcpStream, transport: &mut Transport, payload| {
+            transport.write(stream, Network::Regtest, payload).unwrap()
+        };
+        while let Ok(message) = transport.read(stream, Network::Regtest) {
+            match message {
+                NetworkMessage::Version(version) => {
+                    let mut version = version.clone();
+                    version.services = ServiceFlags::NETWORK
+                        | ServiceFlags::WITNESS
+                        | ServiceFlags::COMPACT_FILTERS
+                        | ServiceFlags::BLOOM;
+                    send(stream, transport, NetworkMessage::Version(version));
+                    send(stream, transport, NetworkMessage::Verack);
+                    send(stream, transport, NetworkMessage::Ping(7));
+                }
+                NetworkMessage::GetHeaders(get) => {
+                    let fork = get
+                        .locator_hashes
+                        .iter()
+                        .filter_map(&position)
+                        .next()
+                        .unwrap_or(0);
+                    let headers = blocks[fork + 1..].iter().map(|b| b.header).collect();
+                    send(stream, transport, NetworkMessage::Headers(headers));
+                }
+                NetworkMessage::GetData(inventory) => match inventory[0] {
+                    Inventory::WitnessBlock(hash) if position(&hash).is_some() => {
+                        let block = blocks[position(&hash).unwrap()].clone();
+                        send(stream, transport, NetworkMessage::Block(block));
+                    }
+                    Inventory::Unknown {
+                        inv_type: MSG_FILTERED_BLOCK,
+                        hash,
+                    } if bloom.is_some() => {
+                        let filter = bloom.as_mut().unwrap();
+                        let block = &blocks[position(&BlockHash::from_inner(hash)).unwrap()];
+                        let matched = block
+                            .txdata
+                            .iter()
+                            .filter(|t| filter.update(t))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        let txids = matched.iter().map(|t| t.txid()).collect();
+                        send(
+                            stream,
+                            transport,
+                            NetworkMessage::Unknown {
+                                command: CommandString::try_from("merkleblock").unwrap(),
+                                payload: encode::serialize(&MerkleBlock::from_block(block, &txids)),
+                            },
+                        );
+                        for transaction in matched {
+                            send(stream, transport, NetworkMessage::Tx(transaction));
+                        }
+                    }
+                    _ => send(stream, transport, NetworkMessage::NotFound(inventory)),
+                },
+                NetworkMessage::Unknown { command, payload }
+                    if command.as_ref() == "filterload" =>
+                {
+                    bloom = Some(encode::deserialize(payload.as_slice()).unwrap());
+                }
+                NetworkMessage::Ping(nonce) => send(stream, transport, NetworkMessage::Pong(nonce)),
+                NetworkMessage::GetCFilters(get) => send(
+                    stream,
+                    transport,
+                    NetworkMessage::CFilter(CFilter {
+                        filter_type: BASIC_FILTER,
+                        block_hash: get.stop_hash,
+                        filter: filter(&blocks[get.start_height as usize]),
+                    }),
+                ),
+                NetworkMessage::GetCFHeaders(get) => {
+                    let mut header = FilterHeader::from_inner([0u8; 32]);
+                    for block in &blocks[..get.start_height as usize] {
+                        header = BlockFilter::new(&filter(block)).filter_header(&header);
+                    }
+                    let filter = filter(&blocks[get.start_height as usize]);
+                    send(
+                        stream,
+                        transport,
+                        NetworkMessage::CFHeaders(CFHeaders {
+                            filter_type: BASIC_FILTER,
+                            stop_hash: get.stop_hash,
+                            previous_filter_header: header,
+                            filter_hashes: vec![bitcoin::FilterHash::hash(&filter)],
+                        }),
+                    )
+                }
+                NetworkMessage::GetAddr => {
+                    let other = SocketAddr::from(([10, 0, 0, 1], 18444));
+                    let address = Address::new(&other, ServiceFlags::NETWORK);
+                    send(
+                        stream,
+                        transport,
+                        NetworkMessage::Addr(vec![(1_600_000_000, address)]),
+                    );
+                }
+                NetworkMessage::Tx(transaction) => relayed.send(transaction.txid()).unwrap(),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn peer() {
+        let (mut master, _) = master_account(Network::Regtest);
+        let script = next_script(&mut master, (0, 0));
+        let transaction = |script: Script, vout: u32| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::default(),
+                    vout,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script,
+            }],
+        };
+        let genesis = genesis_block(Network::Regtest);
+        let one = mine(&genesis, vec![transaction(Script::new(), 1)]);
+        let two = mine(&one, vec![transaction(script, 0)]);
+        let three = mine(&two, vec![transaction(Script::new(), 2)]);
+        let blocks = vec![genesis, one, two, three];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (relayed, received) = mpsc::channel();
+        let served = blocks.clone();
+        thread::spawn(move || serve(listener, served, relayed, true));
+
+        let peer = Peer::connect(&address, Network::Regtest, Duration::from_secs(10)).unwrap();
+        assert!(peer.session_id().is_some());
+        assert!(peer.version().services.has(ServiceFlags::COMPACT_FILTERS));
+        assert_eq!(peer.sync_headers().unwrap(), 3);
+        assert_eq!(peer.header_height(&blocks[2].block_hash()), Some(2));
+        let addresses = peer.addresses().unwrap();
+        assert_eq!(
+            addresses[0].1.socket_addr().unwrap(),
+            SocketAddr::from(([10, 0, 0, 1], 18444))
+        );
+
+        let mut coins = Coins::new();
+        let update = NeutrinoSync::new(0)
+            .sync(&peer, &mut master, &mut coins)
+            .unwrap();
+        assert_eq!((update.height, update.blocks), (3, 1));
+        assert_eq!(coins.confirmed_balance(), 100_000);
+
+        // filtered blocks of BIP37
+        let mut filtered = Coins::new();
+        let update = BloomSync::new(0)
+            .sync(&peer, &mut master, &mut filtered)
+            .unwrap();
+        assert_eq!(update.proofs.len(), 1);
+        assert_eq!(filtered.confirmed_balance(), 100_000);
+
+        let unknown = mine(&blocks[3], Vec::new());
+        assert!(peer.block(&unknown.block_hash()).is_err());
+        let spend = transaction(Script::new(), 3);
+        assert_eq!(peer.broadcast(&spend).unwrap(), spend.txid());
+        assert_eq!(received.recv().unwrap(), spend.txid());
+
+        // a node without BIP324 is connected to unencrypted
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (relayed, _) = mpsc::channel();
+        thread::spawn(move || serve(listener, blocks, relayed, false));
+        let peer = Peer::connect(&address, Network::Regtest, Duration::from_secs(10)).unwrap();
+        assert!(peer.session_id().is_none());
+        assert_eq!(peer.sync_headers().unwrap(), 3);
+    }
+
+    #[test]
+    fn no_fallback_on_timeout() {
+        // a node that accepts but does not answer is not retried unencrypted
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (accepted, connections) = mpsc::channel();
+        thread::spawn(move || {
+            let mut streams = Vec::new();
+            for stream in listener.incoming() {
+                streams.push(stream.unwrap());
+                accepted.send(()).unwrap();
+            }
+        });
+        assert!(Peer::connect(&address, Network::Regtest, Duration::from_millis(200)).is_err());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(connections.try_iter().count(), 1);
+    }
+}
@@ -0,0 +1,372 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Transaction packages
+//!
+//! A child with its unconfirmed parents, relayed as a unit so nodes judge the fee rate of the
+//! package rather than that of a parent alone, e.g. a low fee payment with a CPFP child built
+//! by Coins::cpfp. Nodes accepting packages take them with submitpackage, others get the
+//! transactions one by one, parents first.
+//!
+use std::collections::HashMap;
+
+use bitcoin::{OutPoint, Transaction, Txid};
+
+use backend::ChainBackend;
+use coins::Coins;
+use error::Error;
+use fee::FeeRate;
+
+/// maximum number of transactions in a package accepted by bitcoind
+const MAX_PACKAGE_COUNT: usize = 25;
+
+/// A child with its parents
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Package {
+    transactions: Vec<Transaction>,
+}
+
+impl Package {
+    /// a package of parents followed by their child
+    /// Every transaction but the last must be a parent of the last, parents do not spend each
+    /// other.
+    pub fn new(transactions: Vec<Transaction>) -> Result<Package, Error> {
+        let (child, parents) = transactions
+            .split_last()
+            .ok_or(Error::Broadcast("empty package"))?;
+        if transactions.len() > MAX_PACKAGE_COUNT {
+            return Err(Error::Broadcast("too many transactions in package"));
+        }
+        let txids = parents.iter().map(|t| t.txid()).collect::<Vec<_>>();
+        for (ix, txid) in txids.iter().enumerate() {
+            if txids[..ix].contains(txid) || *txid == child.txid() {
+                return Err(Error::Broadcast("transaction twice in package"));
+            }
+            if !child.input.iter().any(|i| i.previous_output.txid == *txid) {
+                return Err(Error::Broadcast(
+                    "package transaction is not a parent of the child",
+                ));
+            }
+        }
+        if parents.iter().any(|t| {
+            t.input
+                .iter()
+                .any(|i| txids.contains(&i.previous_output.txid))
+        }) {
+            return Err(Error::Broadcast("parents depend on each other"));
+        }
+        Ok(Package { transactions })
+    }
+
+    /// a parent with its child
+    pub fn with_child(parent: Transaction, child: Transaction) -> Result<Package, Error> {
+        Package::new(vec![parent, child])
+    }
+
+    /// parents first, child last
+    pub fn transactions(&self) -> &[Transaction] {
+        self.transactions.as_slice()
+    }
+
+    pub fn txids(&self) -> Vec<Txid> {
+        self.transactions.iter().map(|t| t.txid()).collect()
+    }
+
+    pub fn weight(&self) -> u64 {
+        self.transactions
+            .iter()
+            .map(|t| t.get_weight() as u64)
+            .sum::<u64>()
+    }
+
+    /// fee of all transactions, None if the value of a spent output outside of the package is
+    /// not known to coins
+    pub fn fee(&self, coins: &Coins) -> Option<u64> {
+        let mut outputs = HashMap::new();
+        for transaction in self.transactions.iter() {
+            let txid = transaction.txid();
+            for (vout, output) in transaction.output.iter().enumerate() {
+                outputs.insert(
+                    OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    output.value,
+                );
+            }
+        }
+        let mut spent = 0;
+        for input in self.transactions.iter().flat_map(|t| t.input.iter()) {
+            let point = &input.previous_output;
+            spent += match outputs.get(point) {
+                Some(value) => *value,
+                None => coins.output_value(point)?,
+            };
+        }
+        spent.checked_sub(outputs.values().sum::<u64>())
+    }
+
+    /// fee rate of the package as a whole
+    pub fn feerate(&self, coins: &Coins) -> Option<FeeRate> {
+        let weight = self.weight();
+        self.fee(coins)
+            .map(|fee| FeeRate::from_sat_per_kwu(fee * 1000 / weight))
+    }
+
+    /// relay the package through a backend, see ChainBackend::broadcast_package
+    pub fn broadcast<B: ChainBackend>(&self, backend: &B) -> Result<Vec<Txid>, Error> {
+        backend.broadcast_package(&self.transactions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network, OutPoint, Script, SigHashType};
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    use account::{MasterAccount, Unlocker};
+    use backend::BitcoindBackend;
+    use bitcoin::consensus::encode::serialize_hex;
+    use builder::ChangePosition;
+    use fee::{JsonRpc, RpcError, RPC_METHOD_NOT_FOUND};
+    use fixtures::{funded, master_account, next_script};
+
+    use super::*;
+
+    /// a node that may not know submitpackage
+    struct Node {
+        package: Result<&'static str, RpcError>,
+        calls: RefCell<Vec<(String, String)>>,
+    }
+
+    fn rpc_error(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    impl JsonRpc for Node {
+        fn call(&self, method: &str, params: &str) -> Result<String, Error> {
+            self.calls
+                .borrow_mut()
+                .push((method.to_string(), params.to_string()));
+            match method {
+                "submitpackage" => self
+                    .package
+                    .clone()
+                    .map(|reply| reply.to_string())
+                    .map_err(Error::Rpc),
+                "sendrawtransaction" => Ok("\"txid\"".to_string()),
+                _ => Err(Error::Rpc(rpc_error(
+                    RPC_METHOD_NOT_FOUND,
+                    "Method not found",
+                ))),
+            }
+        }
+    }
+
+    impl Node {
+        fn new(packages: bool) -> Node {
+            Node::replying(if packages {
+                Ok(r#"{"package_msg": "success", "tx-results": {}}"#)
+            } else {
+                Err(rpc_error(RPC_METHOD_NOT_FOUND, "Method not found"))
+            })
+        }
+
+        /// a node replying to submitpackage with package
+        fn replying(package: Result<&'static str, RpcError>) -> Node {
+            Node {
+                package,
+                calls: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    /// a wallet with an unconfirmed parent at 1 sat/vB
+    struct Wallet {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        funding: Transaction,
+        parent: Transaction,
+        change: Script,
+    }
+
+    impl Wallet {
+        fn new() -> Wallet {
+            let (mut master, mut unlocker) = master_account(Network::Testnet);
+            let (mut coins, funding) = funded(&mut master, Network::Testnet, &[100_000]);
+            let to =
+                Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                    .unwrap();
+            let change = next_script(&mut master, (0, 0));
+            let (mut parent, _) = coins
+                .build_tx()
+                .add_recipient(&to, 50_000)
+                .feerate(FeeRate::from_sat_per_vb(1))
+                .change_position(ChangePosition::Last)
+                .build(&change, 1, |_| Some(1))
+                .unwrap();
+            let resolve = |point: &OutPoint| {
+                if point.txid == funding.txid() {
+                    funding.output.get(point.vout as usize).cloned()
+                } else {
+                    None
+                }
+            };
+            master
+                .sign(&mut parent, SigHashType::All, &resolve, &mut unlocker)
+                .unwrap();
+            coins.process_unconfirmed_transaction(&mut master, &parent);
+            Wallet {
+                master,
+                unlocker,
+                coins,
+                funding,
+                parent,
+                change,
+            }
+        }
+
+        /// a signed child bumping the parent to 10 sat/vB
+        fn child(&mut self) -> Transaction {
+            let (mut child, _) = self
+                .coins
+                .cpfp(&self.parent.txid())
+                .feerate(FeeRate::from_sat_per_vb(10))
+                .build(&self.change)
+                .unwrap();
+            let parent = &self.parent;
+            let resolve = |point: &OutPoint| parent.output.get(point.vout as usize).cloned();
+            self.master
+                .sign(&mut child, SigHashType::All, &resolve, &mut self.unlocker)
+                .unwrap();
+            child
+        }
+
+        fn package(&mut self) -> Package {
+            let child = self.child();
+            Package::with_child(self.parent.clone(), child).unwrap()
+        }
+    }
+
+    #[test]
+    fn cpfp() {
+        let mut wallet = Wallet::new();
+        assert!(wallet
+            .coins
+            .cpfp(&bitcoin::Txid::default())
+            .build(&wallet.change)
+            .is_err());
+        let child = wallet.child();
+        let parent = wallet.parent.clone();
+        assert_eq!(child.input.len(), 1);
+        assert_eq!(child.input[0].previous_output.txid, parent.txid());
+        assert!(Package::new(vec![child.clone(), parent.clone()]).is_err());
+        assert!(Package::new(vec![wallet.funding.clone(), child.clone()]).is_err());
+        let package = Package::with_child(parent.clone(), child).unwrap();
+        let feerate = package.feerate(&wallet.coins).unwrap().as_sat_per_vb();
+        assert!((10.0..10.5).contains(&feerate));
+        assert!(package.fee(&wallet.coins).unwrap() > wallet.coins.fee_of(&parent).unwrap());
+
+        // parent and child pay more than 10% of the payment
+        let bump = |absurd: bool| {
+            let cpfp = wallet
+                .coins
+                .cpfp(&parent.txid())
+                .feerate(FeeRate::from_sat_per_vb(100));
+            if absurd {
+                cpfp.allow_absurd_fee()
+            } else {
+                cpfp
+            }
+            .build(&wallet.change)
+        };
+        assert!(matches!(bump(false), Err(Error::FeeBounds(_))));
+        assert!(bump(true).is_ok());
+    }
+
+    #[test]
+    fn submit_package() {
+        let package = Wallet::new().package();
+        let node = Node::new(true);
+        assert_eq!(
+            package.broadcast(&BitcoindBackend::new(&node)).unwrap(),
+            package.txids()
+        );
+        let hex = package
+            .transactions()
+            .iter()
+            .map(|t| format!("\"{}\"", serialize_hex(t)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            *node.calls.borrow(),
+            vec![(
+                "submitpackage".to_string(),
+                format!("[[{}]]", hex.join(","))
+            )]
+        );
+    }
+
+    #[test]
+    fn submit_each() {
+        // a node without submitpackage gets the transactions in order
+        let package = Wallet::new().package();
+        let old = Node::new(false);
+        package.broadcast(&BitcoindBackend::new(&old)).unwrap();
+        let methods = old
+            .calls
+            .borrow()
+            .iter()
+            .skip(1)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            package
+                .transactions()
+                .iter()
+                .map(|t| (
+                    "sendrawtransaction".to_string(),
+                    format!("[\"{}\"]", serialize_hex(t))
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(old.calls.borrow()[0].0, "submitpackage");
+    }
+
+    #[test]
+    fn submit_rejected() {
+        let package = Wallet::new().package();
+        let rejected = Node::replying(Ok(
+            r#"{"package_msg": "transaction failed", "tx-results": {}}"#,
+        ));
+        match package.broadcast(&BitcoindBackend::new(&rejected)) {
+            Err(Error::Broadcast(_)) => {}
+            _ => panic!("package not rejected"),
+        }
+        // errors other than an unknown method are not retried one by one
+        let failed = Node::replying(Err(rpc_error(-25, "bad-txns-inputs-missingorspent")));
+        match package.broadcast(&BitcoindBackend::new(&failed)) {
+            Err(Error::Rpc(error)) => assert_eq!(error.code, -25),
+            _ => panic!("error of the node lost"),
+        }
+        assert_eq!(failed.calls.borrow().len(), 1);
+    }
+}
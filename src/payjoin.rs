@@ -0,0 +1,499 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Payjoin (BIP78)
+//!
+//! The sender posts a signed original PSBT to the pj= endpoint of a BIP21 URI, the receiver
+//! answers with a proposal that also spends coins of the receiver. The sender checks that the
+//! proposal does not take more than it allowed, then signs it again.
+//! This library does not talk HTTP itself, supply a transport.
+//!
+use bitcoin::{OutPoint, Script, TxIn};
+
+use account::MasterAccount;
+use bip21::PaymentUri;
+use coins::Coins;
+use error::Error;
+use fee::FeeRate;
+use psbt::{analyze, preserve_global, update_input, Psbt};
+use selection::estimate_input_weight;
+
+/// A transport of payjoin requests, usually HTTPS
+pub trait PayjoinTransport {
+    /// post the original PSBT to the endpoint with the query parameters and return the
+    /// proposal of the receiver. PSBTs are base64 encoded on the wire.
+    fn post(
+        &self,
+        endpoint: &str,
+        query: &[(String, String)],
+        original: &Psbt,
+    ) -> Result<Psbt, Error>;
+}
+
+/// Sender of a payjoin
+#[derive(Clone, Debug)]
+pub struct Sender {
+    endpoint: String,
+    payee: Script,
+    max_additional_fee: u64,
+    additional_fee_output: Option<usize>,
+    min_feerate: FeeRate,
+    disable_output_substitution: bool,
+}
+
+impl Sender {
+    /// a sender for a URI with pj= parameter
+    /// the endpoint must be https or a Tor hidden service as BIP78 demands
+    pub fn new(uri: &PaymentUri) -> Result<Sender, Error> {
+        let endpoint = uri
+            .get("pj")
+            .ok_or(Error::Payjoin("no pj parameter"))?
+            .to_string();
+        let host = endpoint
+            .split("://")
+            .nth(1)
+            .and_then(|rest| rest.split(&['/', ':'][..]).next())
+            .unwrap_or("");
+        if !endpoint.starts_with("https://") && !host.ends_with(".onion") {
+            return Err(Error::Payjoin("endpoint must be https or onion"));
+        }
+        let payee = uri
+            .address
+            .as_ref()
+            .ok_or(Error::Payjoin("no address to pay"))?
+            .script_pubkey();
+        Ok(Sender {
+            endpoint,
+            payee,
+            max_additional_fee: 0,
+            additional_fee_output: None,
+            min_feerate: FeeRate::default(),
+            disable_output_substitution: uri.get("pjos") == Some("0"),
+        })
+    }
+
+    /// allow the receiver to take up to fee from output index to pay for its inputs
+    pub fn max_additional_fee(mut self, fee: u64, output: usize) -> Sender {
+        self.max_additional_fee = fee;
+        self.additional_fee_output = Some(output);
+        self
+    }
+
+    /// refuse proposals below this fee rate
+    pub fn min_feerate(mut self, feerate: FeeRate) -> Sender {
+        self.min_feerate = feerate;
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        self.endpoint.as_str()
+    }
+
+    /// query parameters of the request
+    pub fn query(&self) -> Vec<(String, String)> {
+        let mut query = vec![("v".to_string(), "1".to_string())];
+        if let Some(output) = self.additional_fee_output {
+            query.push(("additionalfeeoutputindex".to_string(), output.to_string()));
+            query.push((
+                "maxadditionalfeecontribution".to_string(),
+                self.max_additional_fee.to_string(),
+            ));
+        }
+        if self.disable_output_substitution {
+            query.push(("disableoutputsubstitution".to_string(), "true".to_string()));
+        }
+        if self.min_feerate > FeeRate::default() {
+            query.push((
+                "minfeerate".to_string(),
+                format!("{}", self.min_feerate.as_sat_per_vb()),
+            ));
+        }
+        query
+    }
+
+    /// post the signed and finalized original and check the proposal, see process_proposal
+    pub fn send<T: PayjoinTransport>(&self, transport: &T, original: &Psbt) -> Result<Psbt, Error> {
+        let proposal = transport.post(self.endpoint.as_str(), self.query().as_slice(), original)?;
+        self.process_proposal(original, proposal)
+    }
+
+    /// check the proposal of the receiver against the original
+    /// Returns the proposal with the information to sign own inputs restored, sign it with
+    /// MasterAccount::sign_psbt and finalize. The receiver must keep all outputs of the
+    /// original, output substitution is not accepted.
+    pub fn process_proposal(&self, original: &Psbt, mut proposal: Psbt) -> Result<Psbt, Error> {
+        let original_tx = &original.global.unsigned_tx;
+        let proposal_tx = proposal.global.unsigned_tx.clone();
+        if original_tx.version != proposal_tx.version
+            || original_tx.lock_time != proposal_tx.lock_time
+        {
+            return Err(Error::Payjoin("version or lock time changed"));
+        }
+        let mut own = 0;
+        for (ix, txin) in proposal_tx.input.iter().enumerate() {
+            match original_tx
+                .input
+                .iter()
+                .position(|i| i.previous_output == txin.previous_output)
+            {
+                Some(oix) => {
+                    if original_tx.input[oix].sequence != txin.sequence {
+                        return Err(Error::Payjoin("sequence of own input changed"));
+                    }
+                    // restore what was stripped and drop what the receiver should not send
+                    let input = &mut proposal.inputs[ix];
+                    *input = original.inputs[oix].clone();
+                    input.final_script_sig = None;
+                    input.final_script_witness = None;
+                    own += 1;
+                }
+                None => {
+                    let input = &proposal.inputs[ix];
+                    if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+                        return Err(Error::Payjoin("input of the receiver is not final"));
+                    }
+                    if input.witness_utxo.is_none() && input.non_witness_utxo.is_none() {
+                        return Err(Error::Payjoin("input of the receiver without spent output"));
+                    }
+                }
+            }
+        }
+        if own != original_tx.input.len() {
+            return Err(Error::Payjoin("own input missing"));
+        }
+        preserve_global(&mut proposal, original);
+        let mut contribution = 0;
+        for (ix, output) in original_tx.output.iter().enumerate() {
+            let proposed = proposal_tx
+                .output
+                .iter()
+                .find(|o| o.script_pubkey == output.script_pubkey)
+                .ok_or(Error::Payjoin("output missing"))?;
+            if output.script_pubkey == self.payee {
+                if proposed.value < output.value {
+                    return Err(Error::Payjoin("payment decreased"));
+                }
+            } else if Some(ix) == self.additional_fee_output {
+                if proposed.value > output.value {
+                    return Err(Error::Payjoin("own output increased"));
+                }
+                contribution = output.value - proposed.value;
+            } else if proposed.value != output.value {
+                return Err(Error::Payjoin("own output changed"));
+            }
+        }
+        if contribution > self.max_additional_fee {
+            return Err(Error::Payjoin("fee contribution above the maximum"));
+        }
+        let original_fee = analyze(original)
+            .fee
+            .ok_or(Error::Payjoin("fee of the original is not known"))?;
+        let analysis = analyze(&proposal);
+        let fee = analysis
+            .fee
+            .ok_or(Error::Payjoin("fee of the proposal is not known"))?;
+        if fee < original_fee + contribution {
+            return Err(Error::Payjoin("fee contribution does not go to fee"));
+        }
+        if analysis.feerate().unwrap_or_default() < self.min_feerate {
+            return Err(Error::Payjoin("fee rate below minimum"));
+        }
+        Ok(proposal)
+    }
+}
+
+/// check that a payjoin original pays to own scripts and does not spend own coins
+/// inputs must be final and have the spent output, returns the amount paid to own scripts
+pub fn check_original(
+    original: &Psbt,
+    coins: &Coins,
+    master: &MasterAccount,
+) -> Result<u64, Error> {
+    for (ix, input) in original.inputs.iter().enumerate() {
+        if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+            return Err(Error::Payjoin("original is not final"));
+        }
+        if input.witness_utxo.is_none() && input.non_witness_utxo.is_none() {
+            return Err(Error::Payjoin("original input without spent output"));
+        }
+        if coins.is_own(&original.global.unsigned_tx.input[ix].previous_output) {
+            return Err(Error::Payjoin("original spends own coins"));
+        }
+    }
+    let scripts = master.get_scripts().map(|(s, _)| s).collect::<Vec<_>>();
+    let paid = original
+        .global
+        .unsigned_tx
+        .output
+        .iter()
+        .filter(|o| scripts.contains(&o.script_pubkey))
+        .map(|o| o.value)
+        .sum::<u64>();
+    if paid == 0 {
+        return Err(Error::Payjoin("original does not pay to us"));
+    }
+    Ok(paid)
+}
+
+/// the receiver's proposal adding an own coin to the original
+/// The coin's value, less its fee at the fee rate of the original, is added to the first own
+/// output. Sign the result with MasterAccount::sign_psbt and finalize before answering.
+pub fn contribute(
+    original: &Psbt,
+    coins: &Coins,
+    master: &MasterAccount,
+    point: &OutPoint,
+) -> Result<Psbt, Error> {
+    check_original(original, coins, master)?;
+    let coin = coins
+        .confirmed()
+        .get(point)
+        .ok_or(Error::Payjoin("contributed coin is not confirmed"))?;
+    let feerate = analyze(original)
+        .feerate()
+        .ok_or(Error::Payjoin("fee of the original is not known"))?;
+    let scripts = master.get_scripts().map(|(s, _)| s).collect::<Vec<_>>();
+    let mut transaction = original.global.unsigned_tx.clone();
+    let receiver = transaction
+        .output
+        .iter()
+        .position(|o| scripts.contains(&o.script_pubkey))
+        .unwrap();
+    let input_fee = feerate.fee(estimate_input_weight(&coin.output.script_pubkey));
+    transaction.output[receiver].value += coin
+        .output
+        .value
+        .checked_sub(input_fee)
+        .ok_or(Error::Payjoin("contributed coin does not pay its fee"))?;
+    transaction.input.push(TxIn {
+        previous_output: *point,
+        script_sig: Script::new(),
+        sequence: transaction.input[0].sequence,
+        witness: Vec::new(),
+    });
+    let mut proposal = Psbt::from_unsigned_tx(transaction)?;
+    for (ix, input) in original.inputs.iter().enumerate() {
+        // the sender restores its own information
+        proposal.inputs[ix].witness_utxo = input.witness_utxo.clone();
+        proposal.inputs[ix].non_witness_utxo = input.non_witness_utxo.clone();
+    }
+    let own = proposal.inputs.len() - 1;
+    update_input(&mut proposal, own, coins, master)?;
+    Ok(proposal)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use bitcoin::{Address, Network, Transaction, TxOut};
+
+    use account::Unlocker;
+    use fixtures::{block, funding, master_account, next_script};
+    use psbt::{extract, finalize};
+
+    use super::*;
+
+    /// a wallet with a P2WPKH account and its funding transaction
+    fn wallet(value: u64) -> (MasterAccount, Unlocker, Transaction) {
+        let (mut master, unlocker) = master_account(Network::Testnet);
+        let funding = funding(vec![TxOut {
+            value,
+            script_pubkey: next_script(&mut master, (0, 0)),
+        }]);
+        (master, unlocker, funding)
+    }
+
+    /// answers with the receiver's proposal, optionally tampered with
+    struct Receiver {
+        master: MasterAccount,
+        unlocker: RefCell<Unlocker>,
+        coins: Coins,
+        point: OutPoint,
+        tamper: bool,
+    }
+
+    impl PayjoinTransport for Receiver {
+        fn post(
+            &self,
+            endpoint: &str,
+            query: &[(String, String)],
+            original: &Psbt,
+        ) -> Result<Psbt, Error> {
+            assert_eq!(endpoint, "https://example.com/pj");
+            assert!(query.contains(&("v".to_string(), "1".to_string())));
+            let mut proposal = contribute(original, &self.coins, &self.master, &self.point)?;
+            if self.tamper {
+                // take from the sender's change
+                let own = self
+                    .master
+                    .get_scripts()
+                    .map(|(s, _)| s)
+                    .collect::<Vec<_>>();
+                let outputs = &mut proposal.global.unsigned_tx.output;
+                let change = outputs
+                    .iter()
+                    .position(|o| !own.contains(&o.script_pubkey))
+                    .unwrap();
+                outputs[change].value -= 1000;
+            }
+            self.master
+                .sign_psbt(&mut proposal, &mut self.unlocker.borrow_mut())?;
+            assert_eq!(finalize(&mut proposal), 1);
+            Ok(proposal)
+        }
+    }
+
+    /// a signed original of the sender paying 30_000 sat to the receiver
+    struct Payment {
+        sender: MasterAccount,
+        sender_unlocker: Unlocker,
+        funding: [Transaction; 2],
+        receiver: Receiver,
+        address: Address,
+        original: Psbt,
+        change_index: usize,
+    }
+
+    fn payment() -> Payment {
+        let (mut sender, mut sender_unlocker, sender_funding) = wallet(100_000);
+        let (mut master, unlocker, receiver_funding) = wallet(50_000);
+        let block = block(
+            Network::Testnet,
+            vec![sender_funding.clone(), receiver_funding.clone()],
+        );
+        let mut sender_coins = Coins::new();
+        sender_coins.process(&mut sender, &block);
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block);
+
+        let address = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .clone();
+        let change = sender_funding.output[0].script_pubkey.clone();
+        let (mut original, _) = sender_coins
+            .build_tx()
+            .add_recipient(&address, 30_000)
+            .feerate(FeeRate::from_sat_per_vb(2))
+            .build_psbt(&sender, &change, 1, |_| Some(1))
+            .unwrap();
+        let change_index = original
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == change)
+            .unwrap();
+        sender
+            .sign_psbt(&mut original, &mut sender_unlocker)
+            .unwrap();
+        finalize(&mut original);
+
+        let point = OutPoint {
+            txid: receiver_funding.txid(),
+            vout: 0,
+        };
+        Payment {
+            sender,
+            sender_unlocker,
+            funding: [sender_funding, receiver_funding],
+            receiver: Receiver {
+                master,
+                unlocker: RefCell::new(unlocker),
+                coins,
+                point,
+                tamper: false,
+            },
+            address,
+            original,
+            change_index,
+        }
+    }
+
+    impl Payment {
+        fn sender(&self) -> Sender {
+            let uri = PaymentUri::parse(
+                format!(
+                    "bitcoin:{}?amount=0.0003&pj=https://example.com/pj",
+                    self.address
+                )
+                .as_str(),
+            )
+            .unwrap();
+            Sender::new(&uri)
+                .unwrap()
+                .max_additional_fee(500, self.change_index)
+                .min_feerate(FeeRate::from_sat_per_vb(1))
+        }
+    }
+
+    #[test]
+    fn insecure_endpoint() {
+        let uri = "bitcoin:tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7\
+                   ?pj=http://example.com/pj";
+        assert!(Sender::new(&PaymentUri::parse(uri).unwrap()).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let mut payment = payment();
+        payment.receiver.tamper = true;
+        assert!(payment
+            .sender()
+            .send(&payment.receiver, &payment.original)
+            .is_err());
+    }
+
+    #[test]
+    fn payjoin() {
+        let mut payment = payment();
+        let mut proposal = payment
+            .sender()
+            .send(&payment.receiver, &payment.original)
+            .unwrap();
+        assert_eq!(proposal.inputs.len(), 2);
+        assert_eq!(
+            payment
+                .sender
+                .sign_psbt(&mut proposal, &mut payment.sender_unlocker)
+                .unwrap(),
+            1
+        );
+        assert_eq!(finalize(&mut proposal), 2);
+        let transaction = extract(proposal).unwrap();
+        // the receiver got its coin back with the payment, less the fee of its input
+        let received = transaction
+            .output
+            .iter()
+            .find(|o| o.script_pubkey == payment.address.script_pubkey())
+            .unwrap()
+            .value;
+        assert!(received > 70_000 && received < 80_000);
+        transaction
+            .verify(|point| {
+                payment
+                    .funding
+                    .iter()
+                    .find(|t| t.txid() == point.txid)
+                    .map(|t| t.output[point.vout as usize].clone())
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,507 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Peer discovery and management
+//!
+//! Addresses of peers are learned from DNS seeds and from connected peers and kept in an
+//! address store in the spirit of the addrman of Bitcoin Core: addresses a connection
+//! succeeded to are tried, others are new. A bounded number of connection slots is filled
+//! with peers of distinct network groups, so a single operator of many addresses in a range
+//! can not easily eclipse the wallet. Peers collect a score for misbehavior and are banned for
+//! a day once it reaches 100.
+//!
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::consensus::encode;
+use bitcoin::consensus::{deserialize, serialize, Decodable, Encodable};
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::Network;
+use rand::{thread_rng, Rng};
+
+use error::Error;
+use kv::KeyValue;
+use p2p::Peer;
+use proxy::Connector;
+
+const PEER: u8 = b'a';
+/// a peer reaching this score is banned
+pub const BAN_SCORE: u32 = 100;
+/// seconds a peer is banned for
+pub const BAN_TIME: u64 = 24 * 60 * 60;
+/// seconds before an address is tried again
+const RETRY_DELAY: u64 = 10 * 60;
+/// addresses of the store beyond which the worst are evicted
+const MAX_ADDRESSES: usize = 16384;
+/// failed attempts after which a never connected address is forgotten
+const MAX_FAILURES: u32 = 3;
+
+/// resolves host names of DNS seeds
+pub trait SeedResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error>;
+}
+
+/// the resolver of the operating system
+pub struct SystemResolver;
+
+impl SeedResolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// DNS seeds of a network
+pub fn dns_seeds(network: Network) -> &'static [&'static str] {
+    match network {
+        Network::Bitcoin => &[
+            "seed.bitcoin.sipa.be",
+            "dnsseed.bluematt.me",
+            "seed.bitcoinstats.com",
+            "seed.bitcoin.jonasschnelli.ch",
+            "seed.btc.petertodd.net",
+            "seed.bitcoin.sprovoost.nl",
+            "dnsseed.emzy.de",
+            "seed.bitcoin.wiz.biz",
+        ],
+        Network::Testnet => &[
+            "testnet-seed.bitcoin.jonasschnelli.ch",
+            "seed.tbtc.petertodd.net",
+            "seed.testnet.bitcoin.sprovoost.nl",
+            "testnet-seed.bluematt.me",
+        ],
+        Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+        Network::Regtest => &[],
+    }
+}
+
+/// default port of a network
+pub fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8333,
+        Network::Testnet => 18333,
+        Network::Signet => 38333,
+        Network::Regtest => 18444,
+    }
+}
+
+/// What is known of a peer address
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PeerEntry {
+    /// services as last announced
+    pub services: u64,
+    /// seconds since epoch the peer was last heard of
+    pub last_seen: u64,
+    /// seconds since epoch of the last connection attempt
+    pub last_try: u64,
+    /// seconds since epoch of the last successful connection
+    pub last_success: u64,
+    /// failed attempts since the last success
+    pub failures: u32,
+    /// misbehavior score
+    pub score: u32,
+    /// seconds since epoch the ban ends
+    pub banned_until: u64,
+}
+
+impl PeerEntry {
+    /// a connection to the address succeeded before
+    pub fn is_tried(&self) -> bool {
+        self.last_success > 0
+    }
+
+    /// the peer is banned at now
+    pub fn is_banned(&self, now: u64) -> bool {
+        self.banned_until > now
+    }
+}
+
+impl Encodable for PeerEntry {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.services.consensus_encode(&mut w)?;
+        len += self.last_seen.consensus_encode(&mut w)?;
+        len += self.last_try.consensus_encode(&mut w)?;
+        len += self.last_success.consensus_encode(&mut w)?;
+        len += self.failures.consensus_encode(&mut w)?;
+        len += self.score.consensus_encode(&mut w)?;
+        len += self.banned_until.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PeerEntry {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<PeerEntry, encode::Error> {
+        Ok(PeerEntry {
+            services: Decodable::consensus_decode(&mut d)?,
+            last_seen: Decodable::consensus_decode(&mut d)?,
+            last_try: Decodable::consensus_decode(&mut d)?,
+            last_success: Decodable::consensus_decode(&mut d)?,
+            failures: Decodable::consensus_decode(&mut d)?,
+            score: Decodable::consensus_decode(&mut d)?,
+            banned_until: Decodable::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// Address store and connection slots of peers of a network
+pub struct PeerManager {
+    network: Network,
+    slots: usize,
+    required: ServiceFlags,
+    peers: HashMap<SocketAddr, PeerEntry>,
+    connected: HashSet<SocketAddr>,
+    connector: Connector,
+}
+
+impl PeerManager {
+    /// an empty address store with 8 connection slots for peers serving witness blocks
+    pub fn new(network: Network) -> PeerManager {
+        PeerManager {
+            network,
+            slots: 8,
+            required: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            peers: HashMap::new(),
+            connected: HashSet::new(),
+            connector: Connector::direct(),
+        }
+    }
+
+    /// open connections through a proxy, seed with the connector as resolver to not leak DNS
+    pub fn connector(mut self, connector: Connector) -> PeerManager {
+        self.connector = connector;
+        self
+    }
+
+    /// number of peers connected at once
+    pub fn slots(mut self, slots: usize) -> PeerManager {
+        self.slots = slots;
+        self
+    }
+
+    /// services peers must announce, e.g. COMPACT_FILTERS for NeutrinoSync
+    pub fn require(mut self, services: ServiceFlags) -> PeerManager {
+        self.required = services;
+        self
+    }
+
+    /// read the address store from a key-value store
+    pub fn load<K: KeyValue>(network: Network, kv: &K) -> Result<PeerManager, Error> {
+        let mut manager = PeerManager::new(network);
+        for (key, value) in kv.scan_prefix(&[PEER])? {
+            let address = String::from_utf8(key[1..].to_vec())
+                .ok()
+                .and_then(|a| a.parse::<SocketAddr>().ok())
+                .ok_or(Error::Storage("invalid peer address"))?;
+            manager
+                .peers
+                .insert(address, deserialize(value.as_slice())?);
+        }
+        Ok(manager)
+    }
+
+    /// write the address store to a key-value store
+    pub fn save<K: KeyValue>(&self, kv: &mut K) -> Result<(), Error> {
+        for (key, _) in kv.scan_prefix(&[PEER])? {
+            kv.remove(key.as_slice())?;
+        }
+        for (address, entry) in &self.peers {
+            kv.insert(&Self::key(address), &serialize(entry))?;
+        }
+        kv.flush()
+    }
+
+    fn key(address: &SocketAddr) -> Vec<u8> {
+        let mut key = vec![PEER];
+        key.extend_from_slice(address.to_string().as_bytes());
+        key
+    }
+
+    /// resolve the DNS seeds of the network and add their addresses
+    /// returns the number of new addresses, seeds failing to resolve are skipped
+    pub fn seed<R: SeedResolver>(&mut self, resolver: &R) -> usize {
+        let port = default_port(self.network);
+        let before = self.peers.len();
+        for seed in dns_seeds(self.network) {
+            for address in resolver.resolve(seed, port).unwrap_or_default() {
+                // seeds only list full nodes
+                self.add(
+                    address,
+                    ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+                    now(),
+                );
+            }
+        }
+        self.peers.len() - before
+    }
+
+    /// add addresses a peer relayed with their last seen time
+    pub fn add_addresses(&mut self, addresses: &[(u32, Address)]) {
+        for (seen, address) in addresses {
+            if let Ok(socket) = address.socket_addr() {
+                self.add(socket, address.services, *seen as u64);
+            }
+        }
+    }
+
+    /// add an address or refresh what is known of it
+    pub fn add(&mut self, address: SocketAddr, services: ServiceFlags, seen: u64) {
+        if address.ip().is_unspecified() || address.port() == 0 {
+            return;
+        }
+        let entry = self.peers.entry(address).or_default();
+        entry.services |= services.as_u64();
+        entry.last_seen = entry.last_seen.max(seen.min(now()));
+        if self.peers.len() > MAX_ADDRESSES {
+            self.evict();
+        }
+    }
+
+    /// forget the oldest address never connected to
+    fn evict(&mut self) {
+        let connected = &self.connected;
+        let oldest = self
+            .peers
+            .iter()
+            .filter(|(a, e)| !e.is_tried() && !e.is_banned(now()) && !connected.contains(a))
+            .min_by_key(|(_, e)| e.last_seen)
+            .map(|(a, _)| *a);
+        if let Some(address) = oldest {
+            self.peers.remove(&address);
+        }
+    }
+
+    /// what is known of an address
+    pub fn get(&self, address: &SocketAddr) -> Option<&PeerEntry> {
+        self.peers.get(address)
+    }
+
+    /// number of known addresses
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// addresses occupying a connection slot
+    pub fn connected(&self) -> &HashSet<SocketAddr> {
+        &self.connected
+    }
+
+    /// an address to try for a free connection slot, marks the attempt
+    /// half of the time a tried address is preferred, if there is one
+    pub fn candidate(&mut self) -> Option<SocketAddr> {
+        if self.connected.len() >= self.slots {
+            return None;
+        }
+        let now = now();
+        let groups = self
+            .connected
+            .iter()
+            .map(|a| group(&a.ip()))
+            .collect::<HashSet<_>>();
+        let required = self.required.as_u64();
+        let candidates = self
+            .peers
+            .iter()
+            .filter(|(a, e)| {
+                !self.connected.contains(a)
+                    && !e.is_banned(now)
+                    && e.last_try + RETRY_DELAY <= now
+                    && e.services & required == required
+                    && (a.ip().is_loopback() || !groups.contains(&group(&a.ip())))
+            })
+            .map(|(a, e)| (*a, e.is_tried()))
+            .collect::<Vec<_>>();
+        let mut rng = thread_rng();
+        let tried = candidates.iter().filter(|(_, t)| *t).count();
+        let prefer = tried > 0 && (tried == candidates.len() || rng.gen_bool(0.5));
+        let pool = candidates
+            .iter()
+            .filter(|(_, t)| *t == prefer)
+            .map(|(a, _)| *a)
+            .collect::<Vec<_>>();
+        if pool.is_empty() {
+            return None;
+        }
+        let address = pool[rng.gen_range(0, pool.len())];
+        self.peers
+            .get_mut(&address)
+            .expect("candidate is known")
+            .last_try = now;
+        Some(address)
+    }
+
+    /// a connection to address succeeded and occupies a slot
+    pub fn connected_to(&mut self, address: SocketAddr, services: ServiceFlags) {
+        let now = now();
+        let entry = self.peers.entry(address).or_default();
+        entry.services = services.as_u64();
+        entry.last_seen = now;
+        entry.last_success = now;
+        entry.failures = 0;
+        self.connected.insert(address);
+    }
+
+    /// a connection attempt to address failed
+    pub fn failed(&mut self, address: &SocketAddr) {
+        let forget = match self.peers.get_mut(address) {
+            Some(entry) => {
+                entry.failures += 1;
+                !entry.is_tried() && entry.failures >= MAX_FAILURES
+            }
+            None => false,
+        };
+        if forget {
+            self.peers.remove(address);
+        }
+        self.connected.remove(address);
+    }
+
+    /// free the slot of a peer
+    pub fn disconnected(&mut self, address: &SocketAddr) {
+        self.connected.remove(address);
+    }
+
+    /// add to the misbehavior score of a peer, returns true if the peer is now banned
+    /// a banned peer loses its slot and should be disconnected
+    pub fn misbehaved(&mut self, address: &SocketAddr, score: u32) -> bool {
+        let entry = self.peers.entry(*address).or_default();
+        entry.score += score;
+        if entry.score < BAN_SCORE {
+            return false;
+        }
+        entry.score = 0;
+        entry.banned_until = now() + BAN_TIME;
+        self.connected.remove(address);
+        true
+    }
+
+    /// the peer at address is banned
+    pub fn is_banned(&self, address: &SocketAddr) -> bool {
+        self.peers.get(address).is_some_and(|e| e.is_banned(now()))
+    }
+
+    /// lift the ban of a peer
+    pub fn unban(&mut self, address: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(address) {
+            entry.banned_until = 0;
+        }
+    }
+
+    /// connect to candidates until a handshake succeeds, learns addresses from the peer
+    pub fn connect(&mut self, timeout: Duration) -> Result<(SocketAddr, Peer<TcpStream>), Error> {
+        while let Some(address) = self.candidate() {
+            let connector = self.connector.clone().timeout(timeout);
+            let host = address.ip().to_string();
+            match Peer::connect_with(&connector, host.as_str(), address.port(), self.network) {
+                Ok(peer) => {
+                    if !peer.version().services.has(self.required) {
+                        self.failed(&address);
+                        continue;
+                    }
+                    self.connected_to(address, peer.version().services);
+                    if let Ok(addresses) = peer.addresses() {
+                        self.add_addresses(addresses.as_slice());
+                    }
+                    return Ok((address, peer));
+                }
+                Err(Error::Peer(_)) | Err(Error::Network) | Err(Error::Serialize(_)) => {
+                    self.misbehaved(&address, BAN_SCORE);
+                }
+                Err(_) => self.failed(&address),
+            }
+        }
+        Err(Error::Peer("no peer to connect to"))
+    }
+}
+
+/// network group of an address, peers of a group share at most one slot
+fn group(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[..2].to_vec(),
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) => ip.octets()[..2].to_vec(),
+            None => ip.octets()[..4].to_vec(),
+        },
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    struct Seeds;
+
+    impl SeedResolver for Seeds {
+        fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+            match host {
+                "seed.bitcoin.sipa.be" => Ok(vec![
+                    SocketAddr::from(([1, 2, 3, 4], port)),
+                    SocketAddr::from(([1, 2, 5, 6], port)),
+                ]),
+                "dnsseed.bluematt.me" => Ok(vec![SocketAddr::from(([7, 8, 9, 10], port))]),
+                _ => Err(Error::Dns("no answer")),
+            }
+        }
+    }
+
+    #[test]
+    fn peer_manager() {
+        let mut manager = PeerManager::new(Network::Bitcoin).slots(2);
+        assert_eq!(manager.seed(&Seeds), 3);
+        let relayed = Address::new(
+            &SocketAddr::from(([11, 0, 0, 1], 8333)),
+            ServiceFlags::NETWORK,
+        );
+        manager.add_addresses(&[(1_600_000_000, relayed)]);
+        assert_eq!(manager.len(), 4);
+
+        // slots are filled from distinct groups with the required services
+        let first = manager.candidate().unwrap();
+        manager.connected_to(first, ServiceFlags::NETWORK | ServiceFlags::WITNESS);
+        let second = manager.candidate().unwrap();
+        assert_ne!(first.ip().to_string()[..4], second.ip().to_string()[..4]);
+        assert_ne!(second.ip(), "11.0.0.1".parse::<IpAddr>().unwrap());
+        manager.failed(&second);
+        assert!(manager.get(&first).unwrap().is_tried());
+        assert_eq!(manager.get(&second).unwrap().failures, 1);
+
+        // misbehavior bans and frees the slot
+        assert!(!manager.misbehaved(&first, 50));
+        assert!(manager.misbehaved(&first, 50));
+        assert!(manager.is_banned(&first));
+        assert!(manager.connected().is_empty());
+
+        let mut kv = BTreeMap::new();
+        manager.save(&mut kv).unwrap();
+        let loaded = PeerManager::load(Network::Bitcoin, &kv).unwrap();
+        assert_eq!(loaded.len(), 4);
+        assert_eq!(loaded.get(&first), manager.get(&first));
+        assert!(loaded.is_banned(&first));
+    }
+}
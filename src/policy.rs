@@ -0,0 +1,422 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Spending policy
+//!
+//! Rules checked before the wallet signs: a limit of the amount spent per day, a whitelist of
+//! recipients, the approval of a second signer above a threshold and a limit of the number of
+//! transactions in a time window. A transaction violating a rule is not signed, the error tells
+//! which rule it violates.
+//!
+//! The amount of a transaction is what it pays to scripts that are not own.
+//!
+use std::collections::HashSet;
+use std::fmt;
+
+use bitcoin::secp256k1::Signature;
+use bitcoin::{OutPoint, PrivateKey, PublicKey, Script, SigHashType, Transaction, TxOut, Txid};
+
+use account::{MasterAccount, Unlocker};
+use context::SecpContext;
+use error::Error;
+use psbt::Psbt;
+
+/// seconds in the window of the daily limit
+pub const DAY: u32 = 24 * 60 * 60;
+
+/// A rule a transaction violates
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// the amount would exceed the limit of the last 24 hours
+    DailyLimit {
+        limit: u64,
+        /// spent in the last 24 hours
+        spent: u64,
+        amount: u64,
+    },
+    /// pays a script that is not whitelisted
+    Recipient(Script),
+    /// the amount is above the threshold and the second signer did not approve
+    SecondSigner { threshold: u64, amount: u64 },
+    /// more than max transactions in window seconds
+    Velocity { max: usize, window: u32 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::DailyLimit {
+                limit,
+                spent,
+                amount,
+            } => write!(
+                f,
+                "daily limit {} exceeded, spent {} and requested {}",
+                limit, spent, amount
+            ),
+            Violation::Recipient(ref script) => {
+                write!(f, "recipient {} is not whitelisted", script)
+            }
+            Violation::SecondSigner { threshold, amount } => write!(
+                f,
+                "amount {} above {} needs approval of the second signer",
+                amount, threshold
+            ),
+            Violation::Velocity { max, window } => {
+                write!(f, "more than {} transactions in {} seconds", max, window)
+            }
+        }
+    }
+}
+
+/// Rules for signing and the spends they were applied to
+#[derive(Clone, Debug, Default)]
+pub struct SpendingPolicy {
+    daily_limit: Option<u64>,
+    whitelist: Option<HashSet<Script>>,
+    second_signer: Option<(u64, PublicKey)>,
+    velocity: Option<(usize, u32)>,
+    /// txid, unix time and amount of signed transactions
+    spends: Vec<(Txid, u32, u64)>,
+}
+
+impl SpendingPolicy {
+    /// a policy without rules
+    pub fn new() -> SpendingPolicy {
+        SpendingPolicy::default()
+    }
+
+    /// spend at most limit in 24 hours
+    pub fn daily_limit(mut self, limit: u64) -> SpendingPolicy {
+        self.daily_limit = Some(limit);
+        self
+    }
+
+    /// pay only whitelisted scripts, the first call starts the whitelist
+    pub fn allow_recipient(mut self, script: Script) -> SpendingPolicy {
+        self.whitelist
+            .get_or_insert_with(HashSet::new)
+            .insert(script);
+        self
+    }
+
+    /// amounts above threshold need an approval of the key, see approve
+    pub fn second_signer(mut self, threshold: u64, key: PublicKey) -> SpendingPolicy {
+        self.second_signer = Some((threshold, key));
+        self
+    }
+
+    /// sign at most max transactions in window seconds
+    pub fn velocity(mut self, max: usize, window: u32) -> SpendingPolicy {
+        self.velocity = Some((max, window));
+        self
+    }
+
+    /// amount of transactions signed in the window of seconds before now
+    pub fn spent_since(&self, now: u32, window: u32) -> u64 {
+        self.spends
+            .iter()
+            .filter(|(_, time, _)| *time + window > now)
+            .map(|(_, _, amount)| *amount)
+            .sum()
+    }
+
+    /// check a transaction against the rules at unix time now, returns its amount
+    /// Signing a transaction again, e.g. a PSBT after others signed, is not counted twice.
+    pub fn check(
+        &self,
+        master: &MasterAccount,
+        transaction: &Transaction,
+        now: u32,
+        approval: Option<&Signature>,
+    ) -> Result<u64, Error> {
+        let scripts = master.get_scripts().map(|(s, _)| s).collect::<HashSet<_>>();
+        let paid = transaction
+            .output
+            .iter()
+            .filter(|o| !scripts.contains(&o.script_pubkey))
+            .collect::<Vec<_>>();
+        if let Some(ref whitelist) = self.whitelist {
+            if let Some(output) = paid.iter().find(|o| !whitelist.contains(&o.script_pubkey)) {
+                return Err(Error::Policy(Violation::Recipient(
+                    output.script_pubkey.clone(),
+                )));
+            }
+        }
+        let amount = paid.iter().map(|o| o.value).sum::<u64>();
+        let txid = transaction.txid();
+        if self.spends.iter().any(|(t, _, _)| *t == txid) {
+            return Ok(amount);
+        }
+        if let Some(limit) = self.daily_limit {
+            let spent = self.spent_since(now, DAY);
+            if spent + amount > limit {
+                return Err(Error::Policy(Violation::DailyLimit {
+                    limit,
+                    spent,
+                    amount,
+                }));
+            }
+        }
+        if let Some((threshold, ref key)) = self.second_signer {
+            let approved = approval.is_some_and(|signature| {
+                SecpContext::new()
+                    .verify(&transaction.txid()[..], signature, key)
+                    .is_ok()
+            });
+            if amount > threshold && !approved {
+                return Err(Error::Policy(Violation::SecondSigner { threshold, amount }));
+            }
+        }
+        if let Some((max, window)) = self.velocity {
+            if self
+                .spends
+                .iter()
+                .filter(|(_, time, _)| *time + window > now)
+                .count()
+                >= max
+            {
+                return Err(Error::Policy(Violation::Velocity { max, window }));
+            }
+        }
+        Ok(amount)
+    }
+
+    /// sign own inputs of a transaction with SIGHASH_ALL if it complies with the policy
+    pub fn sign<R>(
+        &mut self,
+        master: &MasterAccount,
+        unlocker: &mut Unlocker,
+        transaction: &mut Transaction,
+        resolver: &R,
+        now: u32,
+        approval: Option<&Signature>,
+    ) -> Result<usize, Error>
+    where
+        R: Fn(&OutPoint) -> Option<TxOut>,
+    {
+        let amount = self.check(master, transaction, now, approval)?;
+        let txid = transaction.txid();
+        let signed = master.sign(transaction, SigHashType::All, resolver, unlocker)?;
+        self.record(txid, now, amount, signed);
+        Ok(signed)
+    }
+
+    /// add signatures of own keys to a PSBT if it complies with the policy
+    pub fn sign_psbt(
+        &mut self,
+        master: &MasterAccount,
+        unlocker: &mut Unlocker,
+        psbt: &mut Psbt,
+        now: u32,
+        approval: Option<&Signature>,
+    ) -> Result<usize, Error> {
+        let amount = self.check(master, &psbt.global.unsigned_tx, now, approval)?;
+        let signed = master.sign_psbt(psbt, unlocker)?;
+        self.record(psbt.global.unsigned_tx.txid(), now, amount, signed);
+        Ok(signed)
+    }
+
+    fn record(&mut self, txid: Txid, now: u32, amount: u64, signed: usize) {
+        if signed == 0 || self.spends.iter().any(|(t, _, _)| *t == txid) {
+            return;
+        }
+        self.spends.push((txid, now, amount));
+        // forget what no rule looks at anymore
+        let window = self.velocity.map(|(_, w)| w).unwrap_or(0).max(DAY);
+        self.spends.retain(|(_, time, _)| *time + window > now);
+    }
+}
+
+/// approval of the second signer for a transaction
+pub fn approve(transaction: &Transaction, key: &PrivateKey) -> Result<Signature, Error> {
+    SecpContext::new().sign(&transaction.txid()[..], key)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network};
+    use std::str::FromStr;
+
+    use account::AccountAddressType;
+    use builder::ChangePosition;
+    use coins::{CoinControl, Coins};
+    use fee::FeeRate;
+    use fixtures::{funded, master_account};
+
+    use super::*;
+
+    const NOW: u32 = 1_700_000_000;
+
+    /// four coins of 100_000 sat and a key approving above 50_000 sat
+    struct Wallet {
+        master: MasterAccount,
+        unlocker: Unlocker,
+        coins: Coins,
+        funding: Transaction,
+        second: PrivateKey,
+    }
+
+    impl Wallet {
+        fn new() -> Wallet {
+            let (mut master, mut unlocker) = master_account(Network::Testnet);
+            let (coins, funding) = funded(&mut master, Network::Testnet, &[100_000; 4]);
+            let second = unlocker
+                .unlock(AccountAddressType::P2PKH, 7, 0, 0, None)
+                .unwrap();
+            Wallet {
+                master,
+                unlocker,
+                coins,
+                funding,
+                second,
+            }
+        }
+
+        /// a daily limit of 100_000 sat, twice an hour and only to the friend
+        fn policy(&self) -> SpendingPolicy {
+            SpendingPolicy::new()
+                .daily_limit(100_000)
+                .allow_recipient(friend().script_pubkey())
+                .second_signer(50_000, SecpContext::new().public_from_private(&self.second))
+                .velocity(2, 3600)
+        }
+
+        /// a payment spending only the coin of this output of the funding transaction
+        fn pay(&self, to: &Address, value: u64, coin: u32) -> Transaction {
+            self.coins
+                .build_tx()
+                .add_recipient(to, value)
+                .feerate(FeeRate::from_sat_per_vb(1))
+                .coin_control(CoinControl::new().only_spend_from(&[OutPoint {
+                    txid: self.funding.txid(),
+                    vout: coin,
+                }]))
+                .change_position(ChangePosition::Last)
+                .build(&self.funding.output[0].script_pubkey, 1, |_| Some(1))
+                .unwrap()
+                .0
+        }
+
+        fn sign(
+            &mut self,
+            policy: &mut SpendingPolicy,
+            transaction: &mut Transaction,
+            now: u32,
+            approval: Option<&Signature>,
+        ) -> Result<usize, Error> {
+            let funding = &self.funding;
+            let resolve = |point: &OutPoint| funding.output.get(point.vout as usize).cloned();
+            policy.sign(
+                &self.master,
+                &mut self.unlocker,
+                transaction,
+                &resolve,
+                now,
+                approval,
+            )
+        }
+    }
+
+    fn friend() -> Address {
+        Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7").unwrap()
+    }
+
+    #[test]
+    fn recipient() {
+        let mut wallet = Wallet::new();
+        let mut policy = wallet.policy();
+        let stranger = Address::p2wsh(&Script::new(), Network::Testnet);
+        let mut to_stranger = wallet.pay(&stranger, 10_000, 0);
+        match wallet.sign(&mut policy, &mut to_stranger, NOW, None) {
+            Err(Error::Policy(Violation::Recipient(script))) => {
+                assert_eq!(script, stranger.script_pubkey())
+            }
+            _ => panic!("stranger paid"),
+        }
+        assert!(to_stranger.input[0].witness.is_empty());
+    }
+
+    #[test]
+    fn second_signer() {
+        let mut wallet = Wallet::new();
+        let mut policy = wallet.policy();
+        let mut large = wallet.pay(&friend(), 60_000, 0);
+        match wallet.sign(&mut policy, &mut large, NOW, None) {
+            Err(Error::Policy(Violation::SecondSigner { threshold, amount })) => {
+                assert_eq!((threshold, amount), (50_000, 60_000))
+            }
+            _ => panic!("not approved"),
+        }
+        let wrong = approve(&wallet.pay(&friend(), 60_001, 0), &wallet.second).unwrap();
+        assert!(wallet
+            .sign(&mut policy, &mut large, NOW, Some(&wrong))
+            .is_err());
+        let approval = approve(&large, &wallet.second).unwrap();
+        assert_eq!(
+            wallet
+                .sign(&mut policy, &mut large, NOW, Some(&approval))
+                .unwrap(),
+            1
+        );
+        assert_eq!(policy.spent_since(NOW, DAY), 60_000);
+    }
+
+    #[test]
+    fn daily_limit() {
+        let mut wallet = Wallet::new();
+        let mut policy = wallet.policy();
+        let mut first = wallet.pay(&friend(), 40_000, 0);
+        wallet.sign(&mut policy, &mut first, NOW, None).unwrap();
+        let mut second = wallet.pay(&friend(), 40_000, 1);
+        wallet.sign(&mut policy, &mut second, NOW, None).unwrap();
+        let mut more = wallet.pay(&friend(), 30_000, 2);
+        match wallet.sign(&mut policy, &mut more, NOW + 7200, None) {
+            Err(Error::Policy(Violation::DailyLimit {
+                limit,
+                spent,
+                amount,
+            })) => assert_eq!((limit, spent, amount), (100_000, 80_000, 30_000)),
+            _ => panic!("above daily limit"),
+        }
+        // the next day allows it
+        assert!(wallet.sign(&mut policy, &mut more, NOW + DAY, None).is_ok());
+    }
+
+    #[test]
+    fn velocity() {
+        let mut wallet = Wallet::new();
+        let mut policy = wallet.policy();
+        for coin in 0..2 {
+            let mut small = wallet.pay(&friend(), 1_000, coin);
+            wallet
+                .sign(&mut policy, &mut small, NOW + 60, None)
+                .unwrap();
+        }
+        let mut third = wallet.pay(&friend(), 1_000, 2);
+        match wallet.sign(&mut policy, &mut third, NOW + 120, None) {
+            Err(Error::Policy(Violation::Velocity { max, window })) => {
+                assert_eq!((max, window), (2, 3600))
+            }
+            _ => panic!("too fast"),
+        }
+        // an hour later velocity allows it, the day still counts
+        wallet
+            .sign(&mut policy, &mut third, NOW + 3661, None)
+            .unwrap();
+        assert_eq!(policy.spent_since(NOW + 3661, DAY), 3_000);
+    }
+}
@@ -18,8 +18,15 @@
 //!
 //!
 
+use std::cell::OnceCell;
+use std::io;
+
+use bitcoin::consensus::{encode, serialize, Decodable, Encodable};
 use bitcoin::hashes::{sha256d, Hash, HashEngine};
-use bitcoin::{Block, Transaction};
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::{Block, Transaction, TxMerkleNode, VarInt};
+
+use error::Error;
 
 /// A confirmed transaction with its SPV proof
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -31,14 +38,76 @@ pub struct ProvedTransaction {
 }
 
 impl ProvedTransaction {
+    /// proof of the transaction at position txnr, use a BlockProver for several of a block
     pub fn new(block: &Block, txnr: usize) -> ProvedTransaction {
-        let transaction = block.txdata[txnr].clone();
+        BlockProver::new(block).prove(txnr)
+    }
+
+    /// proofs of the transactions a filtered block matched, given the transactions that
+    /// followed the merkle block
+    pub fn from_merkle_block(
+        block: &MerkleBlock,
+        transactions: &[Transaction],
+    ) -> Result<Vec<ProvedTransaction>, Error> {
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+        block
+            .extract_matches(&mut matches, &mut indexes)
+            .map_err(|_| Error::Peer("merkle block does not match its header"))?;
+        // the tree is valid as checked above, walk it again for the paths of the matches
+        let tree = serialize(&block.txn);
+        let mut reader = tree.as_slice();
+        let total = u32::consensus_decode(&mut reader)?;
+        let hashes = Vec::<TxMerkleNode>::consensus_decode(&mut reader)?;
+        let bits = Vec::<u8>::consensus_decode(&mut reader)?
+            .iter()
+            .flat_map(|b| (0..8).map(move |i| b & (1 << i) != 0))
+            .collect::<Vec<_>>();
+        let mut height = 0;
+        while width(total, height) > 1 {
+            height += 1;
+        }
+        let (_, paths) = walk(total, &bits, &hashes, height, 0, &mut 0, &mut 0);
+        let block_hash = block.header.block_hash();
+        matches
+            .iter()
+            .zip(paths)
+            .map(|(txid, merkle_path)| {
+                let transaction = transactions
+                    .iter()
+                    .find(|t| t.txid() == *txid)
+                    .ok_or(Error::Peer("transaction of a merkle block missing"))?;
+                Ok(ProvedTransaction {
+                    transaction: transaction.clone(),
+                    merkle_path,
+                    block_hash,
+                })
+            })
+            .collect()
+    }
+
+    /// a transaction with its merkle path in the block
+    pub(crate) fn from_path(
+        transaction: Transaction,
+        merkle_path: Vec<(bool, sha256d::Hash)>,
+        block_hash: bitcoin::BlockHash,
+    ) -> ProvedTransaction {
         ProvedTransaction {
-            block_hash: block.header.block_hash(),
-            merkle_path: Self::compute_proof(txnr, block),
             transaction,
+            merkle_path,
+            block_hash,
         }
     }
+
+    /// position of the transaction in its block
+    pub fn position(&self) -> usize {
+        self.merkle_path
+            .iter()
+            .enumerate()
+            .map(|(level, (left, _))| (*left as usize) << level)
+            .sum()
+    }
+
     /// get a copy of the transaction
     pub fn get_transaction(&self) -> Transaction {
         self.transaction.clone()
@@ -119,6 +188,168 @@ impl ProvedTransaction {
     }
 }
 
+/// Proofs of several transactions of a block
+/// Txids are hashed and the levels of the merkle tree computed once, at the first proof, and
+/// shared by all proofs.
+pub struct BlockProver<'a> {
+    block: &'a Block,
+    /// levels of the merkle tree from txids up to the root
+    levels: OnceCell<Vec<Vec<sha256d::Hash>>>,
+}
+
+impl<'a> BlockProver<'a> {
+    pub fn new(block: &'a Block) -> BlockProver<'a> {
+        BlockProver {
+            block,
+            levels: OnceCell::new(),
+        }
+    }
+
+    fn levels(&self) -> &[Vec<sha256d::Hash>] {
+        self.levels.get_or_init(|| {
+            merkle_levels(
+                self.block
+                    .txdata
+                    .iter()
+                    .map(|t| t.txid().as_hash())
+                    .collect(),
+            )
+        })
+    }
+
+    /// merkle path of the transaction at position txnr
+    /// panics if the block has no such transaction
+    pub fn merkle_path(&self, txnr: usize) -> Vec<(bool, sha256d::Hash)> {
+        merkle_path(self.levels(), txnr)
+    }
+
+    /// proof of the transaction at position txnr
+    /// panics if the block has no such transaction
+    pub fn prove(&self, txnr: usize) -> ProvedTransaction {
+        ProvedTransaction::from_path(
+            self.block.txdata[txnr].clone(),
+            self.merkle_path(txnr),
+            self.block.header.block_hash(),
+        )
+    }
+}
+
+/// levels of the merkle tree of txids up to the root
+pub(crate) fn merkle_levels(txids: Vec<sha256d::Hash>) -> Vec<Vec<sha256d::Hash>> {
+    let mut levels = vec![txids];
+    while levels.last().is_some_and(|level| level.len() > 1) {
+        let next = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| {
+                // the last node of an odd level is paired with itself
+                let mut engine = sha256d::Hash::engine();
+                engine.input(&pair[0][..]);
+                engine.input(&pair[pair.len() - 1][..]);
+                sha256d::Hash::from_engine(engine)
+            })
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// merkle path of the transaction at position txnr in the levels of a merkle tree
+/// panics if there is no such transaction
+pub(crate) fn merkle_path(
+    levels: &[Vec<sha256d::Hash>],
+    txnr: usize,
+) -> Vec<(bool, sha256d::Hash)> {
+    assert!(txnr < levels[0].len(), "transaction not in block");
+    let mut track = txnr;
+    let mut path = Vec::new();
+    for level in &levels[..levels.len() - 1] {
+        let sibling = (track ^ 1).min(level.len() - 1);
+        path.push((track % 2 == 1, level[sibling]));
+        track /= 2;
+    }
+    path
+}
+
+/// nodes of a merkle tree of total transactions at height
+fn width(total: u32, height: u32) -> u32 {
+    (total + (1 << height) - 1) >> height
+}
+
+/// hash of a node of a partial merkle tree and merkle paths of the matches below it
+/// bit and hash count the flag bits and hashes used
+fn walk(
+    total: u32,
+    bits: &[bool],
+    hashes: &[TxMerkleNode],
+    height: u32,
+    position: u32,
+    bit: &mut usize,
+    hash: &mut usize,
+) -> (sha256d::Hash, Vec<Vec<(bool, sha256d::Hash)>>) {
+    let parent = bits[*bit];
+    *bit += 1;
+    if height == 0 || !parent {
+        *hash += 1;
+        let paths = if parent { vec![Vec::new()] } else { Vec::new() };
+        return (hashes[*hash - 1].as_hash(), paths);
+    }
+    let (left, mut paths) = walk(total, bits, hashes, height - 1, position * 2, bit, hash);
+    let (right, right_paths) = if position * 2 + 1 < width(total, height - 1) {
+        walk(total, bits, hashes, height - 1, position * 2 + 1, bit, hash)
+    } else {
+        (left, Vec::new())
+    };
+    for path in paths.iter_mut() {
+        path.push((false, right));
+    }
+    for mut path in right_paths {
+        path.push((true, left));
+        paths.push(path);
+    }
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    (sha256d::Hash::from_engine(engine), paths)
+}
+
+impl Encodable for ProvedTransaction {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.transaction.consensus_encode(&mut w)?;
+        len += VarInt(self.merkle_path.len() as u64).consensus_encode(&mut w)?;
+        for (left, hash) in &self.merkle_path {
+            len += left.consensus_encode(&mut w)?;
+            len += hash.consensus_encode(&mut w)?;
+        }
+        len += self.block_hash.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for ProvedTransaction {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<ProvedTransaction, encode::Error> {
+        let transaction = Transaction::consensus_decode(&mut d)?;
+        let n = VarInt::consensus_decode(&mut d)?.0;
+        if n > 64 {
+            return Err(encode::Error::ParseFailed("merkle path is too long"));
+        }
+        let mut merkle_path = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            merkle_path.push((
+                bool::consensus_decode(&mut d)?,
+                sha256d::Hash::consensus_decode(&mut d)?,
+            ));
+        }
+        let block_hash = bitcoin::BlockHash::consensus_decode(&mut d)?;
+        Ok(ProvedTransaction {
+            transaction,
+            merkle_path,
+            block_hash,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use bitcoin::hashes::hex::FromHex;
@@ -139,6 +370,37 @@ mod test {
                 block_hash: block.header.block_hash(),
             };
             assert_eq!(pt.merkle_root(), block.header.merkle_root);
+            assert_eq!(pt.position(), track);
+        }
+
+        // a prover hashes once for all proofs of a block
+        let prover = BlockProver::new(&block);
+        for track in (0..block.txdata.len()).rev() {
+            assert_eq!(
+                prover.merkle_path(track),
+                ProvedTransaction::compute_proof(track, &block)
+            );
+        }
+        let coinbase = Block {
+            header: block.header,
+            txdata: block.txdata[..1].to_vec(),
+        };
+        assert!(BlockProver::new(&coinbase).prove(0).merkle_path.is_empty());
+
+        // proofs of a filtered block match those of the full block
+        let matched = [0, 5, block.txdata.len() - 1]
+            .iter()
+            .map(|i| block.txdata[*i].txid())
+            .collect();
+        let filtered = MerkleBlock::from_block(&block, &matched);
+        let proofs = ProvedTransaction::from_merkle_block(&filtered, &block.txdata).unwrap();
+        assert_eq!(proofs.len(), 3);
+        for proof in proofs {
+            assert_eq!(proof, ProvedTransaction::new(&block, proof.position()));
         }
+        assert!(ProvedTransaction::from_merkle_block(&filtered, &block.txdata[..1]).is_err());
+        let mut forged = filtered;
+        forged.header.merkle_root = TxMerkleNode::default();
+        assert!(ProvedTransaction::from_merkle_block(&forged, &block.txdata).is_err());
     }
 }
@@ -0,0 +1,383 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Proxied connections
+//!
+//! A Connector opens the TCP connections of bitcoind RPC, Esplora over HTTP and peers of the
+//! network, either directly or through a SOCKS5 proxy such as Tor. Through a proxy, host names
+//! are resolved by the proxy, so .onion services are reachable and no DNS query leaks.
+//!
+//! With stream isolation each connection authenticates with fresh random credentials, which
+//! makes Tor use a separate circuit, so servers can not link connections by exit node.
+//!
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use bitcoin::hashes::hex::ToHex;
+use rand::{thread_rng, RngCore};
+
+use error::Error;
+use fee::HttpGet;
+use peers::SeedResolver;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const CONNECT: u8 = 1;
+/// the RESOLVE extension of Tor
+const RESOLVE: u8 = 0xF0;
+
+/// Opens TCP connections, directly or through a SOCKS5 proxy
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Connector {
+    proxy: Option<SocketAddr>,
+    isolate: bool,
+    timeout: Duration,
+}
+
+impl Default for Connector {
+    fn default() -> Connector {
+        Connector::direct()
+    }
+}
+
+impl Connector {
+    /// connect directly
+    pub fn direct() -> Connector {
+        Connector {
+            proxy: None,
+            isolate: false,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// connect through a SOCKS5 proxy with stream isolation
+    pub fn socks5(proxy: SocketAddr) -> Connector {
+        Connector {
+            proxy: Some(proxy),
+            isolate: true,
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// connect through the SOCKS5 port of a local Tor daemon
+    pub fn tor() -> Connector {
+        Connector::socks5(SocketAddr::from(([127, 0, 0, 1], 9050)))
+    }
+
+    /// authenticate each connection to the proxy with fresh credentials
+    pub fn isolate(mut self, isolate: bool) -> Connector {
+        self.isolate = isolate;
+        self
+    }
+
+    /// time to wait for the connection and each read or write
+    pub fn timeout(mut self, timeout: Duration) -> Connector {
+        self.timeout = timeout;
+        self
+    }
+
+    /// the proxy connections go through
+    pub fn proxy(&self) -> Option<SocketAddr> {
+        self.proxy
+    }
+
+    /// open a connection to host, a name, an .onion address or an IP address
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        let proxy = match self.proxy {
+            Some(proxy) => proxy,
+            None => {
+                if host.ends_with(".onion") {
+                    return Err(Error::Proxy("onion services need a proxy"));
+                }
+                let mut last = Error::Proxy("host has no address");
+                for address in (host, port).to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&address, self.timeout) {
+                        Ok(stream) => return self.configure(stream),
+                        Err(e) => last = Error::IO(e),
+                    }
+                }
+                return Err(last);
+            }
+        };
+        let mut stream = self.configure(TcpStream::connect_timeout(&proxy, self.timeout)?)?;
+        self.request(&mut stream, CONNECT, host, port)?;
+        Ok(stream)
+    }
+
+    fn configure(&self, stream: TcpStream) -> Result<TcpStream, Error> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+
+    /// greet, authenticate and send a command, returns the address of the reply
+    fn request(
+        &self,
+        stream: &mut TcpStream,
+        command: u8,
+        host: &str,
+        port: u16,
+    ) -> Result<IpAddr, Error> {
+        let method = if self.isolate { USER_PASS } else { NO_AUTH };
+        stream.write_all(&[SOCKS_VERSION, 1, method])?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply != [SOCKS_VERSION, method] {
+            return Err(Error::Proxy("proxy refused authentication method"));
+        }
+        if self.isolate {
+            let mut random = [0u8; 16];
+            thread_rng().fill_bytes(&mut random);
+            let (user, password) = (random[..8].to_hex(), random[8..].to_hex());
+            let mut auth = vec![1, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(auth.as_slice())?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(Error::Proxy("proxy rejected credentials"));
+            }
+        }
+        let mut message = vec![SOCKS_VERSION, command, 0];
+        match host.trim_matches(&['[', ']'][..]).parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                message.push(1);
+                message.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                message.push(4);
+                message.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(Error::Proxy("host name too long"));
+                }
+                message.push(3);
+                message.push(host.len() as u8);
+                message.extend_from_slice(host.as_bytes());
+            }
+        }
+        message.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(message.as_slice())?;
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head)?;
+        match head[1] {
+            0 => {}
+            2 => return Err(Error::Proxy("connection not allowed by ruleset")),
+            3 => return Err(Error::Proxy("network unreachable")),
+            4 => return Err(Error::Proxy("host unreachable")),
+            5 => return Err(Error::Proxy("connection refused")),
+            6 => return Err(Error::Proxy("TTL expired")),
+            _ => return Err(Error::Proxy("proxy failed")),
+        }
+        let address = match head[3] {
+            1 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip)?;
+                IpAddr::V4(Ipv4Addr::from(ip))
+            }
+            4 => {
+                let mut ip = [0u8; 16];
+                stream.read_exact(&mut ip)?;
+                IpAddr::V6(Ipv6Addr::from(ip))
+            }
+            3 => {
+                let mut len = [0u8];
+                stream.read_exact(&mut len)?;
+                stream.read_exact(vec![0u8; len[0] as usize].as_mut_slice())?;
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            }
+            _ => return Err(Error::Proxy("invalid proxy reply")),
+        };
+        stream.read_exact(&mut [0u8; 2])?;
+        Ok(address)
+    }
+}
+
+/// resolves through the proxy if there is one, so seeding does not leak DNS queries
+impl SeedResolver for Connector {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        match self.proxy {
+            None => Ok((host, port).to_socket_addrs()?.collect()),
+            Some(proxy) => {
+                let mut stream =
+                    self.configure(TcpStream::connect_timeout(&proxy, self.timeout)?)?;
+                let ip = self.request(&mut stream, RESOLVE, host, 0)?;
+                Ok(vec![SocketAddr::new(ip, port)])
+            }
+        }
+    }
+}
+
+/// split host:port or [ipv6]:port, port defaults if missing
+pub(crate) fn host_port(address: &str, default: u16) -> Result<(String, u16), Error> {
+    let (host, port) = match address.rfind(':') {
+        Some(colon) if !address[colon..].contains(']') && address.matches(':').count() == 1 => (
+            &address[..colon],
+            address[colon + 1..]
+                .parse::<u16>()
+                .map_err(|_| Error::Proxy("invalid port"))?,
+        ),
+        Some(colon) if address.starts_with('[') && address[..colon].ends_with(']') => (
+            &address[1..colon - 1],
+            address[colon + 1..]
+                .parse::<u16>()
+                .map_err(|_| Error::Proxy("invalid port"))?,
+        ),
+        _ => (address.trim_matches(&['[', ']'][..]), default),
+    };
+    Ok((host.to_string(), port))
+}
+
+/// An HTTP client for plain http URLs, e.g. of an Esplora onion service
+/// https needs TLS which this library does not implement, use an HttpGet of a TLS client.
+pub struct HttpClient {
+    connector: Connector,
+}
+
+impl HttpClient {
+    pub fn new(connector: Connector) -> HttpClient {
+        HttpClient { connector }
+    }
+}
+
+impl HttpGet for HttpClient {
+    fn get(&self, url: &str) -> Result<String, Error> {
+        let rest = url.strip_prefix("http://").ok_or(Error::Unsupported(
+            "only http URLs, https needs a TLS client",
+        ))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = host_port(authority, 80)?;
+        let mut stream = self.connector.connect(host.as_str(), port)?;
+        // HTTP/1.0 so the body is not chunked
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, authority
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response =
+            String::from_utf8(response).map_err(|_| Error::Backend("invalid HTTP response"))?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or(Error::Backend("invalid HTTP response"))?;
+        if head.split(' ').nth(1) != Some("200") {
+            return Err(Error::Backend("HTTP request failed"));
+        }
+        Ok(body.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    /// a SOCKS5 proxy answering HTTP requests itself, reports host, port and user
+    fn proxy(listener: TcpListener, requests: mpsc::Sender<(String, u16, String)>) {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, greeting[2]]).unwrap();
+            let mut user = String::new();
+            if greeting[2] == USER_PASS {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut name = vec![0u8; len[1] as usize];
+                stream.read_exact(&mut name).unwrap();
+                user = String::from_utf8(name).unwrap();
+                stream.read_exact(&mut len[..1]).unwrap();
+                stream
+                    .read_exact(vec![0u8; len[0] as usize].as_mut_slice())
+                    .unwrap();
+                stream.write_all(&[1, 0]).unwrap();
+            }
+            let mut head = [0u8; 5];
+            stream.read_exact(&mut head).unwrap();
+            let mut host = vec![0u8; head[4] as usize];
+            stream.read_exact(&mut host).unwrap();
+            let mut port = [0u8; 2];
+            stream.read_exact(&mut port).unwrap();
+            stream.write_all(&[5, 0, 0, 1, 10, 0, 0, 7, 0, 0]).unwrap();
+            let host = String::from_utf8(host).unwrap();
+            requests
+                .send((host, u16::from_be_bytes(port), user))
+                .unwrap();
+            if head[1] == CONNECT {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8];
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                stream
+                    .write_all(b"HTTP/1.0 200 OK\r\n\r\n{\"1\":20.5}")
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || proxy(listener, requests));
+
+        let onion = "http://explorerzydxu5ecjrkwceayqybizmpjjznk5izmitf2modhcusuqlid.onion/api";
+        let direct = HttpClient::new(Connector::direct());
+        assert!(direct.get(&format!("{}/fee-estimates", onion)).is_err());
+
+        let http = HttpClient::new(Connector::socks5(address));
+        assert_eq!(
+            http.get(&format!("{}/fee-estimates", onion)).unwrap(),
+            "{\"1\":20.5}"
+        );
+        let (host, port, first) = received.recv().unwrap();
+        assert!(host.starts_with("explorerzydxu5"));
+        assert_eq!(port, 80);
+        http.get(&format!("{}/blocks/tip/height", onion)).unwrap();
+        let (_, _, second) = received.recv().unwrap();
+        assert_ne!(first, second);
+
+        let resolved = Connector::socks5(address)
+            .isolate(false)
+            .resolve("seed.bitcoin.sipa.be", 8333)
+            .unwrap();
+        assert_eq!(resolved, vec![SocketAddr::from(([10, 0, 0, 7], 8333))]);
+        assert_eq!(received.recv().unwrap().2, "");
+
+        assert_eq!(
+            host_port("[::1]:8332", 80).unwrap(),
+            ("::1".to_string(), 8332)
+        );
+        assert_eq!(
+            host_port("node.onion", 8333).unwrap(),
+            ("node.onion".to_string(), 8333)
+        );
+    }
+}
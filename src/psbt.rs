@@ -0,0 +1,791 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Partially signed transactions
+//!
+//! Creator, updater and finalizer roles of BIP174 for coins of this wallet.
+//! The signer role is MasterAccount::sign_psbt.
+//!
+//! Fields this wallet does not interpret, such as proprietary fields or taproot fields of newer
+//! software, are kept through signing, combining and finalizing.
+//!
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::{Builder, Instruction};
+use bitcoin::hashes::{hash160, Hash};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, KeySource};
+use bitcoin::util::psbt::{Input, PartiallySignedTransaction};
+use bitcoin::{Network, PublicKey, Script, SigHashType, Transaction, TxOut, VarInt};
+
+use account::{AccountAddressType, KeyDerivation, MasterAccount};
+use builder::transaction_base_weight;
+use coins::Coins;
+use error::Error;
+use fee::FeeRate;
+use selection::{estimate_input_weight, output_weight};
+
+/// a partially signed transaction
+pub type Psbt = PartiallySignedTransaction;
+
+/// first byte of the annex, the optional last witness element of a taproot spend (BIP341)
+pub const ANNEX_TAG: u8 = 0x50;
+
+/// the annex of the witness of a taproot spend
+/// Only taproot witnesses have an annex, the last element of other witnesses is no annex even
+/// if it starts with the tag.
+pub fn annex(witness: &[Vec<u8>]) -> Option<&[u8]> {
+    match witness.split_last() {
+        Some((last, rest)) if !rest.is_empty() && last.first() == Some(&ANNEX_TAG) => {
+            Some(last.as_slice())
+        }
+        _ => None,
+    }
+}
+
+/// BIP32 origin of an own key
+/// keys with a tweak are not on a derivation path and have no origin
+pub fn key_source(
+    master: &MasterAccount,
+    derivation: &KeyDerivation,
+) -> Option<(PublicKey, KeySource)> {
+    if derivation.tweak.is_some() {
+        return None;
+    }
+    let account = master.get((derivation.account, derivation.sub))?;
+    let key = account.get_key(derivation.kix)?;
+    let coin_type = match account.network() {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+    let path = DerivationPath::from(vec![
+        ChildNumber::Hardened {
+            index: account.address_type().as_u32(),
+        },
+        ChildNumber::Hardened { index: coin_type },
+        ChildNumber::Hardened {
+            index: derivation.account,
+        },
+        ChildNumber::Normal {
+            index: derivation.sub,
+        },
+        ChildNumber::Normal {
+            index: derivation.kix,
+        },
+    ]);
+    Some((key.public, (master.master_public().fingerprint(), path)))
+}
+
+/// script a P2SH wrapped P2WPKH output commits to
+fn p2wpkh_redeem_script(public: &PublicKey) -> Script {
+    Builder::new()
+        .push_int(0)
+        .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
+        .into_script()
+}
+
+/// add what signers need to spend an own coin to input ix of a PSBT
+/// the spent output, redeem or witness script and the BIP32 origin of the key
+pub fn update_input(
+    psbt: &mut Psbt,
+    ix: usize,
+    coins: &Coins,
+    master: &MasterAccount,
+) -> Result<(), Error> {
+    let point = psbt.global.unsigned_tx.input[ix].previous_output;
+    let coin = coins
+        .confirmed()
+        .get(&point)
+        .or_else(|| coins.unconfirmed().get(&point))
+        .ok_or(Error::Unsupported("PSBT input is not an own coin"))?;
+    let d = &coin.derivation;
+    let account = master
+        .get((d.account, d.sub))
+        .ok_or(Error::Unsupported("PSBT input of unknown account"))?;
+    let key = account
+        .get_key(d.kix)
+        .ok_or(Error::Unsupported("PSBT input of unknown key"))?;
+    let input = &mut psbt.inputs[ix];
+    match account.address_type() {
+        AccountAddressType::P2PKH => {
+            let previous = coins
+                .proofs()
+                .get(&point.txid)
+                .map(|p| p.get_transaction().clone())
+                .or_else(|| coins.pending().get(&point.txid).cloned())
+                .ok_or(Error::Unsupported(
+                    "spent transaction of legacy input not known",
+                ))?;
+            input.non_witness_utxo = Some(previous);
+        }
+        AccountAddressType::P2SHWPKH => {
+            input.witness_utxo = Some(coin.output.clone());
+            input.redeem_script = Some(p2wpkh_redeem_script(&key.public));
+        }
+        AccountAddressType::P2WPKH => {
+            input.witness_utxo = Some(coin.output.clone());
+        }
+        AccountAddressType::P2WSH(_) => {
+            input.witness_utxo = Some(coin.output.clone());
+            input.witness_script = Some(key.script_code.clone());
+        }
+    }
+    if let Some((public, source)) = key_source(master, d) {
+        input.bip32_derivation.insert(public, source);
+    }
+    Ok(())
+}
+
+/// create a PSBT of an unsigned transaction spending own coins
+/// Inputs are updated with update_input, own outputs get the origin of their key. Fails if a
+/// spent coin is not own.
+pub fn create(
+    transaction: Transaction,
+    coins: &Coins,
+    master: &MasterAccount,
+) -> Result<Psbt, Error> {
+    let mut psbt = Psbt::from_unsigned_tx(transaction)?;
+    for ix in 0..psbt.inputs.len() {
+        update_input(&mut psbt, ix, coins, master)?;
+    }
+    let scripts = master.get_scripts().collect::<Vec<_>>();
+    for (ix, txout) in psbt.global.unsigned_tx.output.iter().enumerate() {
+        if let Some((_, d)) = scripts.iter().find(|(s, _)| *s == txout.script_pubkey) {
+            let output = &mut psbt.outputs[ix];
+            if let Some(account) = master.get((d.account, d.sub)) {
+                if let Some(key) = account.get_key(d.kix) {
+                    match account.address_type() {
+                        AccountAddressType::P2SHWPKH => {
+                            output.redeem_script = Some(p2wpkh_redeem_script(&key.public))
+                        }
+                        AccountAddressType::P2WSH(_) => {
+                            output.witness_script = Some(key.script_code.clone())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if let Some((public, source)) = key_source(master, d) {
+                output.bip32_derivation.insert(public, source);
+            }
+        }
+    }
+    Ok(psbt)
+}
+
+/// assemble script_sig and witness of inputs that have the signatures they need
+/// returns the number of inputs final after the call
+pub fn finalize(psbt: &mut Psbt) -> usize {
+    let mut finalized = 0;
+    for (ix, input) in psbt.inputs.iter_mut().enumerate() {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            finalized += 1;
+            continue;
+        }
+        let vout = psbt.global.unsigned_tx.input[ix].previous_output.vout as usize;
+        let spent = match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(output), _) => output.script_pubkey.clone(),
+            (None, Some(previous)) => match previous.output.get(vout) {
+                Some(output) => output.script_pubkey.clone(),
+                None => continue,
+            },
+            (None, None) => continue,
+        };
+        // single key scripts of this wallet
+        let (public, signature) = match input.partial_sigs.iter().next() {
+            Some((public, signature)) if input.partial_sigs.len() == 1 => {
+                (public.to_bytes(), signature.clone())
+            }
+            _ => continue,
+        };
+        if spent.is_p2pkh() {
+            input.final_script_sig = Some(
+                Builder::new()
+                    .push_slice(signature.as_slice())
+                    .push_slice(public.as_slice())
+                    .into_script(),
+            );
+        } else if spent.is_v0_p2wpkh() {
+            input.final_script_witness = Some(vec![signature, public]);
+        } else if spent.is_p2sh() {
+            match input.redeem_script {
+                Some(ref redeem) if redeem.is_v0_p2wpkh() => {
+                    input.final_script_sig =
+                        Some(Builder::new().push_slice(&redeem[..]).into_script());
+                    input.final_script_witness = Some(vec![signature, public]);
+                }
+                _ => continue,
+            }
+        } else if spent.is_v0_p2wsh() {
+            match input.witness_script {
+                Some(ref script) => {
+                    input.final_script_witness = Some(vec![signature, script.to_bytes()])
+                }
+                None => continue,
+            }
+        } else {
+            continue;
+        }
+        // BIP174: the finalizer removes what is no longer needed
+        input.partial_sigs.clear();
+        input.sighash_type = None;
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation.clear();
+        finalized += 1;
+    }
+    finalized
+}
+
+/// combine signatures and other information of a PSBT of the same transaction into psbt
+pub fn combine(psbt: &mut Psbt, other: Psbt) -> Result<(), Error> {
+    Ok(psbt.merge(other)?)
+}
+
+/// copy the global fields of other this wallet does not interpret into psbt
+/// for PSBTs of a new transaction built from other, where combine is not possible
+pub fn preserve_global(psbt: &mut Psbt, other: &Psbt) {
+    psbt.global
+        .xpub
+        .extend(other.global.xpub.iter().map(|(k, v)| (*k, v.clone())));
+    psbt.global.proprietary.extend(
+        other
+            .global
+            .proprietary
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone())),
+    );
+    psbt.global.unknown.extend(
+        other
+            .global
+            .unknown
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone())),
+    );
+}
+
+/// Signing progress of an input
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InputAnalysis {
+    /// script_sig and witness are assembled
+    pub is_final: bool,
+    /// partial signatures present
+    pub signatures: usize,
+    /// signatures needed to finalize
+    pub required: usize,
+    /// known keys that did not sign yet
+    pub missing: Vec<PublicKey>,
+    /// estimated weight of the final input
+    pub weight: u64,
+    /// risks of the sighash types requested or signed with
+    pub warnings: Vec<SighashWarning>,
+}
+
+/// What a signature with a sighash type other than SIGHASH_ALL does not commit to
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SighashWarning {
+    /// SIGHASH_ANYONECANPAY, other inputs can be added or removed
+    AnyoneCanPay,
+    /// SIGHASH_NONE, outputs can be changed at will
+    NoOutputs,
+    /// SIGHASH_SINGLE, only the output with the index of the input is committed to
+    SingleOutput,
+    /// SIGHASH_SINGLE without output of the same index, the signature signs the constant 1
+    /// and can be used to spend the coin in any transaction
+    SingleWithoutOutput,
+}
+
+/// Signing progress of a PSBT, see analyze
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Analysis {
+    pub inputs: Vec<InputAnalysis>,
+    /// None if the value of a spent output is not known
+    pub fee: Option<u64>,
+    /// estimated weight of the final transaction
+    pub weight: u64,
+}
+
+impl Analysis {
+    /// all inputs are final
+    pub fn is_complete(&self) -> bool {
+        self.inputs.iter().all(|i| i.is_final)
+    }
+
+    /// fee rate of the final transaction
+    pub fn feerate(&self) -> Option<FeeRate> {
+        self.fee
+            .map(|fee| FeeRate::from_sat_per_kwu(fee * 1000 / self.weight))
+    }
+}
+
+/// output spent by an input of a PSBT
+pub(crate) fn spent_output(psbt: &Psbt, ix: usize) -> Option<TxOut> {
+    let input = &psbt.inputs[ix];
+    match (&input.witness_utxo, &input.non_witness_utxo) {
+        (Some(output), _) => Some(output.clone()),
+        (None, Some(previous)) => {
+            let vout = psbt.global.unsigned_tx.input[ix].previous_output.vout as usize;
+            previous.output.get(vout).cloned()
+        }
+        (None, None) => None,
+    }
+}
+
+/// request a sighash type for an input, signers use SIGHASH_ALL otherwise
+/// refuses SIGHASH_SINGLE without an output of the same index
+pub fn set_sighash_type(psbt: &mut Psbt, ix: usize, hash_type: SigHashType) -> Result<(), Error> {
+    if ix >= psbt.inputs.len() {
+        return Err(Error::Unsupported("no such input"));
+    }
+    if hash_type.as_u32() & 0x1f == SigHashType::Single.as_u32()
+        && ix >= psbt.global.unsigned_tx.output.len()
+    {
+        return Err(Error::Unsupported("SIGHASH_SINGLE without matching output"));
+    }
+    psbt.inputs[ix].sighash_type = Some(hash_type);
+    Ok(())
+}
+
+/// warnings for the sighash types requested for or signed with at input ix
+fn sighash_warnings(psbt: &Psbt, ix: usize) -> Vec<SighashWarning> {
+    let input = &psbt.inputs[ix];
+    let mut types = input
+        .partial_sigs
+        .values()
+        .filter_map(|s| s.last().map(|t| *t as u32))
+        .collect::<Vec<_>>();
+    if let Some(hash_type) = input.sighash_type {
+        types.push(hash_type.as_u32());
+    }
+    let mut warnings = Vec::new();
+    for hash_type in types {
+        let warning = match hash_type & 0x1f {
+            2 => Some(SighashWarning::NoOutputs),
+            3 if ix < psbt.global.unsigned_tx.output.len() => Some(SighashWarning::SingleOutput),
+            3 => Some(SighashWarning::SingleWithoutOutput),
+            _ => None,
+        };
+        let anyonecanpay = if hash_type & 0x80 != 0 {
+            Some(SighashWarning::AnyoneCanPay)
+        } else {
+            None
+        };
+        for warning in warning.into_iter().chain(anyonecanpay) {
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+    }
+    warnings
+}
+
+/// threshold and keys of a bare m of n multisig script
+pub(crate) fn multisig(script: &Script) -> Option<(usize, Vec<PublicKey>)> {
+    let small = |code: u8| {
+        if code >= all::OP_PUSHNUM_1.into_u8() && code <= all::OP_PUSHNUM_16.into_u8() {
+            Some((code - all::OP_PUSHNUM_1.into_u8() + 1) as usize)
+        } else {
+            None
+        }
+    };
+    let mut instructions = script.instructions();
+    let m = match instructions.next() {
+        Some(Ok(Instruction::Op(op))) => small(op.into_u8())?,
+        _ => return None,
+    };
+    let mut keys = Vec::new();
+    loop {
+        match instructions.next() {
+            Some(Ok(Instruction::PushBytes(bytes))) => {
+                keys.push(PublicKey::from_slice(bytes).ok()?)
+            }
+            Some(Ok(Instruction::Op(op))) => {
+                if small(op.into_u8())? != keys.len() {
+                    return None;
+                }
+                break;
+            }
+            _ => return None,
+        }
+    }
+    match (instructions.next(), instructions.next()) {
+        (Some(Ok(Instruction::Op(all::OP_CHECKMULTISIG))), None) if m <= keys.len() => {
+            Some((m, keys))
+        }
+        _ => None,
+    }
+}
+
+/// weight of an input that is final
+/// an input without witness still has the empty witness count in a segwit transaction
+fn final_weight(input: &Input) -> u64 {
+    let script_sig = input
+        .final_script_sig
+        .as_ref()
+        .map(|s| s.len())
+        .unwrap_or(0);
+    let witness = input
+        .final_script_witness
+        .as_ref()
+        .map(|w| {
+            VarInt(w.len() as u64).len()
+                + w.iter()
+                    .map(|i| VarInt(i.len() as u64).len() + i.len())
+                    .sum::<usize>()
+        })
+        .unwrap_or(1);
+    ((32 + 4 + 4 + VarInt(script_sig as u64).len() + script_sig) * 4 + witness) as u64
+}
+
+/// report signing progress of each input and the expected fee and weight
+pub fn analyze(psbt: &Psbt) -> Analysis {
+    let mut fee = Some(0i64);
+    let mut inputs = Vec::new();
+    for (ix, input) in psbt.inputs.iter().enumerate() {
+        let spent = spent_output(psbt, ix);
+        fee = match (fee, &spent) {
+            (Some(fee), Some(output)) => Some(fee + output.value as i64),
+            _ => None,
+        };
+        let is_final = input.final_script_sig.is_some() || input.final_script_witness.is_some();
+        let signatures = input.partial_sigs.len();
+        let (required, keys, weight) = match input.witness_script.as_ref().and_then(multisig) {
+            Some((m, keys)) => {
+                let script = input.witness_script.as_ref().unwrap().len();
+                // empty dummy, signatures and the script
+                let witness = 1 + 1 + m * 73 + VarInt(script as u64).len() + script;
+                (m, keys, ((32 + 4 + 4 + 1) * 4 + witness) as u64)
+            }
+            None => (
+                1,
+                input.bip32_derivation.keys().cloned().collect(),
+                spent
+                    .as_ref()
+                    .map(|o| estimate_input_weight(&o.script_pubkey))
+                    .unwrap_or_else(|| estimate_input_weight(&Script::new())),
+            ),
+        };
+        let missing = if is_final {
+            Vec::new()
+        } else {
+            keys.into_iter()
+                .filter(|k| !input.partial_sigs.contains_key(k))
+                .collect()
+        };
+        inputs.push(InputAnalysis {
+            is_final,
+            signatures,
+            required: if is_final { 0 } else { required },
+            missing,
+            weight: if is_final {
+                final_weight(input)
+            } else {
+                weight
+            },
+            warnings: sighash_warnings(psbt, ix),
+        });
+    }
+    let outputs = &psbt.global.unsigned_tx.output;
+    let fee = fee
+        .map(|fee| fee - outputs.iter().map(|o| o.value as i64).sum::<i64>())
+        .filter(|fee| *fee >= 0)
+        .map(|fee| fee as u64);
+    let weight = transaction_base_weight(inputs.len(), outputs.len())
+        + inputs.iter().map(|i| i.weight).sum::<u64>()
+        + outputs
+            .iter()
+            .map(|o| output_weight(&o.script_pubkey))
+            .sum::<u64>();
+    Analysis {
+        inputs,
+        fee,
+        weight,
+    }
+}
+
+/// extract the signed transaction of a PSBT with all inputs final
+pub fn extract(psbt: Psbt) -> Result<Transaction, Error> {
+    if psbt
+        .inputs
+        .iter()
+        .any(|i| i.final_script_sig.is_none() && i.final_script_witness.is_none())
+    {
+        return Err(Error::Unsupported("PSBT has inputs that are not final"));
+    }
+    Ok(psbt.extract_tx())
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{Address, OutPoint, TxOut};
+
+    use account::{Account, Unlocker};
+    use coins::CoinControl;
+    use fee::FeeRate;
+    use fixtures::{block, funding, master_account, next_script};
+    use selection::{estimate_vsize, InputType};
+
+    use super::*;
+
+    /// a PSBT spending coins of a P2WPKH, a P2SHWPKH and a P2PKH account
+    fn spend_three() -> (MasterAccount, Unlocker, Transaction, Psbt) {
+        let (mut master, mut unlocker) = master_account(Network::Testnet);
+        let types = [AccountAddressType::P2SHWPKH, AccountAddressType::P2PKH];
+        for (n, address_type) in types.iter().enumerate() {
+            master.add_account(
+                Account::new(&mut unlocker, *address_type, n as u32 + 1, 0, 10).unwrap(),
+            );
+        }
+        let funding = funding(
+            (0..3)
+                .map(|n| TxOut {
+                    value: 100_000,
+                    script_pubkey: next_script(&mut master, (n, 0)),
+                })
+                .collect(),
+        );
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block(Network::Testnet, vec![funding.clone()]));
+        assert_eq!(coins.confirmed().len(), 3);
+
+        let mut control = CoinControl::new();
+        for vout in 0..3 {
+            control = control.add_utxo(OutPoint {
+                txid: funding.txid(),
+                vout,
+            });
+        }
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = funding.output[0].script_pubkey.clone();
+        let (psbt, _) = coins
+            .build_tx()
+            .add_recipient(&to, 150_000)
+            .feerate(FeeRate::from_sat_per_vb(1))
+            .coin_control(control)
+            .build_psbt(&master, &change, 1, |_| Some(1))
+            .unwrap();
+        (master, unlocker, funding, psbt)
+    }
+
+    #[test]
+    fn create_sign_finalize() {
+        let (master, mut unlocker, funding, mut psbt) = spend_three();
+        let legacy = psbt
+            .inputs
+            .iter()
+            .filter(|i| i.non_witness_utxo.is_some())
+            .count();
+        assert_eq!(legacy, 1);
+        assert!(psbt.inputs.iter().all(|i| i.bip32_derivation.len() == 1));
+        assert_eq!(
+            psbt.inputs
+                .iter()
+                .filter(|i| i.redeem_script.is_some())
+                .count(),
+            1
+        );
+        // the change output is own
+        let change = psbt
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == funding.output[0].script_pubkey)
+            .unwrap();
+        assert_eq!(psbt.outputs[change].bip32_derivation.len(), 1);
+
+        assert!(extract(psbt.clone()).is_err());
+        assert_eq!(finalize(&mut psbt), 0);
+        assert_eq!(master.sign_psbt(&mut psbt, &mut unlocker).unwrap(), 3);
+        // signing again adds nothing
+        assert_eq!(master.sign_psbt(&mut psbt, &mut unlocker).unwrap(), 0);
+        assert_eq!(finalize(&mut psbt), 3);
+        let transaction = extract(psbt).unwrap();
+        transaction
+            .verify(|point| funding.output.get(point.vout as usize).cloned())
+            .unwrap();
+        // estimates before signing are within a few vbytes of the signed size
+        let inputs = transaction
+            .input
+            .iter()
+            .map(|i| InputType::of(&funding.output[i.previous_output.vout as usize].script_pubkey))
+            .collect::<Vec<_>>();
+        let outputs = transaction
+            .output
+            .iter()
+            .map(|o| o.script_pubkey.clone())
+            .collect::<Vec<_>>();
+        let estimated = estimate_vsize(inputs.as_slice(), outputs.as_slice());
+        let vsize = (transaction.get_weight() as u64).div_ceil(4);
+        assert!(estimated >= vsize && estimated - vsize <= 3);
+    }
+
+    #[test]
+    fn combine_analyze() {
+        let (master, mut unlocker, funding, psbt) = spend_three();
+        let analysis = analyze(&psbt);
+        assert!(!analysis.is_complete());
+        let outputs = psbt
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|o| o.value)
+            .sum::<u64>();
+        assert_eq!(analysis.fee, Some(300_000 - outputs));
+        assert!(analysis.feerate().unwrap() >= FeeRate::from_sat_per_vb(1));
+        for input in analysis.inputs.iter() {
+            assert_eq!((input.signatures, input.required), (0, 1));
+            assert_eq!(input.missing.len(), 1);
+        }
+
+        let mut signed = psbt.clone();
+        master.sign_psbt(&mut signed, &mut unlocker).unwrap();
+        let mut combined = psbt.clone();
+        combine(&mut combined, signed).unwrap();
+        let analysis = analyze(&combined);
+        assert!(analysis
+            .inputs
+            .iter()
+            .all(|i| i.signatures == 1 && i.missing.is_empty()));
+
+        finalize(&mut combined);
+        let analysis = analyze(&combined);
+        assert!(analysis.is_complete());
+        let transaction = extract(combined).unwrap();
+        assert_eq!(analysis.weight, transaction.get_weight() as u64);
+        transaction
+            .verify(|point| funding.output.get(point.vout as usize).cloned())
+            .unwrap();
+
+        let mut other = psbt.clone();
+        other.global.unsigned_tx.lock_time = 1;
+        let mut psbt = psbt;
+        assert!(combine(&mut psbt, other).is_err());
+    }
+
+    #[test]
+    fn future_fields() {
+        use bitcoin::consensus::{deserialize, serialize};
+        use bitcoin::util::psbt::raw::{Key, ProprietaryKey};
+
+        let (master, mut unlocker, _, mut psbt) = spend_three();
+        // taproot key path signature and an unknown global type of newer software
+        let tap_key_sig = Key {
+            type_value: 0x13,
+            key: Vec::new(),
+        };
+        let proprietary = ProprietaryKey {
+            prefix: b"vendor".to_vec(),
+            subtype: 1,
+            key: vec![1, 2, 3],
+        };
+        psbt.global.unknown.insert(
+            Key {
+                type_value: 0x07,
+                key: Vec::new(),
+            },
+            vec![0xaa],
+        );
+        psbt.global
+            .proprietary
+            .insert(proprietary.clone(), vec![0xbb]);
+        psbt.inputs[0]
+            .unknown
+            .insert(tap_key_sig.clone(), vec![0xcc; 64]);
+        psbt.inputs[0]
+            .proprietary
+            .insert(proprietary.clone(), vec![0xdd]);
+        psbt.outputs[0]
+            .proprietary
+            .insert(proprietary.clone(), vec![0xee]);
+        let mut psbt: Psbt = deserialize(&serialize(&psbt)).unwrap();
+
+        let mut signed = psbt.clone();
+        master.sign_psbt(&mut signed, &mut unlocker).unwrap();
+        combine(&mut psbt, signed).unwrap();
+        assert_eq!(finalize(&mut psbt), 3);
+        assert_eq!(psbt.global.unknown.len(), 1);
+        assert_eq!(psbt.global.proprietary[&proprietary], vec![0xbb]);
+        assert_eq!(psbt.inputs[0].unknown[&tap_key_sig], vec![0xcc; 64]);
+        assert_eq!(psbt.inputs[0].proprietary[&proprietary], vec![0xdd]);
+        assert_eq!(psbt.outputs[0].proprietary[&proprietary], vec![0xee]);
+
+        let mut copy = Psbt::from_unsigned_tx(psbt.global.unsigned_tx.clone()).unwrap();
+        preserve_global(&mut copy, &psbt);
+        assert_eq!(copy.global, psbt.global);
+
+        // an annex counts for the weight
+        let weight = analyze(&psbt).weight;
+        let ix = (0..3)
+            .find(|ix| psbt.inputs[*ix].final_script_witness.is_some())
+            .unwrap();
+        let witness = psbt.inputs[ix].final_script_witness.as_mut().unwrap();
+        assert!(annex(witness).is_none());
+        witness.push(vec![ANNEX_TAG, 1, 2, 3]);
+        assert_eq!(annex(witness), Some(&[ANNEX_TAG, 1, 2, 3][..]));
+        assert_eq!(annex(&[vec![ANNEX_TAG]]), None);
+        assert_eq!(analyze(&psbt).weight, weight + 5);
+        let transaction = extract(psbt).unwrap();
+        assert_eq!(transaction.get_weight() as u64, weight + 5);
+        let decoded: Transaction = deserialize(&serialize(&transaction)).unwrap();
+        assert_eq!(decoded.input[ix].witness.last().unwrap()[0], ANNEX_TAG);
+    }
+
+    #[test]
+    fn sighash_types() {
+        let (master, mut unlocker, funding, mut psbt) = spend_three();
+        assert_eq!(psbt.global.unsigned_tx.output.len(), 2);
+        set_sighash_type(&mut psbt, 0, SigHashType::AllPlusAnyoneCanPay).unwrap();
+        set_sighash_type(&mut psbt, 1, SigHashType::Single).unwrap();
+        assert!(set_sighash_type(&mut psbt, 2, SigHashType::Single).is_err());
+        assert!(set_sighash_type(&mut psbt, 3, SigHashType::All).is_err());
+        let analysis = analyze(&psbt);
+        assert_eq!(
+            analysis.inputs[0].warnings,
+            vec![SighashWarning::AnyoneCanPay]
+        );
+        assert_eq!(
+            analysis.inputs[1].warnings,
+            vec![SighashWarning::SingleOutput]
+        );
+        assert!(analysis.inputs[2].warnings.is_empty());
+
+        // a signer ignoring the request is reported
+        let mut forged = psbt.clone();
+        forged.inputs[2].sighash_type = Some(SigHashType::Single);
+        assert_eq!(
+            analyze(&forged).inputs[2].warnings,
+            vec![SighashWarning::SingleWithoutOutput]
+        );
+        assert!(master.sign_psbt(&mut forged, &mut unlocker).is_err());
+
+        assert_eq!(master.sign_psbt(&mut psbt, &mut unlocker).unwrap(), 3);
+        let hash_types = psbt
+            .inputs
+            .iter()
+            .map(|i| *i.partial_sigs.values().next().unwrap().last().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(hash_types, vec![0x81, 0x03, 0x01]);
+        assert_eq!(finalize(&mut psbt), 3);
+        extract(psbt)
+            .unwrap()
+            .verify(|point| funding.output.get(point.vout as usize).cloned())
+            .unwrap();
+    }
+}
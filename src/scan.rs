@@ -0,0 +1,282 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Streaming block scan
+//!
+//! A block matched by its filter usually holds a handful of transactions of the wallet among
+//! thousands. scan_block walks the serialized block instead of decoding it: it hashes txids
+//! over the bytes, looks up output scripts in the script index of the master account and skips
+//! witnesses unread. Only transactions that concern the wallet are decoded, they are processed
+//! into coins with proofs from the merkle tree of the txids.
+//!
+//! Transactions are relevant if they pay to a script of the wallet or a watched script, spend
+//! a coin or an output of an earlier relevant transaction of the block, confirm a pending
+//! transaction or conflict with a draft.
+//!
+use std::collections::HashSet;
+
+use bitcoin::consensus::encode;
+use bitcoin::consensus::{deserialize, Decodable};
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::{BlockHeader, OutPoint, Script, Transaction, Txid};
+
+use account::MasterAccount;
+use coins::Coins;
+use error::Error;
+use proved::{merkle_levels, merkle_path, ProvedTransaction};
+
+/// size of a serialized block header
+const HEADER_SIZE: usize = 80;
+
+/// reads a serialized block without copying
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(Error::Serialize(encode::Error::ParseFailed(
+                "block ends early",
+            )))?;
+        let taken = &self.data[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.data.get(self.position + offset).copied()
+    }
+
+    fn length(&mut self) -> Result<usize, Error> {
+        let length = match self.take(1)?[0] {
+            0xfd => u64::from(u16::consensus_decode(self.take(2)?)?),
+            0xfe => u64::from(u32::consensus_decode(self.take(4)?)?),
+            0xff => u64::consensus_decode(self.take(8)?)?,
+            n => u64::from(n),
+        };
+        if length > self.data.len() as u64 {
+            return Err(Error::Serialize(encode::Error::ParseFailed(
+                "length beyond the block",
+            )));
+        }
+        Ok(length as usize)
+    }
+
+    /// a length prefixed field
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.length()?;
+        self.take(length)
+    }
+}
+
+/// a transaction located in the serialized block
+struct Located {
+    txid: Txid,
+    start: usize,
+    end: usize,
+    inputs: Vec<OutPoint>,
+    /// scripts of the outputs
+    scripts: Vec<Script>,
+}
+
+/// read the next transaction, hashing its txid over the bytes without witnesses
+fn locate(cursor: &mut Cursor) -> Result<Located, Error> {
+    let start = cursor.position;
+    let mut engine = Txid::engine();
+    engine.input(cursor.take(4)?);
+    let segwit = cursor.peek(0) == Some(0) && cursor.peek(1) == Some(1);
+    if segwit {
+        cursor.take(2)?;
+    }
+    let body = cursor.position;
+    let mut inputs = Vec::new();
+    for _ in 0..cursor.length()? {
+        inputs.push(OutPoint::consensus_decode(cursor.take(36)?)?);
+        cursor.bytes()?;
+        cursor.take(4)?;
+    }
+    let mut scripts = Vec::new();
+    for _ in 0..cursor.length()? {
+        cursor.take(8)?;
+        scripts.push(Script::from(cursor.bytes()?.to_vec()));
+    }
+    engine.input(&cursor.data[body..cursor.position]);
+    if segwit {
+        for _ in 0..inputs.len() {
+            for _ in 0..cursor.length()? {
+                cursor.bytes()?;
+            }
+        }
+    }
+    engine.input(cursor.take(4)?);
+    Ok(Located {
+        txid: Txid::from_engine(engine),
+        start,
+        end: cursor.position,
+        inputs,
+        scripts,
+    })
+}
+
+/// process a serialized block into coins, decoding only transactions that concern the wallet
+/// The block is checked against the merkle root of its header. Returns true if coins changed.
+pub fn scan_block(
+    master_account: &mut MasterAccount,
+    coins: &mut Coins,
+    data: &[u8],
+) -> Result<bool, Error> {
+    let mut cursor = Cursor { data, position: 0 };
+    let header: BlockHeader = deserialize(cursor.take(HEADER_SIZE)?)?;
+    let count = cursor.length()?;
+    master_account.index_scripts();
+    let mut txids = Vec::with_capacity(count.min(data.len()));
+    let mut relevant = Vec::new();
+    // outputs of relevant transactions, later ones of the block may spend them
+    let mut created = HashSet::new();
+    for txnr in 0..count {
+        let located = locate(&mut cursor)?;
+        let mut concerns = coins.concerns(&located.txid, &located.inputs)
+            || located.inputs.iter().any(|i| created.contains(i));
+        for script in located.scripts.iter() {
+            if master_account.is_watched(script) {
+                concerns = true;
+            }
+            if let Some(d) = master_account.derivation(script).cloned() {
+                // later transactions of the block may pay to keys of the extended look ahead
+                master_account.look_ahead((d.account, d.sub), d.kix)?;
+                concerns = true;
+            }
+        }
+        if concerns {
+            created.extend((0..located.scripts.len()).map(|vout| OutPoint {
+                txid: located.txid,
+                vout: vout as u32,
+            }));
+            relevant.push((txnr, located.start, located.end));
+        }
+        txids.push(located.txid.as_hash());
+    }
+    if cursor.position != data.len() {
+        return Err(Error::Serialize(encode::Error::ParseFailed(
+            "data after the block",
+        )));
+    }
+    let levels = merkle_levels(txids);
+    if levels.last().and_then(|root| root.first()) != Some(&header.merkle_root.as_hash()) {
+        return Err(Error::Backend("block does not match its header"));
+    }
+    let block_hash = header.block_hash();
+    let proofs = relevant
+        .iter()
+        .map(|(txnr, start, end)| {
+            let transaction: Transaction = deserialize(&data[*start..*end])?;
+            Ok(ProvedTransaction::from_path(
+                transaction,
+                merkle_path(&levels, *txnr),
+                block_hash,
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(coins.process_proved(master_account, &block_hash, &proofs))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::{Network, TxIn, TxMerkleNode, TxOut, WPubkeyHash};
+
+    use account::{Account, AccountAddressType};
+    use fixtures::master_account;
+
+    use super::*;
+
+    #[test]
+    fn scan() {
+        let (mut master, mut unlocker) = master_account(Network::Testnet);
+        let account = master.get((0, 0)).unwrap();
+        let last = account.get_key(9).unwrap().address.script_pubkey();
+        // beyond the lookahead until last is seen
+        let public = account.compute_base_public_key(15).unwrap();
+        let beyond = Script::new_v0_wpkh(&WPubkeyHash::hash(&public.to_bytes()));
+        let transaction = |previous_output: OutPoint, script: Script| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output,
+                sequence: 0xffffffff,
+                witness: vec![vec![1u8; 72], vec![2u8; 33]],
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script,
+            }],
+        };
+        let other = |vout: u32| {
+            transaction(
+                OutPoint {
+                    txid: Txid::default(),
+                    vout,
+                },
+                Script::new(),
+            )
+        };
+        let funding = transaction(other(1).input[0].previous_output, last);
+        let later = transaction(other(2).input[0].previous_output, beyond);
+        let spend = transaction(
+            OutPoint {
+                txid: funding.txid(),
+                vout: 0,
+            },
+            Script::new(),
+        );
+        let mut block = genesis_block(Network::Testnet);
+        block.txdata = vec![other(3), funding, other(4), later, spend, other(5)];
+        block.header.merkle_root = block.merkle_root();
+
+        let mut scanned = Coins::new();
+        let mut processed = Coins::new();
+        let mut copy = MasterAccount::from_encrypted(
+            master.encrypted(),
+            *master.master_public(),
+            master.birth(),
+        );
+        copy.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
+        );
+        assert!(scan_block(&mut master, &mut scanned, &serialize(&block)).unwrap());
+        assert!(processed.process(&mut copy, &block));
+        assert_eq!(scanned.confirmed().len(), 1);
+        assert_eq!(scanned.confirmed(), processed.confirmed());
+        assert_eq!(scanned.proofs(), processed.proofs());
+        assert_eq!(
+            master.get((0, 0)).unwrap().instantiated().len(),
+            copy.get((0, 0)).unwrap().instantiated().len()
+        );
+
+        let mut forged = block.clone();
+        forged.header.merkle_root = TxMerkleNode::default();
+        assert!(scan_block(&mut master, &mut Coins::new(), &serialize(&forged)).is_err());
+        let data = serialize(&block);
+        assert!(scan_block(&mut master, &mut Coins::new(), &data[..data.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,890 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Coin selection
+//!
+//! Strategies to choose the coins a transaction spends. Implement CoinSelector to supply your own.
+//!
+use std::collections::HashMap;
+
+use bitcoin::{OutPoint, Script, VarInt};
+use rand::{prelude::SliceRandom, thread_rng};
+
+use cluster::ClusterId;
+use coins::Coin;
+use error::Error;
+use fee::FeeRate;
+
+/// maximal size of a DER signature with sighash byte
+const SIGNATURE_SIZE: u64 = 72;
+/// witness of a single signature and compressed key: item count, signature, key
+const P2WPKH_WITNESS_WEIGHT: u64 = 1 + 1 + SIGNATURE_SIZE + 1 + 33;
+/// maximum number of branches Branch and Bound visits
+const BNB_TRIES: usize = 100_000;
+/// fee rate expected in the long run, spending now is wasteful if the current rate is higher
+pub const LONG_TERM_FEERATE: u64 = 10;
+
+/// How an input is satisfied, for weight estimation before there are signatures
+/// Signatures are assumed to be of the maximal size, estimates are therefore upper bounds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum InputType {
+    P2PKH,
+    P2SHP2WPKH,
+    P2WPKH,
+    /// single key script with relative time lock of P2WSH accounts of this wallet
+    P2WSHLocked,
+    /// Taproot key path with default sighash
+    P2TRKeyPath,
+    /// required of keys bare multisig in P2SH
+    P2SHMultisig(usize, usize),
+    /// required of keys bare multisig in P2WSH
+    P2WSHMultisig(usize, usize),
+    /// required of keys bare multisig in P2WSH wrapped into P2SH
+    P2SHP2WSHMultisig(usize, usize),
+}
+
+impl InputType {
+    /// the input type for a script of this wallet
+    pub fn of(script_pubkey: &Script) -> InputType {
+        if script_pubkey.is_p2pkh() {
+            InputType::P2PKH
+        } else if script_pubkey.is_p2sh() {
+            InputType::P2SHP2WPKH
+        } else if script_pubkey.is_v0_p2wsh() {
+            InputType::P2WSHLocked
+        } else if script_pubkey.len() == 34 && script_pubkey[0] == 0x51 && script_pubkey[1] == 0x20
+        {
+            InputType::P2TRKeyPath
+        } else {
+            InputType::P2WPKH
+        }
+    }
+
+    /// true if the input has a witness
+    pub fn is_segwit(&self) -> bool {
+        !matches!(self, InputType::P2PKH | InputType::P2SHMultisig(..))
+    }
+
+    /// weight of the input including its witness
+    pub fn weight(&self) -> u64 {
+        // bare multisig script: OP_m, keys, OP_n, OP_CHECKMULTISIG
+        let multisig = |keys: usize| 3 + 34 * keys as u64;
+        // script_sig and witness sizes
+        let (script_sig, witness) = match *self {
+            InputType::P2PKH => (1 + SIGNATURE_SIZE + 1 + 33, 0),
+            InputType::P2SHP2WPKH => (23, P2WPKH_WITNESS_WEIGHT),
+            InputType::P2WPKH => (0, P2WPKH_WITNESS_WEIGHT),
+            InputType::P2WSHLocked => (0, 1 + 1 + SIGNATURE_SIZE + 1 + 40),
+            InputType::P2TRKeyPath => (0, 1 + 1 + 64),
+            InputType::P2SHMultisig(required, keys) => {
+                let script = multisig(keys);
+                let push = match script {
+                    0..=75 => 1,
+                    76..=255 => 2,
+                    _ => 3,
+                };
+                (
+                    1 + required as u64 * (1 + SIGNATURE_SIZE) + push + script,
+                    0,
+                )
+            }
+            InputType::P2WSHMultisig(required, keys)
+            | InputType::P2SHP2WSHMultisig(required, keys) => {
+                let script = multisig(keys);
+                let witness = 1
+                    + 1
+                    + required as u64 * (1 + SIGNATURE_SIZE)
+                    + VarInt(script).len() as u64
+                    + script;
+                let script_sig = if let InputType::P2SHP2WSHMultisig(..) = *self {
+                    35
+                } else {
+                    0
+                };
+                (script_sig, witness)
+            }
+        };
+        (32 + 4 + 4 + VarInt(script_sig).len() as u64 + script_sig) * 4 + witness
+    }
+}
+
+/// estimate the weight of an input spending a script of this wallet
+pub fn estimate_input_weight(script_pubkey: &Script) -> u64 {
+    InputType::of(script_pubkey).weight()
+}
+
+/// estimate the weight of a transaction before it is signed
+pub fn estimate_weight(inputs: &[InputType], outputs: &[Script]) -> u64 {
+    let segwit = inputs.iter().any(|i| i.is_segwit());
+    let mut weight =
+        (4 + 4 + VarInt(inputs.len() as u64).len() + VarInt(outputs.len() as u64).len()) as u64 * 4
+            + inputs.iter().map(|i| i.weight()).sum::<u64>()
+            + outputs.iter().map(output_weight).sum::<u64>();
+    if segwit {
+        // marker, flag and the empty witness of inputs without one
+        weight += 2 + inputs.iter().filter(|i| !i.is_segwit()).count() as u64;
+    }
+    weight
+}
+
+/// estimate the virtual size of a transaction before it is signed
+pub fn estimate_vsize(inputs: &[InputType], outputs: &[Script]) -> u64 {
+    estimate_weight(inputs, outputs).div_ceil(4)
+}
+
+/// weight of an output
+pub fn output_weight(script_pubkey: &Script) -> u64 {
+    (8 + VarInt(script_pubkey.len() as u64).len() + script_pubkey.len()) as u64 * 4
+}
+
+/// A coin that might be spent
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Candidate {
+    pub point: OutPoint,
+    pub coin: Coin,
+    /// confirmation height, 0 for unconfirmed coins
+    pub height: u32,
+    /// weight of the input spending this coin
+    pub weight: u64,
+    /// address linkage cluster of the coin
+    pub cluster: Option<ClusterId>,
+}
+
+impl Candidate {
+    /// a candidate with estimated input weight
+    pub fn new(point: OutPoint, coin: Coin, height: u32) -> Candidate {
+        let weight = estimate_input_weight(&coin.output.script_pubkey);
+        Candidate {
+            point,
+            coin,
+            height,
+            weight,
+            cluster: None,
+        }
+    }
+
+    /// estimate the input weight for how the coin is spent, e.g. a multisig coin
+    pub fn with_input_type(mut self, input_type: InputType) -> Candidate {
+        self.weight = input_type.weight();
+        self
+    }
+
+    pub fn with_cluster(mut self, cluster: Option<ClusterId>) -> Candidate {
+        self.cluster = cluster;
+        self
+    }
+
+    pub fn value(&self) -> u64 {
+        self.coin.output.value
+    }
+
+    /// value less the fee of spending it
+    pub fn effective_value(&self, feerate: FeeRate) -> i64 {
+        self.value() as i64 - feerate.fee(self.weight) as i64
+    }
+}
+
+/// Diagnostics of a selection to compare strategies
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metrics {
+    /// fee rate of the selection
+    pub feerate: FeeRate,
+    /// fee rate waste is measured against
+    pub long_term_feerate: FeeRate,
+    /// effective values of the selected coins at the fee rate, in order of selection
+    pub effective_values: Vec<i64>,
+    /// effective value of the selection above the target
+    pub excess: u64,
+    /// fee of the change output and of spending it later at the long term fee rate
+    pub change_cost: u64,
+    /// fee paid for inputs now above what they would cost at the long term fee rate, plus
+    /// change cost if there is change or excess otherwise. Lower is better.
+    pub waste: i64,
+}
+
+impl Metrics {
+    pub fn new(
+        selected: &[Candidate],
+        has_change: bool,
+        excess: u64,
+        feerate: FeeRate,
+        long_term_feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Metrics {
+        let change_cost = feerate.fee(output_weight(drain_script))
+            + long_term_feerate.fee(estimate_input_weight(drain_script));
+        let timing = selected
+            .iter()
+            .map(|c| feerate.fee(c.weight) as i64 - long_term_feerate.fee(c.weight) as i64)
+            .sum::<i64>();
+        Metrics {
+            feerate,
+            long_term_feerate,
+            effective_values: selected
+                .iter()
+                .map(|c| c.effective_value(feerate))
+                .collect(),
+            excess,
+            change_cost,
+            waste: timing
+                + if has_change {
+                    change_cost as i64
+                } else {
+                    excess as i64
+                },
+        }
+    }
+}
+
+/// Result of coin selection
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Selection {
+    /// coins to spend
+    pub selected: Vec<Candidate>,
+    /// value of the output to the drain script, None if the excess is left to miners
+    pub change: Option<u64>,
+    /// fee for the selected inputs, the change output and the excess left to miners
+    pub fee: u64,
+    /// diagnostics at LONG_TERM_FEERATE
+    pub metrics: Metrics,
+}
+
+impl Selection {
+    /// complete a selection with change if that is worth it
+    pub fn new(
+        selected: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error> {
+        Self::finish(selected, target, feerate, drain_script, true)
+    }
+
+    /// complete a selection leaving the excess to miners
+    pub fn changeless(
+        selected: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error> {
+        Self::finish(selected, target, feerate, drain_script, false)
+    }
+
+    fn finish(
+        selected: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+        allow_change: bool,
+    ) -> Result<Selection, Error> {
+        let effective = selected
+            .iter()
+            .map(|c| c.effective_value(feerate))
+            .sum::<i64>();
+        if effective < target as i64 {
+            return Err(Error::CoinSelection("insufficient funds"));
+        }
+        let excess = effective as u64 - target;
+        let input_fee = selected.iter().map(|c| feerate.fee(c.weight)).sum::<u64>();
+        let change_fee = feerate.fee(output_weight(drain_script));
+        let has_change = allow_change && excess >= change_fee + drain_script.dust_value();
+        let metrics = Metrics::new(
+            selected.as_slice(),
+            has_change,
+            excess,
+            feerate,
+            FeeRate::from_sat_per_vb(LONG_TERM_FEERATE),
+            drain_script,
+        );
+        if has_change {
+            Ok(Selection {
+                selected,
+                change: Some(excess - change_fee),
+                fee: input_fee + change_fee,
+                metrics,
+            })
+        } else {
+            Ok(Selection {
+                selected,
+                change: None,
+                fee: input_fee + excess,
+                metrics,
+            })
+        }
+    }
+
+    /// add coins that were chosen before the selector topped them up
+    pub fn prepend(mut self, required: Vec<Candidate>, feerate: FeeRate) -> Selection {
+        self.fee += required.iter().map(|c| feerate.fee(c.weight)).sum::<u64>();
+        let long_term = self.metrics.long_term_feerate;
+        self.metrics.waste += required
+            .iter()
+            .map(|c| feerate.fee(c.weight) as i64 - long_term.fee(c.weight) as i64)
+            .sum::<i64>();
+        let mut values = required
+            .iter()
+            .map(|c| c.effective_value(feerate))
+            .collect::<Vec<_>>();
+        values.append(&mut self.metrics.effective_values);
+        self.metrics.effective_values = values;
+        let mut selected = required;
+        selected.append(&mut self.selected);
+        self.selected = selected;
+        self
+    }
+
+    /// sum of selected coins
+    pub fn value(&self) -> u64 {
+        self.selected.iter().map(|c| c.value()).sum::<u64>()
+    }
+}
+
+/// A coin selection strategy
+pub trait CoinSelector {
+    /// choose candidates whose effective values reach the target
+    /// target is the value of outputs plus the fee of the transaction without inputs and change
+    fn select(
+        &self,
+        candidates: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error>;
+}
+
+/// take candidates in order until the target is reached, skip those not worth spending
+fn accumulate<I: Iterator<Item = Candidate>>(
+    candidates: I,
+    target: u64,
+    feerate: FeeRate,
+    drain_script: &Script,
+) -> Result<Selection, Error> {
+    let mut selected = Vec::new();
+    let mut effective = 0i64;
+    for candidate in candidates {
+        if effective >= target as i64 {
+            break;
+        }
+        let value = candidate.effective_value(feerate);
+        if value > 0 {
+            effective += value;
+            selected.push(candidate);
+        }
+    }
+    Selection::new(selected, target, feerate, drain_script)
+}
+
+/// spend the largest coins first, this minimizes the number of inputs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(
+        &self,
+        mut candidates: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error> {
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.value()));
+        accumulate(candidates.into_iter(), target, feerate, drain_script)
+    }
+}
+
+/// spend the oldest coins first, unconfirmed coins last
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OldestFirst;
+
+impl CoinSelector for OldestFirst {
+    fn select(
+        &self,
+        mut candidates: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error> {
+        candidates.sort_by_key(|c| if c.height == 0 { u32::MAX } else { c.height });
+        accumulate(candidates.into_iter(), target, feerate, drain_script)
+    }
+}
+
+/// spend coins in random order
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SingleRandomDraw;
+
+impl CoinSelector for SingleRandomDraw {
+    fn select(
+        &self,
+        mut candidates: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error> {
+        candidates.shuffle(&mut thread_rng());
+        accumulate(candidates.into_iter(), target, feerate, drain_script)
+    }
+}
+
+/// Branch and Bound search for a selection that needs no change
+/// an exact match within the cost of creating and later spending change, falls back to an other
+/// strategy if there is none
+#[derive(Clone, Copy, Debug)]
+pub struct BranchAndBound<F = SingleRandomDraw> {
+    fallback: F,
+}
+
+impl Default for BranchAndBound<SingleRandomDraw> {
+    fn default() -> Self {
+        BranchAndBound::new(SingleRandomDraw)
+    }
+}
+
+impl<F: CoinSelector> BranchAndBound<F> {
+    pub fn new(fallback: F) -> BranchAndBound<F> {
+        BranchAndBound { fallback }
+    }
+
+    /// depth first search of the inclusion tree of candidates sorted by descending value,
+    /// keeps the selection with least excess
+    #[allow(clippy::too_many_arguments)]
+    fn search(
+        pool: &[i64],
+        index: usize,
+        value: i64,
+        remaining: i64,
+        target: i64,
+        upper: i64,
+        tries: &mut usize,
+        current: &mut Vec<usize>,
+        best: &mut Option<(i64, Vec<usize>)>,
+    ) {
+        if *tries == 0 || value > upper {
+            return;
+        }
+        *tries -= 1;
+        if value >= target {
+            if best
+                .as_ref()
+                .map(|(e, _)| value - target < *e)
+                .unwrap_or(true)
+            {
+                *best = Some((value - target, current.clone()));
+                if value == target {
+                    *tries = 0;
+                }
+            }
+            return;
+        }
+        if index == pool.len() || value + remaining < target {
+            return;
+        }
+        current.push(index);
+        Self::search(
+            pool,
+            index + 1,
+            value + pool[index],
+            remaining - pool[index],
+            target,
+            upper,
+            tries,
+            current,
+            best,
+        );
+        current.pop();
+        Self::search(
+            pool,
+            index + 1,
+            value,
+            remaining - pool[index],
+            target,
+            upper,
+            tries,
+            current,
+            best,
+        );
+    }
+}
+
+impl<F: CoinSelector> CoinSelector for BranchAndBound<F> {
+    fn select(
+        &self,
+        candidates: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error> {
+        let mut pool = candidates
+            .iter()
+            .filter(|c| c.effective_value(feerate) > 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        pool.sort_by_key(|c| std::cmp::Reverse(c.effective_value(feerate)));
+        let values = pool
+            .iter()
+            .map(|c| c.effective_value(feerate))
+            .collect::<Vec<_>>();
+        let cost_of_change = feerate.fee(output_weight(drain_script))
+            + feerate.fee(estimate_input_weight(drain_script));
+        let mut best = None;
+        let mut tries = BNB_TRIES;
+        Self::search(
+            values.as_slice(),
+            0,
+            0,
+            values.iter().sum::<i64>(),
+            target as i64,
+            (target + cost_of_change) as i64,
+            &mut tries,
+            &mut Vec::new(),
+            &mut best,
+        );
+        match best {
+            Some((_, indices)) => Selection::changeless(
+                indices.iter().map(|i| pool[*i].clone()).collect(),
+                target,
+                feerate,
+                drain_script,
+            ),
+            None => self
+                .fallback
+                .select(candidates, target, feerate, drain_script),
+        }
+    }
+}
+
+/// Prefer spending coins of a single address cluster so the transaction does not link coins
+/// an observer could not yet link. This trades fee efficiency for privacy.
+/// Coins without known cluster are treated as a cluster of their own.
+#[derive(Clone, Copy, Debug)]
+pub struct ClusterPrivacy<S = LargestFirst> {
+    inner: S,
+    allow_merge: bool,
+}
+
+impl Default for ClusterPrivacy<LargestFirst> {
+    fn default() -> Self {
+        ClusterPrivacy::new(LargestFirst)
+    }
+}
+
+impl<S: CoinSelector> ClusterPrivacy<S> {
+    /// inner selects within a cluster
+    pub fn new(inner: S) -> ClusterPrivacy<S> {
+        ClusterPrivacy {
+            inner,
+            allow_merge: true,
+        }
+    }
+
+    /// fail rather than merging clusters if no single one reaches the target
+    pub fn never_merge(mut self) -> ClusterPrivacy<S> {
+        self.allow_merge = false;
+        self
+    }
+}
+
+impl<S: CoinSelector> CoinSelector for ClusterPrivacy<S> {
+    fn select(
+        &self,
+        candidates: Vec<Candidate>,
+        target: u64,
+        feerate: FeeRate,
+        drain_script: &Script,
+    ) -> Result<Selection, Error> {
+        let mut clusters = HashMap::new();
+        let mut groups = Vec::new();
+        for candidate in candidates {
+            match candidate.cluster {
+                Some(id) => clusters.entry(id).or_insert_with(Vec::new).push(candidate),
+                None => groups.push(vec![candidate]),
+            }
+        }
+        groups.extend(clusters.into_values());
+        let value = |group: &Vec<Candidate>| {
+            group
+                .iter()
+                .map(|c| c.effective_value(feerate))
+                .filter(|v| *v > 0)
+                .sum::<i64>()
+        };
+        let best = groups
+            .iter()
+            .filter(|g| value(g) >= target as i64)
+            .filter_map(|g| {
+                self.inner
+                    .select(g.clone(), target, feerate, drain_script)
+                    .ok()
+            })
+            .min_by_key(|s| s.fee);
+        if let Some(selection) = best {
+            return Ok(selection);
+        }
+        if !self.allow_merge {
+            return Err(Error::CoinSelection("no single cluster reaches the target"));
+        }
+        // merge as few clusters as possible
+        groups.sort_by_key(|g| std::cmp::Reverse(value(g)));
+        let mut merged = Vec::new();
+        let mut sum = 0i64;
+        for group in groups {
+            if sum >= target as i64 {
+                break;
+            }
+            sum += value(&group);
+            merged.extend(group);
+        }
+        self.inner.select(merged, target, feerate, drain_script)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+    use std::str::FromStr;
+
+    use account::KeyDerivation;
+    use coins::Coin;
+    use fee::FeeRate;
+
+    use super::*;
+
+    fn candidates(values: &[u64]) -> Vec<Candidate> {
+        let script = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+            .unwrap()
+            .script_pubkey();
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                Candidate::new(
+                    OutPoint {
+                        txid: Txid::default(),
+                        vout: i as u32,
+                    },
+                    Coin {
+                        output: TxOut {
+                            value: *v,
+                            script_pubkey: script.clone(),
+                        },
+                        derivation: KeyDerivation {
+                            account: 0,
+                            sub: 0,
+                            kix: i as u32,
+                            tweak: None,
+                            csv: None,
+                        },
+                    },
+                    10 - i as u32,
+                )
+            })
+            .collect()
+    }
+
+    fn drain() -> Script {
+        Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+            .unwrap()
+            .script_pubkey()
+    }
+
+    #[test]
+    fn strategies() {
+        let feerate = FeeRate::from_sat_per_vb(1);
+        let coins = candidates(&[1000, 5000, 20000, 100]);
+        let selection = LargestFirst
+            .select(coins.clone(), 15000, feerate, &drain())
+            .unwrap();
+        assert_eq!(selection.selected.len(), 1);
+        assert_eq!(selection.selected[0].value(), 20000);
+        assert_eq!(selection.change, Some(20000 - 15000 - 68 - 31));
+        let selection = OldestFirst
+            .select(coins.clone(), 15000, feerate, &drain())
+            .unwrap();
+        assert_eq!(selection.selected.len(), 2);
+        assert_eq!(selection.selected[0].height, 7);
+        let selection = SingleRandomDraw
+            .select(coins.clone(), 25000, feerate, &drain())
+            .unwrap();
+        assert!(selection.value() >= 26000);
+        assert!(SingleRandomDraw
+            .select(coins.clone(), 30000, feerate, &drain())
+            .is_err());
+
+        // 5000 + 1000 less fees of two inputs is an exact match
+        let selection = BranchAndBound::default()
+            .select(coins.clone(), 6000 - 2 * 68, feerate, &drain())
+            .unwrap();
+        assert_eq!(selection.change, None);
+        assert_eq!(selection.value(), 6000);
+        assert_eq!(selection.metrics.excess, 0);
+        assert_eq!(
+            selection.metrics.effective_values,
+            vec![5000 - 68, 1000 - 68]
+        );
+        // spending at 1 sat/vB saves against the long term fee rate
+        assert_eq!(selection.metrics.waste, 2 * (68 - 680));
+        let largest = LargestFirst
+            .select(coins.clone(), 6000 - 2 * 68, feerate, &drain())
+            .unwrap();
+        assert_eq!(largest.metrics.change_cost, 31 + 680);
+        assert_eq!(largest.metrics.waste, 68 - 680 + 31 + 680);
+        assert!(largest.metrics.waste > selection.metrics.waste);
+        let selection = BranchAndBound::new(LargestFirst)
+            .select(coins, 3000, feerate, &drain())
+            .unwrap();
+        assert_eq!(selection.value(), 20000);
+    }
+
+    #[test]
+    fn cluster_privacy() {
+        let feerate = FeeRate::from_sat_per_vb(1);
+        let coins = candidates(&[2000, 3000, 4000, 5000])
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| c.with_cluster(Some(i as u32 % 2)))
+            .collect::<Vec<_>>();
+        // largest first would link 5000 and 4000, the cluster of 5000 and 3000 is sufficient
+        let selection = ClusterPrivacy::default()
+            .select(coins.clone(), 7000, feerate, &drain())
+            .unwrap();
+        assert_eq!(selection.value(), 8000);
+        assert!(ClusterPrivacy::default()
+            .never_merge()
+            .select(coins.clone(), 12000, feerate, &drain())
+            .is_err());
+        let selection = ClusterPrivacy::default()
+            .select(coins, 12000, feerate, &drain())
+            .unwrap();
+        assert_eq!(selection.value(), 14000);
+    }
+
+    /// an input satisfied with signatures of the maximal size
+    fn satisfied(input_type: InputType) -> TxIn {
+        use bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHBYTES_0};
+        use bitcoin::blockdata::script::Builder;
+
+        let signature = vec![0u8; 72];
+        let key = vec![2u8; 33];
+        let multisig = |required: usize, keys: usize| {
+            let mut builder = Builder::new().push_int(required as i64);
+            for _ in 0..keys {
+                builder = builder.push_slice(key.as_slice());
+            }
+            builder
+                .push_int(keys as i64)
+                .push_opcode(OP_CHECKMULTISIG)
+                .into_script()
+        };
+        let multisig_witness = |required: usize, keys: usize| {
+            let mut witness = vec![Vec::new()];
+            witness.extend(vec![signature.clone(); required]);
+            witness.push(multisig(required, keys).to_bytes());
+            witness
+        };
+        let (script_sig, witness) = match input_type {
+            InputType::P2PKH => (
+                Builder::new()
+                    .push_slice(signature.as_slice())
+                    .push_slice(key.as_slice())
+                    .into_script(),
+                Vec::new(),
+            ),
+            InputType::P2SHP2WPKH => (
+                Builder::new().push_slice(&[0u8; 22]).into_script(),
+                vec![signature.clone(), key.clone()],
+            ),
+            InputType::P2WPKH => (Script::new(), vec![signature.clone(), key.clone()]),
+            InputType::P2WSHLocked => (Script::new(), vec![signature.clone(), vec![0u8; 40]]),
+            InputType::P2TRKeyPath => (Script::new(), vec![vec![0u8; 64]]),
+            InputType::P2SHMultisig(required, keys) => {
+                let mut builder = Builder::new().push_opcode(OP_PUSHBYTES_0);
+                for _ in 0..required {
+                    builder = builder.push_slice(signature.as_slice());
+                }
+                (
+                    builder
+                        .push_slice(multisig(required, keys).as_bytes())
+                        .into_script(),
+                    Vec::new(),
+                )
+            }
+            InputType::P2WSHMultisig(required, keys) => {
+                (Script::new(), multisig_witness(required, keys))
+            }
+            InputType::P2SHP2WSHMultisig(required, keys) => (
+                Builder::new().push_slice(&[0u8; 34]).into_script(),
+                multisig_witness(required, keys),
+            ),
+        };
+        TxIn {
+            previous_output: OutPoint::default(),
+            script_sig,
+            sequence: 0xffff_ffff,
+            witness,
+        }
+    }
+
+    #[test]
+    fn weight_models() {
+        let outputs = vec![
+            Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+                .unwrap()
+                .script_pubkey(),
+            Address::from_str("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn")
+                .unwrap()
+                .script_pubkey(),
+        ];
+        let cases = vec![
+            vec![InputType::P2PKH],
+            vec![InputType::P2PKH, InputType::P2SHMultisig(3, 5)],
+            vec![InputType::P2WPKH, InputType::P2TRKeyPath],
+            vec![
+                InputType::P2PKH,
+                InputType::P2SHP2WPKH,
+                InputType::P2WPKH,
+                InputType::P2WSHLocked,
+                InputType::P2TRKeyPath,
+                InputType::P2SHMultisig(2, 3),
+                InputType::P2WSHMultisig(2, 3),
+                InputType::P2SHP2WSHMultisig(11, 15),
+            ],
+        ];
+        for inputs in cases {
+            let transaction = Transaction {
+                version: 2,
+                lock_time: 0,
+                input: inputs.iter().map(|i| satisfied(*i)).collect(),
+                output: outputs
+                    .iter()
+                    .map(|s| TxOut {
+                        value: 1000,
+                        script_pubkey: s.clone(),
+                    })
+                    .collect(),
+            };
+            assert_eq!(
+                estimate_weight(inputs.as_slice(), outputs.as_slice()),
+                transaction.get_weight() as u64
+            );
+            assert_eq!(
+                estimate_vsize(inputs.as_slice(), outputs.as_slice()),
+                (transaction.get_weight() as u64).div_ceil(4)
+            );
+        }
+        let taproot = Script::from([&[0x51u8, 0x20][..], &[0u8; 32][..]].concat());
+        assert_eq!(InputType::of(&taproot), InputType::P2TRKeyPath);
+        assert_eq!(InputType::of(&outputs[1]), InputType::P2PKH);
+    }
+}
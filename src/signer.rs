@@ -0,0 +1,268 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # External signers
+//!
+//! Keys may live outside of this library, in a hardware device, an HSM or a remote service.
+//! The wallet computes the sighashes a PSBT needs signed, a SignerProvider returns signatures
+//! that are verified before they are added to the PSBT.
+//!
+use bitcoin::secp256k1::Signature;
+use bitcoin::util::bip143;
+use bitcoin::util::bip32::{ChildNumber, KeySource};
+use bitcoin::{PublicKey, Script, SigHash, SigHashType};
+
+use account::{MasterAccount, Unlocker};
+use context::SecpContext;
+use error::Error;
+use psbt::{spent_output, Psbt};
+
+/// A sighash to sign with a key
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SignRequest {
+    /// index of the input of the PSBT
+    pub input: usize,
+    pub public: PublicKey,
+    /// BIP32 origin of the key
+    pub source: KeySource,
+    pub sighash: SigHash,
+    pub hash_type: SigHashType,
+}
+
+/// A holder of keys
+pub trait SignerProvider {
+    /// sign the sighash of the request, None if the key is not known to the signer
+    fn sign(&mut self, request: &SignRequest) -> Result<Option<Signature>, Error>;
+}
+
+/// sighash of input ix of a PSBT for a signature with the key
+/// None if the spent output or the redeem or witness script is missing
+pub fn sighash(
+    psbt: &Psbt,
+    ix: usize,
+    public: &PublicKey,
+    hash_type: SigHashType,
+) -> Option<SigHash> {
+    let transaction = &psbt.global.unsigned_tx;
+    let input = psbt.inputs.get(ix)?;
+    let spent = spent_output(psbt, ix)?;
+    let wrapped_p2wpkh = input
+        .redeem_script
+        .as_ref()
+        .is_some_and(|s| s.is_v0_p2wpkh());
+    let mut bip143hasher = bip143::SigHashCache::new(transaction);
+    if let Some(ref witness_script) = input.witness_script {
+        Some(bip143hasher.signature_hash(ix, witness_script, spent.value, hash_type))
+    } else if spent.script_pubkey.is_v0_p2wpkh() || wrapped_p2wpkh {
+        let script_code = Script::new_p2pkh(&public.pubkey_hash());
+        Some(bip143hasher.signature_hash(ix, &script_code, spent.value, hash_type))
+    } else if let Some(ref redeem_script) = input.redeem_script {
+        Some(transaction.signature_hash(ix, redeem_script, hash_type.as_u32()))
+    } else if spent.script_pubkey.is_p2pkh() {
+        Some(transaction.signature_hash(ix, &spent.script_pubkey, hash_type.as_u32()))
+    } else {
+        None
+    }
+}
+
+/// sighashes to sign for the BIP32 keys of inputs that are not final and not yet signed with
+/// the key. Inputs must have the spent output and the redeem or witness script.
+pub fn sign_requests(psbt: &Psbt) -> Result<Vec<SignRequest>, Error> {
+    let mut requests = Vec::new();
+    for (ix, input) in psbt.inputs.iter().enumerate() {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        let hash_type = input.sighash_type.unwrap_or(SigHashType::All);
+        if hash_type.as_u32() & 0x1f == SigHashType::Single.as_u32()
+            && ix >= psbt.global.unsigned_tx.output.len()
+        {
+            return Err(Error::Signer("SIGHASH_SINGLE without matching output"));
+        }
+        for (public, source) in input.bip32_derivation.iter() {
+            if input.partial_sigs.contains_key(public) {
+                continue;
+            }
+            if let Some(sighash) = sighash(psbt, ix, public, hash_type) {
+                requests.push(SignRequest {
+                    input: ix,
+                    public: *public,
+                    source: source.clone(),
+                    sighash,
+                    hash_type,
+                });
+            }
+        }
+    }
+    Ok(requests)
+}
+
+/// sign a PSBT with a provider, returns the number of signatures added
+/// A signature that does not verify is an error and nothing is added.
+pub fn sign<P: SignerProvider>(psbt: &mut Psbt, provider: &mut P) -> Result<usize, Error> {
+    let requests = sign_requests(psbt)?;
+    let context = SecpContext::new();
+    let mut signatures = Vec::new();
+    for request in requests {
+        if let Some(signature) = provider.sign(&request)? {
+            context
+                .verify(&request.sighash[..], &signature, &request.public)
+                .map_err(|_| Error::Signer("invalid signature"))?;
+            signatures.push((request, signature));
+        }
+    }
+    let signed = signatures.len();
+    for (request, signature) in signatures {
+        let mut signature = signature.serialize_der().to_vec();
+        signature.push(request.hash_type.as_u32() as u8);
+        psbt.inputs[request.input]
+            .partial_sigs
+            .insert(request.public, signature);
+    }
+    Ok(signed)
+}
+
+/// A provider of the keys of a master account
+pub struct MasterSigner<'a> {
+    master: &'a MasterAccount,
+    unlocker: &'a mut Unlocker,
+}
+
+impl<'a> MasterSigner<'a> {
+    pub fn new(master: &'a MasterAccount, unlocker: &'a mut Unlocker) -> MasterSigner<'a> {
+        MasterSigner { master, unlocker }
+    }
+}
+
+impl<'a> SignerProvider for MasterSigner<'a> {
+    fn sign(&mut self, request: &SignRequest) -> Result<Option<Signature>, Error> {
+        let (fingerprint, ref path) = request.source;
+        if fingerprint != self.master.master_public().fingerprint() {
+            return Ok(None);
+        }
+        // m / purpose' / coin_type' / account' / sub / kix
+        let numbers = path
+            .as_ref()
+            .iter()
+            .map(|c| match *c {
+                ChildNumber::Normal { index } | ChildNumber::Hardened { index } => index,
+            })
+            .collect::<Vec<_>>();
+        if numbers.len() != 5 {
+            return Ok(None);
+        }
+        let account = match self.master.get((numbers[2], numbers[3])) {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+        let key = match account.get_key(numbers[4]) {
+            Some(key) if key.public == request.public => key,
+            _ => return Ok(None),
+        };
+        let private = self.unlocker.unlock(
+            account.address_type(),
+            numbers[2],
+            numbers[3],
+            numbers[4],
+            key.tweak.clone(),
+        )?;
+        Ok(Some(
+            self.unlocker
+                .context()
+                .sign(&request.sighash[..], &private)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Address, Network, Transaction, TxOut};
+    use std::str::FromStr;
+
+    use account::{Account, AccountAddressType};
+    use coins::Coins;
+    use fixtures::{block, funding, master_account, next_script};
+    use psbt::{extract, finalize};
+
+    use super::*;
+
+    /// signs some other digest
+    struct Faulty<'a>(MasterSigner<'a>);
+
+    impl<'a> SignerProvider for Faulty<'a> {
+        fn sign(&mut self, request: &SignRequest) -> Result<Option<Signature>, Error> {
+            let mut request = request.clone();
+            request.sighash = SigHash::default();
+            self.0.sign(&request)
+        }
+    }
+
+    /// a PSBT spending a coin of each address type, and the transaction funding them
+    fn psbt() -> (MasterAccount, Unlocker, Psbt, Transaction) {
+        let (mut master, mut unlocker) = master_account(Network::Testnet);
+        let types = [AccountAddressType::P2SHWPKH, AccountAddressType::P2PKH];
+        for (n, address_type) in types.iter().enumerate() {
+            master.add_account(
+                Account::new(&mut unlocker, *address_type, n as u32 + 1, 0, 10).unwrap(),
+            );
+        }
+        let funding = funding(
+            (0..3)
+                .map(|n| TxOut {
+                    value: 100_000,
+                    script_pubkey: next_script(&mut master, (n, 0)),
+                })
+                .collect(),
+        );
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block(Network::Testnet, vec![funding.clone()]));
+        let to =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap();
+        let change = funding.output[0].script_pubkey.clone();
+        let (psbt, _) = coins
+            .build_tx()
+            .add_recipient(&to, 250_000)
+            .build_psbt(&master, &change, 1, |_| Some(1))
+            .unwrap();
+        (master, unlocker, psbt, funding)
+    }
+
+    #[test]
+    fn external_signer() {
+        let (master, mut unlocker, mut psbt, funding) = psbt();
+        assert_eq!(sign_requests(&psbt).unwrap().len(), 3);
+        let mut signer = MasterSigner::new(&master, &mut unlocker);
+        assert_eq!(sign(&mut psbt, &mut signer).unwrap(), 3);
+        assert!(sign_requests(&psbt).unwrap().is_empty());
+        assert_eq!(finalize(&mut psbt), 3);
+        extract(psbt)
+            .unwrap()
+            .verify(|point| funding.output.get(point.vout as usize).cloned())
+            .unwrap();
+    }
+
+    #[test]
+    fn faulty_signer() {
+        let (master, mut unlocker, mut psbt, _) = psbt();
+        assert!(sign(
+            &mut psbt,
+            &mut Faulty(MasterSigner::new(&master, &mut unlocker))
+        )
+        .is_err());
+        assert!(psbt.inputs.iter().all(|i| i.partial_sigs.is_empty()));
+    }
+}
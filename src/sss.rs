@@ -38,7 +38,7 @@ const ID_LENGTH_BITS: usize = 15; // The length of the random identifier in bits
 const ITERATION_EXP_LENGTH_BITS: usize = 5; // The length of the iteration exponent in bits.
 
 const fn bits_to_words(n: usize) -> usize {
-    (n + RADIX_BITS - 1) / RADIX_BITS
+    n.div_ceil(RADIX_BITS)
 }
 
 const ID_EXP_LENGTH_WORDS: usize = bits_to_words(ID_LENGTH_BITS + ITERATION_EXP_LENGTH_BITS); // The length of the random identifier and iteration exponent in words.
@@ -76,7 +76,7 @@ impl ShamirSecretSharing {
         iteration_exponent: u8,
     ) -> Result<Vec<Share>, Error> {
         let secret = seed.0.as_slice();
-        if secret.len() * 8 < MIN_STRENGTH_BITS || secret.len() % 2 != 0 {
+        if secret.len() * 8 < MIN_STRENGTH_BITS || !secret.len().is_multiple_of(2) {
             return Err(Error::Unsupported(
                 "master key entropy must be at least 128 bits and multiple of 16 bits",
             ));
@@ -181,10 +181,11 @@ impl ShamirSecretSharing {
         )?))
     }
 
+    #[allow(clippy::type_complexity)]
     fn preprocess(
         shares: &[Share],
     ) -> Result<(u16, u8, u8, HashMap<u8, Vec<(u8, u8, Vec<u8>)>>), Error> {
-        if shares.len() < 1 {
+        if shares.is_empty() {
             return Err(Error::Unsupported(
                 "need at least one share to reconstruct secret",
             ));
@@ -232,12 +233,12 @@ impl ShamirSecretSharing {
                 share.value.clone(),
             ));
         }
-        return Ok((
-            identifiers.iter().next().unwrap().clone(),
-            iteration_exponents.iter().next().unwrap().clone(),
-            group_thresholds.iter().next().unwrap().clone(),
+        Ok((
+            *identifiers.iter().next().unwrap(),
+            *iteration_exponents.iter().next().unwrap(),
+            *group_thresholds.iter().next().unwrap(),
             groups,
-        ));
+        ))
     }
 
     fn recover_secret(threshold: u8, shares: &[(u8, Vec<u8>)]) -> Result<Vec<u8>, Error> {
@@ -314,7 +315,7 @@ impl ShamirSecretSharing {
         if x_coordinates.len() != shares.len() {
             return Err(Error::Unsupported("need unique shares for interpolation"));
         }
-        if shares.len() < 1 {
+        if shares.is_empty() {
             return Err(Error::Unsupported(
                 "need at least one share for interpolation",
             ));
@@ -350,7 +351,7 @@ impl ShamirSecretSharing {
                     - shares
                         .iter()
                         .map(|(j, _)| Self::LOG[(*j ^ *i) as usize])
-                        .fold(0i16, |a, v| a + v as i16) as i16,
+                        .fold(0i16, |a, v| a + v as i16),
             );
             result.iter_mut().zip(share.iter()).for_each(|(r, s)| {
                 *r ^= if *s != 0 {
@@ -579,7 +580,7 @@ impl Share {
         writer.write(self.member_index as u64, 4).unwrap();
         writer.write((self.member_threshold - 1) as u64, 4).unwrap();
         writer.flush().unwrap();
-        let value_word_count = (self.value.len() * 8 + RADIX_BITS - 1) / RADIX_BITS;
+        let value_word_count = (self.value.len() * 8).div_ceil(RADIX_BITS);
         let padding = value_word_count * 10 - self.value.len() * 8;
         let mut padded_value = Vec::new();
         let mut writer = BitStreamWriter::new(&mut padded_value);
@@ -674,6 +675,7 @@ impl Share {
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod test {
     use std::collections::HashSet;
     use std::str::FromStr;
@@ -781,22 +783,20 @@ mod test {
 
     #[test]
     pub fn wordlist_checks() {
-        let mut words = WORDS.clone();
+        let mut words = WORDS;
         words.sort();
         assert_eq!(&words[..], &WORDS[..]);
         assert!(!WORDS.iter().any(|w| w.len() < 4 || w.len() > 8));
         let mut first4 = HashSet::new();
-        assert!(!WORDS
-            .iter()
-            .any(|w| first4.insert(w[..4].to_string()) == false));
+        assert!(!WORDS.iter().any(|w| !first4.insert(w[..4].to_string())));
     }
 
     #[test]
     pub fn trezor_tests() {
         let json: Value = serde_json::from_str(TEST_CASES).unwrap();
         let tests = json.as_array().unwrap();
-        for t in 0..tests.len() {
-            let values = tests[t].as_array().unwrap();
+        for test in tests {
+            let values = test.as_array().unwrap();
             let title = values[0].as_str().unwrap();
             println!("{}", title);
             let result = values[2].as_str().unwrap();
@@ -805,13 +805,7 @@ mod test {
                     .as_array()
                     .unwrap()
                     .iter()
-                    .filter_map(|v| {
-                        if let Ok(s) = Share::from_mnemonic(v.as_str().unwrap()) {
-                            Some(s)
-                        } else {
-                            None
-                        }
-                    })
+                    .filter_map(|v| Share::from_mnemonic(v.as_str().unwrap()).ok())
                     .collect::<Vec<_>>();
                 if !shares.is_empty() {
                     assert!(ShamirSecretSharing::combine(&shares, Some("TREZOR")).is_err());
@@ -823,8 +817,12 @@ mod test {
                     .iter()
                     .map(|v| Share::from_mnemonic(v.as_str().unwrap()).unwrap())
                     .collect::<Vec<_>>();
-                assert_eq!(result,
-                    ShamirSecretSharing::combine(&shares, Some("TREZOR")).unwrap().0.to_hex(),
+                assert_eq!(
+                    result,
+                    ShamirSecretSharing::combine(&shares, Some("TREZOR"))
+                        .unwrap()
+                        .0
+                        .to_hex(),
                 );
             }
         }
@@ -0,0 +1,691 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Wallet file
+//!
+//! An encrypted container of the master account, its accounts and the coins of a wallet with
+//! the notes of its transactions and its vaults.
+//!
+//! ```text
+//! magic "RWWF" | version | KDF id | log_n | r | p | salt | AEAD id | key nonce |
+//!     wrapped data key | key tag | data nonce | ciphertext | tag
+//! ```
+//!
+//! The payload is encrypted and authenticated with ChaCha20-Poly1305 under a random data key.
+//! The data key is wrapped with ChaCha20-Poly1305 under a key derived from the passphrase with
+//! scrypt, the header up to the key nonce is authenticated as additional data of the wrapped key,
+//! magic, version and data nonce as additional data of the payload. A passphrase change only
+//! wraps the data key again, the data key can be rotated independently. Files of version 1 and
+//! 2 encrypt the payload directly under the derived key, they are upgraded by the migrations of
+//! the migration module when opened and sealed again as envelopes, the prior file is kept as a
+//! backup.
+//!
+//! Files are written to a temporary file, synced and renamed over the previous file, so a
+//! crash leaves either the old or the new wallet, never a partial one.
+//!
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bitcoin::consensus::{serialize, Decodable, Encodable};
+use bitcoin::{Txid, VarInt};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use rand::{thread_rng, RngCore};
+
+use account::MasterAccount;
+use coins::Coins;
+use error::Error;
+use migration;
+use vault::Vault;
+
+pub(crate) const WALLET_FILE_MAGIC: &[u8; 4] = b"RWWF";
+/// version of wallet files written by WalletFile::save
+pub const WALLET_FILE_VERSION: u8 = 4;
+/// the first version with a wrapped data key
+const ENVELOPE_VERSION: u8 = 3;
+const KDF_SCRYPT: u8 = 1;
+const AEAD_CHACHA20_POLY1305: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+/// magic, version, KDF id and parameters, salt, AEAD id and nonce
+const HEADER_LEN: usize = 4 + 1 + 1 + 1 + 4 + 4 + SALT_LEN + 1 + NONCE_LEN;
+/// header, wrapped data key with its tag and the data nonce
+const ENVELOPE_LEN: usize = HEADER_LEN + KEY_LEN + TAG_LEN + NONCE_LEN;
+
+/// Cost of the scrypt key derivation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KdfParams {
+    /// log2 of the scrypt parameter N
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    /// N = 2^15, r = 8, p = 1, about 32 MiB of memory
+    fn default() -> KdfParams {
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// parameters a file may ask for, bounding memory and time of opening it
+    fn check(&self) -> Result<(), Error> {
+        if self.log_n == 0
+            || self.log_n > 22
+            || self.r == 0
+            || self.r > 32
+            || self.p == 0
+            || self.p > 16
+        {
+            return Err(Error::Storage("unsupported key derivation parameters"));
+        }
+        Ok(())
+    }
+
+    fn derive(&self, passphrase: &str, salt: &[u8]) -> Result<[u8; 32], Error> {
+        self.check()?;
+        let mut key = [0u8; 32];
+        scrypt(
+            passphrase.as_bytes(),
+            salt,
+            &ScryptParams::new(self.log_n, self.r, self.p),
+            &mut key,
+        );
+        Ok(key)
+    }
+}
+
+/// magic, version, key derivation and encryption with a key derived from the passphrase
+fn header(
+    version: u8,
+    passphrase: &str,
+    kdf: KdfParams,
+) -> Result<(Vec<u8>, [u8; KEY_LEN]), Error> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut salt);
+    thread_rng().fill_bytes(&mut nonce);
+    let key = kdf.derive(passphrase, &salt)?;
+
+    let mut header = WALLET_FILE_MAGIC.to_vec();
+    header.push(version);
+    header.push(KDF_SCRYPT);
+    header.push(kdf.log_n);
+    header.extend_from_slice(&kdf.r.to_le_bytes());
+    header.extend_from_slice(&kdf.p.to_le_bytes());
+    header.extend_from_slice(&salt);
+    header.push(AEAD_CHACHA20_POLY1305);
+    header.extend_from_slice(&nonce);
+    Ok((header, key))
+}
+
+/// header and data key wrapped under a key derived from the passphrase
+fn wrap(
+    version: u8,
+    data_key: &[u8; KEY_LEN],
+    passphrase: &str,
+    kdf: KdfParams,
+) -> Result<Vec<u8>, Error> {
+    let (mut envelope, key) = header(version, passphrase, kdf)?;
+    let mut wrapped = [0u8; KEY_LEN];
+    let mut tag = [0u8; TAG_LEN];
+    ChaCha20Poly1305::new(&key, &envelope[HEADER_LEN - NONCE_LEN..], &envelope).encrypt(
+        data_key,
+        &mut wrapped,
+        &mut tag,
+    );
+    envelope.extend_from_slice(&wrapped);
+    envelope.extend_from_slice(&tag);
+    Ok(envelope)
+}
+
+/// key derivation parameters of a sealed file, checks its format
+fn kdf_of(data: &[u8]) -> Result<KdfParams, Error> {
+    if data.len() < HEADER_LEN + TAG_LEN || &data[..4] != WALLET_FILE_MAGIC {
+        return Err(Error::Storage("not a wallet file"));
+    }
+    if data[4] == 0 || data[4] > WALLET_FILE_VERSION {
+        return Err(Error::Storage("unknown wallet file version"));
+    }
+    if data[4] >= ENVELOPE_VERSION && data.len() < ENVELOPE_LEN + TAG_LEN {
+        return Err(Error::Storage("not a wallet file"));
+    }
+    if data[5] != KDF_SCRYPT {
+        return Err(Error::Storage("unknown key derivation"));
+    }
+    if data[15 + SALT_LEN] != AEAD_CHACHA20_POLY1305 {
+        return Err(Error::Storage("unknown encryption"));
+    }
+    let u32_at = |pos: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[pos..pos + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    Ok(KdfParams {
+        log_n: data[6],
+        r: u32_at(7),
+        p: u32_at(11),
+    })
+}
+
+/// the key derived from the passphrase, the data key of envelopes
+fn unwrap(data: &[u8], passphrase: &str) -> Result<[u8; KEY_LEN], Error> {
+    let kdf = kdf_of(data)?;
+    let key = kdf.derive(passphrase, &data[15..15 + SALT_LEN])?;
+    if data[4] < ENVELOPE_VERSION {
+        return Ok(key);
+    }
+    let mut data_key = [0u8; KEY_LEN];
+    if !ChaCha20Poly1305::new(
+        &key,
+        &data[HEADER_LEN - NONCE_LEN..HEADER_LEN],
+        &data[..HEADER_LEN],
+    )
+    .decrypt(
+        &data[HEADER_LEN..HEADER_LEN + KEY_LEN],
+        &mut data_key,
+        &data[HEADER_LEN + KEY_LEN..HEADER_LEN + KEY_LEN + TAG_LEN],
+    ) {
+        return Err(Error::Storage("wrong passphrase or corrupted wallet file"));
+    }
+    Ok(data_key)
+}
+
+/// encrypt a payload under a random data key wrapped with a key derived from the passphrase
+pub fn seal(payload: &[u8], passphrase: &str, kdf: KdfParams) -> Result<Vec<u8>, Error> {
+    let mut data_key = [0u8; KEY_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut data_key);
+    thread_rng().fill_bytes(&mut nonce);
+    let mut data = wrap(WALLET_FILE_VERSION, &data_key, passphrase, kdf)?;
+    data.extend_from_slice(&nonce);
+
+    let mut aad = data[..5].to_vec();
+    aad.extend_from_slice(&nonce);
+    let mut ciphertext = vec![0u8; payload.len()];
+    let mut tag = [0u8; TAG_LEN];
+    ChaCha20Poly1305::new(&data_key, &nonce, &aad).encrypt(payload, &mut ciphertext, &mut tag);
+    data.extend(ciphertext);
+    data.extend_from_slice(&tag);
+    Ok(data)
+}
+
+/// decrypt the payload of seal
+/// A wrong passphrase and a modified file are not told apart.
+pub fn unseal(data: &[u8], passphrase: &str) -> Result<Vec<u8>, Error> {
+    let key = unwrap(data, passphrase)?;
+    let (nonce, aad, start) = if data[4] < ENVELOPE_VERSION {
+        let nonce = &data[HEADER_LEN - NONCE_LEN..HEADER_LEN];
+        (nonce, data[..HEADER_LEN].to_vec(), HEADER_LEN)
+    } else {
+        let nonce = &data[ENVELOPE_LEN - NONCE_LEN..ENVELOPE_LEN];
+        let mut aad = data[..5].to_vec();
+        aad.extend_from_slice(nonce);
+        (nonce, aad, ENVELOPE_LEN)
+    };
+    let (ciphertext, tag) = data[start..].split_at(data.len() - start - TAG_LEN);
+    let mut payload = vec![0u8; ciphertext.len()];
+    if !ChaCha20Poly1305::new(&key, nonce, &aad).decrypt(ciphertext, &mut payload, tag) {
+        return Err(Error::Storage("wrong passphrase or corrupted wallet file"));
+    }
+    Ok(payload)
+}
+
+/// wrap the data key of sealed data with a new passphrase, the payload is not encrypted again
+/// Data sealed before envelopes must be migrated first.
+pub fn rewrap(
+    data: &[u8],
+    passphrase: &str,
+    new_passphrase: &str,
+    kdf: KdfParams,
+) -> Result<Vec<u8>, Error> {
+    kdf_of(data)?;
+    if data[4] < ENVELOPE_VERSION {
+        return Err(Error::Storage("wallet file must be migrated first"));
+    }
+    let data_key = unwrap(data, passphrase)?;
+    let mut rewrapped = wrap(data[4], &data_key, new_passphrase, kdf)?;
+    rewrapped.extend_from_slice(&data[ENVELOPE_LEN - NONCE_LEN..]);
+    Ok(rewrapped)
+}
+
+/// encrypt sealed data again under a new data key, the passphrase and its key derivation cost
+/// stay. Data sealed before envelopes must be migrated first.
+pub fn rotate_data_key(data: &[u8], passphrase: &str) -> Result<Vec<u8>, Error> {
+    let kdf = kdf_of(data)?;
+    if data[4] < ENVELOPE_VERSION {
+        return Err(Error::Storage("wallet file must be migrated first"));
+    }
+    seal(unseal(data, passphrase)?.as_slice(), passphrase, kdf)
+}
+
+/// append notes of transactions ordered by transaction id
+pub(crate) fn encode_notes(
+    notes: &HashMap<Txid, String>,
+    payload: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut notes = notes.iter().collect::<Vec<_>>();
+    notes.sort();
+    VarInt(notes.len() as u64).consensus_encode(&mut *payload)?;
+    for (txid, note) in notes {
+        txid.consensus_encode(&mut *payload)?;
+        note.consensus_encode(&mut *payload)?;
+    }
+    Ok(())
+}
+
+/// notes of transactions as written by encode_notes
+pub(crate) fn decode_notes(data: &mut &[u8]) -> Result<HashMap<Txid, String>, Error> {
+    let mut notes = HashMap::new();
+    for _ in 0..VarInt::consensus_decode(&mut *data)?.0 {
+        let txid = Txid::consensus_decode(&mut *data)?;
+        notes.insert(txid, String::consensus_decode(&mut *data)?);
+    }
+    Ok(notes)
+}
+
+/// replace the file at path with data through a synced temporary file
+pub fn write_atomic<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp, path)?;
+    // persist the rename, directories can not be synced on all platforms
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(directory) = fs::File::open(directory) {
+        directory.sync_all().ok();
+    }
+    Ok(())
+}
+
+/// Storage a wallet held in memory can be materialized to, see EphemeralWallet::persist_to
+pub trait WalletStorage {
+    /// store the master account with its accounts and the coins
+    fn store(
+        &mut self,
+        master: &MasterAccount,
+        coins: &Coins,
+        passphrase: &str,
+    ) -> Result<(), Error>;
+}
+
+/// A wallet stored in an encrypted file
+#[derive(Clone, Debug)]
+pub struct WalletFile {
+    path: PathBuf,
+    kdf: KdfParams,
+    read_only: bool,
+}
+
+impl WalletFile {
+    /// a wallet file at path, saved with the default key derivation cost
+    pub fn new<P: AsRef<Path>>(path: P) -> WalletFile {
+        WalletFile {
+            path: path.as_ref().to_path_buf(),
+            kdf: KdfParams::default(),
+            read_only: false,
+        }
+    }
+
+    /// key derivation cost of saved files, files are opened with the parameters they were
+    /// saved with
+    pub fn with_kdf(mut self, kdf: KdfParams) -> WalletFile {
+        self.kdf = kdf;
+        self
+    }
+
+    /// load wallets that reject address revelation, drafts, signing and saving with
+    /// Error::ReadOnly, e.g. for audit tools pointed at a production wallet
+    pub fn read_only(mut self) -> WalletFile {
+        self.read_only = true;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// save the master account with its accounts, the coins and the vaults
+    pub fn save(
+        &self,
+        master: &MasterAccount,
+        coins: &Coins,
+        vaults: &[&Vault],
+        passphrase: &str,
+    ) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("saving the wallet file"));
+        }
+        let mut payload = serialize(master);
+        coins.export_snapshot()?.consensus_encode(&mut payload)?;
+        encode_notes(coins.notes(), &mut payload)?;
+        VarInt(vaults.len() as u64).consensus_encode(&mut payload)?;
+        for vault in vaults {
+            vault.consensus_encode(&mut payload)?;
+        }
+        write_atomic(&self.path, seal(&payload, passphrase, self.kdf)?.as_slice())
+    }
+
+    /// wrap the data key of the file with a new passphrase, the wallet is not encrypted again
+    pub fn change_passphrase(&self, passphrase: &str, new_passphrase: &str) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("changing the passphrase"));
+        }
+        let data = rewrap(
+            self.upgrade(passphrase)?.as_slice(),
+            passphrase,
+            new_passphrase,
+            self.kdf,
+        )?;
+        write_atomic(&self.path, data.as_slice())
+    }
+
+    /// encrypt the file again under a new data key
+    pub fn rotate_data_key(&self, passphrase: &str) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly("rotating the data key"));
+        }
+        let data = rotate_data_key(self.upgrade(passphrase)?.as_slice(), passphrase)?;
+        write_atomic(&self.path, data.as_slice())
+    }
+
+    /// the file in the current version
+    /// A file of an earlier version is upgraded by the migrations of wallet files, sealed again
+    /// and replaced after a backup of the prior file, unless the wallet file is read only.
+    fn upgrade(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
+        let file = fs::read(&self.path)?;
+        let migrations = migration::wallet_file();
+        if !migrations
+            .needs_migration(file.as_slice())
+            .map_err(|_| Error::Storage("unknown wallet file version"))?
+        {
+            return Ok(file);
+        }
+        let mut payload = file[..5].to_vec();
+        payload.extend(unseal(file.as_slice(), passphrase)?);
+        let migrated = migrations.migrate(payload.as_slice())?;
+        let upgraded = seal(&migrated[5..], passphrase, kdf_of(file.as_slice())?)?;
+        if !self.read_only {
+            migrations.backup(&self.path, file.as_slice())?;
+            write_atomic(&self.path, upgraded.as_slice())?;
+        }
+        Ok(upgraded)
+    }
+
+    /// load the master account, the coins and the vaults, a file of an earlier version is
+    /// upgraded
+    pub fn load(&self, passphrase: &str) -> Result<(MasterAccount, Coins, Vec<Vault>), Error> {
+        let payload = unseal(self.upgrade(passphrase)?.as_slice(), passphrase)?;
+        let mut data = payload.as_slice();
+        let mut master = MasterAccount::consensus_decode(&mut data)?;
+        let mut coins = Coins::import_snapshot(Vec::<u8>::consensus_decode(&mut data)?.as_slice())?;
+        for (txid, note) in decode_notes(&mut data)? {
+            coins.set_note(&txid, &note)?;
+        }
+        let mut vaults = Vec::new();
+        for _ in 0..VarInt::consensus_decode(&mut data)?.0 {
+            vaults.push(Vault::consensus_decode(&mut data)?);
+        }
+        if !data.is_empty() {
+            return Err(Error::Storage("trailing data in wallet file"));
+        }
+        coins.follow_archived(&master);
+        master.set_read_only(self.read_only);
+        coins.set_read_only(self.read_only);
+        Ok((master, coins, vaults))
+    }
+}
+
+/// wallets held in memory have no vaults
+impl WalletStorage for WalletFile {
+    fn store(
+        &mut self,
+        master: &MasterAccount,
+        coins: &Coins,
+        passphrase: &str,
+    ) -> Result<(), Error> {
+        self.save(master, coins, &[], passphrase)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, OutPoint, Script, SigHashType, Transaction};
+
+    use account::{Account, AccountAddressType, Unlocker};
+    use fixtures::{funded, master_account, PASSPHRASE};
+    use psbt::Psbt;
+
+    use super::*;
+
+    const CHEAP: KdfParams = KdfParams {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    };
+    const NOTE: &str = "invoice #1234 from Acme";
+
+    /// a named account, a time locked account, a watched script and a noted coin
+    fn wallet() -> (MasterAccount, Coins, Transaction) {
+        let (mut master, mut unlocker) = master_account(Network::Regtest);
+        let mut timelocked =
+            Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 1, 0, 0).unwrap();
+        timelocked
+            .add_script_key(
+                |_, _| Script::new_op_return(&[1]),
+                Some(&[7u8; 32]),
+                Some(10),
+            )
+            .unwrap();
+        master.add_account(timelocked);
+        master.get_mut((0, 0)).unwrap().set_name("savings");
+        master
+            .add_watched_script(Script::new_op_return(&[2]))
+            .unwrap();
+        let (mut coins, funding) = funded(&mut master, Network::Regtest, &[100_000]);
+        coins.set_note(&funding.txid(), NOTE).unwrap();
+        (master, coins, funding)
+    }
+
+    /// a file of the test not yet written
+    fn path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wallet-{}-{}.dat", test, std::process::id()))
+    }
+
+    #[test]
+    fn save_load() {
+        let (master, coins, funding) = wallet();
+        let txid = funding.txid();
+        let path = path("save_load");
+        let file = WalletFile::new(&path).with_kdf(CHEAP);
+        assert!(!file.exists());
+        file.save(&master, &coins, &[], PASSPHRASE).unwrap();
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        assert!(!PathBuf::from(temp).exists());
+
+        let (loaded, loaded_coins, _) = file.load(PASSPHRASE).unwrap();
+        assert_eq!(serialize(&loaded), serialize(&master));
+        assert!(loaded_coins == coins);
+        assert_eq!(loaded.master_public().network, Network::Regtest);
+        assert_eq!(loaded.get((0, 0)).unwrap().metadata().name, "savings");
+        assert_eq!(
+            loaded.get((1, 0)).unwrap().get_key(0).unwrap().csv,
+            Some(10)
+        );
+        assert!(loaded.is_watched(&Script::new_op_return(&[2])));
+        assert_eq!(loaded_coins.note(&txid), Some(&NOTE.to_string()));
+        let plain = fs::read(&path).unwrap();
+        assert!(!plain.windows(4).any(|w| w == b"Acme"));
+        // the seed is usable
+        assert!(Unlocker::new_for_master(&loaded, PASSPHRASE).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tampered() {
+        let (master, coins, _) = wallet();
+        let path = path("tampered");
+        let file = WalletFile::new(&path).with_kdf(CHEAP);
+        file.save(&master, &coins, &[], PASSPHRASE).unwrap();
+        assert!(file.load("wrong").is_err());
+        let data = fs::read(&path).unwrap();
+        for pos in [4, 6, 20, HEADER_LEN, data.len() - 1].iter() {
+            let mut modified = data.clone();
+            modified[*pos] ^= 1;
+            assert!(unseal(&modified, PASSPHRASE).is_err());
+        }
+        assert!(unseal(&data[..data.len() - 1], PASSPHRASE).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only() {
+        let (master, coins, funding) = wallet();
+        let txid = funding.txid();
+        let path = path("read_only");
+        WalletFile::new(&path)
+            .with_kdf(CHEAP)
+            .save(&master, &coins, &[], PASSPHRASE)
+            .unwrap();
+        let audit = WalletFile::new(&path).read_only();
+        let (mut loaded, mut loaded_coins, _) = audit.load(PASSPHRASE).unwrap();
+        match loaded.get_mut((0, 0)).unwrap().next_key() {
+            Err(Error::ReadOnly(_)) => {}
+            _ => panic!("address revealed"),
+        }
+        let mut unlocker = Unlocker::new_for_master(&loaded, PASSPHRASE).unwrap();
+        let mut transaction = funding;
+        assert!(loaded
+            .sign(&mut transaction, SigHashType::All, &|_| None, &mut unlocker)
+            .is_err());
+        assert!(loaded_coins
+            .save_draft("audit", Psbt::from_unsigned_tx(transaction).unwrap())
+            .is_err());
+        match loaded_coins.set_note(&txid, "") {
+            Err(Error::ReadOnly(_)) => {}
+            _ => panic!("note removed"),
+        }
+        assert!(loaded_coins.note(&txid).is_some());
+        let point = OutPoint::new(txid, 0);
+        fn read_only<T>(result: Result<T, Error>) -> bool {
+            matches!(result, Err(Error::ReadOnly(_)))
+        }
+        assert!(read_only(loaded_coins.freeze(&point)));
+        assert!(read_only(loaded_coins.unfreeze(&point)));
+        assert!(read_only(loaded_coins.dismiss_dust(&point)));
+        assert!(read_only(loaded_coins.set_memo(&point, "audit")));
+        assert!(read_only(loaded_coins.add_tag(&point, "audit")));
+        assert!(read_only(loaded_coins.remove_tag(&point, "audit")));
+        assert!(read_only(loaded_coins.discard_draft("audit")));
+        let edits = loaded_coins.edits(&loaded);
+        assert!(read_only(loaded_coins.sync(&mut loaded, &edits)));
+        assert!(read_only(
+            loaded.add_watched_script(Script::new_op_return(&[3]))
+        ));
+        assert!(audit.save(&loaded, &loaded_coins, &[], PASSPHRASE).is_err());
+        loaded.set_read_only(false);
+        assert!(loaded.get_mut((0, 0)).unwrap().next_key().is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn change_passphrase() {
+        let (master, coins, _) = wallet();
+        let path = path("change_passphrase");
+        let file = WalletFile::new(&path).with_kdf(CHEAP);
+        file.save(&master, &coins, &[], PASSPHRASE).unwrap();
+        // a new passphrase wraps the data key again, the payload stays
+        let before = fs::read(&path).unwrap();
+        file.change_passphrase(PASSPHRASE, "new passphrase")
+            .unwrap();
+        let after = fs::read(&path).unwrap();
+        assert_eq!(
+            before[ENVELOPE_LEN - NONCE_LEN..],
+            after[ENVELOPE_LEN - NONCE_LEN..]
+        );
+        assert!(file.load(PASSPHRASE).is_err());
+        assert!(file.load("new passphrase").unwrap().1 == coins);
+        file.rotate_data_key("new passphrase").unwrap();
+        let rotated = fs::read(&path).unwrap();
+        assert_ne!(after[ENVELOPE_LEN..], rotated[ENVELOPE_LEN..]);
+        assert!(file.load("new passphrase").unwrap().1 == coins);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrate() {
+        let (master, coins, _) = wallet();
+        let path = path("migrate");
+        let file = WalletFile::new(&path).with_kdf(CHEAP);
+        // files of version 1 have no notes nor vaults and are sealed before envelopes
+        let mut payload = serialize(&master);
+        coins
+            .export_snapshot()
+            .unwrap()
+            .consensus_encode(&mut payload)
+            .unwrap();
+        let (mut legacy, key) = header(1, PASSPHRASE, CHEAP).unwrap();
+        let mut ciphertext = vec![0u8; payload.len()];
+        let mut tag = [0u8; TAG_LEN];
+        ChaCha20Poly1305::new(&key, &legacy[HEADER_LEN - NONCE_LEN..], &legacy).encrypt(
+            &payload,
+            &mut ciphertext,
+            &mut tag,
+        );
+        legacy.extend(ciphertext);
+        legacy.extend_from_slice(&tag);
+        assert!(rewrap(&legacy, PASSPHRASE, "new passphrase", CHEAP).is_err());
+        fs::write(&path, &legacy).unwrap();
+        assert!(WalletFile::new(&path).read_only().load(PASSPHRASE).is_ok());
+        assert_eq!(fs::read(&path).unwrap(), legacy);
+        let (_, upgraded, vaults) = file.load(PASSPHRASE).unwrap();
+        assert!(upgraded.notes().is_empty());
+        assert!(vaults.is_empty());
+        assert_eq!(fs::read(&path).unwrap()[4], WALLET_FILE_VERSION);
+        let mut backup = path.clone().into_os_string();
+        backup.push(".v1.bak");
+        assert_eq!(fs::read(&backup).unwrap(), legacy);
+        file.change_passphrase(PASSPHRASE, "new passphrase")
+            .unwrap();
+        assert!(file.load("new passphrase").is_ok());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+    }
+}
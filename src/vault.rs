@@ -0,0 +1,686 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Vaults
+//!
+//! Vaults emulated with pre-signed transactions. Funds are deposited to a one time key that
+//! signs an unvault transaction and an emergency sweep to cold storage, then the key is
+//! forgotten, so the deposit can only move along these transactions. The unvault output can
+//! be spent with the hot key after a relative delay, or at any time by a pre-signed clawback
+//! to cold storage, again signed with a forgotten key.
+//!
+//! Deposits and unvault outputs are watched scripts of the wallet. Once an unvault appears
+//! that the user did not initiate, broadcast the clawback before the delay expires.
+//!
+//! The keys of unfunded deposits and the pre-signed transactions are the only way to move a
+//! deposit, they are persisted, e.g. with WalletFile::save, before a deposit address is handed
+//! out and before a key is forgotten.
+//!
+use std::collections::HashMap;
+use std::io;
+
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::util::bip143;
+use bitcoin::{
+    Address, Network, OutPoint, PrivateKey, PublicKey, Script, SigHashType, Transaction, TxIn,
+    TxOut, VarInt,
+};
+use rand::{thread_rng, RngCore};
+
+use account::MasterAccount;
+use builder::transaction_base_weight;
+use coins::Coins;
+use context::SecpContext;
+use error::Error;
+use fee::FeeRate;
+use selection::{estimate_input_weight, output_weight};
+
+/// The pre-signed transactions of a deposit
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VaultTemplates {
+    pub deposit: OutPoint,
+    /// witness script of the unvault output
+    pub unvault_script: Script,
+    /// moves the deposit to the unvault output
+    pub unvault: Transaction,
+    /// moves the unvault output to cold storage
+    pub clawback: Transaction,
+    /// moves the deposit to cold storage
+    pub emergency: Transaction,
+}
+
+impl VaultTemplates {
+    /// the output of the unvault transaction
+    pub fn unvault_output(&self) -> OutPoint {
+        OutPoint {
+            txid: self.unvault.txid(),
+            vout: 0,
+        }
+    }
+}
+
+/// A vault with deposits
+#[derive(Clone, Debug)]
+pub struct Vault {
+    cold: Script,
+    hot: PublicKey,
+    delay: u16,
+    feerate: FeeRate,
+    /// one time keys of deposit scripts not yet funded
+    pending: HashMap<Script, PrivateKey>,
+    templates: HashMap<OutPoint, VaultTemplates>,
+}
+
+impl Vault {
+    /// a vault paying to cold storage, spent with the hot key delay blocks after unvaulting
+    /// Pre-signed transactions pay feerate.
+    pub fn new(cold: Script, hot: PublicKey, delay: u16, feerate: FeeRate) -> Vault {
+        Vault {
+            cold,
+            hot,
+            delay,
+            feerate,
+            pending: HashMap::new(),
+            templates: HashMap::new(),
+        }
+    }
+
+    /// a new deposit address for a single deposit, watched by the wallet
+    /// Its key is kept until the deposit is seen by sync. The address is returned once persist
+    /// stored the master account and the vault.
+    pub fn deposit_address<P>(
+        &mut self,
+        master: &mut MasterAccount,
+        mut persist: P,
+    ) -> Result<Address, Error>
+    where
+        P: FnMut(&MasterAccount, &Vault) -> Result<(), Error>,
+    {
+        let network = master.master_public().network;
+        let key = one_time_key(network)?;
+        let address = Address::p2wpkh(&SecpContext::new().public_from_private(&key), network)
+            .map_err(|_| Error::Vault("can not create deposit address"))?;
+        let script = address.script_pubkey();
+        master.add_watched_script(script.clone())?;
+        self.pending.insert(script.clone(), key);
+        if let Err(e) = persist(master, self) {
+            self.pending.remove(&script);
+            master.remove_watched_script(&script)?;
+            return Err(e);
+        }
+        Ok(address)
+    }
+
+    /// templates of deposits
+    pub fn templates(&self) -> &HashMap<OutPoint, VaultTemplates> {
+        &self.templates
+    }
+
+    /// sign templates of new deposits and forget their keys, forget templates of deposits
+    /// no longer in the vault. Returns the number of new deposits.
+    /// Templates are stored with persist before the keys that signed them are forgotten, the
+    /// vault is stored again without those keys.
+    pub fn sync<P>(
+        &mut self,
+        master: &mut MasterAccount,
+        coins: &Coins,
+        mut persist: P,
+    ) -> Result<usize, Error>
+    where
+        P: FnMut(&MasterAccount, &Vault) -> Result<(), Error>,
+    {
+        let mut deposits = coins
+            .watched()
+            .iter()
+            .filter(|(_, w)| self.pending.contains_key(&w.output.script_pubkey))
+            .map(|(p, w)| (*p, w.output.clone()))
+            .collect::<Vec<_>>();
+        deposits.sort_by_key(|(p, _)| *p);
+        let mut armed = Vec::new();
+        for (point, output) in deposits {
+            if self.templates.contains_key(&point) {
+                continue;
+            }
+            let key = self.pending[&output.script_pubkey];
+            let templates = self.arm(point, &output, &key)?;
+            master.add_watched_script(templates.unvault.output[0].script_pubkey.clone())?;
+            self.templates.insert(point, templates);
+            armed.push(output.script_pubkey);
+        }
+        let done = self
+            .templates
+            .values()
+            .filter(|t| {
+                !coins.watched().contains_key(&t.deposit)
+                    && !coins.watched().contains_key(&t.unvault_output())
+            })
+            .map(|t| t.deposit)
+            .collect::<Vec<_>>();
+        for deposit in done.iter() {
+            let templates = self.templates.remove(deposit).expect("template");
+            master.remove_watched_script(&templates.unvault.output[0].script_pubkey)?;
+        }
+        if armed.is_empty() {
+            if !done.is_empty() {
+                persist(master, self)?;
+            }
+            return Ok(0);
+        }
+        persist(master, self)?;
+        // the key of a deposit is forgotten once its templates are stored
+        for script in armed.iter() {
+            self.pending.remove(script);
+        }
+        persist(master, self)?;
+        Ok(armed.len())
+    }
+
+    /// deposits with an unvault output, broadcast their clawback unless the unvault was
+    /// intended
+    pub fn unvaulting(&self, coins: &Coins) -> Vec<OutPoint> {
+        let mut deposits = self
+            .templates
+            .values()
+            .filter(|t| coins.watched().contains_key(&t.unvault_output()))
+            .map(|t| t.deposit)
+            .collect::<Vec<_>>();
+        deposits.sort();
+        deposits
+    }
+
+    /// spend the unvault output of a deposit with the hot key
+    /// Valid delay blocks after the unvault transaction confirmed.
+    pub fn spend(
+        &self,
+        deposit: &OutPoint,
+        to: &Script,
+        hot: &PrivateKey,
+    ) -> Result<Transaction, Error> {
+        let templates = self
+            .templates
+            .get(deposit)
+            .ok_or(Error::Vault("unknown deposit"))?;
+        let context = SecpContext::new();
+        if context.public_from_private(hot) != self.hot {
+            return Err(Error::Vault("not the hot key"));
+        }
+        let witness_script = &templates.unvault_script;
+        let spent = &templates.unvault.output[0];
+        let mut transaction = spending(templates.unvault_output(), self.delay as u32, to.clone());
+        transaction.output[0].value = self.pay(spent.value, branch_weight(witness_script), to)?;
+        let signature = sign(&context, &transaction, witness_script, spent.value, hot)?;
+        transaction.input[0].witness = vec![signature, vec![1], witness_script.to_bytes()];
+        Ok(transaction)
+    }
+
+    /// the unvault output: the hot key after the delay or the clawback key
+    fn unvault_script(&self, clawback: &PublicKey) -> Script {
+        Builder::new()
+            .push_opcode(all::OP_IF)
+            .push_int(self.delay as i64)
+            .push_opcode(all::OP_CSV)
+            .push_opcode(all::OP_DROP)
+            .push_key(&self.hot)
+            .push_opcode(all::OP_CHECKSIG)
+            .push_opcode(all::OP_ELSE)
+            .push_key(clawback)
+            .push_opcode(all::OP_CHECKSIG)
+            .push_opcode(all::OP_ENDIF)
+            .into_script()
+    }
+
+    /// value left of spent after the fee of a transaction with one input of input_weight
+    /// paying to
+    fn pay(&self, spent: u64, input_weight: u64, to: &Script) -> Result<u64, Error> {
+        let weight = transaction_base_weight(1, 1) + input_weight + output_weight(to);
+        let value = spent
+            .checked_sub(self.feerate.fee(weight))
+            .ok_or(Error::Vault("deposit does not pay the fee"))?;
+        if value < to.dust_value() {
+            return Err(Error::Vault("deposit does not pay the fee"));
+        }
+        Ok(value)
+    }
+
+    /// pre-sign the transactions of a deposit
+    fn arm(
+        &self,
+        deposit: OutPoint,
+        output: &TxOut,
+        key: &PrivateKey,
+    ) -> Result<VaultTemplates, Error> {
+        let context = SecpContext::new();
+        let public = context.public_from_private(key);
+        let code = Script::new_p2pkh(&public.pubkey_hash());
+        let spend_deposit = |to: &Script| -> Result<Transaction, Error> {
+            let mut transaction = spending(deposit, 0xffff_ffff, to.clone());
+            transaction.output[0].value = self.pay(
+                output.value,
+                estimate_input_weight(&output.script_pubkey),
+                to,
+            )?;
+            let signature = sign(&context, &transaction, &code, output.value, key)?;
+            transaction.input[0].witness = vec![signature, public.to_bytes()];
+            Ok(transaction)
+        };
+        let clawback_key = one_time_key(key.network)?;
+        let clawback_public = context.public_from_private(&clawback_key);
+        let witness_script = self.unvault_script(&clawback_public);
+        let unvault = spend_deposit(&Script::new_v0_wsh(&witness_script.wscript_hash()))?;
+        let emergency = spend_deposit(&self.cold)?;
+
+        let spent = &unvault.output[0];
+        let mut clawback = spending(
+            OutPoint {
+                txid: unvault.txid(),
+                vout: 0,
+            },
+            0xffff_ffff,
+            self.cold.clone(),
+        );
+        clawback.output[0].value =
+            self.pay(spent.value, branch_weight(&witness_script), &self.cold)?;
+        let signature = sign(
+            &context,
+            &clawback,
+            &witness_script,
+            spent.value,
+            &clawback_key,
+        )?;
+        clawback.input[0].witness = vec![signature, Vec::new(), witness_script.to_bytes()];
+        Ok(VaultTemplates {
+            deposit,
+            unvault_script: witness_script,
+            unvault,
+            clawback,
+            emergency,
+        })
+    }
+}
+
+impl Encodable for VaultTemplates {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.deposit.consensus_encode(&mut w)?;
+        len += self.unvault_script.consensus_encode(&mut w)?;
+        len += self.unvault.consensus_encode(&mut w)?;
+        len += self.clawback.consensus_encode(&mut w)?;
+        len += self.emergency.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for VaultTemplates {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<VaultTemplates, encode::Error> {
+        Ok(VaultTemplates {
+            deposit: OutPoint::consensus_decode(&mut d)?,
+            unvault_script: Script::consensus_decode(&mut d)?,
+            unvault: Transaction::consensus_decode(&mut d)?,
+            clawback: Transaction::consensus_decode(&mut d)?,
+            emergency: Transaction::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+impl Encodable for Vault {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.cold.consensus_encode(&mut w)?;
+        len += self.hot.to_bytes().consensus_encode(&mut w)?;
+        len += self.delay.consensus_encode(&mut w)?;
+        len += self.feerate.as_sat_per_kwu().consensus_encode(&mut w)?;
+        let mut pending = self.pending.iter().collect::<Vec<_>>();
+        pending.sort_by_key(|(s, _)| *s);
+        len += VarInt(pending.len() as u64).consensus_encode(&mut w)?;
+        for (script, key) in pending {
+            len += script.consensus_encode(&mut w)?;
+            len += key.network.magic().consensus_encode(&mut w)?;
+            len += key.key[..].to_vec().consensus_encode(&mut w)?;
+        }
+        let mut templates = self.templates.values().collect::<Vec<_>>();
+        templates.sort_by_key(|t| t.deposit);
+        len += VarInt(templates.len() as u64).consensus_encode(&mut w)?;
+        for template in templates {
+            len += template.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Vault {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Vault, encode::Error> {
+        let cold = Script::consensus_decode(&mut d)?;
+        let hot = PublicKey::from_slice(Vec::<u8>::consensus_decode(&mut d)?.as_slice())
+            .map_err(|_| encode::Error::ParseFailed("invalid public key"))?;
+        let delay = u16::consensus_decode(&mut d)?;
+        let feerate = FeeRate::from_sat_per_kwu(u64::consensus_decode(&mut d)?);
+        let mut vault = Vault::new(cold, hot, delay, feerate);
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let script = Script::consensus_decode(&mut d)?;
+            let network = Network::from_magic(u32::consensus_decode(&mut d)?)
+                .ok_or(encode::Error::ParseFailed("unknown network"))?;
+            let key = SecretKey::from_slice(Vec::<u8>::consensus_decode(&mut d)?.as_slice())
+                .map_err(|_| encode::Error::ParseFailed("invalid private key"))?;
+            vault.pending.insert(
+                script,
+                PrivateKey {
+                    compressed: true,
+                    network,
+                    key,
+                },
+            );
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let templates = VaultTemplates::consensus_decode(&mut d)?;
+            vault.templates.insert(templates.deposit, templates);
+        }
+        Ok(vault)
+    }
+}
+
+/// a fresh key, to be forgotten after signing
+fn one_time_key(network: Network) -> Result<PrivateKey, Error> {
+    let mut secret = [0u8; 32];
+    thread_rng().fill_bytes(&mut secret);
+    Ok(PrivateKey {
+        compressed: true,
+        network,
+        key: SecretKey::from_slice(&secret)?,
+    })
+}
+
+/// weight of an input spending a branch of the unvault script
+fn branch_weight(witness_script: &Script) -> u64 {
+    // signature, branch selector and script
+    let witness = 1 + 1 + 72 + 1 + 1 + 1 + witness_script.len() as u64;
+    (32 + 4 + 4 + 1) * 4 + witness
+}
+
+/// unsigned transaction with a single input and output
+fn spending(point: OutPoint, sequence: u32, to: Script) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: point,
+            script_sig: Script::new(),
+            sequence,
+            witness: Vec::new(),
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: to,
+        }],
+    }
+}
+
+/// signature of the single input of a transaction spending a segwit output
+fn sign(
+    context: &SecpContext,
+    transaction: &Transaction,
+    script_code: &Script,
+    value: u64,
+    key: &PrivateKey,
+) -> Result<Vec<u8>, Error> {
+    let sighash = bip143::SigHashCache::new(transaction).signature_hash(
+        0,
+        script_code,
+        value,
+        SigHashType::All,
+    );
+    let mut signature = context.sign(&sighash[..], key)?.serialize_der().to_vec();
+    signature.push(SigHashType::All.as_u32() as u8);
+    Ok(signature)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use fixtures::{block, PASSPHRASE};
+    use storage::{KdfParams, WalletFile};
+
+    use super::*;
+
+    /// a vault with a deposit of 100_000 confirmed at height 1
+    struct Deposit {
+        master: MasterAccount,
+        coins: Coins,
+        vault: Vault,
+        hot: PrivateKey,
+        cold: Script,
+        funding: Transaction,
+        deposit: OutPoint,
+    }
+
+    impl Deposit {
+        fn new() -> Deposit {
+            let mut master =
+                MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE)
+                    .unwrap();
+            let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+            let hot = unlocker
+                .unlock(AccountAddressType::P2WPKH, 0, 0, 0, None)
+                .unwrap();
+            let cold =
+                Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                    .unwrap()
+                    .script_pubkey();
+            let mut vault = Vault::new(
+                cold.clone(),
+                SecpContext::new().public_from_private(&hot),
+                6,
+                FeeRate::from_sat_per_vb(2),
+            );
+            let address = vault.deposit_address(&mut master, |_, _| Ok(())).unwrap();
+            let mut funding = spending(
+                OutPoint {
+                    txid: bitcoin::Txid::default(),
+                    vout: 1,
+                },
+                0xffff_ffff,
+                address.script_pubkey(),
+            );
+            funding.output[0].value = 100_000;
+            let mut coins = Coins::new();
+            coins.process(&mut master, &block(Network::Testnet, vec![funding.clone()]));
+            assert_eq!(vault.sync(&mut master, &coins, |_, _| Ok(())).unwrap(), 1);
+            let deposit = OutPoint {
+                txid: funding.txid(),
+                vout: 0,
+            };
+            Deposit {
+                master,
+                coins,
+                vault,
+                hot,
+                cold,
+                funding,
+                deposit,
+            }
+        }
+
+        fn templates(&self) -> VaultTemplates {
+            self.vault.templates()[&self.deposit].clone()
+        }
+    }
+
+    #[test]
+    fn templates() {
+        let mut deposit = Deposit::new();
+        assert_eq!(
+            deposit
+                .vault
+                .sync(&mut deposit.master, &deposit.coins, |_, _| Ok(()))
+                .unwrap(),
+            0
+        );
+        assert!(deposit.vault.pending.is_empty());
+        let templates = deposit.templates();
+        let funded = |_: &OutPoint| Some(deposit.funding.output[0].clone());
+        templates.unvault.verify(funded).unwrap();
+        templates.emergency.verify(funded).unwrap();
+        assert_eq!(templates.emergency.output[0].script_pubkey, deposit.cold);
+        let unvault_output = |_: &OutPoint| Some(templates.unvault.output[0].clone());
+        templates.clawback.verify(unvault_output).unwrap();
+        assert_eq!(templates.clawback.output[0].script_pubkey, deposit.cold);
+        assert!(deposit.vault.unvaulting(&deposit.coins).is_empty());
+    }
+
+    #[test]
+    fn unvault() {
+        let mut deposit = Deposit::new();
+        let templates = deposit.templates();
+        deposit
+            .coins
+            .process_unconfirmed_transaction(&mut deposit.master, &templates.unvault);
+        assert_eq!(
+            deposit.vault.unvaulting(&deposit.coins),
+            vec![deposit.deposit]
+        );
+        assert_eq!(
+            deposit
+                .vault
+                .sync(&mut deposit.master, &deposit.coins, |_, _| Ok(()))
+                .unwrap(),
+            0
+        );
+        assert_eq!(deposit.vault.templates().len(), 1);
+
+        let to = deposit.funding.output[0].script_pubkey.clone();
+        let other = one_time_key(Network::Testnet).unwrap();
+        assert!(deposit.vault.spend(&deposit.deposit, &to, &other).is_err());
+        let spend = deposit
+            .vault
+            .spend(&deposit.deposit, &to, &deposit.hot)
+            .unwrap();
+        let unvault_output = |_: &OutPoint| Some(templates.unvault.output[0].clone());
+        spend.verify(unvault_output).unwrap();
+        let mut early = spend.clone();
+        early.input[0].sequence = 5;
+        assert!(early.verify(unvault_output).is_err());
+    }
+
+    #[test]
+    fn clawback() {
+        let mut deposit = Deposit::new();
+        let templates = deposit.templates();
+        deposit
+            .coins
+            .process_unconfirmed_transaction(&mut deposit.master, &templates.unvault);
+        deposit
+            .coins
+            .process_unconfirmed_transaction(&mut deposit.master, &templates.clawback);
+        assert!(deposit.vault.unvaulting(&deposit.coins).is_empty());
+        deposit
+            .vault
+            .sync(&mut deposit.master, &deposit.coins, |_, _| Ok(()))
+            .unwrap();
+        assert!(deposit.vault.templates().is_empty());
+        assert!(!deposit
+            .master
+            .is_watched(&templates.unvault.output[0].script_pubkey));
+    }
+
+    #[test]
+    fn restart() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let hot = unlocker
+            .unlock(AccountAddressType::P2WPKH, 0, 0, 0, None)
+            .unwrap();
+        let cold =
+            Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+                .unwrap()
+                .script_pubkey();
+        let mut vault = Vault::new(
+            cold.clone(),
+            SecpContext::new().public_from_private(&hot),
+            6,
+            FeeRate::from_sat_per_vb(2),
+        );
+        let path = std::env::temp_dir().join(format!("vault-{}.dat", std::process::id()));
+        let file = WalletFile::new(&path).with_kdf(KdfParams {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        });
+        let address = vault
+            .deposit_address(&mut master, |master, vault| {
+                file.save(master, &Coins::new(), &[vault], PASSPHRASE)
+            })
+            .unwrap();
+        // the address is not handed out if the vault can not be stored
+        assert!(vault
+            .deposit_address(&mut master, |_, _| Err(Error::Vault("disk full")))
+            .is_err());
+        drop(vault);
+
+        // the deposit key survives a restart before the deposit is seen
+        let (mut master, mut coins, mut vaults) = file.load(PASSPHRASE).unwrap();
+        let mut vault = vaults.pop().unwrap();
+        assert_eq!(vault.pending.len(), 1);
+        let mut funding = spending(
+            OutPoint {
+                txid: bitcoin::Txid::default(),
+                vout: 1,
+            },
+            0xffff_ffff,
+            address.script_pubkey(),
+        );
+        funding.output[0].value = 100_000;
+        coins.process(&mut master, &block(Network::Testnet, vec![funding.clone()]));
+        let mut stored = Vec::new();
+        let armed = vault
+            .sync(&mut master, &coins, |master, vault| {
+                stored.push(vault.pending.len());
+                file.save(master, &coins, &[vault], PASSPHRASE)
+            })
+            .unwrap();
+        assert_eq!(armed, 1);
+        // templates were stored while the key was still known, then the key was forgotten
+        assert_eq!(stored, vec![1, 0]);
+        drop(vault);
+
+        // the templates survive a restart after the key is forgotten
+        let (mut master, mut coins, mut vaults) = file.load(PASSPHRASE).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let vault = vaults.pop().unwrap();
+        assert!(vault.pending.is_empty());
+        let deposit = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        let templates = vault.templates()[&deposit].clone();
+        templates
+            .emergency
+            .verify(|_| Some(funding.output[0].clone()))
+            .unwrap();
+        coins.process_unconfirmed_transaction(&mut master, &templates.unvault);
+        assert_eq!(vault.unvaulting(&coins), vec![deposit]);
+        let unvault_output = |_: &OutPoint| Some(templates.unvault.output[0].clone());
+        templates.clawback.verify(unvault_output).unwrap();
+        assert_eq!(templates.clawback.output[0].script_pubkey, cold);
+        vault
+            .spend(&deposit, &cold, &hot)
+            .unwrap()
+            .verify(unvault_output)
+            .unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # ZMQ notifications of bitcoind
+//!
+//! bitcoind started with -zmqpubrawblock and -zmqpubrawtx publishes blocks and transactions
+//! as they arrive. ZmqSubscriber subscribes to them with a minimal ZMTP 3.0 SUB socket without
+//! security mechanism, and drives ChainSync and Coins without polling.
+//!
+//! A block that extends the processed chain is processed as published, otherwise, e.g. after a
+//! reorg or missed notifications, ChainSync catches up with the backend. Transactions missed
+//! while disconnected are not recovered, they are found once they confirm.
+//!
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::{Block, BlockHash, Transaction, Txid};
+
+use account::MasterAccount;
+use backend::{ChainBackend, ChainSync};
+use coins::Coins;
+use error::Error;
+use proxy::{host_port, Connector};
+
+/// topic of serialized blocks
+pub const RAW_BLOCK: &str = "rawblock";
+/// topic of serialized transactions
+pub const RAW_TX: &str = "rawtx";
+/// the port of the examples of bitcoind, it has no default
+const DEFAULT_PORT: u16 = 28332;
+/// frames larger than a block of maximal weight are refused
+const MAX_FRAME: u64 = 4_000_000;
+/// flags of a frame
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+
+/// A notification published by bitcoind
+#[derive(Clone, Debug, PartialEq)]
+pub enum Notification {
+    Block(Block),
+    Transaction(Transaction),
+}
+
+/// What a notification did to the wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ZmqEvent {
+    /// a block was published, processed blocks including blocks synced to catch up
+    Block { hash: BlockHash, processed: usize },
+    /// a transaction entered the mempool, relevant if it touched the wallet
+    Transaction { txid: Txid, relevant: bool },
+}
+
+/// A SUB socket connected to the ZMQ publisher of bitcoind
+pub struct ZmqSubscriber<S: Read + Write> {
+    stream: S,
+    /// last sequence number per topic
+    sequences: HashMap<Vec<u8>, u32>,
+    /// notifications lost since the last call to missed
+    missed: u32,
+}
+
+impl ZmqSubscriber<TcpStream> {
+    /// connect to host:port of a ZMQ publisher of bitcoind and subscribe to topics
+    pub fn connect(address: &str, topics: &[&str]) -> Result<ZmqSubscriber<TcpStream>, Error> {
+        let (host, port) = host_port(address, DEFAULT_PORT)?;
+        let stream = Connector::direct().connect(host.as_str(), port)?;
+        // notifications arrive when they arrive
+        stream.set_read_timeout(None)?;
+        ZmqSubscriber::new(stream, topics)
+    }
+}
+
+impl<S: Read + Write> ZmqSubscriber<S> {
+    /// greet a ZMQ publisher over a connected stream and subscribe to topics
+    pub fn new(mut stream: S, topics: &[&str]) -> Result<ZmqSubscriber<S>, Error> {
+        let mut greeting = [0u8; 64];
+        greeting[0] = 0xff;
+        greeting[8] = 0x01;
+        greeting[9] = 0x7f;
+        greeting[10] = 3;
+        greeting[12..16].copy_from_slice(b"NULL");
+        stream.write_all(&greeting)?;
+        let mut theirs = [0u8; 64];
+        stream.read_exact(&mut theirs)?;
+        if theirs[0] != 0xff || theirs[9] != 0x7f || theirs[10] < 3 {
+            return Err(Error::Zmq("not a ZMTP 3 peer"));
+        }
+        if theirs[12..32] != greeting[12..32] {
+            return Err(Error::Zmq("security mechanism not supported"));
+        }
+        let mut ready = b"\x05READY\x0bSocket-Type\x00\x00\x00\x03SUB".to_vec();
+        write_frame(&mut stream, COMMAND, &ready)?;
+        let (flags, command) = read_frame(&mut stream)?;
+        if flags & COMMAND == 0 || !command.starts_with(b"\x05READY") {
+            return Err(Error::Zmq("expected READY"));
+        }
+        match property(&command[6..], b"Socket-Type") {
+            Some(b"PUB") | Some(b"XPUB") => {}
+            _ => return Err(Error::Zmq("not a publisher")),
+        }
+        for topic in topics {
+            ready.clear();
+            ready.push(0x01);
+            ready.extend_from_slice(topic.as_bytes());
+            write_frame(&mut stream, 0, &ready)?;
+        }
+        stream.flush()?;
+        Ok(ZmqSubscriber {
+            stream,
+            sequences: HashMap::new(),
+            missed: 0,
+        })
+    }
+
+    /// notifications lost since the last call, e.g. because bitcoind dropped them under load
+    pub fn missed(&mut self) -> u32 {
+        let missed = self.missed;
+        self.missed = 0;
+        missed
+    }
+
+    /// wait for the next notification of a known topic
+    pub fn receive(&mut self) -> Result<Notification, Error> {
+        loop {
+            let mut parts = Vec::new();
+            loop {
+                let (flags, body) = read_frame(&mut self.stream)?;
+                if flags & COMMAND != 0 {
+                    continue;
+                }
+                parts.push(body);
+                if flags & MORE == 0 {
+                    break;
+                }
+            }
+            if parts.len() != 3 || parts[2].len() != 4 {
+                return Err(Error::Zmq("expected topic, body and sequence"));
+            }
+            let mut sequence = [0u8; 4];
+            sequence.copy_from_slice(&parts[2]);
+            let sequence = u32::from_le_bytes(sequence);
+            if let Some(last) = self.sequences.insert(parts[0].clone(), sequence) {
+                self.missed += sequence.wrapping_sub(last).wrapping_sub(1);
+            }
+            match parts[0].as_slice() {
+                b"rawblock" => return Ok(Notification::Block(deserialize(&parts[1])?)),
+                b"rawtx" => return Ok(Notification::Transaction(deserialize(&parts[1])?)),
+                _ => {}
+            }
+        }
+    }
+
+    /// wait for the next notification and process it
+    pub fn next<B: ChainBackend>(
+        &mut self,
+        backend: &B,
+        sync: &mut ChainSync,
+        master: &mut MasterAccount,
+        coins: &mut Coins,
+    ) -> Result<ZmqEvent, Error> {
+        match self.receive()? {
+            Notification::Block(block) => {
+                let hash = block.block_hash();
+                let processed = if sync.connect(master, coins, &block) {
+                    1
+                } else {
+                    sync.sync(backend, master, coins)?
+                };
+                Ok(ZmqEvent::Block { hash, processed })
+            }
+            Notification::Transaction(transaction) => Ok(ZmqEvent::Transaction {
+                txid: transaction.txid(),
+                relevant: coins.process_unconfirmed_transaction(master, &transaction),
+            }),
+        }
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, flags: u8, body: &[u8]) -> Result<(), Error> {
+    if body.len() > 255 {
+        writer.write_all(&[flags | LONG])?;
+        writer.write_all(&(body.len() as u64).to_be_bytes())?;
+    } else {
+        writer.write_all(&[flags, body.len() as u8])?;
+    }
+    writer.write_all(body)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<(u8, Vec<u8>), Error> {
+    let mut flags = [0u8; 1];
+    reader.read_exact(&mut flags)?;
+    let size = if flags[0] & LONG != 0 {
+        let mut size = [0u8; 8];
+        reader.read_exact(&mut size)?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8; 1];
+        reader.read_exact(&mut size)?;
+        size[0] as u64
+    };
+    if size > MAX_FRAME {
+        return Err(Error::Zmq("frame too large"));
+    }
+    let mut body = vec![0u8; size as usize];
+    reader.read_exact(&mut body)?;
+    Ok((flags[0], body))
+}
+
+/// value of a property of a READY command
+fn property<'a>(mut properties: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    while let Some(&length) = properties.first() {
+        let length = length as usize;
+        if properties.len() < 1 + length + 4 {
+            return None;
+        }
+        let key = &properties[1..1 + length];
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&properties[1 + length..5 + length]);
+        let size = u32::from_be_bytes(size) as usize;
+        let value = properties.get(5 + length..5 + length + size)?;
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        properties = &properties[5 + length + size..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::util::bip158::BlockFilter;
+    use bitcoin::{BlockHeader, Network, OutPoint, Script, TxIn, TxOut};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use backend::TxStatus;
+    use fee::FeeRate;
+    use fixtures::{block_after, master_account, next_script};
+
+    use super::*;
+
+    /// a node that only serves blocks up to its tip
+    struct Node(Vec<Block>);
+
+    impl ChainBackend for Node {
+        fn tip(&self) -> Result<u32, Error> {
+            Ok(self.0.len() as u32 - 1)
+        }
+
+        fn header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+            Ok(self.0.get(height as usize).map(|b| b.header))
+        }
+
+        fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+            self.0
+                .iter()
+                .find(|b| b.block_hash() == *hash)
+                .cloned()
+                .ok_or(Error::Backend("unknown block"))
+        }
+
+        fn filter(&self, _: &BlockHash) -> Result<Option<BlockFilter>, Error> {
+            Ok(None)
+        }
+
+        fn tx_status(&self, _: &Txid) -> Result<TxStatus, Error> {
+            Ok(TxStatus::Unknown)
+        }
+
+        fn broadcast(&self, transaction: &Transaction) -> Result<Txid, Error> {
+            Ok(transaction.txid())
+        }
+
+        fn feerate(&self, _: u16) -> Result<FeeRate, Error> {
+            Err(Error::Backend("no fee rates"))
+        }
+    }
+
+    /// publish messages to the first subscriber, checks its subscriptions
+    fn publish(listener: TcpListener, messages: Vec<(&'static str, Vec<u8>, u32)>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 64];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting[12..16], b"NULL");
+        stream.write_all(&greeting).unwrap();
+        let (flags, ready) = read_frame(&mut stream).unwrap();
+        assert_eq!(flags, COMMAND);
+        assert_eq!(property(&ready[6..], b"socket-type"), Some(&b"SUB"[..]));
+        write_frame(
+            &mut stream,
+            COMMAND,
+            b"\x05READY\x0bSocket-Type\x00\x00\x00\x03PUB",
+        )
+        .unwrap();
+        for topic in &[RAW_BLOCK, RAW_TX] {
+            let (_, subscription) = read_frame(&mut stream).unwrap();
+            assert_eq!(subscription[0], 0x01);
+            assert_eq!(&subscription[1..], topic.as_bytes());
+        }
+        for (topic, body, sequence) in messages {
+            write_frame(&mut stream, MORE, topic.as_bytes()).unwrap();
+            write_frame(&mut stream, MORE, &body).unwrap();
+            write_frame(&mut stream, 0, &sequence.to_le_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn zmq_subscriber() {
+        let (mut master, _) = master_account(Network::Testnet);
+        let script = next_script(&mut master, (0, 0));
+        let transaction = |script: Script, value: u64| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::default(),
+                    vout: value as u32,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script,
+            }],
+        };
+        let genesis = genesis_block(Network::Testnet);
+        let one = block_after(&genesis, vec![transaction(Script::new(), 1)]);
+        let funding = transaction(script, 100_000);
+        let two = block_after(&one, vec![funding.clone()]);
+        let node = Node(vec![genesis, one.clone()]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let messages = vec![
+            (RAW_BLOCK, serialize(&one), 0),
+            (RAW_TX, serialize(&transaction(Script::new(), 2)), 0),
+            (RAW_TX, serialize(&funding), 3),
+            ("hashblock", two.block_hash().to_vec(), 0),
+            (RAW_BLOCK, serialize(&two), 1),
+        ];
+        let publisher = thread::spawn(move || publish(listener, messages));
+        let mut subscriber = ZmqSubscriber::connect(&address, &[RAW_BLOCK, RAW_TX]).unwrap();
+        let mut coins = Coins::new();
+        let mut sync = ChainSync::new(0);
+        let mut next = || subscriber.next(&node, &mut sync, &mut master, &mut coins);
+
+        // nothing processed yet, catch up with the node
+        assert_eq!(
+            next().unwrap(),
+            ZmqEvent::Block {
+                hash: one.block_hash(),
+                processed: 2
+            }
+        );
+        assert!(
+            next().unwrap()
+                == ZmqEvent::Transaction {
+                    txid: transaction(Script::new(), 2).txid(),
+                    relevant: false
+                }
+        );
+        assert!(
+            next().unwrap()
+                == ZmqEvent::Transaction {
+                    txid: funding.txid(),
+                    relevant: true
+                }
+        );
+        // unknown topics are skipped, the node does not know the block yet
+        assert_eq!(
+            next().unwrap(),
+            ZmqEvent::Block {
+                hash: two.block_hash(),
+                processed: 1
+            }
+        );
+        assert_eq!(subscriber.missed(), 2);
+        assert_eq!(coins.confirmed_balance(), 100_000);
+        assert_eq!(sync.tip().unwrap(), (2, two.block_hash()));
+        publisher.join().unwrap();
+    }
+}